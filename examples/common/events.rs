@@ -0,0 +1,118 @@
+//! Newline-delimited JSON events printed by the examples in `--json` mode
+//!
+//! Every line on stdout is one JSON object whose `event` field names its kind. Addresses are
+//! checksummed, token amounts are decimal strings in the token's smallest unit:
+//!
+//! - `block`: `number`, `hash`, `timestamp`, and the number of `new_pools`, `updated_pools` and
+//!   `removed_pools` of a block update
+//! - `pool`: `action` (`added` or `removed`), `id`, `protocol` and `tokens` of a pool
+//! - `price`: spot `price` of `base` in `quote` on pool `id` at `block`, as a decimal string in
+//!   whole units with the decimals of `quote`, `null` with an `error` if it couldn't be computed
+//! - `quote`: `amount_out` and `gas` for swapping `amount_in` of `token_in` to `token_out` on pool
+//!   `id` at `block`, `null` with an `error` if the swap failed
+//!
+//! Fields are only ever added, never renamed or removed, so scripts can rely on them. Logs go to
+//! stderr or files, never to stdout.
+use std::io::{self, Write};
+
+use num_bigint::BigUint;
+use serde_json::{json, Value};
+use tycho_core::Bytes;
+use tycho_simulation::{
+    models::Token,
+    protocol::{
+        errors::SimulationError,
+        models::{BlockUpdate, GetAmountOutResult, ProtocolComponent},
+        price::Price,
+    },
+    tokens::to_checksum,
+};
+
+/// Prints an event as one line on stdout.
+pub fn emit(event: Value) {
+    let mut stdout = io::stdout().lock();
+    // A closed pipe, e.g. `| head`, ends the output but not the example
+    let _ = writeln!(stdout, "{event}");
+}
+
+fn address(address: &Bytes) -> String {
+    to_checksum(address).unwrap_or_else(|_| address.to_string())
+}
+
+pub fn block(update: &BlockUpdate) -> Value {
+    json!({
+        "event": "block",
+        "number": update.block_number,
+        "hash": update.block.as_ref().map(|block| block.hash.to_string()),
+        "timestamp": update.block.as_ref().map(|block| block.timestamp),
+        "new_pools": update.new_pairs.len(),
+        "updated_pools": update.states.len(),
+        "removed_pools": update.removed_pairs.len(),
+    })
+}
+
+pub fn pool(action: &str, id: &str, component: &ProtocolComponent) -> Value {
+    json!({
+        "event": "pool",
+        "action": action,
+        "id": id,
+        "protocol": component.protocol_system,
+        "tokens": component
+            .tokens
+            .iter()
+            .map(|token| address(&token.address))
+            .collect::<Vec<_>>(),
+    })
+}
+
+pub fn price(
+    block: u64,
+    id: &str,
+    component: &ProtocolComponent,
+    base: &Token,
+    quote: &Token,
+    price: Result<f64, SimulationError>,
+) -> Value {
+    let (price, error) = match price.map(|price| Price::from_f64(base, quote, price)) {
+        Ok(Some(price)) => (Some(price.to_string()), None),
+        Ok(None) => (None, Some("Invalid price".to_string())),
+        Err(e) => (None, Some(e.to_string())),
+    };
+    json!({
+        "event": "price",
+        "block": block,
+        "id": id,
+        "protocol": component.protocol_system,
+        "base": address(&base.address),
+        "quote": address(&quote.address),
+        "price": price,
+        "error": error,
+    })
+}
+
+pub fn quote(
+    block: u64,
+    id: &str,
+    component: &ProtocolComponent,
+    token_in: &Token,
+    token_out: &Token,
+    amount_in: &BigUint,
+    result: &Result<GetAmountOutResult, SimulationError>,
+) -> Value {
+    let (amount_out, gas, error) = match result {
+        Ok(result) => (Some(result.amount.to_string()), Some(result.gas.to_string()), None),
+        Err(e) => (None, None, Some(e.to_string())),
+    };
+    json!({
+        "event": "quote",
+        "block": block,
+        "id": id,
+        "protocol": component.protocol_system,
+        "token_in": address(&token_in.address),
+        "token_out": address(&token_out.address),
+        "amount_in": amount_in.to_string(),
+        "amount_out": amount_out,
+        "gas": gas,
+        "error": error,
+    })
+}
@@ -1,12 +1,19 @@
+// Shared with the other examples, not every event is used by each
+#[allow(dead_code)]
+#[path = "../common/events.rs"]
+mod events;
 mod ui;
 pub mod utils;
 
 extern crate tycho_simulation;
-use std::{env, str::FromStr};
+use std::{collections::HashMap, env, str::FromStr};
 
 use clap::Parser;
 use futures::{future::select_all, StreamExt};
-use tokio::{sync::mpsc, task::JoinHandle};
+use tokio::{
+    sync::{mpsc, watch},
+    task::JoinHandle,
+};
 use tycho_client::feed::component_tracker::ComponentFilter;
 use tycho_core::models::Chain;
 use tycho_simulation::{
@@ -19,7 +26,7 @@ use tycho_simulation::{
             uniswap_v4::state::UniswapV4State,
             vm::state::EVMPoolState,
         },
-        stream::ProtocolStreamBuilder,
+        stream::{Health, ProtocolStreamBuilder},
     },
     protocol::models::BlockUpdate,
     utils::load_all_tokens,
@@ -34,6 +41,9 @@ struct Cli {
     /// The target blockchain
     #[clap(long, default_value = "ethereum")]
     pub chain: String,
+    /// Print newline-delimited JSON events instead of running the TUI
+    #[arg(long)]
+    json: bool,
 }
 
 fn register_exchanges(
@@ -82,6 +92,7 @@ async fn main() {
     utils::setup_tracing();
     // Parse command-line arguments into a Cli struct
     let cli = Cli::parse();
+    let json = cli.json;
     let chain =
         Chain::from_str(&cli.chain).unwrap_or_else(|_| panic!("Unknown chain {}", cli.chain));
 
@@ -99,6 +110,7 @@ async fn main() {
 
     // Create communication channels for inter-thread communication
     let (tick_tx, tick_rx) = mpsc::channel::<BlockUpdate>(12);
+    let (health_tx, health_rx) = watch::channel(Health::default());
 
     let tycho_message_processor: JoinHandle<anyhow::Result<()>> = tokio::spawn(async move {
         let all_tokens = load_all_tokens(
@@ -111,18 +123,19 @@ async fn main() {
         )
         .await;
         let tvl_filter = ComponentFilter::with_tvl_range(cli.tvl_threshold, cli.tvl_threshold);
-        let mut protocol_stream =
+        let (stream_handle, mut protocol_stream) =
             register_exchanges(ProtocolStreamBuilder::new(&tycho_url, chain), &chain, tvl_filter)
                 .auth_key(Some(tycho_api_key.clone()))
                 .skip_state_decode_failures(true)
                 .set_tokens(all_tokens)
                 .await
-                .build()
+                .build_with_handle()
                 .await
                 .expect("Failed building protocol stream");
 
         // Loop through block updates
         while let Some(msg) = protocol_stream.next().await {
+            health_tx.send_replace(stream_handle.health());
             tick_tx
                 .send(msg.unwrap())
                 .await
@@ -131,9 +144,15 @@ async fn main() {
         anyhow::Result::Ok(())
     });
 
+    if json {
+        let printer = tokio::spawn(print_events(tick_rx));
+        let _ = select_all([tycho_message_processor, printer]).await;
+        return;
+    }
+
     let terminal = ratatui::init();
     let terminal_app = tokio::spawn(async move {
-        ui::App::new(tick_rx)
+        ui::App::new(tick_rx, health_rx)
             .run(terminal)
             .await
     });
@@ -141,3 +160,26 @@ async fn main() {
     let _ = select_all(tasks).await;
     ratatui::restore();
 }
+
+/// Prints the pools and spot prices of each block update as JSON events, see `events`.
+async fn print_events(mut rx: mpsc::Receiver<BlockUpdate>) -> anyhow::Result<()> {
+    let mut components = HashMap::new();
+    while let Some(update) = rx.recv().await {
+        events::emit(events::block(&update));
+        for (id, component) in &update.new_pairs {
+            events::emit(events::pool("added", id, component));
+            components.insert(id.clone(), component.clone());
+        }
+        for (id, component) in &update.removed_pairs {
+            events::emit(events::pool("removed", id, component));
+            components.remove(id);
+        }
+        for (id, state) in &update.states {
+            let Some(component) = components.get(id) else { continue };
+            let (base, quote) = (&component.tokens[0], &component.tokens[1]);
+            let price = state.spot_price(base, quote);
+            events::emit(events::price(update.block_number, id, component, base, quote, price));
+        }
+    }
+    Ok(())
+}
@@ -1,4 +1,8 @@
-use std::{str::FromStr, time::Instant};
+use std::{
+    collections::{BTreeSet, HashMap},
+    str::FromStr,
+    time::Instant,
+};
 
 use futures::StreamExt;
 use itertools::Itertools;
@@ -15,21 +19,30 @@ use ratatui::{
     },
     DefaultTerminal, Frame,
 };
-use tokio::{select, sync::mpsc::Receiver};
+use tokio::{
+    select,
+    sync::{mpsc::Receiver, watch},
+};
 use tracing::warn;
 use tycho_core::Bytes;
-use tycho_simulation::protocol::{
-    models::{BlockUpdate, ProtocolComponent},
-    state::ProtocolSim,
+use tycho_simulation::{
+    evm::stream::Health,
+    protocol::{
+        models::{BlockUpdate, ProtocolComponent},
+        price::Price,
+        state::ProtocolSim,
+    },
 };
 
 const INFO_TEXT: [&str; 2] = [
     "(Esc) quit | (↑) move up | (↓) move down | (↵) Toggle Quote | (+) Increase Quote Amount",
-    "(-) Decrease Quote Amount | (z) Flip Quote Direction ",
+    "(-) Decrease Quote Amount | (z) Flip Quote Direction | (h) Toggle Protocol Health",
 ];
 
 const ITEM_HEIGHT: usize = 3;
 
+const HEALTH_HEIGHT: u16 = 10;
+
 struct TableColors {
     buffer_bg: Color,
     header_bg: Color,
@@ -68,6 +81,13 @@ struct Data {
     price: String,
 }
 
+/// Spot prices computed for the pools of a protocol, and how many of them failed
+#[derive(Default)]
+struct QuoteStats {
+    quotes: u64,
+    errors: u64,
+}
+
 impl Data {
     const fn ref_array(&self) -> [&String; 4] {
         [&self.name, &self.component.protocol_system, &self.tokens, &self.price]
@@ -81,16 +101,22 @@ pub struct App {
     zero2one: bool,
     items: Vec<Data>,
     rx: Receiver<BlockUpdate>,
+    health: watch::Receiver<Health>,
+    show_health: bool,
+    quote_stats: HashMap<String, QuoteStats>,
     scroll_state: ScrollbarState,
     colors: TableColors,
 }
 
 impl App {
-    pub fn new(rx: Receiver<BlockUpdate>) -> Self {
+    pub fn new(rx: Receiver<BlockUpdate>, health: watch::Receiver<Health>) -> Self {
         let data_vec = Vec::new();
         Self {
             state: TableState::default().with_selected(0),
             show_popup: false,
+            health,
+            show_health: false,
+            quote_stats: HashMap::new(),
             quote_amount: BigUint::one(),
             zero2one: true,
             rx,
@@ -149,20 +175,15 @@ impl App {
                 .iter()
                 .map(|a| a.symbol.clone())
                 .join("/");
-            let price = update
-                .states
-                .get(id)
-                .map(|el| el.spot_price(&comp.tokens[0], &comp.tokens[1]))
-                .unwrap_or(Ok(0.0));
-
             match update.states.get(id) {
                 Some(state) => {
+                    let price = self.spot_price(comp, state.as_ref());
                     self.items.push(Data {
                         component: comp.clone(),
                         state: state.clone(),
                         name,
                         tokens,
-                        price: format!("{}", price.expect("Expected f64 as spot price")),
+                        price,
                     });
                 }
                 None => {
@@ -178,9 +199,10 @@ impl App {
                 .iter()
                 .find_position(|e| e.component.id == eth_address);
             if let Some((index, _)) = entry {
+                let component = self.items[index].component.clone();
+                let price = self.spot_price(&component, state.as_ref());
                 let row = self.items.get_mut(index).unwrap();
-                let price = state.spot_price(&row.component.tokens[0], &row.component.tokens[1]);
-                row.price = format!("{}", price.expect("Expected f64 as spot price"));
+                row.price = price;
                 row.state = state.clone();
             }
         }
@@ -197,6 +219,25 @@ impl App {
         }
     }
 
+    /// Formats the spot price of a pool, counting failures per protocol.
+    fn spot_price(&mut self, component: &ProtocolComponent, state: &dyn ProtocolSim) -> String {
+        let stats = self
+            .quote_stats
+            .entry(component.protocol_system.clone())
+            .or_default();
+        stats.quotes += 1;
+        let (base, quote) = (&component.tokens[0], &component.tokens[1]);
+        match state.spot_price(base, quote) {
+            Ok(price) => Price::from_f64(base, quote, price)
+                .map_or_else(|| "invalid".to_string(), |price| price.to_string()),
+            Err(err) => {
+                stats.errors += 1;
+                warn!(pool = %component.id, ?err, "Failed to compute spot price");
+                "error".to_string()
+            }
+        }
+    }
+
     pub async fn run(mut self, mut terminal: DefaultTerminal) -> anyhow::Result<()> {
         let mut reader = event::EventStream::new();
         loop {
@@ -231,6 +272,7 @@ impl App {
                                 }
                                 KeyCode::Char('k') | KeyCode::Up => self.move_row(-1),
                                 KeyCode::Enter => self.show_popup = !self.show_popup,
+                                KeyCode::Char('h') => self.show_health = !self.show_health,
                                 _ => {}
                             }
                         }
@@ -261,12 +303,20 @@ impl App {
     }
 
     fn draw(&mut self, frame: &mut Frame) {
-        let vertical = &Layout::vertical([Constraint::Min(5), Constraint::Length(4)]);
+        let health_height = if self.show_health { HEALTH_HEIGHT } else { 0 };
+        let vertical = &Layout::vertical([
+            Constraint::Min(5),
+            Constraint::Length(health_height),
+            Constraint::Length(4),
+        ]);
         let rects = vertical.split(frame.area());
 
         self.render_table(frame, rects[0]);
         self.render_scrollbar(frame, rects[0]);
-        self.render_footer(frame, rects[1]);
+        if self.show_health {
+            self.render_health(frame, rects[1]);
+        }
+        self.render_footer(frame, rects[2]);
         if self.items.is_empty() {
             self.render_loading(frame);
         }
@@ -334,6 +384,79 @@ impl App {
         frame.render_stateful_widget(t, area, &mut self.state);
     }
 
+    /// Renders decoding and quoting statistics per protocol. Protocols with decode failures or
+    /// quarantined pools are highlighted.
+    fn render_health(&self, frame: &mut Frame, area: Rect) {
+        let health = self.health.borrow();
+        let header_style = Style::default()
+            .fg(self.colors.header_fg)
+            .bg(self.colors.header_bg);
+        let header = [
+            "Protocol",
+            "Pools",
+            "Updates/block",
+            "Decode failures",
+            "Quarantined",
+            "Quote errors",
+        ]
+        .into_iter()
+        .map(Cell::from)
+        .collect::<Row>()
+        .style(header_style);
+
+        let protocols: BTreeSet<&String> = health
+            .protocols
+            .keys()
+            .chain(self.quote_stats.keys())
+            .collect();
+        let rows = protocols.into_iter().map(|protocol| {
+            let stats = health
+                .protocols
+                .get(protocol)
+                .cloned()
+                .unwrap_or_default();
+            let quotes = self
+                .quote_stats
+                .get(protocol)
+                .map_or((0, 0), |stats| (stats.quotes, stats.errors));
+            let error_rate = if quotes.0 == 0 { 0.0 } else { quotes.1 as f64 / quotes.0 as f64 };
+            let color = if stats.decode_failures > 0 || stats.quarantined > 0 || quotes.1 > 0 {
+                tailwind::RED.c400
+            } else {
+                self.colors.row_fg
+            };
+            Row::new([
+                protocol.clone(),
+                stats.pools.to_string(),
+                format!("{:.2}", stats.updates as f64 / health.blocks.max(1) as f64),
+                stats.decode_failures.to_string(),
+                stats.quarantined.to_string(),
+                format!("{}/{} ({:.1}%)", quotes.1, quotes.0, error_rate * 100.0),
+            ])
+            .style(Style::new().fg(color))
+        });
+
+        let title = format!(
+            " Protocol health | block {} | lag {}s | stream errors {} ",
+            health
+                .last_block
+                .map_or("-".to_string(), |block| block.to_string()),
+            health
+                .lag
+                .map_or("-".to_string(), |lag| lag.to_string()),
+            health.errors
+        );
+        let table = Table::new(rows, [Constraint::Min(1); 6])
+            .header(header)
+            .bg(self.colors.buffer_bg)
+            .block(
+                Block::bordered()
+                    .title(title)
+                    .border_style(Style::new().fg(self.colors.footer_border_color)),
+            );
+        frame.render_widget(table, area);
+    }
+
     fn render_scrollbar(&mut self, frame: &mut Frame, area: Rect) {
         frame.render_stateful_widget(
             Scrollbar::default()
@@ -0,0 +1,305 @@
+use std::{
+    collections::HashMap,
+    env,
+    fmt::Write,
+    net::SocketAddr,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use futures::StreamExt;
+use num_bigint::BigUint;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    sync::RwLock,
+};
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+use tycho_client::feed::component_tracker::ComponentFilter;
+use tycho_core::{models::Chain, Bytes};
+use tycho_simulation::{
+    evm::{
+        protocol::{uniswap_v2::state::UniswapV2State, uniswap_v3::state::UniswapV3State},
+        stream::ProtocolStreamBuilder,
+    },
+    models::Token,
+    protocol::{
+        freshness::StateStore,
+        registry::{PoolQuery, PoolRegistry},
+    },
+    utils::load_all_tokens,
+};
+
+#[derive(Parser)]
+struct Cli {
+    /// Pairs to monitor, as `<base token>:<quote token>` addresses. Can be repeated.
+    #[arg(short, long, required = true)]
+    pair: Vec<String>,
+    /// The tvl threshold to filter the pools by
+    #[arg(short, long, default_value_t = 100.0)]
+    tvl_threshold: f64,
+    /// Address to serve the metrics on, at `/metrics`
+    #[arg(short, long, default_value = "0.0.0.0:9100")]
+    listen: SocketAddr,
+    #[arg(short, long, default_value = "ethereum")]
+    chain: String,
+}
+
+/// A monitored pool and the metrics of its last quote
+struct PoolMetrics {
+    pool: String,
+    protocol_system: String,
+    base: String,
+    quote: String,
+    spot_price: Option<f64>,
+    /// Amount out for one unit of the base token, in units of the quote token
+    quote_amount: Option<f64>,
+    quote_latency: Duration,
+}
+
+#[derive(Default)]
+struct Metrics {
+    block_number: u64,
+    block_lag: Option<u64>,
+    pools: usize,
+    processing_time: Duration,
+    blocks_processed: u64,
+    quote_errors: u64,
+    monitored: Vec<PoolMetrics>,
+}
+
+impl Metrics {
+    /// Renders the metrics in the Prometheus text exposition format.
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, f64)>| {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{name}{labels} {value}");
+            }
+        };
+        metric(
+            "tycho_block_number",
+            "gauge",
+            "Block of the held states",
+            vec![(String::new(), self.block_number as f64)],
+        );
+        metric(
+            "tycho_block_lag",
+            "gauge",
+            "Number of blocks the states lag behind the chain",
+            self.block_lag
+                .map(|lag| (String::new(), lag as f64))
+                .into_iter()
+                .collect(),
+        );
+        metric(
+            "tycho_pools",
+            "gauge",
+            "Number of tracked pools",
+            vec![(String::new(), self.pools as f64)],
+        );
+        metric(
+            "tycho_block_processing_seconds",
+            "gauge",
+            "Time spent applying the last block and quoting the monitored pools",
+            vec![(String::new(), self.processing_time.as_secs_f64())],
+        );
+        metric(
+            "tycho_blocks_processed_total",
+            "counter",
+            "Number of blocks received from the stream",
+            vec![(String::new(), self.blocks_processed as f64)],
+        );
+        metric(
+            "tycho_quote_errors_total",
+            "counter",
+            "Number of failed quotes",
+            vec![(String::new(), self.quote_errors as f64)],
+        );
+        let labels = |m: &PoolMetrics| {
+            format!(
+                "{{pool=\"{}\",protocol=\"{}\",base=\"{}\",quote=\"{}\"}}",
+                m.pool, m.protocol_system, m.base, m.quote
+            )
+        };
+        metric(
+            "tycho_spot_price",
+            "gauge",
+            "Spot price of the base token in the quote token",
+            self.monitored
+                .iter()
+                .filter_map(|m| Some((labels(m), m.spot_price?)))
+                .collect(),
+        );
+        metric(
+            "tycho_quote_amount",
+            "gauge",
+            "Amount out for one unit of the base token",
+            self.monitored
+                .iter()
+                .filter_map(|m| Some((labels(m), m.quote_amount?)))
+                .collect(),
+        );
+        metric(
+            "tycho_quote_latency_seconds",
+            "gauge",
+            "Latency of the last quote",
+            self.monitored
+                .iter()
+                .map(|m| (labels(m), m.quote_latency.as_secs_f64()))
+                .collect(),
+        );
+        out
+    }
+}
+
+fn parse_pair(pair: &str, tokens: &HashMap<Bytes, Token>) -> (Token, Token) {
+    let (base, quote) = pair
+        .split_once(':')
+        .unwrap_or_else(|| panic!("Invalid pair {pair}, expected <base>:<quote>"));
+    let token = |address: &str| {
+        let address = Bytes::from_str(address).expect("Invalid token address");
+        tokens
+            .get(&address)
+            .unwrap_or_else(|| panic!("Unknown token {address}"))
+            .clone()
+    };
+    (token(base), token(quote))
+}
+
+/// Quotes one unit of the base token on every pool of the pair.
+fn quote_pair(
+    store: &StateStore,
+    registry: &PoolRegistry,
+    base: &Token,
+    quote: &Token,
+    metrics: &mut Metrics,
+) {
+    let query = PoolQuery::new().pair(base.address.clone(), quote.address.clone());
+    for entry in registry.query(&query).pools {
+        let Some(state) = store.get(entry.id) else { continue };
+        let start = Instant::now();
+        let amount = store.get_amount_out(
+            entry.id,
+            BigUint::from(10u64).pow(base.decimals as u32),
+            base,
+            quote,
+        );
+        let quote_latency = start.elapsed();
+        let quote_amount = match amount {
+            Ok(result) => Some(
+                result
+                    .amount
+                    .to_string()
+                    .parse::<f64>()
+                    .unwrap_or(f64::NAN) /
+                    10f64.powi(quote.decimals as i32),
+            ),
+            Err(e) => {
+                warn!(pool = entry.id, error = %e, "Quote failed");
+                metrics.quote_errors += 1;
+                None
+            }
+        };
+        metrics.monitored.push(PoolMetrics {
+            pool: entry.id.to_string(),
+            protocol_system: entry.component.protocol_system.clone(),
+            base: base.symbol.clone(),
+            quote: quote.symbol.clone(),
+            spot_price: state.spot_price(base, quote).ok(),
+            quote_amount,
+            quote_latency,
+        });
+    }
+}
+
+async fn serve(listener: TcpListener, metrics: Arc<RwLock<Metrics>>) {
+    loop {
+        let Ok((mut socket, _)) = listener.accept().await else { continue };
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let mut request = [0u8; 1024];
+            let Ok(n) = socket.read(&mut request).await else { return };
+            let response = if request[..n].starts_with(b"GET /metrics") {
+                let body = metrics.read().await.render();
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            } else {
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string()
+            };
+            let _ = socket
+                .write_all(response.as_bytes())
+                .await;
+        });
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
+    let cli = Cli::parse();
+    let chain = Chain::from_str(&cli.chain).expect("Invalid chain");
+    let tycho_url =
+        env::var("TYCHO_URL").unwrap_or_else(|_| "tycho-beta.propellerheads.xyz".to_string());
+    let tycho_api_key = env::var("TYCHO_API_KEY").unwrap_or_else(|_| "sampletoken".to_string());
+
+    let all_tokens =
+        load_all_tokens(&tycho_url, false, Some(tycho_api_key.as_str()), chain, None, None).await;
+    let pairs: Vec<_> = cli
+        .pair
+        .iter()
+        .map(|pair| parse_pair(pair, &all_tokens))
+        .collect();
+
+    let metrics = Arc::new(RwLock::new(Metrics::default()));
+    let listener = TcpListener::bind(cli.listen).await?;
+    info!("Serving metrics on http://{}/metrics", cli.listen);
+    tokio::spawn(serve(listener, metrics.clone()));
+
+    let tvl_filter = ComponentFilter::with_tvl_range(cli.tvl_threshold, cli.tvl_threshold);
+    let mut stream = ProtocolStreamBuilder::new(&tycho_url, chain)
+        .exchange::<UniswapV2State>("uniswap_v2", tvl_filter.clone(), None)
+        .exchange::<UniswapV3State>("uniswap_v3", tvl_filter, None)
+        .auth_key(Some(tycho_api_key))
+        .skip_state_decode_failures(true)
+        .set_tokens(all_tokens)
+        .await
+        .build()
+        .await?;
+
+    let mut store = StateStore::new(None, 12);
+    let mut registry = PoolRegistry::new();
+    let mut blocks_processed = 0;
+    let mut quote_errors = 0;
+    while let Some(update) = stream.next().await {
+        let start = Instant::now();
+        let update = update?;
+        registry.apply(&update);
+        store.apply(update);
+        blocks_processed += 1;
+
+        let mut next = Metrics { blocks_processed, quote_errors, ..Default::default() };
+        for (base, quote) in &pairs {
+            quote_pair(&store, &registry, base, quote, &mut next);
+        }
+        next.block_number = store
+            .block()
+            .map_or(0, |block| block.number);
+        next.block_lag = store.staleness();
+        next.pools = registry.len();
+        next.processing_time = start.elapsed();
+        quote_errors = next.quote_errors;
+        *metrics.write().await = next;
+    }
+    Ok(())
+}
@@ -1,3 +1,8 @@
+// Shared with the other examples, not every event is used by each
+#[allow(dead_code)]
+#[path = "../common/events.rs"]
+mod events;
+
 use std::{
     collections::{HashMap, HashSet},
     default::Default,
@@ -47,7 +52,10 @@ use tycho_simulation::{
         stream::ProtocolStreamBuilder,
     },
     models::Token,
-    protocol::models::{BlockUpdate, ProtocolComponent},
+    protocol::{
+        models::{BlockUpdate, ProtocolComponent},
+        price::Price,
+    },
     tycho_client::feed::component_tracker::ComponentFilter,
     tycho_core::models::Chain,
     utils::load_all_tokens,
@@ -72,6 +80,13 @@ static ROUTER_ADDRESSES: LazyLock<HashMap<Chain, Bytes>> = LazyLock::new(|| {
     ])
 });
 
+type SwapProvider = FillProvider<
+    JoinFill<Identity, WalletFiller<EthereumWallet>>,
+    ReqwestProvider,
+    Http<Client>,
+    Ethereum,
+>;
+
 #[derive(Parser)]
 struct Cli {
     #[arg(short, long, default_value = "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2")]
@@ -87,13 +102,18 @@ struct Cli {
     swapper_pk: String,
     #[arg(short, long, default_value = "ethereum")]
     chain: String,
+    /// Print newline-delimited JSON quote events instead of interactively executing the best swap
+    #[arg(long)]
+    json: bool,
 }
 
 #[tokio::main]
 async fn main() {
+    // Logs go to stderr, keeping stdout for the results, see `--json`
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env())
         .with_target(false)
+        .with_writer(std::io::stderr)
         .init();
 
     let tycho_url =
@@ -105,11 +125,15 @@ async fn main() {
     let tvl_filter = ComponentFilter::with_tvl_range(cli.tvl_threshold, cli.tvl_threshold);
 
     let chain = Chain::from_str(&cli.chain).expect("Invalid chain");
-    println!("Loading tokens from Tycho... {}", tycho_url.as_str());
+    if !cli.json {
+        println!("Loading tokens from Tycho... {}", tycho_url.as_str());
+    }
     let all_tokens =
         load_all_tokens(tycho_url.as_str(), false, Some(tycho_api_key.as_str()), chain, None, None)
             .await;
-    println!("Tokens loaded: {}", all_tokens.len());
+    if !cli.json {
+        println!("Tokens loaded: {}", all_tokens.len());
+    }
 
     let sell_token_address =
         Bytes::from_str(&cli.sell_token).expect("Invalid address for sell token");
@@ -125,10 +149,12 @@ async fn main() {
     let amount_in =
         BigUint::from((cli.sell_amount * 10f64.powi(sell_token.decimals as i32)) as u128);
 
-    println!(
-        "Looking for pool with best price for {} {} -> {}",
-        cli.sell_amount, sell_token.symbol, buy_token.symbol
-    );
+    if !cli.json {
+        println!(
+            "Looking for pool with best price for {} {} -> {}",
+            cli.sell_amount, sell_token.symbol, buy_token.symbol
+        );
+    }
     let mut pairs: HashMap<String, ProtocolComponent> = HashMap::new();
     let mut amounts_out: HashMap<String, BigUint> = HashMap::new();
 
@@ -148,14 +174,9 @@ async fn main() {
         .await
         .expect("Failed building protocol stream");
 
-    // Initialize the encoder
-    let encoder = EVMEncoderBuilder::new()
-        .chain(chain)
-        .initialize_tycho_router_with_permit2(cli.swapper_pk.clone())
-        .expect("Failed to create encoder builder")
-        .build()
-        .expect("Failed to build encoder");
-
+    // The encoder and the provider are only needed to execute swaps, they are built on first use
+    let mut encoder: Option<EVMTychoEncoder> = None;
+    let mut provider: Option<SwapProvider> = None;
     let wallet = PrivateKeySigner::from_bytes(
         &B256::from_str(&cli.swapper_pk).expect("Failed to convert swapper pk to B256"),
     )
@@ -163,15 +184,6 @@ async fn main() {
     let tx_signer = EthereumWallet::from(wallet.clone());
     let named_chain =
         NamedChain::from_str(&cli.chain.replace("ethereum", "mainnet")).expect("Invalid chain");
-    let provider = ProviderBuilder::new()
-        .with_chain(named_chain)
-        .wallet(tx_signer.clone())
-        .on_http(
-            env::var("RPC_URL")
-                .expect("RPC_URL env var not set")
-                .parse()
-                .expect("Failed to parse RPC_URL"),
-        );
 
     while let Some(message_result) = protocol_stream.next().await {
         let message = match message_result {
@@ -182,6 +194,11 @@ async fn main() {
             }
         };
 
+        if cli.json {
+            emit_quotes(message, &mut pairs, &amount_in, &sell_token, &buy_token);
+            continue;
+        }
+
         let best_swap = get_best_swap(
             message,
             &mut pairs,
@@ -197,6 +214,14 @@ async fn main() {
                 .expect("Best pool not found")
                 .clone();
 
+            let encoder = encoder.get_or_insert_with(|| {
+                EVMEncoderBuilder::new()
+                    .chain(chain)
+                    .initialize_tycho_router_with_permit2(cli.swapper_pk.clone())
+                    .expect("Failed to create encoder builder")
+                    .build()
+                    .expect("Failed to build encoder")
+            });
             let tx = encode(
                 encoder.clone(),
                 component,
@@ -226,8 +251,14 @@ async fn main() {
             let choice = match selection {
                 0 => "simulate",
                 1 => "execute",
-                _ => "skip",
+                _ => {
+                    println!("Skipping this swap...");
+                    continue;
+                }
             };
+            let provider = provider
+                .get_or_insert_with(|| swap_provider(named_chain, tx_signer.clone()))
+                .clone();
 
             match choice {
                 "simulate" => {
@@ -324,19 +355,61 @@ async fn main() {
                         }
                     }
                 }
-                "skip" => {
-                    println!("Skipping this swap...");
-                    continue;
-                }
-                _ => {
-                    println!("Invalid input. Please choose 'simulate', 'execute' or 'skip'.");
-                    continue;
-                }
+                _ => unreachable!(),
             }
         }
     }
 }
 
+/// Connects to the node at `RPC_URL`, signing transactions with `signer`.
+fn swap_provider(chain: NamedChain, signer: EthereumWallet) -> SwapProvider {
+    ProviderBuilder::new()
+        .with_chain(chain)
+        .wallet(signer)
+        .on_http(
+            env::var("RPC_URL")
+                .expect("RPC_URL env var not set")
+                .parse()
+                .expect("Failed to parse RPC_URL"),
+        )
+}
+
+/// Prints the block and a quote for every updated pool of the pair as JSON events, see `events`.
+fn emit_quotes(
+    message: BlockUpdate,
+    pairs: &mut HashMap<String, ProtocolComponent>,
+    amount_in: &BigUint,
+    sell_token: &Token,
+    buy_token: &Token,
+) {
+    events::emit(events::block(&message));
+    for (id, comp) in message.new_pairs.iter() {
+        pairs
+            .entry(id.clone())
+            .or_insert_with(|| comp.clone());
+    }
+    for id in message.removed_pairs.keys() {
+        pairs.remove(id);
+    }
+    for (id, state) in message.states.iter() {
+        let Some(component) = pairs.get(id) else { continue };
+        let tokens = &component.tokens;
+        if HashSet::from([sell_token, buy_token]) != HashSet::from([&tokens[0], &tokens[1]]) {
+            continue;
+        }
+        let result = state.get_amount_out(amount_in.clone(), sell_token, buy_token);
+        events::emit(events::quote(
+            message.block_number,
+            id,
+            component,
+            sell_token,
+            buy_token,
+            amount_in,
+            &result,
+        ));
+    }
+}
+
 fn get_best_swap(
     message: BlockUpdate,
     pairs: &mut HashMap<String, ProtocolComponent>,
@@ -390,19 +463,23 @@ fn get_best_swap(
         println!("Pool address: {:?}", key);
         let formatted_in = format_token_amount(&amount_in, &sell_token);
         let formatted_out = format_token_amount(amount_out, &buy_token);
-        let (forward_price, reverse_price) =
-            format_price_ratios(&amount_in, amount_out, &sell_token, &buy_token);
+        let forward_price = Price::from_swap(&sell_token, &amount_in, &buy_token, amount_out);
+        let reverse_price = forward_price
+            .as_ref()
+            .and_then(Price::invert);
+        let format_price =
+            |price: Option<Price>| price.map_or("-".to_string(), |p| p.to_decimal_string(6));
 
         println!(
-            "Swap: {} {} -> {} {} \nPrice: {:.6} {} per {}, {:.6} {} per {}",
+            "Swap: {} {} -> {} {} \nPrice: {} {} per {}, {} {} per {}",
             formatted_in,
             sell_token.symbol,
             formatted_out,
             buy_token.symbol,
-            forward_price,
+            format_price(forward_price),
             buy_token.symbol,
             sell_token.symbol,
-            reverse_price,
+            format_price(reverse_price),
             sell_token.symbol,
             buy_token.symbol
         );
@@ -462,12 +539,7 @@ fn encode(
 }
 
 async fn get_tx_requests(
-    provider: FillProvider<
-        JoinFill<Identity, WalletFiller<EthereumWallet>>,
-        ReqwestProvider,
-        Http<Client>,
-        Ethereum,
-    >,
+    provider: SwapProvider,
     amount_in: U256,
     user_address: Address,
     sell_token_address: Address,
@@ -533,32 +605,8 @@ fn format_token_amount(amount: &BigUint, token: &Token) -> String {
     format!("{:.6}", decimal_amount)
 }
 
-// Calculate price ratios in both directions
-fn format_price_ratios(
-    amount_in: &BigUint,
-    amount_out: &BigUint,
-    token_in: &Token,
-    token_out: &Token,
-) -> (f64, f64) {
-    let decimal_in = amount_in.to_f64().unwrap_or(0.0) / 10f64.powi(token_in.decimals as i32);
-    let decimal_out = amount_out.to_f64().unwrap_or(0.0) / 10f64.powi(token_out.decimals as i32);
-
-    if decimal_in > 0.0 && decimal_out > 0.0 {
-        let forward = decimal_out / decimal_in;
-        let reverse = decimal_in / decimal_out;
-        (forward, reverse)
-    } else {
-        (0.0, 0.0)
-    }
-}
-
 async fn execute_swap_transaction(
-    provider: FillProvider<
-        JoinFill<Identity, WalletFiller<EthereumWallet>>,
-        ReqwestProvider,
-        Http<Client>,
-        Ethereum,
-    >,
+    provider: SwapProvider,
     amount_in: &BigUint,
     wallet_address: Address,
     sell_token_address: &Bytes,
@@ -0,0 +1,45 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use tracing_subscriber::EnvFilter;
+use tycho_simulation::evm::stress::{replay, StressFixture};
+
+#[derive(Parser)]
+struct Cli {
+    /// Fixture with the tokens, the snapshot and the deltas to replay
+    #[arg(short, long)]
+    fixture: PathBuf,
+    /// Number of deltas to replay, the fixture's deltas are repeated as needed
+    #[arg(short, long, default_value_t = 1000)]
+    blocks: usize,
+    /// Print the measurements of every block
+    #[arg(long)]
+    verbose: bool,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
+    let cli = Cli::parse();
+
+    let fixture = StressFixture::read(&cli.fixture)?;
+    println!(
+        "Loaded fixture with {} tokens and {} deltas",
+        fixture.tokens.len(),
+        fixture.deltas.len()
+    );
+    let report = replay(fixture, cli.blocks).await?;
+
+    if cli.verbose {
+        for sample in &report.blocks {
+            println!(
+                "block {}: {} pools updated in {:?}, rss {:?}",
+                sample.block_number, sample.updated_pools, sample.latency, sample.rss_bytes
+            );
+        }
+    }
+    println!("{}", report);
+    Ok(())
+}
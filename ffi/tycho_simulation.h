@@ -0,0 +1,40 @@
+/* C interface of tycho-simulation, see `src/evm/ffi.rs` for the documentation. */
+#ifndef TYCHO_SIMULATION_H
+#define TYCHO_SIMULATION_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define TYCHO_OK 0
+#define TYCHO_ERR_NULL -1
+#define TYCHO_ERR_INVALID_ARGUMENT -2
+#define TYCHO_ERR_UNKNOWN -3
+#define TYCHO_ERR_SIMULATION -4
+#define TYCHO_ERR_PANIC -5
+
+typedef struct TychoEngine TychoEngine;
+
+/* Creates an engine from a snapshot file. Returns NULL on failure. */
+TychoEngine *tycho_engine_new(const char *snapshot_path);
+
+/* Quotes selling `amount_in` of `token_in` for `token_out` on a pool. On success `*amount_out`
+ * must be released with `tycho_string_free`. */
+int32_t tycho_quote(const TychoEngine *engine, const char *pool_id, const char *token_in,
+                    const char *token_out, const char *amount_in, char **amount_out,
+                    uint64_t *gas);
+
+/* Message of the last error on the calling thread, owned by the library. */
+const char *tycho_last_error(void);
+
+void tycho_string_free(char *string);
+
+void tycho_engine_free(TychoEngine *engine);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* TYCHO_SIMULATION_H */
@@ -1,34 +1,71 @@
-use std::collections::{hash_map::Entry::Vacant, HashMap};
+use std::{
+    collections::{hash_map::Entry::Vacant, HashMap},
+    sync::Arc,
+};
 
-use alloy_primitives::{Address, U256};
-use revm::primitives::AccountInfo;
+use alloy_primitives::{keccak256, Address, Bytes, U256};
+use revm::primitives::{AccountInfo, Bytecode};
 use tracing::{debug, warn};
 
+use crate::evm::StorageMap;
+
 /// Represents an account in the account storage.
 ///
 /// # Fields
 ///
 /// * `info` - The account information of type `AccountInfo`.
-/// * `permanent_storage` - The permanent storage of the account.
+/// * `permanent_storage` - The permanent storage of the account. It is copied on write, so clones
+///   of an account, e.g. in database snapshots, share it until either of them is updated.
 /// * `temp_storage` - The temporary storage of the account.
 /// * `mocked` - A boolean flag indicating whether the account is mocked.
+/// * `missing_storage` - What reads of slots without a value return, if the account is mocked.
 #[derive(Clone, Default, Debug)]
 pub struct Account {
     pub info: AccountInfo,
-    pub permanent_storage: HashMap<U256, U256>,
-    pub temp_storage: HashMap<U256, U256>,
+    pub permanent_storage: Arc<StorageMap<U256, U256>>,
+    pub temp_storage: StorageMap<U256, U256>,
     pub mocked: bool,
+    pub missing_storage: MissingStoragePolicy,
+}
+
+/// What a database returns when a slot of a mocked account is read that it has no value for.
+///
+/// Databases only store non-zero slots, so by default a missing slot is zero. For partially mocked
+/// accounts, e.g. a token whose balances were only set for some holders, a missing slot may
+/// rather mean the data is incomplete, and reading zero leads to subtly wrong results.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MissingStoragePolicy {
+    /// The slot is zero
+    #[default]
+    ZeroDefault,
+    /// The slot is fetched from the node. Databases without a node fail the read instead.
+    FetchFromChain,
+    /// The read fails
+    Error,
+}
+
+/// The runtime code of an account, empty if it has none.
+pub(crate) fn original_code(info: &AccountInfo) -> Bytes {
+    info.code
+        .as_ref()
+        .map(Bytecode::original_bytes)
+        .unwrap_or_default()
 }
 
+/// Changes of an account. Fields that didn't change are `None`.
 #[derive(Default, Clone, PartialEq, Eq, Debug)]
 pub struct StateUpdate {
-    pub storage: Option<HashMap<U256, U256>>,
+    /// Changed slots
+    pub storage: Option<StorageMap<U256, U256>>,
     pub balance: Option<U256>,
+    /// Runtime code of a created or updated contract
+    pub code: Option<Bytes>,
+    pub nonce: Option<u64>,
 }
 #[derive(Clone, Default, Debug)]
 /// A simpler implementation of CacheDB that can't query a node. It just stores data.
 pub struct AccountStorage {
-    accounts: HashMap<Address, Account>,
+    accounts: StorageMap<Address, Account>,
 }
 
 impl AccountStorage {
@@ -60,9 +97,14 @@ impl AccountStorage {
         if let Vacant(e) = self.accounts.entry(address) {
             e.insert(Account {
                 info,
-                permanent_storage: permanent_storage.unwrap_or_default(),
-                temp_storage: HashMap::new(),
+                permanent_storage: Arc::new(
+                    permanent_storage
+                        .map(|storage| storage.into_iter().collect())
+                        .unwrap_or_default(),
+                ),
+                temp_storage: StorageMap::default(),
                 mocked,
+                missing_storage: MissingStoragePolicy::default(),
             });
             debug!(
                 "Inserted a {} account {:x?}",
@@ -83,9 +125,9 @@ impl AccountStorage {
     ///
     /// This function looks for the account information and storage associated with the provided
     /// `address`. If the `address` exists in the `accounts` collection, it updates the account
-    /// information based on the `balance` field in the `update` parameter. If the `address` exists
-    /// in the `storage` collection, it updates the storage information based on the `storage` field
-    /// in the `update` parameter.
+    /// information based on the `balance`, `nonce` and `code` fields in the `update` parameter. If
+    /// the `address` exists in the `storage` collection, it updates the storage information
+    /// based on the `storage` field in the `update` parameter.
     ///
     /// If the `address` is not found in either collection, a warning is logged and no changes are
     /// made.
@@ -94,12 +136,15 @@ impl AccountStorage {
             if let Some(new_balance) = update.balance {
                 account.info.balance = new_balance;
             }
+            if let Some(new_nonce) = update.nonce {
+                account.info.nonce = new_nonce;
+            }
+            if let Some(new_code) = &update.code {
+                account.info.code_hash = keccak256(new_code);
+                account.info.code = Some(Bytecode::new_raw(new_code.clone()));
+            }
             if let Some(new_storage) = &update.storage {
-                for (index, value) in new_storage {
-                    account
-                        .permanent_storage
-                        .insert(*index, *value);
-                }
+                Arc::make_mut(&mut account.permanent_storage).extend(new_storage);
             }
         } else {
             warn!(?address, "Tried to update account {:x?} that was not initialized", address);
@@ -225,6 +270,58 @@ impl AccountStorage {
             .get(address)
             .map(|acc| acc.mocked)
     }
+
+    /// Sets what reads of slots without a value return for a mocked account. Returns `false` if
+    /// the account is unknown.
+    pub fn set_missing_storage_policy(
+        &mut self,
+        address: &Address,
+        policy: MissingStoragePolicy,
+    ) -> bool {
+        match self.accounts.get_mut(address) {
+            Some(account) => {
+                account.missing_storage = policy;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the missing storage policy of an account, see `MissingStoragePolicy`.
+    pub fn missing_storage_policy(&self, address: &Address) -> Option<MissingStoragePolicy> {
+        self.accounts
+            .get(address)
+            .map(|acc| acc.missing_storage)
+    }
+
+    /// Retrieves the full account, including its storage, for a given address.
+    pub fn get_account(&self, address: &Address) -> Option<&Account> {
+        self.accounts.get(address)
+    }
+
+    /// Retrieves a mutable reference to the full account for a given address.
+    pub fn get_account_mut(&mut self, address: &Address) -> Option<&mut Account> {
+        self.accounts.get_mut(address)
+    }
+
+    /// Removes an account and all of its storage. Returns the removed account, if any.
+    pub fn remove_account(&mut self, address: &Address) -> Option<Account> {
+        self.accounts.remove(address)
+    }
+
+    /// Returns an iterator over all stored accounts.
+    pub fn iter(&self) -> impl Iterator<Item = (&Address, &Account)> {
+        self.accounts.iter()
+    }
+
+    /// Returns the number of stored accounts.
+    pub fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
 }
 
 #[cfg(test)]
@@ -280,24 +377,29 @@ mod tests {
             code: None,
             code_hash: KECCAK_EMPTY,
         };
-        let mut original_storage = HashMap::new();
+        let mut original_storage = StorageMap::default();
         let storage_index = U256::from_str("1").unwrap();
         original_storage.insert(storage_index, U256::from_str("5").unwrap());
         account_storage.accounts.insert(
             acc_address,
             Account {
                 info,
-                permanent_storage: original_storage,
-                temp_storage: HashMap::new(),
+                permanent_storage: Arc::new(original_storage),
+                temp_storage: StorageMap::default(),
                 mocked: false,
+                missing_storage: MissingStoragePolicy::default(),
             },
         );
         let updated_balance = U256::from(100);
         let updated_storage_value = U256::from_str("999").unwrap();
-        let mut updated_storage = HashMap::new();
+        let mut updated_storage = StorageMap::default();
         updated_storage.insert(storage_index, updated_storage_value);
-        let state_update =
-            StateUpdate { balance: Some(updated_balance), storage: Some(updated_storage) };
+        let state_update = StateUpdate {
+            balance: Some(updated_balance),
+            storage: Some(updated_storage),
+            code: None,
+            nonce: None,
+        };
 
         account_storage.update_account(&acc_address, &state_update);
 
@@ -482,6 +584,25 @@ mod tests {
         assert_eq!(account_storage.is_mocked_account(&unknown_address), None);
     }
 
+    #[test]
+    fn test_missing_storage_policy() {
+        let mut account_storage = AccountStorage::default();
+        let address = Address::repeat_byte(0x01);
+        account_storage.init_account(address, AccountInfo::default(), None, true);
+
+        assert_eq!(
+            account_storage.missing_storage_policy(&address),
+            Some(MissingStoragePolicy::ZeroDefault)
+        );
+        assert!(account_storage.set_missing_storage_policy(&address, MissingStoragePolicy::Error));
+        assert_eq!(
+            account_storage.missing_storage_policy(&address),
+            Some(MissingStoragePolicy::Error)
+        );
+        assert!(!account_storage
+            .set_missing_storage_policy(&Address::repeat_byte(0x02), MissingStoragePolicy::Error));
+    }
+
     #[test]
     fn test_clear_temp_storage() {
         let mut account_storage = AccountStorage::default();
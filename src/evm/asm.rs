@@ -0,0 +1,130 @@
+//! Minimal EVM assembler for the sources of the contracts the engine installs, see `assets/`.
+//!
+//! Each line holds one instruction, a mnemonic optionally followed by its immediate, or a label
+//! followed by `:`. Labels don't emit any code, the `JUMPDEST` is written out explicitly.
+//! Immediates of `PUSH1` to `PUSH32` are hex numbers with `0x` or labels with `@`, which are
+//! replaced by the label's offset. Everything after `;` is a comment. Only the opcodes the sources
+//! use are known.
+use std::collections::HashMap;
+
+use alloy_primitives::hex;
+
+fn opcode(mnemonic: &str) -> Option<u8> {
+    let opcode = match mnemonic {
+        "STOP" => 0x00,
+        "ADD" => 0x01,
+        "SUB" => 0x03,
+        "LT" => 0x10,
+        "SGT" => 0x13,
+        "EQ" => 0x14,
+        "ISZERO" => 0x15,
+        "AND" => 0x16,
+        "OR" => 0x17,
+        "KECCAK256" => 0x20,
+        "SHL" => 0x1b,
+        "SHR" => 0x1c,
+        "CALLER" => 0x33,
+        "CALLDATALOAD" => 0x35,
+        "CALLDATACOPY" => 0x37,
+        "RETURNDATASIZE" => 0x3d,
+        "RETURNDATACOPY" => 0x3e,
+        "POP" => 0x50,
+        "MLOAD" => 0x51,
+        "MSTORE" => 0x52,
+        "SLOAD" => 0x54,
+        "SSTORE" => 0x55,
+        "JUMP" => 0x56,
+        "JUMPI" => 0x57,
+        "GAS" => 0x5a,
+        "JUMPDEST" => 0x5b,
+        "CALL" => 0xf1,
+        "RETURN" => 0xf3,
+        "REVERT" => 0xfd,
+        _ => {
+            let numbered = |prefix: &str, range: std::ops::RangeInclusive<u8>| {
+                mnemonic
+                    .strip_prefix(prefix)?
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|n| range.contains(n))
+            };
+            return numbered("PUSH", 1..=32)
+                .map(|n| 0x5f + n)
+                .or_else(|| numbered("DUP", 1..=16).map(|n| 0x7f + n))
+                .or_else(|| numbered("SWAP", 1..=16).map(|n| 0x8f + n));
+        }
+    };
+    Some(opcode)
+}
+
+/// Assembles `source` into runtime code. Panics on invalid sources.
+pub fn assemble(source: &str) -> Vec<u8> {
+    let lines: Vec<Vec<&str>> = source
+        .lines()
+        .map(|line| {
+            line.split(';')
+                .next()
+                .unwrap_or_default()
+                .split_whitespace()
+                .collect()
+        })
+        .filter(|tokens: &Vec<&str>| !tokens.is_empty())
+        .collect();
+
+    // Every instruction has a fixed size, so labels can be resolved before emitting any code
+    let mut labels = HashMap::new();
+    let mut offset = 0;
+    for tokens in &lines {
+        match tokens[..] {
+            [label] if label.ends_with(':') => {
+                labels.insert(label.trim_end_matches(':'), offset);
+            }
+            [mnemonic, ..] => offset += 1 + push_size(mnemonic),
+            [] => unreachable!(),
+        }
+    }
+
+    let mut code = Vec::with_capacity(offset);
+    for tokens in &lines {
+        match tokens[..] {
+            [label] if label.ends_with(':') => {}
+            [mnemonic] => {
+                assert_eq!(push_size(mnemonic), 0, "Missing immediate of {mnemonic}");
+                code.push(opcode(mnemonic).unwrap_or_else(|| panic!("Unknown opcode {mnemonic}")));
+            }
+            [mnemonic, immediate] => {
+                let size = push_size(mnemonic);
+                assert!(size > 0, "Unexpected immediate of {mnemonic}");
+                let value = match immediate.strip_prefix('@') {
+                    Some(label) => {
+                        let offset = labels
+                            .get(label)
+                            .unwrap_or_else(|| panic!("Unknown label {label}"));
+                        format!("{offset:0>width$x}", width = 2 * size)
+                    }
+                    None => format!(
+                        "{:0>width$}",
+                        immediate
+                            .strip_prefix("0x")
+                            .unwrap_or_else(|| panic!("Invalid immediate {immediate}")),
+                        width = 2 * size
+                    ),
+                };
+                let value = hex::decode(&value).expect("Invalid immediate");
+                assert_eq!(value.len(), size, "Immediate {immediate} doesn't fit {mnemonic}");
+                code.push(opcode(mnemonic).expect("Invalid push"));
+                code.extend(value);
+            }
+            _ => panic!("Invalid instruction {}", tokens.join(" ")),
+        }
+    }
+    code
+}
+
+/// Size of the immediate of `mnemonic`, 0 if it isn't a push.
+fn push_size(mnemonic: &str) -> usize {
+    match opcode(mnemonic) {
+        Some(opcode @ 0x60..=0x7f) => (opcode - 0x5f) as usize,
+        _ => 0,
+    }
+}
@@ -0,0 +1,147 @@
+; Minimal Multicall3 `aggregate3((address,bool,bytes)[])`, installed by `Multicall` if a database
+; has no code at the Multicall3 address. Assembled with `crate::evm::asm`, see `AGGREGATE3_CODE`.
+;
+; The function selector is ignored. The result `(bool success, bytes returnData)[]` is built in
+; memory in place: a word with the offset of the array, its length, the offsets of the results and
+; then the results themselves. Each call's data is first copied to where its result goes, so no
+; other memory is needed.
+;
+; Stack comments list the stack bottom to top.
+
+    PUSH1 0x04
+    CALLDATALOAD        ; offset of calls
+    PUSH1 0x04
+    ADD                 ; calls: position of the length of the calls
+    DUP1
+    CALLDATALOAD        ; calls, n
+    SWAP1
+    PUSH1 0x20
+    ADD                 ; n, heads: position of the offsets of the calls
+    SWAP1               ; heads, n
+    PUSH1 0x20
+    PUSH1 0x00
+    MSTORE              ; mem[0x00] = offset of the results
+    DUP1
+    PUSH1 0x20
+    MSTORE              ; mem[0x20] = n
+    DUP1
+    PUSH1 0x05
+    SHL
+    PUSH1 0x40
+    ADD                 ; heads, n, ptr: end of the results, after their offsets
+    PUSH1 0x00          ; heads, n, ptr, i
+
+loop:
+    JUMPDEST
+    DUP3
+    DUP2
+    LT
+    ISZERO
+    PUSH2 @end
+    JUMPI               ; done once i == n
+
+    DUP1
+    PUSH1 0x05
+    SHL
+    DUP5
+    ADD
+    CALLDATALOAD
+    DUP5
+    ADD                 ; heads, n, ptr, i, call: position of the call's tuple
+    DUP1
+    PUSH1 0x40
+    ADD
+    CALLDATALOAD
+    DUP2
+    ADD                 ; heads, n, ptr, i, call, data: position of the length of the call data
+    DUP1
+    CALLDATALOAD        ; heads, n, ptr, i, call, data, len
+    DUP1
+    DUP3
+    PUSH1 0x20
+    ADD
+    DUP7
+    CALLDATACOPY        ; mem[ptr..ptr + len] = call data
+    PUSH1 0x00          ; retSize
+    PUSH1 0x00          ; retOffset
+    DUP3                ; argsSize: len
+    DUP8                ; argsOffset: ptr
+    PUSH1 0x00          ; value
+    DUP8
+    CALLDATALOAD        ; address: target
+    GAS
+    CALL                ; heads, n, ptr, i, call, data, len, success
+    DUP1
+    DUP5
+    PUSH1 0x20
+    ADD
+    CALLDATALOAD        ; allowFailure
+    OR
+    PUSH2 @succeeded
+    JUMPI
+    PUSH1 0x00
+    DUP1
+    REVERT              ; the call failed and doesn't allow failure
+
+succeeded:
+    JUMPDEST
+    PUSH1 0x40
+    DUP7
+    SUB                 ; offset of the result, relative to the offsets of the results
+    DUP6
+    PUSH1 0x05
+    SHL
+    PUSH1 0x40
+    ADD
+    MSTORE              ; mem[0x40 + 32 * i] = ptr - 0x40
+    DUP6
+    MSTORE              ; mem[ptr] = success
+    PUSH1 0x40
+    DUP6
+    PUSH1 0x20
+    ADD
+    MSTORE              ; mem[ptr + 0x20] = offset of returnData, relative to the result
+    POP
+    POP
+    POP                 ; heads, n, ptr, i
+    RETURNDATASIZE
+    DUP1
+    DUP4
+    PUSH1 0x40
+    ADD
+    MSTORE              ; mem[ptr + 0x40] = length of returnData
+    DUP1
+    PUSH1 0x00
+    DUP5
+    PUSH1 0x60
+    ADD
+    RETURNDATACOPY      ; mem[ptr + 0x60..] = returnData
+    PUSH1 0x00
+    DUP2
+    DUP5
+    ADD
+    PUSH1 0x60
+    ADD
+    MSTORE              ; zero the padding of returnData
+    PUSH1 0x1f
+    ADD
+    PUSH1 0x05
+    SHR
+    PUSH1 0x05
+    SHL
+    PUSH1 0x60
+    ADD
+    DUP3
+    ADD                 ; heads, n, ptr, i, end of the result
+    SWAP2
+    POP                 ; heads, n, ptr, i
+    PUSH1 0x01
+    ADD
+    PUSH2 @loop
+    JUMP
+
+end:
+    JUMPDEST
+    POP                 ; heads, n, ptr
+    PUSH1 0x00
+    RETURN              ; mem[0..ptr]
@@ -0,0 +1,156 @@
+; Callback executor installed by `CallbackExecutor`, see `EXECUTOR_CODE`. Assembled with
+; `crate::evm::asm`.
+;
+; Storage: slot 0 holds the pool while a swap runs, 0 otherwise. Slots 1 and 2 hold the pool's
+; tokens.
+;
+; Called while no swap runs, it handles `execute(address pool, address token0, address token1,
+; bytes data)`: it stores the pool and its tokens, calls the pool with `data` and returns or
+; reverts with the pool's return data. The function selector is ignored.
+;
+; Called while a swap runs, only the pool may call it. Every call is handled as a Uniswap V3 style
+; swap callback `(int256 amount0Delta, int256 amount1Delta, ...)`: the positive amounts are
+; transferred to the pool with `transfer(address,uint256)` of token0 and token1. Tokens returning
+; nothing or `true` are accepted. The callback's selector is ignored.
+;
+; Stack comments list the stack bottom to top.
+
+    PUSH1 0x00
+    SLOAD               ; pool
+    DUP1
+    PUSH2 @callback
+    JUMPI               ; a swap runs
+    POP
+
+    PUSH1 0x04
+    CALLDATALOAD        ; pool
+    DUP1
+    PUSH1 0x00
+    SSTORE              ; slot 0 = pool
+    PUSH1 0x24
+    CALLDATALOAD
+    PUSH1 0x01
+    SSTORE              ; slot 1 = token0
+    PUSH1 0x44
+    CALLDATALOAD
+    PUSH1 0x02
+    SSTORE              ; slot 2 = token1
+    PUSH1 0x64
+    CALLDATALOAD
+    PUSH1 0x04
+    ADD                 ; pool, data: position of the length of data
+    DUP1
+    CALLDATALOAD        ; pool, data, len
+    DUP1
+    SWAP2               ; pool, len, len, data
+    PUSH1 0x20
+    ADD
+    PUSH1 0x00
+    CALLDATACOPY        ; mem[0..len] = data
+    PUSH1 0x00          ; retSize
+    PUSH1 0x00          ; retOffset
+    DUP3                ; argsSize: len
+    PUSH1 0x00          ; argsOffset
+    PUSH1 0x00          ; value
+    DUP7                ; address: pool
+    GAS
+    CALL                ; pool, len, success
+    PUSH1 0x00
+    PUSH1 0x00
+    SSTORE              ; slot 0 = 0, the swap is over
+    RETURNDATASIZE
+    PUSH1 0x00
+    PUSH1 0x00
+    RETURNDATACOPY      ; mem[0..] = return data
+    PUSH2 @succeeded
+    JUMPI
+    RETURNDATASIZE
+    PUSH1 0x00
+    REVERT
+
+succeeded:
+    JUMPDEST
+    RETURNDATASIZE
+    PUSH1 0x00
+    RETURN
+
+callback:
+    JUMPDEST            ; pool
+    CALLER
+    EQ
+    ISZERO
+    PUSH2 @fail
+    JUMPI               ; only the pool may call back
+    PUSH1 0x00          ; i
+
+loop:
+    JUMPDEST
+    PUSH1 0x02
+    DUP2
+    LT
+    ISZERO
+    PUSH2 @done
+    JUMPI               ; done once i == 2
+    DUP1
+    PUSH1 0x05
+    SHL
+    PUSH1 0x04
+    ADD
+    CALLDATALOAD        ; i, amount: the amount of token i owed to the pool
+    PUSH1 0x00
+    DUP2
+    SGT
+    ISZERO
+    PUSH2 @next
+    JUMPI               ; nothing to pay unless amount > 0
+    PUSH4 0xa9059cbb
+    PUSH1 0xe0
+    SHL
+    PUSH1 0x00
+    MSTORE              ; selector of transfer(address,uint256)
+    CALLER
+    PUSH1 0x04
+    MSTORE              ; to: the pool
+    DUP1
+    PUSH1 0x24
+    MSTORE              ; amount
+    PUSH1 0x20          ; retSize
+    PUSH1 0x00          ; retOffset
+    PUSH1 0x44          ; argsSize
+    PUSH1 0x00          ; argsOffset
+    PUSH1 0x00          ; value
+    DUP7
+    PUSH1 0x01
+    ADD
+    SLOAD               ; address: token i
+    GAS
+    CALL                ; i, amount, success
+    RETURNDATASIZE
+    ISZERO
+    PUSH1 0x00
+    MLOAD
+    ISZERO
+    ISZERO
+    OR                  ; returned nothing or true
+    AND
+    ISZERO
+    PUSH2 @fail
+    JUMPI
+
+next:
+    JUMPDEST
+    POP                 ; i
+    PUSH1 0x01
+    ADD
+    PUSH2 @loop
+    JUMP
+
+done:
+    JUMPDEST
+    STOP
+
+fail:
+    JUMPDEST
+    PUSH1 0x00
+    DUP1
+    REVERT
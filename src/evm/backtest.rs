@@ -0,0 +1,376 @@
+//! Backtesting of strategies against recorded streams
+//!
+//! Replays historical blocks through the same decoder a live stream uses and hands every decoded
+//! block, together with the states of all pools at that block, to a strategy callback. Blocks are
+//! read from recorded stream fixtures: a snapshot followed by the deltas of the next blocks. A
+//! backtest over a long period is split into several fixtures, one per block range.
+//!
+//! Stateful strategies replay the ranges one after another with `Backtest::run`. Stateless
+//! strategies can replay the ranges in parallel with `Backtest::run_parallel`, each range on its
+//! own decoder. VM states are backed by a single global simulation engine, so backtests of VM
+//! protocols can't run in parallel.
+//!
+//! Each range is replayed with its own `MockClock`, moved to the timestamp of every replayed
+//! block, so time dependent quotes and strategies see the time of the block, see `clock::now`.
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::BufReader,
+    path::Path,
+    sync::Arc,
+};
+
+use serde::Deserialize;
+use thiserror::Error;
+use tycho_client::feed::{synchronizer::ComponentWithState, FeedMessage};
+use tycho_core::{dto::ResponseToken, Bytes};
+
+use crate::{
+    evm::decoder::{StreamDecodeError, TychoStreamDecoder},
+    models::Token,
+    protocol::{
+        clock::{self, MockClock},
+        errors::InvalidSnapshotError,
+        models::{BlockUpdate, ProtocolComponent, TryFromWithBlock},
+        state::ProtocolSim,
+    },
+};
+
+#[derive(Error, Debug)]
+pub enum BacktestError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid fixture: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Decoding failed: {0}")]
+    Decode(#[from] StreamDecodeError),
+    #[error("Exchange {0} can't be backtested in parallel: VM states share one simulation engine")]
+    SharedEngine(String),
+    #[error("Backtest task failed: {0}")]
+    Task(String),
+}
+
+/// A recorded block range: the tokens, a snapshot of the pools and the deltas of the following
+/// blocks.
+#[derive(Deserialize)]
+pub struct BacktestFixture {
+    pub tokens: Vec<ResponseToken>,
+    pub snapshot: FeedMessage,
+    #[serde(default)]
+    pub deltas: Vec<FeedMessage>,
+}
+
+impl BacktestFixture {
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, BacktestError> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    fn tokens(&self) -> HashMap<Bytes, Token> {
+        self.tokens
+            .iter()
+            .filter_map(|token| {
+                Token::try_from(token.clone())
+                    .ok()
+                    .map(|token| (token.address.clone(), token))
+            })
+            .collect()
+    }
+}
+
+/// A replayed block, as seen by a strategy.
+pub struct BacktestBlock<'a> {
+    /// The decoded update of the block
+    pub update: &'a BlockUpdate,
+    /// States of all pools after the block, by pool id. Sorted, so strategies iterating over the
+    /// pools see them in the same order on every run.
+    pub states: &'a BTreeMap<String, Box<dyn ProtocolSim>>,
+    /// Components of all pools, by pool id
+    pub components: &'a BTreeMap<String, ProtocolComponent>,
+}
+
+type Registration = Arc<dyn Fn(&mut TychoStreamDecoder) + Send + Sync>;
+
+/// Replays recorded block ranges through the decoder and a strategy, see module docs.
+#[derive(Clone, Default)]
+pub struct Backtest {
+    exchanges: Vec<String>,
+    registrations: Vec<Registration>,
+    skip_state_decode_failures: bool,
+}
+
+impl Backtest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the state decoding of the exchange `name`, and optionally a filter excluding
+    /// components the state can't simulate, like `ProtocolStreamBuilder::exchange`.
+    pub fn exchange<T>(
+        mut self,
+        name: &str,
+        filter_fn: Option<fn(&ComponentWithState) -> bool>,
+    ) -> Self
+    where
+        T: ProtocolSim
+            + TryFromWithBlock<ComponentWithState, Error = InvalidSnapshotError>
+            + Send
+            + 'static,
+    {
+        let exchange = name.to_string();
+        self.registrations
+            .push(Arc::new(move |decoder: &mut TychoStreamDecoder| {
+                decoder.register_decoder::<T>(&exchange);
+                if let Some(predicate) = filter_fn {
+                    decoder.register_filter(&exchange, predicate);
+                }
+            }));
+        self.exchanges.push(name.to_string());
+        self
+    }
+
+    /// Skips pools whose state fails to decode instead of aborting the backtest.
+    pub fn skip_state_decode_failures(mut self, skip: bool) -> Self {
+        self.skip_state_decode_failures = skip;
+        self
+    }
+
+    /// Replays `ranges` in order, calling `strategy` for every block. Returns the strategy's
+    /// results in block order.
+    pub async fn run<O>(
+        &self,
+        ranges: Vec<BacktestFixture>,
+        mut strategy: impl FnMut(&BacktestBlock) -> O,
+    ) -> Result<Vec<O>, BacktestError> {
+        let mut results = Vec::new();
+        for range in ranges {
+            results.extend(
+                self.replay(range, &mut strategy)
+                    .await?,
+            );
+        }
+        Ok(results)
+    }
+
+    /// Replays `ranges` in parallel, each on its own decoder, calling the stateless `strategy`
+    /// for every block. Returns the strategy's results in block order.
+    ///
+    /// # Errors
+    ///
+    /// Returns `BacktestError::SharedEngine` if a VM exchange is registered, see module docs.
+    pub async fn run_parallel<O, F>(
+        &self,
+        ranges: Vec<BacktestFixture>,
+        strategy: F,
+    ) -> Result<Vec<O>, BacktestError>
+    where
+        O: Send + 'static,
+        F: Fn(&BacktestBlock) -> O + Send + Sync + 'static,
+    {
+        if let Some(exchange) = self
+            .exchanges
+            .iter()
+            .find(|exchange| exchange.starts_with("vm:"))
+        {
+            return Err(BacktestError::SharedEngine(exchange.clone()));
+        }
+
+        let strategy = Arc::new(strategy);
+        let tasks: Vec<_> = ranges
+            .into_iter()
+            .map(|range| {
+                let backtest = self.clone();
+                let strategy = strategy.clone();
+                tokio::spawn(async move {
+                    backtest
+                        .replay(range, |block| strategy(block))
+                        .await
+                })
+            })
+            .collect();
+
+        let mut results = Vec::new();
+        for task in tasks {
+            let range_results = task
+                .await
+                .map_err(|e| BacktestError::Task(e.to_string()))??;
+            results.extend(range_results);
+        }
+        Ok(results)
+    }
+
+    /// Replays a single range on a new decoder, with its own clock, see module docs.
+    async fn replay<O>(
+        &self,
+        range: BacktestFixture,
+        strategy: impl FnMut(&BacktestBlock) -> O,
+    ) -> Result<Vec<O>, BacktestError> {
+        // The clock starts at the first timestamp of the range, blocks without one keep the time
+        // of the previous block
+        let start = std::iter::once(&range.snapshot)
+            .chain(&range.deltas)
+            .find_map(block_timestamp)
+            .unwrap_or_else(clock::now);
+        let clock = MockClock::new(start);
+        clock::scope_clock(Arc::new(clock.clone()), self.replay_with_clock(range, strategy, clock))
+            .await
+    }
+
+    async fn replay_with_clock<O>(
+        &self,
+        range: BacktestFixture,
+        mut strategy: impl FnMut(&BacktestBlock) -> O,
+        clock: MockClock,
+    ) -> Result<Vec<O>, BacktestError> {
+        let mut decoder = TychoStreamDecoder::new();
+        decoder.skip_state_decode_failures(self.skip_state_decode_failures);
+        for registration in &self.registrations {
+            registration(&mut decoder);
+        }
+        decoder.set_tokens(range.tokens()).await;
+
+        let mut states: BTreeMap<String, Box<dyn ProtocolSim>> = BTreeMap::new();
+        let mut components = BTreeMap::new();
+        let mut results = Vec::with_capacity(range.deltas.len() + 1);
+        for msg in std::iter::once(range.snapshot).chain(range.deltas) {
+            if let Some(timestamp) = block_timestamp(&msg) {
+                clock.set(timestamp);
+            }
+            let update = decoder.decode(msg).await?;
+            for id in update.removed_pairs.keys() {
+                states.remove(id);
+                components.remove(id);
+            }
+            components.extend(
+                update
+                    .new_pairs
+                    .iter()
+                    .map(|(id, component)| (id.clone(), component.clone())),
+            );
+            states.extend(
+                update
+                    .states
+                    .iter()
+                    .map(|(id, state)| (id.clone(), state.clone())),
+            );
+            results.push(strategy(&BacktestBlock {
+                update: &update,
+                states: &states,
+                components: &components,
+            }));
+        }
+        Ok(results)
+    }
+}
+
+/// Timestamp of the block of a message, if it carries one. Like in the decoder, only the deltas
+/// know it.
+fn block_timestamp(msg: &FeedMessage) -> Option<u64> {
+    msg.state_msgs
+        .values()
+        .find_map(|protocol_msg| protocol_msg.deltas.as_ref())
+        .map(|deltas| deltas.block.ts.and_utc().timestamp() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::evm::{
+        engine_db::tycho_db::PreCachedDB,
+        protocol::{uniswap_v2::state::UniswapV2State, vm::state::EVMPoolState},
+    };
+
+    fn load_test_msg(name: &str) -> FeedMessage {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join(format!("tests/assets/decoder/{}.json", name));
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    fn fixture() -> BacktestFixture {
+        let tokens = [
+            ("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2", "WETH"),
+            ("0xdac17f958d2ee523a2206206994597c13d831ec7", "USDT"),
+        ]
+        .iter()
+        .map(|(address, symbol)| {
+            serde_json::from_value(serde_json::json!({
+                "chain": "ethereum",
+                "address": address,
+                "symbol": symbol,
+                "decimals": 18,
+                "tax": 0,
+                "gas": [100000],
+                "quality": 100
+            }))
+            .unwrap()
+        })
+        .collect();
+        BacktestFixture {
+            tokens,
+            snapshot: load_test_msg("uniswap_v2_snapshot"),
+            deltas: vec![load_test_msg("uniswap_v2_delta")],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let backtest = Backtest::new().exchange::<UniswapV2State>("uniswap_v2", None);
+        let mut blocks = 0;
+
+        let results = backtest
+            .run(vec![fixture(), fixture()], |block| {
+                blocks += 1;
+                (block.update.states.len(), block.states.len(), block.components.len())
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(blocks, 4);
+        assert_eq!(results, vec![(1, 1, 1); 4]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_run_parallel() {
+        let backtest = Backtest::new().exchange::<UniswapV2State>("uniswap_v2", None);
+
+        let sequential = backtest
+            .run(vec![fixture(), fixture()], |block| block.update.block_number)
+            .await
+            .unwrap();
+        let parallel = backtest
+            .run_parallel(vec![fixture(), fixture()], |block| block.update.block_number)
+            .await
+            .unwrap();
+
+        assert_eq!(parallel, sequential);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_run_parallel_block_time() {
+        let backtest = Backtest::new().exchange::<UniswapV2State>("uniswap_v2", None);
+
+        let times = backtest
+            .run_parallel(vec![fixture(), fixture()], |_| clock::now())
+            .await
+            .unwrap();
+
+        // Every range sees the time of its own blocks
+        assert_eq!(times, vec![1732771799, 1732771835, 1732771799, 1732771835]);
+    }
+
+    #[tokio::test]
+    async fn test_run_parallel_rejects_vm_exchanges() {
+        let backtest = Backtest::new()
+            .exchange::<UniswapV2State>("uniswap_v2", None)
+            .exchange::<EVMPoolState<PreCachedDB>>("vm:balancer_v2", None);
+
+        let result = backtest
+            .run_parallel(vec![fixture()], |block| block.update.block_number)
+            .await;
+
+        assert!(
+            matches!(result, Err(BacktestError::SharedEngine(exchange)) if exchange == "vm:balancer_v2")
+        );
+    }
+}
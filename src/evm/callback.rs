@@ -0,0 +1,371 @@
+//! Swaps through pool callbacks
+//!
+//! Some pools don't pull the input token from the caller, but call back into it mid-swap and
+//! expect to be paid there, e.g. `uniswapV3SwapCallback` of Uniswap V3 and its forks. Calling
+//! such a pool's `swap` from an externally owned account reverts, so quoting it on-chain needs a
+//! contract implementing the callback. `CallbackExecutor` installs one into the engine's database
+//! and swaps through it, so no Solidity has to be written for it.
+//!
+//! The installed contract forwards a call to the pool and handles every call it receives from the
+//! pool during the swap as a callback following the convention of Uniswap V3: the first two
+//! arguments are the amounts of `token0` and `token1` owed to the pool, and the positive ones are
+//! transferred to it. The callback's selector is ignored, so forks naming it differently, like
+//! `pancakeV3SwapCallback` or `algebraSwapCallback`, work too. The executor pays from its own token
+//! balances, which are set with storage overrides on every call.
+//!
+//! `CallbackPoolState` (see `evm::protocol::callback`) quotes pools of callback based protocols
+//! through the executor.
+use std::{collections::HashMap, fmt::Debug, str::FromStr};
+
+use alloy_primitives::{hex, Address, Bytes, I256, U256};
+use alloy_sol_types::SolValue;
+use lazy_static::lazy_static;
+use revm::DatabaseRef;
+
+use crate::{
+    evm::{
+        account_storage::StateUpdate,
+        engine_db::{
+            account_builder::AccountBuilder, engine_db_interface::EngineDatabaseInterface,
+            simulation_db::BlockHeader,
+        },
+        protocol::{
+            utils::uniswap::tick_math::{MAX_SQRT_RATIO, MIN_SQRT_RATIO},
+            vm::{
+                constants::MAX_BALANCE,
+                tycho_simulation_contract::{
+                    encode_call, TychoSimulationContract, TychoSimulationResponse,
+                },
+                utils::get_storage_slot_index_at_key,
+            },
+        },
+        simulation::SimulationEngine,
+        ContractCompiler, SlotId, StorageMap,
+    },
+    protocol::errors::SimulationError,
+};
+
+lazy_static! {
+    /// Address the callback executor is installed at
+    pub static ref CALLBACK_EXECUTOR: Address =
+        Address::from_str(&format!("{:0>40}", hex::encode("callback_executor")))
+            .expect("Invalid string for callback executor address");
+}
+
+/// Runtime code of the executor. Called from outside a swap, it stores the pool and its tokens,
+/// calls the pool and returns or reverts with the pool's return data. Called by the pool during
+/// the swap, it transfers the positive amounts of the first two arguments to the pool.
+///
+/// Assembled from `assets/callback_executor.asm`, a test checks that it matches.
+const EXECUTOR_CODE: &str =
+    "6000548061005057506004358060005560243560015560443560025560643560040180\
+    358091602001600037600060008260006000865af160006000553d600060003e61004b573d6000fd5b3d6000f35b33\
+    14156100b25760005b60028110156100b0578060051b6004013560008113156100a75763a9059cbb60e01b60005233\
+    600452806024526020600060446000600086600101545af13d1560005115151716156100b2575b5060010161005a56\
+    5b005b600080fd";
+
+/// A pool paying out through a Uniswap V3 style swap callback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallbackPool {
+    pub address: Address,
+    pub token0: Address,
+    pub token1: Address,
+}
+
+/// Outcome of a swap through the executor. Amounts are the pool's balance changes, as returned by
+/// Uniswap V3's `swap`: positive amounts were paid to the pool, negative ones by it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallbackSwap {
+    pub amount0: I256,
+    pub amount1: I256,
+    pub gas_used: u64,
+    /// State changes of the swap, e.g. the pool's storage after it
+    pub state_updates: StorageMap<Address, StateUpdate>,
+}
+
+impl CallbackSwap {
+    /// The amount paid to the pool.
+    pub fn amount_in(&self) -> U256 {
+        self.amount0
+            .max(self.amount1)
+            .unsigned_abs()
+    }
+
+    /// The amount paid by the pool.
+    pub fn amount_out(&self) -> U256 {
+        self.amount0
+            .min(self.amount1)
+            .unsigned_abs()
+    }
+}
+
+/// Swaps through pools that require a callback, see module docs.
+#[derive(Debug, Clone)]
+pub struct CallbackExecutor<D: EngineDatabaseInterface + Clone + Debug>
+where
+    <D as DatabaseRef>::Error: Debug,
+    <D as EngineDatabaseInterface>::Error: Debug,
+{
+    engine: SimulationEngine<D>,
+    /// Balance slots of tokens whose balance mapping isn't at slot 0 of a Solidity contract
+    balance_slots: HashMap<Address, (SlotId, ContractCompiler)>,
+}
+
+impl<D: EngineDatabaseInterface + Clone + Debug> CallbackExecutor<D>
+where
+    <D as DatabaseRef>::Error: Debug,
+    <D as EngineDatabaseInterface>::Error: Debug,
+{
+    /// Installs the executor into the engine's database.
+    pub fn new(engine: SimulationEngine<D>) -> Self {
+        AccountBuilder::new(*CALLBACK_EXECUTOR)
+            .code(hex::decode(EXECUTOR_CODE).expect("Invalid executor code"))
+            .empty_storage()
+            .mocked()
+            .init(&engine.state);
+        Self { engine, balance_slots: HashMap::new() }
+    }
+
+    /// Sets the slot of the balance mapping of `token`, used to fund the executor. Defaults to
+    /// slot 0 of a Solidity contract.
+    pub fn with_balance_slot(
+        mut self,
+        token: Address,
+        slot: SlotId,
+        compiler: ContractCompiler,
+    ) -> Self {
+        self.balance_slots
+            .insert(token, (slot, compiler));
+        self
+    }
+
+    /// Sells exactly `amount_in` of `token0` if `zero_for_one`, else of `token1`, by calling
+    /// Uniswap V3's `swap(address,bool,int256,uint160,bytes)` on the pool.
+    pub fn swap_exact_in(
+        &self,
+        pool: &CallbackPool,
+        zero_for_one: bool,
+        amount_in: U256,
+        block: &BlockHeader,
+    ) -> Result<CallbackSwap, SimulationError> {
+        self.uniswap_v3_swap(pool, zero_for_one, to_i256(amount_in)?, block, HashMap::new())
+    }
+
+    /// Like `swap_exact_in`, with storage `overrides` on top of the database, e.g. the pool's
+    /// storage after previous swaps.
+    pub fn swap_exact_in_with_overrides(
+        &self,
+        pool: &CallbackPool,
+        zero_for_one: bool,
+        amount_in: U256,
+        block: &BlockHeader,
+        overrides: HashMap<Address, HashMap<U256, U256>>,
+    ) -> Result<CallbackSwap, SimulationError> {
+        self.uniswap_v3_swap(pool, zero_for_one, to_i256(amount_in)?, block, overrides)
+    }
+
+    /// Buys exactly `amount_out` of `token1` if `zero_for_one`, else of `token0`, by calling
+    /// Uniswap V3's `swap(address,bool,int256,uint160,bytes)` on the pool.
+    pub fn swap_exact_out(
+        &self,
+        pool: &CallbackPool,
+        zero_for_one: bool,
+        amount_out: U256,
+        block: &BlockHeader,
+    ) -> Result<CallbackSwap, SimulationError> {
+        self.uniswap_v3_swap(pool, zero_for_one, -to_i256(amount_out)?, block, HashMap::new())
+    }
+
+    /// Calls the pool with `data` through the executor, for pools whose `swap` differs from
+    /// Uniswap V3's but that pay out through the same callback.
+    pub fn call(
+        &self,
+        pool: &CallbackPool,
+        data: Vec<u8>,
+        block: &BlockHeader,
+    ) -> Result<TychoSimulationResponse, SimulationError> {
+        self.call_with_overrides(pool, data, block, HashMap::new())
+    }
+
+    /// Like `call`, with storage `overrides` on top of the database. The balances of the
+    /// executor are overridden last.
+    pub fn call_with_overrides(
+        &self,
+        pool: &CallbackPool,
+        data: Vec<u8>,
+        block: &BlockHeader,
+        mut overrides: HashMap<Address, HashMap<U256, U256>>,
+    ) -> Result<TychoSimulationResponse, SimulationError> {
+        for token in [pool.token0, pool.token1] {
+            let (slot, compiler) = self
+                .balance_slots
+                .get(&token)
+                .copied()
+                .unwrap_or((SlotId::ZERO, ContractCompiler::Solidity));
+            overrides
+                .entry(token)
+                .or_default()
+                .insert(
+                    get_storage_slot_index_at_key(*CALLBACK_EXECUTOR, slot, compiler),
+                    *MAX_BALANCE,
+                );
+        }
+        TychoSimulationContract::new(*CALLBACK_EXECUTOR, self.engine.clone())?.call(
+            "execute(address,address,address,bytes)",
+            (pool.address, pool.token0, pool.token1, Bytes::from(data)),
+            block,
+            Some(overrides),
+            None,
+            U256::ZERO,
+        )
+    }
+
+    fn uniswap_v3_swap(
+        &self,
+        pool: &CallbackPool,
+        zero_for_one: bool,
+        amount_specified: I256,
+        block: &BlockHeader,
+        overrides: HashMap<Address, HashMap<U256, U256>>,
+    ) -> Result<CallbackSwap, SimulationError> {
+        let price_limit = if zero_for_one {
+            MIN_SQRT_RATIO + U256::from(1)
+        } else {
+            MAX_SQRT_RATIO - U256::from(1)
+        };
+        let data = encode_call(
+            "swap(address,bool,int256,uint160,bytes)",
+            (*CALLBACK_EXECUTOR, zero_for_one, amount_specified, price_limit, Bytes::new()),
+        );
+        let res = self.call_with_overrides(pool, data, block, overrides)?;
+        let (amount0, amount1) =
+            <(I256, I256)>::abi_decode(&res.return_value, true).map_err(|e| {
+                SimulationError::FatalError(format!("Failed to decode swap return value: {e:?}"))
+            })?;
+        Ok(CallbackSwap {
+            amount0,
+            amount1,
+            gas_used: res.simulation_result.gas_used,
+            state_updates: res.simulation_result.state_updates,
+        })
+    }
+}
+
+fn to_i256(amount: U256) -> Result<I256, SimulationError> {
+    I256::try_from(amount)
+        .map_err(|_| SimulationError::InvalidInput(format!("Amount {amount} too large"), None))
+}
+
+/// A pool and an engine to swap through it with the executor, for tests.
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use revm::primitives::AccountInfo;
+
+    use super::*;
+    use crate::evm::{
+        engine_db::{create_engine, tycho_db::PreCachedDB},
+        protocol::vm::constants::{ERC20_BYTECODE, EXTERNAL_ACCOUNT},
+    };
+
+    // A pool selling token1 for token0 at a price of 2, whatever the direction: it reads the
+    // amount specified to `swap`, calls `uniswapV3SwapCallback` on the caller with the deltas and
+    // returns them, without paying out.
+    const POOL: &str =
+        "60443560008113610016578060000360011c61001f565b8060011b600003905b63fa461e3360e0\
+        1b60005280600452816024526060604452600060645260006000608460006000335af11561005a576000526020\
+        5260406000f35b600080fd";
+
+    pub(crate) fn pool() -> CallbackPool {
+        CallbackPool {
+            address: Address::repeat_byte(1),
+            token0: Address::repeat_byte(0x10),
+            token1: Address::repeat_byte(0x11),
+        }
+    }
+
+    /// An engine holding the pool and its tokens.
+    pub(crate) fn engine() -> SimulationEngine<PreCachedDB> {
+        let engine = create_engine(PreCachedDB::new().unwrap(), false).unwrap();
+        engine
+            .state
+            .init_account(*EXTERNAL_ACCOUNT, AccountInfo::default(), None, false);
+        let pool = pool();
+        AccountBuilder::new(pool.address)
+            .code(hex::decode(POOL).unwrap())
+            .empty_storage()
+            .mocked()
+            .init(&engine.state);
+        for token in [pool.token0, pool.token1] {
+            AccountBuilder::new(token)
+                .code(ERC20_BYTECODE)
+                .empty_storage()
+                .mocked()
+                .init(&engine.state);
+        }
+        engine
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        fixtures::{engine, pool},
+        *,
+    };
+    use crate::evm::{asm::assemble, engine_db::tycho_db::PreCachedDB};
+
+    fn executor() -> CallbackExecutor<PreCachedDB> {
+        CallbackExecutor::new(engine())
+    }
+
+    #[test]
+    fn test_executor_code_matches_source() {
+        assert_eq!(
+            hex::encode(assemble(include_str!("assets/callback_executor.asm"))),
+            EXECUTOR_CODE
+        );
+    }
+
+    #[test]
+    fn test_swap() {
+        let executor = executor();
+        let block = BlockHeader::default();
+
+        let sell = executor
+            .swap_exact_in(&pool(), true, U256::from(100), &block)
+            .unwrap();
+        let buy = executor
+            .swap_exact_out(&pool(), true, U256::from(200), &block)
+            .unwrap();
+
+        assert_eq!(
+            (sell.amount0, sell.amount1),
+            (I256::try_from(100).unwrap(), I256::try_from(-200).unwrap())
+        );
+        assert_eq!((sell.amount_in(), sell.amount_out()), (U256::from(100), U256::from(200)));
+        assert!(sell.gas_used > 0);
+        assert_eq!((buy.amount_in(), buy.amount_out()), (U256::from(100), U256::from(200)));
+    }
+
+    #[test]
+    fn test_callback_pays_pool() {
+        let executor = executor();
+        let pool = pool();
+        let data = encode_call(
+            "swap(address,bool,int256,uint160,bytes)",
+            (*CALLBACK_EXECUTOR, true, I256::try_from(100).unwrap(), U256::ZERO, Bytes::new()),
+        );
+
+        let res = executor
+            .call(&pool, data, &BlockHeader::default())
+            .unwrap();
+
+        let balance_slot =
+            get_storage_slot_index_at_key(pool.address, SlotId::ZERO, ContractCompiler::Solidity);
+        let storage = res.simulation_result.state_updates[&pool.token0]
+            .storage
+            .clone()
+            .unwrap();
+        assert_eq!(storage[&balance_slot], U256::from(100));
+    }
+}
@@ -1,12 +1,16 @@
 use std::{
     collections::{hash_map::Entry, HashMap, HashSet},
     future::Future,
+    num::NonZeroUsize,
     pin::Pin,
     str::FromStr,
-    sync::Arc,
+    sync::{Arc, Mutex},
+    thread,
 };
 
-use alloy_primitives::Address;
+use alloy_primitives::{keccak256, Address, B256};
+use futures::{stream, StreamExt};
+use revm::{primitives::KECCAK_EMPTY, DatabaseRef};
 use thiserror::Error;
 use tokio::sync::{RwLock, RwLockReadGuard};
 use tracing::{debug, error, info, warn};
@@ -15,13 +19,18 @@ use tycho_core::{dto::ProtocolStateDelta, Bytes};
 
 use crate::{
     evm::{
-        engine_db::{update_engine, SHARED_TYCHO_DB},
-        tycho_models::{AccountUpdate, ResponseAccount},
+        engine_db::{
+            simulation_db::BlockHeader, tycho_db::PreCachedDB, update_engine, EngineUpdateReport,
+            SHARED_TYCHO_DB,
+        },
+        protocol::vm::state::EVMPoolState,
+        tycho_models::{AccountUpdate, Chain, ResponseAccount},
     },
-    models::{Balances, Token},
+    models::{Balances, Token, TokenSanity},
     protocol::{
+        clock,
         errors::InvalidSnapshotError,
-        models::{BlockUpdate, ProtocolComponent, TryFromWithBlock},
+        models::{BlockInfo, BlockUpdate, CodeUpgrade, ProtocolComponent, TryFromWithBlock},
         state::ProtocolSim,
     },
 };
@@ -38,6 +47,83 @@ struct DecoderState {
     states: HashMap<String, Box<dyn ProtocolSim>>,
     // maps contract address to the pools they affect
     contracts_map: HashMap<Bytes, HashSet<String>>,
+    // maps contract address to all pools executing its code
+    code_dependents: HashMap<Bytes, HashSet<String>>,
+    // maps account to the VM pools depending on it, as of the pools' last update
+    vm_dependents: HashMap<Address, HashSet<String>>,
+    // maps protocol to its pools with a dynamic fee, refreshed on every block
+    dynamic_fee_pools: HashMap<String, HashSet<String>>,
+}
+
+/// Decoding statistics of a protocol, see `Health::protocols`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProtocolStats {
+    /// Number of pools with a decoded state
+    pub pools: usize,
+    /// Number of pool states emitted, decoded from snapshots or updated by deltas
+    pub updates: u64,
+    /// Number of snapshots that failed to decode
+    pub decode_failures: u64,
+    /// Number of pools left out of the stream because their last snapshot failed to decode
+    pub quarantined: usize,
+}
+
+#[derive(Debug, Default)]
+struct ProtocolStatsState {
+    pools: HashSet<String>,
+    updates: u64,
+    decode_failures: u64,
+    quarantined: HashSet<String>,
+}
+
+/// Decoding statistics by protocol.
+#[derive(Debug, Default)]
+struct DecoderStats {
+    protocols: HashMap<String, ProtocolStatsState>,
+}
+
+impl DecoderStats {
+    fn protocol(&mut self, protocol: &str) -> &mut ProtocolStatsState {
+        self.protocols
+            .entry(protocol.to_string())
+            .or_default()
+    }
+
+    fn record_decoded(&mut self, protocol: &str, id: &str) {
+        let stats = self.protocol(protocol);
+        stats.pools.insert(id.to_string());
+        stats.quarantined.remove(id);
+        stats.updates += 1;
+    }
+
+    fn record_failed(&mut self, protocol: &str, id: &str) {
+        let stats = self.protocol(protocol);
+        stats.decode_failures += 1;
+        stats.quarantined.insert(id.to_string());
+    }
+
+    fn record_removed(&mut self, protocol: &str, id: &str) {
+        let stats = self.protocol(protocol);
+        stats.pools.remove(id);
+        stats.quarantined.remove(id);
+    }
+
+    fn snapshot(&self) -> HashMap<String, ProtocolStats> {
+        self.protocols
+            .iter()
+            .map(|(protocol, stats)| {
+                (
+                    protocol.clone(),
+                    ProtocolStats {
+                        pools: stats.pools.len(),
+                        updates: stats.updates,
+                        decode_failures: stats.decode_failures,
+                        quarantined: stats.quarantined.len(),
+                    },
+                )
+            })
+            .collect()
+    }
 }
 
 type DecodeFut =
@@ -64,8 +150,11 @@ pub(super) struct TychoStreamDecoder {
     state: Arc<RwLock<DecoderState>>,
     skip_state_decode_failures: bool,
     min_token_quality: u32,
+    decode_concurrency: usize,
+    chain: Chain,
     registry: HashMap<String, Box<RegistryFn>>,
     inclusion_filters: HashMap<String, FilterFn>,
+    stats: Mutex<DecoderStats>,
 }
 
 impl TychoStreamDecoder {
@@ -74,24 +163,50 @@ impl TychoStreamDecoder {
             state: Arc::new(RwLock::new(DecoderState::default())),
             skip_state_decode_failures: false,
             min_token_quality: 51,
+            decode_concurrency: thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            chain: Chain::default(),
             registry: HashMap::new(),
             inclusion_filters: HashMap::new(),
+            stats: Mutex::new(DecoderStats::default()),
         }
     }
 
     /// Sets the currently known tokens which will be considered during decoding.
     ///
     /// Protocol components containing tokens which are not included in this initial list, or
-    /// added when applying deltas, will not be decoded.
+    /// added when applying deltas, will not be decoded. Tokens whose amounts can't be represented,
+    /// see `TokenSanity::Unsupported`, are dropped.
     pub async fn set_tokens(&self, tokens: HashMap<Bytes, Token>) {
         let mut guard = self.state.write().await;
-        guard.tokens = tokens;
+        guard.tokens = tokens
+            .into_iter()
+            .filter(|(_, token)| is_supported(token))
+            .collect();
     }
 
     pub fn skip_state_decode_failures(&mut self, skip: bool) {
         self.skip_state_decode_failures = skip;
     }
 
+    /// Sets the number of snapshots decoded in parallel. Defaults to the number of available CPUs.
+    ///
+    /// Only has an effect on a multi-threaded runtime; on a current thread runtime decoding is
+    /// concurrent but not parallel.
+    pub fn decode_concurrency(&mut self, concurrency: usize) {
+        self.decode_concurrency = concurrency.max(1);
+    }
+
+    /// Sets the chain of the decoded messages, recorded in the block headers applied to the
+    /// engine.
+    pub fn chain(&mut self, chain: Chain) {
+        self.chain = chain;
+    }
+
+    /// Decoding statistics by protocol, since the decoder was created.
+    pub fn stats(&self) -> HashMap<String, ProtocolStats> {
+        self.stats.lock().unwrap().snapshot()
+    }
+
     /// Registers a decoder for a given exchange.
     ///
     /// This method maps an exchange identifier to a specific protocol simulation type.
@@ -147,6 +262,38 @@ impl TychoStreamDecoder {
             .insert(exchange.to_string(), predicate);
     }
 
+    /// Registers the decoders and filters of all exchanges supported out of the box.
+    #[cfg(any(feature = "ffi", feature = "stress"))]
+    pub fn register_builtin_exchanges(&mut self) {
+        use crate::evm::{
+            engine_db::tycho_db::PreCachedDB,
+            protocol::{
+                algebra::state::AlgebraState,
+                filters::{
+                    balancer_pool_filter, curve_pool_filter, uniswap_v4_pool_with_hook_filter,
+                },
+                fraxswap::state::FraxswapState,
+                uniswap_v2::state::UniswapV2State,
+                uniswap_v3::state::UniswapV3State,
+                uniswap_v4::state::UniswapV4State,
+                vm::state::EVMPoolState,
+            },
+        };
+
+        self.register_decoder::<UniswapV2State>("uniswap_v2");
+        self.register_decoder::<UniswapV3State>("uniswap_v3");
+        self.register_decoder::<UniswapV3State>("pancakeswap_v3");
+        self.register_decoder::<AlgebraState>("camelot_v3");
+        self.register_decoder::<AlgebraState>("quickswap_v3");
+        self.register_decoder::<FraxswapState>("fraxswap");
+        self.register_decoder::<UniswapV4State>("uniswap_v4");
+        self.register_filter("uniswap_v4", uniswap_v4_pool_with_hook_filter);
+        self.register_decoder::<EVMPoolState<PreCachedDB>>("vm:balancer_v2");
+        self.register_filter("vm:balancer_v2", balancer_pool_filter);
+        self.register_decoder::<EVMPoolState<PreCachedDB>>("vm:curve");
+        self.register_filter("vm:curve", curve_pool_filter);
+    }
+
     /// Decodes a `FeedMessage` into a `BlockUpdate` containing the updated states of protocol
     /// components
     pub async fn decode(&self, msg: FeedMessage) -> Result<BlockUpdate, StreamDecodeError> {
@@ -155,6 +302,12 @@ impl TychoStreamDecoder {
         let mut new_pairs = HashMap::new();
         let mut removed_pairs = HashMap::new();
         let mut contracts_map = HashMap::new();
+        let mut code_dependents: HashMap<Bytes, HashSet<String>> = HashMap::new();
+        let mut code_upgrades = Vec::new();
+        let mut new_pools = HashSet::new();
+        let mut tvl = HashMap::new();
+        let mut state_deltas: HashMap<String, ProtocolStateDelta> = HashMap::new();
+        let mut dynamic_fee_pools: HashMap<String, HashSet<String>> = HashMap::new();
 
         let block = msg
             .state_msgs
@@ -163,6 +316,14 @@ impl TychoStreamDecoder {
             .ok_or_else(|| StreamDecodeError::Fatal("Missing block!".into()))?
             .header
             .clone();
+        // Headers carry no timestamp, the block of the deltas does. Messages with snapshots only
+        // are stamped with the current time.
+        let block_timestamp = msg
+            .state_msgs
+            .values()
+            .find_map(|protocol_msg| protocol_msg.deltas.as_ref())
+            .map(|deltas| deltas.block.ts.and_utc().timestamp() as u64)
+            .unwrap_or_else(clock::now);
 
         for (protocol, protocol_msg) in msg.state_msgs.iter() {
             // Add any new tokens
@@ -181,6 +342,7 @@ impl TychoStreamDecoder {
 
                         let token: Result<Token, std::num::TryFromIntError> = t.clone().try_into();
                         let result = match token {
+                            Ok(t) if !is_supported(&t) => return None,
                             Ok(t) => Ok((addr.clone(), t)),
                             Err(e) => Err(StreamDecodeError::Fatal(format!(
                                 "Failed decoding token {e} {addr:#044x}"
@@ -197,6 +359,12 @@ impl TychoStreamDecoder {
             }
 
             // Remove untracked components
+            {
+                let mut stats = self.stats.lock().unwrap();
+                for id in protocol_msg.removed_components.keys() {
+                    stats.record_removed(protocol, id);
+                }
+            }
             let state_guard = self.state.read().await;
             removed_pairs.extend(
                 protocol_msg
@@ -254,23 +422,39 @@ impl TychoStreamDecoder {
                 })
                 .collect::<AccountBalances>();
             info!("Updating engine with {} snapshots", storage_by_address.len());
-            update_engine(
+            let report = update_engine(
                 SHARED_TYCHO_DB.clone(),
-                block.clone().into(),
+                self.block_header(&block, block_timestamp),
                 Some(storage_by_address),
                 HashMap::new(),
             )
             .await;
+            log_failed_updates(&report);
             info!("Engine updated");
 
             let mut new_components = HashMap::new();
 
             // PROCESS SNAPSHOTS
-            'outer: for (id, snapshot) in protocol_msg
+            // Pools created in this block are decoded first, so they can be quoted as early as
+            // possible. Components are otherwise visited in id order, so failures are reported
+            // deterministically
+            let created: HashSet<&String> = protocol_msg
+                .deltas
+                .iter()
+                .flat_map(|deltas| deltas.new_protocol_components.keys())
+                .collect();
+            let mut snapshots: Vec<_> = protocol_msg
                 .snapshots
                 .get_states()
                 .clone()
-            {
+                .into_iter()
+                .collect();
+            snapshots.sort_unstable_by(|(a, _), (b, _)| {
+                (!created.contains(a), a).cmp(&(!created.contains(b), b))
+            });
+
+            let mut pending = Vec::new();
+            'outer: for (id, snapshot) in snapshots {
                 // Skip any unsupported pools
                 if let Some(predicate) = self
                     .inclusion_filters
@@ -311,42 +495,100 @@ impl TychoStreamDecoder {
                     }
                 }
 
+                for contract in &component.contract_ids {
+                    code_dependents
+                        .entry(contract.clone())
+                        .or_default()
+                        .insert(id.clone());
+                }
+
+                if component
+                    .static_attributes
+                    .contains_key("fee_controller")
+                {
+                    dynamic_fee_pools
+                        .entry(protocol.clone())
+                        .or_default()
+                        .insert(id.clone());
+                }
+
                 new_pairs.insert(id.clone(), component);
+                if let Some(component_tvl) = snapshot.component_tvl {
+                    tvl.insert(id.clone(), component_tvl);
+                }
+
+                state_deltas.insert(
+                    id.clone(),
+                    ProtocolStateDelta {
+                        component_id: id.clone(),
+                        updated_attributes: snapshot.state.attributes.clone(),
+                        ..Default::default()
+                    },
+                );
 
                 // Construct state from snapshot
                 if let Some(state_decode_f) = self.registry.get(protocol.as_str()) {
-                    match state_decode_f(
+                    let decode = state_decode_f(
                         snapshot,
                         block.clone(),
                         account_balances.clone(),
                         self.state.clone(),
-                    )
-                    .await
-                    {
-                        Ok(state) => {
-                            new_components.insert(id.clone(), state);
-                        }
-                        Err(e) => {
-                            if self.skip_state_decode_failures {
-                                warn!(pool = id, error = %e, "StateDecodingFailure");
-                                continue 'outer;
-                            } else {
-                                error!(pool = id, error = %e, "StateDecodingFailure");
-                                return Err(StreamDecodeError::Fatal(format!("{e}")));
-                            }
-                        }
-                    }
+                    );
+                    pending.push((id, decode));
                 } else if self.skip_state_decode_failures {
                     warn!(pool = id, "MissingDecoderRegistration");
+                    self.stats
+                        .lock()
+                        .unwrap()
+                        .record_failed(protocol, &id);
                     continue 'outer;
                 } else {
                     error!(pool = id, "MissingDecoderRegistration");
+                    self.stats
+                        .lock()
+                        .unwrap()
+                        .record_failed(protocol, &id);
                     return Err(StreamDecodeError::Fatal(format!(
                         "Missing decoder registration for: {id}"
                     )));
                 }
             }
 
+            // Decode the states in parallel, at most `decode_concurrency` at a time. Each decoding
+            // is spawned so it can run on another worker thread; results are handled in order.
+            let mut decoded = stream::iter(pending)
+                .map(|(id, decode)| async move { (id, tokio::spawn(decode).await) })
+                .buffered(self.decode_concurrency);
+            while let Some((id, result)) = decoded.next().await {
+                let result = result.map_err(|e| {
+                    StreamDecodeError::Fatal(format!("Decoding {id} panicked: {e}"))
+                })?;
+                match result {
+                    Ok(state) => {
+                        self.stats
+                            .lock()
+                            .unwrap()
+                            .record_decoded(protocol, &id);
+                        if created.contains(&id) {
+                            new_pools.insert(id.clone());
+                        }
+                        new_components.insert(id, state);
+                    }
+                    Err(e) => {
+                        self.stats
+                            .lock()
+                            .unwrap()
+                            .record_failed(protocol, &id);
+                        if self.skip_state_decode_failures {
+                            warn!(pool = id, error = %e, "StateDecodingFailure");
+                        } else {
+                            error!(pool = id, error = %e, "StateDecodingFailure");
+                            return Err(StreamDecodeError::Fatal(format!("{e}")));
+                        }
+                    }
+                }
+            }
+
             if !new_components.is_empty() {
                 info!("Decoded {} snapshots for protocol {}", new_components.len(), protocol);
             }
@@ -354,6 +596,7 @@ impl TychoStreamDecoder {
 
             // PROCESS DELTAS
             if let Some(deltas) = protocol_msg.deltas.clone() {
+                let mut delta_updated = HashSet::new();
                 // Update engine with account changes
                 let account_update_by_address: HashMap<Address, AccountUpdate> = deltas
                     .account_updates
@@ -361,19 +604,74 @@ impl TychoStreamDecoder {
                     .iter()
                     .map(|(key, value)| (Address::from_slice(&key[..20]), value.clone().into()))
                     .collect();
+
+                // Detect code changes, before the engine is updated with the new code
+                let mut upgraded_pools = HashSet::new();
+                for (account, update) in &deltas.account_updates {
+                    let Some(code) = update.code.as_ref() else { continue };
+                    let Some(previous_code_hash) = deployed_code_hash(account) else { continue };
+                    let code_hash = keccak256(code);
+                    if code_hash == previous_code_hash {
+                        continue;
+                    }
+                    let mut pools: Vec<String> = code_dependents
+                        .get(account)
+                        .into_iter()
+                        .chain(state_guard.code_dependents.get(account))
+                        .flatten()
+                        .cloned()
+                        .collect::<HashSet<_>>()
+                        .into_iter()
+                        .collect();
+                    pools.sort_unstable();
+                    warn!(
+                        address = %account,
+                        %previous_code_hash,
+                        %code_hash,
+                        n_pools = pools.len(),
+                        "ContractCodeUpgrade"
+                    );
+                    upgraded_pools.extend(pools.iter().cloned());
+                    code_upgrades.push(CodeUpgrade {
+                        address: account.clone(),
+                        previous_code_hash,
+                        code_hash,
+                        pools,
+                    });
+                }
+
                 info!("Updating engine with {} contract deltas", deltas.state_updates.len());
-                update_engine(
+                let report = update_engine(
                     SHARED_TYCHO_DB.clone(),
-                    block.clone().into(),
+                    self.block_header(&block, block_timestamp),
                     None,
                     account_update_by_address,
                 )
                 .await;
+                log_failed_updates(&report);
                 info!("Engine updated");
 
-                // Collect all pools related to the updated accounts
+                // Collect all pools related to the updated accounts. States of this block are not
+                // indexed yet.
+                let block_vm_dependents = if deltas.account_updates.is_empty() {
+                    HashMap::new()
+                } else {
+                    vm_dependents(updated_states.iter())
+                };
                 let mut pools_to_update = HashSet::new();
                 for (account, _update) in deltas.account_updates {
+                    // get VM pools whose simulations accessed the account, e.g. oracles
+                    if account.len() >= 20 {
+                        let address = Address::from_slice(&account[..20]);
+                        for dependents in [&state_guard.vm_dependents, &block_vm_dependents] {
+                            pools_to_update.extend(
+                                dependents
+                                    .get(&address)
+                                    .cloned()
+                                    .unwrap_or_default(),
+                            );
+                        }
+                    }
                     // get new pools related to the account updated
                     pools_to_update.extend(
                         contracts_map
@@ -424,31 +722,98 @@ impl TychoStreamDecoder {
                         .collect(),
                 };
 
+                tvl.extend(deltas.component_tvl);
+
                 // update states with protocol state deltas (attribute changes etc.)
                 for (id, update) in deltas.state_updates {
-                    Self::apply_update(
+                    let delta = state_deltas
+                        .entry(id.clone())
+                        .or_insert_with(|| ProtocolStateDelta {
+                            component_id: id.clone(),
+                            ..Default::default()
+                        });
+                    for attribute in &update.deleted_attributes {
+                        delta
+                            .updated_attributes
+                            .remove(attribute);
+                    }
+                    delta
+                        .updated_attributes
+                        .extend(update.updated_attributes.clone());
+                    delta
+                        .deleted_attributes
+                        .extend(update.deleted_attributes.clone());
+                    if Self::apply_update(
                         &id,
                         update,
                         &mut updated_states,
                         &state_guard,
                         &all_balances,
-                    )?;
+                    )? {
+                        delta_updated.insert(id.clone());
+                    }
                     pools_to_update.remove(&id);
                 }
 
+                // refresh pools running upgraded code, including manually updated ones, and pools
+                // with a dynamic fee, which may change without any update to the pool
+                let refreshed_pools: HashSet<String> = upgraded_pools
+                    .into_iter()
+                    .chain(
+                        state_guard
+                            .dynamic_fee_pools
+                            .get(protocol)
+                            .into_iter()
+                            .flatten()
+                            .cloned(),
+                    )
+                    .collect();
+                for pool in refreshed_pools {
+                    let refresh = ProtocolStateDelta {
+                        component_id: pool.clone(),
+                        updated_attributes: HashMap::from([(
+                            "update_marker".to_string(),
+                            Bytes::from(vec![1u8]),
+                        )]),
+                        ..Default::default()
+                    };
+                    if Self::apply_update(
+                        &pool,
+                        refresh,
+                        &mut updated_states,
+                        &state_guard,
+                        &all_balances,
+                    )? {
+                        delta_updated.insert(pool.clone());
+                    }
+                    pools_to_update.remove(&pool);
+                }
+
                 // update remaining pools linked to updated contracts/updated balances
                 for pool in pools_to_update {
-                    Self::apply_update(
+                    if Self::apply_update(
                         &pool,
                         ProtocolStateDelta::default(),
                         &mut updated_states,
                         &state_guard,
                         &all_balances,
-                    )?;
+                    )? {
+                        delta_updated.insert(pool);
+                    }
                 }
+
+                self.stats
+                    .lock()
+                    .unwrap()
+                    .protocol(protocol)
+                    .updates += delta_updated.len() as u64;
             };
         }
 
+        for state in updated_states.values_mut() {
+            state.set_block_timestamp(block_timestamp);
+        }
+
         // Persist the newly added/updated states
         let mut state_guard = self.state.write().await;
         state_guard
@@ -461,19 +826,70 @@ impl TychoStreamDecoder {
                 .or_insert_with(HashSet::new)
                 .extend(values);
         }
+        for (key, values) in code_dependents {
+            state_guard
+                .code_dependents
+                .entry(key)
+                .or_default()
+                .extend(values);
+        }
+        for (address, pools) in vm_dependents(updated_states.iter()) {
+            state_guard
+                .vm_dependents
+                .entry(address)
+                .or_default()
+                .extend(pools);
+        }
+        for (protocol, pools) in dynamic_fee_pools {
+            state_guard
+                .dynamic_fee_pools
+                .entry(protocol)
+                .or_default()
+                .extend(pools);
+        }
+        for pools in state_guard
+            .dynamic_fee_pools
+            .values_mut()
+        {
+            pools.retain(|id| !removed_pairs.contains_key(id));
+        }
+        state_guard
+            .code_dependents
+            .retain(|_, pools| {
+                pools.retain(|id| !removed_pairs.contains_key(id));
+                !pools.is_empty()
+            });
+        if !removed_pairs.is_empty() {
+            state_guard
+                .vm_dependents
+                .retain(|_, pools| {
+                    pools.retain(|id| !removed_pairs.contains_key(id));
+                    !pools.is_empty()
+                });
+        }
 
         // Send the tick with all updated states
         Ok(BlockUpdate::new(block.number, updated_states, new_pairs)
-            .set_removed_pairs(removed_pairs))
+            .set_removed_pairs(removed_pairs)
+            .set_code_upgrades(code_upgrades)
+            .set_new_pools(new_pools)
+            .set_tvl(tvl)
+            .set_state_deltas(state_deltas)
+            .set_block(BlockInfo {
+                number: block.number,
+                hash: block.hash.clone(),
+                timestamp: block_timestamp,
+            }))
     }
 
+    /// Applies a delta to the state of a pool. Returns whether the pool had a state to update.
     fn apply_update(
         id: &String,
         update: ProtocolStateDelta,
         updated_states: &mut HashMap<String, Box<dyn ProtocolSim>>,
         state_guard: &RwLockReadGuard<'_, DecoderState>,
         all_balances: &Balances,
-    ) -> Result<(), StreamDecodeError> {
+    ) -> Result<bool, StreamDecodeError> {
         match updated_states.entry(id.clone()) {
             Entry::Occupied(mut entry) => {
                 // If state exists in updated_states, apply the delta to it
@@ -499,25 +915,93 @@ impl TychoStreamDecoder {
                             })?;
                         updated_states.insert(id.clone(), state);
                     }
-                    None => debug!(pool = id, reason = "MissingState", "DeltaTransitionError"),
+                    None => {
+                        debug!(pool = id, reason = "MissingState", "DeltaTransitionError");
+                        return Ok(false);
+                    }
                 }
             }
         }
-        Ok(())
+        Ok(true)
+    }
+
+    /// The header of the block applied to the engine, with the chain and timestamp tycho headers
+    /// lack.
+    fn block_header(&self, header: &Header, timestamp: u64) -> BlockHeader {
+        BlockHeader { chain: self.chain, timestamp, ..header.clone().into() }
     }
 }
 
+/// Maps accounts to the VM pools depending on them, see `EVMPoolState::dependencies`.
+fn vm_dependents<'a>(
+    states: impl Iterator<Item = (&'a String, &'a Box<dyn ProtocolSim>)>,
+) -> HashMap<Address, HashSet<String>> {
+    let mut dependents: HashMap<Address, HashSet<String>> = HashMap::new();
+    for (id, state) in states {
+        if let Some(state) = state
+            .as_any()
+            .downcast_ref::<EVMPoolState<PreCachedDB>>()
+        {
+            for address in state.dependencies() {
+                dependents
+                    .entry(address)
+                    .or_default()
+                    .insert(id.clone());
+            }
+        }
+    }
+    dependents
+}
+
+fn log_failed_updates(report: &EngineUpdateReport) {
+    for failure in &report.failed {
+        warn!(
+            address = %failure.update.address,
+            error = %failure.error,
+            "Skipped account update that failed to apply"
+        );
+    }
+}
+
+/// Whether amounts of `token` can be scaled by its decimals, see `Token::sanity`. Pools with
+/// unsupported tokens are then skipped like pools with unknown tokens.
+fn is_supported(token: &Token) -> bool {
+    if token.sanity() == TokenSanity::Unsupported {
+        warn!(token = %token.address, decimals = token.decimals, "Skipping token with unsupported decimals");
+        return false;
+    }
+    true
+}
+
+/// Returns the hash of the code `address` currently has in the shared engine, if it is a known
+/// contract.
+fn deployed_code_hash(address: &Bytes) -> Option<B256> {
+    if address.len() < 20 {
+        return None;
+    }
+    SHARED_TYCHO_DB
+        .basic_ref(Address::from_slice(&address[..20]))
+        .ok()
+        .flatten()
+        .filter(|info| info.code_hash != KECCAK_EMPTY)
+        .map(|info| info.code_hash)
+}
+
 #[cfg(test)]
 mod tests {
     use std::{fs, path::Path};
 
+    use alloy_primitives::U256;
     use mockall::predicate::*;
     use num_bigint::ToBigUint;
     use rstest::*;
 
     use super::*;
     use crate::{
-        evm::protocol::uniswap_v2::state::UniswapV2State, models::Token,
+        evm::{
+            engine_db::account_builder::AccountBuilder, protocol::uniswap_v2::state::UniswapV2State,
+        },
+        models::Token,
         protocol::state::MockProtocolSim,
     };
 
@@ -565,6 +1049,128 @@ mod tests {
 
         assert_eq!(res1.states.len(), 1);
         assert_eq!(res2.states.len(), 1);
+        assert_eq!(
+            decoder.stats()["uniswap_v2"],
+            ProtocolStats { pools: 1, updates: 2, decode_failures: 0, quarantined: 0 }
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_decode_parallel() {
+        let mut decoder = setup_decoder(true).await;
+        decoder.decode_concurrency(4);
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/assets/decoder/uniswap_v2_snapshot.json");
+        let mut msg: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        // Copy the pool with distinct reserves, more pools than decoded at a time
+        let states = &mut msg["state_msgs"]["uniswap_v2"]["snapshots"]["states"];
+        let snapshot = states["0x0d4a11d5eeaac28ec3f61d100daf4d40471f1852"].clone();
+        let ids: Vec<_> = (1..=10u8)
+            .map(|pool| format!("0x{pool:040x}"))
+            .collect();
+        for (pool, id) in (1..=10u8).zip(&ids) {
+            let mut snapshot = snapshot.clone();
+            snapshot["state"]["component_id"] = id.clone().into();
+            snapshot["component"]["id"] = id.clone().into();
+            snapshot["state"]["attributes"]["reserve0"] = format!("0x{pool:02x}").into();
+            states[id.as_str()] = snapshot;
+        }
+
+        let res = decoder
+            .decode(serde_json::from_value(msg).unwrap())
+            .await
+            .expect("decode failure");
+
+        assert_eq!(res.states.len(), 11);
+        assert_eq!(res.new_pairs.len(), 11);
+        for (pool, id) in (1..=10u8).zip(&ids) {
+            let state = res.states[id]
+                .as_any()
+                .downcast_ref::<UniswapV2State>()
+                .unwrap();
+            assert_eq!(state.reserve0, U256::from(pool));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_decode_block_timestamp() {
+        let decoder = setup_decoder(true).await;
+
+        let res = decoder
+            .decode(load_test_msg("uniswap_v2_snapshot"))
+            .await
+            .expect("decode failure");
+
+        // The time of the deltas' block, 2024-11-28T05:29:59
+        assert_eq!(res.block.map(|block| block.timestamp), Some(1732771799));
+    }
+
+    #[tokio::test]
+    async fn test_decode_state_deltas() {
+        let decoder = setup_decoder(true).await;
+        let id = "0x0d4a11d5eeaac28ec3f61d100daf4d40471f1852";
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/assets/decoder/uniswap_v2_snapshot.json");
+        let mut msg: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        msg["state_msgs"]["uniswap_v2"]["deltas"]["state_updates"][id] = serde_json::json!({
+            "component_id": id,
+            "updated_attributes": {"reserve0": "0x01", "reserve1": "0x02"},
+            "deleted_attributes": [],
+        });
+
+        let res = decoder
+            .decode(serde_json::from_value(msg).unwrap())
+            .await
+            .expect("decode failure");
+
+        // The snapshot's attributes, overwritten by the delta of the same block
+        let delta = &res.state_deltas[id];
+        assert_eq!(delta.updated_attributes.len(), 2);
+        assert_eq!(delta.updated_attributes["reserve0"], Bytes::from(vec![1u8]));
+        assert_eq!(delta.updated_attributes["reserve1"], Bytes::from(vec![2u8]));
+    }
+
+    #[tokio::test]
+    async fn test_decode_reports_new_pools() {
+        let decoder = setup_decoder(true).await;
+        let id = "0x0d4a11d5eeaac28ec3f61d100daf4d40471f1852";
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/assets/decoder/uniswap_v2_snapshot.json");
+        let mut msg: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        let protocol_msg = &mut msg["state_msgs"]["uniswap_v2"];
+        let component = protocol_msg["snapshots"]["states"][id]["component"].clone();
+        protocol_msg["deltas"]["new_protocol_components"][id] = component;
+
+        let res = decoder
+            .decode(serde_json::from_value(msg).unwrap())
+            .await
+            .expect("decode failure");
+
+        assert_eq!(res.new_pools, HashSet::from([id.to_string()]));
+        assert!(res.states.contains_key(id));
+
+        let res = decoder
+            .decode(load_test_msg("uniswap_v2_snapshot"))
+            .await
+            .expect("decode failure");
+
+        assert!(res.new_pools.is_empty());
+    }
+
+    #[test]
+    fn test_deployed_code_hash() {
+        let address = Address::with_last_byte(0xc0);
+        let code = vec![0x60, 0x80, 0x60, 0x40, 0x52];
+        AccountBuilder::new(address)
+            .code(code.clone())
+            .mocked()
+            .init(&*SHARED_TYCHO_DB);
+
+        assert_eq!(deployed_code_hash(&Bytes::from(address.to_vec())), Some(keccak256(&code)));
+        assert_eq!(deployed_code_hash(&Bytes::from(Address::with_last_byte(0xc1).to_vec())), None);
     }
 
     #[tokio::test]
@@ -588,6 +1194,32 @@ mod tests {
         assert_eq!(res1.states.len(), 0);
     }
 
+    #[tokio::test]
+    async fn test_decode_component_unsupported_token() {
+        let decoder = setup_decoder(false).await;
+        let tokens = [
+            (Bytes::from("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2").lpad(20, 0), 18),
+            (Bytes::from("0xdac17f958d2ee523a2206206994597c13d831ec7").lpad(20, 0), 78),
+        ]
+        .iter()
+        .map(|(addr, decimals)| {
+            let addr_str = format!("{:x}", addr);
+            (
+                addr.clone(),
+                Token::new(&addr_str, *decimals, &addr_str, 100_000.to_biguint().unwrap()),
+            )
+        })
+        .collect();
+        decoder.set_tokens(tokens).await;
+
+        let res = decoder
+            .decode(load_test_msg("uniswap_v2_snapshot"))
+            .await
+            .expect("decode failure");
+
+        assert_eq!(res.states.len(), 0);
+    }
+
     #[rstest]
     #[case(true)]
     #[case(false)]
@@ -643,6 +1275,10 @@ mod tests {
                 }
             }
         }
+        assert_eq!(
+            decoder.stats()["uniswap_v2"],
+            ProtocolStats { pools: 0, updates: 0, decode_failures: 1, quarantined: 1 }
+        );
     }
 
     #[tokio::test]
@@ -699,4 +1335,132 @@ mod tests {
 
         // The mock framework will assert that `delta_transition` was called exactly once
     }
+
+    #[tokio::test]
+    async fn test_decode_code_upgrade() {
+        let decoder = setup_decoder(true).await;
+        let address = Address::with_last_byte(0xc2);
+        let previous_code = vec![0x60, 0x00];
+        let code = vec![0x60, 0x01, 0x00];
+        AccountBuilder::new(address)
+            .code(previous_code.clone())
+            .mocked()
+            .init(&*SHARED_TYCHO_DB);
+        let mut mock_state = MockProtocolSim::new();
+        mock_state
+            .expect_clone_box()
+            .times(1)
+            .returning(|| {
+                let mut cloned_mock_state = MockProtocolSim::new();
+                cloned_mock_state
+                    .expect_delta_transition()
+                    .withf(|delta, _, _| {
+                        delta
+                            .updated_attributes
+                            .contains_key("update_marker")
+                    })
+                    .times(1)
+                    .returning(|_, _, _| Ok(()));
+                cloned_mock_state
+                    .expect_clone_box()
+                    .times(1)
+                    .returning(|| Box::new(MockProtocolSim::new()));
+                Box::new(cloned_mock_state)
+            });
+        let pool_id = "0xupgraded".to_string();
+        {
+            let mut state = decoder.state.write().await;
+            state
+                .states
+                .insert(pool_id.clone(), Box::new(mock_state) as Box<dyn ProtocolSim>);
+            state
+                .code_dependents
+                .insert(Bytes::from(address.to_vec()), HashSet::from([pool_id.clone()]));
+        }
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/assets/decoder/uniswap_v2_delta.json");
+        let mut msg: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        let account_updates = &mut msg["state_msgs"]["uniswap_v2"]["deltas"]["account_updates"];
+        account_updates[address.to_string()] = serde_json::json!({
+            "address": address.to_string(),
+            "chain": "ethereum",
+            "slots": {},
+            "balance": null,
+            "code": alloy_primitives::hex::encode_prefixed(&code),
+            "change": "Update"
+        });
+
+        let res = decoder
+            .decode(serde_json::from_value(msg).unwrap())
+            .await
+            .expect("decode failure");
+
+        assert_eq!(
+            res.code_upgrades,
+            vec![CodeUpgrade {
+                address: Bytes::from(address.to_vec()),
+                previous_code_hash: keccak256(&previous_code),
+                code_hash: keccak256(&code),
+                pools: vec![pool_id.clone()],
+            }]
+        );
+        assert!(res.states.contains_key(&pool_id));
+        // The engine runs the new code
+        let info = SHARED_TYCHO_DB
+            .basic_ref(address)
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.code_hash, keccak256(&code));
+        assert_eq!(
+            info.code
+                .unwrap()
+                .original_bytes()
+                .to_vec(),
+            code
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_refreshes_dynamic_fee_pools() {
+        let decoder = setup_decoder(true).await;
+        let mut mock_state = MockProtocolSim::new();
+        mock_state
+            .expect_clone_box()
+            .times(1)
+            .returning(|| {
+                let mut cloned_mock_state = MockProtocolSim::new();
+                cloned_mock_state
+                    .expect_delta_transition()
+                    .withf(|delta, _, _| {
+                        delta
+                            .updated_attributes
+                            .contains_key("update_marker")
+                    })
+                    .times(1)
+                    .returning(|_, _, _| Ok(()));
+                cloned_mock_state
+                    .expect_clone_box()
+                    .times(1)
+                    .returning(|| Box::new(MockProtocolSim::new()));
+                Box::new(cloned_mock_state)
+            });
+        let pool_id = "0xdynamicfee".to_string();
+        {
+            let mut state = decoder.state.write().await;
+            state
+                .states
+                .insert(pool_id.clone(), Box::new(mock_state) as Box<dyn ProtocolSim>);
+            state
+                .dynamic_fee_pools
+                .insert("uniswap_v2".to_string(), HashSet::from([pool_id.clone()]));
+        }
+
+        let res = decoder
+            .decode(load_test_msg("uniswap_v2_delta"))
+            .await
+            .expect("decode failure");
+
+        assert!(res.states.contains_key(&pool_id));
+    }
 }
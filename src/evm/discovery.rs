@@ -0,0 +1,361 @@
+//! On-demand pool discovery
+//!
+//! `discover_pools` looks up the pools of a token pair in the factories and registries of the
+//! supported protocols and builds their states, calling the contracts through a simulation engine
+//! on top of an `RpcDB`. Useful to quote pools the Tycho stream does not include (yet), e.g.
+//! because they were just created.
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
+
+use alloy_primitives::{hex, Address, I256, U256};
+use alloy_sol_types::SolValue;
+use revm::primitives::Bytecode;
+use tracing::warn;
+use tycho_core::Bytes;
+
+use crate::{
+    evm::{
+        engine_db::{
+            create_engine,
+            simulation_db::{BlockHeader, RpcDB},
+        },
+        multicall::{CallResult, Multicall},
+        protocol::{
+            uniswap_v2::state::UniswapV2State,
+            uniswap_v3::{enums::FeeAmount, state::UniswapV3State},
+            utils::{
+                bytes_to_address,
+                uniswap::{
+                    tick_list::TickInfo,
+                    tick_math::{MAX_TICK, MIN_TICK},
+                },
+            },
+            vm::{
+                constants::CURVE, state_builder::EVMPoolStateBuilder,
+                tycho_simulation_contract::TychoSimulationContract,
+            },
+        },
+        simulation::SimulationEngine,
+    },
+    models::Token,
+    protocol::{errors::SimulationError, state::ProtocolSim},
+};
+
+/// A contract to look up pools in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolSource {
+    /// A Uniswap V2 style factory, queried with `getPair`
+    UniswapV2Factory(Address),
+    /// A Uniswap V3 style factory, queried with `getPool` for every fee tier
+    UniswapV3Factory(Address),
+    /// A Curve registry, queried with `find_pool_for_coins`
+    CurveRegistry(Address),
+}
+
+impl PoolSource {
+    /// The Uniswap V2 and V3 factories and the Curve registry on Ethereum mainnet
+    pub fn ethereum() -> Vec<Self> {
+        let address = |address: &str| Address::from_str(address).expect("Invalid address");
+        vec![
+            PoolSource::UniswapV2Factory(address("0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f")),
+            PoolSource::UniswapV3Factory(address("0x1F98431c8aD98523631AE4a59f267346ea31F984")),
+            PoolSource::CurveRegistry(address("0x90E00ACe148ca3b23Ac1bC8C240C2a7Dd9c2d7f5")),
+        ]
+    }
+}
+
+/// A discovered pool and its state.
+#[derive(Debug)]
+pub struct DiscoveredPool {
+    pub address: Address,
+    /// Protocol system of the pool, named like in Tycho, e.g. `uniswap_v2` or `vm:curve`
+    pub protocol_system: String,
+    pub state: Box<dyn ProtocolSim>,
+}
+
+/// Finds the pools trading `token_a` against `token_b` in `sources` and builds their states at
+/// the block `db` is pinned to.
+///
+/// Uniswap V3 states are built from the tick bitmap of the pool, which takes one call per 256
+/// tick spacings, so discovering pools of the low fee tiers takes a while. Curve pools are built
+/// with `EVMPoolStateBuilder::build_from_rpc` and only quote the given pair; pools the adapter
+/// fails to build are skipped.
+///
+/// Blocks on node requests, so it must be called outside of an async runtime, see
+/// `RpcDB::connect`.
+pub fn discover_pools(
+    db: &RpcDB,
+    token_a: &Token,
+    token_b: &Token,
+    sources: &[PoolSource],
+) -> Result<Vec<DiscoveredPool>, SimulationError> {
+    let block = db
+        .block()
+        .ok_or_else(|| SimulationError::FatalError("Database is not pinned to a block".into()))?;
+    let finder = PoolFinder {
+        engine: create_engine(db.clone(), false)?,
+        block,
+        token_a: bytes_to_address(&token_a.address)?,
+        token_b: bytes_to_address(&token_b.address)?,
+    };
+
+    let mut pools = Vec::new();
+    for source in sources {
+        match *source {
+            PoolSource::UniswapV2Factory(factory) => pools.extend(finder.uniswap_v2(factory)?),
+            PoolSource::UniswapV3Factory(factory) => pools.extend(finder.uniswap_v3(factory)?),
+            PoolSource::CurveRegistry(registry) => {
+                for pool in finder.curve(registry)? {
+                    match build_curve_state(db, pool, token_a, token_b) {
+                        Ok(state) => pools.push(state),
+                        Err(e) => warn!(?pool, ?e, "Failed to build discovered Curve pool"),
+                    }
+                }
+            }
+        }
+    }
+    Ok(pools)
+}
+
+struct PoolFinder {
+    engine: SimulationEngine<RpcDB>,
+    block: BlockHeader,
+    token_a: Address,
+    token_b: Address,
+}
+
+impl PoolFinder {
+    fn call(
+        &self,
+        to: Address,
+        selector: &str,
+        args: impl SolValue,
+    ) -> Result<Vec<u8>, SimulationError> {
+        let contract = TychoSimulationContract::new(to, self.engine.clone())?;
+        Ok(contract
+            .call(selector, args, &self.block, None, None, U256::ZERO)?
+            .return_value)
+    }
+
+    fn uniswap_v2(&self, factory: Address) -> Result<Option<DiscoveredPool>, SimulationError> {
+        let res = self.call(factory, "getPair(address,address)", (self.token_a, self.token_b))?;
+        let pair = address_word(&res, 0)?;
+        if pair.is_zero() {
+            return Ok(None);
+        }
+
+        let reserves = self.call(pair, "getReserves()", ())?;
+        Ok(Some(DiscoveredPool {
+            address: pair,
+            protocol_system: "uniswap_v2".to_string(),
+            state: Box::new(UniswapV2State::new(word(&reserves, 0)?, word(&reserves, 1)?)),
+        }))
+    }
+
+    fn uniswap_v3(&self, factory: Address) -> Result<Vec<DiscoveredPool>, SimulationError> {
+        let mut pools = Vec::new();
+        for fee in [FeeAmount::Lowest, FeeAmount::Low, FeeAmount::Medium, FeeAmount::High] {
+            let res = self.call(
+                factory,
+                "getPool(address,address,uint24)",
+                (self.token_a, self.token_b, U256::from(fee as u32)),
+            )?;
+            let pool = address_word(&res, 0)?;
+            if pool.is_zero() {
+                continue;
+            }
+
+            let slot0 = self.call(pool, "slot0()", ())?;
+            let liquidity = word(&self.call(pool, "liquidity()", ())?, 0)?;
+            let ticks = self.uniswap_v3_ticks(pool, UniswapV3State::get_spacing(fee) as i32)?;
+            pools.push(DiscoveredPool {
+                address: pool,
+                protocol_system: "uniswap_v3".to_string(),
+                state: Box::new(UniswapV3State::new(
+                    liquidity.saturating_to(),
+                    word(&slot0, 0)?,
+                    fee,
+                    signed_word(&slot0, 1)?,
+                    ticks,
+                )),
+            });
+        }
+        Ok(pools)
+    }
+
+    /// Reads the initialized ticks of a pool from its tick bitmap.
+    fn uniswap_v3_ticks(
+        &self,
+        pool: Address,
+        spacing: i32,
+    ) -> Result<Vec<TickInfo>, SimulationError> {
+        // One bitmap read per word and one read per initialized tick: batched, as there are
+        // thousands of words for low tick spacings
+        let words: Vec<i32> = ((MIN_TICK / spacing) >> 8..=(MAX_TICK / spacing) >> 8).collect();
+        let mut bitmaps = Multicall::new(self.engine.clone());
+        for word_pos in &words {
+            bitmaps.add(pool, "tickBitmap(int16)", *word_pos);
+        }
+        let mut indices = Vec::new();
+        for (word_pos, result) in words
+            .iter()
+            .zip(bitmaps.execute(&self.block)?)
+        {
+            let bitmap = word(&successful(result)?, 0)?;
+            indices.extend(
+                (0..256)
+                    .filter(|bit| bitmap.bit(*bit))
+                    .map(|bit| ((*word_pos << 8) + bit as i32) * spacing),
+            );
+        }
+
+        let mut ticks = Multicall::new(self.engine.clone());
+        for index in &indices {
+            ticks.add(pool, "ticks(int24)", *index);
+        }
+        indices
+            .into_iter()
+            .zip(ticks.execute(&self.block)?)
+            .map(|(index, result)| Ok(TickInfo::new(index, signed_word(&successful(result)?, 1)?)))
+            .collect()
+    }
+
+    fn curve(&self, registry: Address) -> Result<Vec<Address>, SimulationError> {
+        let mut pools = Vec::new();
+        loop {
+            let res = self.call(
+                registry,
+                "find_pool_for_coins(address,address,uint256)",
+                (self.token_a, self.token_b, U256::from(pools.len())),
+            )?;
+            let pool = address_word(&res, 0)?;
+            if pool.is_zero() {
+                return Ok(pools);
+            }
+            pools.push(pool);
+        }
+    }
+}
+
+fn build_curve_state(
+    db: &RpcDB,
+    pool: Address,
+    token_a: &Token,
+    token_b: &Token,
+) -> Result<DiscoveredPool, SimulationError> {
+    // Same adapter address the Tycho decoder assigns to `vm:curve` pools
+    let adapter_address = Address::from_str(&format!("{:0>40}", hex::encode("curve")))
+        .expect("Valid adapter address");
+    let mut state = EVMPoolStateBuilder::new(
+        format!("{:#x}", pool),
+        vec![token_a.address.clone(), token_b.address.clone()],
+        BlockHeader::default(),
+        adapter_address,
+    )
+    .adapter_contract_bytecode(Bytecode::new_raw(CURVE.into()))
+    .involved_contracts(HashSet::from([pool]))
+    .build_from_rpc(db.clone())?;
+    state.set_spot_prices(&HashMap::from([
+        (token_a.address.clone(), token_a.clone()),
+        (token_b.address.clone(), token_b.clone()),
+    ]))?;
+
+    Ok(DiscoveredPool {
+        address: pool,
+        protocol_system: "vm:curve".to_string(),
+        state: Box::new(state),
+    })
+}
+
+/// Returns the `index`th 32 byte word of ABI encoded return data.
+fn word(data: &[u8], index: usize) -> Result<U256, SimulationError> {
+    data.get(index * 32..(index + 1) * 32)
+        .map(U256::from_be_slice)
+        .ok_or_else(|| {
+            SimulationError::FatalError(format!("Return data too short: 0x{}", hex::encode(data)))
+        })
+}
+
+fn address_word(data: &[u8], index: usize) -> Result<Address, SimulationError> {
+    Ok(Address::from_word(word(data, index)?.into()))
+}
+
+fn successful(result: CallResult) -> Result<Vec<u8>, SimulationError> {
+    if !result.success {
+        return Err(SimulationError::FatalError(format!(
+            "Call reverted: 0x{}",
+            hex::encode(&result.return_data)
+        )));
+    }
+    Ok(result.return_data.to_vec())
+}
+
+/// Decodes a sign extended word into a narrower signed integer, e.g. an `int24` tick.
+fn signed_word<T: TryFrom<I256>>(data: &[u8], index: usize) -> Result<T, SimulationError> {
+    let value = I256::from_raw(word(data, index)?);
+    T::try_from(value)
+        .map_err(|_| SimulationError::FatalError(format!("Signed value out of range: {}", value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigUint;
+
+    use super::*;
+
+    #[test]
+    fn test_decode_words() {
+        let mut data = [0u8; 64];
+        data[31] = 1;
+        data[32..].fill(0xff);
+
+        assert_eq!(word(&data, 0).unwrap(), U256::from(1u64));
+        assert_eq!(address_word(&data, 0).unwrap(), Address::with_last_byte(1));
+        assert_eq!(signed_word::<i32>(&data, 1).unwrap(), -1);
+        assert!(signed_word::<u8>(&data, 1).is_err());
+        assert!(word(&data, 2).is_err());
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "network_tests"), ignore)]
+    fn test_discover_pools() {
+        dotenv::dotenv().ok();
+        let rpc_url = std::env::var("RPC_URL").expect("Missing RPC_URL in environment");
+        let usdc =
+            Token::new("0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48", 6, "USDC", 10_000u32.into());
+        let weth =
+            Token::new("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2", 18, "WETH", 10_000u32.into());
+        let db = RpcDB::connect(&rpc_url, Some(20463609)).unwrap();
+        let sources = [
+            PoolSource::UniswapV2Factory(
+                Address::from_str("0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f").unwrap(),
+            ),
+            PoolSource::UniswapV3Factory(
+                Address::from_str("0x1F98431c8aD98523631AE4a59f267346ea31F984").unwrap(),
+            ),
+        ];
+
+        let pools = discover_pools(&db, &usdc, &weth, &sources).unwrap();
+
+        let v2 = pools
+            .iter()
+            .find(|pool| pool.protocol_system == "uniswap_v2")
+            .expect("USDC/WETH V2 pair exists");
+        assert_eq!(
+            v2.address,
+            Address::from_str("0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc").unwrap()
+        );
+        assert!(pools
+            .iter()
+            .any(|pool| pool.protocol_system == "uniswap_v3"));
+        for pool in &pools {
+            let res = pool
+                .state
+                .get_amount_out(BigUint::from(10u64.pow(9)), &usdc, &weth)
+                .unwrap();
+            assert!(res.amount > BigUint::from(0u32));
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+use alloy_primitives::{Address, U256};
+use revm::primitives::{AccountInfo, Bytecode, Bytes, KECCAK_EMPTY};
+
+use super::engine_db_interface::EngineDatabaseInterface;
+
+/// Builder for an account to initialize in an engine database.
+///
+/// Replaces positional calls to `EngineDatabaseInterface::init_account`. The code hash is derived
+/// from the code, and accounts are on-chain (not mocked) unless `mocked` is called.
+///
+/// # Example
+///
+/// ```ignore
+/// AccountBuilder::new(address)
+///     .code(bytecode)
+///     .balance(U256::MAX)
+///     .storage(U256::ZERO, U256::from(1))
+///     .mocked()
+///     .init(&db);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountBuilder {
+    address: Address,
+    balance: U256,
+    nonce: u64,
+    code: Option<Bytecode>,
+    storage: Option<HashMap<U256, U256>>,
+    mocked: bool,
+}
+
+impl AccountBuilder {
+    /// Creates a builder for an on-chain account without code, balance or storage.
+    pub fn new(address: Address) -> Self {
+        Self { address, balance: U256::ZERO, nonce: 0, code: None, storage: None, mocked: false }
+    }
+
+    /// Sets the runtime bytecode of the account.
+    pub fn code(mut self, code: impl Into<Bytes>) -> Self {
+        self.code = Some(Bytecode::new_raw(code.into()));
+        self
+    }
+
+    /// Sets an already built bytecode, e.g. an analysed one.
+    pub fn bytecode(mut self, code: Bytecode) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn balance(mut self, balance: U256) -> Self {
+        self.balance = balance;
+        self
+    }
+
+    pub fn nonce(mut self, nonce: u64) -> Self {
+        self.nonce = nonce;
+        self
+    }
+
+    /// Sets a permanent storage slot. Permanent storage can only be updated manually.
+    pub fn storage(mut self, slot: U256, value: U256) -> Self {
+        self.storage
+            .get_or_insert_with(HashMap::new)
+            .insert(slot, value);
+        self
+    }
+
+    /// Starts the account from empty permanent storage, so slots that are not set read as zero
+    /// instead of being fetched.
+    pub fn empty_storage(mut self) -> Self {
+        self.storage
+            .get_or_insert_with(HashMap::new);
+        self
+    }
+
+    /// Marks the account as mocked: nothing is downloaded from a node, all data must be inserted
+    /// manually.
+    pub fn mocked(mut self) -> Self {
+        self.mocked = true;
+        self
+    }
+
+    /// Marks the account as on-chain: missing data may be fetched from a node. This is the default.
+    pub fn onchain(mut self) -> Self {
+        self.mocked = false;
+        self
+    }
+
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// Returns the account information, with the code hash matching the code.
+    pub fn account_info(&self) -> AccountInfo {
+        let code_hash = self
+            .code
+            .as_ref()
+            .map_or(KECCAK_EMPTY, Bytecode::hash_slow);
+        AccountInfo { balance: self.balance, nonce: self.nonce, code_hash, code: self.code.clone() }
+    }
+
+    /// Initializes the account in the given database.
+    pub fn init<D: EngineDatabaseInterface + ?Sized>(self, db: &D) {
+        let info = self.account_info();
+        db.init_account(self.address, info, self.storage, self.mocked);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy_primitives::keccak256;
+
+    use super::*;
+
+    #[test]
+    fn test_account_builder() {
+        let address = Address::repeat_byte(0x01);
+        let code = vec![0x60, 0x00];
+
+        let builder = AccountBuilder::new(address)
+            .code(code.clone())
+            .balance(U256::from(10))
+            .storage(U256::from(1), U256::from(2))
+            .mocked();
+        let info = builder.account_info();
+
+        assert_eq!(builder.address(), address);
+        assert_eq!(info.balance, U256::from(10));
+        assert_eq!(info.code_hash, keccak256(&code));
+        assert!(builder.mocked);
+        assert_eq!(builder.storage, Some(HashMap::from([(U256::from(1), U256::from(2))])));
+        assert!(!builder.onchain().mocked);
+    }
+
+    #[test]
+    fn test_account_builder_defaults() {
+        let builder = AccountBuilder::new(Address::ZERO);
+
+        let info = builder.account_info();
+
+        assert_eq!(info.balance, U256::ZERO);
+        assert_eq!(info.code_hash, KECCAK_EMPTY);
+        assert_eq!(info.code, None);
+        assert_eq!(builder.storage, None);
+        assert!(!builder.mocked);
+    }
+}
@@ -3,6 +3,8 @@ use std::collections::HashMap;
 use alloy_primitives::U256;
 use revm::{precompile::Address, primitives::AccountInfo, DatabaseRef};
 
+use super::{account_builder::AccountBuilder, simulation_db::BlockHeader};
+
 pub trait EngineDatabaseInterface: DatabaseRef + Send + Sync {
     type Error;
 
@@ -27,5 +29,31 @@ pub trait EngineDatabaseInterface: DatabaseRef + Send + Sync {
         mocked: bool,
     );
 
+    /// Sets up multiple accounts
+    ///
+    /// # Arguments
+    ///
+    /// * `accounts` - Builders of the accounts to set up, see `AccountBuilder`.
+    fn init_accounts(&self, accounts: impl IntoIterator<Item = AccountBuilder>)
+    where
+        Self: Sized,
+    {
+        for account in accounts {
+            account.init(self);
+        }
+    }
+
     fn clear_temp_storage(&mut self);
+
+    /// The block of the state, if known. Used to validate simulation parameters, see
+    /// `SimulationEngine::validate_params`.
+    fn block_header(&self) -> Option<BlockHeader> {
+        None
+    }
+
+    /// Whether `error`, returned by a read of this database, means the account is unknown rather
+    /// than that the database failed. Such accounts can still be created, e.g. by state overrides.
+    fn is_missing_account(_error: &<Self as DatabaseRef>::Error) -> bool {
+        false
+    }
 }
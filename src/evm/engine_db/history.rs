@@ -0,0 +1,140 @@
+//! Retained databases of recent blocks
+//!
+//! A `BlockHistory` keeps checkpoints of a `PreCachedDB` for the last N blocks, so pools can still
+//! be simulated at any of them. Checkpoints share the storage of unchanged accounts, see
+//! `PreCachedDB::checkpoint`, so retaining a block only costs the accounts updated since.
+use std::collections::VecDeque;
+
+use alloy_primitives::B256;
+
+use crate::evm::engine_db::{simulation_db::BlockHeader, tycho_db::PreCachedDB};
+
+/// Checkpoints of a database for its last blocks, oldest first.
+#[derive(Debug, Clone)]
+pub struct BlockHistory {
+    capacity: usize,
+    checkpoints: VecDeque<PreCachedDB>,
+}
+
+impl BlockHistory {
+    /// Retains at most `capacity` blocks.
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, checkpoints: VecDeque::with_capacity(capacity) }
+    }
+
+    /// Retains a checkpoint of `db` at its current block, dropping the oldest block beyond the
+    /// capacity. A block that was already recorded is replaced, as it was updated again. Databases
+    /// without a block are ignored.
+    pub fn record(&mut self, db: &PreCachedDB) {
+        let Some(block) = db.block() else {
+            return;
+        };
+        if self.capacity == 0 {
+            return;
+        }
+        if self
+            .checkpoints
+            .back()
+            .and_then(PreCachedDB::block)
+            .is_some_and(|last| last.hash == block.hash)
+        {
+            self.checkpoints.pop_back();
+        }
+        self.checkpoints
+            .push_back(db.checkpoint());
+        while self.checkpoints.len() > self.capacity {
+            self.checkpoints.pop_front();
+        }
+    }
+
+    /// The database at the block with the given hash, if it is retained.
+    pub fn at(&self, block_hash: &B256) -> Option<&PreCachedDB> {
+        self.checkpoints
+            .iter()
+            .rev()
+            .find(|db| {
+                db.block()
+                    .is_some_and(|block| block.hash == *block_hash)
+            })
+    }
+
+    /// The retained blocks, oldest first.
+    pub fn blocks(&self) -> Vec<BlockHeader> {
+        self.checkpoints
+            .iter()
+            .filter_map(PreCachedDB::block)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.checkpoints.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.checkpoints.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy_primitives::{Address, U256};
+    use revm::DatabaseRef;
+
+    use super::*;
+    use crate::evm::{
+        account_storage::StateUpdate, engine_db::account_builder::AccountBuilder, StorageMap,
+    };
+
+    fn block(number: u64) -> BlockHeader {
+        BlockHeader { number, hash: B256::with_last_byte(number as u8), ..Default::default() }
+    }
+
+    #[test]
+    fn test_block_history() {
+        let mut db = PreCachedDB::new().unwrap();
+        let address = Address::with_last_byte(0x01);
+        AccountBuilder::new(address)
+            .storage(U256::from(1), U256::from(10))
+            .init(&db);
+        let mut history = BlockHistory::new(2);
+
+        for number in 1..=3u64 {
+            db.update_state(
+                &StorageMap::from_iter([(
+                    address,
+                    StateUpdate {
+                        storage: Some(StorageMap::from_iter([(U256::from(1), U256::from(number))])),
+                        balance: None,
+                        code: None,
+                        nonce: None,
+                    },
+                )]),
+                block(number),
+            );
+            history.record(&db);
+        }
+        // Updating a block again replaces it
+        history.record(&db);
+
+        assert_eq!(
+            history
+                .blocks()
+                .iter()
+                .map(|block| block.number)
+                .collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert!(history.at(&block(1).hash).is_none());
+        let at_2 = history.at(&block(2).hash).unwrap();
+        assert_eq!(
+            at_2.storage_ref(address, U256::from(1))
+                .unwrap(),
+            U256::from(2)
+        );
+        assert_eq!(
+            db.storage_ref(address, U256::from(1))
+                .unwrap(),
+            U256::from(3)
+        );
+    }
+}
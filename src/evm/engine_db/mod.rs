@@ -2,16 +2,15 @@ use std::{collections::HashMap, fmt::Debug};
 
 use alloy_primitives::Address;
 use lazy_static::lazy_static;
-use revm::{
-    primitives::{AccountInfo, KECCAK_EMPTY},
-    DatabaseRef,
-};
+use revm::DatabaseRef;
 
 use crate::{
     evm::{
         engine_db::{
-            engine_db_interface::EngineDatabaseInterface, simulation_db::BlockHeader,
-            tycho_db::PreCachedDB,
+            account_builder::AccountBuilder,
+            engine_db_interface::EngineDatabaseInterface,
+            simulation_db::BlockHeader,
+            tycho_db::{FailedUpdate, PreCachedDB},
         },
         simulation::SimulationEngine,
         tycho_models::{AccountUpdate, ChangeType, ResponseAccount},
@@ -19,8 +18,12 @@ use crate::{
     protocol::errors::SimulationError,
 };
 
+pub mod account_builder;
 pub mod engine_db_interface;
+pub mod history;
 pub mod simulation_db;
+pub mod state_dump;
+pub mod throttle;
 pub mod tycho_db;
 
 lazy_static! {
@@ -30,6 +33,9 @@ lazy_static! {
 
 /// Creates a simulation engine.
 ///
+/// The engine is gasless, see `SimulationEngine::with_gasless`: like `eth_call`, its simulations
+/// don't pay gas, so they succeed at blocks with a base fee without funding the caller.
+///
 /// # Parameters
 ///
 /// - `trace`: Whether to trace calls. Only meant for debugging purposes, might print a lot of data
@@ -42,41 +48,36 @@ where
     <D as EngineDatabaseInterface>::Error: Debug,
     <D as DatabaseRef>::Error: Debug,
 {
-    let engine = SimulationEngine::new(db.clone(), trace);
-
-    let zero_account_info =
-        AccountInfo { balance: Default::default(), nonce: 0, code_hash: KECCAK_EMPTY, code: None };
+    let engine = SimulationEngine::new(db.clone(), trace).with_gasless();
 
     // Accounts necessary for enabling pre-compilation are initialized by default.
-    engine.state.init_account(
-        Address::from_slice(
-            &hex::decode("0000000000000000000000000000000000000000")
-                .expect("Invalid string for precompile-enabling address"),
-        ),
-        zero_account_info.clone(),
-        None,
-        false,
-    );
-
-    engine.state.init_account(
-        Address::from_slice(
-            &hex::decode("0000000000000000000000000000000000000004")
-                .expect("Invalid string for precompile-enabling address"),
-        ),
-        zero_account_info.clone(),
-        None,
-        false,
-    );
+    engine.state.init_accounts([
+        AccountBuilder::new(Address::ZERO),
+        AccountBuilder::new(Address::with_last_byte(4)),
+    ]);
 
     Ok(engine)
 }
 
+/// Outcome of `update_engine`.
+#[derive(Debug, Default)]
+pub struct EngineUpdateReport {
+    /// Updates applied to the database
+    pub applied: Vec<AccountUpdate>,
+    /// Updates that could not be applied and left their account untouched
+    pub failed: Vec<FailedUpdate>,
+}
+
+/// Applies account snapshots and updates to `db`.
+///
+/// Updates are applied per account: an update that fails to apply, e.g. because of malformed
+/// code, is reported in the returned `EngineUpdateReport` without affecting the other accounts.
 pub async fn update_engine(
     db: PreCachedDB,
     block: BlockHeader,
     vm_storage: Option<HashMap<Address, ResponseAccount>>,
     account_updates: HashMap<Address, AccountUpdate>,
-) -> Vec<AccountUpdate> {
+) -> EngineUpdateReport {
     let mut vm_updates: Vec<AccountUpdate> = Vec::new();
 
     for (_address, account_update) in account_updates.iter() {
@@ -97,9 +98,18 @@ pub async fn update_engine(
         }
     }
 
-    if !vm_updates.is_empty() {
-        db.update(vm_updates.clone(), Some(block));
+    if vm_updates.is_empty() {
+        return EngineUpdateReport::default();
     }
 
-    vm_updates
+    let failed = db.update(vm_updates.clone(), Some(block));
+    let applied = vm_updates
+        .into_iter()
+        .filter(|update| {
+            !failed
+                .iter()
+                .any(|failure| failure.update.address == update.address)
+        })
+        .collect();
+    EngineUpdateReport { applied, failed }
 }
@@ -4,21 +4,35 @@ use std::{
     sync::{Arc, RwLock},
 };
 
-use alloy::providers::Provider;
+use alloy::{
+    eips::{BlockId, BlockNumberOrTag},
+    providers::{Provider, ProviderBuilder, RootProvider},
+    transports::BoxTransport,
+};
 use alloy_primitives::StorageValue;
 use revm::{
     db::DatabaseRef,
     interpreter::analysis::to_analysed,
-    primitives::{AccountInfo, Address, Bytecode, B256, U256},
+    primitives::{AccountInfo, Address, BlockEnv, Bytecode, B256, U256},
 };
-use tracing::{debug, info};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
 
 use super::{
-    super::account_storage::{AccountStorage, StateUpdate},
+    super::account_storage::{original_code, AccountStorage, MissingStoragePolicy, StateUpdate},
     engine_db_interface::EngineDatabaseInterface,
+    throttle::{CircuitBreaker, CircuitBreakerConfig, ProviderMetrics, RateLimiter, SingleFlight},
+};
+use crate::{
+    evm::{tycho_models::Chain, StorageMap},
+    protocol::models::BlockInfo,
 };
 
 /// A wrapper over an actual SimulationDB that allows overriding specific storage slots
+///
+/// Overrides only provide the initial value of a slot: the EVM loads every slot once per
+/// simulation and keeps later writes in its journal, so all calls of a simulation, nested ones
+/// included, read the values written earlier in the simulation.
 pub struct OverriddenSimulationDB<'a, DB: DatabaseRef> {
     /// Wrapped database. Will be queried if a requested item is not found in the overrides.
     pub inner_db: &'a DB,
@@ -77,22 +91,84 @@ impl<DB: DatabaseRef> DatabaseRef for OverriddenSimulationDB<'_, DB> {
     }
 }
 
-#[derive(Debug, Clone, Copy, Eq, Hash, PartialEq, Default)]
+/// The block simulations run in.
+///
+/// Sets the block context of the EVM, see `block_env`, and identifies the block states were
+/// loaded at.
+#[derive(Debug, Clone, Copy, Eq, Hash, PartialEq, Default, Serialize, Deserialize)]
 pub struct BlockHeader {
     pub number: u64,
     pub hash: B256,
+    pub parent_hash: B256,
     pub timestamp: u64,
+    /// Base fee per gas, `None` if unknown, e.g. for blocks received from Tycho
+    pub basefee: Option<u64>,
+    pub chain: Chain,
+}
+
+impl BlockHeader {
+    /// Returns the EVM block environment of this block. Unknown fields keep revm's defaults.
+    pub fn block_env(&self) -> BlockEnv {
+        BlockEnv {
+            number: U256::from(self.number),
+            timestamp: U256::from(self.timestamp),
+            basefee: U256::from(self.basefee.unwrap_or_default()),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<&BlockHeader> for BlockInfo {
+    fn from(header: &BlockHeader) -> Self {
+        BlockInfo {
+            number: header.number,
+            hash: tycho_core::Bytes::from(header.hash.to_vec()),
+            timestamp: header.timestamp,
+        }
+    }
+}
+
+/// Number of block hashes kept in the cache. `BLOCKHASH` only returns non-zero values for the 256
+/// most recent blocks.
+const BLOCK_HASH_CACHE_SIZE: usize = 256;
+
+/// A `SimulationDB` connected to a node by URL, see `SimulationDB::connect`.
+pub type RpcDB = SimulationDB<RootProvider<BoxTransport>>;
+
+/// A node the database sends requests to, with the breaker tracking its health.
+#[derive(Clone, Debug)]
+struct Endpoint<P> {
+    client: Arc<P>,
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl<P> Endpoint<P> {
+    fn new(client: Arc<P>, config: CircuitBreakerConfig) -> Self {
+        Self { client, breaker: Arc::new(CircuitBreaker::new(config)) }
+    }
 }
 
 /// A wrapper over an Alloy Provider with local storage cache and overrides.
+///
+/// Node requests go to the first node whose circuit breaker is closed, see
+/// `SimulationDB::with_fallbacks`.
 #[derive(Clone, Debug)]
 pub struct SimulationDB<P: Provider + Debug> {
-    /// Client to connect to the RPC
-    client: Arc<P>,
+    /// Clients to connect to the RPC, in order of preference
+    endpoints: Vec<Endpoint<P>>,
+    breaker_config: CircuitBreakerConfig,
     /// Cached data
     account_storage: Arc<RwLock<AccountStorage>>,
+    /// Cached block hashes, fetched from the node
+    block_hashes: Arc<RwLock<HashMap<u64, B256>>>,
+    /// Block hashes set manually, take precedence over the node
+    block_hash_overrides: Arc<RwLock<HashMap<u64, B256>>>,
     /// Current block
     block: Option<BlockHeader>,
+    /// Storage requests in flight, by account, slot and block
+    storage_requests: Arc<SingleFlight<(Address, U256, BlockId), U256>>,
+    /// Limits the rate of node requests, shared by all clones
+    rate_limiter: Option<Arc<RateLimiter>>,
     /// Tokio runtime to execute async code
     pub runtime: Option<Arc<tokio::runtime::Runtime>>,
 }
@@ -103,14 +179,128 @@ impl<P: Provider + Debug + 'static> SimulationDB<P> {
         runtime: Option<Arc<tokio::runtime::Runtime>>,
         block: Option<BlockHeader>,
     ) -> Self {
+        let breaker_config = CircuitBreakerConfig::default();
         Self {
-            client,
+            endpoints: vec![Endpoint::new(client, breaker_config)],
+            breaker_config,
             account_storage: Arc::new(RwLock::new(AccountStorage::new())),
+            block_hashes: Arc::new(RwLock::new(HashMap::new())),
+            block_hash_overrides: Arc::new(RwLock::new(HashMap::new())),
             block,
+            storage_requests: Arc::new(SingleFlight::new()),
+            rate_limiter: None,
             runtime,
         }
     }
 
+    /// Limits the requests sent to the node to `requests_per_second`, across all clones of this
+    /// database.
+    pub fn with_rate_limit(mut self, requests_per_second: u32) -> Self {
+        self.rate_limiter = Some(Arc::new(RateLimiter::new(requests_per_second)));
+        self
+    }
+
+    /// Adds nodes to fail over to, in order of preference, when the previous ones fail. Each node
+    /// has its own circuit breaker, see `SimulationDB::with_circuit_breaker`.
+    pub fn with_fallbacks(mut self, clients: impl IntoIterator<Item = Arc<P>>) -> Self {
+        let config = self.breaker_config;
+        self.endpoints.extend(
+            clients
+                .into_iter()
+                .map(|client| Endpoint::new(client, config)),
+        );
+        self
+    }
+
+    /// Sets when the circuit breakers of the nodes open and how long they stay open. Resets the
+    /// breakers.
+    pub fn with_circuit_breaker(mut self, config: CircuitBreakerConfig) -> Self {
+        self.breaker_config = config;
+        for endpoint in &mut self.endpoints {
+            endpoint.breaker = Arc::new(CircuitBreaker::new(config));
+        }
+        self
+    }
+
+    /// Request counters and circuit breaker state of each node, in order of preference.
+    pub fn provider_metrics(&self) -> Vec<ProviderMetrics> {
+        self.endpoints
+            .iter()
+            .map(|endpoint| endpoint.breaker.metrics())
+            .collect()
+    }
+
+    /// Sends a request to the first node whose circuit breaker allows it, failing over to the next
+    /// node if it fails. Returns the error of the last node tried if all fail.
+    fn request<T, E, F, Fut>(&self, request: F) -> Result<T, <Self as DatabaseRef>::Error>
+    where
+        F: Fn(Arc<P>) -> Fut,
+        Fut: core::future::Future<Output = Result<T, E>>,
+        E: Into<<Self as DatabaseRef>::Error>,
+    {
+        let mut last_error = None;
+        for (index, endpoint) in self.endpoints.iter().enumerate() {
+            if !endpoint.breaker.allow() {
+                continue;
+            }
+            match self.block_on(request(endpoint.client.clone())) {
+                Ok(value) => {
+                    endpoint.breaker.record_success();
+                    return Ok(value);
+                }
+                Err(e) => {
+                    let e: <Self as DatabaseRef>::Error = e.into();
+                    warn!(endpoint = index, error = %e, "Node request failed");
+                    endpoint.breaker.record_failure();
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| "All nodes are unavailable".into()))
+    }
+
+    /// Blocks until `requests` more requests may be sent to the node.
+    fn throttle(&self, requests: u32) {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire(requests);
+        }
+    }
+
+    /// Sets the hash returned by `BLOCKHASH` for the given block number, instead of querying a
+    /// node. Useful for deterministic tests.
+    pub fn set_block_hash_override(&self, number: u64, hash: B256) {
+        self.block_hash_overrides
+            .write()
+            .unwrap()
+            .insert(number, hash);
+    }
+
+    /// Removes all block hash overrides.
+    pub fn clear_block_hash_overrides(&self) {
+        self.block_hash_overrides
+            .write()
+            .unwrap()
+            .clear();
+    }
+
+    /// Sets what reads of slots without a value return for a mocked account, see
+    /// `MissingStoragePolicy`. Returns `false` if the account is unknown.
+    pub fn set_missing_storage_policy(
+        &self,
+        address: &Address,
+        policy: MissingStoragePolicy,
+    ) -> bool {
+        self.account_storage
+            .write()
+            .unwrap()
+            .set_missing_storage_policy(address, policy)
+    }
+
+    /// Returns the block used when querying the node.
+    pub fn block(&self) -> Option<BlockHeader> {
+        self.block
+    }
+
     /// Set the block that will be used when querying a node
     pub fn set_block(&mut self, block: Option<BlockHeader>) {
         self.block = block;
@@ -129,11 +319,11 @@ impl<P: Provider + Debug + 'static> SimulationDB<P> {
     /// Returns a state update struct to revert this update.
     pub fn update_state(
         &mut self,
-        updates: &HashMap<Address, StateUpdate>,
+        updates: &StorageMap<Address, StateUpdate>,
         block: BlockHeader,
-    ) -> HashMap<Address, StateUpdate> {
+    ) -> StorageMap<Address, StateUpdate> {
         info!("Received account state update.");
-        let mut revert_updates = HashMap::new();
+        let mut revert_updates = StorageMap::default();
         self.block = Some(block);
         for (address, update_info) in updates.iter() {
             let mut revert_entry = StateUpdate::default();
@@ -143,10 +333,19 @@ impl<P: Provider + Debug + 'static> SimulationDB<P> {
                 .unwrap()
                 .get_account_info(address)
             {
-                revert_entry.balance = Some(current_account.balance);
+                revert_entry.balance = update_info
+                    .balance
+                    .map(|_| current_account.balance);
+                revert_entry.code = update_info
+                    .code
+                    .as_ref()
+                    .map(|_| original_code(current_account));
+                revert_entry.nonce = update_info
+                    .nonce
+                    .map(|_| current_account.nonce);
             }
             if update_info.storage.is_some() {
-                let mut revert_storage = HashMap::default();
+                let mut revert_storage = StorageMap::default();
                 for index in update_info
                     .storage
                     .as_ref()
@@ -174,6 +373,39 @@ impl<P: Provider + Debug + 'static> SimulationDB<P> {
         revert_updates
     }
 
+    /// Commits the state updates of simulations as the new baseline for following simulations,
+    /// e.g. to simulate a sequence of transactions.
+    ///
+    /// Unlike `update_state`, accounts that aren't cached yet are loaded from the node first, so
+    /// updates of them aren't dropped, and accounts created by the simulations are added. Slots
+    /// cached by previous simulations are cleared, as they would shadow the committed values.
+    ///
+    /// # Arguments
+    ///
+    /// * `updates` - State updates of simulation results, see `SimulationResult::state_updates`
+    /// * `block` - The block the updated state belongs to
+    ///
+    /// Returns a state update struct to revert this update.
+    ///
+    /// # Errors
+    ///
+    /// Fails if an account can't be loaded from the node, nothing is applied in that case.
+    pub fn apply_state_updates(
+        &mut self,
+        updates: &StorageMap<Address, StateUpdate>,
+        block: BlockHeader,
+    ) -> Result<StorageMap<Address, StateUpdate>, <Self as DatabaseRef>::Error>
+    where
+        P: Send + Sync,
+    {
+        for address in updates.keys() {
+            self.basic_ref(*address)?;
+        }
+        let revert_updates = self.update_state(updates, block);
+        self.clear_temp_storage();
+        Ok(revert_updates)
+    }
+
     /// Query information about an Ethereum account.
     /// Gets account information not including storage.
     ///
@@ -191,28 +423,30 @@ impl<P: Provider + Debug + 'static> SimulationDB<P> {
     ) -> Result<AccountInfo, <SimulationDB<P> as DatabaseRef>::Error> {
         debug!("Querying account info of {:x?} at block {:?}", address, self.block);
 
-        let (balance, nonce, code) = self.block_on(async {
-            let mut balance_request = self.client.get_balance(address);
-            let mut nonce_request = self
-                .client
-                .get_transaction_count(address);
-            let mut code_request = self.client.get_code_at(address);
-
-            if let Some(block) = &self.block {
-                balance_request = balance_request.number(block.number);
-                nonce_request = nonce_request.number(block.number);
-                code_request = code_request.number(block.number);
-            }
-
-            tokio::join!(balance_request, nonce_request, code_request,)
-        });
-        let code = to_analysed(Bytecode::new_raw(revm::primitives::Bytes::copy_from_slice(&code?)));
-
-        Ok(AccountInfo::new(balance?, nonce?, code.hash_slow(), code))
+        self.throttle(3);
+        let block_id = pinned_block_id(self.block.as_ref());
+        let (balance, nonce, code) = self.request(|client| async move {
+            let balance_request = client
+                .get_balance(address)
+                .block_id(block_id);
+            let nonce_request = client
+                .get_transaction_count(address)
+                .block_id(block_id);
+            let code_request = client
+                .get_code_at(address)
+                .block_id(block_id);
+
+            tokio::try_join!(balance_request, nonce_request, code_request)
+        })?;
+        let code = to_analysed(Bytecode::new_raw(revm::primitives::Bytes::copy_from_slice(&code)));
+
+        Ok(AccountInfo::new(balance, nonce, code.hash_slow(), code))
     }
 
     /// Queries a value from storage at the specified index for a given Ethereum account.
     ///
+    /// Concurrent queries of the same slot are coalesced into a single request.
+    ///
     /// # Arguments
     ///
     /// * `address` - The Ethereum address of the account.
@@ -227,17 +461,40 @@ impl<P: Provider + Debug + 'static> SimulationDB<P> {
         address: Address,
         index: U256,
     ) -> Result<StorageValue, <SimulationDB<P> as DatabaseRef>::Error> {
-        let storage = self.block_on(async {
-            let mut request = self
-                .client
-                .get_storage_at(address, index);
-            if let Some(block) = &self.block {
-                request = request.number(block.number);
-            }
-            request.await.unwrap()
-        });
+        let block_id = pinned_block_id(self.block.as_ref());
+        self.storage_requests
+            .run((address, index, block_id), || {
+                self.throttle(1);
+                self.request(|client| async move {
+                    client
+                        .get_storage_at(address, index)
+                        .block_id(block_id)
+                        .await
+                })
+            })
+    }
+
+    /// Queries the hash of the block with the given number.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing the block hash, or a zero hash if the node doesn't know the
+    /// block, or an error of type `SimulationDB<M>::Error` if the query fails.
+    pub fn query_block_hash(
+        &self,
+        number: u64,
+    ) -> Result<B256, <SimulationDB<P> as DatabaseRef>::Error> {
+        debug!("Querying hash of block {}", number);
+        self.throttle(1);
+        let block = self.request(|client| async move {
+            client
+                .get_block_by_number(BlockNumberOrTag::Number(number), false)
+                .await
+        })?;
 
-        Ok(storage)
+        Ok(block
+            .map(|block| block.header.hash)
+            .unwrap_or(B256::ZERO))
     }
 
     fn block_on<F: core::future::Future>(&self, f: F) -> F::Output {
@@ -251,6 +508,56 @@ impl<P: Provider + Debug + 'static> SimulationDB<P> {
     }
 }
 
+/// The block node requests are pinned to.
+///
+/// Requests are pinned by hash if known, so all data of a simulation comes from the same block
+/// even if the node reorgs or moves on while the simulation fetches it. Without a block, requests
+/// go to the latest block.
+fn pinned_block_id(block: Option<&BlockHeader>) -> BlockId {
+    match block {
+        Some(block) if block.hash != B256::ZERO => BlockId::hash(block.hash),
+        Some(block) => BlockId::number(block.number),
+        None => BlockId::latest(),
+    }
+}
+
+impl RpcDB {
+    /// Connects to the node at `rpc_url` and pins the database to block `number`, or to the latest
+    /// block if `None`.
+    ///
+    /// The database owns a runtime to send node requests on and blocks on them, so it must be
+    /// created and used outside of an async runtime.
+    pub fn connect(
+        rpc_url: &str,
+        number: Option<u64>,
+    ) -> Result<Self, <Self as DatabaseRef>::Error> {
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return Err("RpcDB can't be created from within an async runtime".into());
+        }
+        let runtime = Arc::new(tokio::runtime::Runtime::new()?);
+        let tag = number.map_or(BlockNumberOrTag::Latest, BlockNumberOrTag::Number);
+        let (client, block) = runtime.block_on(async {
+            let client = ProviderBuilder::new()
+                .on_builtin(rpc_url)
+                .await?;
+            let block = client
+                .get_block_by_number(tag, false)
+                .await?
+                .ok_or_else(|| format!("Block {} not found", tag))?;
+            Ok::<_, <Self as DatabaseRef>::Error>((client, block))
+        })?;
+        let block = BlockHeader {
+            number: block.header.number,
+            hash: block.header.hash,
+            parent_hash: block.header.parent_hash,
+            timestamp: block.header.timestamp,
+            basefee: block.header.base_fee_per_gas,
+            ..Default::default()
+        };
+        Ok(Self::new(Arc::new(client), Some(runtime), Some(block)))
+    }
+}
+
 impl<P: Provider + Debug> EngineDatabaseInterface for SimulationDB<P>
 where
     P: Provider + Send + Sync + 'static,
@@ -296,6 +603,10 @@ where
             .unwrap()
             .clear_temp_storage();
     }
+
+    fn block_header(&self) -> Option<BlockHeader> {
+        self.block
+    }
 }
 
 impl<P: Provider> DatabaseRef for SimulationDB<P>
@@ -377,7 +688,8 @@ where
     /// * If the contract is present locally and is mocked, the function first checks if the storage
     ///   value exists locally. If found, it returns the stored value. If not found, it returns an
     ///   empty slot. Mocked contracts are not expected to have valid storage values, so the
-    ///   function does not query a node in this case.
+    ///   function does not query a node in this case. The account's `MissingStoragePolicy` can
+    ///   change this to query a node or to return an error instead.
     ///
     /// * If the contract is present locally and is not mocked, the function checks if the storage
     ///   value exists locally. If found, it returns the stored value. If not found, it queries the
@@ -389,10 +701,14 @@ where
     fn storage_ref(&self, address: Address, index: U256) -> Result<U256, Self::Error> {
         debug!("Requested storage of account {:x?} slot {}", address, index);
         let is_mocked; // will be None if we don't have this account at all
+        let missing_storage;
         {
             let account_storage = self.account_storage.read().unwrap();
             // This scope is to not make two simultaneous borrows
             is_mocked = account_storage.is_mocked_account(&address);
+            missing_storage = account_storage
+                .missing_storage_policy(&address)
+                .unwrap_or_default();
             if let Some(storage_value) = account_storage.get_storage(&address, &index) {
                 debug!(
                     "Got value locally. This is a {} account. Value: {}",
@@ -403,12 +719,15 @@ where
             }
         }
         // At this point we know we don't have data for this storage slot.
-        match is_mocked {
-            Some(true) => {
+        match (is_mocked, missing_storage) {
+            (Some(true), MissingStoragePolicy::ZeroDefault) => {
                 debug!("This is a mocked account for which we don't have data. Returning zero.");
                 Ok(U256::ZERO)
             }
-            Some(false) => {
+            (Some(true), MissingStoragePolicy::Error) => {
+                Err(format!("Missing storage slot {} of mocked account {}", index, address).into())
+            }
+            (Some(_), _) => {
                 let storage_value = self.query_storage(address, index)?;
                 let mut account_storage = self.account_storage.write().unwrap();
 
@@ -419,7 +738,7 @@ where
                 );
                 Ok(storage_value)
             }
-            None => {
+            (None, _) => {
                 let account_info = self.query_account_info(address)?;
                 let storage_value = self.query_storage(address, index)?;
                 self.init_account(address, account_info, None, false);
@@ -431,19 +750,51 @@ where
         }
     }
 
-    /// If block header is set, returns the hash. Otherwise returns a zero hash
-    /// instead of querying a node.
-    fn block_hash_ref(&self, _number: u64) -> Result<B256, Self::Error> {
-        match &self.block {
-            Some(header) => Ok(header.hash),
-            None => Ok(B256::ZERO),
+    /// Retrieves the hash of the block with the given number.
+    ///
+    /// Overrides set with `set_block_hash_override` take precedence. If the number is the one of
+    /// the current block header, its hash is returned. Otherwise the hash is served from the cache
+    /// or queried from a node and cached. The cache holds the hashes of the last
+    /// `BLOCK_HASH_CACHE_SIZE` requested blocks.
+    fn block_hash_ref(&self, number: u64) -> Result<B256, Self::Error> {
+        if let Some(hash) = self
+            .block_hash_overrides
+            .read()
+            .unwrap()
+            .get(&number)
+        {
+            return Ok(*hash);
+        }
+        if let Some(header) = self
+            .block
+            .filter(|header| header.number == number)
+        {
+            return Ok(header.hash);
+        }
+        if let Some(hash) = self
+            .block_hashes
+            .read()
+            .unwrap()
+            .get(&number)
+        {
+            return Ok(*hash);
         }
+
+        let hash = self.query_block_hash(number)?;
+        let mut block_hashes = self.block_hashes.write().unwrap();
+        if block_hashes.len() >= BLOCK_HASH_CACHE_SIZE {
+            if let Some(oldest) = block_hashes.keys().min().copied() {
+                block_hashes.remove(&oldest);
+            }
+        }
+        block_hashes.insert(number, hash);
+        Ok(hash)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{env, error::Error, str::FromStr};
+    use std::{env, error::Error, str::FromStr, time::Duration};
 
     use alloy::{
         providers::{ProviderBuilder, RootProvider},
@@ -454,6 +805,7 @@ mod tests {
     use tokio::runtime::Runtime;
 
     use super::*;
+    use crate::evm::engine_db::throttle::CircuitState;
 
     fn get_runtime() -> Option<Arc<Runtime>> {
         let runtime = tokio::runtime::Handle::try_current()
@@ -478,6 +830,51 @@ mod tests {
         Arc::new(client)
     }
 
+    #[test]
+    fn test_pinned_block_id() {
+        let mut block = BlockHeader { number: 7, ..Default::default() };
+
+        assert_eq!(pinned_block_id(None), BlockId::latest());
+        assert_eq!(pinned_block_id(Some(&block)), BlockId::number(7));
+        block.hash = B256::repeat_byte(0x01);
+        assert_eq!(pinned_block_id(Some(&block)), BlockId::hash(B256::repeat_byte(0x01)));
+    }
+
+    #[test]
+    fn test_failover_opens_circuit_breakers() {
+        let runtime = get_runtime().unwrap();
+        // Nothing listens on port 1, so requests fail fast
+        let dead_client = || {
+            Arc::new(
+                runtime
+                    .block_on(ProviderBuilder::new().on_builtin("http://127.0.0.1:1"))
+                    .unwrap(),
+            )
+        };
+        let db = SimulationDB::new(dead_client(), Some(runtime.clone()), None)
+            .with_fallbacks([dead_client()])
+            .with_circuit_breaker(CircuitBreakerConfig {
+                failure_threshold: 1,
+                cooldown: Duration::from_secs(60),
+            });
+
+        // Both nodes are tried and their breakers open
+        assert!(db.query_block_hash(1).is_err());
+        let metrics = db.provider_metrics();
+        assert_eq!(metrics.len(), 2);
+        assert!(metrics
+            .iter()
+            .all(|m| m.state == CircuitState::Open && m.failures == 1));
+
+        // No node is tried until the cooldown passed
+        let err = db.query_block_hash(1).unwrap_err();
+        assert_eq!(err.to_string(), "All nodes are unavailable");
+        assert!(db
+            .provider_metrics()
+            .iter()
+            .all(|m| m.requests == 1 && m.rejected == 1));
+    }
+
     #[rstest]
     fn test_query_storage_latest_block() -> Result<(), Box<dyn Error>> {
         let db = SimulationDB::new(get_client(), get_runtime(), None);
@@ -504,6 +901,7 @@ mod tests {
             )
             .unwrap(),
             timestamp: 234,
+            ..Default::default()
         };
         db.set_block(Some(block));
         let address = Address::from_str("0x168b93113fe5902c87afaecE348581A1481d0f93").unwrap();
@@ -515,6 +913,41 @@ mod tests {
         assert_eq!(account_info.nonce, 17);
     }
 
+    #[rstest]
+    fn test_block_hash_ref() {
+        let db = SimulationDB::new(get_client(), get_runtime(), None);
+        let number = 20308186;
+
+        let hash = db.block_hash_ref(number).unwrap();
+
+        assert_eq!(
+            hash,
+            B256::from_str("0x61c51e3640b02ae58a03201be0271e84e02dac8a4826501995cbe4da24174b52")
+                .unwrap()
+        );
+        assert_eq!(
+            db.block_hashes
+                .read()
+                .unwrap()
+                .get(&number),
+            Some(&hash)
+        );
+    }
+
+    #[rstest]
+    fn test_block_hash_override() {
+        let db = SimulationDB::new(get_client(), get_runtime(), None);
+        let hash = B256::repeat_byte(0x11);
+        db.set_block_hash_override(20308186, hash);
+
+        assert_eq!(db.block_hash_ref(20308186).unwrap(), hash);
+        assert!(db
+            .block_hashes
+            .read()
+            .unwrap()
+            .is_empty());
+    }
+
     #[rstest]
     fn test_mock_account_get_acc_info() {
         let db = SimulationDB::new(get_client(), get_runtime(), None);
@@ -552,20 +985,74 @@ mod tests {
         assert_eq!(storage, U256::ZERO);
     }
 
+    #[test]
+    fn test_mock_account_missing_storage_policy() {
+        let runtime = get_runtime().unwrap();
+        // Nothing listens on port 1, so fetching from the node fails
+        let client = runtime
+            .block_on(ProviderBuilder::new().on_builtin("http://127.0.0.1:1"))
+            .unwrap();
+        let db = SimulationDB::new(Arc::new(client), Some(runtime), None);
+        let address = Address::repeat_byte(0x01);
+        db.init_account(
+            address,
+            AccountInfo::default(),
+            Some(HashMap::from([(U256::from(1), U256::from(10))])),
+            true,
+        );
+
+        assert_eq!(
+            db.storage_ref(address, U256::from(2))
+                .unwrap(),
+            U256::ZERO
+        );
+
+        assert!(db.set_missing_storage_policy(&address, MissingStoragePolicy::Error));
+        assert_eq!(
+            db.storage_ref(address, U256::from(1))
+                .unwrap(),
+            U256::from(10)
+        );
+        let err = db
+            .storage_ref(address, U256::from(2))
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Missing storage slot"));
+
+        // The slot is fetched from the node, which fails differently
+        assert!(db.set_missing_storage_policy(&address, MissingStoragePolicy::FetchFromChain));
+        let err = db
+            .storage_ref(address, U256::from(2))
+            .unwrap_err();
+        assert!(!err
+            .to_string()
+            .starts_with("Missing storage slot"));
+
+        assert!(!db
+            .set_missing_storage_policy(&Address::repeat_byte(0x02), MissingStoragePolicy::Error));
+    }
+
     #[rstest]
     fn test_update_state() {
         let mut db = SimulationDB::new(get_client(), get_runtime(), None);
         let address = Address::from_str("0xb4e16d0168e52d35cacd2c6185b44281ec28c9dc").unwrap();
         db.init_account(address, AccountInfo::default(), None, false);
 
-        let mut new_storage = HashMap::default();
+        let mut new_storage = StorageMap::default();
         let new_storage_value_index = U256::from_limbs_slice(&[123]);
         new_storage.insert(new_storage_value_index, new_storage_value_index);
         let new_balance = U256::from_limbs_slice(&[500]);
-        let update = StateUpdate { storage: Some(new_storage), balance: Some(new_balance) };
-        let mut updates = HashMap::default();
+        let update = StateUpdate {
+            storage: Some(new_storage),
+            balance: Some(new_balance),
+            code: None,
+            nonce: None,
+        };
+        let mut updates = StorageMap::default();
         updates.insert(address, update);
-        let new_block = BlockHeader { number: 1, hash: B256::default(), timestamp: 234 };
+        let new_block =
+            BlockHeader { number: 1, hash: B256::default(), timestamp: 234, ..Default::default() };
 
         let reverse_update = db.update_state(&updates, new_block);
 
@@ -601,7 +1088,7 @@ mod tests {
                 .get(&address)
                 .unwrap()
                 .storage,
-            Some(HashMap::default())
+            Some(StorageMap::default())
         );
     }
 
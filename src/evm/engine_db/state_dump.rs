@@ -0,0 +1,216 @@
+//! Import of local dev node state dumps
+//!
+//! Reads the state written by `anvil --dump-state` (or `anvil_dumpState`) into an engine database,
+//! so pools deployed on a local dev node can be quoted with the same code used in production. A
+//! plain map of accounts, as used by hardhat and genesis `alloc` sections, is accepted as well.
+use std::{collections::HashMap, fs::File, io::BufReader, path::Path};
+
+use alloy_primitives::{Address, Bytes, B256, U256};
+use serde::{Deserialize, Deserializer};
+use thiserror::Error;
+
+use super::{
+    account_builder::AccountBuilder, engine_db_interface::EngineDatabaseInterface,
+    simulation_db::BlockHeader,
+};
+
+#[derive(Error, Debug)]
+pub enum StateDumpError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid state dump: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// An account of a state dump.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct DumpAccount {
+    #[serde(default, deserialize_with = "deserialize_quantity")]
+    pub nonce: u64,
+    #[serde(default)]
+    pub balance: U256,
+    #[serde(default)]
+    pub code: Bytes,
+    #[serde(default)]
+    pub storage: HashMap<U256, U256>,
+}
+
+/// Block environment of an anvil state dump.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+struct DumpBlock {
+    number: U256,
+    timestamp: U256,
+    #[serde(default)]
+    basefee: Option<U256>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DumpFormat {
+    Anvil { block: Option<DumpBlock>, accounts: HashMap<Address, DumpAccount> },
+    Alloc(HashMap<Address, DumpAccount>),
+}
+
+/// State of a local dev node.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StateDump {
+    /// Block the state was dumped at, if recorded. Dumps don't contain block hashes, so the hash
+    /// is always zero.
+    pub block: Option<BlockHeader>,
+    pub accounts: HashMap<Address, DumpAccount>,
+}
+
+impl StateDump {
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, StateDumpError> {
+        let format: DumpFormat = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        Ok(format.into())
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, StateDumpError> {
+        let format: DumpFormat = serde_json::from_str(json)?;
+        Ok(format.into())
+    }
+
+    /// Initializes all accounts of the dump in `db`.
+    ///
+    /// Accounts are initialized as mocked: the dump is the complete state of the dev node, so
+    /// missing storage slots are zero and are never fetched from a node.
+    pub fn init<D: EngineDatabaseInterface>(&self, db: &D) {
+        db.init_accounts(
+            self.accounts
+                .iter()
+                .map(|(address, account)| {
+                    let mut builder = AccountBuilder::new(*address)
+                        .balance(account.balance)
+                        .nonce(account.nonce)
+                        .empty_storage()
+                        .mocked();
+                    if !account.code.is_empty() {
+                        builder = builder.code(account.code.clone());
+                    }
+                    account
+                        .storage
+                        .iter()
+                        .fold(builder, |builder, (slot, value)| builder.storage(*slot, *value))
+                }),
+        );
+    }
+}
+
+impl From<DumpFormat> for StateDump {
+    fn from(format: DumpFormat) -> Self {
+        match format {
+            DumpFormat::Anvil { block, accounts } => StateDump {
+                block: block.map(|block| BlockHeader {
+                    number: block.number.saturating_to(),
+                    hash: B256::ZERO,
+                    timestamp: block.timestamp.saturating_to(),
+                    basefee: block
+                        .basefee
+                        .map(|basefee| basefee.saturating_to()),
+                    ..Default::default()
+                }),
+                accounts,
+            },
+            DumpFormat::Alloc(accounts) => StateDump { block: None, accounts },
+        }
+    }
+}
+
+/// Deserializes a quantity given either as a JSON number or as a hex string.
+fn deserialize_quantity<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Quantity {
+        Number(u64),
+        String(U256),
+    }
+
+    match Quantity::deserialize(deserializer)? {
+        Quantity::Number(n) => Ok(n),
+        Quantity::String(n) => n
+            .try_into()
+            .map_err(|_| serde::de::Error::custom("quantity exceeds u64")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use revm::DatabaseRef;
+
+    use super::*;
+    use crate::evm::engine_db::tycho_db::PreCachedDB;
+
+    const ANVIL_DUMP: &str = r#"{
+        "block": { "number": "0x2a", "coinbase": "0x0000000000000000000000000000000000000000", "timestamp": "0x6553f100", "gas_limit": "0x1c9c380" },
+        "accounts": {
+            "0x5fbdb2315678afecb367f032d93f642f64180aa3": {
+                "nonce": 1,
+                "balance": "0x0",
+                "code": "0x6080604052",
+                "storage": { "0x0": "0x2a", "0x1": "0x0" }
+            },
+            "0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266": {
+                "nonce": "0x3",
+                "balance": "0x21e19e0c9bab2400000",
+                "code": "0x",
+                "storage": {}
+            }
+        },
+        "best_block_number": "0x2a"
+    }"#;
+
+    #[test]
+    fn test_import_anvil_dump() {
+        let dump = StateDump::from_json(ANVIL_DUMP).unwrap();
+        let contract = Address::from_str("0x5fbdb2315678afecb367f032d93f642f64180aa3").unwrap();
+        let deployer = Address::from_str("0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266").unwrap();
+
+        assert_eq!(
+            dump.block,
+            Some(BlockHeader {
+                number: 42,
+                hash: B256::ZERO,
+                timestamp: 1_700_000_000,
+                ..Default::default()
+            })
+        );
+
+        let db = PreCachedDB::new().unwrap();
+        dump.init(&db);
+
+        let info = db.basic_ref(contract).unwrap().unwrap();
+        assert_eq!(info.nonce, 1);
+        assert_eq!(
+            info.code.unwrap().original_bytes(),
+            Bytes::from_static(&[0x60, 0x80, 0x60, 0x40, 0x52])
+        );
+        assert_eq!(
+            db.storage_ref(contract, U256::ZERO)
+                .unwrap(),
+            U256::from(42)
+        );
+        assert_eq!(
+            db.storage_ref(contract, U256::from(2))
+                .unwrap(),
+            U256::ZERO
+        );
+
+        let info = db.basic_ref(deployer).unwrap().unwrap();
+        assert_eq!(info.nonce, 3);
+        assert_eq!(info.balance, U256::from(10_000u64) * U256::from(10u64).pow(U256::from(18)));
+    }
+
+    #[test]
+    fn test_import_alloc() {
+        let dump = StateDump::from_json(
+            r#"{ "0x0000000000000000000000000000000000000001": { "balance": "0x1" } }"#,
+        )
+        .unwrap();
+
+        assert_eq!(dump.block, None);
+        assert_eq!(dump.accounts[&Address::with_last_byte(1)].balance, U256::from(1));
+    }
+}
@@ -0,0 +1,331 @@
+//! Throttling of node requests
+//!
+//! `SingleFlight` coalesces identical concurrent requests into one, `RateLimiter` caps the number
+//! of requests per second. Both are blocking, like the node backed databases using them.
+//!
+//! `CircuitBreaker` tracks the health of a node: after a number of consecutive failed requests it
+//! opens and stops requests to the node for a cooldown. After the cooldown a single probe request
+//! is let through; if it succeeds the breaker closes again, otherwise it stays open for another
+//! cooldown. Databases with several nodes use it to fail over to the next healthy node.
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Coalesces concurrent calls with the same key: the first caller runs the request, callers
+/// arriving while it is in flight wait for it and share its result.
+#[derive(Debug)]
+pub struct SingleFlight<K, V> {
+    in_flight: Mutex<HashMap<K, Arc<Mutex<Option<V>>>>>,
+}
+
+impl<K, V> Default for SingleFlight<K, V> {
+    fn default() -> Self {
+        Self { in_flight: Mutex::new(HashMap::new()) }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> SingleFlight<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `request` unless a request for `key` is already in flight, in which case its result
+    /// is returned instead. If the in-flight request fails, waiting callers run their own request,
+    /// so errors are never shared.
+    pub fn run<E>(&self, key: K, request: impl FnOnce() -> Result<V, E>) -> Result<V, E> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(cell) = in_flight.get(&key).cloned() {
+            drop(in_flight);
+            // Blocks until the leader stored its result
+            if let Some(value) = cell.lock().unwrap().clone() {
+                return Ok(value);
+            }
+            return request();
+        }
+
+        let cell = Arc::new(Mutex::new(None));
+        let mut result_guard = cell.lock().unwrap();
+        in_flight.insert(key.clone(), cell.clone());
+        drop(in_flight);
+
+        let result = request();
+        if let Ok(value) = &result {
+            *result_guard = Some(value.clone());
+        }
+        self.in_flight
+            .lock()
+            .unwrap()
+            .remove(&key);
+        drop(result_guard);
+        result
+    }
+}
+
+/// Limits the rate of requests by spacing them evenly.
+#[derive(Debug)]
+pub struct RateLimiter {
+    interval: Duration,
+    next_slot: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    /// Creates a limiter allowing `requests_per_second` requests per second.
+    ///
+    /// # Panics
+    ///
+    /// If `requests_per_second` is zero.
+    pub fn new(requests_per_second: u32) -> Self {
+        assert!(requests_per_second > 0, "Rate limit must be positive");
+        Self {
+            interval: Duration::from_secs(1) / requests_per_second,
+            next_slot: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Blocks until `requests` more requests may be sent.
+    pub fn acquire(&self, requests: u32) {
+        let wait = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let now = Instant::now();
+            let slot = (*next_slot).max(now);
+            *next_slot = slot + self.interval * requests;
+            slot - now
+        };
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// State of a `CircuitBreaker`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests pass
+    #[default]
+    Closed,
+    /// Requests are rejected until the cooldown passed
+    Open,
+    /// The cooldown passed, a single probe request may pass
+    HalfOpen,
+}
+
+/// Settings of a `CircuitBreaker`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitBreakerConfig {
+    /// Number of consecutive failures opening the breaker
+    pub failure_threshold: u32,
+    /// Time the breaker stays open before probing
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self { failure_threshold: 5, cooldown: Duration::from_secs(30) }
+    }
+}
+
+/// Request counters and state of a node, see `CircuitBreaker::metrics`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProviderMetrics {
+    pub state: CircuitState,
+    /// Number of requests sent
+    pub requests: u64,
+    /// Number of failed requests
+    pub failures: u64,
+    pub consecutive_failures: u32,
+    /// Number of requests rejected while the breaker was open
+    pub rejected: u64,
+    /// Number of times the breaker opened
+    pub times_opened: u64,
+}
+
+#[derive(Debug)]
+struct BreakerState {
+    metrics: ProviderMetrics,
+    opened_at: Option<Instant>,
+    /// Whether the probe of a half-open breaker is in flight
+    probing: bool,
+}
+
+/// Stops requests to a failing node, see the module docs.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(BreakerState {
+                metrics: ProviderMetrics::default(),
+                opened_at: None,
+                probing: false,
+            }),
+        }
+    }
+
+    /// Whether a request may be sent. Moves an open breaker whose cooldown passed to half-open and
+    /// lets its probe through. Rejected requests are counted in the metrics.
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let allowed = match state.metrics.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => {
+                let cooled_down = state
+                    .opened_at
+                    .is_none_or(|opened_at| opened_at.elapsed() >= self.config.cooldown);
+                if cooled_down {
+                    state.metrics.state = CircuitState::HalfOpen;
+                    state.probing = true;
+                }
+                cooled_down
+            }
+            CircuitState::HalfOpen => !std::mem::replace(&mut state.probing, true),
+        };
+        if allowed {
+            state.metrics.requests += 1;
+        } else {
+            state.metrics.rejected += 1;
+        }
+        allowed
+    }
+
+    /// Records a successful request, closing the breaker.
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.metrics.state = CircuitState::Closed;
+        state.metrics.consecutive_failures = 0;
+        state.opened_at = None;
+        state.probing = false;
+    }
+
+    /// Records a failed request. Opens the breaker if the probe failed or the failure threshold is
+    /// reached.
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.metrics.failures += 1;
+        state.metrics.consecutive_failures += 1;
+        state.probing = false;
+        let open = state.metrics.state == CircuitState::HalfOpen ||
+            (state.metrics.state == CircuitState::Closed &&
+                state.metrics.consecutive_failures >= self.config.failure_threshold);
+        if open {
+            state.metrics.state = CircuitState::Open;
+            state.metrics.times_opened += 1;
+            state.opened_at = Some(Instant::now());
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.state.lock().unwrap().metrics.state
+    }
+
+    pub fn metrics(&self) -> ProviderMetrics {
+        self.state.lock().unwrap().metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Barrier,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_single_flight_coalesces() {
+        let flight = Arc::new(SingleFlight::<u32, u64>::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(8));
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let (flight, calls, barrier) = (flight.clone(), calls.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    flight.run(1, || {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(100));
+                        Ok::<_, ()>(42)
+                    })
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), Ok(42));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_single_flight_errors_not_shared() {
+        let flight = SingleFlight::<u32, u64>::new();
+
+        assert_eq!(flight.run(1, || Err("boom")), Err("boom"));
+        assert_eq!(flight.run(1, || Ok::<_, &str>(1)), Ok(1));
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(20);
+        let start = Instant::now();
+
+        for _ in 0..5 {
+            limiter.acquire(1);
+        }
+
+        // The first request passes immediately, the other four are spaced by 50ms
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            failure_threshold: 2,
+            cooldown: Duration::from_millis(50),
+        });
+
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.allow());
+
+        thread::sleep(Duration::from_millis(60));
+        // A single probe passes, and fails
+        assert!(breaker.allow());
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(!breaker.allow());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        thread::sleep(Duration::from_millis(60));
+        assert!(breaker.allow());
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.allow());
+
+        assert_eq!(
+            breaker.metrics(),
+            ProviderMetrics {
+                state: CircuitState::Closed,
+                requests: 5,
+                failures: 3,
+                consecutive_failures: 0,
+                rejected: 2,
+                times_opened: 2,
+            }
+        );
+    }
+}
@@ -1,20 +1,30 @@
 use std::{
-    collections::HashMap,
-    sync::{Arc, RwLock},
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
 };
 
 use alloy_primitives::{Address, B256, U256};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use revm::{
     db::DatabaseRef,
     primitives::{AccountInfo, Bytecode, Bytes},
 };
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tracing::{debug, error, info, instrument, warn};
 
 use crate::evm::{
-    account_storage::{AccountStorage, StateUpdate},
+    account_storage::{original_code, Account, AccountStorage, MissingStoragePolicy, StateUpdate},
     engine_db::{engine_db_interface::EngineDatabaseInterface, simulation_db::BlockHeader},
-    tycho_models::{AccountUpdate, ChangeType},
+    tycho_models::{AccountUpdate, Chain, ChangeType},
+    StorageMap,
 };
 
 /// Perform bytecode analysis on the code of an account.
@@ -47,6 +57,75 @@ pub enum PreCachedDBError {
     BlockNotSet(),
     #[error("Tycho Client error: {0}")]
     TychoClientError(#[from] TychoClientError),
+    #[error("Snapshot IO error: {0}")]
+    SnapshotIo(#[from] std::io::Error),
+    #[error("Invalid snapshot: {0}")]
+    InvalidSnapshot(String),
+    #[error("Invalid update of account {0}: {1}")]
+    InvalidAccountUpdate(Address, String),
+    #[error("Missing storage slot {1} of account {0}")]
+    MissingStorage(Address, U256),
+}
+
+/// An account update that could not be applied, see `PreCachedDB::update`.
+#[derive(Debug)]
+pub struct FailedUpdate {
+    pub update: AccountUpdate,
+    pub error: PreCachedDBError,
+}
+
+/// Magic bytes and format version at the start of every snapshot file.
+const SNAPSHOT_MAGIC: &[u8; 8] = b"TYCHODB\x03";
+
+/// Makes the names of offloaded storage files unique, see `PreCachedDB::offload`.
+static OFFLOAD_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Changes of a single account between two `PreCachedDB` instances.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountDelta {
+    /// New balance, if changed
+    pub balance: Option<U256>,
+    /// New nonce, if changed
+    pub nonce: Option<u64>,
+    /// New runtime code, if changed. Empty if the code was removed.
+    pub code: Option<Bytes>,
+    /// Changed storage slots. Slots removed in the target are set to zero.
+    pub storage: HashMap<U256, U256>,
+}
+
+/// The changes turning one `PreCachedDB` into another, see `PreCachedDB::diff`.
+///
+/// Only contains what differs, so it is much smaller than a snapshot of the target and can be
+/// sent to followers to keep their databases in sync with a leader.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DbDelta {
+    /// Block of the target, if it differs from the base
+    pub block: Option<BlockHeader>,
+    /// Accounts that were created or changed. Created accounts contain their full state.
+    pub accounts: HashMap<Address, AccountDelta>,
+    /// Accounts present in the base but not in the target
+    pub removed: HashSet<Address>,
+}
+
+impl DbDelta {
+    pub fn is_empty(&self) -> bool {
+        self.block.is_none() && self.accounts.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// A file holding the permanent storage of an offloaded account, see `PreCachedDB::offload`.
+///
+/// The file is shared by a database and its checkpoints and is removed once none of them
+/// references it anymore, i.e. once all of them loaded the storage back or dropped the account.
+#[derive(Debug)]
+struct OffloadFile(PathBuf);
+
+impl Drop for OffloadFile {
+    fn drop(&mut self) {
+        if let Err(error) = std::fs::remove_file(&self.0) {
+            warn!(path = %self.0.display(), %error, "Failed to remove offloaded storage file");
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -55,6 +134,46 @@ pub struct PreCachedDBInner {
     accounts: AccountStorage,
     /// Current block
     block: Option<BlockHeader>,
+    /// Files holding the permanent storage of offloaded accounts, see `PreCachedDB::offload`
+    offloaded: HashMap<Address, Arc<OffloadFile>>,
+}
+
+impl PreCachedDBInner {
+    /// Loads the storage of `address` back into memory if it was offloaded.
+    ///
+    /// This reads the file while holding the lock, it's only a fallback for accounts offloaded
+    /// after `PreCachedDB::ensure_all_resident` loaded them.
+    fn rehydrate(&mut self, address: &Address) -> Result<(), PreCachedDBError> {
+        let Some(file) = self.offloaded.get(address).cloned() else {
+            return Ok(());
+        };
+        let storage = read_offloaded_storage(&file.0)?;
+        self.install(address, &file, storage);
+        Ok(())
+    }
+
+    /// Puts the storage read from `file` back into the account, unless the account was loaded
+    /// back or removed since the file was read. Returns whether the storage was installed.
+    fn install(
+        &mut self,
+        address: &Address,
+        file: &Arc<OffloadFile>,
+        storage: StorageMap<U256, U256>,
+    ) -> bool {
+        if !self
+            .offloaded
+            .get(address)
+            .is_some_and(|current| Arc::ptr_eq(current, file))
+        {
+            return false;
+        }
+        if let Some(account) = self.accounts.get_account_mut(address) {
+            account.permanent_storage = Arc::new(storage);
+        }
+        debug!(%address, "Rehydrated offloaded storage");
+        self.offloaded.remove(address);
+        true
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -65,6 +184,8 @@ pub struct PreCachedDB {
     /// exclusive write access to the data and `Arc` for shared ownership of the lock across
     /// threads.
     pub inner: Arc<RwLock<PreCachedDBInner>>,
+    /// Number of offloaded accounts, so that reads can skip the lock if there are none
+    offloaded_count: Arc<AtomicUsize>,
 }
 
 impl PreCachedDB {
@@ -74,64 +195,134 @@ impl PreCachedDB {
             inner: Arc::new(RwLock::new(PreCachedDBInner {
                 accounts: AccountStorage::new(),
                 block: None,
+                offloaded: HashMap::new(),
             })),
+            offloaded_count: Arc::default(),
         })
     }
 
+    /// Applies account updates and sets the current block.
+    ///
+    /// Each update is applied completely or not at all: updates that can't be applied, e.g.
+    /// because their code is malformed or they update an unknown account, are skipped and
+    /// returned, while all other updates are applied.
     #[instrument(skip_all)]
-    pub fn update(&self, account_updates: Vec<AccountUpdate>, block: Option<BlockHeader>) {
+    pub fn update(
+        &self,
+        account_updates: Vec<AccountUpdate>,
+        block: Option<BlockHeader>,
+    ) -> Vec<FailedUpdate> {
+        self.preload(
+            account_updates
+                .iter()
+                .map(|update| update.address),
+        );
+
         // Hold the write lock for the duration of the function so that no other thread can
         // write to the storage.
         let mut write_guard = self.inner.write().unwrap();
 
         write_guard.block = block;
 
+        let mut failed = Vec::new();
         for update in account_updates {
-            match update.change {
-                ChangeType::Update => {
-                    info!(%update.address, "Updating account");
-
-                    // If the account is not present, the internal storage will handle throwing
-                    // an exception.
-                    write_guard.accounts.update_account(
-                        &update.address,
-                        &StateUpdate {
-                            storage: Some(update.slots.clone()),
-                            balance: update.balance,
-                        },
-                    );
-                }
-                ChangeType::Deletion => {
-                    info!(%update.address, "Deleting account");
+            let applied = write_guard
+                .rehydrate(&update.address)
+                .and_then(|_| Self::apply_account_update(&mut write_guard.accounts, &update));
+            if let Err(error) = applied {
+                warn!(%update.address, %error, "Failed to apply account update");
+                failed.push(FailedUpdate { update, error });
+            }
+        }
+        self.sync_offloaded_count(&write_guard);
+        failed
+    }
 
-                    warn!(%update.address, "Deletion not implemented");
+    /// Applies a single account update. Everything is validated before the storage is modified.
+    fn apply_account_update(
+        accounts: &mut AccountStorage,
+        update: &AccountUpdate,
+    ) -> Result<(), PreCachedDBError> {
+        match update.change {
+            ChangeType::Update => {
+                info!(%update.address, "Updating account");
+
+                if !accounts.account_present(&update.address) {
+                    return Err(PreCachedDBError::MissingAccount(update.address));
                 }
-                ChangeType::Creation => {
-                    info!(%update.address, "Creating account");
-
-                    // We expect the code and balance to be present.
-                    let code = Bytecode::new_raw(Bytes::from(
-                        update
-                            .code
-                            .clone()
-                            .expect("account code"),
-                    ));
-                    let balance = update.balance.expect("account balance");
-
-                    // Initialize the account.
-                    write_guard.accounts.init_account(
-                        update.address,
-                        AccountInfo::new(balance, 0, code.hash_slow(), code),
-                        Some(update.slots.clone()),
-                        true, /* Flag all accounts in TychoDB mocked to sign that we cannot
-                               * call an RPC provider for an update */
-                    );
-                }
-                ChangeType::Unspecified => {
-                    warn!(%update.address, "Unspecified change type");
+                // Validate upgraded code before changing anything, like for created accounts
+                let code = update
+                    .code
+                    .clone()
+                    .map(|code| {
+                        Bytecode::new_raw_checked(Bytes::from(code)).map_err(|e| {
+                            PreCachedDBError::InvalidAccountUpdate(
+                                update.address,
+                                format!("malformed code: {:?}", e),
+                            )
+                        })
+                    })
+                    .transpose()?;
+                accounts.update_account(
+                    &update.address,
+                    &StateUpdate {
+                        storage: Some(
+                            update
+                                .slots
+                                .clone()
+                                .into_iter()
+                                .collect(),
+                        ),
+                        balance: update.balance,
+                        code: None,
+                        nonce: None,
+                    },
+                );
+                if let Some(code) = code {
+                    info!(%update.address, "Upgrading account code");
+                    if let Some(account) = accounts.get_account_mut(&update.address) {
+                        account.info = to_analysed(AccountInfo {
+                            code_hash: code.hash_slow(),
+                            code: Some(code),
+                            ..account.info.clone()
+                        });
+                    }
                 }
             }
+            ChangeType::Deletion => {
+                info!(%update.address, "Deleting account");
+
+                warn!(%update.address, "Deletion not implemented");
+            }
+            ChangeType::Creation => {
+                info!(%update.address, "Creating account");
+
+                let invalid = |reason: &str| {
+                    PreCachedDBError::InvalidAccountUpdate(update.address, reason.into())
+                };
+                let code = update
+                    .code
+                    .clone()
+                    .ok_or_else(|| invalid("missing code"))?;
+                let code = Bytecode::new_raw_checked(Bytes::from(code))
+                    .map_err(|e| invalid(&format!("malformed code: {:?}", e)))?;
+                let balance = update
+                    .balance
+                    .ok_or_else(|| invalid("missing balance"))?;
+
+                accounts.init_account(
+                    update.address,
+                    AccountInfo::new(balance, 0, code.hash_slow(), code),
+                    Some(update.slots.clone()),
+                    true, /* Flag all accounts in TychoDB mocked to sign that we cannot
+                           * call an RPC provider for an update */
+                );
+            }
+            ChangeType::Unspecified => {
+                warn!(%update.address, "Unspecified change type");
+            }
         }
+        Ok(())
     }
 
     /// Retrieves the storage value at the specified index for the given account, if it exists.
@@ -150,6 +341,10 @@ impl PreCachedDB {
     /// Returns an `Option` containing a reference to the storage value if it exists, otherwise
     /// returns `None`.
     pub fn get_storage(&self, address: &Address, index: &U256) -> Option<U256> {
+        if let Err(error) = self.ensure_resident(address) {
+            error!(%address, %error, "Failed to rehydrate offloaded storage");
+            return None;
+        }
         self.inner
             .read()
             .unwrap()
@@ -168,28 +363,43 @@ impl PreCachedDB {
     /// * `new_state`: A struct containing all the state changes for a particular block.
     pub fn update_state(
         &mut self,
-        updates: &HashMap<Address, StateUpdate>,
+        updates: &StorageMap<Address, StateUpdate>,
         block: BlockHeader,
-    ) -> HashMap<Address, StateUpdate> {
+    ) -> StorageMap<Address, StateUpdate> {
+        self.preload(updates.keys().copied());
+
         // Hold the write lock for the duration of the function so that no other thread can
         // write to the storage.
         let mut write_guard = self.inner.write().unwrap();
 
-        let mut revert_updates = HashMap::new();
+        let mut revert_updates = StorageMap::default();
         write_guard.block = Some(block);
 
         for (address, update_info) in updates.iter() {
+            if let Err(error) = write_guard.rehydrate(address) {
+                error!(%address, %error, "Failed to rehydrate offloaded storage, skipping update");
+                continue;
+            }
             let mut revert_entry = StateUpdate::default();
 
             if let Some(current_account) = write_guard
                 .accounts
                 .get_account_info(address)
             {
-                revert_entry.balance = Some(current_account.balance);
+                revert_entry.balance = update_info
+                    .balance
+                    .map(|_| current_account.balance);
+                revert_entry.code = update_info
+                    .code
+                    .as_ref()
+                    .map(|_| original_code(current_account));
+                revert_entry.nonce = update_info
+                    .nonce
+                    .map(|_| current_account.nonce);
             }
 
             if update_info.storage.is_some() {
-                let mut revert_storage = HashMap::default();
+                let mut revert_storage = StorageMap::default();
                 for index in update_info
                     .storage
                     .as_ref()
@@ -210,10 +420,44 @@ impl PreCachedDB {
                 .accounts
                 .update_account(address, update_info);
         }
+        self.sync_offloaded_count(&write_guard);
 
         revert_updates
     }
 
+    /// Commits the state updates of simulations as the new baseline for following simulations,
+    /// e.g. to simulate a sequence of transactions.
+    ///
+    /// Unlike `update_state`, accounts unknown to the database, e.g. contracts created by the
+    /// simulations or EOAs that received native tokens, are added instead of ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `updates` - State updates of simulation results, see `SimulationResult::state_updates`
+    /// * `block` - The block the updated state belongs to
+    ///
+    /// Returns a state update struct to revert this update.
+    pub fn apply_state_updates(
+        &mut self,
+        updates: &StorageMap<Address, StateUpdate>,
+        block: BlockHeader,
+    ) -> StorageMap<Address, StateUpdate> {
+        {
+            let mut write_guard = self.inner.write().unwrap();
+            for address in updates.keys() {
+                if !write_guard
+                    .accounts
+                    .account_present(address)
+                {
+                    write_guard
+                        .accounts
+                        .init_account(*address, AccountInfo::default(), None, true);
+                }
+            }
+        }
+        self.update_state(updates, block)
+    }
+
     #[cfg(test)]
     pub fn get_account_storage(&self) -> AccountStorage {
         self.inner
@@ -223,6 +467,411 @@ impl PreCachedDB {
             .clone()
     }
 
+    /// Exports all cached accounts, code and storage, together with the current block, into a
+    /// binary snapshot file.
+    ///
+    /// The snapshot can be loaded with `import` to seed another instance without syncing it from
+    /// the tycho stream. Temp storage is not exported.
+    ///
+    /// # Format
+    ///
+    /// All integers are big-endian. After the magic bytes follows an optional block header
+    /// (`u8` flag, then number, hash, parent hash, timestamp, the base fee as `u8` flag and `u64`
+    /// and the chain name prefixed by its length as `u8`), the account count (`u64`) and for each
+    /// account: address, whether it is mocked (`u8` flag), balance, nonce (`u64`), code length
+    /// (`u32`) and original code, storage slot count (`u64`) and the slots as `(index, value)`
+    /// pairs of 32 bytes each.
+    pub fn export(&self, path: impl AsRef<Path>) -> Result<(), PreCachedDBError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_snapshot(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Replaces the content of this database with a snapshot written by `export`.
+    ///
+    /// The snapshot is fully decoded before the database is modified, so on error the current
+    /// content is left untouched.
+    pub fn import(&self, path: impl AsRef<Path>) -> Result<(), PreCachedDBError> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let inner = read_snapshot(&mut reader)?;
+        let mut write_guard = self.inner.write().unwrap();
+        *write_guard = inner;
+        self.sync_offloaded_count(&write_guard);
+        Ok(())
+    }
+
+    fn write_snapshot(&self, writer: &mut impl Write) -> Result<(), PreCachedDBError> {
+        self.ensure_all_resident(self.offloaded_addresses())?;
+        let read_guard = self.inner.read().unwrap();
+        writer.write_all(SNAPSHOT_MAGIC)?;
+        match read_guard.block {
+            Some(header) => {
+                writer.write_all(&[1])?;
+                writer.write_all(&header.number.to_be_bytes())?;
+                writer.write_all(header.hash.as_slice())?;
+                writer.write_all(header.parent_hash.as_slice())?;
+                writer.write_all(&header.timestamp.to_be_bytes())?;
+                match header.basefee {
+                    Some(basefee) => {
+                        writer.write_all(&[1])?;
+                        writer.write_all(&basefee.to_be_bytes())?;
+                    }
+                    None => writer.write_all(&[0])?,
+                }
+                let chain = header.chain.to_string();
+                writer.write_all(&[chain.len() as u8])?;
+                writer.write_all(chain.as_bytes())?;
+            }
+            None => writer.write_all(&[0])?,
+        }
+
+        writer.write_all(&(read_guard.accounts.len() as u64).to_be_bytes())?;
+        for (address, account) in read_guard.accounts.iter() {
+            writer.write_all(address.as_slice())?;
+            writer.write_all(&[account.mocked as u8])?;
+            writer.write_all(&account.info.balance.to_be_bytes::<32>())?;
+            writer.write_all(&account.info.nonce.to_be_bytes())?;
+            let code = account
+                .info
+                .code
+                .as_ref()
+                .map(Bytecode::original_bytes)
+                .unwrap_or_default();
+            let code_len = u32::try_from(code.len()).map_err(|_| {
+                PreCachedDBError::InvalidSnapshot(format!("Code of {} is too large", address))
+            })?;
+            writer.write_all(&code_len.to_be_bytes())?;
+            writer.write_all(&code)?;
+            writer.write_all(&(account.permanent_storage.len() as u64).to_be_bytes())?;
+            for (index, value) in account.permanent_storage.iter() {
+                writer.write_all(&index.to_be_bytes::<32>())?;
+                writer.write_all(&value.to_be_bytes::<32>())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Computes the changes turning `base` into `target`.
+    ///
+    /// Applying the returned delta to a database holding the same data as `base` makes it hold the
+    /// same data as `target`. Temp storage is ignored.
+    pub fn diff(base: &PreCachedDB, target: &PreCachedDB) -> DbDelta {
+        if Arc::ptr_eq(&base.inner, &target.inner) {
+            return DbDelta::default();
+        }
+        for db in [base, target] {
+            if let Err(error) = db.ensure_all_resident(db.offloaded_addresses()) {
+                error!(%error, "Failed to rehydrate offloaded storage, the delta may be incomplete");
+            }
+        }
+        let base = base.inner.read().unwrap();
+        let target = target.inner.read().unwrap();
+
+        let mut delta = DbDelta {
+            block: target
+                .block
+                .filter(|block| Some(*block) != base.block),
+            ..Default::default()
+        };
+
+        for (address, account) in target.accounts.iter() {
+            let code = account
+                .info
+                .code
+                .as_ref()
+                .map(Bytecode::original_bytes);
+            let account_delta = match base.accounts.get_account(address) {
+                None => AccountDelta {
+                    balance: Some(account.info.balance),
+                    nonce: Some(account.info.nonce),
+                    code,
+                    storage: account
+                        .permanent_storage
+                        .iter()
+                        .map(|(index, value)| (*index, *value))
+                        .collect(),
+                },
+                Some(previous) => {
+                    // Storage shared since a snapshot is unchanged
+                    let storage =
+                        if Arc::ptr_eq(&account.permanent_storage, &previous.permanent_storage) {
+                            HashMap::new()
+                        } else {
+                            changed_slots(previous, account)
+                        };
+                    AccountDelta {
+                        balance: Some(account.info.balance)
+                            .filter(|balance| *balance != previous.info.balance),
+                        nonce: Some(account.info.nonce)
+                            .filter(|nonce| *nonce != previous.info.nonce),
+                        code: (account.info.code_hash != previous.info.code_hash)
+                            .then(|| code.unwrap_or_default()),
+                        storage,
+                    }
+                }
+            };
+            if account_delta != AccountDelta::default() {
+                delta
+                    .accounts
+                    .insert(*address, account_delta);
+            }
+        }
+
+        delta.removed = base
+            .accounts
+            .iter()
+            .map(|(address, _)| *address)
+            .filter(|address| !target.accounts.account_present(address))
+            .collect();
+
+        delta
+    }
+
+    /// Applies a delta computed by `diff`.
+    ///
+    /// Accounts unknown to this database are created from the delta, with empty code if the delta
+    /// doesn't contain any.
+    pub fn apply(&self, delta: &DbDelta) {
+        self.preload(delta.accounts.keys().copied());
+        let mut write_guard = self.inner.write().unwrap();
+
+        if let Some(block) = delta.block {
+            write_guard.block = Some(block);
+        }
+
+        for address in &delta.removed {
+            write_guard
+                .accounts
+                .remove_account(address);
+            write_guard.offloaded.remove(address);
+        }
+
+        for (address, account_delta) in &delta.accounts {
+            if let Err(error) = write_guard.rehydrate(address) {
+                error!(%address, %error, "Failed to rehydrate offloaded storage, skipping delta");
+                continue;
+            }
+            match write_guard
+                .accounts
+                .get_account_mut(address)
+            {
+                Some(account) => {
+                    if let Some(balance) = account_delta.balance {
+                        account.info.balance = balance;
+                    }
+                    if let Some(nonce) = account_delta.nonce {
+                        account.info.nonce = nonce;
+                    }
+                    if let Some(code) = &account_delta.code {
+                        let code = Bytecode::new_raw(code.clone());
+                        account.info = to_analysed(AccountInfo {
+                            code_hash: code.hash_slow(),
+                            code: Some(code),
+                            ..account.info.clone()
+                        });
+                    }
+                    Arc::make_mut(&mut account.permanent_storage)
+                        .extend(account_delta.storage.clone());
+                }
+                None => {
+                    let code = Bytecode::new_raw(
+                        account_delta
+                            .code
+                            .clone()
+                            .unwrap_or_default(),
+                    );
+                    write_guard.accounts.init_account(
+                        *address,
+                        to_analysed(AccountInfo::new(
+                            account_delta
+                                .balance
+                                .unwrap_or_default(),
+                            account_delta.nonce.unwrap_or_default(),
+                            code.hash_slow(),
+                            code,
+                        )),
+                        Some(account_delta.storage.clone()),
+                        true,
+                    );
+                }
+            }
+        }
+        self.sync_offloaded_count(&write_guard);
+    }
+
+    /// Returns a detached copy of the database at its current block, e.g. to keep quoting a past
+    /// block, see `BlockHistory`.
+    ///
+    /// Account storage is copied on write, so the checkpoint shares the storage of all accounts
+    /// with this database until they are updated: it costs memory proportional to the number of
+    /// accounts, not to their storage.
+    pub fn checkpoint(&self) -> PreCachedDB {
+        let mut inner = self.inner.read().unwrap().clone();
+        inner.accounts.clear_temp_storage();
+        let offloaded_count = Arc::new(AtomicUsize::new(inner.offloaded.len()));
+        PreCachedDB { inner: Arc::new(RwLock::new(inner)), offloaded_count }
+    }
+
+    /// Moves the permanent storage of `addresses` into compressed files in `dir` to free its
+    /// memory, e.g. for the contracts of pools that are rarely quoted. Returns the number of
+    /// accounts offloaded.
+    ///
+    /// Offloaded accounts stay usable: their storage is loaded back transparently the next time
+    /// it is read or updated. A file is removed once this database and all its checkpoints loaded
+    /// the storage back or dropped the account, so `dir` should be a directory dedicated to this
+    /// process, e.g. a temp dir.
+    ///
+    /// The files are written without holding the lock. Accounts updated meanwhile are skipped.
+    pub fn offload(
+        &self,
+        addresses: impl IntoIterator<Item = Address>,
+        dir: impl AsRef<Path>,
+    ) -> Result<usize, PreCachedDBError> {
+        let candidates: Vec<_> = {
+            let read_guard = self.inner.read().unwrap();
+            addresses
+                .into_iter()
+                .filter(|address| {
+                    !read_guard
+                        .offloaded
+                        .contains_key(address)
+                })
+                .filter_map(|address| {
+                    let account = read_guard
+                        .accounts
+                        .get_account(&address)?;
+                    (!account.permanent_storage.is_empty())
+                        .then(|| (address, account.permanent_storage.clone()))
+                })
+                .collect()
+        };
+
+        let mut files = Vec::with_capacity(candidates.len());
+        for (address, storage) in candidates {
+            let file = Arc::new(OffloadFile(dir.as_ref().join(format!(
+                "{address}-{}.slots.gz",
+                OFFLOAD_COUNTER.fetch_add(1, Ordering::Relaxed)
+            ))));
+            write_offloaded_storage(&file.0, &storage)?;
+            files.push((address, storage, file));
+        }
+
+        let mut write_guard = self.inner.write().unwrap();
+        let inner = &mut *write_guard;
+        let mut offloaded = 0;
+        for (address, storage, file) in files {
+            if inner.offloaded.contains_key(&address) {
+                continue;
+            }
+            // Storage is copied on write, so an updated account holds a different map
+            let Some(account) = inner
+                .accounts
+                .get_account_mut(&address)
+                .filter(|account| Arc::ptr_eq(&account.permanent_storage, &storage))
+            else {
+                continue;
+            };
+            account.permanent_storage = Arc::default();
+            inner.offloaded.insert(address, file);
+            offloaded += 1;
+        }
+        self.sync_offloaded_count(inner);
+        debug!(offloaded, "Offloaded account storage");
+        Ok(offloaded)
+    }
+
+    /// Sets what reads of slots without a value return for a mocked account, see
+    /// `MissingStoragePolicy`. The database has no node to fetch slots from, so `FetchFromChain`
+    /// fails reads like `Error`. Returns `false` if the account is unknown.
+    pub fn set_missing_storage_policy(
+        &self,
+        address: &Address,
+        policy: MissingStoragePolicy,
+    ) -> bool {
+        self.inner
+            .write()
+            .unwrap()
+            .accounts
+            .set_missing_storage_policy(address, policy)
+    }
+
+    /// Whether the storage of `address` is offloaded, see `offload`.
+    pub fn is_offloaded(&self, address: &Address) -> bool {
+        self.inner
+            .read()
+            .unwrap()
+            .offloaded
+            .contains_key(address)
+    }
+
+    /// Loads the storage of `address` back into memory if it was offloaded.
+    fn ensure_resident(&self, address: &Address) -> Result<(), PreCachedDBError> {
+        self.ensure_all_resident([*address])
+    }
+
+    /// Loads the storage of `addresses` back into memory if it was offloaded. The files are read
+    /// without holding the lock.
+    fn ensure_all_resident(
+        &self,
+        addresses: impl IntoIterator<Item = Address>,
+    ) -> Result<(), PreCachedDBError> {
+        if self
+            .offloaded_count
+            .load(Ordering::Acquire) ==
+            0
+        {
+            return Ok(());
+        }
+        let files: Vec<_> = {
+            let read_guard = self.inner.read().unwrap();
+            addresses
+                .into_iter()
+                .filter_map(|address| {
+                    read_guard
+                        .offloaded
+                        .get(&address)
+                        .map(|file| (address, file.clone()))
+                })
+                .collect()
+        };
+        for (address, file) in files {
+            let storage = read_offloaded_storage(&file.0)?;
+            let mut write_guard = self.inner.write().unwrap();
+            write_guard.install(&address, &file, storage);
+            self.sync_offloaded_count(&write_guard);
+        }
+        Ok(())
+    }
+
+    /// Loads offloaded storage before taking the write lock for an update. Failures are left to
+    /// the update, which retries and reports them.
+    fn preload(&self, addresses: impl IntoIterator<Item = Address>) {
+        if let Err(error) = self.ensure_all_resident(addresses) {
+            debug!(%error, "Failed to preload offloaded storage");
+        }
+    }
+
+    fn offloaded_addresses(&self) -> Vec<Address> {
+        self.inner
+            .read()
+            .unwrap()
+            .offloaded
+            .keys()
+            .copied()
+            .collect()
+    }
+
+    fn sync_offloaded_count(&self, inner: &PreCachedDBInner) {
+        self.offloaded_count
+            .store(inner.offloaded.len(), Ordering::Release);
+    }
+
+    /// Returns the current block, if set.
+    pub fn block(&self) -> Option<BlockHeader> {
+        self.inner.read().unwrap().block
+    }
+
     /// If block is set, returns the number. Otherwise returns None.
     pub fn block_number(&self) -> Option<u64> {
         self.inner
@@ -234,6 +883,145 @@ impl PreCachedDB {
     }
 }
 
+/// Returns the slots whose value differs between the storage of `previous` and `account`.
+fn changed_slots(previous: &Account, account: &Account) -> HashMap<U256, U256> {
+    // Missing slots are zero
+    let mut storage: HashMap<U256, U256> = account
+        .permanent_storage
+        .iter()
+        .filter(|(index, value)| {
+            previous
+                .permanent_storage
+                .get(index)
+                .copied()
+                .unwrap_or_default() !=
+                **value
+        })
+        .map(|(index, value)| (*index, *value))
+        .collect();
+    for (index, value) in previous.permanent_storage.iter() {
+        if !value.is_zero() &&
+            !account
+                .permanent_storage
+                .contains_key(index)
+        {
+            storage.insert(*index, U256::ZERO);
+        }
+    }
+    storage
+}
+
+/// Writes storage slots into a gzip file, as slot count (`u64`) followed by the slots as
+/// `(index, value)` pairs of 32 bytes each.
+fn write_offloaded_storage(
+    path: &Path,
+    storage: &StorageMap<U256, U256>,
+) -> Result<(), PreCachedDBError> {
+    let mut writer = GzEncoder::new(BufWriter::new(File::create(path)?), Compression::fast());
+    writer.write_all(&(storage.len() as u64).to_be_bytes())?;
+    for (index, value) in storage.iter() {
+        writer.write_all(&index.to_be_bytes::<32>())?;
+        writer.write_all(&value.to_be_bytes::<32>())?;
+    }
+    writer.finish()?.flush()?;
+    Ok(())
+}
+
+fn read_offloaded_storage(path: &Path) -> Result<StorageMap<U256, U256>, PreCachedDBError> {
+    let mut reader = GzDecoder::new(BufReader::new(File::open(path)?));
+    let n_slots = u64::from_be_bytes(read_array(&mut reader)?);
+    let mut storage = StorageMap::default();
+    for _ in 0..n_slots {
+        let index = U256::from_be_bytes(read_array::<32>(&mut reader)?);
+        let value = U256::from_be_bytes(read_array::<32>(&mut reader)?);
+        storage.insert(index, value);
+    }
+    Ok(storage)
+}
+
+fn read_snapshot(reader: &mut impl Read) -> Result<PreCachedDBInner, PreCachedDBError> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != SNAPSHOT_MAGIC {
+        return Err(PreCachedDBError::InvalidSnapshot("Unknown format or version".to_string()));
+    }
+
+    let block = match read_array::<1>(reader)? {
+        [0] => None,
+        [1] => Some(read_block_header(reader)?),
+        [flag] => {
+            return Err(PreCachedDBError::InvalidSnapshot(format!("Invalid block flag {}", flag)))
+        }
+    };
+
+    let mut accounts = AccountStorage::new();
+    let n_accounts = u64::from_be_bytes(read_array(reader)?);
+    for _ in 0..n_accounts {
+        let address = Address::from(read_array::<20>(reader)?);
+        let mocked = match read_array::<1>(reader)? {
+            [0] => false,
+            [1] => true,
+            [flag] => {
+                return Err(PreCachedDBError::InvalidSnapshot(format!(
+                    "Invalid mocked flag {}",
+                    flag
+                )))
+            }
+        };
+        let balance = U256::from_be_bytes(read_array::<32>(reader)?);
+        let nonce = u64::from_be_bytes(read_array(reader)?);
+        let code_len = u32::from_be_bytes(read_array(reader)?) as usize;
+        let mut code = vec![0u8; code_len];
+        reader.read_exact(&mut code)?;
+        let code = Bytecode::new_raw(Bytes::from(code));
+
+        let n_slots = u64::from_be_bytes(read_array(reader)?);
+        let mut storage = HashMap::new();
+        for _ in 0..n_slots {
+            let index = U256::from_be_bytes(read_array::<32>(reader)?);
+            let value = U256::from_be_bytes(read_array::<32>(reader)?);
+            storage.insert(index, value);
+        }
+
+        accounts.init_account(
+            address,
+            to_analysed(AccountInfo::new(balance, nonce, code.hash_slow(), code)),
+            Some(storage),
+            mocked,
+        );
+    }
+
+    Ok(PreCachedDBInner { accounts, block, offloaded: HashMap::new() })
+}
+
+fn read_block_header(reader: &mut impl Read) -> Result<BlockHeader, PreCachedDBError> {
+    let number = u64::from_be_bytes(read_array(reader)?);
+    let hash = B256::from(read_array::<32>(reader)?);
+    let parent_hash = B256::from(read_array::<32>(reader)?);
+    let timestamp = u64::from_be_bytes(read_array(reader)?);
+    let basefee = match read_array::<1>(reader)? {
+        [0] => None,
+        [1] => Some(u64::from_be_bytes(read_array(reader)?)),
+        [flag] => {
+            return Err(PreCachedDBError::InvalidSnapshot(format!("Invalid base fee flag {}", flag)))
+        }
+    };
+    let [chain_len] = read_array::<1>(reader)?;
+    let mut chain = vec![0u8; chain_len as usize];
+    reader.read_exact(&mut chain)?;
+    let chain = String::from_utf8(chain)
+        .ok()
+        .and_then(|chain| Chain::from_str(&chain).ok())
+        .ok_or_else(|| PreCachedDBError::InvalidSnapshot("Invalid chain".to_string()))?;
+    Ok(BlockHeader { number, hash, parent_hash, timestamp, basefee, chain })
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> Result<[u8; N], PreCachedDBError> {
+    let mut buf = [0u8; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
 impl EngineDatabaseInterface for PreCachedDB {
     type Error = String;
 
@@ -247,24 +1035,34 @@ impl EngineDatabaseInterface for PreCachedDB {
     /// * `account` - The account information
     /// * `permanent_storage` - Storage to init the account with, this storage can only be updated
     ///   manually
+    /// * `mocked` - Whether the account is mocked. Nothing is ever fetched from a node, the flag
+    ///   only decides whether the account's `MissingStoragePolicy` applies.
     fn init_account(
         &self,
         address: Address,
         account: AccountInfo,
         permanent_storage: Option<HashMap<U256, U256>>,
-        _mocked: bool,
+        mocked: bool,
     ) {
         self.inner
             .write()
             .unwrap()
             .accounts
-            .init_account(address, to_analysed(account), permanent_storage, true)
+            .init_account(address, to_analysed(account), permanent_storage, mocked)
     }
 
     /// Deprecated in TychoDB
     fn clear_temp_storage(&mut self) {
         debug!("Temp storage in TychoDB is never set, nothing to clear");
     }
+
+    fn block_header(&self) -> Option<BlockHeader> {
+        self.block()
+    }
+
+    fn is_missing_account(error: &PreCachedDBError) -> bool {
+        matches!(error, PreCachedDBError::MissingAccount(_))
+    }
 }
 
 impl DatabaseRef for PreCachedDB {
@@ -311,6 +1109,7 @@ impl DatabaseRef for PreCachedDB {
     /// Returns an error if the storage value is not found.
     fn storage_ref(&self, address: Address, index: U256) -> Result<U256, Self::Error> {
         debug!(%address, %index, "Requested storage of account");
+        self.ensure_resident(&address)?;
         let read_guard = self.inner.read().unwrap();
         if let Some(storage_value) = read_guard
             .accounts
@@ -320,14 +1119,24 @@ impl DatabaseRef for PreCachedDB {
             Ok(storage_value)
         } else {
             // At this point we either don't know this address or we don't have anything at this
-            if read_guard
-                .accounts
-                .account_present(&address)
-            {
-                // As we only store non-zero values, if the account is present it means this
-                // slot is zero.
-                debug!(%address, %index, "Account found, but slot is zero");
-                Ok(U256::ZERO)
+            let accounts = &read_guard.accounts;
+            if let Some(mocked) = accounts.is_mocked_account(&address) {
+                // The policy only applies to mocked accounts
+                let policy = accounts
+                    .missing_storage_policy(&address)
+                    .filter(|_| mocked)
+                    .unwrap_or_default();
+                match policy {
+                    // As we only store non-zero values, if the account is present it means this
+                    // slot is zero.
+                    MissingStoragePolicy::ZeroDefault => {
+                        debug!(%address, %index, "Account found, but slot is zero");
+                        Ok(U256::ZERO)
+                    }
+                    MissingStoragePolicy::FetchFromChain | MissingStoragePolicy::Error => {
+                        Err(PreCachedDBError::MissingStorage(address, index))
+                    }
+                }
             } else {
                 // At this point we know we don't have data for this address.
                 debug!(%address, %index, "Account not found");
@@ -350,7 +1159,7 @@ mod tests {
     use std::{error::Error, str::FromStr};
 
     use chrono::DateTime;
-    use revm::primitives::U256;
+    use revm::primitives::{keccak256, KECCAK_EMPTY, U256};
     use rstest::{fixture, rstest};
 
     use super::*;
@@ -362,7 +1171,9 @@ mod tests {
             inner: Arc::new(RwLock::new(PreCachedDBInner {
                 accounts: AccountStorage::new(),
                 block: None,
+                offloaded: HashMap::new(),
             })),
+            offloaded_count: Arc::default(),
         }
     }
 
@@ -424,6 +1235,46 @@ mod tests {
         Ok(())
     }
 
+    #[rstest]
+    fn test_missing_storage_policy(mock_db: PreCachedDB) {
+        let address = Address::repeat_byte(0x01);
+        let not_mocked = Address::repeat_byte(0x02);
+        mock_db.init_account(
+            address,
+            AccountInfo::default(),
+            Some(HashMap::from([(U256::from(1), U256::from(10))])),
+            true,
+        );
+        mock_db.init_account(not_mocked, AccountInfo::default(), None, false);
+
+        assert_eq!(
+            mock_db
+                .storage_ref(address, U256::from(2))
+                .unwrap(),
+            U256::ZERO
+        );
+        assert!(mock_db.set_missing_storage_policy(&address, MissingStoragePolicy::Error));
+        assert_eq!(
+            mock_db
+                .storage_ref(address, U256::from(1))
+                .unwrap(),
+            U256::from(10)
+        );
+        assert!(matches!(
+            mock_db.storage_ref(address, U256::from(2)),
+            Err(PreCachedDBError::MissingStorage(a, i)) if a == address && i == U256::from(2)
+        ));
+
+        // Accounts that aren't mocked keep reading zero
+        assert!(mock_db.set_missing_storage_policy(&not_mocked, MissingStoragePolicy::Error));
+        assert_eq!(
+            mock_db
+                .storage_ref(not_mocked, U256::from(2))
+                .unwrap(),
+            U256::ZERO
+        );
+    }
+
     #[rstest]
     #[should_panic(
         expected = "called `Result::unwrap()` on an `Err` value: MissingAccount(0xb4e16d0168e52d35cacd2c6185b44281ec28c9dc)"
@@ -445,11 +1296,16 @@ mod tests {
         let address = Address::from_str("0xb4e16d0168e52d35cacd2c6185b44281ec28c9dc")?;
         mock_db.init_account(address, AccountInfo::default(), None, false);
 
-        let mut new_storage = HashMap::default();
+        let mut new_storage = StorageMap::default();
         let new_storage_value_index = U256::from_limbs_slice(&[123]);
         new_storage.insert(new_storage_value_index, new_storage_value_index);
         let new_balance = U256::from_limbs_slice(&[500]);
-        let update = StateUpdate { storage: Some(new_storage), balance: Some(new_balance) };
+        let update = StateUpdate {
+            storage: Some(new_storage),
+            balance: Some(new_balance),
+            code: None,
+            nonce: None,
+        };
         let new_block = Block {
             number: 1,
             hash: B256::default(),
@@ -459,7 +1315,7 @@ mod tests {
                 .unwrap()
                 .naive_utc(),
         };
-        let mut updates = HashMap::default();
+        let mut updates = StorageMap::default();
         updates.insert(address, update);
 
         mock_db.update_state(&updates, new_block.into());
@@ -486,6 +1342,31 @@ mod tests {
         Ok(())
     }
 
+    #[rstest]
+    fn test_apply_state_updates(mut mock_db: PreCachedDB) -> Result<(), Box<dyn Error>> {
+        let created = Address::repeat_byte(0x11);
+        let code = Bytes::from_static(&[0x00]);
+        let updates = StorageMap::from_iter([(
+            created,
+            StateUpdate {
+                storage: Some(StorageMap::from_iter([(U256::from(1), U256::from(2))])),
+                balance: Some(U256::from(3)),
+                code: Some(code.clone()),
+                nonce: None,
+            },
+        )]);
+
+        let revert = mock_db.apply_state_updates(&updates, BlockHeader::default());
+
+        let info = mock_db.basic_ref(created)?.unwrap();
+        assert_eq!(info.balance, U256::from(3));
+        assert_eq!(info.code_hash, keccak256(&code));
+        assert_eq!(mock_db.storage_ref(created, U256::from(1))?, U256::from(2));
+        assert_eq!(revert[&created].balance, Some(U256::ZERO));
+        assert_eq!(revert[&created].code, Some(Bytes::new()));
+        Ok(())
+    }
+
     #[rstest]
     #[tokio::test]
     async fn test_block_number_getter(mut mock_db: PreCachedDB) -> Result<(), Box<dyn Error>> {
@@ -501,7 +1382,7 @@ mod tests {
                 .unwrap()
                 .naive_utc(),
         };
-        let updates = HashMap::default();
+        let updates = StorageMap::default();
 
         mock_db.update_state(&updates, new_block.into());
 
@@ -511,6 +1392,198 @@ mod tests {
         Ok(())
     }
 
+    #[rstest]
+    fn test_export_import(mock_db: PreCachedDB) -> Result<(), Box<dyn Error>> {
+        let address = Address::from_str("0xb4e16d0168e52d35cacd2c6185b44281ec28c9dc")?;
+        let code = Bytecode::new_raw(Bytes::from_static(&[0x60, 0x00, 0x60, 0x00, 0xf3]));
+        mock_db.init_account(
+            address,
+            AccountInfo::new(U256::from(7), 3, code.hash_slow(), code.clone()),
+            Some(HashMap::from([(U256::from(1), U256::from(10))])),
+            false,
+        );
+        let mocked = Address::repeat_byte(0x01);
+        mock_db.init_account(mocked, AccountInfo::default(), None, true);
+        let block = BlockHeader {
+            number: 1,
+            hash: B256::repeat_byte(0x11),
+            parent_hash: B256::repeat_byte(0x10),
+            timestamp: 100,
+            basefee: Some(7),
+            chain: Chain::Arbitrum,
+        };
+        mock_db.inner.write().unwrap().block = Some(block);
+        let file = tempfile::NamedTempFile::new()?;
+
+        mock_db.export(file.path())?;
+        let imported = PreCachedDB::new()?;
+        imported.import(file.path())?;
+
+        let info = imported.basic_ref(address)?.unwrap();
+        assert_eq!(info.balance, U256::from(7));
+        assert_eq!(info.nonce, 3);
+        assert_eq!(info.code_hash, code.hash_slow());
+        assert_eq!(info.code.unwrap().original_bytes(), code.original_bytes());
+        assert_eq!(imported.storage_ref(address, U256::from(1))?, U256::from(10));
+        assert_eq!(imported.block(), Some(block));
+        assert_eq!(imported.block_hash_ref(1)?, block.hash);
+        let read_guard = imported.inner.read().unwrap();
+        assert_eq!(
+            read_guard
+                .accounts
+                .is_mocked_account(&address),
+            Some(false)
+        );
+        assert_eq!(
+            read_guard
+                .accounts
+                .is_mocked_account(&mocked),
+            Some(true)
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_offload(mut mock_db: PreCachedDB) -> Result<(), Box<dyn Error>> {
+        let address = Address::repeat_byte(0x01);
+        let code = Bytecode::new_raw(Bytes::from_static(&[0x60, 0x00]));
+        mock_db.init_account(
+            address,
+            AccountInfo::new(U256::from(7), 0, code.hash_slow(), code),
+            Some(HashMap::from([(U256::from(1), U256::from(10)), (U256::from(2), U256::from(20))])),
+            false,
+        );
+        let dir = tempfile::tempdir()?;
+
+        assert_eq!(mock_db.offload([address, Address::repeat_byte(0x02)], dir.path())?, 1);
+        assert!(mock_db.is_offloaded(&address));
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+        let checkpoint = mock_db.checkpoint();
+
+        // Updates load the storage before applying
+        mock_db.update_state(
+            &StorageMap::from_iter([(
+                address,
+                StateUpdate {
+                    storage: Some(StorageMap::from_iter([(U256::from(2), U256::from(21))])),
+                    balance: None,
+                    code: None,
+                    nonce: None,
+                },
+            )]),
+            BlockHeader::default(),
+        );
+        assert!(!mock_db.is_offloaded(&address));
+        assert_eq!(mock_db.storage_ref(address, U256::from(1))?, U256::from(10));
+        assert_eq!(mock_db.storage_ref(address, U256::from(2))?, U256::from(21));
+
+        // Reads load the storage too, the checkpoint still sees the offloaded values
+        assert!(checkpoint.is_offloaded(&address));
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+        assert_eq!(checkpoint.storage_ref(address, U256::from(2))?, U256::from(20));
+        assert!(!checkpoint.is_offloaded(&address));
+
+        // The file is removed once no database references it anymore
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 0);
+
+        // Removing an offloaded account removes its file too
+        assert_eq!(mock_db.offload([address], dir.path())?, 1);
+        mock_db.apply(&DbDelta { removed: HashSet::from([address]), ..Default::default() });
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 0);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_diff_apply(mock_db: PreCachedDB) -> Result<(), Box<dyn Error>> {
+        let changed = Address::repeat_byte(0x01);
+        let removed = Address::repeat_byte(0x02);
+        let created = Address::repeat_byte(0x03);
+        let code = Bytecode::new_raw(Bytes::from_static(&[0x60, 0x00]));
+        let storage =
+            HashMap::from([(U256::from(1), U256::from(10)), (U256::from(2), U256::from(20))]);
+        // The code of `changed` is removed in the target
+        mock_db.init_account(
+            changed,
+            AccountInfo::new(U256::ZERO, 0, code.hash_slow(), code.clone()),
+            Some(storage.clone()),
+            false,
+        );
+        mock_db.init_account(removed, AccountInfo::default(), None, false);
+
+        let target = PreCachedDB::new()?;
+        target.init_account(
+            changed,
+            AccountInfo { balance: U256::from(5), ..Default::default() },
+            Some(HashMap::from([(U256::from(1), U256::from(11))])),
+            false,
+        );
+        target.init_account(
+            created,
+            AccountInfo::new(U256::ZERO, 1, code.hash_slow(), code.clone()),
+            None,
+            false,
+        );
+        let block = BlockHeader {
+            number: 2,
+            hash: B256::repeat_byte(0x22),
+            timestamp: 24,
+            ..Default::default()
+        };
+        target.inner.write().unwrap().block = Some(block);
+
+        let delta = PreCachedDB::diff(&mock_db, &target);
+
+        assert_eq!(delta.block, Some(block));
+        assert_eq!(delta.removed, HashSet::from([removed]));
+        assert_eq!(
+            delta.accounts[&changed],
+            AccountDelta {
+                balance: Some(U256::from(5)),
+                nonce: None,
+                code: Some(Bytes::new()),
+                storage: HashMap::from([
+                    (U256::from(1), U256::from(11)),
+                    (U256::from(2), U256::ZERO)
+                ]),
+            }
+        );
+        assert_eq!(delta.accounts[&created].code, Some(code.original_bytes()));
+        // Deltas are sent to followers serialized
+        let delta: DbDelta = serde_json::from_str(&serde_json::to_string(&delta)?)?;
+
+        mock_db.apply(&delta);
+
+        assert!(PreCachedDB::diff(&mock_db, &target).is_empty());
+        assert_eq!(mock_db.storage_ref(changed, U256::from(2))?, U256::ZERO);
+        assert!(mock_db.basic_ref(removed).is_err());
+        assert_eq!(
+            mock_db
+                .basic_ref(changed)?
+                .unwrap()
+                .code_hash,
+            KECCAK_EMPTY
+        );
+        assert_eq!(
+            mock_db
+                .basic_ref(created)?
+                .unwrap()
+                .code_hash,
+            code.hash_slow()
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_import_invalid_snapshot(mock_db: PreCachedDB) -> Result<(), Box<dyn Error>> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        file.write_all(b"not a snapshot")?;
+
+        let res = mock_db.import(file.path());
+
+        assert!(matches!(res, Err(PreCachedDBError::InvalidSnapshot(_))));
+        Ok(())
+    }
+
     #[rstest]
     #[tokio::test]
     async fn test_update() {
@@ -518,7 +1591,9 @@ mod tests {
             inner: Arc::new(RwLock::new(PreCachedDBInner {
                 accounts: AccountStorage::new(),
                 block: None,
+                offloaded: HashMap::new(),
             })),
+            offloaded_count: Arc::default(),
         };
 
         let account_update = AccountUpdate::new(
@@ -572,6 +1647,58 @@ mod tests {
         );
     }
 
+    #[rstest]
+    fn test_update_isolates_failures(mock_db: PreCachedDB) {
+        let creation = |address: Address, code: Option<Vec<u8>>| {
+            AccountUpdate::new(
+                address,
+                Chain::Ethereum,
+                HashMap::new(),
+                Some(U256::from(500)),
+                code,
+                ChangeType::Creation,
+            )
+        };
+        let valid = Address::with_last_byte(1);
+        let malformed = Address::with_last_byte(2);
+        let missing_code = Address::with_last_byte(3);
+        let unknown = Address::with_last_byte(4);
+
+        let failed = mock_db.update(
+            vec![
+                creation(valid, Some(vec![0x60, 0x00])),
+                // EOF magic followed by an invalid container
+                creation(malformed, Some(vec![0xef, 0x00, 0x01])),
+                creation(missing_code, None),
+                AccountUpdate::new(
+                    unknown,
+                    Chain::Ethereum,
+                    HashMap::from([(U256::ZERO, U256::from(1))]),
+                    None,
+                    None,
+                    ChangeType::Update,
+                ),
+            ],
+            None,
+        );
+
+        let failed: Vec<_> = failed
+            .iter()
+            .map(|failure| failure.update.address)
+            .collect();
+        assert_eq!(failed, vec![malformed, missing_code, unknown]);
+        assert!(mock_db
+            .basic_ref(valid)
+            .unwrap()
+            .is_some());
+        assert!(!mock_db
+            .inner
+            .read()
+            .unwrap()
+            .accounts
+            .account_present(&malformed));
+    }
+
     /// This test requires a running TychoDB instance.
     ///
     /// To run this test, start TychoDB with the following command:
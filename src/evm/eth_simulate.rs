@@ -0,0 +1,424 @@
+//! Multi-block simulation
+//!
+//! Mirrors the `eth_simulateV1` RPC method on top of the local `SimulationEngine`: a sequence of
+//! blocks is simulated, each with optional block overrides, state overrides and a list of calls.
+//! Calls are executed in order and see the state changes of all previous calls, including those
+//! of previous blocks. None of the changes are written to the engine's database.
+use std::{collections::HashMap, fmt::Debug};
+
+use alloy_primitives::{keccak256, Address, Bytes, B256, U256};
+use revm::{
+    db::CacheDB,
+    primitives::{BlockEnv, Bytecode, EVMError, ExecutionResult, Log, SpecId, TransactTo, TxEnv},
+    Database, DatabaseCommit, DatabaseRef, Evm,
+};
+
+use super::{
+    engine_db::{engine_db_interface::EngineDatabaseInterface, simulation_db::BlockHeader},
+    protocol::vm::constants::EXTERNAL_ACCOUNT,
+    simulation::{configure_gas, gas_price, SimulationEngine, SimulationEngineError},
+};
+
+/// Default time between two simulated blocks, if not overridden
+const DEFAULT_BLOCK_TIME: u64 = 12;
+/// Default gas limit of a call, if not set
+const DEFAULT_CALL_GAS_LIMIT: u64 = 8_000_000;
+
+/// Overrides of the block environment. Unset fields are derived from the previous block.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockOverrides {
+    pub number: Option<u64>,
+    pub time: Option<u64>,
+    pub gas_limit: Option<u64>,
+    pub fee_recipient: Option<Address>,
+    pub base_fee_per_gas: Option<U256>,
+    pub prev_randao: Option<B256>,
+}
+
+/// Overrides of a single account, applied before the calls of a block.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccountOverride {
+    pub balance: Option<U256>,
+    pub nonce: Option<u64>,
+    pub code: Option<Bytes>,
+    /// Storage slots to set, other slots keep their current value
+    pub state_diff: Option<HashMap<U256, U256>>,
+}
+
+/// A call to simulate.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SimulatedCall {
+    /// Sender of the call, defaults to the engine's external account
+    pub from: Option<Address>,
+    /// Receiver of the call, `None` deploys a contract with `data` as init code
+    pub to: Option<Address>,
+    pub data: Bytes,
+    pub value: U256,
+    pub gas_limit: Option<u64>,
+}
+
+/// A block to simulate.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockStateCalls {
+    pub block_overrides: Option<BlockOverrides>,
+    pub state_overrides: Option<HashMap<Address, AccountOverride>>,
+    pub calls: Vec<SimulatedCall>,
+}
+
+/// Result of a single simulated call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimulatedCallResult {
+    /// Whether the call succeeded
+    pub status: bool,
+    /// Return data, or revert data if the call reverted
+    pub return_data: Bytes,
+    pub gas_used: u64,
+    /// Logs emitted by the call, empty if the call failed
+    pub logs: Vec<Log>,
+    /// Reason of the failure if the call reverted or halted
+    pub error: Option<String>,
+}
+
+/// Result of a simulated block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimulatedBlock {
+    pub number: u64,
+    pub timestamp: u64,
+    pub gas_used: u64,
+    pub calls: Vec<SimulatedCallResult>,
+}
+
+impl<D: EngineDatabaseInterface + Clone + Debug> SimulationEngine<D>
+where
+    <D as DatabaseRef>::Error: std::fmt::Debug,
+    <D as EngineDatabaseInterface>::Error: std::fmt::Debug,
+{
+    /// Simulates a sequence of blocks, the equivalent of `eth_simulateV1`.
+    ///
+    /// # Arguments
+    ///
+    /// * `parent` - The block the simulation builds on. The first simulated block defaults to
+    ///   `parent.number + 1` and `parent.timestamp + 12`.
+    /// * `blocks` - The blocks to simulate, in order.
+    ///
+    /// # Errors
+    ///
+    /// Reverted or halted calls do not fail the simulation, they are reported in the call results.
+    /// An error is returned if the database can't be accessed or a call is invalid (e.g. the
+    /// sender can't pay for the value sent).
+    pub fn simulate_blocks(
+        &self,
+        parent: &BlockHeader,
+        blocks: &[BlockStateCalls],
+    ) -> Result<Vec<SimulatedBlock>, SimulationEngineError> {
+        let spec_id = self.resolve_spec_id(None)?;
+        let mut db = CacheDB::new(&self.state);
+        let mut number = parent.number;
+        let mut timestamp = parent.timestamp;
+        let mut results = Vec::with_capacity(blocks.len());
+
+        for block in blocks {
+            let overrides = block
+                .block_overrides
+                .clone()
+                .unwrap_or_default();
+            number = overrides.number.unwrap_or(number + 1);
+            timestamp = overrides
+                .time
+                .unwrap_or(timestamp + DEFAULT_BLOCK_TIME);
+
+            let mut block_env = BlockEnv {
+                number: U256::from(number),
+                timestamp: U256::from(timestamp),
+                ..Default::default()
+            };
+            if let Some(gas_limit) = overrides.gas_limit {
+                block_env.gas_limit = U256::from(gas_limit);
+            }
+            if let Some(fee_recipient) = overrides.fee_recipient {
+                block_env.coinbase = fee_recipient;
+            }
+            if let Some(base_fee) = overrides.base_fee_per_gas {
+                block_env.basefee = base_fee;
+            }
+            if let Some(prev_randao) = overrides.prev_randao {
+                block_env.prevrandao = Some(prev_randao);
+            }
+
+            if let Some(state_overrides) = &block.state_overrides {
+                apply_state_overrides(&mut db, state_overrides, D::is_missing_account)?;
+            }
+
+            let mut calls = Vec::with_capacity(block.calls.len());
+            let mut gas_used = 0;
+            for call in &block.calls {
+                let result =
+                    simulate_call(&mut db, spec_id, block_env.clone(), call, self.is_gasless())?;
+                gas_used += result.gas_used;
+                calls.push(result);
+            }
+
+            results.push(SimulatedBlock { number, timestamp, gas_used, calls });
+        }
+
+        Ok(results)
+    }
+}
+
+fn storage_error<E: Debug>(err: E) -> SimulationEngineError {
+    SimulationEngineError::StorageError(format!("Storage error: {:?}", err))
+}
+
+/// Applies `overrides` to `db`. Accounts whose read fails with an error for which
+/// `is_missing_account` holds are created, other errors are returned.
+fn apply_state_overrides<DB: DatabaseRef>(
+    db: &mut CacheDB<DB>,
+    overrides: &HashMap<Address, AccountOverride>,
+    is_missing_account: impl Fn(&DB::Error) -> bool,
+) -> Result<(), SimulationEngineError>
+where
+    DB::Error: Debug,
+{
+    for (address, account_override) in overrides {
+        // Overrides may create accounts unknown to the underlying database
+        let existing = match db.basic(*address) {
+            Ok(existing) => existing,
+            Err(error) if is_missing_account(&error) => None,
+            Err(error) => return Err(storage_error(error)),
+        };
+        let is_new = existing.is_none();
+        let mut info = existing.unwrap_or_default();
+        if let Some(balance) = account_override.balance {
+            info.balance = balance;
+        }
+        if let Some(nonce) = account_override.nonce {
+            info.nonce = nonce;
+        }
+        if let Some(code) = &account_override.code {
+            info.code_hash = keccak256(code);
+            info.code = Some(Bytecode::new_raw(code.clone()));
+        }
+        db.insert_account_info(*address, info);
+        if is_new {
+            // Start from empty storage instead of querying the underlying database
+            db.replace_account_storage(*address, Default::default())
+                .map_err(storage_error)?;
+        }
+
+        if let Some(state_diff) = &account_override.state_diff {
+            for (slot, value) in state_diff {
+                db.insert_account_storage(*address, *slot, *value)
+                    .map_err(storage_error)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn simulate_call<DB: DatabaseRef>(
+    db: &mut CacheDB<DB>,
+    spec_id: SpecId,
+    block_env: BlockEnv,
+    call: &SimulatedCall,
+    gasless: bool,
+) -> Result<SimulatedCallResult, SimulationEngineError>
+where
+    DB::Error: Debug,
+{
+    let tx_env = TxEnv {
+        caller: call.from.unwrap_or(*EXTERNAL_ACCOUNT),
+        gas_limit: call
+            .gas_limit
+            .unwrap_or(DEFAULT_CALL_GAS_LIMIT),
+        transact_to: match call.to {
+            Some(to) => TransactTo::Call(to),
+            None => TransactTo::Create,
+        },
+        gas_price: gas_price(&block_env, gasless),
+        value: call.value,
+        data: call.data.clone(),
+        ..Default::default()
+    };
+
+    let result_and_state = {
+        let mut vm = Evm::builder()
+            .with_spec_id(spec_id)
+            .with_db(&mut *db)
+            .with_block_env(block_env)
+            .with_tx_env(tx_env)
+            .modify_cfg_env(|cfg| configure_gas(cfg, gasless))
+            .build();
+        vm.transact().map_err(|err| match err {
+            EVMError::Database(db_error) => storage_error(db_error),
+            other => SimulationEngineError::TransactionError {
+                data: format!("EVM error: {:?}", other),
+                gas_used: None,
+            },
+        })?
+    };
+
+    // Commit so that following calls see the changes of this one, reverted calls only commit
+    // the sender's nonce and gas payment.
+    db.commit(result_and_state.state);
+
+    Ok(match result_and_state.result {
+        ExecutionResult::Success { gas_used, output, logs, .. } => SimulatedCallResult {
+            status: true,
+            return_data: output.into_data(),
+            gas_used,
+            logs,
+            error: None,
+        },
+        ExecutionResult::Revert { gas_used, output } => SimulatedCallResult {
+            status: false,
+            error: Some(format!("Revert: 0x{}", hex::encode(&output))),
+            return_data: output,
+            gas_used,
+            logs: Vec::new(),
+        },
+        ExecutionResult::Halt { reason, gas_used } => SimulatedCallResult {
+            status: false,
+            return_data: Bytes::new(),
+            gas_used,
+            logs: Vec::new(),
+            error: Some(format!("{:?}", reason)),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use revm::primitives::{hex, AccountInfo};
+
+    use super::*;
+    use crate::evm::engine_db::{create_engine, tycho_db::PreCachedDB};
+
+    // Runtime code storing calldata word 0 into slot 0, then returning slot 0:
+    // PUSH1 0 CALLDATALOAD PUSH1 0 SSTORE PUSH1 0 SLOAD PUSH1 0 MSTORE PUSH1 32 PUSH1 0 RETURN
+    const STORE_AND_RETURN: &str = "60003560005560005460005260206000f3";
+    // Runtime code returning slot 0:
+    // PUSH1 0 SLOAD PUSH1 0 MSTORE PUSH1 32 PUSH1 0 RETURN
+    const LOAD_AND_RETURN: &str = "60005460005260206000f3";
+
+    fn engine() -> SimulationEngine<PreCachedDB> {
+        let engine = create_engine(PreCachedDB::new().unwrap(), false).unwrap();
+        engine
+            .state
+            .init_account(*EXTERNAL_ACCOUNT, AccountInfo::default(), None, false);
+        engine
+    }
+
+    fn code_override(code: &str) -> AccountOverride {
+        AccountOverride {
+            code: Some(Bytes::from(hex::decode(code).unwrap())),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_simulate_blocks_state_carries_over() {
+        let engine = engine();
+        let writer = Address::repeat_byte(0xaa);
+        let parent =
+            BlockHeader { number: 100, hash: B256::ZERO, timestamp: 1_000, ..Default::default() };
+
+        let blocks = vec![
+            BlockStateCalls {
+                block_overrides: None,
+                state_overrides: Some(HashMap::from([(writer, code_override(STORE_AND_RETURN))])),
+                calls: vec![SimulatedCall {
+                    to: Some(writer),
+                    data: Bytes::from(
+                        U256::from(42)
+                            .to_be_bytes::<32>()
+                            .to_vec(),
+                    ),
+                    ..Default::default()
+                }],
+            },
+            // Replacing the code keeps the storage written in the previous block
+            BlockStateCalls {
+                block_overrides: Some(BlockOverrides { time: Some(5_000), ..Default::default() }),
+                state_overrides: Some(HashMap::from([(writer, code_override(LOAD_AND_RETURN))])),
+                calls: vec![SimulatedCall { to: Some(writer), ..Default::default() }],
+            },
+            BlockStateCalls {
+                block_overrides: None,
+                state_overrides: Some(HashMap::from([(writer, code_override(STORE_AND_RETURN))])),
+                calls: vec![SimulatedCall { to: Some(writer), ..Default::default() }],
+            },
+        ];
+
+        let res = engine
+            .simulate_blocks(&parent, &blocks)
+            .unwrap();
+
+        assert_eq!(res.len(), 3);
+        assert_eq!((res[0].number, res[0].timestamp), (101, 1_012));
+        assert_eq!((res[1].number, res[1].timestamp), (102, 5_000));
+        assert!(res[0].calls[0].status);
+        assert_eq!(U256::from_be_slice(&res[0].calls[0].return_data), U256::from(42));
+        // The value stored in the first block is read in the second one
+        assert!(res[1].calls[0].status);
+        assert_eq!(U256::from_be_slice(&res[1].calls[0].return_data), U256::from(42));
+        // The third call stores 0, previously stored value is overwritten
+        assert_eq!(U256::from_be_slice(&res[2].calls[0].return_data), U256::ZERO);
+    }
+
+    /// Fails every read
+    #[derive(Debug)]
+    struct FailingDatabase;
+
+    impl DatabaseRef for FailingDatabase {
+        type Error = String;
+
+        fn basic_ref(&self, _address: Address) -> Result<Option<AccountInfo>, Self::Error> {
+            Err("Unavailable".to_string())
+        }
+
+        fn code_by_hash_ref(&self, _code_hash: B256) -> Result<Bytecode, Self::Error> {
+            Err("Unavailable".to_string())
+        }
+
+        fn storage_ref(&self, _address: Address, _index: U256) -> Result<U256, Self::Error> {
+            Err("Unavailable".to_string())
+        }
+
+        fn block_hash_ref(&self, _number: u64) -> Result<B256, Self::Error> {
+            Err("Unavailable".to_string())
+        }
+    }
+
+    #[test]
+    fn test_apply_state_overrides_db_error() {
+        let mut db = CacheDB::new(FailingDatabase);
+        let overrides =
+            HashMap::from([(Address::repeat_byte(0xaa), code_override(LOAD_AND_RETURN))]);
+
+        let res = apply_state_overrides(&mut db, &overrides, |_| false);
+
+        assert!(matches!(res, Err(SimulationEngineError::StorageError(_))));
+    }
+
+    #[test]
+    fn test_simulate_blocks_state_diff_override() {
+        let engine = engine();
+        let reader = Address::repeat_byte(0xbb);
+        let mut account_override = code_override(LOAD_AND_RETURN);
+        account_override.state_diff = Some(HashMap::from([(U256::ZERO, U256::from(7))]));
+
+        let res = engine
+            .simulate_blocks(
+                &BlockHeader::default(),
+                &[BlockStateCalls {
+                    block_overrides: None,
+                    state_overrides: Some(HashMap::from([(reader, account_override)])),
+                    calls: vec![SimulatedCall { to: Some(reader), ..Default::default() }],
+                }],
+            )
+            .unwrap();
+
+        assert_eq!(U256::from_be_slice(&res[0].calls[0].return_data), U256::from(7));
+        assert_eq!(res[0].gas_used, res[0].calls[0].gas_used);
+    }
+}
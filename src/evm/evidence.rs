@@ -0,0 +1,394 @@
+//! Evidence bundles of simulations
+//!
+//! Quotes can be disputed long after they were served, when neither the pool's state nor a node
+//! serving the state of that block may be available anymore. `SimulationEngine::export_evidence`
+//! re-runs a simulation while recording everything it reads and packs it into an
+//! `EvidenceBundle`: the block, the calldata, the values of every account and storage slot the
+//! transaction touched, a fingerprint of that state and the outcome. `verify_evidence` re-runs a
+//! bundle offline, against nothing but the recorded state, and checks it reproduces the outcome.
+//!
+//! Bundles can be signed by whoever served the quote, see `EvidenceBundle::sign`, so third parties
+//! can check who attested to it.
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+use alloy::signers::{local::PrivateKeySigner, SignerSync};
+use alloy_primitives::{keccak256, Address, Bytes, Signature, B256, U256};
+use revm::{primitives::SpecId, DatabaseRef, Evm};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::{
+    engine_db::{
+        engine_db_interface::EngineDatabaseInterface,
+        simulation_db::{BlockHeader, OverriddenSimulationDB},
+    },
+    failure_dump::{DumpedAccount, RecordedDB, RecordingDB},
+    simulation::{
+        interpret_evm_result, SimulationEngine, SimulationEngineError, SimulationParameters,
+        SimulationResult,
+    },
+};
+
+#[derive(Error, Debug)]
+pub enum EvidenceError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid bundle: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Simulation failed: {0:?}")]
+    Simulation(SimulationEngineError),
+    #[error("Recorded state doesn't match the fingerprint {expected}, got {actual}")]
+    Fingerprint { expected: B256, actual: B256 },
+    #[error("Replayed simulation doesn't match the bundle: {0}")]
+    Mismatch(String),
+    #[error("Invalid signature: {0}")]
+    Signature(String),
+}
+
+/// A simulation with everything needed to reproduce it, see module docs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EvidenceBundle {
+    /// The block the simulation ran in
+    pub block: BlockHeader,
+    pub caller: Address,
+    pub to: Address,
+    pub data: Bytes,
+    pub value: U256,
+    pub overrides: Option<HashMap<Address, HashMap<U256, U256>>>,
+    pub gas_limit: Option<u64>,
+    pub spec_id: SpecId,
+    /// Whether the simulation didn't pay gas, see `SimulationEngine::with_gasless`
+    pub gasless: bool,
+    /// Accounts and storage slots read by the simulation, with the values it saw
+    pub accounts: HashMap<Address, DumpedAccount>,
+    pub block_hashes: HashMap<u64, B256>,
+    /// Hash of the recorded state, see `EvidenceBundle::state_fingerprint`
+    pub state_fingerprint: B256,
+    /// Output of the simulation
+    pub output: Bytes,
+    pub gas_used: u64,
+    /// Signature of `EvidenceBundle::digest`, `None` if the bundle isn't signed
+    pub signature: Option<Bytes>,
+}
+
+impl EvidenceBundle {
+    pub fn params(&self) -> SimulationParameters {
+        SimulationParameters {
+            caller: self.caller,
+            to: self.to,
+            data: self.data.to_vec(),
+            value: self.value,
+            overrides: self.overrides.clone(),
+            gas_limit: self.gas_limit,
+            spec_id: Some(self.spec_id),
+            block: self.block,
+        }
+    }
+
+    /// Hash of the recorded accounts, storage slots and block hashes, independent of their order.
+    pub fn state_fingerprint(&self) -> B256 {
+        let mut buf = Vec::new();
+        let mut accounts: Vec<_> = self.accounts.iter().collect();
+        accounts.sort_by_key(|(address, _)| **address);
+        for (address, account) in accounts {
+            buf.extend_from_slice(address.as_slice());
+            buf.push(account.exists as u8);
+            buf.extend_from_slice(&account.balance.to_be_bytes::<32>());
+            buf.extend_from_slice(&account.nonce.to_be_bytes());
+            buf.extend_from_slice(account.code_hash.as_slice());
+            encode_slots(&mut buf, &account.storage);
+        }
+        let mut block_hashes: Vec<_> = self.block_hashes.iter().collect();
+        block_hashes.sort();
+        for (number, hash) in block_hashes {
+            buf.extend_from_slice(&number.to_be_bytes());
+            buf.extend_from_slice(hash.as_slice());
+        }
+        keccak256(buf)
+    }
+
+    /// Hash of everything the bundle attests to: the transaction, its block, the state it ran on
+    /// and its outcome. This is what signatures sign.
+    pub fn digest(&self) -> B256 {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.block.number.to_be_bytes());
+        buf.extend_from_slice(self.block.hash.as_slice());
+        buf.extend_from_slice(self.block.parent_hash.as_slice());
+        buf.extend_from_slice(&self.block.timestamp.to_be_bytes());
+        match self.block.basefee {
+            Some(basefee) => {
+                buf.push(1);
+                buf.extend_from_slice(&basefee.to_be_bytes());
+            }
+            None => buf.push(0),
+        }
+        buf.extend_from_slice(self.caller.as_slice());
+        buf.extend_from_slice(self.to.as_slice());
+        buf.extend_from_slice(keccak256(&self.data).as_slice());
+        buf.extend_from_slice(&self.value.to_be_bytes::<32>());
+        let mut overrides: Vec<_> = self
+            .overrides
+            .iter()
+            .flatten()
+            .collect();
+        overrides.sort_by_key(|(address, _)| **address);
+        for (address, slots) in overrides {
+            buf.extend_from_slice(address.as_slice());
+            encode_slots(&mut buf, slots);
+        }
+        buf.extend_from_slice(
+            &self
+                .gas_limit
+                .unwrap_or_default()
+                .to_be_bytes(),
+        );
+        buf.push(self.spec_id as u8);
+        buf.push(self.gasless as u8);
+        buf.extend_from_slice(self.state_fingerprint.as_slice());
+        buf.extend_from_slice(keccak256(&self.output).as_slice());
+        buf.extend_from_slice(&self.gas_used.to_be_bytes());
+        keccak256(buf)
+    }
+
+    /// Signs the bundle's digest.
+    pub fn sign(&mut self, signer: &PrivateKeySigner) -> Result<(), EvidenceError> {
+        let signature = signer
+            .sign_hash_sync(&self.digest())
+            .map_err(|e| EvidenceError::Signature(e.to_string()))?;
+        self.signature = Some(Bytes::copy_from_slice(&signature.as_bytes()));
+        Ok(())
+    }
+
+    /// The address that signed the bundle, `None` if it isn't signed.
+    pub fn signer(&self) -> Result<Option<Address>, EvidenceError> {
+        let Some(signature) = &self.signature else {
+            return Ok(None);
+        };
+        let signature = Signature::try_from(signature.as_ref())
+            .map_err(|e| EvidenceError::Signature(e.to_string()))?;
+        signature
+            .recover_address_from_prehash(&self.digest())
+            .map(Some)
+            .map_err(|e| EvidenceError::Signature(e.to_string()))
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), EvidenceError> {
+        serde_json::to_writer_pretty(BufWriter::new(File::create(path)?), self)?;
+        Ok(())
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, EvidenceError> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+}
+
+fn encode_slots(buf: &mut Vec<u8>, slots: &HashMap<U256, U256>) {
+    let mut slots: Vec<_> = slots.iter().collect();
+    slots.sort();
+    buf.extend_from_slice(&(slots.len() as u64).to_be_bytes());
+    for (slot, value) in slots {
+        buf.extend_from_slice(&slot.to_be_bytes::<32>());
+        buf.extend_from_slice(&value.to_be_bytes::<32>());
+    }
+}
+
+impl<D: EngineDatabaseInterface + Clone + std::fmt::Debug> SimulationEngine<D>
+where
+    <D as DatabaseRef>::Error: std::fmt::Debug,
+    <D as EngineDatabaseInterface>::Error: std::fmt::Debug,
+{
+    /// Simulates a transaction and records the evidence needed to reproduce it, see module docs.
+    ///
+    /// # Errors
+    ///
+    /// * `Simulation` - the simulation failed, there is no outcome to attest to
+    pub fn export_evidence(
+        &self,
+        params: &SimulationParameters,
+    ) -> Result<EvidenceBundle, EvidenceError> {
+        let spec_id = self
+            .resolve_spec_id(params.spec_id)
+            .map_err(EvidenceError::Simulation)?;
+        let overrides = params
+            .overrides
+            .clone()
+            .unwrap_or_default();
+        let db_ref = OverriddenSimulationDB { inner_db: &self.state, overrides: &overrides };
+        let recording = RecordingDB::new(&db_ref);
+        let evm_result = {
+            let mut vm = Evm::builder()
+                .with_spec_id(spec_id)
+                .with_ref_db(&recording)
+                .with_block_env(params.block_env())
+                .with_tx_env(params.tx_env(self.is_gasless()))
+                .modify_cfg_env(|cfg| configure_gas(cfg, self.is_gasless()))
+                .build();
+            vm.transact()
+        };
+        let result =
+            interpret_evm_result(evm_result, |_| None).map_err(EvidenceError::Simulation)?;
+
+        let mut bundle = EvidenceBundle {
+            block: params.block,
+            caller: params.caller,
+            to: params.to,
+            data: Bytes::copy_from_slice(&params.data),
+            value: params.value,
+            overrides: params.overrides.clone(),
+            gas_limit: params.gas_limit,
+            spec_id,
+            gasless: self.is_gasless(),
+            accounts: recording.accounts.into_inner().unwrap(),
+            block_hashes: recording
+                .block_hashes
+                .into_inner()
+                .unwrap(),
+            state_fingerprint: B256::ZERO,
+            output: result.result.into(),
+            gas_used: result.gas_used,
+            signature: None,
+        };
+        bundle.state_fingerprint = bundle.state_fingerprint();
+        Ok(bundle)
+    }
+}
+
+/// Re-runs the simulation of a bundle offline, against only its recorded state, and checks that
+/// it reproduces the recorded outcome. Signatures aren't checked, see `EvidenceBundle::signer`.
+///
+/// # Errors
+///
+/// * `Fingerprint` - the recorded state was changed after the bundle was created
+/// * `Simulation` - the replayed simulation failed
+/// * `Mismatch` - the replayed simulation has a different outcome
+pub fn verify_evidence(bundle: &EvidenceBundle) -> Result<SimulationResult, EvidenceError> {
+    let fingerprint = bundle.state_fingerprint();
+    if fingerprint != bundle.state_fingerprint {
+        return Err(EvidenceError::Fingerprint {
+            expected: bundle.state_fingerprint,
+            actual: fingerprint,
+        });
+    }
+    for (address, account) in &bundle.accounts {
+        if let Some(code) = &account.code {
+            if keccak256(code) != account.code_hash {
+                return Err(EvidenceError::Mismatch(format!(
+                    "Code of {address} doesn't match its hash"
+                )));
+            }
+        }
+    }
+
+    let mut engine =
+        SimulationEngine::new(RecordedDB::new(&bundle.accounts, &bundle.block_hashes), false);
+    if bundle.gasless {
+        engine = engine.with_gasless();
+    }
+    let result = engine
+        .simulate(&bundle.params())
+        .map_err(EvidenceError::Simulation)?;
+    if result.result.as_ref() != bundle.output.as_ref() {
+        return Err(EvidenceError::Mismatch(format!(
+            "Output {} instead of {}",
+            Bytes::copy_from_slice(&result.result),
+            bundle.output
+        )));
+    }
+    if result.gas_used != bundle.gas_used {
+        return Err(EvidenceError::Mismatch(format!(
+            "Gas used {} instead of {}",
+            result.gas_used, bundle.gas_used
+        )));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy_primitives::hex;
+    use revm::primitives::{AccountInfo, Bytecode};
+
+    use super::*;
+    use crate::evm::engine_db::tycho_db::PreCachedDB;
+
+    // Runtime code returning slot 0:
+    // PUSH1 0 SLOAD PUSH1 0 MSTORE PUSH1 32 PUSH1 0 RETURN
+    const LOAD_AND_RETURN: &str = "60005460005260206000f3";
+
+    #[test]
+    fn test_export_and_verify_evidence() {
+        let dir = tempfile::tempdir().unwrap();
+        let contract = Address::repeat_byte(0xaa);
+        let caller = Address::repeat_byte(0x01);
+        let code = Bytecode::new_raw(
+            hex::decode(LOAD_AND_RETURN)
+                .unwrap()
+                .into(),
+        );
+        // The caller has no balance, so the simulation only succeeds gasless
+        let engine = SimulationEngine::new(PreCachedDB::new().unwrap(), false).with_gasless();
+        engine.state.init_account(
+            contract,
+            AccountInfo { code_hash: code.hash_slow(), code: Some(code), ..Default::default() },
+            Some(HashMap::from([(U256::ZERO, U256::from(42)), (U256::from(1), U256::from(5))])),
+            false,
+        );
+        engine
+            .state
+            .init_account(caller, AccountInfo::default(), None, false);
+        let params = SimulationParameters {
+            caller,
+            to: contract,
+            data: vec![],
+            value: U256::ZERO,
+            overrides: None,
+            gas_limit: None,
+            spec_id: None,
+            block: BlockHeader {
+                number: 1,
+                hash: B256::repeat_byte(1),
+                timestamp: 1,
+                basefee: Some(7),
+                ..Default::default()
+            },
+        };
+        let signer = PrivateKeySigner::random();
+
+        let mut bundle = engine.export_evidence(&params).unwrap();
+        bundle.sign(&signer).unwrap();
+        let path = dir.path().join("evidence.json");
+        bundle.write(&path).unwrap();
+        let bundle = EvidenceBundle::read(&path).unwrap();
+
+        // Only the touched slot is recorded
+        assert_eq!(
+            bundle.accounts[&contract].storage,
+            HashMap::from([(U256::ZERO, U256::from(42))])
+        );
+        assert_eq!(U256::from_be_slice(&bundle.output), U256::from(42));
+        assert_eq!(bundle.signer().unwrap(), Some(signer.address()));
+        assert_eq!(bundle.block, params.block);
+        assert!(bundle.gasless);
+        let replayed = verify_evidence(&bundle).unwrap();
+        assert_eq!(replayed.gas_used, bundle.gas_used);
+
+        // Tampering with the state or the outcome is detected
+        let mut tampered = bundle.clone();
+        tampered
+            .accounts
+            .get_mut(&contract)
+            .unwrap()
+            .storage
+            .insert(U256::ZERO, U256::from(43));
+        assert!(matches!(verify_evidence(&tampered), Err(EvidenceError::Fingerprint { .. })));
+        let mut tampered = bundle.clone();
+        tampered.output = Bytes::from(U256::from(43).to_be_bytes::<32>());
+        assert!(matches!(verify_evidence(&tampered), Err(EvidenceError::Mismatch(_))));
+        assert_ne!(tampered.signer().unwrap(), Some(signer.address()));
+    }
+}
@@ -0,0 +1,474 @@
+//! Reproducer dumps of failing simulations
+//!
+//! If enabled with `SimulationEngine::with_failure_dumps`, every failing simulation is written to
+//! a self-contained JSON file: the simulation parameters, the error and every account, storage
+//! slot and block hash the transaction read, with their values. `replay_failure` re-runs such a
+//! file offline, without access to the original database or a node.
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use alloy_primitives::{Address, Bytes, B256, U256};
+use revm::{
+    primitives::{AccountInfo, Bytecode, SpecId, KECCAK_EMPTY},
+    DatabaseRef, Evm,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{info, warn};
+
+use super::{
+    engine_db::{
+        engine_db_interface::EngineDatabaseInterface,
+        simulation_db::{BlockHeader, OverriddenSimulationDB},
+        tycho_db::{PreCachedDB, PreCachedDBError},
+    },
+    simulation::{
+        configure_gas, SimulationEngine, SimulationEngineError, SimulationParameters,
+        SimulationResult,
+    },
+};
+
+#[derive(Error, Debug)]
+pub enum FailureDumpError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid dump: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Replayed simulation failed: {0:?}")]
+    Simulation(SimulationEngineError),
+}
+
+/// State of an account read by a failing simulation.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DumpedAccount {
+    /// Whether the account existed. Storage of missing accounts is always zero.
+    pub exists: bool,
+    pub balance: U256,
+    pub nonce: u64,
+    pub code_hash: B256,
+    pub code: Option<Bytes>,
+    /// Read storage slots, with the values seen by the simulation
+    pub storage: HashMap<U256, U256>,
+}
+
+/// A failing simulation, with everything needed to re-run it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FailureDump {
+    pub caller: Address,
+    pub to: Address,
+    pub data: Bytes,
+    pub value: U256,
+    pub overrides: Option<HashMap<Address, HashMap<U256, U256>>>,
+    pub gas_limit: Option<u64>,
+    pub spec_id: SpecId,
+    /// The block the simulation ran in
+    pub block: BlockHeader,
+    /// Whether the failing engine was gasless, see `SimulationEngine::with_gasless`
+    #[serde(default)]
+    pub gasless: bool,
+    /// The error of the original simulation
+    pub error: String,
+    pub accounts: HashMap<Address, DumpedAccount>,
+    pub block_hashes: HashMap<u64, B256>,
+}
+
+impl FailureDump {
+    pub fn params(&self) -> SimulationParameters {
+        SimulationParameters {
+            caller: self.caller,
+            to: self.to,
+            data: self.data.to_vec(),
+            value: self.value,
+            overrides: self.overrides.clone(),
+            gas_limit: self.gas_limit,
+            spec_id: Some(self.spec_id),
+            block: self.block,
+        }
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), FailureDumpError> {
+        serde_json::to_writer_pretty(BufWriter::new(File::create(path)?), self)?;
+        Ok(())
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, FailureDumpError> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+}
+
+/// Database wrapper recording everything read through it.
+pub(crate) struct RecordingDB<'a, DB: DatabaseRef> {
+    inner: &'a DB,
+    pub(crate) accounts: Mutex<HashMap<Address, DumpedAccount>>,
+    pub(crate) block_hashes: Mutex<HashMap<u64, B256>>,
+}
+
+impl<'a, DB: DatabaseRef> RecordingDB<'a, DB> {
+    pub(crate) fn new(inner: &'a DB) -> Self {
+        Self { inner, accounts: Mutex::default(), block_hashes: Mutex::default() }
+    }
+}
+
+impl<DB: DatabaseRef> DatabaseRef for RecordingDB<'_, DB> {
+    type Error = DB::Error;
+
+    fn basic_ref(&self, address: Address) -> Result<Option<AccountInfo>, Self::Error> {
+        let info = self.inner.basic_ref(address)?;
+        let mut accounts = self.accounts.lock().unwrap();
+        let account = accounts.entry(address).or_default();
+        if let Some(info) = &info {
+            account.exists = true;
+            account.balance = info.balance;
+            account.nonce = info.nonce;
+            account.code_hash = info.code_hash;
+            account.code = info
+                .code
+                .as_ref()
+                .map(Bytecode::original_bytes);
+        }
+        Ok(info)
+    }
+
+    fn code_by_hash_ref(&self, code_hash: B256) -> Result<Bytecode, Self::Error> {
+        let code = self.inner.code_by_hash_ref(code_hash)?;
+        for account in self
+            .accounts
+            .lock()
+            .unwrap()
+            .values_mut()
+        {
+            if account.code_hash == code_hash {
+                account.code = Some(code.original_bytes());
+            }
+        }
+        Ok(code)
+    }
+
+    fn storage_ref(&self, address: Address, index: U256) -> Result<U256, Self::Error> {
+        let value = self.inner.storage_ref(address, index)?;
+        self.accounts
+            .lock()
+            .unwrap()
+            .entry(address)
+            .or_default()
+            .storage
+            .insert(index, value);
+        Ok(value)
+    }
+
+    fn block_hash_ref(&self, number: u64) -> Result<B256, Self::Error> {
+        let hash = self.inner.block_hash_ref(number)?;
+        self.block_hashes
+            .lock()
+            .unwrap()
+            .insert(number, hash);
+        Ok(hash)
+    }
+}
+
+impl<D: EngineDatabaseInterface + Clone + std::fmt::Debug> SimulationEngine<D>
+where
+    <D as DatabaseRef>::Error: std::fmt::Debug,
+    <D as EngineDatabaseInterface>::Error: std::fmt::Debug,
+{
+    /// Re-runs a failed simulation while recording the state it reads and writes a reproducer
+    /// into `dir`. Returns the path of the written file.
+    pub(crate) fn dump_failure(
+        &self,
+        dir: &Path,
+        params: &SimulationParameters,
+        spec_id: SpecId,
+        error: &SimulationEngineError,
+    ) -> Result<PathBuf, FailureDumpError> {
+        let overrides = params
+            .overrides
+            .clone()
+            .unwrap_or_default();
+        let db_ref = OverriddenSimulationDB { inner_db: &self.state, overrides: &overrides };
+        let recording = RecordingDB::new(&db_ref);
+        {
+            let mut vm = Evm::builder()
+                .with_spec_id(spec_id)
+                .with_ref_db(&recording)
+                .with_block_env(params.block_env())
+                .with_tx_env(params.tx_env(self.is_gasless()))
+                .modify_cfg_env(|cfg| configure_gas(cfg, self.is_gasless()))
+                .build();
+            // The result is already known, only the reads matter
+            let _ = vm.transact();
+        }
+
+        let dump = FailureDump {
+            caller: params.caller,
+            to: params.to,
+            data: Bytes::copy_from_slice(&params.data),
+            value: params.value,
+            overrides: params.overrides.clone(),
+            gas_limit: params.gas_limit,
+            spec_id,
+            block: params.block,
+            gasless: self.is_gasless(),
+            error: format!("{:?}", error),
+            accounts: recording.accounts.into_inner().unwrap(),
+            block_hashes: recording
+                .block_hashes
+                .into_inner()
+                .unwrap(),
+        };
+
+        fs::create_dir_all(dir)?;
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_nanos();
+        let path =
+            dir.join(format!("failure_{}_{}_{}.json", params.block.number, params.to, nanos));
+        dump.write(&path)?;
+        info!(?path, "Wrote simulation failure reproducer");
+        Ok(path)
+    }
+
+    /// Writes a reproducer if failure dumps are enabled. Errors are logged, never returned, so
+    /// dumping can't change the outcome of a simulation.
+    pub(crate) fn maybe_dump_failure(
+        &self,
+        params: &SimulationParameters,
+        spec_id: SpecId,
+        error: &SimulationEngineError,
+    ) {
+        if let Some(dir) = self.failure_dump_dir() {
+            if let Err(e) = self.dump_failure(dir, params, spec_id, error) {
+                warn!(error = %e, "Failed to write simulation failure reproducer");
+            }
+        }
+    }
+}
+
+/// Re-runs a simulation from a reproducer file written for a failing simulation.
+///
+/// The simulation runs against an in-memory database holding only the recorded state, see
+/// `RecordedDB`.
+pub fn replay_failure(path: impl AsRef<Path>) -> Result<SimulationResult, FailureDumpError> {
+    let dump = FailureDump::read(path)?;
+    let mut engine =
+        SimulationEngine::new(RecordedDB::new(&dump.accounts, &dump.block_hashes), false);
+    if dump.gasless {
+        engine = engine.with_gasless();
+    }
+    engine
+        .simulate(&dump.params())
+        .map_err(FailureDumpError::Simulation)
+}
+
+/// An in-memory database serving only recorded state, to replay a recorded simulation the way it
+/// ran: accounts recorded as missing don't exist and `BLOCKHASH` returns the recorded hashes, zero
+/// for blocks the simulation didn't read.
+#[derive(Debug, Clone)]
+pub(crate) struct RecordedDB {
+    state: PreCachedDB,
+    missing: HashSet<Address>,
+    block_hashes: HashMap<u64, B256>,
+}
+
+impl RecordedDB {
+    pub(crate) fn new(
+        accounts: &HashMap<Address, DumpedAccount>,
+        block_hashes: &HashMap<u64, B256>,
+    ) -> Self {
+        let state = PreCachedDB::new().expect("Creating an empty PreCachedDB can't fail");
+        let mut missing = HashSet::new();
+        for (address, account) in accounts {
+            if !account.exists {
+                missing.insert(*address);
+                continue;
+            }
+            let code = account
+                .code
+                .clone()
+                .map(Bytecode::new_raw);
+            let code_hash = match &code {
+                Some(code) => code.hash_slow(),
+                None => KECCAK_EMPTY,
+            };
+            state.init_account(
+                *address,
+                AccountInfo { balance: account.balance, nonce: account.nonce, code_hash, code },
+                Some(account.storage.clone()),
+                true,
+            );
+        }
+        Self { state, missing, block_hashes: block_hashes.clone() }
+    }
+}
+
+impl DatabaseRef for RecordedDB {
+    type Error = PreCachedDBError;
+
+    fn basic_ref(&self, address: Address) -> Result<Option<AccountInfo>, Self::Error> {
+        if self.missing.contains(&address) {
+            return Ok(None);
+        }
+        self.state.basic_ref(address)
+    }
+
+    fn code_by_hash_ref(&self, code_hash: B256) -> Result<Bytecode, Self::Error> {
+        self.state.code_by_hash_ref(code_hash)
+    }
+
+    fn storage_ref(&self, address: Address, index: U256) -> Result<U256, Self::Error> {
+        if self.missing.contains(&address) {
+            return Ok(U256::ZERO);
+        }
+        self.state.storage_ref(address, index)
+    }
+
+    fn block_hash_ref(&self, number: u64) -> Result<B256, Self::Error> {
+        Ok(self
+            .block_hashes
+            .get(&number)
+            .copied()
+            .unwrap_or_default())
+    }
+}
+
+impl EngineDatabaseInterface for RecordedDB {
+    type Error = String;
+
+    fn init_account(
+        &self,
+        address: Address,
+        account: AccountInfo,
+        permanent_storage: Option<HashMap<U256, U256>>,
+        mocked: bool,
+    ) {
+        self.state
+            .init_account(address, account, permanent_storage, mocked)
+    }
+
+    fn clear_temp_storage(&mut self) {
+        self.state.clear_temp_storage()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy_primitives::hex;
+
+    use super::*;
+
+    // Runtime code reverting unless slot 0 is non-zero:
+    // PUSH1 0 SLOAD PUSH1 9 JUMPI PUSH1 0 DUP1 REVERT JUMPDEST STOP
+    const REVERT_UNLESS_SET: &str = "600054600a57600080fd5b00";
+
+    #[test]
+    fn test_dump_and_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let contract = Address::repeat_byte(0xaa);
+        let caller = Address::repeat_byte(0x01);
+        let code = Bytecode::new_raw(
+            hex::decode(REVERT_UNLESS_SET)
+                .unwrap()
+                .into(),
+        );
+        let engine = SimulationEngine::new(PreCachedDB::new().unwrap(), false)
+            .with_failure_dumps(dir.path());
+        engine.state.init_account(
+            contract,
+            AccountInfo {
+                balance: U256::ZERO,
+                nonce: 0,
+                code_hash: code.hash_slow(),
+                code: Some(code),
+            },
+            Some(HashMap::from([(U256::from(1), U256::from(5))])),
+            false,
+        );
+        engine
+            .state
+            .init_account(caller, AccountInfo::default(), None, false);
+        let mut params = SimulationParameters {
+            caller,
+            to: contract,
+            data: vec![],
+            value: U256::ZERO,
+            overrides: None,
+            gas_limit: None,
+            spec_id: None,
+            block: BlockHeader { number: 1, timestamp: 1, ..Default::default() },
+        };
+
+        assert!(engine.simulate(&params).is_err());
+
+        let path = fs::read_dir(dir.path())
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let dump = FailureDump::read(&path).unwrap();
+        assert_eq!(dump.accounts[&contract].storage, HashMap::from([(U256::ZERO, U256::ZERO)]));
+        assert!(dump.accounts[&contract].code.is_some());
+        assert!(matches!(
+            replay_failure(&path),
+            Err(FailureDumpError::Simulation(SimulationEngineError::TransactionError { .. }))
+        ));
+
+        // Successful simulations are not dumped
+        params.overrides =
+            Some(HashMap::from([(contract, HashMap::from([(U256::ZERO, U256::from(1))]))]));
+        assert!(engine.simulate(&params).is_ok());
+        assert_eq!(
+            fs::read_dir(dir.path())
+                .unwrap()
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_recorded_db() {
+        let existing = Address::repeat_byte(0x01);
+        let missing = Address::repeat_byte(0x02);
+        let accounts = HashMap::from([
+            (
+                existing,
+                DumpedAccount {
+                    exists: true,
+                    balance: U256::from(3),
+                    code_hash: KECCAK_EMPTY,
+                    storage: HashMap::from([(U256::ZERO, U256::from(7))]),
+                    ..Default::default()
+                },
+            ),
+            (missing, DumpedAccount::default()),
+        ]);
+        let db = RecordedDB::new(&accounts, &HashMap::from([(9, B256::repeat_byte(9))]));
+
+        assert_eq!(
+            db.basic_ref(existing)
+                .unwrap()
+                .unwrap()
+                .balance,
+            U256::from(3)
+        );
+        assert_eq!(
+            db.storage_ref(existing, U256::ZERO)
+                .unwrap(),
+            U256::from(7)
+        );
+        assert_eq!(db.basic_ref(missing).unwrap(), None);
+        assert_eq!(
+            db.storage_ref(missing, U256::ZERO)
+                .unwrap(),
+            U256::ZERO
+        );
+        assert_eq!(db.block_hash_ref(9).unwrap(), B256::repeat_byte(9));
+        assert_eq!(db.block_hash_ref(8).unwrap(), B256::ZERO);
+    }
+}
@@ -0,0 +1,331 @@
+//! C ABI for embedding the quoting library
+//!
+//! Enabled with the `ffi` feature. Build a shared library with
+//! `cargo rustc --lib --release --features ffi --crate-type cdylib`; the matching header is
+//! `ffi/tycho_simulation.h`.
+//!
+//! An engine is created from a snapshot file and quotes the pools it contains:
+//!
+//! ```c
+//! TychoEngine *engine = tycho_engine_new("snapshot.json");
+//! if (engine == NULL) { fprintf(stderr, "%s\n", tycho_last_error()); }
+//! char *amount_out = NULL;
+//! uint64_t gas = 0;
+//! if (tycho_quote(engine, pool_id, token_in, token_out, "1000000", &amount_out, &gas) == 0) {
+//!     printf("%s\n", amount_out);
+//!     tycho_string_free(amount_out);
+//! }
+//! tycho_engine_free(engine);
+//! ```
+//!
+//! All strings are NUL terminated UTF-8. Addresses are hex strings, amounts are decimal strings in
+//! the token's smallest unit. Functions returning `int32_t` return `TYCHO_OK` (0) on success and a
+//! negative error code otherwise; the message of the last error on the calling thread is returned
+//! by `tycho_last_error`. Panics don't unwind into the caller: they are reported as
+//! `TYCHO_ERR_PANIC`.
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    ffi::{c_char, CStr, CString},
+    fs::File,
+    io::BufReader,
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::Path,
+    ptr,
+    str::FromStr,
+};
+
+use num_bigint::BigUint;
+use serde::Deserialize;
+use tokio::runtime::Runtime;
+use tycho_client::feed::FeedMessage;
+use tycho_core::{dto::ResponseToken, Bytes};
+
+use crate::{evm::decoder::TychoStreamDecoder, models::Token, protocol::freshness::StateStore};
+
+pub const TYCHO_OK: i32 = 0;
+/// A required pointer argument was null
+pub const TYCHO_ERR_NULL: i32 = -1;
+/// An argument is not valid UTF-8 or could not be parsed
+pub const TYCHO_ERR_INVALID_ARGUMENT: i32 = -2;
+/// The token or pool is unknown to the engine
+pub const TYCHO_ERR_UNKNOWN: i32 = -3;
+/// The simulation failed
+pub const TYCHO_ERR_SIMULATION: i32 = -4;
+/// The library panicked
+pub const TYCHO_ERR_PANIC: i32 = -5;
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_last_error(message: impl Into<String>) {
+    let message = CString::new(message.into().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+}
+
+/// Runs `f`, turning a panic into a `TYCHO_ERR_PANIC` error: unwinding across the C ABI is
+/// undefined behaviour.
+fn catch_panic<T>(f: impl FnOnce() -> Result<T, (i32, String)>) -> Result<T, (i32, String)> {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| {
+                payload
+                    .downcast_ref::<String>()
+                    .cloned()
+            })
+            .unwrap_or_else(|| "unknown panic".to_string());
+        Err((TYCHO_ERR_PANIC, format!("Panicked: {}", message)))
+    })
+}
+
+/// Snapshot an engine is created from: the tokens and a tycho feed message holding the snapshots
+/// of the pools.
+#[derive(Deserialize)]
+struct FfiSnapshot {
+    tokens: Vec<ResponseToken>,
+    message: FeedMessage,
+}
+
+/// Quoting engine handed out through the C ABI.
+pub struct TychoEngine {
+    states: StateStore,
+    tokens: HashMap<Bytes, Token>,
+}
+
+impl TychoEngine {
+    fn from_snapshot(path: &Path) -> Result<Self, String> {
+        let snapshot: FfiSnapshot =
+            serde_json::from_reader(BufReader::new(File::open(path).map_err(|e| e.to_string())?))
+                .map_err(|e| format!("Invalid snapshot: {}", e))?;
+        let tokens: HashMap<Bytes, Token> = snapshot
+            .tokens
+            .into_iter()
+            .filter_map(|token| {
+                Token::try_from(token)
+                    .ok()
+                    .map(|token| (token.address.clone(), token))
+            })
+            .collect();
+
+        let mut decoder = TychoStreamDecoder::new();
+        decoder.skip_state_decode_failures(true);
+        decoder.register_builtin_exchanges();
+
+        let runtime = Runtime::new().map_err(|e| e.to_string())?;
+        let update = runtime.block_on(async {
+            decoder.set_tokens(tokens.clone()).await;
+            decoder.decode(snapshot.message).await
+        });
+        let update = update.map_err(|e| format!("Failed to decode snapshot: {}", e))?;
+
+        let mut states = StateStore::new(None, 0);
+        states.apply(update);
+        Ok(Self { states, tokens })
+    }
+
+    fn quote(
+        &self,
+        pool_id: &str,
+        token_in: &str,
+        token_out: &str,
+        amount_in: &str,
+    ) -> Result<(BigUint, u64), (i32, String)> {
+        let token = |address: &str| {
+            let address = Bytes::from_str(address)
+                .map_err(|e| (TYCHO_ERR_INVALID_ARGUMENT, format!("Invalid address: {}", e)))?;
+            self.tokens
+                .get(&address)
+                .ok_or_else(|| (TYCHO_ERR_UNKNOWN, format!("Unknown token {}", address)))
+        };
+        let (token_in, token_out) = (token(token_in)?, token(token_out)?);
+        let amount_in = BigUint::from_str(amount_in)
+            .map_err(|e| (TYCHO_ERR_INVALID_ARGUMENT, format!("Invalid amount: {}", e)))?;
+        if self.states.get(pool_id).is_none() {
+            return Err((TYCHO_ERR_UNKNOWN, format!("Unknown pool {}", pool_id)));
+        }
+
+        let result = self
+            .states
+            .get_amount_out(pool_id, amount_in, token_in, token_out)
+            .map_err(|e| (TYCHO_ERR_SIMULATION, e.to_string()))?;
+        let gas = u64::try_from(&result.gas).unwrap_or(u64::MAX);
+        Ok((result.amount, gas))
+    }
+}
+
+/// Reads a NUL terminated UTF-8 string argument.
+///
+/// # Safety
+///
+/// `ptr` must be null or point to a NUL terminated string.
+unsafe fn read_str<'a>(ptr: *const c_char, name: &str) -> Result<&'a str, (i32, String)> {
+    if ptr.is_null() {
+        return Err((TYCHO_ERR_NULL, format!("{} is null", name)));
+    }
+    CStr::from_ptr(ptr)
+        .to_str()
+        .map_err(|_| (TYCHO_ERR_INVALID_ARGUMENT, format!("{} is not valid UTF-8", name)))
+}
+
+/// Creates an engine from a snapshot file. Returns null on failure.
+///
+/// # Safety
+///
+/// `snapshot_path` must be null or point to a NUL terminated string. The returned engine must be
+/// released with `tycho_engine_free`.
+#[no_mangle]
+pub unsafe extern "C" fn tycho_engine_new(snapshot_path: *const c_char) -> *mut TychoEngine {
+    let result = catch_panic(|| {
+        let path = read_str(snapshot_path, "snapshot_path")?;
+        TychoEngine::from_snapshot(Path::new(path)).map_err(|message| (TYCHO_ERR_UNKNOWN, message))
+    });
+    match result {
+        Ok(engine) => Box::into_raw(Box::new(engine)),
+        Err((_, message)) => {
+            set_last_error(message);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Quotes selling `amount_in` of `token_in` for `token_out` on a pool.
+///
+/// On success, `*amount_out` is set to the amount received, to be released with
+/// `tycho_string_free`, and `*gas` to the estimated gas of the swap.
+///
+/// # Safety
+///
+/// `engine` must be null or an engine returned by `tycho_engine_new`. String arguments must be
+/// null or point to NUL terminated strings, `amount_out` and `gas` must be null or valid for
+/// writes.
+#[no_mangle]
+pub unsafe extern "C" fn tycho_quote(
+    engine: *const TychoEngine,
+    pool_id: *const c_char,
+    token_in: *const c_char,
+    token_out: *const c_char,
+    amount_in: *const c_char,
+    amount_out: *mut *mut c_char,
+    gas: *mut u64,
+) -> i32 {
+    let result = catch_panic(|| {
+        let engine = engine
+            .as_ref()
+            .ok_or_else(|| (TYCHO_ERR_NULL, "engine is null".to_string()))?;
+        if amount_out.is_null() || gas.is_null() {
+            return Err((TYCHO_ERR_NULL, "output pointer is null".to_string()));
+        }
+        let (amount, gas_used) = engine.quote(
+            read_str(pool_id, "pool_id")?,
+            read_str(token_in, "token_in")?,
+            read_str(token_out, "token_out")?,
+            read_str(amount_in, "amount_in")?,
+        )?;
+        let amount = CString::new(amount.to_string()).expect("Decimal strings contain no NUL");
+        *amount_out = amount.into_raw();
+        *gas = gas_used;
+        Ok(())
+    });
+
+    match result {
+        Ok(()) => TYCHO_OK,
+        Err((code, message)) => {
+            set_last_error(message);
+            code
+        }
+    }
+}
+
+/// Returns the message of the last error on the calling thread. The string is owned by the
+/// library and valid until the next call on this thread.
+#[no_mangle]
+pub extern "C" fn tycho_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+/// Releases a string returned by the library.
+///
+/// # Safety
+///
+/// `string` must be null or a string returned by the library, not yet released.
+#[no_mangle]
+pub unsafe extern "C" fn tycho_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+/// Releases an engine.
+///
+/// # Safety
+///
+/// `engine` must be null or an engine returned by `tycho_engine_new`, not yet released.
+#[no_mangle]
+pub unsafe extern "C" fn tycho_engine_free(engine: *mut TychoEngine) {
+    if !engine.is_null() {
+        drop(Box::from_raw(engine));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(tycho_last_error()) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_engine_new_missing_snapshot() {
+        let path = CString::new("/nonexistent/snapshot.json").unwrap();
+
+        let engine = unsafe { tycho_engine_new(path.as_ptr()) };
+
+        assert!(engine.is_null());
+        assert!(!last_error().is_empty());
+    }
+
+    #[test]
+    fn test_quote_null_arguments() {
+        let mut amount_out = ptr::null_mut();
+        let mut gas = 0;
+        let pool = CString::new("0x01").unwrap();
+
+        let code = unsafe {
+            tycho_quote(
+                ptr::null(),
+                pool.as_ptr(),
+                pool.as_ptr(),
+                pool.as_ptr(),
+                pool.as_ptr(),
+                &mut amount_out,
+                &mut gas,
+            )
+        };
+
+        assert_eq!(code, TYCHO_ERR_NULL);
+        assert_eq!(last_error(), "engine is null");
+        assert!(amount_out.is_null());
+    }
+
+    #[test]
+    fn test_quote_unknown_token() {
+        let engine = TychoEngine { states: StateStore::new(None, 0), tokens: HashMap::new() };
+
+        let result = engine.quote("0x01", "0x02", "0x03", "100");
+
+        assert_eq!(result.map_err(|(code, _)| code), Err(TYCHO_ERR_UNKNOWN));
+    }
+
+    #[test]
+    fn test_catch_panic() {
+        let result: Result<(), _> = catch_panic(|| panic!("boom"));
+
+        assert_eq!(result, Err((TYCHO_ERR_PANIC, "Panicked: boom".to_string())));
+    }
+}
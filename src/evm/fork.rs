@@ -0,0 +1,265 @@
+//! In-process fork of a chain for integration tests
+//!
+//! `TestFork` runs simulations against the state of a node at a fixed block, like a lightweight
+//! anvil running in the test process. State not touched by the test is fetched lazily from the
+//! node; contracts deployed and accounts funded by the test only exist locally. Transactions run
+//! with `execute` are committed, so later calls observe their effects.
+use std::sync::Arc;
+
+use alloy::{
+    eips::BlockNumberOrTag,
+    providers::{Provider, ProviderBuilder, RootProvider},
+    transports::BoxTransport,
+};
+use alloy_primitives::{Address, Bytes, U256};
+use revm::DatabaseRef;
+use thiserror::Error;
+use tokio::runtime::Runtime;
+
+use super::{
+    account_storage::StateUpdate,
+    engine_db::{
+        account_builder::AccountBuilder,
+        create_engine,
+        simulation_db::{BlockHeader, SimulationDB},
+    },
+    simulation::{SimulationEngine, SimulationEngineError, SimulationParameters, SimulationResult},
+    StorageMap,
+};
+
+type ForkDB = SimulationDB<RootProvider<BoxTransport>>;
+
+#[derive(Error, Debug)]
+pub enum TestForkError {
+    #[error("TestFork can't be created from within an async runtime")]
+    InsideRuntime,
+    #[error("RPC error: {0}")]
+    Rpc(String),
+    #[error("Block {0} not found")]
+    UnknownBlock(u64),
+    #[error("Simulation failed: {0:?}")]
+    Simulation(SimulationEngineError),
+}
+
+/// A fork of a chain at a fixed block, see module docs.
+///
+/// `TestFork` blocks on network requests and owns its own runtime, so it must be used from
+/// synchronous code, e.g. plain `#[test]` functions.
+pub struct TestFork {
+    engine: SimulationEngine<ForkDB>,
+    block: BlockHeader,
+    // Keeps the runtime used by the database alive
+    _runtime: Arc<Runtime>,
+}
+
+impl TestFork {
+    /// Forks the chain of the node at `rpc_url` at `block_number`.
+    pub fn new(rpc_url: &str, block_number: u64) -> Result<Self, TestForkError> {
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return Err(TestForkError::InsideRuntime);
+        }
+        let runtime = Arc::new(Runtime::new().map_err(|e| TestForkError::Rpc(e.to_string()))?);
+
+        let (client, block) = runtime.block_on(async {
+            let client = ProviderBuilder::new()
+                .on_builtin(rpc_url)
+                .await
+                .map_err(|e| TestForkError::Rpc(e.to_string()))?;
+            let block = client
+                .get_block_by_number(BlockNumberOrTag::Number(block_number), false)
+                .await
+                .map_err(|e| TestForkError::Rpc(e.to_string()))?
+                .ok_or(TestForkError::UnknownBlock(block_number))?;
+            Ok::<_, TestForkError>((client, block))
+        })?;
+        // The node does not report a `Chain`, the default is kept
+        let block = BlockHeader {
+            number: block_number,
+            hash: block.header.hash,
+            parent_hash: block.header.parent_hash,
+            timestamp: block.header.timestamp,
+            basefee: block.header.base_fee_per_gas,
+            ..Default::default()
+        };
+
+        let db = SimulationDB::new(Arc::new(client), Some(runtime.clone()), Some(block));
+        let engine =
+            create_engine(db, false).map_err(|e| TestForkError::Rpc(format!("{:?}", e)))?;
+        Ok(Self { engine, block, _runtime: runtime })
+    }
+
+    /// Returns the block the chain was forked at.
+    pub fn block(&self) -> &BlockHeader {
+        &self.block
+    }
+
+    /// Returns the engine running the fork, e.g. to build pool states on top of it.
+    pub fn engine(&self) -> &SimulationEngine<ForkDB> {
+        &self.engine
+    }
+
+    /// Deploys runtime code at `address`, replacing any code and storage it has on chain.
+    pub fn deploy(&self, address: Address, code: impl Into<Bytes>) {
+        AccountBuilder::new(address)
+            .code(code)
+            .mocked()
+            .init(&self.engine.state);
+    }
+
+    /// Sets the native balance of an account.
+    pub fn fund(&mut self, address: Address, balance: U256) -> Result<(), TestForkError> {
+        self.commit(StorageMap::from_iter([(
+            address,
+            StateUpdate { balance: Some(balance), ..Default::default() },
+        )]))
+    }
+
+    /// Sets a storage slot of an account.
+    pub fn set_storage(
+        &mut self,
+        address: Address,
+        slot: U256,
+        value: U256,
+    ) -> Result<(), TestForkError> {
+        self.commit(StorageMap::from_iter([(
+            address,
+            StateUpdate {
+                storage: Some(StorageMap::from_iter([(slot, value)])),
+                ..Default::default()
+            },
+        )]))
+    }
+
+    /// Returns the native balance of an account.
+    pub fn balance(&self, address: Address) -> Result<U256, TestForkError> {
+        Ok(self
+            .engine
+            .state
+            .basic_ref(address)
+            .map_err(|e| TestForkError::Rpc(e.to_string()))?
+            .map(|info| info.balance)
+            .unwrap_or_default())
+    }
+
+    /// Returns the value of a storage slot.
+    pub fn storage(&self, address: Address, slot: U256) -> Result<U256, TestForkError> {
+        self.load(address)?;
+        self.engine
+            .state
+            .storage_ref(address, slot)
+            .map_err(|e| TestForkError::Rpc(e.to_string()))
+    }
+
+    /// Simulates a call without committing its state changes.
+    pub fn call(
+        &self,
+        caller: Address,
+        to: Address,
+        data: impl Into<Vec<u8>>,
+        value: U256,
+    ) -> Result<SimulationResult, TestForkError> {
+        let params = SimulationParameters {
+            caller,
+            to,
+            data: data.into(),
+            value,
+            overrides: None,
+            gas_limit: None,
+            spec_id: None,
+            block: self.block,
+        };
+        self.engine
+            .simulate(&params)
+            .map_err(TestForkError::Simulation)
+    }
+
+    /// Runs a transaction and commits its state changes.
+    pub fn execute(
+        &mut self,
+        caller: Address,
+        to: Address,
+        data: impl Into<Vec<u8>>,
+        value: U256,
+    ) -> Result<SimulationResult, TestForkError> {
+        let result = self.call(caller, to, data, value)?;
+        let updates = result
+            .committable_state_updates()
+            .map_err(TestForkError::Simulation)?;
+        self.commit(updates.clone())?;
+        Ok(result)
+    }
+
+    /// Runs a call and asserts that it reverts.
+    ///
+    /// # Panics
+    ///
+    /// If the call succeeds or fails for a reason other than a revert.
+    pub fn assert_reverts(&self, caller: Address, to: Address, data: impl Into<Vec<u8>>) {
+        match self.call(caller, to, data, U256::ZERO) {
+            Err(TestForkError::Simulation(SimulationEngineError::TransactionError { .. })) => {}
+            other => panic!("Expected the call to revert, got {:?}", other),
+        }
+    }
+
+    /// Makes sure an account is present locally, fetching it from the node if needed.
+    fn load(&self, address: Address) -> Result<(), TestForkError> {
+        self.engine
+            .state
+            .basic_ref(address)
+            .map_err(|e| TestForkError::Rpc(e.to_string()))?;
+        Ok(())
+    }
+
+    fn commit(&mut self, updates: StorageMap<Address, StateUpdate>) -> Result<(), TestForkError> {
+        self.engine
+            .state
+            .apply_state_updates(&updates, self.block)
+            .map_err(|e| TestForkError::Rpc(e.to_string()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use alloy_primitives::hex;
+    use dotenv::dotenv;
+
+    use super::*;
+
+    // PUSH1 0x2a PUSH1 0 SSTORE STOP
+    const STORE_42: &str = "602a60005500";
+
+    fn fork() -> TestFork {
+        dotenv().ok();
+        let rpc_url = env::var("RPC_URL").expect("Missing RPC_URL in environment");
+        TestFork::new(&rpc_url, 20308186).unwrap()
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "network_tests"), ignore)]
+    fn test_fork_deploy_and_execute() {
+        let mut fork = fork();
+        let contract = Address::repeat_byte(0xaa);
+        let caller = Address::repeat_byte(0x01);
+        fork.deploy(contract, hex::decode(STORE_42).unwrap());
+        fork.fund(caller, U256::from(10).pow(U256::from(18)))
+            .unwrap();
+
+        assert_eq!(
+            fork.storage(contract, U256::ZERO)
+                .unwrap(),
+            U256::ZERO
+        );
+        fork.execute(caller, contract, vec![], U256::ZERO)
+            .unwrap();
+
+        assert_eq!(
+            fork.storage(contract, U256::ZERO)
+                .unwrap(),
+            U256::from(42)
+        );
+        assert_eq!(fork.balance(caller).unwrap(), U256::from(10).pow(U256::from(18)));
+    }
+}
@@ -0,0 +1,154 @@
+//! Gas price estimates from a node
+//!
+//! `FeeHistoryOracle` estimates the gas price of the next block from `eth_feeHistory`: the base fee
+//! the node projects for the next block and the median of the priority fees paid at a percentile
+//! over the last blocks. Estimates are cached, so quoting never waits for the node; call
+//! `refresh` on every block or let `spawn_refresh` do it periodically.
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use alloy::{
+    eips::BlockNumberOrTag,
+    providers::{Provider, ProviderBuilder, RootProvider},
+    transports::BoxTransport,
+};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::protocol::{
+    errors::SimulationError,
+    gas::{GasPrice, GasPriceOracle},
+};
+
+/// A `GasPriceOracle` backed by `eth_feeHistory`.
+#[derive(Debug)]
+pub struct FeeHistoryOracle {
+    provider: RootProvider<BoxTransport>,
+    /// Number of past blocks to sample priority fees from
+    block_count: u64,
+    /// Percentile of the priority fees paid within each block
+    reward_percentile: f64,
+    latest: RwLock<Option<GasPrice>>,
+}
+
+impl FeeHistoryOracle {
+    pub fn new(provider: RootProvider<BoxTransport>) -> Self {
+        Self { provider, block_count: 10, reward_percentile: 50.0, latest: RwLock::new(None) }
+    }
+
+    pub async fn connect(rpc_url: &str) -> Result<Self, SimulationError> {
+        let provider = ProviderBuilder::new()
+            .on_builtin(rpc_url)
+            .await
+            .map_err(|e| SimulationError::FatalError(format!("Failed to connect: {e}")))?;
+        Ok(Self::new(provider))
+    }
+
+    /// Sets the number of past blocks priority fees are sampled from. Defaults to 10.
+    pub fn block_count(mut self, block_count: u64) -> Self {
+        self.block_count = block_count.max(1);
+        self
+    }
+
+    /// Sets the percentile of the priority fees paid within each block. Defaults to the median.
+    pub fn reward_percentile(mut self, percentile: f64) -> Self {
+        self.reward_percentile = percentile.clamp(0.0, 100.0);
+        self
+    }
+
+    /// Fetches a new estimate from the node and caches it.
+    pub async fn refresh(&self) -> Result<GasPrice, SimulationError> {
+        let history = self
+            .provider
+            .get_fee_history(self.block_count, BlockNumberOrTag::Latest, &[self.reward_percentile])
+            .await
+            .map_err(|e| {
+                SimulationError::RecoverableError(format!("eth_feeHistory failed: {e}"))
+            })?;
+        let price = estimate(&history.base_fee_per_gas, history.reward.as_deref().unwrap_or(&[]))
+            .ok_or_else(|| {
+            SimulationError::RecoverableError("eth_feeHistory returned no fees".to_string())
+        })?;
+        *self
+            .latest
+            .write()
+            .expect("Gas price lock poisoned") = Some(price);
+        Ok(price)
+    }
+
+    /// Refreshes the estimate every `interval` until the returned task is aborted. Failed
+    /// refreshes are logged and keep the previous estimate.
+    pub fn spawn_refresh(self: Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = self.refresh().await {
+                    warn!(error = %e, "Failed to refresh gas price");
+                }
+            }
+        })
+    }
+}
+
+impl GasPriceOracle for FeeHistoryOracle {
+    fn gas_price(&self) -> Result<GasPrice, SimulationError> {
+        self.latest
+            .read()
+            .expect("Gas price lock poisoned")
+            .ok_or_else(|| {
+                SimulationError::RecoverableError("No gas price fetched yet".to_string())
+            })
+    }
+}
+
+/// Estimates the next block's gas price from a fee history.
+///
+/// `base_fees` holds one entry more than the sampled blocks: the base fee of the next block.
+/// `rewards` holds the priority fee percentiles of each sampled block; the first percentile is
+/// used.
+fn estimate(base_fees: &[u128], rewards: &[Vec<u128>]) -> Option<GasPrice> {
+    let base_fee = *base_fees.last()?;
+    let mut priority_fees: Vec<u128> = rewards
+        .iter()
+        .filter_map(|block| block.first().copied())
+        .collect();
+    priority_fees.sort_unstable();
+    let priority_fee = priority_fees
+        .get(priority_fees.len() / 2)
+        .copied()
+        .unwrap_or(0);
+    Some(GasPrice::new(base_fee, priority_fee))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate() {
+        let rewards = vec![vec![3], vec![1], vec![2], vec![]];
+
+        assert_eq!(estimate(&[10, 11, 12], &rewards), Some(GasPrice::new(12, 2)));
+        assert_eq!(estimate(&[10], &[]), Some(GasPrice::new(10, 0)));
+        assert_eq!(estimate(&[], &rewards), None);
+    }
+
+    #[tokio::test]
+    #[cfg_attr(not(feature = "network_tests"), ignore)]
+    async fn test_refresh() {
+        dotenv::dotenv().ok();
+        let rpc_url = std::env::var("RPC_URL").expect("Missing RPC_URL in environment");
+        let oracle = FeeHistoryOracle::connect(&rpc_url)
+            .await
+            .unwrap();
+        assert!(oracle.gas_price().is_err());
+
+        let price = oracle.refresh().await.unwrap();
+
+        assert!(price.base_fee > 0);
+        assert_eq!(oracle.gas_price().unwrap(), price);
+    }
+}
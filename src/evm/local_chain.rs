@@ -0,0 +1,372 @@
+//! Local chain emulation
+//!
+//! `LocalChain` mines blocks of simulated transactions on top of a fork point, like a minimal anvil
+//! running in-process. Each mined block gets a header following its parent, and the state changes
+//! of its successful transactions are committed to the engine's database, so later transactions
+//! and blocks build on them. It's meant for integration tests and dry-runs of strategies spanning
+//! several blocks.
+//!
+//! The database keeps the block of the fork point: state that wasn't changed locally is read as of
+//! the fork point, which is the only block a node can serve it for.
+use std::fmt::Debug;
+
+use alloy::providers::Provider;
+use alloy_primitives::{keccak256, Address, B256, U256};
+use revm::DatabaseRef;
+use thiserror::Error;
+
+use super::{
+    account_storage::StateUpdate,
+    engine_db::{
+        engine_db_interface::EngineDatabaseInterface,
+        simulation_db::{BlockHeader, SimulationDB},
+        tycho_db::PreCachedDB,
+    },
+    eth_simulate::SimulatedCall,
+    protocol::vm::constants::EXTERNAL_ACCOUNT,
+    simulation::{SimulationEngine, SimulationEngineError, SimulationParameters, SimulationResult},
+    StorageMap,
+};
+
+/// Default time between two mined blocks
+const DEFAULT_BLOCK_TIME: u64 = 12;
+
+#[derive(Error, Debug)]
+pub enum LocalChainError {
+    #[error("Failed to commit the state of block {0}: {1}")]
+    Commit(u64, String),
+}
+
+/// A database `LocalChain` can commit the state changes of mined transactions to.
+pub trait CommitState {
+    /// Commits state updates of simulations, see `SimulationDB::apply_state_updates`.
+    fn commit_state(
+        &mut self,
+        updates: &StorageMap<Address, StateUpdate>,
+        block: BlockHeader,
+    ) -> Result<(), String>;
+}
+
+impl CommitState for PreCachedDB {
+    fn commit_state(
+        &mut self,
+        updates: &StorageMap<Address, StateUpdate>,
+        block: BlockHeader,
+    ) -> Result<(), String> {
+        self.apply_state_updates(updates, block);
+        Ok(())
+    }
+}
+
+impl<P> CommitState for SimulationDB<P>
+where
+    P: Provider + Debug + Send + Sync + 'static,
+{
+    fn commit_state(
+        &mut self,
+        updates: &StorageMap<Address, StateUpdate>,
+        block: BlockHeader,
+    ) -> Result<(), String> {
+        self.apply_state_updates(updates, block)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// A block mined by a `LocalChain`.
+#[derive(Debug, Clone)]
+pub struct MinedBlock {
+    pub header: BlockHeader,
+    /// Results of the block's transactions, in order. Failed transactions, and transactions whose
+    /// state updates were truncated, don't change the state.
+    pub results: Vec<Result<SimulationResult, SimulationEngineError>>,
+    /// Gas used by the successful transactions
+    pub gas_used: u64,
+}
+
+/// A chain of locally mined blocks on top of a fork point, see module docs.
+pub struct LocalChain<D: EngineDatabaseInterface + Clone + Debug>
+where
+    <D as DatabaseRef>::Error: Debug,
+    <D as EngineDatabaseInterface>::Error: Debug,
+{
+    engine: SimulationEngine<D>,
+    fork_block: BlockHeader,
+    blocks: Vec<MinedBlock>,
+    block_time: u64,
+}
+
+impl<D: EngineDatabaseInterface + CommitState + Clone + Debug> LocalChain<D>
+where
+    <D as DatabaseRef>::Error: Debug,
+    <D as EngineDatabaseInterface>::Error: Debug,
+{
+    /// Starts a chain on top of `fork_block`, the block the engine's state belongs to.
+    pub fn new(engine: SimulationEngine<D>, fork_block: BlockHeader) -> Self {
+        Self { engine, fork_block, blocks: Vec::new(), block_time: DEFAULT_BLOCK_TIME }
+    }
+
+    /// Sets the seconds between two mined blocks.
+    pub fn with_block_time(mut self, block_time: u64) -> Self {
+        self.block_time = block_time;
+        self
+    }
+
+    /// Mines a block with the given transactions, executed in order. Each transaction sees the
+    /// state changes of the previous ones. Failing transactions are reported in the block's
+    /// results and don't stop the block.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the state changes of a transaction can't be committed, e.g. because an account
+    /// can't be loaded from the node. The block is not added to the chain in that case, but the
+    /// changes of its previous transactions remain committed.
+    pub fn mine_block(&mut self, txs: &[SimulatedCall]) -> Result<&MinedBlock, LocalChainError> {
+        let parent = self.head();
+        let header = BlockHeader {
+            number: parent.number + 1,
+            hash: keccak256([parent.hash.as_slice(), &(parent.number + 1).to_be_bytes()].concat()),
+            parent_hash: parent.hash,
+            timestamp: parent.timestamp + self.block_time,
+            ..parent
+        };
+
+        let mut results = Vec::with_capacity(txs.len());
+        let mut gas_used = 0;
+        for tx in txs {
+            let result = self
+                .engine
+                .simulate(&Self::params(tx, header))
+                .and_then(|result| {
+                    result.committable_state_updates()?;
+                    Ok(result)
+                });
+            if let Ok(result) = &result {
+                self.engine
+                    .state
+                    .commit_state(&result.state_updates, self.fork_block)
+                    .map_err(|e| LocalChainError::Commit(header.number, e))?;
+                gas_used += result.gas_used;
+            }
+            results.push(result);
+        }
+
+        self.blocks
+            .push(MinedBlock { header, results, gas_used });
+        Ok(self
+            .blocks
+            .last()
+            .expect("block was just mined"))
+    }
+
+    /// Simulates a transaction on top of the latest block without committing it.
+    pub fn call(&self, tx: &SimulatedCall) -> Result<SimulationResult, SimulationEngineError> {
+        let head = self.head();
+        let pending = BlockHeader {
+            number: head.number + 1,
+            timestamp: head.timestamp + self.block_time,
+            parent_hash: head.hash,
+            hash: B256::ZERO,
+            ..head
+        };
+        self.engine
+            .simulate(&Self::params(tx, pending))
+    }
+
+    /// The latest block, the fork point if no block was mined yet.
+    pub fn head(&self) -> BlockHeader {
+        self.blocks
+            .last()
+            .map_or(self.fork_block, |block| block.header)
+    }
+
+    pub fn fork_block(&self) -> BlockHeader {
+        self.fork_block
+    }
+
+    /// The mined blocks, oldest first.
+    pub fn blocks(&self) -> &[MinedBlock] {
+        &self.blocks
+    }
+
+    /// A mined block by number.
+    pub fn block(&self, number: u64) -> Option<&MinedBlock> {
+        let index = number.checked_sub(self.fork_block.number + 1)?;
+        self.blocks.get(index as usize)
+    }
+
+    pub fn engine(&self) -> &SimulationEngine<D> {
+        &self.engine
+    }
+
+    fn params(tx: &SimulatedCall, block: BlockHeader) -> SimulationParameters {
+        SimulationParameters {
+            caller: tx.from.unwrap_or(*EXTERNAL_ACCOUNT),
+            to: tx.to.unwrap_or(Address::ZERO),
+            data: tx.data.to_vec(),
+            value: tx.value,
+            overrides: None,
+            gas_limit: tx.gas_limit,
+            spec_id: None,
+            block,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy_primitives::{hex, Bytes};
+    use revm::primitives::{AccountInfo, Bytecode};
+
+    use super::*;
+    use crate::evm::{engine_db::create_engine, simulation::StateUpdateLimits};
+
+    // Runtime code storing calldata word 0 into slot 0:
+    // PUSH1 0 CALLDATALOAD PUSH1 0 SSTORE STOP
+    const STORE: &str = "600035600055";
+
+    fn chain() -> LocalChain<PreCachedDB> {
+        let engine = create_engine(PreCachedDB::new().unwrap(), false).unwrap();
+        engine
+            .state
+            .init_account(*EXTERNAL_ACCOUNT, AccountInfo::default(), None, false);
+        LocalChain::new(
+            engine,
+            BlockHeader {
+                number: 100,
+                hash: B256::repeat_byte(1),
+                timestamp: 1_000,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn store(contract: Address, value: u64) -> SimulatedCall {
+        SimulatedCall {
+            to: Some(contract),
+            data: Bytes::from(U256::from(value).to_be_bytes::<32>()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_mine_blocks() {
+        let mut chain = chain();
+        let contract = Address::repeat_byte(0xaa);
+        let code = Bytecode::new_raw(Bytes::from(hex::decode(STORE).unwrap()));
+        chain.engine.state.init_account(
+            contract,
+            AccountInfo { code_hash: code.hash_slow(), code: Some(code), ..Default::default() },
+            None,
+            true,
+        );
+        // Init code returning the runtime code `STOP`
+        let deploy = SimulatedCall {
+            data: Bytes::from_static(&[
+                0x60, 0x01, 0x60, 0x0c, 0x60, 0x00, 0x39, 0x60, 0x01, 0x60, 0x00, 0xf3, 0x00,
+            ]),
+            ..Default::default()
+        };
+        let revert =
+            SimulatedCall { to: Some(contract), value: U256::from(1), ..Default::default() };
+
+        let first = chain
+            .mine_block(&[store(contract, 42), revert, deploy])
+            .unwrap()
+            .clone();
+
+        assert_eq!((first.header.number, first.header.timestamp), (101, 1_012));
+        assert_eq!(first.header.parent_hash, B256::repeat_byte(1));
+        assert!(first.results[0].is_ok() && first.results[2].is_ok());
+        // The caller has no balance to send
+        assert!(first.results[1].is_err());
+        assert_eq!(
+            chain
+                .engine()
+                .state
+                .storage_ref(contract, U256::ZERO)
+                .unwrap(),
+            U256::from(42)
+        );
+        // The store transaction was sent before, the failed one doesn't count
+        let created = EXTERNAL_ACCOUNT.create(1);
+        assert!(chain
+            .engine()
+            .state
+            .basic_ref(created)
+            .unwrap()
+            .unwrap()
+            .code
+            .is_some());
+        assert_eq!(
+            chain
+                .engine()
+                .state
+                .basic_ref(*EXTERNAL_ACCOUNT)
+                .unwrap()
+                .unwrap()
+                .nonce,
+            2
+        );
+
+        let second = chain
+            .mine_block(&[store(contract, 7)])
+            .unwrap()
+            .clone();
+
+        assert_eq!(second.header.parent_hash, first.header.hash);
+        assert_eq!(chain.head(), second.header);
+        assert_eq!(chain.block(101).unwrap().header, first.header);
+        assert!(chain.block(100).is_none());
+        assert_eq!(
+            chain
+                .engine()
+                .state
+                .storage_ref(contract, U256::ZERO)
+                .unwrap(),
+            U256::from(7)
+        );
+        // Calls see the mined state without changing it
+        chain.call(&store(contract, 1)).unwrap();
+        assert_eq!(
+            chain
+                .engine()
+                .state
+                .storage_ref(contract, U256::ZERO)
+                .unwrap(),
+            U256::from(7)
+        );
+    }
+
+    #[test]
+    fn test_mine_block_skips_truncated_updates() {
+        let mut chain = chain();
+        chain.engine = chain
+            .engine
+            .with_state_update_limits(StateUpdateLimits::new(None, Some(0)));
+        let contract = Address::repeat_byte(0xaa);
+        let code = Bytecode::new_raw(Bytes::from(hex::decode(STORE).unwrap()));
+        chain.engine.state.init_account(
+            contract,
+            AccountInfo { code_hash: code.hash_slow(), code: Some(code), ..Default::default() },
+            None,
+            true,
+        );
+
+        let block = chain
+            .mine_block(&[store(contract, 42)])
+            .unwrap()
+            .clone();
+
+        assert!(matches!(block.results[0], Err(SimulationEngineError::TruncatedStateUpdates(_))));
+        assert_eq!(block.gas_used, 0);
+        assert_eq!(
+            chain
+                .engine()
+                .state
+                .storage_ref(contract, U256::ZERO)
+                .unwrap(),
+            U256::ZERO
+        );
+    }
+}
@@ -2,16 +2,55 @@ use alloy_primitives::U256;
 use tycho_core::keccak256;
 
 pub mod account_storage;
+#[cfg(test)]
+mod asm;
+pub mod backtest;
+pub mod callback;
 pub mod decoder;
+pub mod discovery;
 pub mod engine_db;
+pub mod eth_simulate;
+pub mod evidence;
+pub mod failure_dump;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod fork;
+pub mod gas_oracle;
+pub mod local_chain;
+pub mod multicall;
+pub mod permit;
+pub mod plugin;
 pub mod protocol;
+pub mod recording;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod reorg;
+pub mod settlement;
+pub mod sharded_engine;
 pub mod simulation;
+pub mod storage_layout;
 pub mod stream;
+#[cfg(feature = "stress")]
+pub mod stress;
+pub mod trace_export;
 pub mod traces;
 pub mod tycho_models;
 
 pub type SlotId = U256;
 
+/// Hasher of the maps on hot storage paths: the accounts and storage of the engine databases and
+/// the state updates of simulation results.
+///
+/// With the `fast-hash` feature this is foldhash instead of the DoS resistant SipHash. The keys
+/// are addresses and slots of the simulated contracts, which are not attacker controlled, and
+/// hashing them dominates storage lookups at scale.
+#[cfg(feature = "fast-hash")]
+pub type StorageHasher = alloy_primitives::map::DefaultHashBuilder;
+#[cfg(not(feature = "fast-hash"))]
+pub type StorageHasher = std::collections::hash_map::RandomState;
+
+/// A hash map hashed with `StorageHasher`. Create it with `StorageMap::default()`.
+pub type StorageMap<K, V> = std::collections::HashMap<K, V, StorageHasher>;
+
 /// Enum representing the type of contract compiler.
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum ContractCompiler {
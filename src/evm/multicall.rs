@@ -0,0 +1,263 @@
+//! Aggregated view calls
+//!
+//! Reading many values through the simulation engine, e.g. token balances or the tick bitmap of a
+//! pool, costs one simulation per call. `Multicall` batches the calls into simulated calls of
+//! Multicall3's `aggregate3`, so a batch takes a single simulation.
+//!
+//! Multicall3 is deployed at the same address on most chains, and databases backed by a node load
+//! its code from there. If the database has no code at that address, e.g. a `PreCachedDB`, a
+//! minimal implementation of `aggregate3` is installed there first. It behaves like the deployed
+//! contract for `aggregate3`, except that it reverts without a reason if a call that doesn't allow
+//! failure fails, and ignores the function selector, so it can't be used for the other functions
+//! of Multicall3.
+use std::fmt::Debug;
+
+use alloy_primitives::{hex, Address, Bytes, U256};
+use alloy_sol_types::SolValue;
+use lazy_static::lazy_static;
+use revm::{primitives::KECCAK_EMPTY, DatabaseRef};
+
+use crate::{
+    evm::{
+        engine_db::{
+            account_builder::AccountBuilder, engine_db_interface::EngineDatabaseInterface,
+            simulation_db::BlockHeader,
+        },
+        protocol::vm::{constants::EXTERNAL_ACCOUNT, tycho_simulation_contract::encode_call},
+        simulation::{SimulationEngine, SimulationParameters},
+    },
+    protocol::errors::SimulationError,
+};
+
+lazy_static! {
+    /// Address Multicall3 is deployed at
+    pub static ref MULTICALL3: Address = Address::from_slice(
+        &hex::decode("cA11bde05977b3631167028862bE2a173976CA11")
+            .expect("Invalid string for Multicall3 address"),
+    );
+}
+
+/// Selector of `aggregate3((address,bool,bytes)[])`
+const AGGREGATE3: [u8; 4] = [0x82, 0xad, 0x56, 0xcb];
+
+/// Runtime code of the minimal `aggregate3` installed when Multicall3 is missing. For every call
+/// it copies the call data to the end of the result being built, calls the target, reverts if the
+/// call failed and doesn't allow failure, and writes `(success, returnData)` over the call data.
+///
+/// Assembled from `assets/aggregate3.asm`, a test checks that it matches.
+const AGGREGATE3_CODE: &str = "600435600401803590602001906020600052806020528060051b60400160005b\
+    828110156100a4578060051b840135840180604001358101803580826020018637600060008287600087355af1\
+    8084602001351761005c57600080fd5b604086038560051b604001528552604085602001525050503d80836040\
+    0152806000846060013e600081840160600152601f0160051c60051b6060018201915060010161001f565b5060\
+    00f3";
+
+/// Default number of calls per simulation
+const DEFAULT_BATCH_SIZE: usize = 500;
+
+/// A call of a `Multicall`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Call {
+    pub target: Address,
+    /// Whether the other calls of the batch still run if this one fails
+    pub allow_failure: bool,
+    pub data: Bytes,
+}
+
+/// Outcome of a call of a `Multicall`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallResult {
+    pub success: bool,
+    /// Return data of the call, or its revert data if it failed
+    pub return_data: Bytes,
+}
+
+/// Batches view calls into few simulations, see module docs.
+#[derive(Debug, Clone)]
+pub struct Multicall<D: EngineDatabaseInterface + Clone + Debug>
+where
+    <D as DatabaseRef>::Error: Debug,
+    <D as EngineDatabaseInterface>::Error: Debug,
+{
+    engine: SimulationEngine<D>,
+    calls: Vec<Call>,
+    batch_size: usize,
+}
+
+impl<D: EngineDatabaseInterface + Clone + Debug> Multicall<D>
+where
+    <D as DatabaseRef>::Error: Debug,
+    <D as EngineDatabaseInterface>::Error: Debug,
+{
+    pub fn new(engine: SimulationEngine<D>) -> Self {
+        Self { engine, calls: Vec::new(), batch_size: DEFAULT_BATCH_SIZE }
+    }
+
+    /// Sets the maximum number of calls per simulation. Every call of a batch shares the gas limit
+    /// of its simulation, so batches of expensive calls should be smaller. Defaults to 500.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Adds a call to the function `selector` of `target`, e.g. `balanceOf(address)`, encoded
+    /// like `TychoSimulationContract::call`. Its failure doesn't affect the other calls. Returns
+    /// the index of its result.
+    pub fn add(&mut self, target: Address, selector: &str, args: impl SolValue) -> usize {
+        self.add_call(Call {
+            target,
+            allow_failure: true,
+            data: encode_call(selector, args).into(),
+        })
+    }
+
+    /// Adds a call. Returns the index of its result.
+    pub fn add_call(&mut self, call: Call) -> usize {
+        self.calls.push(call);
+        self.calls.len() - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.calls.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.calls.is_empty()
+    }
+
+    /// Runs the calls at `block` and returns their results, in the order they were added.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a simulation fails, e.g. because a call that doesn't allow failure failed, or its
+    /// return data can't be decoded.
+    pub fn execute(&self, block: &BlockHeader) -> Result<Vec<CallResult>, SimulationError> {
+        self.ensure_deployed();
+        let mut results = Vec::with_capacity(self.calls.len());
+        for batch in self.calls.chunks(self.batch_size) {
+            results.extend(self.aggregate(batch, block)?);
+        }
+        Ok(results)
+    }
+
+    /// Installs the minimal `aggregate3` if the database has no code at the Multicall3 address.
+    fn ensure_deployed(&self) {
+        let deployed = self
+            .engine
+            .state
+            .basic_ref(*MULTICALL3)
+            .ok()
+            .flatten()
+            .is_some_and(|account| {
+                account.code_hash != KECCAK_EMPTY && !account.code_hash.is_zero()
+            });
+        if !deployed {
+            AccountBuilder::new(*MULTICALL3)
+                .code(hex::decode(AGGREGATE3_CODE).expect("Invalid aggregate3 code"))
+                .empty_storage()
+                .mocked()
+                .init(&self.engine.state);
+        }
+    }
+
+    fn aggregate(
+        &self,
+        calls: &[Call],
+        block: &BlockHeader,
+    ) -> Result<Vec<CallResult>, SimulationError> {
+        let calls: Vec<_> = calls
+            .iter()
+            .map(|call| (call.target, call.allow_failure, call.data.clone()))
+            .collect();
+        let mut data = AGGREGATE3.to_vec();
+        data.extend((calls,).abi_encode_params());
+        let params = SimulationParameters {
+            caller: *EXTERNAL_ACCOUNT,
+            to: *MULTICALL3,
+            data,
+            value: U256::ZERO,
+            overrides: None,
+            gas_limit: None,
+            spec_id: None,
+            block: *block,
+        };
+        let result = self
+            .engine
+            .simulate(&params)
+            .map_err(|e| SimulationError::FatalError(format!("Multicall failed: {e:?}")))?;
+        let decoded = Vec::<(bool, Bytes)>::abi_decode(&result.result, true).map_err(|e| {
+            SimulationError::FatalError(format!(
+                "Multicall failed: Failed to decode return value: {e:?}"
+            ))
+        })?;
+        Ok(decoded
+            .into_iter()
+            .map(|(success, return_data)| CallResult { success, return_data })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use revm::primitives::AccountInfo;
+
+    use super::*;
+    use crate::evm::{
+        asm::assemble,
+        engine_db::{create_engine, tycho_db::PreCachedDB},
+    };
+
+    // Returns the first argument: PUSH1 32 PUSH1 4 PUSH1 0 CALLDATACOPY PUSH1 32 PUSH1 0 RETURN
+    const ECHO: &str = "6020600460003760206000f3";
+    // Reverts: PUSH1 0 PUSH1 0 REVERT
+    const REVERT: &str = "60006000fd";
+
+    fn engine() -> SimulationEngine<PreCachedDB> {
+        let engine = create_engine(PreCachedDB::new().unwrap(), false).unwrap();
+        engine
+            .state
+            .init_account(*EXTERNAL_ACCOUNT, AccountInfo::default(), None, false);
+        for (address, code) in [(Address::repeat_byte(1), ECHO), (Address::repeat_byte(2), REVERT)]
+        {
+            AccountBuilder::new(address)
+                .code(hex::decode(code).unwrap())
+                .empty_storage()
+                .mocked()
+                .init(&engine.state);
+        }
+        engine
+    }
+
+    #[test]
+    fn test_aggregate3_code_matches_source() {
+        assert_eq!(hex::encode(assemble(include_str!("assets/aggregate3.asm"))), AGGREGATE3_CODE);
+    }
+
+    #[test]
+    fn test_multicall() {
+        let mut multicall = Multicall::new(engine()).with_batch_size(2);
+        let first = multicall.add(Address::repeat_byte(1), "echo(uint256)", U256::from(7));
+        let failing = multicall.add(Address::repeat_byte(2), "fail()", ());
+        let last = multicall.add(Address::repeat_byte(1), "echo(uint256)", U256::from(9));
+
+        let results = multicall
+            .execute(&BlockHeader::default())
+            .unwrap();
+
+        assert_eq!(results.len(), 3);
+        assert!(results[first].success && results[last].success);
+        assert_eq!(U256::abi_decode(&results[first].return_data, true).unwrap(), U256::from(7));
+        assert_eq!(U256::abi_decode(&results[last].return_data, true).unwrap(), U256::from(9));
+        assert!(!results[failing].success);
+        assert!(results[failing].return_data.is_empty());
+
+        // A failing call that doesn't allow failure fails the batch
+        multicall.add_call(Call {
+            target: Address::repeat_byte(2),
+            allow_failure: false,
+            data: Bytes::new(),
+        });
+        assert!(multicall
+            .execute(&BlockHeader::default())
+            .is_err());
+    }
+}
@@ -0,0 +1,376 @@
+//! Permit approvals in simulated sequences
+//!
+//! Routers increasingly take allowances from signed permits instead of `approve` transactions:
+//! either from the token's own EIP-2612 `permit`, or from Uniswap's Permit2 contract. The helpers
+//! here build these approvals for `SimulationEngine::simulate_blocks`, in one of three ways:
+//!
+//! - Signed: with the owner's key, e.g. of a throwaway account funded through overrides, `sign` the
+//!   permit and submit it with `call`, like a relayer or router would.
+//! - Mocked signer: `mock_signer` gives the owner code accepting any EIP-1271 signature, so the
+//!   permit verifies with any signature. Permit2 and tokens checking signatures with EIP-1271, like
+//!   USDC, support this; the owner can't send calls itself anymore though, as accounts with code
+//!   can't.
+//! - Overrides: `overrides` skip the permit and write its effect, the allowance and the used nonce,
+//!   to storage.
+use std::{collections::HashMap, str::FromStr};
+
+use alloy::signers::{local::PrivateKeySigner, SignerSync};
+use alloy_primitives::{hex, keccak256, Address, Bytes, Signature, B256, U256};
+use alloy_sol_types::SolValue;
+use lazy_static::lazy_static;
+use thiserror::Error;
+
+use crate::evm::{
+    eth_simulate::{AccountOverride, SimulatedCall},
+    protocol::vm::{tycho_simulation_contract::encode_call, utils::get_storage_slot_index_at_key},
+    ContractCompiler, SlotId,
+};
+
+lazy_static! {
+    /// Address Permit2 is deployed at
+    pub static ref PERMIT2: Address = Address::from_str("0x000000000022D473030F116dDEE9F6B43aC78BA3")
+        .expect("Invalid string for Permit2 address");
+    static ref EIP712_DOMAIN_TYPEHASH: B256 =
+        keccak256("EIP712Domain(string name,uint256 chainId,address verifyingContract)");
+    static ref PERMIT_TYPEHASH: B256 = keccak256(
+        "Permit(address owner,address spender,uint256 value,uint256 nonce,uint256 deadline)"
+    );
+    static ref PERMIT_DETAILS_TYPEHASH: B256 =
+        keccak256("PermitDetails(address token,uint160 amount,uint48 expiration,uint48 nonce)");
+    static ref PERMIT_SINGLE_TYPEHASH: B256 = keccak256(
+        "PermitSingle(PermitDetails details,address spender,uint256 sigDeadline)PermitDetails(address token,uint160 amount,uint48 expiration,uint48 nonce)"
+    );
+}
+
+/// Storage slot of Permit2's `allowance` mapping
+const PERMIT2_ALLOWANCE_SLOT: u8 = 1;
+
+/// Runtime code returning the EIP-1271 magic value for any call:
+/// PUSH4 0x1626ba7e PUSH1 224 SHL PUSH1 0 MSTORE PUSH1 32 PUSH1 0 RETURN
+const EIP1271_ALWAYS_VALID: &str = "631626ba7e60e01b60005260206000f3";
+
+#[derive(Error, Debug)]
+pub enum PermitError {
+    #[error("Failed to sign permit: {0}")]
+    Signature(String),
+}
+
+/// An EIP-2612 permit of `token`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Eip2612Permit {
+    pub token: Address,
+    pub owner: Address,
+    pub spender: Address,
+    pub value: U256,
+    /// The owner's current nonce on the token, see `nonces(address)`
+    pub nonce: U256,
+    pub deadline: U256,
+}
+
+/// Storage slots of a token's `allowance` and `nonces` mappings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Eip2612Slots {
+    pub allowance: SlotId,
+    pub nonces: SlotId,
+    pub compiler: ContractCompiler,
+}
+
+impl Eip2612Permit {
+    /// The EIP-712 digest the owner signs. `domain_separator` is the token's, see
+    /// `DOMAIN_SEPARATOR()`.
+    pub fn digest(&self, domain_separator: B256) -> B256 {
+        let permit =
+            (*PERMIT_TYPEHASH, self.owner, self.spender, self.value, self.nonce, self.deadline);
+        eip712_digest(domain_separator, keccak256(permit.abi_encode()))
+    }
+
+    pub fn sign(
+        &self,
+        domain_separator: B256,
+        signer: &PrivateKeySigner,
+    ) -> Result<Signature, PermitError> {
+        sign(signer, &self.digest(domain_separator))
+    }
+
+    /// The call of `permit` on the token. Anyone can submit it, so it is sent from the engine's
+    /// external account.
+    pub fn call(&self, signature: &Signature) -> SimulatedCall {
+        let (v, r, s) = split_signature(signature);
+        let data = encode_call(
+            "permit(address,address,uint256,uint256,uint8,bytes32,bytes32)",
+            (self.owner, self.spender, self.value, self.deadline, v, r, s),
+        );
+        SimulatedCall { to: Some(self.token), data: data.into(), ..Default::default() }
+    }
+
+    /// Overrides of the token's storage with the effect of the permit: the allowance is set and
+    /// the nonce is used.
+    pub fn overrides(&self, slots: &Eip2612Slots) -> HashMap<Address, AccountOverride> {
+        let mut state_diff = allowance_state_diff(
+            self.owner,
+            self.spender,
+            self.value,
+            slots.allowance,
+            slots.compiler,
+        );
+        state_diff.insert(
+            get_storage_slot_index_at_key(self.owner, slots.nonces, slots.compiler),
+            self.nonce + U256::from(1),
+        );
+        HashMap::from([(self.token, state_override(state_diff))])
+    }
+}
+
+/// A Permit2 `PermitSingle`, allowing `spender` to transfer `amount` of the owner's `token`
+/// through Permit2 until `expiration`.
+///
+/// Permit2 only moves tokens it is approved for, so the owner must also have approved Permit2 on
+/// the token, e.g. with `erc20_approval`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Permit2Single {
+    pub owner: Address,
+    pub token: Address,
+    /// Allowed amount, a `uint160`
+    pub amount: U256,
+    /// Timestamp the allowance expires at, a `uint48`
+    pub expiration: u64,
+    /// The owner's current nonce for `token` and `spender` on Permit2, a `uint48`
+    pub nonce: u64,
+    pub spender: Address,
+    /// Timestamp the signature expires at
+    pub sig_deadline: U256,
+}
+
+impl Permit2Single {
+    /// The EIP-712 digest the owner signs, for Permit2 on chain `chain_id`.
+    pub fn digest(&self, chain_id: u64) -> B256 {
+        let details = (
+            *PERMIT_DETAILS_TYPEHASH,
+            self.token,
+            self.amount,
+            U256::from(self.expiration),
+            U256::from(self.nonce),
+        );
+        let permit = (
+            *PERMIT_SINGLE_TYPEHASH,
+            keccak256(details.abi_encode()),
+            self.spender,
+            self.sig_deadline,
+        );
+        eip712_digest(permit2_domain_separator(chain_id), keccak256(permit.abi_encode()))
+    }
+
+    pub fn sign(&self, chain_id: u64, signer: &PrivateKeySigner) -> Result<Signature, PermitError> {
+        sign(signer, &self.digest(chain_id))
+    }
+
+    /// The call of Permit2's `permit` with the 65 byte `signature`, e.g. `signature.as_bytes()`.
+    /// Anyone can submit it, so it is sent from the engine's external account. With a mocked
+    /// signer any signature, even an empty one, is accepted.
+    pub fn call(&self, signature: Bytes) -> SimulatedCall {
+        let details =
+            (self.token, self.amount, U256::from(self.expiration), U256::from(self.nonce));
+        let data = encode_call(
+            "permit(address,((address,uint160,uint48,uint48),address,uint256),bytes)",
+            (self.owner, (details, self.spender, self.sig_deadline), signature),
+        );
+        SimulatedCall { to: Some(*PERMIT2), data: data.into(), ..Default::default() }
+    }
+
+    /// Overrides of Permit2's storage with the effect of the permit: the allowance is set and the
+    /// nonce is used.
+    pub fn overrides(&self) -> HashMap<Address, AccountOverride> {
+        let compiler = ContractCompiler::Solidity;
+        let owner_slot = get_storage_slot_index_at_key(
+            self.owner,
+            SlotId::from(PERMIT2_ALLOWANCE_SLOT),
+            compiler,
+        );
+        let token_slot = get_storage_slot_index_at_key(self.token, owner_slot, compiler);
+        let slot = get_storage_slot_index_at_key(self.spender, token_slot, compiler);
+        // PackedAllowance { uint160 amount; uint48 expiration; uint48 nonce; }
+        let packed =
+            self.amount | U256::from(self.expiration) << 160 | U256::from(self.nonce + 1) << 208;
+        HashMap::from([(*PERMIT2, state_override(HashMap::from([(slot, packed)])))])
+    }
+}
+
+/// The domain separator of Permit2 on chain `chain_id`.
+pub fn permit2_domain_separator(chain_id: u64) -> B256 {
+    keccak256(
+        (*EIP712_DOMAIN_TYPEHASH, keccak256("Permit2"), U256::from(chain_id), *PERMIT2)
+            .abi_encode(),
+    )
+}
+
+/// Override giving `owner` code that accepts any EIP-1271 signature, see module docs.
+pub fn mock_signer(owner: Address) -> HashMap<Address, AccountOverride> {
+    let code = hex::decode(EIP1271_ALWAYS_VALID).expect("Invalid EIP-1271 code");
+    HashMap::from([(owner, AccountOverride { code: Some(code.into()), ..Default::default() })])
+}
+
+/// Override of `token`'s storage approving `spender` for `amount` of the owner's tokens, e.g. to
+/// approve Permit2. `allowance_slot` is the slot of the token's `allowance` mapping.
+pub fn erc20_approval(
+    token: Address,
+    owner: Address,
+    spender: Address,
+    amount: U256,
+    allowance_slot: SlotId,
+    compiler: ContractCompiler,
+) -> HashMap<Address, AccountOverride> {
+    let state_diff = allowance_state_diff(owner, spender, amount, allowance_slot, compiler);
+    HashMap::from([(token, state_override(state_diff))])
+}
+
+fn allowance_state_diff(
+    owner: Address,
+    spender: Address,
+    amount: U256,
+    allowance_slot: SlotId,
+    compiler: ContractCompiler,
+) -> HashMap<U256, U256> {
+    let owner_slot = get_storage_slot_index_at_key(owner, allowance_slot, compiler);
+    HashMap::from([(get_storage_slot_index_at_key(spender, owner_slot, compiler), amount)])
+}
+
+fn state_override(state_diff: HashMap<U256, U256>) -> AccountOverride {
+    AccountOverride { state_diff: Some(state_diff), ..Default::default() }
+}
+
+fn eip712_digest(domain_separator: B256, struct_hash: B256) -> B256 {
+    let mut buf = Vec::with_capacity(66);
+    buf.extend_from_slice(&[0x19, 0x01]);
+    buf.extend_from_slice(domain_separator.as_slice());
+    buf.extend_from_slice(struct_hash.as_slice());
+    keccak256(buf)
+}
+
+fn sign(signer: &PrivateKeySigner, digest: &B256) -> Result<Signature, PermitError> {
+    signer
+        .sign_hash_sync(digest)
+        .map_err(|e| PermitError::Signature(e.to_string()))
+}
+
+/// Splits a signature into the `(v, r, s)` arguments of EIP-2612's `permit`.
+fn split_signature(signature: &Signature) -> (u8, B256, B256) {
+    let bytes = signature.as_bytes();
+    (bytes[64], B256::from_slice(&bytes[..32]), B256::from_slice(&bytes[32..64]))
+}
+
+#[cfg(test)]
+mod tests {
+    use revm::primitives::AccountInfo;
+
+    use super::*;
+    use crate::evm::{
+        engine_db::{create_engine, simulation_db::BlockHeader, tycho_db::PreCachedDB},
+        eth_simulate::BlockStateCalls,
+        protocol::vm::constants::{ERC20_BYTECODE, EXTERNAL_ACCOUNT},
+    };
+
+    #[test]
+    fn test_permit2_domain_separator() {
+        assert_eq!(
+            permit2_domain_separator(1),
+            B256::from_str("0x866a5aba21966af95d6c7ab78eb2b2fc913915c28be3b9aa07cc04ff903e3f28")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_sign_eip2612_permit() {
+        let signer = PrivateKeySigner::random();
+        let permit = Eip2612Permit {
+            token: Address::repeat_byte(0x22),
+            owner: signer.address(),
+            spender: Address::repeat_byte(0x33),
+            value: U256::from(1_000),
+            nonce: U256::ZERO,
+            deadline: U256::MAX,
+        };
+        let domain_separator = B256::repeat_byte(0x44);
+
+        let signature = permit
+            .sign(domain_separator, &signer)
+            .unwrap();
+        let call = permit.call(&signature);
+
+        assert_eq!(
+            signature
+                .recover_address_from_prehash(&permit.digest(domain_separator))
+                .unwrap(),
+            signer.address()
+        );
+        assert_eq!(call.to, Some(permit.token));
+        assert_eq!(call.data[..4], hex::decode("d505accf").unwrap());
+        // v is the fifth argument
+        assert!([27, 28].contains(&call.data[4 + 4 * 32 + 31]));
+    }
+
+    #[test]
+    fn test_permit2_overrides() {
+        let permit = Permit2Single {
+            owner: Address::repeat_byte(0x11),
+            token: Address::repeat_byte(0x22),
+            amount: U256::from(5),
+            expiration: 7,
+            nonce: 3,
+            spender: Address::repeat_byte(0x33),
+            sig_deadline: U256::MAX,
+        };
+
+        let overrides = permit.overrides();
+
+        let slot =
+            U256::from_str("0x5cd1940083c14fdbb16de8a71fb459142bfb6fed539afa1bad7cf90c2b8dfa2e")
+                .unwrap();
+        let packed =
+            U256::from_str("0x40000000000070000000000000000000000000000000000000005").unwrap();
+        assert_eq!(overrides[&*PERMIT2].state_diff, Some(HashMap::from([(slot, packed)])));
+    }
+
+    #[test]
+    fn test_eip2612_overrides() {
+        let engine = create_engine(PreCachedDB::new().unwrap(), false).unwrap();
+        engine
+            .state
+            .init_account(*EXTERNAL_ACCOUNT, AccountInfo::default(), None, false);
+        let permit = Eip2612Permit {
+            token: Address::repeat_byte(0x22),
+            owner: Address::repeat_byte(0x11),
+            spender: Address::repeat_byte(0x33),
+            value: U256::from(1_000),
+            nonce: U256::from(2),
+            deadline: U256::MAX,
+        };
+        let slots = Eip2612Slots {
+            allowance: SlotId::from(1),
+            nonces: SlotId::from(5),
+            compiler: ContractCompiler::Solidity,
+        };
+        let mut overrides = permit.overrides(&slots);
+        overrides
+            .get_mut(&permit.token)
+            .unwrap()
+            .code = Some(ERC20_BYTECODE.into());
+        let allowance = SimulatedCall {
+            to: Some(permit.token),
+            data: encode_call("allowance(address,address)", (permit.owner, permit.spender)).into(),
+            ..Default::default()
+        };
+
+        let res = engine
+            .simulate_blocks(
+                &BlockHeader::default(),
+                &[BlockStateCalls {
+                    block_overrides: None,
+                    state_overrides: Some(overrides),
+                    calls: vec![allowance],
+                }],
+            )
+            .unwrap();
+
+        assert_eq!(U256::from_be_slice(&res[0].calls[0].return_data), permit.value);
+    }
+}
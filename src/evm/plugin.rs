@@ -0,0 +1,187 @@
+//! External protocol plugins
+//!
+//! Protocols can be implemented outside of this crate: a plugin implements `ProtocolPlugin` and
+//! registers the decoders of its exchanges, just like `ProtocolStreamBuilder::exchange` does for
+//! the built-in ones. Plugins are either linked statically and passed to
+//! `ProtocolStreamBuilder::plugin`, or built as a `cdylib` exporting the plugin with
+//! `declare_plugin!` and loaded at runtime with `load_plugin` (requires the `plugins` feature).
+//!
+//! Rust has no stable ABI: a dynamically loaded plugin must be built with the same compiler and
+//! the same version of this crate as the host. The crate version is checked on load; mismatching
+//! compilers are not detected.
+use tycho_client::feed::synchronizer::ComponentWithState;
+
+use crate::{
+    evm::decoder::TychoStreamDecoder,
+    protocol::{errors::InvalidSnapshotError, models::TryFromWithBlock, state::ProtocolSim},
+};
+
+/// Version of this crate, which plugins are checked against when loaded
+pub const PLUGIN_CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// A set of protocols implemented outside of this crate.
+pub trait ProtocolPlugin: Send + Sync {
+    /// Name of the plugin, used for logging
+    fn name(&self) -> &str;
+
+    /// Registers the decoders of the plugin's exchanges.
+    fn register(&self, registrar: &mut PluginRegistrar);
+}
+
+/// Registers the exchanges of a plugin with the stream decoder.
+pub struct PluginRegistrar<'a> {
+    decoder: &'a mut TychoStreamDecoder,
+    exchanges: Vec<String>,
+}
+
+impl<'a> PluginRegistrar<'a> {
+    pub(super) fn new(decoder: &'a mut TychoStreamDecoder) -> Self {
+        Self { decoder, exchanges: Vec::new() }
+    }
+
+    /// Registers the state decoding components of the exchange `name`, and optionally a filter
+    /// excluding components the state can't simulate.
+    pub fn exchange<T>(&mut self, name: &str, filter_fn: Option<fn(&ComponentWithState) -> bool>)
+    where
+        T: ProtocolSim
+            + TryFromWithBlock<ComponentWithState, Error = InvalidSnapshotError>
+            + Send
+            + 'static,
+    {
+        self.decoder.register_decoder::<T>(name);
+        if let Some(predicate) = filter_fn {
+            self.decoder
+                .register_filter(name, predicate);
+        }
+        self.exchanges.push(name.to_string());
+    }
+
+    /// The exchanges registered so far.
+    pub(super) fn into_exchanges(self) -> Vec<String> {
+        self.exchanges
+    }
+}
+
+/// Exports a `ProtocolPlugin` from a `cdylib` so that `load_plugin` can load it.
+///
+/// # Example
+///
+/// ```ignore
+/// struct MyPlugin;
+///
+/// impl ProtocolPlugin for MyPlugin {
+///     fn name(&self) -> &str {
+///         "my_plugin"
+///     }
+///
+///     fn register(&self, registrar: &mut PluginRegistrar) {
+///         registrar.exchange::<MyState>("my_protocol", None);
+///     }
+/// }
+///
+/// declare_plugin!(MyPlugin, || MyPlugin);
+/// ```
+#[macro_export]
+macro_rules! declare_plugin {
+    ($plugin:ty, $constructor:expr) => {
+        #[no_mangle]
+        pub static _TYCHO_SIMULATION_PLUGIN_VERSION: &str =
+            $crate::evm::plugin::PLUGIN_CRATE_VERSION;
+
+        #[no_mangle]
+        #[allow(improper_ctypes_definitions)]
+        pub extern "C" fn _tycho_simulation_plugin_create(
+        ) -> *mut dyn $crate::evm::plugin::ProtocolPlugin {
+            let constructor: fn() -> $plugin = $constructor;
+            let plugin: Box<dyn $crate::evm::plugin::ProtocolPlugin> = Box::new(constructor());
+            Box::into_raw(plugin)
+        }
+    };
+}
+
+#[cfg(feature = "plugins")]
+pub use loader::{load_plugin, PluginError};
+
+#[cfg(feature = "plugins")]
+mod loader {
+    use std::path::Path;
+
+    use libloading::{Library, Symbol};
+    use thiserror::Error;
+    use tracing::info;
+
+    use super::{ProtocolPlugin, PLUGIN_CRATE_VERSION};
+
+    #[derive(Error, Debug)]
+    pub enum PluginError {
+        #[error("Failed to load plugin library: {0}")]
+        Load(#[from] libloading::Error),
+        #[error(
+            "Plugin was built against tycho-simulation {plugin}, expected {PLUGIN_CRATE_VERSION}"
+        )]
+        VersionMismatch { plugin: String },
+    }
+
+    /// Loads a plugin exported with `declare_plugin!` from the dynamic library at `path`.
+    ///
+    /// The library stays loaded for the lifetime of the process: states decoded by the plugin
+    /// may outlive any stream and execute the library's code.
+    ///
+    /// # Safety
+    ///
+    /// Loading a library runs its initialisation code, and the plugin must have been built with
+    /// the same compiler as the host. See the module documentation.
+    pub unsafe fn load_plugin(
+        path: impl AsRef<Path>,
+    ) -> Result<Box<dyn ProtocolPlugin>, PluginError> {
+        let library = Library::new(path.as_ref())?;
+        let version: Symbol<*const &str> = library.get(b"_TYCHO_SIMULATION_PLUGIN_VERSION")?;
+        let version = **version;
+        if version != PLUGIN_CRATE_VERSION {
+            return Err(PluginError::VersionMismatch { plugin: version.to_string() });
+        }
+        let create: Symbol<unsafe extern "C" fn() -> *mut dyn ProtocolPlugin> =
+            library.get(b"_tycho_simulation_plugin_create")?;
+        let plugin = Box::from_raw(create());
+        info!(plugin = plugin.name(), path = %path.as_ref().display(), "Loaded plugin");
+        std::mem::forget(library);
+        Ok(plugin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evm::protocol::{
+        filters::uniswap_v4_pool_with_hook_filter, uniswap_v2::state::UniswapV2State,
+        uniswap_v4::state::UniswapV4State,
+    };
+
+    struct TestPlugin;
+
+    impl ProtocolPlugin for TestPlugin {
+        fn name(&self) -> &str {
+            "test"
+        }
+
+        fn register(&self, registrar: &mut PluginRegistrar) {
+            registrar.exchange::<UniswapV2State>("forked_v2", None);
+            registrar
+                .exchange::<UniswapV4State>("forked_v4", Some(uniswap_v4_pool_with_hook_filter));
+        }
+    }
+
+    declare_plugin!(TestPlugin, || TestPlugin);
+
+    #[test]
+    fn test_register_plugin() {
+        let mut decoder = TychoStreamDecoder::new();
+        let mut registrar = PluginRegistrar::new(&mut decoder);
+
+        let plugin = unsafe { Box::from_raw(_tycho_simulation_plugin_create()) };
+        plugin.register(&mut registrar);
+
+        assert_eq!(registrar.into_exchanges(), vec!["forked_v2", "forked_v4"]);
+        assert_eq!(_TYCHO_SIMULATION_PLUGIN_VERSION, PLUGIN_CRATE_VERSION);
+    }
+}
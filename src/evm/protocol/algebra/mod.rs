@@ -0,0 +1,7 @@
+//! Algebra concentrated liquidity pools
+//!
+//! Algebra is a Uniswap V3 variant used by forks like Camelot V3 and QuickSwap V3. Instead of fee
+//! tiers, each pool charges a dynamic fee that the pool updates on swaps, and the tick spacing is
+//! configured per pool rather than derived from the fee.
+pub mod state;
+pub mod tycho_decoder;
@@ -0,0 +1,370 @@
+use std::{any::Any, collections::HashMap};
+
+use alloy_primitives::{Sign, I256, U256};
+use num_bigint::BigUint;
+use tracing::trace;
+use tycho_core::{dto::ProtocolStateDelta, Bytes};
+
+use crate::{
+    evm::protocol::{
+        u256_num::{checked_biguint_to_u256, u256_to_biguint},
+        utils::uniswap::{
+            concentrated_liquidity::{ConcentratedPool, SwapGas},
+            i24_be_bytes_to_i32,
+            sqrt_price_math::sqrt_price_q96_to_f64,
+            tick_list::{TickInfo, TickList},
+            SwapResults,
+        },
+    },
+    models::{Balances, Token},
+    protocol::{
+        errors::{SimulationError, TransitionError},
+        models::GetAmountOutResult,
+        state::{fingerprint, ProtocolSim},
+    },
+};
+
+// Algebra pools additionally write the volatility oracle and the dynamic fee on swaps
+const SWAP_GAS: SwapGas = SwapGas { base: 160_000, per_step: 2000 };
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AlgebraState {
+    liquidity: u128,
+    sqrt_price: U256,
+    /// Current dynamic fee, in hundredths of a bip
+    fee: u32,
+    tick: i32,
+    ticks: TickList,
+}
+
+impl AlgebraState {
+    /// Creates a new instance of `AlgebraState`.
+    ///
+    /// # Arguments
+    /// - `liquidity`: The initial liquidity of the pool.
+    /// - `sqrt_price`: The square root of the current price.
+    /// - `fee`: The current fee of the pool, in hundredths of a bip.
+    /// - `tick`: The current tick of the pool.
+    /// - `tick_spacing`: The tick spacing configured for the pool.
+    /// - `ticks`: A vector of `TickInfo` representing the tick information for the pool.
+    pub fn new(
+        liquidity: u128,
+        sqrt_price: U256,
+        fee: u32,
+        tick: i32,
+        tick_spacing: i32,
+        ticks: Vec<TickInfo>,
+    ) -> Self {
+        let tick_list = TickList::from(
+            tick_spacing
+                .try_into()
+                .expect("tick_spacing should always be positive"),
+            ticks,
+        );
+        AlgebraState { liquidity, sqrt_price, fee, tick, ticks: tick_list }
+    }
+
+    fn swap(
+        &self,
+        zero_for_one: bool,
+        amount_specified: I256,
+        sqrt_price_limit: Option<U256>,
+    ) -> Result<SwapResults, SimulationError> {
+        ConcentratedPool {
+            liquidity: self.liquidity,
+            sqrt_price: self.sqrt_price,
+            tick: self.tick,
+            ticks: &self.ticks,
+            fee: self.fee,
+            gas: SWAP_GAS,
+        }
+        .swap(zero_for_one, amount_specified, sqrt_price_limit, |result| {
+            Box::new(self.with_swap(result))
+        })
+    }
+
+    /// The state after a swap.
+    fn with_swap(&self, result: &SwapResults) -> Self {
+        let mut new_state = self.clone();
+        new_state.liquidity = result.liquidity;
+        new_state.tick = result.tick;
+        new_state.sqrt_price = result.sqrt_price;
+        new_state
+    }
+}
+
+impl ProtocolSim for AlgebraState {
+    fn fee(&self) -> f64 {
+        self.fee as f64 / 1_000_000.0
+    }
+
+    fn spot_price(&self, a: &Token, b: &Token) -> Result<f64, SimulationError> {
+        if a < b {
+            Ok(sqrt_price_q96_to_f64(self.sqrt_price, a.decimals as u32, b.decimals as u32))
+        } else {
+            Ok(1.0f64 /
+                sqrt_price_q96_to_f64(self.sqrt_price, b.decimals as u32, a.decimals as u32))
+        }
+    }
+
+    fn get_amount_out(
+        &self,
+        amount_in: BigUint,
+        token_a: &Token,
+        token_b: &Token,
+    ) -> Result<GetAmountOutResult, SimulationError> {
+        let zero_for_one = token_a < token_b;
+        let amount_specified =
+            I256::checked_from_sign_and_abs(Sign::Positive, checked_biguint_to_u256(&amount_in)?)
+                .ok_or_else(|| {
+                SimulationError::InvalidInput(
+                    format!("Amount {} exceeds 255 bits", amount_in),
+                    None,
+                )
+            })?;
+
+        let result = self.swap(zero_for_one, amount_specified, None)?;
+
+        trace!(?amount_in, ?token_a, ?token_b, ?zero_for_one, ?result, "ALGEBRA SWAP");
+        let new_state = self.with_swap(&result);
+
+        Ok(GetAmountOutResult::new(
+            u256_to_biguint(
+                result
+                    .amount_calculated
+                    .abs()
+                    .into_raw(),
+            ),
+            u256_to_biguint(result.gas_used),
+            Box::new(new_state),
+        ))
+    }
+
+    fn state_fingerprint(&self) -> u64 {
+        fingerprint(self)
+    }
+
+    fn delta_transition(
+        &mut self,
+        delta: ProtocolStateDelta,
+        _tokens: &HashMap<Bytes, Token>,
+        _balances: &Balances,
+    ) -> Result<(), TransitionError<String>> {
+        // apply attribute changes
+        if let Some(liquidity) = delta
+            .updated_attributes
+            .get("liquidity")
+        {
+            // Liquidity that was never updated after creation is encoded as H256::zero(), see
+            // the Uniswap V3 state.
+            let liq_16_bytes = if liquidity.len() == 32 {
+                if liquidity == &Bytes::zero(32) {
+                    Bytes::from([0; 16])
+                } else {
+                    return Err(TransitionError::DecodeError(format!(
+                        "Liquidity bytes too long for {}, expected 16",
+                        liquidity
+                    )));
+                }
+            } else {
+                liquidity.clone()
+            };
+
+            self.liquidity = u128::from(liq_16_bytes);
+        }
+        if let Some(sqrt_price) = delta
+            .updated_attributes
+            .get("sqrt_price_x96")
+        {
+            self.sqrt_price = U256::from_be_slice(sqrt_price);
+        }
+        if let Some(tick) = delta.updated_attributes.get("tick") {
+            let ticks_4_bytes = if tick.len() == 32 {
+                if tick == &Bytes::zero(32) {
+                    Bytes::from([0; 4])
+                } else {
+                    return Err(TransitionError::DecodeError(format!(
+                        "Tick bytes too long for {}, expected 4",
+                        tick
+                    )));
+                }
+            } else {
+                tick.clone()
+            };
+            self.tick = i24_be_bytes_to_i32(&ticks_4_bytes);
+        }
+        // the fee is recomputed by the pool on swaps
+        if let Some(fee) = delta.updated_attributes.get("fee") {
+            self.fee = u32::from(fee.clone());
+        }
+
+        // apply tick changes
+        for (key, value) in delta.updated_attributes.iter() {
+            // tick liquidity keys are in the format "ticks/{tick_index}/net_liquidity"
+            if key.starts_with("ticks/") {
+                let parts: Vec<&str> = key.split('/').collect();
+                self.ticks.set_tick_liquidity(
+                    parts[1]
+                        .parse::<i32>()
+                        .map_err(|err| TransitionError::DecodeError(err.to_string()))?,
+                    i128::from(value.clone()),
+                )
+            }
+        }
+        // delete ticks - ignores deletes for attributes other than tick liquidity
+        for key in delta.deleted_attributes.iter() {
+            if key.starts_with("ticks/") {
+                let parts: Vec<&str> = key.split('/').collect();
+                self.ticks.set_tick_liquidity(
+                    parts[1]
+                        .parse::<i32>()
+                        .map_err(|err| TransitionError::DecodeError(err.to_string()))?,
+                    0,
+                )
+            }
+        }
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn ProtocolSim> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn eq(&self, other: &dyn ProtocolSim) -> bool {
+        if let Some(other_state) = other
+            .as_any()
+            .downcast_ref::<AlgebraState>()
+        {
+            self.liquidity == other_state.liquidity &&
+                self.sqrt_price == other_state.sqrt_price &&
+                self.fee == other_state.fee &&
+                self.tick == other_state.tick &&
+                self.ticks == other_state.ticks
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        str::FromStr,
+    };
+
+    use num_bigint::ToBigUint;
+    use tycho_core::hex_bytes::Bytes;
+
+    use super::*;
+
+    fn wbtc_weth_pool(fee: u32) -> AlgebraState {
+        AlgebraState::new(
+            377952820878029838,
+            U256::from_str("28437325270877025820973479874632004").unwrap(),
+            fee,
+            255830,
+            10,
+            vec![
+                TickInfo::new(255760, 1759015528199933i128),
+                TickInfo::new(255770, 6393138051835308i128),
+                TickInfo::new(255780, 228206673808681i128),
+                TickInfo::new(255820, 1319490609195820i128),
+                TickInfo::new(255830, 678916926147901i128),
+                TickInfo::new(255840, 12208947683433103i128),
+                TickInfo::new(255850, 1177970713095301i128),
+                TickInfo::new(255860, 8752304680520407i128),
+                TickInfo::new(255880, 1486478248067104i128),
+                TickInfo::new(255890, 1878744276123248i128),
+                TickInfo::new(255900, 77340284046725227i128),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_get_amount_out() {
+        let wbtc = Token::new(
+            "0x2260FAC5E5542a773Aa44fBCfeDf7C193bc2C599",
+            8,
+            "WBTC",
+            10_000.to_biguint().unwrap(),
+        );
+        let weth = Token::new(
+            "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2",
+            18,
+            "WETH",
+            10_000.to_biguint().unwrap(),
+        );
+        // with a 0.05% fee and a spacing of 10 the pool matches the Uniswap V3 0.05% pool
+        let pool = wbtc_weth_pool(500);
+
+        let res = pool
+            .get_amount_out(BigUint::from_str("3000000000").unwrap(), &wbtc, &weth)
+            .unwrap();
+        assert_eq!(res.amount, BigUint::from_str("385196519076234662939").unwrap());
+
+        let res = pool
+            .get_amount_out(BigUint::from_str("385000000000000000000").unwrap(), &weth, &wbtc)
+            .unwrap();
+        assert_eq!(res.amount, BigUint::from_str("2978713582").unwrap());
+
+        // a higher dynamic fee yields less
+        let expensive = wbtc_weth_pool(3000)
+            .get_amount_out(BigUint::from_str("3000000000").unwrap(), &wbtc, &weth)
+            .unwrap();
+        assert!(expensive.amount < BigUint::from_str("385196519076234662939").unwrap());
+    }
+
+    #[test]
+    fn test_delta_transition() {
+        let mut pool = AlgebraState::new(
+            1000,
+            U256::from_str("1000").unwrap(),
+            500,
+            100,
+            60,
+            vec![TickInfo::new(255720, 10000), TickInfo::new(255900, -10000)],
+        );
+        let attributes: HashMap<String, Bytes> = [
+            ("liquidity".to_string(), Bytes::from(2000_u64.to_be_bytes().to_vec())),
+            ("sqrt_price_x96".to_string(), Bytes::from(1001_u64.to_be_bytes().to_vec())),
+            ("tick".to_string(), Bytes::from(120_i32.to_be_bytes().to_vec())),
+            ("fee".to_string(), Bytes::from(2900_u32.to_be_bytes().to_vec())),
+            (
+                "ticks/255720/net_liquidity".to_string(),
+                Bytes::from(10200_u64.to_be_bytes().to_vec()),
+            ),
+        ]
+        .into_iter()
+        .collect();
+        let delta = ProtocolStateDelta {
+            component_id: "State1".to_owned(),
+            updated_attributes: attributes,
+            deleted_attributes: HashSet::from(["ticks/255900/net_liquidity".to_string()]),
+        };
+
+        pool.delta_transition(delta, &HashMap::new(), &Balances::default())
+            .unwrap();
+
+        assert_eq!(pool.liquidity, 2000);
+        assert_eq!(pool.sqrt_price, U256::from(1001));
+        assert_eq!(pool.tick, 120);
+        assert_eq!(pool.fee, 2900);
+        assert_eq!(
+            pool.ticks
+                .get_tick(255720)
+                .unwrap()
+                .net_liquidity,
+            10200
+        );
+        assert!(pool.ticks.get_tick(255900).is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+
+use tycho_client::feed::{synchronizer::ComponentWithState, Header};
+use tycho_core::Bytes;
+
+use super::state::AlgebraState;
+use crate::{
+    evm::protocol::utils::uniswap::concentrated_liquidity::decode_snapshot,
+    models::Token,
+    protocol::{errors::InvalidSnapshotError, models::TryFromWithBlock},
+};
+
+impl TryFromWithBlock<ComponentWithState> for AlgebraState {
+    type Error = InvalidSnapshotError;
+
+    /// Decodes a `ComponentWithState` into an `AlgebraState`. Errors with a `InvalidSnapshotError`
+    /// if the snapshot is missing any required attributes.
+    async fn try_from_with_block(
+        snapshot: ComponentWithState,
+        _block: Header,
+        _account_balances: &HashMap<Bytes, HashMap<Bytes, Bytes>>,
+        _all_tokens: &HashMap<Bytes, Token>,
+    ) -> Result<Self, Self::Error> {
+        let pool = decode_snapshot(&snapshot.state.attributes)?;
+
+        // The fee is dynamic, so unlike Uniswap V3 it is part of the state
+        let fee = u32::from(
+            snapshot
+                .state
+                .attributes
+                .get("fee")
+                .ok_or_else(|| InvalidSnapshotError::MissingAttribute("fee".to_string()))?
+                .clone(),
+        );
+
+        let tick_spacing = i32::from(
+            snapshot
+                .component
+                .static_attributes
+                .get("tick_spacing")
+                .ok_or_else(|| InvalidSnapshotError::MissingAttribute("tick_spacing".to_string()))?
+                .clone(),
+        );
+        if tick_spacing <= 0 {
+            return Err(InvalidSnapshotError::ValueError(format!(
+                "Invalid tick spacing {tick_spacing}"
+            )));
+        }
+
+        Ok(AlgebraState::new(
+            pool.liquidity,
+            pool.sqrt_price,
+            fee,
+            pool.tick,
+            tick_spacing,
+            pool.ticks,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use alloy_primitives::U256;
+    use chrono::DateTime;
+    use rstest::rstest;
+    use tycho_core::dto::{Chain, ChangeType, ProtocolComponent, ResponseProtocolState};
+
+    use super::*;
+    use crate::evm::protocol::utils::uniswap::tick_list::TickInfo;
+
+    fn algebra_component() -> ProtocolComponent {
+        let creation_time = DateTime::from_timestamp(1622526000, 0)
+            .unwrap()
+            .naive_utc(); //Sample timestamp
+
+        let mut static_attributes: HashMap<String, Bytes> = HashMap::new();
+        static_attributes
+            .insert("tick_spacing".to_string(), Bytes::from(60_i32.to_be_bytes().to_vec()));
+
+        ProtocolComponent {
+            id: "State1".to_string(),
+            protocol_system: "system1".to_string(),
+            protocol_type_name: "typename1".to_string(),
+            chain: Chain::Ethereum,
+            tokens: Vec::new(),
+            contract_ids: Vec::new(),
+            static_attributes,
+            change: ChangeType::Creation,
+            creation_tx: Bytes::from_str("0x0000").unwrap(),
+            created_at: creation_time,
+        }
+    }
+
+    fn algebra_attributes() -> HashMap<String, Bytes> {
+        vec![
+            ("liquidity".to_string(), Bytes::from(100_u64.to_be_bytes().to_vec())),
+            ("sqrt_price_x96".to_string(), Bytes::from(200_u64.to_be_bytes().to_vec())),
+            ("tick".to_string(), Bytes::from(300_i32.to_be_bytes().to_vec())),
+            ("fee".to_string(), Bytes::from(2500_u32.to_be_bytes().to_vec())),
+            ("ticks/60/net_liquidity".to_string(), Bytes::from(400_i128.to_be_bytes().to_vec())),
+        ]
+        .into_iter()
+        .collect::<HashMap<String, Bytes>>()
+    }
+
+    fn header() -> Header {
+        Header {
+            number: 1,
+            hash: Bytes::from(vec![0; 32]),
+            parent_hash: Bytes::from(vec![0; 32]),
+            revert: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_algebra_try_from() {
+        let snapshot = ComponentWithState {
+            state: ResponseProtocolState {
+                component_id: "State1".to_owned(),
+                attributes: algebra_attributes(),
+                balances: HashMap::new(),
+            },
+            component: algebra_component(),
+        };
+
+        let result =
+            AlgebraState::try_from_with_block(snapshot, header(), &HashMap::new(), &HashMap::new())
+                .await;
+
+        let expected =
+            AlgebraState::new(100, U256::from(200), 2500, 300, 60, vec![TickInfo::new(60, 400)]);
+        assert_eq!(result.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    #[rstest]
+    #[case::missing_liquidity("liquidity")]
+    #[case::missing_sqrt_price("sqrt_price")]
+    #[case::missing_tick("tick")]
+    #[case::missing_fee("fee")]
+    #[case::missing_tick_liquidity("tick_liquidities")]
+    #[case::missing_tick_spacing("tick_spacing")]
+    async fn test_algebra_try_from_invalid(#[case] missing_attribute: String) {
+        let mut attributes = algebra_attributes();
+        attributes.remove(&missing_attribute);
+
+        if missing_attribute == "tick_liquidities" {
+            attributes.remove("ticks/60/net_liquidity");
+        }
+
+        if missing_attribute == "sqrt_price" {
+            attributes.remove("sqrt_price_x96");
+        }
+
+        let mut component = algebra_component();
+        if missing_attribute == "tick_spacing" {
+            component
+                .static_attributes
+                .remove("tick_spacing");
+        }
+
+        let snapshot = ComponentWithState {
+            state: ResponseProtocolState {
+                component_id: "State1".to_owned(),
+                attributes,
+                balances: HashMap::new(),
+            },
+            component,
+        };
+
+        let result =
+            AlgebraState::try_from_with_block(snapshot, header(), &HashMap::new(), &HashMap::new())
+                .await;
+
+        assert!(matches!(
+            result.err().unwrap(),
+            InvalidSnapshotError::MissingAttribute(attr) if attr == missing_attribute
+        ));
+    }
+}
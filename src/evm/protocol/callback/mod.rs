@@ -0,0 +1,7 @@
+//! Callback based pools
+//!
+//! Pools that pay out through a Uniswap V3 style swap callback, quoted by simulating their `swap`
+//! through the `CallbackExecutor` (see `evm::callback`). This covers forks of Uniswap V3 without a
+//! native implementation, as long as their contracts are in the engine's database.
+pub mod state;
+mod tycho_decoder;
@@ -0,0 +1,341 @@
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use alloy_primitives::{Address, U256};
+use num_bigint::BigUint;
+use revm::DatabaseRef;
+use tycho_core::{dto::ProtocolStateDelta, Bytes};
+
+use crate::{
+    evm::{
+        callback::{CallbackExecutor, CallbackPool, CallbackSwap},
+        engine_db::{
+            engine_db_interface::EngineDatabaseInterface, simulation_db::BlockHeader,
+            tycho_db::PreCachedDB,
+        },
+        protocol::{
+            u256_num::{checked_biguint_to_u256, u256_to_biguint, u256_to_f64},
+            utils::bytes_to_address,
+        },
+        simulation::SimulationEngine,
+        ContractCompiler, SlotId,
+    },
+    models::{Balances, Token},
+    protocol::{
+        errors::{SimulationError, TransitionError},
+        models::GetAmountOutResult,
+        state::{fingerprint, ProtocolSim},
+    },
+};
+
+/// State of a pool paying out through a Uniswap V3 style swap callback.
+///
+/// Amounts are obtained by simulating the pool's `swap(address,bool,int256,uint160,bytes)` through
+/// the `CallbackExecutor`, so the pool's contracts must be in the engine's database. Swaps that
+/// change the pool's storage carry it into the `new_state` returned by `get_amount_out`, until the
+/// next call to `delta_transition`.
+#[derive(Clone, Debug)]
+pub struct CallbackPoolState<D: EngineDatabaseInterface + Clone + Debug>
+where
+    <D as DatabaseRef>::Error: Debug,
+    <D as EngineDatabaseInterface>::Error: Debug,
+{
+    pool: CallbackPool,
+    /// The pool's fee as a ratio, only used to report it: quotes already include it
+    fee: f64,
+    /// The current block, will be used to set vm context
+    block: BlockHeader,
+    /// Storage of the pool after the swaps quoted on this state
+    overwrites: HashMap<Address, HashMap<U256, U256>>,
+    /// Gas of the spot price simulations of the current block
+    spot_price_gas: Arc<AtomicU64>,
+    executor: CallbackExecutor<D>,
+    /// Number of deltas applied, stands in for the pool storage in the state fingerprint
+    revision: u64,
+}
+
+impl<D> CallbackPoolState<D>
+where
+    D: EngineDatabaseInterface + Clone + Debug + 'static,
+    <D as DatabaseRef>::Error: Debug,
+    <D as EngineDatabaseInterface>::Error: Debug,
+{
+    /// Creates a new `CallbackPoolState` and installs the executor into the engine's database.
+    ///
+    /// # Arguments
+    ///
+    /// * `pool` - The pool and its tokens, `token0` sorting before `token1` like in Uniswap V3.
+    /// * `fee` - The pool's fee as a ratio.
+    /// * `block` - The current block, used to set the vm context.
+    /// * `engine` - The engine used to simulate the swaps. The pool and all contracts it calls into
+    ///   must be available in the engine's database.
+    pub fn new(
+        pool: CallbackPool,
+        fee: f64,
+        block: BlockHeader,
+        engine: SimulationEngine<D>,
+    ) -> Self {
+        Self {
+            pool,
+            fee,
+            block,
+            overwrites: HashMap::new(),
+            spot_price_gas: Arc::default(),
+            executor: CallbackExecutor::new(engine),
+            revision: 0,
+        }
+    }
+
+    /// Sets the slot of the balance mapping of `token`, see
+    /// `CallbackExecutor::with_balance_slot`.
+    pub fn with_balance_slot(
+        mut self,
+        token: Address,
+        slot: SlotId,
+        compiler: ContractCompiler,
+    ) -> Self {
+        self.executor = self
+            .executor
+            .with_balance_slot(token, slot, compiler);
+        self
+    }
+
+    pub fn pool(&self) -> &CallbackPool {
+        &self.pool
+    }
+
+    /// Whether swapping `token_in` for `token_out` sells token0.
+    fn zero_for_one(&self, token_in: &Token, token_out: &Token) -> Result<bool, SimulationError> {
+        let token_in = bytes_to_address(&token_in.address)?;
+        let token_out = bytes_to_address(&token_out.address)?;
+        if token_in == self.pool.token0 && token_out == self.pool.token1 {
+            Ok(true)
+        } else if token_in == self.pool.token1 && token_out == self.pool.token0 {
+            Ok(false)
+        } else {
+            Err(SimulationError::InvalidInput(
+                format!(
+                    "Pool {} only supports swaps between {} and {}, got {} -> {}",
+                    self.pool.address, self.pool.token0, self.pool.token1, token_in, token_out
+                ),
+                None,
+            ))
+        }
+    }
+
+    /// Simulates selling `amount_in` of `token_in` on the pool.
+    fn swap(
+        &self,
+        amount_in: U256,
+        token_in: &Token,
+        token_out: &Token,
+    ) -> Result<CallbackSwap, SimulationError> {
+        let zero_for_one = self.zero_for_one(token_in, token_out)?;
+        self.executor
+            .swap_exact_in_with_overrides(
+                &self.pool,
+                zero_for_one,
+                amount_in,
+                &self.block,
+                self.overwrites.clone(),
+            )
+    }
+}
+
+impl<D> ProtocolSim for CallbackPoolState<D>
+where
+    D: EngineDatabaseInterface + Clone + Debug + 'static,
+    <D as DatabaseRef>::Error: Debug,
+    <D as EngineDatabaseInterface>::Error: Debug,
+{
+    fn fee(&self) -> f64 {
+        self.fee
+    }
+
+    fn spot_price(&self, base: &Token, quote: &Token) -> Result<f64, SimulationError> {
+        let swap = self.swap(base.try_one()?, base, quote)?;
+        self.spot_price_gas
+            .fetch_add(swap.gas_used, Ordering::Relaxed);
+        Ok(u256_to_f64(swap.amount_out()) / u256_to_f64(quote.try_one()?))
+    }
+
+    fn spot_price_gas(&self) -> u64 {
+        self.spot_price_gas
+            .load(Ordering::Relaxed)
+    }
+
+    fn get_amount_out(
+        &self,
+        amount_in: BigUint,
+        token_in: &Token,
+        token_out: &Token,
+    ) -> Result<GetAmountOutResult, SimulationError> {
+        let amount_in = checked_biguint_to_u256(&amount_in)?;
+        if amount_in == U256::ZERO {
+            return Err(SimulationError::InvalidInput("Amount in cannot be zero".to_string(), None));
+        }
+        let swap = self.swap(amount_in, token_in, token_out)?;
+
+        let mut new_state = self.clone();
+        if let Some(storage) = swap
+            .state_updates
+            .get(&self.pool.address)
+            .and_then(|update| update.storage.as_ref())
+        {
+            new_state
+                .overwrites
+                .entry(self.pool.address)
+                .or_default()
+                .extend(storage);
+        }
+        let result = GetAmountOutResult::new(
+            u256_to_biguint(swap.amount_out()),
+            BigUint::from(swap.gas_used),
+            Box::new(new_state),
+        );
+        // The swap stops at the price limit if the pool runs out of liquidity
+        if swap.amount_in() < amount_in {
+            return Err(SimulationError::InvalidInput(
+                format!(
+                    "Pool {} only sold {} of {}",
+                    self.pool.address,
+                    swap.amount_in(),
+                    amount_in
+                ),
+                Some(result),
+            ));
+        }
+        Ok(result)
+    }
+
+    fn is_blocking(&self) -> bool {
+        true
+    }
+
+    fn state_fingerprint(&self) -> u64 {
+        let overwrites: BTreeMap<_, BTreeMap<_, _>> = self
+            .overwrites
+            .iter()
+            .map(|(address, slots)| (address, slots.iter().collect()))
+            .collect();
+        fingerprint(&(self.pool.address, self.revision, overwrites))
+    }
+
+    fn delta_transition(
+        &mut self,
+        _delta: ProtocolStateDelta,
+        _tokens: &HashMap<Bytes, Token>,
+        _balances: &Balances,
+    ) -> Result<(), TransitionError<String>> {
+        // The pool storage is kept up to date in the engine's database, we only need to drop the
+        // storage of quoted swaps and the gas of the previous block.
+        self.overwrites.clear();
+        self.spot_price_gas = Arc::default();
+        self.revision += 1;
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn ProtocolSim> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn eq(&self, other: &dyn ProtocolSim) -> bool {
+        if let Some(other_state) = other
+            .as_any()
+            .downcast_ref::<CallbackPoolState<PreCachedDB>>()
+        {
+            self.pool == other_state.pool && self.overwrites == other_state.overwrites
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::ToBigUint;
+
+    use super::*;
+    use crate::evm::callback::fixtures::{engine, pool};
+
+    fn token(address: Address) -> Token {
+        Token::new(&address.to_string(), 18, "T", 10_000.to_biguint().unwrap())
+    }
+
+    fn state() -> CallbackPoolState<PreCachedDB> {
+        CallbackPoolState::new(pool(), 0.003, BlockHeader::default(), engine())
+    }
+
+    #[test]
+    fn test_get_amount_out() {
+        let state = state();
+        let (token0, token1) = (token(pool().token0), token(pool().token1));
+
+        let res = state
+            .get_amount_out(BigUint::from(100u32), &token0, &token1)
+            .unwrap();
+
+        assert_eq!(res.amount, BigUint::from(200u32));
+        assert!(res.gas > BigUint::ZERO);
+        assert!(res.new_state.eq(&state));
+    }
+
+    #[test]
+    fn test_spot_price() {
+        let state = state();
+        let (token0, token1) = (token(pool().token0), token(pool().token1));
+
+        assert_eq!(
+            state
+                .spot_price(&token0, &token1)
+                .unwrap(),
+            2.0
+        );
+        assert!(state.spot_price_gas() > 0);
+        assert_eq!(state.fee(), 0.003);
+    }
+
+    #[test]
+    fn test_get_amount_out_unknown_token() {
+        let state = state();
+
+        let res = state.get_amount_out(
+            BigUint::from(100u32),
+            &token(pool().token0),
+            &token(Address::repeat_byte(0x12)),
+        );
+
+        assert!(matches!(res, Err(SimulationError::InvalidInput(_, None))));
+    }
+
+    #[test]
+    fn test_delta_transition() {
+        let mut state = state();
+        state
+            .spot_price(&token(pool().token0), &token(pool().token1))
+            .unwrap();
+        let fingerprint = state.state_fingerprint();
+
+        state
+            .delta_transition(ProtocolStateDelta::default(), &HashMap::new(), &Balances::default())
+            .unwrap();
+
+        assert_eq!(state.spot_price_gas(), 0);
+        assert_ne!(state.state_fingerprint(), fingerprint);
+    }
+}
@@ -0,0 +1,170 @@
+use std::{collections::HashMap, str::FromStr};
+
+use alloy_primitives::Address;
+use tycho_client::feed::{synchronizer::ComponentWithState, Header};
+use tycho_core::Bytes;
+
+use super::state::CallbackPoolState;
+use crate::{
+    evm::{
+        callback::CallbackPool,
+        engine_db::{
+            create_engine, simulation_db::BlockHeader, tycho_db::PreCachedDB, SHARED_TYCHO_DB,
+        },
+    },
+    models::Token,
+    protocol::{errors::InvalidSnapshotError, models::TryFromWithBlock},
+};
+
+/// Fees are given in hundredths of a basis point, like in Uniswap V3
+const FEE_PRECISION: f64 = 1_000_000.0;
+
+impl TryFromWithBlock<ComponentWithState> for CallbackPoolState<PreCachedDB> {
+    type Error = InvalidSnapshotError;
+
+    /// Decodes a `ComponentWithState` into a `CallbackPoolState`.
+    ///
+    /// The component id is expected to be the pool address and its two tokens token0 and token1,
+    /// sorted by address. The fee is read from the optional `fee` static attribute, in hundredths
+    /// of a basis point. The pool contracts must be present in the shared tycho db.
+    async fn try_from_with_block(
+        snapshot: ComponentWithState,
+        block: Header,
+        _account_balances: &HashMap<Bytes, HashMap<Bytes, Bytes>>,
+        _all_tokens: &HashMap<Bytes, Token>,
+    ) -> Result<Self, Self::Error> {
+        let address = to_address(
+            &Bytes::from_str(&snapshot.component.id)
+                .map_err(|e| InvalidSnapshotError::ValueError(e.to_string()))?,
+            "Pool id",
+        )?;
+        let [token0, token1] = snapshot.component.tokens.as_slice() else {
+            return Err(InvalidSnapshotError::ValueError(format!(
+                "Pool {} needs two tokens, got {}",
+                snapshot.component.id,
+                snapshot.component.tokens.len()
+            )));
+        };
+        let (token0, token1) = (to_address(token0, "Token")?, to_address(token1, "Token")?);
+        if token0 >= token1 {
+            return Err(InvalidSnapshotError::ValueError(format!(
+                "Tokens of pool {} are not sorted",
+                snapshot.component.id
+            )));
+        }
+
+        let fee = snapshot
+            .component
+            .static_attributes
+            .get("fee")
+            .map(|fee| f64::from(u32::from(fee.clone())) / FEE_PRECISION)
+            .unwrap_or_default();
+
+        let engine = create_engine(SHARED_TYCHO_DB.clone(), false)?;
+
+        Ok(CallbackPoolState::new(
+            CallbackPool { address, token0, token1 },
+            fee,
+            BlockHeader::from(block),
+            engine,
+        ))
+    }
+}
+
+fn to_address(bytes: &Bytes, name: &str) -> Result<Address, InvalidSnapshotError> {
+    if bytes.len() != 20 {
+        return Err(InvalidSnapshotError::ValueError(format!(
+            "{} {} is not an address",
+            name, bytes
+        )));
+    }
+    Ok(Address::from_slice(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+    use tycho_core::dto::{Chain, ChangeType, ProtocolComponent, ResponseProtocolState};
+
+    use super::*;
+
+    const POOL: &str = "0x1f98431c8ad98523631ae4a59f267346ea31f984";
+    const USDC: &str = "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48";
+    const WETH: &str = "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2";
+
+    fn snapshot(tokens: &[&str], static_attributes: HashMap<String, Bytes>) -> ComponentWithState {
+        let creation_time = DateTime::from_timestamp(1622526000, 0)
+            .unwrap()
+            .naive_utc(); //Sample timestamp
+
+        ComponentWithState {
+            state: ResponseProtocolState {
+                component_id: POOL.to_owned(),
+                attributes: HashMap::new(),
+                balances: HashMap::new(),
+            },
+            component: ProtocolComponent {
+                id: POOL.to_string(),
+                protocol_system: "vm:callback".to_string(),
+                protocol_type_name: "callback_pool".to_string(),
+                chain: Chain::Ethereum,
+                tokens: tokens
+                    .iter()
+                    .map(|token| Bytes::from_str(token).unwrap())
+                    .collect(),
+                contract_ids: Vec::new(),
+                static_attributes,
+                change: ChangeType::Creation,
+                creation_tx: Bytes::from_str("0x0000").unwrap(),
+                created_at: creation_time,
+            },
+        }
+    }
+
+    fn header() -> Header {
+        Header {
+            number: 1,
+            hash: Bytes::from(vec![0; 32]),
+            parent_hash: Bytes::from(vec![0; 32]),
+            revert: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_callback_pool_try_from() {
+        let attributes =
+            HashMap::from([("fee".to_string(), Bytes::from(3000_u32.to_be_bytes().to_vec()))]);
+
+        let state = CallbackPoolState::try_from_with_block(
+            snapshot(&[USDC, WETH], attributes),
+            header(),
+            &HashMap::new(),
+            &HashMap::new(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            *state.pool(),
+            CallbackPool {
+                address: Address::from_str(POOL).unwrap(),
+                token0: Address::from_str(USDC).unwrap(),
+                token1: Address::from_str(WETH).unwrap(),
+            }
+        );
+        assert_eq!(crate::protocol::state::ProtocolSim::fee(&state), 0.003);
+    }
+
+    #[tokio::test]
+    async fn test_callback_pool_try_from_unsorted_tokens() {
+        let result = CallbackPoolState::try_from_with_block(
+            snapshot(&[WETH, USDC], HashMap::new()),
+            header(),
+            &HashMap::new(),
+            &HashMap::new(),
+        )
+        .await;
+
+        assert!(matches!(result, Err(InvalidSnapshotError::ValueError(_))));
+    }
+}
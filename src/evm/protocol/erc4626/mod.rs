@@ -0,0 +1,6 @@
+//! ERC-4626 Tokenized Vaults
+//!
+//! Deposits and redemptions of ERC-4626 vaults (e.g. yvTokens, sDAI, Morpho vaults) are modelled
+//! as swaps between the vault's underlying asset and its share token.
+pub mod state;
+mod tycho_decoder;
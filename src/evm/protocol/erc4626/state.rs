@@ -0,0 +1,417 @@
+use std::{
+    any::Any,
+    collections::HashMap,
+    fmt::Debug,
+    sync::{Arc, RwLock},
+};
+
+use alloy_primitives::{Address, U256};
+use alloy_sol_types::SolValue;
+use num_bigint::BigUint;
+use revm::DatabaseRef;
+use strum_macros::Display;
+use tycho_core::{dto::ProtocolStateDelta, Bytes};
+
+use crate::{
+    evm::{
+        engine_db::{
+            engine_db_interface::EngineDatabaseInterface, simulation_db::BlockHeader,
+            tycho_db::PreCachedDB,
+        },
+        protocol::{
+            u256_num::{checked_biguint_to_u256, u256_to_biguint, u256_to_f64},
+            utils::bytes_to_address,
+            vm::tycho_simulation_contract::TychoSimulationContract,
+        },
+        simulation::SimulationEngine,
+    },
+    models::{Balances, Token},
+    protocol::{
+        errors::{SimulationError, TransitionError},
+        models::GetAmountOutResult,
+        state::{fingerprint, ProtocolSim},
+    },
+};
+
+type PreviewReturn = U256;
+
+/// The vault entrypoint used to move between the underlying asset and the vault shares.
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VaultOperation {
+    /// Underlying asset in, vault shares out. Quoted with `previewDeposit`.
+    Deposit,
+    /// Vault shares in, underlying asset out. Quoted with `previewRedeem`.
+    Redeem,
+}
+
+impl VaultOperation {
+    fn selector(&self) -> &'static str {
+        match self {
+            VaultOperation::Deposit => "previewDeposit(uint256)",
+            VaultOperation::Redeem => "previewRedeem(uint256)",
+        }
+    }
+}
+
+/// Preview results keyed by operation and input amount. Holds the output amount and the gas used
+/// by the simulated preview call.
+type PreviewCache = HashMap<(VaultOperation, U256), (U256, u64)>;
+
+/// State of an ERC-4626 tokenized vault.
+///
+/// The vault is exposed as a two token pool between its underlying `asset` and its share token
+/// (the vault contract itself). Amounts are obtained by simulating the vault's `previewDeposit`
+/// and `previewRedeem` view functions on the engine. Preview results are cached until the next
+/// call to `delta_transition` or until the state moves to a later block, see
+/// `ProtocolSim::set_block_timestamp`.
+///
+/// Note that quoting does not modify the vault storage, so the `new_state` returned by
+/// `get_amount_out` is equal to the current state.
+#[derive(Clone, Debug)]
+pub struct Erc4626State<D: EngineDatabaseInterface + Clone + Debug>
+where
+    <D as DatabaseRef>::Error: Debug,
+    <D as EngineDatabaseInterface>::Error: Debug,
+{
+    /// The vault address, which is also the address of the share token
+    vault: Address,
+    /// The address of the vault's underlying asset
+    asset: Address,
+    /// The current block, will be used to set vm context
+    block: BlockHeader,
+    /// Preview results of the current block
+    preview_cache: Arc<RwLock<PreviewCache>>,
+    /// The vault contract, used to run the preview simulations
+    vault_contract: TychoSimulationContract<D>,
+    /// Number of deltas applied, stands in for the vault storage in the state fingerprint
+    revision: u64,
+}
+
+impl<D> Erc4626State<D>
+where
+    D: EngineDatabaseInterface + Clone + Debug + 'static,
+    <D as DatabaseRef>::Error: Debug,
+    <D as EngineDatabaseInterface>::Error: Debug,
+{
+    /// Creates a new `Erc4626State`.
+    ///
+    /// # Arguments
+    ///
+    /// * `vault` - Address of the vault (share token).
+    /// * `asset` - Address of the vault's underlying asset.
+    /// * `block` - The current block, used to set the vm context.
+    /// * `engine` - The engine used to simulate the preview calls. The vault contract and all
+    ///   contracts it calls into must be available in the engine's database.
+    pub fn new(
+        vault: Address,
+        asset: Address,
+        block: BlockHeader,
+        engine: SimulationEngine<D>,
+    ) -> Result<Self, SimulationError> {
+        Ok(Self {
+            vault,
+            asset,
+            block,
+            preview_cache: Arc::new(RwLock::new(HashMap::new())),
+            vault_contract: TychoSimulationContract::new(vault, engine)?,
+            revision: 0,
+        })
+    }
+
+    pub fn vault(&self) -> Address {
+        self.vault
+    }
+
+    pub fn asset(&self) -> Address {
+        self.asset
+    }
+
+    /// Determines the vault operation needed to swap `token_in` for `token_out`.
+    pub fn operation(
+        &self,
+        token_in: &Token,
+        token_out: &Token,
+    ) -> Result<VaultOperation, SimulationError> {
+        let token_in = bytes_to_address(&token_in.address)?;
+        let token_out = bytes_to_address(&token_out.address)?;
+        if token_in == self.asset && token_out == self.vault {
+            Ok(VaultOperation::Deposit)
+        } else if token_in == self.vault && token_out == self.asset {
+            Ok(VaultOperation::Redeem)
+        } else {
+            Err(SimulationError::InvalidInput(
+                format!(
+                    "Vault {} only supports swaps between {} and its shares, got {} -> {}",
+                    self.vault, self.asset, token_in, token_out
+                ),
+                None,
+            ))
+        }
+    }
+
+    /// Simulates the preview function for the given operation and amount.
+    ///
+    /// Returns the output amount together with the gas used by the simulation. Results are
+    /// served from the block cache if available.
+    fn preview(
+        &self,
+        operation: VaultOperation,
+        amount: U256,
+    ) -> Result<(U256, u64), SimulationError> {
+        if let Some(cached) = self
+            .preview_cache
+            .read()
+            .map_err(|_| SimulationError::FatalError("Preview cache lock poisoned".to_string()))?
+            .get(&(operation, amount))
+        {
+            return Ok(*cached);
+        }
+
+        let res = self.vault_contract.call(
+            operation.selector(),
+            amount,
+            &self.block,
+            None,
+            None,
+            U256::ZERO,
+        )?;
+        let amount_out = PreviewReturn::abi_decode(&res.return_value, true).map_err(|e| {
+            SimulationError::FatalError(format!(
+                "Vault {} call failed: Failed to decode return value: {:?}",
+                operation, e
+            ))
+        })?;
+        let result = (amount_out, res.simulation_result.gas_used);
+
+        self.preview_cache
+            .write()
+            .map_err(|_| SimulationError::FatalError("Preview cache lock poisoned".to_string()))?
+            .insert((operation, amount), result);
+        Ok(result)
+    }
+}
+
+impl<D> ProtocolSim for Erc4626State<D>
+where
+    D: EngineDatabaseInterface + Clone + Debug + 'static,
+    <D as DatabaseRef>::Error: Debug,
+    <D as EngineDatabaseInterface>::Error: Debug,
+{
+    fn fee(&self) -> f64 {
+        0.0
+    }
+
+    fn spot_price(&self, base: &Token, quote: &Token) -> Result<f64, SimulationError> {
+        let operation = self.operation(base, quote)?;
+        let (amount_out, _) = self.preview(operation, base.try_one()?)?;
+        Ok(u256_to_f64(amount_out) / u256_to_f64(quote.try_one()?))
+    }
+
+    /// Gas of the previews simulated in the current block, spot prices included once computed.
+    fn spot_price_gas(&self) -> u64 {
+        self.preview_cache
+            .read()
+            .map(|cache| cache.values().map(|(_, gas)| gas).sum())
+            .unwrap_or_default()
+    }
+
+    fn get_amount_out(
+        &self,
+        amount_in: BigUint,
+        token_in: &Token,
+        token_out: &Token,
+    ) -> Result<GetAmountOutResult, SimulationError> {
+        let amount_in = checked_biguint_to_u256(&amount_in)?;
+        if amount_in == U256::ZERO {
+            return Err(SimulationError::InvalidInput("Amount in cannot be zero".to_string(), None));
+        }
+        let operation = self.operation(token_in, token_out)?;
+        let (amount_out, gas_used) = self.preview(operation, amount_in)?;
+
+        Ok(GetAmountOutResult::new(
+            u256_to_biguint(amount_out),
+            BigUint::from(gas_used),
+            Box::new(self.clone()),
+        ))
+    }
+
+    fn is_blocking(&self) -> bool {
+        true
+    }
+
+    fn state_fingerprint(&self) -> u64 {
+        fingerprint(&(self.vault, self.revision))
+    }
+
+    fn delta_transition(
+        &mut self,
+        _delta: ProtocolStateDelta,
+        _tokens: &HashMap<Bytes, Token>,
+        _balances: &Balances,
+    ) -> Result<(), TransitionError<String>> {
+        // The vault storage is kept up to date in the engine's database, we only need to drop
+        // previews computed on the previous block.
+        self.preview_cache = Arc::new(RwLock::new(HashMap::new()));
+        self.revision += 1;
+        Ok(())
+    }
+
+    fn set_block_timestamp(&mut self, timestamp: u64) {
+        // Previews accrue interest with the block timestamp, so they can't outlive their block.
+        // Only the timestamp is known here, the block number stays unchanged.
+        if self.block.timestamp != timestamp {
+            self.block.timestamp = timestamp;
+            self.preview_cache = Arc::new(RwLock::new(HashMap::new()));
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn ProtocolSim> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn eq(&self, other: &dyn ProtocolSim) -> bool {
+        if let Some(other_state) = other
+            .as_any()
+            .downcast_ref::<Erc4626State<PreCachedDB>>()
+        {
+            self.vault == other_state.vault && self.asset == other_state.asset
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use num_bigint::ToBigUint;
+
+    use super::*;
+    use crate::evm::engine_db::create_engine;
+
+    fn sdai() -> Token {
+        Token::new(
+            "0x83f20f44975d03b1b09e64809b757c47f942beea",
+            18,
+            "sDAI",
+            10_000.to_biguint().unwrap(),
+        )
+    }
+
+    fn dai() -> Token {
+        Token::new(
+            "0x6b175474e89094c44da98b954eedeac495271d0f",
+            18,
+            "DAI",
+            10_000.to_biguint().unwrap(),
+        )
+    }
+
+    fn usdc() -> Token {
+        Token::new(
+            "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48",
+            6,
+            "USDC",
+            10_000.to_biguint().unwrap(),
+        )
+    }
+
+    fn vault_state() -> Erc4626State<PreCachedDB> {
+        let engine = create_engine(PreCachedDB::new().unwrap(), false).unwrap();
+        Erc4626State::new(
+            Address::from_str("0x83f20f44975d03b1b09e64809b757c47f942beea").unwrap(),
+            Address::from_str("0x6b175474e89094c44da98b954eedeac495271d0f").unwrap(),
+            BlockHeader::default(),
+            engine,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_operation() {
+        let state = vault_state();
+
+        assert_eq!(
+            state
+                .operation(&dai(), &sdai())
+                .unwrap(),
+            VaultOperation::Deposit
+        );
+        assert_eq!(
+            state
+                .operation(&sdai(), &dai())
+                .unwrap(),
+            VaultOperation::Redeem
+        );
+        assert!(matches!(
+            state.operation(&usdc(), &sdai()),
+            Err(SimulationError::InvalidInput(_, None))
+        ));
+    }
+
+    #[test]
+    fn test_get_amount_out_zero() {
+        let state = vault_state();
+
+        let res = state.get_amount_out(BigUint::ZERO, &dai(), &sdai());
+
+        assert!(matches!(res, Err(SimulationError::InvalidInput(_, None))));
+    }
+
+    #[test]
+    fn test_delta_transition_resets_cache() {
+        let mut state = vault_state();
+        state
+            .preview_cache
+            .write()
+            .unwrap()
+            .insert((VaultOperation::Deposit, U256::from(1)), (U256::from(1), 50_000));
+        assert_eq!(state.spot_price_gas(), 50_000);
+        let old_state = state.clone();
+
+        state
+            .delta_transition(ProtocolStateDelta::default(), &HashMap::new(), &Balances::default())
+            .unwrap();
+
+        assert!(state
+            .preview_cache
+            .read()
+            .unwrap()
+            .is_empty());
+        assert_eq!(state.spot_price_gas(), 0);
+        assert_eq!(
+            old_state
+                .preview_cache
+                .read()
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_set_block_timestamp_resets_cache() {
+        let mut state = vault_state();
+        state
+            .preview_cache
+            .write()
+            .unwrap()
+            .insert((VaultOperation::Deposit, U256::from(1)), (U256::from(1), 50_000));
+
+        state.set_block_timestamp(state.block.timestamp);
+        assert_eq!(state.spot_price_gas(), 50_000);
+
+        state.set_block_timestamp(state.block.timestamp + 12);
+        assert_eq!(state.block.timestamp, 12);
+        assert_eq!(state.spot_price_gas(), 0);
+    }
+}
@@ -0,0 +1,154 @@
+use std::{collections::HashMap, str::FromStr};
+
+use alloy_primitives::Address;
+use tycho_client::feed::{synchronizer::ComponentWithState, Header};
+use tycho_core::Bytes;
+
+use super::state::Erc4626State;
+use crate::{
+    evm::engine_db::{
+        create_engine, simulation_db::BlockHeader, tycho_db::PreCachedDB, SHARED_TYCHO_DB,
+    },
+    models::Token,
+    protocol::{errors::InvalidSnapshotError, models::TryFromWithBlock},
+};
+
+impl TryFromWithBlock<ComponentWithState> for Erc4626State<PreCachedDB> {
+    type Error = InvalidSnapshotError;
+
+    /// Decodes a `ComponentWithState` into an `Erc4626State`.
+    ///
+    /// The component id is expected to be the vault address. The underlying asset is read from
+    /// the `asset` static attribute, or, if absent, taken to be the component token which is not
+    /// the vault itself. The vault contract storage must be present in the shared tycho db.
+    async fn try_from_with_block(
+        snapshot: ComponentWithState,
+        block: Header,
+        _account_balances: &HashMap<Bytes, HashMap<Bytes, Bytes>>,
+        _all_tokens: &HashMap<Bytes, Token>,
+    ) -> Result<Self, Self::Error> {
+        let vault_bytes = Bytes::from_str(&snapshot.component.id)
+            .map_err(|e| InvalidSnapshotError::ValueError(e.to_string()))?;
+        if vault_bytes.len() != 20 {
+            return Err(InvalidSnapshotError::ValueError(format!(
+                "Vault id {} is not an address",
+                snapshot.component.id
+            )));
+        }
+        let vault = Address::from_slice(&vault_bytes);
+
+        let asset = match snapshot
+            .component
+            .static_attributes
+            .get("asset")
+        {
+            Some(asset) => asset.clone(),
+            None => snapshot
+                .component
+                .tokens
+                .iter()
+                .find(|token| **token != vault_bytes)
+                .cloned()
+                .ok_or_else(|| InvalidSnapshotError::MissingAttribute("asset".to_string()))?,
+        };
+        if asset.len() != 20 {
+            return Err(InvalidSnapshotError::ValueError(format!(
+                "Vault asset {} is not an address",
+                asset
+            )));
+        }
+
+        let engine = create_engine(SHARED_TYCHO_DB.clone(), false)?;
+
+        Ok(Erc4626State::new(vault, Address::from_slice(&asset), BlockHeader::from(block), engine)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+    use tycho_core::dto::{Chain, ChangeType, ProtocolComponent, ResponseProtocolState};
+
+    use super::*;
+
+    fn vault_component(tokens: Vec<Bytes>) -> ProtocolComponent {
+        let creation_time = DateTime::from_timestamp(1622526000, 0)
+            .unwrap()
+            .naive_utc(); //Sample timestamp
+
+        ProtocolComponent {
+            id: "0x83f20f44975d03b1b09e64809b757c47f942beea".to_string(),
+            protocol_system: "vm:erc4626".to_string(),
+            protocol_type_name: "erc4626_vault".to_string(),
+            chain: Chain::Ethereum,
+            tokens,
+            contract_ids: Vec::new(),
+            static_attributes: HashMap::new(),
+            change: ChangeType::Creation,
+            creation_tx: Bytes::from_str("0x0000").unwrap(),
+            created_at: creation_time,
+        }
+    }
+
+    fn header() -> Header {
+        Header {
+            number: 1,
+            hash: Bytes::from(vec![0; 32]),
+            parent_hash: Bytes::from(vec![0; 32]),
+            revert: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_erc4626_try_from() {
+        let snapshot = ComponentWithState {
+            state: ResponseProtocolState {
+                component_id: "0x83f20f44975d03b1b09e64809b757c47f942beea".to_owned(),
+                attributes: HashMap::new(),
+                balances: HashMap::new(),
+            },
+            component: vault_component(vec![
+                Bytes::from_str("0x6b175474e89094c44da98b954eedeac495271d0f").unwrap(),
+                Bytes::from_str("0x83f20f44975d03b1b09e64809b757c47f942beea").unwrap(),
+            ]),
+        };
+
+        let result =
+            Erc4626State::try_from_with_block(snapshot, header(), &HashMap::new(), &HashMap::new())
+                .await
+                .unwrap();
+
+        assert_eq!(
+            result.vault(),
+            Address::from_str("0x83f20f44975d03b1b09e64809b757c47f942beea").unwrap()
+        );
+        assert_eq!(
+            result.asset(),
+            Address::from_str("0x6b175474e89094c44da98b954eedeac495271d0f").unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_erc4626_try_from_missing_asset() {
+        let snapshot = ComponentWithState {
+            state: ResponseProtocolState {
+                component_id: "0x83f20f44975d03b1b09e64809b757c47f942beea".to_owned(),
+                attributes: HashMap::new(),
+                balances: HashMap::new(),
+            },
+            component: vault_component(vec![Bytes::from_str(
+                "0x83f20f44975d03b1b09e64809b757c47f942beea",
+            )
+            .unwrap()]),
+        };
+
+        let result =
+            Erc4626State::try_from_with_block(snapshot, header(), &HashMap::new(), &HashMap::new())
+                .await;
+
+        assert!(matches!(
+            result.err().unwrap(),
+            InvalidSnapshotError::MissingAttribute(attr) if attr == "asset"
+        ));
+    }
+}
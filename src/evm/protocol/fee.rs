@@ -0,0 +1,283 @@
+//! Swap fees of analytical states
+//!
+//! `FeeModel` describes how a pool charges its fee and applies it with checked integer math, so
+//! states share one implementation instead of each rounding fees its own way. Amounts are rounded
+//! once, at the end, and fees round in the pool's favour: the input left for the swap and the
+//! output after the fee are rounded down.
+use std::{fmt, sync::Arc};
+
+use alloy_primitives::U256;
+
+use crate::{
+    evm::protocol::safe_math::{
+        safe_add_u256, safe_div_u256, safe_div_u256_rounded, safe_mul_u256, safe_sub_u256,
+    },
+    protocol::{errors::SimulationError, rounding::RoundingPolicy},
+};
+
+/// Basis points in a whole
+pub const BPS: u32 = 10_000;
+
+/// Fee rate in basis points charged on the input of a swap of the given amount.
+pub type DynamicFee = Arc<dyn Fn(U256) -> u32 + Send + Sync>;
+
+/// How a pool charges its swap fee, see module docs.
+#[derive(Clone)]
+pub enum FeeModel {
+    /// A share of the input, in basis points
+    InputBps(u32),
+    /// A share of the output, in basis points
+    OutputBps(u32),
+    /// A fixed amount of the input token
+    Flat(U256),
+    /// A share of the input, in basis points, depending on the amount swapped
+    Dynamic(DynamicFee),
+}
+
+impl fmt::Debug for FeeModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InputBps(bps) => f
+                .debug_tuple("InputBps")
+                .field(bps)
+                .finish(),
+            Self::OutputBps(bps) => f
+                .debug_tuple("OutputBps")
+                .field(bps)
+                .finish(),
+            Self::Flat(amount) => f
+                .debug_tuple("Flat")
+                .field(amount)
+                .finish(),
+            Self::Dynamic(_) => f.write_str("Dynamic"),
+        }
+    }
+}
+
+impl FeeModel {
+    /// The fee as a fraction of the amount swapped, as returned by `ProtocolSim::fee`. Flat fees
+    /// don't depend on the amount and report zero; dynamic fees report their rate for a zero
+    /// input.
+    pub fn rate(&self) -> f64 {
+        match self {
+            Self::InputBps(bps) | Self::OutputBps(bps) => *bps as f64 / BPS as f64,
+            Self::Flat(_) => 0.0,
+            Self::Dynamic(fee) => fee(U256::ZERO) as f64 / BPS as f64,
+        }
+    }
+
+    /// The input left for the swap after the fee, rounded down.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the rate exceeds 100% or a flat fee exceeds the input.
+    pub fn net_input(&self, amount_in: U256) -> Result<U256, SimulationError> {
+        match self {
+            Self::InputBps(_) | Self::Dynamic(_) => safe_div_u256(
+                safe_mul_u256(amount_in, U256::from(BPS - self.input_bps(amount_in)?))?,
+                U256::from(BPS),
+            ),
+            Self::OutputBps(_) => Ok(amount_in),
+            Self::Flat(fee) => safe_sub_u256(amount_in, *fee).map_err(|_| {
+                SimulationError::InvalidInput(
+                    format!("Amount {amount_in} doesn't cover the fee {fee}"),
+                    None,
+                )
+            }),
+        }
+    }
+
+    /// The output left after the fee, rounded down.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the rate exceeds 100%.
+    pub fn net_output(&self, amount_out: U256) -> Result<U256, SimulationError> {
+        match self {
+            Self::OutputBps(bps) => safe_div_u256(
+                safe_mul_u256(amount_out, U256::from(BPS - checked_bps(*bps)?))?,
+                U256::from(BPS),
+            ),
+            _ => Ok(amount_out),
+        }
+    }
+
+    /// The output of a constant product pool (`x * y = k`) for `amount_in`, after the fee. The
+    /// fee is applied exactly and the result rounded once, according to `rounding`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the rate exceeds 100%, a flat fee exceeds the input, or on overflow.
+    pub fn constant_product_out(
+        &self,
+        amount_in: U256,
+        reserve_in: U256,
+        reserve_out: U256,
+        rounding: RoundingPolicy,
+    ) -> Result<U256, SimulationError> {
+        let bps = U256::from(BPS);
+        let (numerator, denominator) = match self {
+            // out = in * reserve_out / (reserve_in + in) * (BPS - fee) / BPS
+            Self::OutputBps(fee) => {
+                let fee_factor = U256::from(BPS - checked_bps(*fee)?);
+                (
+                    safe_mul_u256(safe_mul_u256(amount_in, reserve_out)?, fee_factor)?,
+                    safe_mul_u256(safe_add_u256(reserve_in, amount_in)?, bps)?,
+                )
+            }
+            Self::Flat(_) => {
+                let amount_in = self.net_input(amount_in)?;
+                (safe_mul_u256(amount_in, reserve_out)?, safe_add_u256(reserve_in, amount_in)?)
+            }
+            // out = in' * reserve_out / (reserve_in * BPS + in'), in' = in * (BPS - fee)
+            Self::InputBps(_) | Self::Dynamic(_) => {
+                let fee_factor = U256::from(BPS - self.input_bps(amount_in)?);
+                let amount_in_with_fee = safe_mul_u256(amount_in, fee_factor)?;
+                (
+                    safe_mul_u256(amount_in_with_fee, reserve_out)?,
+                    safe_add_u256(safe_mul_u256(reserve_in, bps)?, amount_in_with_fee)?,
+                )
+            }
+        };
+        safe_div_u256_rounded(numerator, denominator, rounding)
+    }
+
+    /// The rate charged on the input, zero if the fee isn't a share of the input.
+    fn input_bps(&self, amount_in: U256) -> Result<u32, SimulationError> {
+        match self {
+            Self::InputBps(bps) => checked_bps(*bps),
+            Self::Dynamic(fee) => checked_bps(fee(amount_in)),
+            Self::OutputBps(_) | Self::Flat(_) => Ok(0),
+        }
+    }
+}
+
+fn checked_bps(bps: u32) -> Result<u32, SimulationError> {
+    if bps > BPS {
+        return Err(SimulationError::FatalError(format!("Fee of {bps} bps exceeds 100%")));
+    }
+    Ok(bps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uniswap_v2_out(amount_in: u64, reserve_in: u64, reserve_out: u64) -> U256 {
+        // getAmountOut of the Uniswap V2 router
+        let amount_in_with_fee = U256::from(amount_in) * U256::from(997);
+        amount_in_with_fee * U256::from(reserve_out) /
+            (U256::from(reserve_in) * U256::from(1000) + amount_in_with_fee)
+    }
+
+    #[test]
+    fn test_input_fee() {
+        let fee = FeeModel::InputBps(30);
+
+        for (amount_in, reserve_in, reserve_out) in
+            [(1_000, 1_000_000, 2_000_000), (7, 13, 1_000_003), (999_999, 1, 1)]
+        {
+            assert_eq!(
+                fee.constant_product_out(
+                    U256::from(amount_in),
+                    U256::from(reserve_in),
+                    U256::from(reserve_out),
+                    RoundingPolicy::Floor
+                )
+                .unwrap(),
+                uniswap_v2_out(amount_in, reserve_in, reserve_out)
+            );
+        }
+        // 1_001 * 0.997 = 997.997
+        assert_eq!(
+            fee.net_input(U256::from(1_001))
+                .unwrap(),
+            U256::from(997)
+        );
+        assert_eq!(
+            fee.net_output(U256::from(1_001))
+                .unwrap(),
+            U256::from(1_001)
+        );
+        assert_eq!(fee.rate(), 0.003);
+    }
+
+    #[test]
+    fn test_rounding() {
+        let fee = FeeModel::InputBps(0);
+        // 2 * 5 / (2 + 2) = 2.5
+        let out = |rounding| {
+            fee.constant_product_out(U256::from(2), U256::from(2), U256::from(5), rounding)
+                .unwrap()
+        };
+
+        assert_eq!(out(RoundingPolicy::Floor), U256::from(2));
+        assert_eq!(out(RoundingPolicy::Nearest), U256::from(3));
+    }
+
+    #[test]
+    fn test_output_fee() {
+        let fee = FeeModel::OutputBps(100);
+
+        // 1_000 * 1_000 / (1_000 + 1_000) = 500, 1% of which is the fee
+        assert_eq!(
+            fee.constant_product_out(
+                U256::from(1_000),
+                U256::from(1_000),
+                U256::from(1_000),
+                RoundingPolicy::Floor
+            )
+            .unwrap(),
+            U256::from(495)
+        );
+        assert_eq!(fee.net_output(U256::from(199)).unwrap(), U256::from(197));
+        assert_eq!(fee.net_input(U256::from(199)).unwrap(), U256::from(199));
+    }
+
+    #[test]
+    fn test_flat_fee() {
+        let fee = FeeModel::Flat(U256::from(10));
+
+        assert_eq!(
+            fee.constant_product_out(
+                U256::from(1_010),
+                U256::from(1_000),
+                U256::from(1_000),
+                RoundingPolicy::Floor
+            )
+            .unwrap(),
+            U256::from(500)
+        );
+        assert_eq!(fee.rate(), 0.0);
+        assert!(matches!(fee.net_input(U256::from(9)), Err(SimulationError::InvalidInput(..))));
+    }
+
+    #[test]
+    fn test_dynamic_fee() {
+        // 0.3% up to 1_000, 1% above
+        let fee = FeeModel::Dynamic(Arc::new(
+            |amount_in| {
+                if amount_in > U256::from(1_000) {
+                    100
+                } else {
+                    30
+                }
+            },
+        ));
+
+        assert_eq!(
+            fee.net_input(U256::from(1_000))
+                .unwrap(),
+            U256::from(997)
+        );
+        assert_eq!(
+            fee.net_input(U256::from(2_000))
+                .unwrap(),
+            U256::from(1_980)
+        );
+        assert_eq!(fee.rate(), 0.003);
+        assert!(FeeModel::InputBps(BPS + 1)
+            .net_input(U256::from(1))
+            .is_err());
+    }
+}
@@ -0,0 +1,8 @@
+//! Fraxswap TWAMM pairs
+//!
+//! Fraxswap pairs are constant product pools with an embedded TWAMM: long-term orders sell at a
+//! constant rate against the pool and are only settled ("virtually executed") by the next
+//! interaction. The reserves reported by a snapshot are therefore stale, quotes first execute the
+//! pending virtual orders up to the quote timestamp.
+pub mod state;
+pub mod tycho_decoder;
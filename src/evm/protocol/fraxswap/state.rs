@@ -0,0 +1,585 @@
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap},
+};
+
+use alloy_primitives::U256;
+use num_bigint::BigUint;
+use tycho_core::{dto::ProtocolStateDelta, Bytes};
+
+use crate::{
+    evm::protocol::{
+        fee::{FeeModel, BPS},
+        safe_math::{safe_add_u256, safe_div_u256, safe_mul_u256, safe_sub_u256},
+        u256_num::{checked_biguint_to_u256, u256_to_biguint},
+        uniswap_v2::reserve_price::spot_price_from_reserves,
+    },
+    models::{Balances, Token},
+    protocol::{
+        errors::{SimulationError, TransitionError},
+        models::GetAmountOutResult,
+        rounding::RoundingPolicy,
+        state::{fingerprint, ProtocolSim},
+    },
+};
+
+/// Sale rates are stored with additional precision, in token units per second times this value
+pub const SELL_RATE_PRECISION: u64 = 1_000_000;
+
+/// Checks that a fee in basis points is at most 100%.
+pub(super) fn checked_fee(fee: u32) -> Result<u32, String> {
+    if fee > BPS {
+        return Err(format!("Fee of {fee} bps exceeds 100%"));
+    }
+    Ok(fee)
+}
+
+/// Pending sale rates of the long-term orders of one pair, per direction
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct OrderPools {
+    /// Rate token0 is currently sold at
+    pub sale_rate0: U256,
+    /// Rate token1 is currently sold at
+    pub sale_rate1: U256,
+    /// Sale rates ending at each expiry timestamp, as `(token0, token1)`
+    pub expirations: BTreeMap<u64, (U256, U256)>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FraxswapState {
+    reserve0: U256,
+    reserve1: U256,
+    /// Swap fee, in basis points
+    fee: u32,
+    /// Interval orders expire at multiples of, in seconds
+    order_time_interval: u64,
+    /// Time the virtual orders were last executed until
+    last_virtual_order_timestamp: u64,
+    orders: OrderPools,
+    /// Time quotes execute the virtual orders until
+    timestamp: u64,
+}
+
+impl FraxswapState {
+    /// Creates a new instance of `FraxswapState`. Quotes execute the virtual orders until
+    /// `set_timestamp`, by default until `last_virtual_order_timestamp`.
+    ///
+    /// # Arguments
+    ///
+    /// * `reserve0` - Reserve of token 0, as of `last_virtual_order_timestamp`.
+    /// * `reserve1` - Reserve of token 1, as of `last_virtual_order_timestamp`.
+    /// * `fee` - Swap fee, in basis points.
+    /// * `order_time_interval` - Interval orders expire at multiples of, in seconds.
+    /// * `last_virtual_order_timestamp` - Time the virtual orders were last executed until.
+    /// * `orders` - The pending long-term orders.
+    pub fn new(
+        reserve0: U256,
+        reserve1: U256,
+        fee: u32,
+        order_time_interval: u64,
+        last_virtual_order_timestamp: u64,
+        orders: OrderPools,
+    ) -> Self {
+        FraxswapState {
+            reserve0,
+            reserve1,
+            fee,
+            order_time_interval: order_time_interval.max(1),
+            last_virtual_order_timestamp,
+            orders,
+            timestamp: last_virtual_order_timestamp,
+        }
+    }
+
+    fn fee_model(&self) -> FeeModel {
+        FeeModel::InputBps(self.fee)
+    }
+
+    /// Sets the time quotes execute the virtual orders until, usually the timestamp of the block
+    /// the quote is for. Times before the last execution are ignored.
+    pub fn set_timestamp(&mut self, timestamp: u64) {
+        self.timestamp = timestamp.max(self.last_virtual_order_timestamp);
+    }
+
+    /// Reserves after executing the virtual orders until `timestamp`.
+    pub fn virtual_reserves(&self, timestamp: u64) -> Result<(U256, U256), SimulationError> {
+        let mut state = self.clone();
+        state.execute_virtual_orders(timestamp)?;
+        Ok((state.reserve0, state.reserve1))
+    }
+
+    /// Executes the long-term orders until `timestamp`, interval by interval, so that orders
+    /// expiring in between stop selling at their expiry. Returns the number of intervals crossed.
+    fn execute_virtual_orders(&mut self, timestamp: u64) -> Result<u64, SimulationError> {
+        let mut crossed = 0;
+        let interval = self.order_time_interval;
+        let mut next_expiry = self.last_virtual_order_timestamp -
+            self.last_virtual_order_timestamp % interval +
+            interval;
+        while next_expiry <= timestamp {
+            // no order is left selling, the reserves stay as they are
+            if self.orders.sale_rate0.is_zero() && self.orders.sale_rate1.is_zero() {
+                break;
+            }
+            self.execute_until(next_expiry)?;
+            if let Some((ending0, ending1)) = self
+                .orders
+                .expirations
+                .remove(&next_expiry)
+            {
+                self.orders.sale_rate0 = self
+                    .orders
+                    .sale_rate0
+                    .saturating_sub(ending0);
+                self.orders.sale_rate1 = self
+                    .orders
+                    .sale_rate1
+                    .saturating_sub(ending1);
+            }
+            crossed += 1;
+            next_expiry += interval;
+        }
+        self.execute_until(timestamp)?;
+        Ok(crossed)
+    }
+
+    /// Sells the tokens of the current sale rates from the last execution until `timestamp`.
+    fn execute_until(&mut self, timestamp: u64) -> Result<(), SimulationError> {
+        if timestamp <= self.last_virtual_order_timestamp {
+            return Ok(());
+        }
+        let elapsed = U256::from(timestamp - self.last_virtual_order_timestamp);
+        let precision = U256::from(SELL_RATE_PRECISION);
+        let token0_in = safe_div_u256(safe_mul_u256(self.orders.sale_rate0, elapsed)?, precision)?;
+        let token1_in = safe_div_u256(safe_mul_u256(self.orders.sale_rate1, elapsed)?, precision)?;
+        (self.reserve0, self.reserve1) =
+            virtual_balances(self.reserve0, self.reserve1, token0_in, token1_in, self.fee)?;
+        self.last_virtual_order_timestamp = timestamp;
+        Ok(())
+    }
+
+    /// Parses a `twamm/expirations/{timestamp}/sale_rate{0,1}` attribute key into the expiry and
+    /// whether the rate is of token 0. Returns `None` for other keys.
+    pub(super) fn expiration_key(key: &str) -> Option<Result<(u64, bool), String>> {
+        let rest = key.strip_prefix("twamm/expirations/")?;
+        let (timestamp, rate) = match rest.split_once('/') {
+            Some(parts) => parts,
+            None => return Some(Err(format!("Invalid expiration attribute {key}"))),
+        };
+        let token0 = match rate {
+            "sale_rate0" => true,
+            "sale_rate1" => false,
+            _ => return Some(Err(format!("Invalid expiration attribute {key}"))),
+        };
+        Some(
+            timestamp
+                .parse::<u64>()
+                .map(|timestamp| (timestamp, token0))
+                .map_err(|err| err.to_string()),
+        )
+    }
+
+    /// Sets the sale rate ending at `timestamp`, removing the expiry once both rates are zero.
+    pub(super) fn set_expiration(&mut self, timestamp: u64, token0: bool, rate: U256) {
+        let entry = self
+            .orders
+            .expirations
+            .entry(timestamp)
+            .or_default();
+        if token0 {
+            entry.0 = rate;
+        } else {
+            entry.1 = rate;
+        }
+        if entry.0.is_zero() && entry.1.is_zero() {
+            self.orders
+                .expirations
+                .remove(&timestamp);
+        }
+    }
+}
+
+/// Reserves after selling `token0_in` and `token1_in` against the pool at once.
+///
+/// Mirrors `computeVirtualBalances` of the Fraxswap pair: if only one side sells, it is a regular
+/// swap, otherwise both sides are netted against each other at the price reached after adding
+/// both inputs. Fees stay in the pool.
+fn virtual_balances(
+    reserve0: U256,
+    reserve1: U256,
+    token0_in: U256,
+    token1_in: U256,
+    fee: u32,
+) -> Result<(U256, U256), SimulationError> {
+    if token0_in.is_zero() && token1_in.is_zero() {
+        return Ok((reserve0, reserve1));
+    }
+    let fee = FeeModel::InputBps(fee);
+    if token0_in.is_zero() {
+        let token0_out =
+            fee.constant_product_out(token1_in, reserve1, reserve0, RoundingPolicy::Floor)?;
+        return Ok((safe_sub_u256(reserve0, token0_out)?, safe_add_u256(reserve1, token1_in)?));
+    }
+    if token1_in.is_zero() {
+        let token1_out =
+            fee.constant_product_out(token0_in, reserve0, reserve1, RoundingPolicy::Floor)?;
+        return Ok((safe_add_u256(reserve0, token0_in)?, safe_sub_u256(reserve1, token1_out)?));
+    }
+    let token0_in_after_fee = fee.net_input(token0_in)?;
+    let token1_in_after_fee = fee.net_input(token1_in)?;
+    let k = safe_mul_u256(reserve0, reserve1)?;
+    let end1 = safe_div_u256(
+        safe_mul_u256(reserve0, safe_add_u256(reserve1, token1_in_after_fee)?)?,
+        safe_add_u256(reserve0, token0_in_after_fee)?,
+    )?;
+    let end0 = safe_div_u256(k, end1)?;
+    Ok((
+        safe_add_u256(end0, safe_sub_u256(token0_in, token0_in_after_fee)?)?,
+        safe_add_u256(end1, safe_sub_u256(token1_in, token1_in_after_fee)?)?,
+    ))
+}
+
+impl ProtocolSim for FraxswapState {
+    fn fee(&self) -> f64 {
+        self.fee_model().rate()
+    }
+
+    fn spot_price(&self, base: &Token, quote: &Token) -> Result<f64, SimulationError> {
+        let (reserve0, reserve1) = self.virtual_reserves(self.timestamp)?;
+        if base < quote {
+            Ok(spot_price_from_reserves(
+                reserve0,
+                reserve1,
+                base.decimals as u32,
+                quote.decimals as u32,
+            ))
+        } else {
+            Ok(spot_price_from_reserves(
+                reserve1,
+                reserve0,
+                base.decimals as u32,
+                quote.decimals as u32,
+            ))
+        }
+    }
+
+    fn get_amount_out(
+        &self,
+        amount_in: BigUint,
+        token_in: &Token,
+        token_out: &Token,
+    ) -> Result<GetAmountOutResult, SimulationError> {
+        let amount_in = checked_biguint_to_u256(&amount_in)?;
+        if amount_in == U256::from(0u64) {
+            return Err(SimulationError::InvalidInput("Amount in cannot be zero".to_string(), None));
+        }
+        let mut new_state = self.clone();
+        let crossed = new_state.execute_virtual_orders(self.timestamp)?;
+
+        let zero2one = token_in.address < token_out.address;
+        let reserve_sell = if zero2one { new_state.reserve0 } else { new_state.reserve1 };
+        let reserve_buy = if zero2one { new_state.reserve1 } else { new_state.reserve0 };
+        if reserve_sell == U256::from(0u64) && reserve_buy == U256::from(0u64) {
+            return Err(SimulationError::PoolNotInitialized("No reserves".to_string()));
+        }
+        if reserve_sell == U256::from(0u64) || reserve_buy == U256::from(0u64) {
+            return Err(SimulationError::RecoverableError("No liquidity".to_string()));
+        }
+
+        let amount_out = self.fee_model().constant_product_out(
+            amount_in,
+            reserve_sell,
+            reserve_buy,
+            RoundingPolicy::current(),
+        )?;
+        if zero2one {
+            new_state.reserve0 = safe_add_u256(new_state.reserve0, amount_in)?;
+            new_state.reserve1 = safe_sub_u256(new_state.reserve1, amount_out)?;
+        } else {
+            new_state.reserve0 = safe_sub_u256(new_state.reserve0, amount_out)?;
+            new_state.reserve1 = safe_add_u256(new_state.reserve1, amount_in)?;
+        };
+        // executing the virtual orders of each crossed interval costs extra gas
+        let gas = 140_000u64 + 25_000 * crossed;
+        Ok(GetAmountOutResult::new(
+            u256_to_biguint(amount_out),
+            BigUint::from(gas),
+            Box::new(new_state),
+        ))
+    }
+
+    fn state_fingerprint(&self) -> u64 {
+        fingerprint(self)
+    }
+
+    fn delta_transition(
+        &mut self,
+        delta: ProtocolStateDelta,
+        _tokens: &HashMap<Bytes, Token>,
+        _balances: &Balances,
+    ) -> Result<(), TransitionError<String>> {
+        // the pair emits all attributes an interaction changed: reserves, sale rates and
+        // expirations change together whenever the virtual orders are executed
+        for (key, value) in delta.updated_attributes.iter() {
+            match key.as_str() {
+                "reserve0" => self.reserve0 = U256::from_be_slice(value),
+                "reserve1" => self.reserve1 = U256::from_be_slice(value),
+                "fee" => {
+                    self.fee = checked_fee(u32::from(value.clone()))
+                        .map_err(TransitionError::DecodeError)?
+                }
+                "twamm/last_virtual_order_timestamp" => {
+                    self.last_virtual_order_timestamp =
+                        U256::from_be_slice(value).saturating_to::<u64>()
+                }
+                "twamm/sale_rate0" => self.orders.sale_rate0 = U256::from_be_slice(value),
+                "twamm/sale_rate1" => self.orders.sale_rate1 = U256::from_be_slice(value),
+                _ => {
+                    if let Some(expiration) = Self::expiration_key(key) {
+                        let (timestamp, token0) =
+                            expiration.map_err(TransitionError::DecodeError)?;
+                        self.set_expiration(timestamp, token0, U256::from_be_slice(value));
+                    }
+                }
+            }
+        }
+        for key in delta.deleted_attributes.iter() {
+            if let Some(expiration) = Self::expiration_key(key) {
+                let (timestamp, token0) = expiration.map_err(TransitionError::DecodeError)?;
+                self.set_expiration(timestamp, token0, U256::ZERO);
+            }
+        }
+        self.timestamp = self
+            .timestamp
+            .max(self.last_virtual_order_timestamp);
+        Ok(())
+    }
+
+    fn set_block_timestamp(&mut self, timestamp: u64) {
+        self.set_timestamp(timestamp);
+    }
+
+    fn clone_box(&self) -> Box<dyn ProtocolSim> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn eq(&self, other: &dyn ProtocolSim) -> bool {
+        if let Some(other_state) = other
+            .as_any()
+            .downcast_ref::<FraxswapState>()
+        {
+            self.reserve0 == other_state.reserve0 &&
+                self.reserve1 == other_state.reserve1 &&
+                self.fee == other_state.fee &&
+                self.order_time_interval == other_state.order_time_interval &&
+                self.last_virtual_order_timestamp == other_state.last_virtual_order_timestamp &&
+                self.orders == other_state.orders
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    const START: u64 = 1_700_000_000 - 1_700_000_000 % 3600;
+
+    fn tokens() -> (Token, Token) {
+        let token0 = Token::new(
+            "0x0000000000000000000000000000000000000001",
+            18,
+            "T0",
+            BigUint::from(10_000u32),
+        );
+        let token1 = Token::new(
+            "0x0000000000000000000000000000000000000002",
+            18,
+            "T1",
+            BigUint::from(10_000u32),
+        );
+        (token0, token1)
+    }
+
+    fn pool(orders: OrderPools) -> FraxswapState {
+        let mut state = FraxswapState::new(
+            U256::from(1_000_000_000u64),
+            U256::from(1_000_000_000u64),
+            30,
+            3600,
+            START,
+            orders,
+        );
+        state.set_timestamp(START);
+        state
+    }
+
+    #[test]
+    fn test_get_amount_out_without_orders() {
+        let (token0, token1) = tokens();
+        let mut state = pool(OrderPools::default());
+        state.set_timestamp(START + 7200);
+
+        let res = state
+            .get_amount_out(BigUint::from(1_000_000u64), &token0, &token1)
+            .unwrap();
+
+        // 1_000_000 * 9970 * 1e9 / (1e9 * 10_000 + 1_000_000 * 9970)
+        assert_eq!(res.amount, BigUint::from(996_006u64));
+        assert_eq!(res.gas, BigUint::from(140_000u64));
+    }
+
+    #[test]
+    fn test_long_term_order_moves_reserves() {
+        let (token0, token1) = tokens();
+        // sells 100 token0 per second
+        let orders = OrderPools {
+            sale_rate0: U256::from(100 * SELL_RATE_PRECISION),
+            sale_rate1: U256::ZERO,
+            expirations: BTreeMap::from([(
+                START + 3600,
+                (U256::from(100 * SELL_RATE_PRECISION), U256::ZERO),
+            )]),
+        };
+        let mut state = pool(orders);
+
+        // half way through the order
+        state.set_timestamp(START + 1800);
+        let (reserve0, reserve1) = state
+            .virtual_reserves(START + 1800)
+            .unwrap();
+        assert_eq!(reserve0, U256::from(1_000_180_000u64));
+        assert!(reserve1 < U256::from(1_000_000_000u64));
+        let mid = state
+            .get_amount_out(BigUint::from(1_000_000u64), &token1, &token0)
+            .unwrap();
+
+        // the order expired after one interval and stops selling
+        let (reserve0, _) = state
+            .virtual_reserves(START + 7200)
+            .unwrap();
+        assert_eq!(reserve0, U256::from(1_000_360_000u64));
+        state.set_timestamp(START + 7200);
+        let end = state
+            .get_amount_out(BigUint::from(1_000_000u64), &token1, &token0)
+            .unwrap();
+
+        // token0 got cheaper as the order sold it, a naive constant product quote is off
+        let naive = pool(OrderPools::default())
+            .get_amount_out(BigUint::from(1_000_000u64), &token1, &token0)
+            .unwrap();
+        assert!(naive.amount < mid.amount);
+        assert!(mid.amount < end.amount);
+        assert_eq!(end.gas, BigUint::from(165_000u64));
+
+        let new_state = end
+            .new_state
+            .as_any()
+            .downcast_ref::<FraxswapState>()
+            .unwrap();
+        assert_eq!(new_state.last_virtual_order_timestamp, START + 7200);
+        assert!(new_state.orders.sale_rate0.is_zero());
+        assert!(new_state.orders.expirations.is_empty());
+    }
+
+    #[test]
+    fn test_virtual_balances_both_directions() {
+        let reserve = U256::from(1_000_000_000u64);
+        let amount = U256::from(1_000_000u64);
+
+        let (reserve0, reserve1) = virtual_balances(reserve, reserve, amount, amount, 30).unwrap();
+
+        // opposite orders of the same size cancel out, the pool only keeps the fees
+        assert_eq!(reserve0, U256::from(1_000_003_000u64));
+        assert_eq!(reserve1, U256::from(1_000_003_000u64));
+    }
+
+    #[test]
+    fn test_delta_transition() {
+        let mut state = pool(OrderPools::default());
+        let attributes: HashMap<String, Bytes> = [
+            ("reserve0".to_string(), Bytes::from(1_500_u64.to_be_bytes().to_vec())),
+            (
+                "twamm/last_virtual_order_timestamp".to_string(),
+                Bytes::from((START + 12).to_be_bytes().to_vec()),
+            ),
+            ("twamm/sale_rate1".to_string(), Bytes::from(7_u64.to_be_bytes().to_vec())),
+            (
+                format!("twamm/expirations/{}/sale_rate1", START + 3600),
+                Bytes::from(7_u64.to_be_bytes().to_vec()),
+            ),
+        ]
+        .into_iter()
+        .collect();
+        let delta = ProtocolStateDelta {
+            component_id: "State1".to_owned(),
+            updated_attributes: attributes,
+            deleted_attributes: HashSet::new(),
+        };
+
+        state
+            .delta_transition(delta, &HashMap::new(), &Balances::default())
+            .unwrap();
+
+        assert_eq!(state.reserve0, U256::from(1_500));
+        assert_eq!(state.reserve1, U256::from(1_000_000_000u64));
+        assert_eq!(state.last_virtual_order_timestamp, START + 12);
+        // Quotes don't execute before the last execution
+        assert_eq!(state.timestamp, START + 12);
+        assert_eq!(state.orders.sale_rate1, U256::from(7));
+        assert_eq!(state.orders.expirations[&(START + 3600)], (U256::ZERO, U256::from(7)));
+
+        let delta = ProtocolStateDelta {
+            component_id: "State1".to_owned(),
+            updated_attributes: HashMap::new(),
+            deleted_attributes: HashSet::from([format!(
+                "twamm/expirations/{}/sale_rate1",
+                START + 3600
+            )]),
+        };
+        state
+            .delta_transition(delta, &HashMap::new(), &Balances::default())
+            .unwrap();
+
+        assert!(state.orders.expirations.is_empty());
+    }
+
+    #[test]
+    fn test_block_timestamp() {
+        let mut state = pool(OrderPools::default());
+
+        ProtocolSim::set_block_timestamp(&mut state, START + 600);
+        assert_eq!(state.timestamp, START + 600);
+        ProtocolSim::set_block_timestamp(&mut state, START - 600);
+        assert_eq!(state.timestamp, START);
+    }
+
+    #[test]
+    fn test_delta_transition_invalid_fee() {
+        let mut state = pool(OrderPools::default());
+        let delta = ProtocolStateDelta {
+            component_id: "State1".to_owned(),
+            updated_attributes: HashMap::from([(
+                "fee".to_string(),
+                Bytes::from(10_001_u32.to_be_bytes().to_vec()),
+            )]),
+            deleted_attributes: HashSet::new(),
+        };
+
+        let result = state.delta_transition(delta, &HashMap::new(), &Balances::default());
+
+        assert!(matches!(result, Err(TransitionError::DecodeError(_))));
+        assert_eq!(state.fee, 30);
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+
+use alloy_primitives::U256;
+use tycho_client::feed::{synchronizer::ComponentWithState, Header};
+use tycho_core::Bytes;
+
+use super::state::{checked_fee, FraxswapState, OrderPools};
+use crate::{
+    models::Token,
+    protocol::{errors::InvalidSnapshotError, models::TryFromWithBlock},
+};
+
+/// Fee of pairs that don't report one, in basis points
+const DEFAULT_FEE: u32 = 30;
+/// Order expiry interval of pairs that don't report one, in seconds
+const DEFAULT_ORDER_TIME_INTERVAL: u64 = 3600;
+
+impl TryFromWithBlock<ComponentWithState> for FraxswapState {
+    type Error = InvalidSnapshotError;
+
+    /// Decodes a `ComponentWithState` into a `FraxswapState`. Errors with a `InvalidSnapshotError`
+    /// if the reserves or the time of the last virtual order execution are missing, or the fee
+    /// exceeds 100%.
+    async fn try_from_with_block(
+        snapshot: ComponentWithState,
+        _block: Header,
+        _account_balances: &HashMap<Bytes, HashMap<Bytes, Bytes>>,
+        _all_tokens: &HashMap<Bytes, Token>,
+    ) -> Result<Self, Self::Error> {
+        let attributes = &snapshot.state.attributes;
+        let required = |name: &str| {
+            attributes
+                .get(name)
+                .map(|value| U256::from_be_slice(value))
+                .ok_or_else(|| InvalidSnapshotError::MissingAttribute(name.to_string()))
+        };
+        let optional = |name: &str| {
+            attributes
+                .get(name)
+                .map(|value| U256::from_be_slice(value))
+                .unwrap_or_default()
+        };
+
+        let reserve0 = required("reserve0")?;
+        let reserve1 = required("reserve1")?;
+        let last_virtual_order_timestamp =
+            required("twamm/last_virtual_order_timestamp")?.saturating_to::<u64>();
+        let fee = attributes
+            .get("fee")
+            .map(|fee| checked_fee(u32::from(fee.clone())))
+            .transpose()
+            .map_err(InvalidSnapshotError::ValueError)?
+            .unwrap_or(DEFAULT_FEE);
+        let order_time_interval = snapshot
+            .component
+            .static_attributes
+            .get("order_time_interval")
+            .map(|interval| U256::from_be_slice(interval).saturating_to::<u64>())
+            .unwrap_or(DEFAULT_ORDER_TIME_INTERVAL);
+
+        let orders = OrderPools {
+            sale_rate0: optional("twamm/sale_rate0"),
+            sale_rate1: optional("twamm/sale_rate1"),
+            ..Default::default()
+        };
+        let mut state = FraxswapState::new(
+            reserve0,
+            reserve1,
+            fee,
+            order_time_interval,
+            last_virtual_order_timestamp,
+            orders,
+        );
+        for (key, value) in attributes {
+            if let Some(expiration) = FraxswapState::expiration_key(key) {
+                let (timestamp, token0) = expiration.map_err(InvalidSnapshotError::ValueError)?;
+                state.set_expiration(timestamp, token0, U256::from_be_slice(value));
+            }
+        }
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, str::FromStr};
+
+    use chrono::DateTime;
+    use rstest::rstest;
+    use tycho_core::dto::{Chain, ChangeType, ProtocolComponent, ResponseProtocolState};
+
+    use super::*;
+    use crate::protocol::state::ProtocolSim;
+
+    fn fraxswap_component() -> ProtocolComponent {
+        let creation_time = DateTime::from_timestamp(1622526000, 0)
+            .unwrap()
+            .naive_utc(); //Sample timestamp
+
+        ProtocolComponent {
+            id: "State1".to_string(),
+            protocol_system: "system1".to_string(),
+            protocol_type_name: "typename1".to_string(),
+            chain: Chain::Ethereum,
+            tokens: Vec::new(),
+            contract_ids: Vec::new(),
+            static_attributes: HashMap::new(),
+            change: ChangeType::Creation,
+            creation_tx: Bytes::from_str("0x0000").unwrap(),
+            created_at: creation_time,
+        }
+    }
+
+    fn fraxswap_attributes() -> HashMap<String, Bytes> {
+        vec![
+            ("reserve0".to_string(), Bytes::from(100_u64.to_be_bytes().to_vec())),
+            ("reserve1".to_string(), Bytes::from(200_u64.to_be_bytes().to_vec())),
+            (
+                "twamm/last_virtual_order_timestamp".to_string(),
+                Bytes::from(1_699_999_200_u64.to_be_bytes().to_vec()),
+            ),
+            ("twamm/sale_rate0".to_string(), Bytes::from(5_u64.to_be_bytes().to_vec())),
+            (
+                "twamm/expirations/1700002800/sale_rate0".to_string(),
+                Bytes::from(5_u64.to_be_bytes().to_vec()),
+            ),
+        ]
+        .into_iter()
+        .collect::<HashMap<String, Bytes>>()
+    }
+
+    fn header() -> Header {
+        Header {
+            number: 1,
+            hash: Bytes::from(vec![0; 32]),
+            parent_hash: Bytes::from(vec![0; 32]),
+            revert: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fraxswap_try_from() {
+        let snapshot = ComponentWithState {
+            state: ResponseProtocolState {
+                component_id: "State1".to_owned(),
+                attributes: fraxswap_attributes(),
+                balances: HashMap::new(),
+            },
+            component: fraxswap_component(),
+        };
+
+        let result = FraxswapState::try_from_with_block(
+            snapshot,
+            header(),
+            &HashMap::new(),
+            &HashMap::new(),
+        )
+        .await;
+
+        let expected = FraxswapState::new(
+            U256::from(100),
+            U256::from(200),
+            30,
+            3600,
+            1_699_999_200,
+            OrderPools {
+                sale_rate0: U256::from(5),
+                sale_rate1: U256::ZERO,
+                expirations: BTreeMap::from([(1_700_002_800, (U256::from(5), U256::ZERO))]),
+            },
+        );
+        // compares the pool state, not the time quotes execute at
+        assert!(ProtocolSim::eq(&result.unwrap(), &expected));
+    }
+
+    #[tokio::test]
+    #[rstest]
+    #[case::missing_reserve0("reserve0")]
+    #[case::missing_reserve1("reserve1")]
+    #[case::missing_last_virtual_order_timestamp("twamm/last_virtual_order_timestamp")]
+    async fn test_fraxswap_try_from_invalid(#[case] missing_attribute: String) {
+        let mut attributes = fraxswap_attributes();
+        attributes.remove(&missing_attribute);
+        let snapshot = ComponentWithState {
+            state: ResponseProtocolState {
+                component_id: "State1".to_owned(),
+                attributes,
+                balances: HashMap::new(),
+            },
+            component: fraxswap_component(),
+        };
+
+        let result = FraxswapState::try_from_with_block(
+            snapshot,
+            header(),
+            &HashMap::new(),
+            &HashMap::new(),
+        )
+        .await;
+
+        assert!(matches!(
+            result.err().unwrap(),
+            InvalidSnapshotError::MissingAttribute(attr) if attr == missing_attribute
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_fraxswap_try_from_invalid_fee() {
+        let mut attributes = fraxswap_attributes();
+        attributes.insert("fee".to_string(), Bytes::from(10_001_u32.to_be_bytes().to_vec()));
+        let snapshot = ComponentWithState {
+            state: ResponseProtocolState {
+                component_id: "State1".to_owned(),
+                attributes,
+                balances: HashMap::new(),
+            },
+            component: fraxswap_component(),
+        };
+
+        let result = FraxswapState::try_from_with_block(
+            snapshot,
+            header(),
+            &HashMap::new(),
+            &HashMap::new(),
+        )
+        .await;
+
+        assert!(matches!(result, Err(InvalidSnapshotError::ValueError(_))));
+    }
+}
@@ -0,0 +1,110 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    sync::{Arc, RwLock},
+};
+
+use alloy_primitives::U256;
+use tycho_core::Bytes;
+
+use crate::protocol::errors::SimulationError;
+
+/// A signed off-chain limit order.
+///
+/// The maker offers `maker_amount` of `maker_token` in exchange for `taker_amount` of
+/// `taker_token`. Orders may be partially filled; `filled_taker_amount` tracks how much of the
+/// taker side has already been consumed.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LimitOrder {
+    /// Unique hash of the order
+    pub hash: Bytes,
+    pub maker: Bytes,
+    pub maker_token: Bytes,
+    pub taker_token: Bytes,
+    pub maker_amount: U256,
+    pub taker_amount: U256,
+    /// Amount of the taker side already filled
+    pub filled_taker_amount: U256,
+    /// Unix timestamp after which the order can no longer be filled
+    pub expiry: u64,
+    /// The order signature, forwarded unchanged to the settlement
+    pub signature: Bytes,
+}
+
+impl LimitOrder {
+    /// Returns the taker amount which can still be filled.
+    pub fn remaining_taker_amount(&self) -> U256 {
+        self.taker_amount
+            .saturating_sub(self.filled_taker_amount)
+    }
+
+    /// Returns whether the order can still be filled at the given timestamp.
+    pub fn is_fillable(&self, timestamp: u64) -> bool {
+        self.expiry > timestamp && !self.remaining_taker_amount().is_zero()
+    }
+}
+
+/// A source of signed limit orders.
+///
+/// Implementations may poll an order book API, listen to a websocket or read from a database.
+/// `fetch_orders` is called on every state transition and should return all currently known
+/// orders trading between the two tokens, in either direction.
+pub trait OrderFeed: Debug + Send + Sync {
+    fn fetch_orders(
+        &self,
+        token_a: &Bytes,
+        token_b: &Bytes,
+    ) -> Result<Vec<LimitOrder>, SimulationError>;
+}
+
+/// An order feed backed by an in-memory order set.
+///
+/// Cloned feeds share the same orders, so a feed can be handed to the protocol states while
+/// the orders are added and removed from elsewhere.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryOrderFeed {
+    orders: Arc<RwLock<HashMap<Bytes, LimitOrder>>>,
+}
+
+impl InMemoryOrderFeed {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds or replaces an order, keyed by its hash.
+    pub fn add_order(&self, order: LimitOrder) {
+        self.orders
+            .write()
+            .unwrap()
+            .insert(order.hash.clone(), order);
+    }
+
+    /// Removes an order, e.g. after it was cancelled. Returns the removed order if present.
+    pub fn remove_order(&self, hash: &Bytes) -> Option<LimitOrder> {
+        self.orders
+            .write()
+            .unwrap()
+            .remove(hash)
+    }
+}
+
+impl OrderFeed for InMemoryOrderFeed {
+    fn fetch_orders(
+        &self,
+        token_a: &Bytes,
+        token_b: &Bytes,
+    ) -> Result<Vec<LimitOrder>, SimulationError> {
+        let orders = self
+            .orders
+            .read()
+            .map_err(|_| SimulationError::FatalError("Order feed lock poisoned".to_string()))?;
+        Ok(orders
+            .values()
+            .filter(|order| {
+                (&order.maker_token == token_a && &order.taker_token == token_b) ||
+                    (&order.maker_token == token_b && &order.taker_token == token_a)
+            })
+            .cloned()
+            .collect())
+    }
+}
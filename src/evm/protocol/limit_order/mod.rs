@@ -0,0 +1,6 @@
+//! Limit order book
+//!
+//! Exposes signed off-chain limit orders (e.g. 0x or 1inch limit orders) as a `ProtocolSim`.
+//! Orders are provided by a pluggable `OrderFeed` and filled best price first.
+pub mod feed;
+pub mod state;
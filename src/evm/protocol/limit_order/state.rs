@@ -0,0 +1,425 @@
+use std::{any::Any, cmp::Ordering, collections::HashMap, sync::Arc};
+
+use alloy_primitives::U256;
+use num_bigint::BigUint;
+use tycho_core::{dto::ProtocolStateDelta, Bytes};
+
+use super::feed::{LimitOrder, OrderFeed};
+use crate::{
+    evm::protocol::{
+        safe_math::{safe_add_u256, safe_div_u256, safe_mul_u256},
+        u256_num::{checked_biguint_to_u256, u256_to_biguint, u256_to_f64},
+    },
+    models::{Balances, Token},
+    protocol::{
+        errors::{SimulationError, TransitionError},
+        models::GetAmountOutResult,
+        state::{fingerprint, ProtocolSim},
+    },
+};
+
+/// Gas used by the settlement regardless of the number of orders filled.
+const BASE_GAS: u64 = 50_000;
+/// Additional gas used per filled order (signature check, transfers, fill accounting).
+const GAS_PER_ORDER: u64 = 60_000;
+
+/// State of a limit order book between two tokens.
+///
+/// Swaps are simulated by filling the orders that sell `token_out` for `token_in`, best price
+/// first, until the input amount is consumed. Partially filled orders are carried over to the
+/// `new_state` returned by `get_amount_out`, so consecutive quotes on the same path see the
+/// liquidity consumed by earlier ones.
+///
+/// Orders are refreshed from the `OrderFeed` on every `delta_transition`. Orders which are expired
+/// at `timestamp` are ignored. The stream decoder advances `timestamp` to the time of every block,
+/// see `ProtocolSim::set_block_timestamp`.
+#[derive(Clone, Debug)]
+pub struct LimitOrderState {
+    token_a: Bytes,
+    token_b: Bytes,
+    orders: Vec<LimitOrder>,
+    /// Timestamp used to filter expired orders
+    timestamp: u64,
+    feed: Option<Arc<dyn OrderFeed>>,
+}
+
+impl LimitOrderState {
+    /// Creates a new `LimitOrderState` from a fixed set of orders.
+    ///
+    /// Orders not trading between `token_a` and `token_b` are ignored.
+    pub fn new(token_a: Bytes, token_b: Bytes, orders: Vec<LimitOrder>, timestamp: u64) -> Self {
+        let orders = orders
+            .into_iter()
+            .filter(|order| Self::is_pair_order(&token_a, &token_b, order))
+            .collect();
+        Self { token_a, token_b, orders, timestamp, feed: None }
+    }
+
+    /// Creates a new `LimitOrderState` whose orders are fetched from `feed`.
+    pub fn from_feed(
+        token_a: Bytes,
+        token_b: Bytes,
+        feed: Arc<dyn OrderFeed>,
+        timestamp: u64,
+    ) -> Result<Self, SimulationError> {
+        let orders = feed.fetch_orders(&token_a, &token_b)?;
+        let mut state = Self::new(token_a, token_b, orders, timestamp);
+        state.feed = Some(feed);
+        Ok(state)
+    }
+
+    pub fn orders(&self) -> &[LimitOrder] {
+        &self.orders
+    }
+
+    /// Sets the timestamp used to filter out expired orders.
+    pub fn set_timestamp(&mut self, timestamp: u64) {
+        self.timestamp = timestamp;
+    }
+
+    fn is_pair_order(token_a: &Bytes, token_b: &Bytes, order: &LimitOrder) -> bool {
+        (&order.maker_token == token_a && &order.taker_token == token_b) ||
+            (&order.maker_token == token_b && &order.taker_token == token_a)
+    }
+
+    /// Returns the indices of the fillable orders selling `maker_token` for `taker_token`,
+    /// sorted best price (most maker token per taker token) first. Ties are broken by expiry and
+    /// hash, so fills don't depend on the order the feed returned the orders in.
+    fn sorted_orders(&self, maker_token: &Bytes, taker_token: &Bytes) -> Vec<usize> {
+        let mut indices: Vec<usize> = self
+            .orders
+            .iter()
+            .enumerate()
+            .filter(|(_, order)| {
+                &order.maker_token == maker_token &&
+                    &order.taker_token == taker_token &&
+                    !order.maker_amount.is_zero() &&
+                    order.is_fillable(self.timestamp)
+            })
+            .map(|(idx, _)| idx)
+            .collect();
+        indices.sort_by(|a, b| {
+            let (a, b) = (&self.orders[*a], &self.orders[*b]);
+            compare_orders(a, b)
+                .then_with(|| a.expiry.cmp(&b.expiry))
+                .then_with(|| a.hash.cmp(&b.hash))
+        });
+        indices
+    }
+
+    fn check_tokens(&self, token_in: &Token, token_out: &Token) -> Result<(), SimulationError> {
+        let pair = [&self.token_a, &self.token_b];
+        if token_in.address == token_out.address ||
+            !pair.contains(&&token_in.address) ||
+            !pair.contains(&&token_out.address)
+        {
+            return Err(SimulationError::InvalidInput(
+                format!(
+                    "Order book {} / {} does not support {} -> {}",
+                    self.token_a, self.token_b, token_in.address, token_out.address
+                ),
+                None,
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl ProtocolSim for LimitOrderState {
+    fn fee(&self) -> f64 {
+        0.0
+    }
+
+    fn spot_price(&self, base: &Token, quote: &Token) -> Result<f64, SimulationError> {
+        self.check_tokens(base, quote)?;
+        // The best order selling the base token determines the price a taker pays in quote.
+        let best = self
+            .sorted_orders(&base.address, &quote.address)
+            .first()
+            .map(|idx| &self.orders[*idx])
+            .ok_or_else(|| {
+                SimulationError::RecoverableError(format!(
+                    "No fillable orders selling {} for {}",
+                    base.address, quote.address
+                ))
+            })?;
+        Ok((u256_to_f64(best.taker_amount) / u256_to_f64(quote.try_one()?)) /
+            (u256_to_f64(best.maker_amount) / u256_to_f64(base.try_one()?)))
+    }
+
+    fn get_amount_out(
+        &self,
+        amount_in: BigUint,
+        token_in: &Token,
+        token_out: &Token,
+    ) -> Result<GetAmountOutResult, SimulationError> {
+        self.check_tokens(token_in, token_out)?;
+        let amount_in = checked_biguint_to_u256(&amount_in)?;
+        if amount_in == U256::ZERO {
+            return Err(SimulationError::InvalidInput("Amount in cannot be zero".to_string(), None));
+        }
+
+        let mut new_state = self.clone();
+        let mut remaining = amount_in;
+        let mut amount_out = U256::ZERO;
+        let mut filled_orders = 0u64;
+        for idx in self.sorted_orders(&token_out.address, &token_in.address) {
+            if remaining.is_zero() {
+                break;
+            }
+            let order = &mut new_state.orders[idx];
+            let fill = remaining.min(order.remaining_taker_amount());
+            let maker_fill =
+                safe_div_u256(safe_mul_u256(fill, order.maker_amount)?, order.taker_amount)?;
+            order.filled_taker_amount = safe_add_u256(order.filled_taker_amount, fill)?;
+            amount_out = safe_add_u256(amount_out, maker_fill)?;
+            remaining -= fill;
+            filled_orders += 1;
+        }
+
+        let gas = BigUint::from(BASE_GAS + GAS_PER_ORDER * filled_orders);
+        let result = GetAmountOutResult::new(u256_to_biguint(amount_out), gas, Box::new(new_state));
+        if !remaining.is_zero() {
+            return Err(SimulationError::InvalidInput(
+                format!(
+                    "Not enough order liquidity, {} of the amount in could not be filled",
+                    remaining
+                ),
+                Some(result),
+            ));
+        }
+        Ok(result)
+    }
+
+    fn state_fingerprint(&self) -> u64 {
+        // Orders are kept in the order the feed returned them, which is stable between blocks
+        fingerprint(&(&self.token_a, &self.token_b, &self.orders, self.timestamp))
+    }
+
+    fn delta_transition(
+        &mut self,
+        _delta: ProtocolStateDelta,
+        _tokens: &HashMap<Bytes, Token>,
+        _balances: &Balances,
+    ) -> Result<(), TransitionError<String>> {
+        if let Some(feed) = &self.feed {
+            let orders = feed
+                .fetch_orders(&self.token_a, &self.token_b)
+                .map_err(TransitionError::SimulationError)?;
+            self.orders = orders
+                .into_iter()
+                .filter(|order| Self::is_pair_order(&self.token_a, &self.token_b, order))
+                .collect();
+        }
+        Ok(())
+    }
+
+    fn set_block_timestamp(&mut self, timestamp: u64) {
+        self.set_timestamp(timestamp);
+    }
+
+    fn clone_box(&self) -> Box<dyn ProtocolSim> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn eq(&self, other: &dyn ProtocolSim) -> bool {
+        if let Some(other_state) = other
+            .as_any()
+            .downcast_ref::<LimitOrderState>()
+        {
+            self.token_a == other_state.token_a &&
+                self.token_b == other_state.token_b &&
+                self.orders.len() == other_state.orders.len() &&
+                self.orders
+                    .iter()
+                    .all(|order| other_state.orders.contains(order))
+        } else {
+            false
+        }
+    }
+}
+
+impl PartialEq for LimitOrderState {
+    fn eq(&self, other: &Self) -> bool {
+        ProtocolSim::eq(self, other)
+    }
+}
+
+/// Orders two limit orders by the price offered to a taker, best first.
+///
+/// Compares `a.maker / a.taker` against `b.maker / b.taker` without division.
+fn compare_orders(a: &LimitOrder, b: &LimitOrder) -> Ordering {
+    let lhs = a
+        .maker_amount
+        .saturating_mul(b.taker_amount);
+    let rhs = b
+        .maker_amount
+        .saturating_mul(a.taker_amount);
+    rhs.cmp(&lhs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use num_bigint::ToBigUint;
+
+    use super::*;
+    use crate::evm::protocol::limit_order::feed::InMemoryOrderFeed;
+
+    fn weth() -> Token {
+        Token::new(
+            "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2",
+            18,
+            "WETH",
+            10_000.to_biguint().unwrap(),
+        )
+    }
+
+    fn usdc() -> Token {
+        Token::new(
+            "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48",
+            6,
+            "USDC",
+            10_000.to_biguint().unwrap(),
+        )
+    }
+
+    fn order(hash: u8, maker_amount: u64, taker_amount: u64, expiry: u64) -> LimitOrder {
+        // Sells WETH for USDC
+        LimitOrder {
+            hash: Bytes::from(vec![hash]),
+            maker: Bytes::from_str("0x0000000000000000000000000000000000000001").unwrap(),
+            maker_token: weth().address,
+            taker_token: usdc().address,
+            maker_amount: U256::from(maker_amount),
+            taker_amount: U256::from(taker_amount),
+            filled_taker_amount: U256::ZERO,
+            expiry,
+            signature: Bytes::from(vec![0; 65]),
+        }
+    }
+
+    fn state(orders: Vec<LimitOrder>) -> LimitOrderState {
+        LimitOrderState::new(weth().address, usdc().address, orders, 100)
+    }
+
+    #[test]
+    fn test_get_amount_out_fills_best_price_first() {
+        let state = state(vec![order(1, 100, 200, 1000), order(2, 100, 100, 1000)]);
+
+        let res = state
+            .get_amount_out(BigUint::from(150u64), &usdc(), &weth())
+            .unwrap();
+
+        // 100 USDC at 1:1 from order 2, then 50 USDC at 2:1 from order 1
+        assert_eq!(res.amount, BigUint::from(125u64));
+        assert_eq!(res.gas, BigUint::from(BASE_GAS + 2 * GAS_PER_ORDER));
+        let new_state = res
+            .new_state
+            .as_any()
+            .downcast_ref::<LimitOrderState>()
+            .unwrap();
+        assert_eq!(new_state.orders()[0].filled_taker_amount, U256::from(50));
+        assert_eq!(new_state.orders()[1].filled_taker_amount, U256::from(100));
+    }
+
+    #[test]
+    fn test_get_amount_out_skips_expired() {
+        let state = state(vec![order(1, 100, 200, 1000), order(2, 100, 100, 50)]);
+
+        let res = state
+            .get_amount_out(BigUint::from(100u64), &usdc(), &weth())
+            .unwrap();
+
+        assert_eq!(res.amount, BigUint::from(50u64));
+    }
+
+    #[test]
+    fn test_get_amount_out_insufficient_liquidity() {
+        let state = state(vec![order(1, 100, 200, 1000)]);
+
+        let res = state.get_amount_out(BigUint::from(300u64), &usdc(), &weth());
+
+        match res {
+            Err(SimulationError::InvalidInput(_, Some(partial))) => {
+                assert_eq!(partial.amount, BigUint::from(100u64));
+            }
+            _ => panic!("Expected InvalidInput with partial result"),
+        }
+    }
+
+    #[test]
+    fn test_spot_price() {
+        // 1 WETH for 2000 USDC
+        let mut best = order(1, 0, 0, 1000);
+        best.maker_amount = U256::from(10).pow(U256::from(18));
+        best.taker_amount = U256::from(2_000_000_000u64);
+        let mut worse = best.clone();
+        worse.hash = Bytes::from(vec![2]);
+        worse.taker_amount = U256::from(2_100_000_000u64);
+        let state = state(vec![worse, best]);
+
+        let price = state
+            .spot_price(&weth(), &usdc())
+            .unwrap();
+
+        assert_eq!(price, 2000.0);
+        assert!(matches!(
+            state.spot_price(&usdc(), &weth()),
+            Err(SimulationError::RecoverableError(_))
+        ));
+    }
+
+    #[test]
+    fn test_delta_transition_refreshes_from_feed() {
+        let feed = InMemoryOrderFeed::new();
+        feed.add_order(order(1, 100, 200, 1000));
+        let mut state =
+            LimitOrderState::from_feed(weth().address, usdc().address, Arc::new(feed.clone()), 100)
+                .unwrap();
+        assert_eq!(state.orders().len(), 1);
+
+        feed.add_order(order(2, 100, 100, 1000));
+        feed.remove_order(&Bytes::from(vec![1]));
+        state
+            .delta_transition(ProtocolStateDelta::default(), &HashMap::new(), &Balances::default())
+            .unwrap();
+
+        assert_eq!(state.orders().len(), 1);
+        assert_eq!(state.orders()[0].hash, Bytes::from(vec![2]));
+    }
+
+    #[test]
+    fn test_state_fingerprint() {
+        let state = state(vec![order(1, 100, 200, 1000)]);
+
+        let res = state
+            .get_amount_out(BigUint::from(100u64), &usdc(), &weth())
+            .unwrap();
+
+        assert_eq!(state.state_fingerprint(), state.clone().state_fingerprint());
+        assert_ne!(res.new_state.state_fingerprint(), state.state_fingerprint());
+    }
+
+    #[test]
+    fn test_set_block_timestamp_expires_orders() {
+        let mut state = state(vec![order(1, 100, 200, 1000), order(2, 100, 100, 500)]);
+
+        state.set_block_timestamp(600);
+
+        // Order 2 expired, only order 1 at 2:1 is filled
+        let res = state
+            .get_amount_out(BigUint::from(100u64), &usdc(), &weth())
+            .unwrap();
+        assert_eq!(res.amount, BigUint::from(50u64));
+    }
+}
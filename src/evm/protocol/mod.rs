@@ -1,4 +1,12 @@
+pub mod algebra;
+pub mod callback;
+pub mod erc4626;
+pub mod fee;
 pub mod filters;
+pub mod fraxswap;
+pub mod limit_order;
+pub mod money_market;
+pub mod rfq;
 pub mod safe_math;
 pub mod u256_num;
 pub mod uniswap_v2;
@@ -0,0 +1,6 @@
+//! Money market wrappers
+//!
+//! Models supplying to and withdrawing from lending markets (Aave V3 aTokens, Compound V2
+//! cTokens) as conversion edges between the underlying token and its interest bearing wrapper.
+pub mod state;
+mod tycho_decoder;
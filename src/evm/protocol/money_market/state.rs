@@ -0,0 +1,627 @@
+use std::{
+    any::Any,
+    collections::HashMap,
+    fmt::Debug,
+    sync::{Arc, RwLock},
+};
+
+use alloy_primitives::{Address, U256};
+use alloy_sol_types::SolValue;
+use num_bigint::BigUint;
+use revm::DatabaseRef;
+use tycho_core::{dto::ProtocolStateDelta, Bytes};
+
+use crate::{
+    evm::{
+        engine_db::{
+            engine_db_interface::EngineDatabaseInterface, simulation_db::BlockHeader,
+            tycho_db::PreCachedDB,
+        },
+        protocol::{
+            safe_math::{safe_add_u256, safe_div_u256, safe_mul_u256, safe_sub_u256},
+            u256_num::{checked_biguint_to_u256, u256_to_biguint, u256_to_f64},
+            utils::bytes_to_address,
+            vm::tycho_simulation_contract::TychoSimulationContract,
+        },
+        simulation::SimulationEngine,
+    },
+    models::{Balances, Token},
+    protocol::{
+        errors::{SimulationError, TransitionError},
+        models::GetAmountOutResult,
+        state::{fingerprint, ProtocolSim},
+    },
+};
+
+/// Aave's fixed point unit for indexes (27 decimals).
+const RAY: U256 = U256::from_limbs([0x9fd0803ce8000000, 0x33b2e3c, 0, 0]);
+/// Compound's fixed point unit for exchange rates (18 decimals).
+const WAD: U256 = U256::from_limbs([1_000_000_000_000_000_000, 0, 0, 0]);
+
+// Aave V3 reserve configuration bitmap layout
+const AAVE_DECIMALS_START_BIT: usize = 48;
+const AAVE_ACTIVE_BIT: usize = 56;
+const AAVE_FROZEN_BIT: usize = 57;
+const AAVE_PAUSED_BIT: usize = 60;
+const AAVE_SUPPLY_CAP_START_BIT: usize = 116;
+
+// Approximate gas costs of the wrapping operations. The view calls simulated to obtain the
+// limits are not representative of the actual supply/withdraw costs.
+const AAVE_V3_SUPPLY_GAS: u64 = 180_000;
+const AAVE_V3_WITHDRAW_GAS: u64 = 160_000;
+const COMPOUND_V2_MINT_GAS: u64 = 150_000;
+const COMPOUND_V2_REDEEM_GAS: u64 = 130_000;
+
+/// Return type of Aave V3 `Pool.getReserveData(address)`. All integers are decoded as `U256`.
+///
+/// (configuration, liquidityIndex, currentLiquidityRate, variableBorrowIndex,
+/// currentVariableBorrowRate, currentStableBorrowRate, lastUpdateTimestamp, id, aTokenAddress,
+/// stableDebtTokenAddress, variableDebtTokenAddress, interestRateStrategyAddress,
+/// accruedToTreasury, unbacked, isolationModeTotalDebt)
+type AaveReserveDataReturn = (
+    U256,
+    U256,
+    U256,
+    U256,
+    U256,
+    U256,
+    U256,
+    U256,
+    Address,
+    Address,
+    Address,
+    Address,
+    U256,
+    U256,
+    U256,
+);
+type U256Return = U256;
+
+/// The lending market a wrapper token belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoneyMarket {
+    /// Aave V3. Wrapper tokens (aTokens) are minted 1:1 for the supplied amount.
+    AaveV3 {
+        /// Address of the Aave V3 `Pool` contract
+        pool: Address,
+    },
+    /// Compound V2 and forks. Wrapper tokens (cTokens) are minted at the current exchange rate.
+    CompoundV2,
+}
+
+/// Direction of a conversion between the underlying token and the wrapper.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarketOperation {
+    /// Underlying in, wrapper out.
+    Supply,
+    /// Wrapper in, underlying out.
+    Withdraw,
+}
+
+/// Reserve limits obtained from the market at the current block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReserveLimits {
+    /// Amount of underlying received per 1e18 wrapper tokens.
+    pub exchange_rate: U256,
+    /// Amount of underlying that can currently be withdrawn from the market.
+    pub available_liquidity: U256,
+    /// Amount of underlying that can still be supplied before hitting the supply cap, `None` if
+    /// the market has no cap.
+    pub supply_headroom: Option<U256>,
+    /// Whether the reserve currently accepts supplies and withdrawals.
+    pub active: bool,
+}
+
+/// State of a money market wrapper, e.g. aUSDC <-> USDC on Aave V3 or cUSDC <-> USDC on
+/// Compound V2.
+///
+/// The reserve limits (exchange rate, withdrawable liquidity, supply cap headroom) are obtained by
+/// simulating view calls on the market contracts and cached until the next `delta_transition`.
+/// Quotes exceeding the limits return a `SimulationError::InvalidInput` holding the result for the
+/// largest amount that can be converted.
+#[derive(Clone, Debug)]
+pub struct MoneyMarketState<D: EngineDatabaseInterface + Clone + Debug>
+where
+    <D as DatabaseRef>::Error: Debug,
+    <D as EngineDatabaseInterface>::Error: Debug,
+{
+    /// The lending market
+    market: MoneyMarket,
+    /// The interest bearing wrapper token (aToken/cToken)
+    wrapper: Address,
+    /// The underlying token
+    underlying: Address,
+    /// The current block, will be used to set vm context
+    block: BlockHeader,
+    /// Reserve limits of the current block
+    limits: Arc<RwLock<Option<ReserveLimits>>>,
+    /// The engine used to simulate the market view calls
+    engine: SimulationEngine<D>,
+    /// Number of deltas applied, stands in for the market storage in the state fingerprint
+    revision: u64,
+}
+
+impl<D> MoneyMarketState<D>
+where
+    D: EngineDatabaseInterface + Clone + Debug + 'static,
+    <D as DatabaseRef>::Error: Debug,
+    <D as EngineDatabaseInterface>::Error: Debug,
+{
+    /// Creates a new `MoneyMarketState`.
+    ///
+    /// # Arguments
+    ///
+    /// * `market` - The lending market the wrapper belongs to.
+    /// * `wrapper` - Address of the interest bearing wrapper token.
+    /// * `underlying` - Address of the underlying token.
+    /// * `block` - The current block, used to set the vm context.
+    /// * `engine` - The engine used to simulate the market view calls.
+    pub fn new(
+        market: MoneyMarket,
+        wrapper: Address,
+        underlying: Address,
+        block: BlockHeader,
+        engine: SimulationEngine<D>,
+    ) -> Self {
+        Self {
+            market,
+            wrapper,
+            underlying,
+            block,
+            limits: Arc::new(RwLock::new(None)),
+            engine,
+            revision: 0,
+        }
+    }
+
+    pub fn market(&self) -> MoneyMarket {
+        self.market
+    }
+
+    pub fn wrapper(&self) -> Address {
+        self.wrapper
+    }
+
+    pub fn underlying(&self) -> Address {
+        self.underlying
+    }
+
+    /// Determines the market operation needed to swap `token_in` for `token_out`.
+    pub fn operation(
+        &self,
+        token_in: &Token,
+        token_out: &Token,
+    ) -> Result<MarketOperation, SimulationError> {
+        let token_in = bytes_to_address(&token_in.address)?;
+        let token_out = bytes_to_address(&token_out.address)?;
+        if token_in == self.underlying && token_out == self.wrapper {
+            Ok(MarketOperation::Supply)
+        } else if token_in == self.wrapper && token_out == self.underlying {
+            Ok(MarketOperation::Withdraw)
+        } else {
+            Err(SimulationError::InvalidInput(
+                format!(
+                    "Wrapper {} only supports swaps between {} and itself, got {} -> {}",
+                    self.wrapper, self.underlying, token_in, token_out
+                ),
+                None,
+            ))
+        }
+    }
+
+    /// Returns the reserve limits at the current block, simulating the market view calls if they
+    /// are not cached yet.
+    pub fn reserve_limits(&self) -> Result<ReserveLimits, SimulationError> {
+        if let Some(limits) = self
+            .limits
+            .read()
+            .map_err(|_| SimulationError::FatalError("Reserve limits lock poisoned".to_string()))?
+            .as_ref()
+        {
+            return Ok(limits.clone());
+        }
+
+        let limits = match self.market {
+            MoneyMarket::AaveV3 { pool } => self.aave_v3_limits(pool)?,
+            MoneyMarket::CompoundV2 => self.compound_v2_limits()?,
+        };
+
+        *self.limits.write().map_err(|_| {
+            SimulationError::FatalError("Reserve limits lock poisoned".to_string())
+        })? = Some(limits.clone());
+        Ok(limits)
+    }
+
+    fn aave_v3_limits(&self, pool: Address) -> Result<ReserveLimits, SimulationError> {
+        let res = self.call(pool, "getReserveData(address)", self.underlying)?;
+        let reserve = AaveReserveDataReturn::abi_decode(&res, true).map_err(|e| {
+            SimulationError::FatalError(format!(
+                "Aave getReserveData call failed: Failed to decode return value: {:?}",
+                e
+            ))
+        })?;
+        let (configuration, liquidity_index, accrued_to_treasury) =
+            (reserve.0, reserve.1, reserve.12);
+
+        let available_liquidity = self.decode_u256(
+            self.call(self.underlying, "balanceOf(address)", self.wrapper)?,
+            "balanceOf",
+        )?;
+
+        let decimals = config_bits(configuration, AAVE_DECIMALS_START_BIT, 8);
+        let supply_cap = config_bits(configuration, AAVE_SUPPLY_CAP_START_BIT, 36);
+        let supply_headroom = if supply_cap.is_zero() {
+            None
+        } else {
+            let cap = safe_mul_u256(supply_cap, U256::from(10).pow(decimals))?;
+            let total_supply =
+                self.decode_u256(self.call(self.wrapper, "totalSupply()", ())?, "totalSupply")?;
+            let accrued = safe_div_u256(safe_mul_u256(accrued_to_treasury, liquidity_index)?, RAY)?;
+            Some(cap.saturating_sub(safe_add_u256(total_supply, accrued)?))
+        };
+
+        let active = configuration.bit(AAVE_ACTIVE_BIT) &&
+            !configuration.bit(AAVE_FROZEN_BIT) &&
+            !configuration.bit(AAVE_PAUSED_BIT);
+
+        Ok(ReserveLimits { exchange_rate: WAD, available_liquidity, supply_headroom, active })
+    }
+
+    fn compound_v2_limits(&self) -> Result<ReserveLimits, SimulationError> {
+        let exchange_rate = self.decode_u256(
+            self.call(self.wrapper, "exchangeRateStored()", ())?,
+            "exchangeRateStored",
+        )?;
+        let available_liquidity =
+            self.decode_u256(self.call(self.wrapper, "getCash()", ())?, "getCash")?;
+
+        Ok(ReserveLimits {
+            exchange_rate,
+            available_liquidity,
+            supply_headroom: None,
+            active: !exchange_rate.is_zero(),
+        })
+    }
+
+    fn call(
+        &self,
+        to: Address,
+        selector: &str,
+        args: impl SolValue,
+    ) -> Result<Vec<u8>, SimulationError> {
+        let contract = TychoSimulationContract::new(to, self.engine.clone())?;
+        let res = contract.call(selector, args, &self.block, None, None, U256::ZERO)?;
+        Ok(res.return_value)
+    }
+
+    fn decode_u256(&self, res: Vec<u8>, name: &str) -> Result<U256, SimulationError> {
+        U256Return::abi_decode(&res, true).map_err(|e| {
+            SimulationError::FatalError(format!(
+                "{} call failed: Failed to decode return value: {:?}",
+                name, e
+            ))
+        })
+    }
+
+    /// Converts `amount` for the given operation using the reserve's exchange rate.
+    fn convert(
+        &self,
+        operation: MarketOperation,
+        amount: U256,
+        limits: &ReserveLimits,
+    ) -> Result<U256, SimulationError> {
+        match operation {
+            MarketOperation::Supply => {
+                safe_div_u256(safe_mul_u256(amount, WAD)?, limits.exchange_rate)
+            }
+            MarketOperation::Withdraw => {
+                safe_div_u256(safe_mul_u256(amount, limits.exchange_rate)?, WAD)
+            }
+        }
+    }
+
+    fn gas(&self, operation: MarketOperation) -> u64 {
+        match (self.market, operation) {
+            (MoneyMarket::AaveV3 { .. }, MarketOperation::Supply) => AAVE_V3_SUPPLY_GAS,
+            (MoneyMarket::AaveV3 { .. }, MarketOperation::Withdraw) => AAVE_V3_WITHDRAW_GAS,
+            (MoneyMarket::CompoundV2, MarketOperation::Supply) => COMPOUND_V2_MINT_GAS,
+            (MoneyMarket::CompoundV2, MarketOperation::Withdraw) => COMPOUND_V2_REDEEM_GAS,
+        }
+    }
+
+    /// Returns a copy of this state with the limits adjusted for a conversion of
+    /// `underlying_amount`.
+    fn with_applied(
+        &self,
+        operation: MarketOperation,
+        underlying_amount: U256,
+        limits: &ReserveLimits,
+    ) -> Result<Self, SimulationError> {
+        let mut new_limits = limits.clone();
+        match operation {
+            MarketOperation::Supply => {
+                new_limits.available_liquidity =
+                    safe_add_u256(new_limits.available_liquidity, underlying_amount)?;
+                new_limits.supply_headroom = new_limits
+                    .supply_headroom
+                    .map(|headroom| headroom.saturating_sub(underlying_amount));
+            }
+            MarketOperation::Withdraw => {
+                new_limits.available_liquidity =
+                    safe_sub_u256(new_limits.available_liquidity, underlying_amount)?;
+                new_limits.supply_headroom = new_limits
+                    .supply_headroom
+                    .map(|headroom| headroom.saturating_add(underlying_amount));
+            }
+        }
+        let mut new_state = self.clone();
+        new_state.limits = Arc::new(RwLock::new(Some(new_limits)));
+        Ok(new_state)
+    }
+}
+
+/// Extracts `len` bits starting at `start` from a configuration bitmap.
+fn config_bits(configuration: U256, start: usize, len: usize) -> U256 {
+    (configuration >> start) & ((U256::from(1) << len) - U256::from(1))
+}
+
+impl<D> ProtocolSim for MoneyMarketState<D>
+where
+    D: EngineDatabaseInterface + Clone + Debug + 'static,
+    <D as DatabaseRef>::Error: Debug,
+    <D as EngineDatabaseInterface>::Error: Debug,
+{
+    fn fee(&self) -> f64 {
+        0.0
+    }
+
+    fn spot_price(&self, base: &Token, quote: &Token) -> Result<f64, SimulationError> {
+        let operation = self.operation(base, quote)?;
+        let limits = self.reserve_limits()?;
+        let amount_out = self.convert(operation, base.try_one()?, &limits)?;
+        Ok(u256_to_f64(amount_out) / u256_to_f64(quote.try_one()?))
+    }
+
+    fn get_amount_out(
+        &self,
+        amount_in: BigUint,
+        token_in: &Token,
+        token_out: &Token,
+    ) -> Result<GetAmountOutResult, SimulationError> {
+        let amount_in = checked_biguint_to_u256(&amount_in)?;
+        if amount_in == U256::ZERO {
+            return Err(SimulationError::InvalidInput("Amount in cannot be zero".to_string(), None));
+        }
+        let operation = self.operation(token_in, token_out)?;
+        let limits = self.reserve_limits()?;
+        if !limits.active {
+            return Err(SimulationError::RecoverableError(format!(
+                "Reserve {} is not active",
+                self.wrapper
+            )));
+        }
+        let gas = BigUint::from(self.gas(operation));
+
+        let (underlying_amount, limit) = match operation {
+            MarketOperation::Supply => (amount_in, limits.supply_headroom),
+            MarketOperation::Withdraw => {
+                (self.convert(operation, amount_in, &limits)?, Some(limits.available_liquidity))
+            }
+        };
+
+        if let Some(limit) = limit.filter(|limit| underlying_amount > *limit) {
+            let amount_out = match operation {
+                MarketOperation::Supply => self.convert(operation, limit, &limits)?,
+                MarketOperation::Withdraw => limit,
+            };
+            return Err(SimulationError::InvalidInput(
+                format!(
+                    "Amount exceeds the reserve limit of {} (underlying {})",
+                    limit, self.underlying
+                ),
+                Some(GetAmountOutResult::new(
+                    u256_to_biguint(amount_out),
+                    gas,
+                    Box::new(self.with_applied(operation, limit, &limits)?),
+                )),
+            ));
+        }
+
+        let amount_out = match operation {
+            MarketOperation::Supply => self.convert(operation, amount_in, &limits)?,
+            MarketOperation::Withdraw => underlying_amount,
+        };
+        Ok(GetAmountOutResult::new(
+            u256_to_biguint(amount_out),
+            gas,
+            Box::new(self.with_applied(operation, underlying_amount, &limits)?),
+        ))
+    }
+
+    fn is_blocking(&self) -> bool {
+        true
+    }
+
+    fn state_fingerprint(&self) -> u64 {
+        fingerprint(&(self.wrapper, self.revision))
+    }
+
+    fn delta_transition(
+        &mut self,
+        _delta: ProtocolStateDelta,
+        _tokens: &HashMap<Bytes, Token>,
+        _balances: &Balances,
+    ) -> Result<(), TransitionError<String>> {
+        // The market storage is kept up to date in the engine's database, we only need to drop
+        // the limits computed on the previous block.
+        self.limits = Arc::new(RwLock::new(None));
+        self.revision += 1;
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn ProtocolSim> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn eq(&self, other: &dyn ProtocolSim) -> bool {
+        if let Some(other_state) = other
+            .as_any()
+            .downcast_ref::<MoneyMarketState<PreCachedDB>>()
+        {
+            self.market == other_state.market &&
+                self.wrapper == other_state.wrapper &&
+                self.underlying == other_state.underlying
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use num_bigint::ToBigUint;
+
+    use super::*;
+    use crate::evm::engine_db::create_engine;
+
+    fn ausdc() -> Token {
+        Token::new(
+            "0x98c23e9d8f34fefb1b7bd6a91b7ff122f4e16f5c",
+            6,
+            "aEthUSDC",
+            10_000.to_biguint().unwrap(),
+        )
+    }
+
+    fn usdc() -> Token {
+        Token::new(
+            "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48",
+            6,
+            "USDC",
+            10_000.to_biguint().unwrap(),
+        )
+    }
+
+    fn market_state(limits: ReserveLimits) -> MoneyMarketState<PreCachedDB> {
+        let engine = create_engine(PreCachedDB::new().unwrap(), false).unwrap();
+        let state = MoneyMarketState::new(
+            MoneyMarket::AaveV3 {
+                pool: Address::from_str("0x87870bca3f3fd6335c3f4ce8392d69350b4fa4e2").unwrap(),
+            },
+            Address::from_str("0x98c23e9d8f34fefb1b7bd6a91b7ff122f4e16f5c").unwrap(),
+            Address::from_str("0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48").unwrap(),
+            BlockHeader::default(),
+            engine,
+        );
+        *state.limits.write().unwrap() = Some(limits);
+        state
+    }
+
+    fn limits() -> ReserveLimits {
+        ReserveLimits {
+            exchange_rate: WAD,
+            available_liquidity: U256::from(1_000_000),
+            supply_headroom: Some(U256::from(500_000)),
+            active: true,
+        }
+    }
+
+    #[test]
+    fn test_config_bits() {
+        let configuration = (U256::from(1_000) << AAVE_SUPPLY_CAP_START_BIT) |
+            (U256::from(6) << AAVE_DECIMALS_START_BIT) |
+            (U256::from(1) << AAVE_ACTIVE_BIT);
+
+        assert_eq!(config_bits(configuration, AAVE_SUPPLY_CAP_START_BIT, 36), U256::from(1_000));
+        assert_eq!(config_bits(configuration, AAVE_DECIMALS_START_BIT, 8), U256::from(6));
+        assert!(configuration.bit(AAVE_ACTIVE_BIT));
+    }
+
+    #[test]
+    fn test_get_amount_out_supply() {
+        let state = market_state(limits());
+
+        let res = state
+            .get_amount_out(BigUint::from(1_000u64), &usdc(), &ausdc())
+            .unwrap();
+
+        assert_eq!(res.amount, BigUint::from(1_000u64));
+        let new_state = res
+            .new_state
+            .as_any()
+            .downcast_ref::<MoneyMarketState<PreCachedDB>>()
+            .unwrap();
+        assert_eq!(
+            new_state.reserve_limits().unwrap(),
+            ReserveLimits {
+                available_liquidity: U256::from(1_001_000),
+                supply_headroom: Some(U256::from(499_000)),
+                ..limits()
+            }
+        );
+    }
+
+    #[test]
+    fn test_get_amount_out_exceeds_liquidity() {
+        let state = market_state(limits());
+
+        let res = state.get_amount_out(BigUint::from(2_000_000u64), &ausdc(), &usdc());
+
+        match res {
+            Err(SimulationError::InvalidInput(_, Some(partial))) => {
+                assert_eq!(partial.amount, BigUint::from(1_000_000u64));
+            }
+            _ => panic!("Expected InvalidInput with partial result"),
+        }
+    }
+
+    #[test]
+    fn test_get_amount_out_exceeds_supply_cap() {
+        let state = market_state(limits());
+
+        let res = state.get_amount_out(BigUint::from(600_000u64), &usdc(), &ausdc());
+
+        match res {
+            Err(SimulationError::InvalidInput(_, Some(partial))) => {
+                assert_eq!(partial.amount, BigUint::from(500_000u64));
+            }
+            _ => panic!("Expected InvalidInput with partial result"),
+        }
+    }
+
+    #[test]
+    fn test_compound_exchange_rate() {
+        let mut state = market_state(ReserveLimits {
+            // 0.02 underlying per cToken, scaled by 1e18
+            exchange_rate: U256::from(20_000_000_000_000_000u64),
+            supply_headroom: None,
+            ..limits()
+        });
+        state.market = MoneyMarket::CompoundV2;
+
+        let res = state
+            .get_amount_out(BigUint::from(1_000u64), &usdc(), &ausdc())
+            .unwrap();
+
+        assert_eq!(res.amount, BigUint::from(50_000u64));
+    }
+
+    #[test]
+    fn test_inactive_reserve() {
+        let state = market_state(ReserveLimits { active: false, ..limits() });
+
+        let res = state.get_amount_out(BigUint::from(1_000u64), &usdc(), &ausdc());
+
+        assert!(matches!(res, Err(SimulationError::RecoverableError(_))));
+    }
+}
@@ -0,0 +1,174 @@
+use std::{collections::HashMap, str::FromStr};
+
+use alloy_primitives::Address;
+use tycho_client::feed::{synchronizer::ComponentWithState, Header};
+use tycho_core::Bytes;
+
+use super::state::{MoneyMarket, MoneyMarketState};
+use crate::{
+    evm::engine_db::{
+        create_engine, simulation_db::BlockHeader, tycho_db::PreCachedDB, SHARED_TYCHO_DB,
+    },
+    models::Token,
+    protocol::{errors::InvalidSnapshotError, models::TryFromWithBlock},
+};
+
+fn attribute_to_address(value: &Bytes, name: &str) -> Result<Address, InvalidSnapshotError> {
+    if value.len() != 20 {
+        return Err(InvalidSnapshotError::ValueError(format!(
+            "Attribute {} with value {} is not an address",
+            name, value
+        )));
+    }
+    Ok(Address::from_slice(value))
+}
+
+impl TryFromWithBlock<ComponentWithState> for MoneyMarketState<PreCachedDB> {
+    type Error = InvalidSnapshotError;
+
+    /// Decodes a `ComponentWithState` into a `MoneyMarketState`.
+    ///
+    /// The component id is expected to be the wrapper token address. The static attributes must
+    /// contain the `underlying` token and the `market` ("aave_v3" or "compound_v2"); Aave V3
+    /// markets additionally require the `pool` address. The market contracts must be present in
+    /// the shared tycho db.
+    async fn try_from_with_block(
+        snapshot: ComponentWithState,
+        block: Header,
+        _account_balances: &HashMap<Bytes, HashMap<Bytes, Bytes>>,
+        _all_tokens: &HashMap<Bytes, Token>,
+    ) -> Result<Self, Self::Error> {
+        let wrapper_bytes = Bytes::from_str(&snapshot.component.id)
+            .map_err(|e| InvalidSnapshotError::ValueError(e.to_string()))?;
+        let wrapper = attribute_to_address(&wrapper_bytes, "id")?;
+
+        let static_attributes = &snapshot.component.static_attributes;
+        let get_attribute = |name: &str| {
+            static_attributes
+                .get(name)
+                .ok_or_else(|| InvalidSnapshotError::MissingAttribute(name.to_string()))
+        };
+
+        let underlying = attribute_to_address(get_attribute("underlying")?, "underlying")?;
+        let market_name = String::from_utf8(get_attribute("market")?.to_vec())
+            .map_err(|e| InvalidSnapshotError::ValueError(e.to_string()))?;
+        let market = match market_name.as_str() {
+            "aave_v3" => {
+                MoneyMarket::AaveV3 { pool: attribute_to_address(get_attribute("pool")?, "pool")? }
+            }
+            "compound_v2" => MoneyMarket::CompoundV2,
+            other => {
+                return Err(InvalidSnapshotError::ValueError(format!(
+                    "Unsupported money market: {}",
+                    other
+                )))
+            }
+        };
+
+        let engine = create_engine(SHARED_TYCHO_DB.clone(), false)?;
+
+        Ok(MoneyMarketState::new(market, wrapper, underlying, BlockHeader::from(block), engine))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+    use tycho_core::dto::{Chain, ChangeType, ProtocolComponent, ResponseProtocolState};
+
+    use super::*;
+
+    fn snapshot(static_attributes: HashMap<String, Bytes>) -> ComponentWithState {
+        let creation_time = DateTime::from_timestamp(1622526000, 0)
+            .unwrap()
+            .naive_utc(); //Sample timestamp
+
+        ComponentWithState {
+            state: ResponseProtocolState {
+                component_id: "0x98c23e9d8f34fefb1b7bd6a91b7ff122f4e16f5c".to_owned(),
+                attributes: HashMap::new(),
+                balances: HashMap::new(),
+            },
+            component: ProtocolComponent {
+                id: "0x98c23e9d8f34fefb1b7bd6a91b7ff122f4e16f5c".to_string(),
+                protocol_system: "vm:aave_v3".to_string(),
+                protocol_type_name: "aave_v3_reserve".to_string(),
+                chain: Chain::Ethereum,
+                tokens: Vec::new(),
+                contract_ids: Vec::new(),
+                static_attributes,
+                change: ChangeType::Creation,
+                creation_tx: Bytes::from_str("0x0000").unwrap(),
+                created_at: creation_time,
+            },
+        }
+    }
+
+    fn header() -> Header {
+        Header {
+            number: 1,
+            hash: Bytes::from(vec![0; 32]),
+            parent_hash: Bytes::from(vec![0; 32]),
+            revert: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_money_market_try_from() {
+        let static_attributes = HashMap::from([
+            (
+                "underlying".to_string(),
+                Bytes::from_str("0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48").unwrap(),
+            ),
+            ("market".to_string(), Bytes::from("aave_v3".as_bytes().to_vec())),
+            (
+                "pool".to_string(),
+                Bytes::from_str("0x87870bca3f3fd6335c3f4ce8392d69350b4fa4e2").unwrap(),
+            ),
+        ]);
+
+        let result = MoneyMarketState::try_from_with_block(
+            snapshot(static_attributes),
+            header(),
+            &HashMap::new(),
+            &HashMap::new(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            result.market(),
+            MoneyMarket::AaveV3 {
+                pool: Address::from_str("0x87870bca3f3fd6335c3f4ce8392d69350b4fa4e2").unwrap()
+            }
+        );
+        assert_eq!(
+            result.underlying(),
+            Address::from_str("0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48").unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_money_market_try_from_missing_pool() {
+        let static_attributes = HashMap::from([
+            (
+                "underlying".to_string(),
+                Bytes::from_str("0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48").unwrap(),
+            ),
+            ("market".to_string(), Bytes::from("aave_v3".as_bytes().to_vec())),
+        ]);
+
+        let result = MoneyMarketState::try_from_with_block(
+            snapshot(static_attributes),
+            header(),
+            &HashMap::new(),
+            &HashMap::new(),
+        )
+        .await;
+
+        assert!(matches!(
+            result.err().unwrap(),
+            InvalidSnapshotError::MissingAttribute(attr) if attr == "pool"
+        ));
+    }
+}
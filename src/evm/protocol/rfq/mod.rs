@@ -0,0 +1,8 @@
+//! Request-for-quote liquidity
+//!
+//! Integrates external RFQ market makers: an async `RfqProvider` supplies indicative and firm
+//! quotes, `RfqState` exposes the indicative quotes as a `ProtocolSim` so they can be routed
+//! through alongside AMM pools, and `validate_firm_quote` checks a firm quote by simulating its
+//! settlement call.
+pub mod provider;
+pub mod state;
@@ -0,0 +1,79 @@
+use std::{fmt::Debug, future::Future};
+
+use alloy_primitives::{Address, U256};
+use tycho_core::Bytes;
+
+use crate::protocol::errors::SimulationError;
+
+/// A single price level of an indicative quote.
+///
+/// The maker is willing to buy up to `amount_in` of the sell token for `amount_out` of the buy
+/// token. Levels are filled in order, partial fills are priced pro rata.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct QuoteLevel {
+    pub amount_in: U256,
+    pub amount_out: U256,
+}
+
+/// An indicative (non binding) quote for one direction of a token pair.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct IndicativeQuote {
+    pub sell_token: Bytes,
+    pub buy_token: Bytes,
+    /// Price levels, best first
+    pub levels: Vec<QuoteLevel>,
+    /// Unix timestamp at which the quote was issued
+    pub timestamp: u64,
+    /// Unix timestamp after which the quote must not be used anymore
+    pub expiry: u64,
+}
+
+/// Request for a firm quote.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirmQuoteRequest {
+    pub sell_token: Bytes,
+    pub buy_token: Bytes,
+    pub amount_in: U256,
+    /// The account that will execute the settlement
+    pub taker: Address,
+}
+
+/// A firm (binding) quote together with the call that settles it on-chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirmQuote {
+    pub quote_id: String,
+    pub sell_token: Bytes,
+    pub buy_token: Bytes,
+    pub amount_in: U256,
+    pub amount_out: U256,
+    /// Unix timestamp after which the quote can no longer be settled
+    pub expiry: u64,
+    /// The settlement contract to call
+    pub settlement: Address,
+    /// Calldata of the settlement call, including the maker signature
+    pub calldata: Bytes,
+    /// Native token value sent with the settlement call
+    pub value: U256,
+}
+
+/// A source of RFQ quotes, e.g. a market maker API.
+///
+/// Indicative quotes are polled regularly to keep `RfqState`s up to date, firm quotes are only
+/// requested once a route through the RFQ liquidity has been chosen.
+pub trait RfqProvider: Debug + Send + Sync {
+    /// Name of the provider, used to identify its liquidity.
+    fn name(&self) -> &str;
+
+    /// Fetches the current indicative quote for selling `sell_token` for `buy_token`.
+    fn indicative_quote(
+        &self,
+        sell_token: &Bytes,
+        buy_token: &Bytes,
+    ) -> impl Future<Output = Result<IndicativeQuote, SimulationError>> + Send;
+
+    /// Requests a firm quote.
+    fn firm_quote(
+        &self,
+        request: &FirmQuoteRequest,
+    ) -> impl Future<Output = Result<FirmQuote, SimulationError>> + Send;
+}
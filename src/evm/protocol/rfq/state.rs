@@ -0,0 +1,429 @@
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+};
+
+use alloy_primitives::{Address, U256};
+use alloy_sol_types::SolValue;
+use num_bigint::BigUint;
+use revm::DatabaseRef;
+use tycho_core::{dto::ProtocolStateDelta, Bytes};
+
+use super::provider::{FirmQuote, IndicativeQuote, RfqProvider};
+use crate::{
+    evm::{
+        engine_db::{engine_db_interface::EngineDatabaseInterface, simulation_db::BlockHeader},
+        protocol::{
+            safe_math::{safe_add_u256, safe_div_u256, safe_mul_u256},
+            u256_num::{checked_biguint_to_u256, u256_to_biguint, u256_to_f64},
+            vm::utils::coerce_error,
+        },
+        simulation::{SimulationEngine, SimulationParameters},
+    },
+    models::{Balances, Token},
+    protocol::{
+        clock::now,
+        errors::{SimulationError, TransitionError},
+        models::GetAmountOutResult,
+        state::{fingerprint, ProtocolSim},
+    },
+};
+
+/// Estimated gas of an RFQ settlement, used for indicative quotes.
+const SETTLEMENT_GAS: u64 = 120_000;
+
+/// RFQ liquidity for a token pair, based on the latest indicative quotes of one provider.
+///
+/// Quotes are kept up to date by calling `refresh`. A quote is considered stale once it is
+/// expired or older than `max_age` seconds; stale quotes are rejected with a
+/// `SimulationError::RecoverableError`, so the pair is skipped until the next refresh.
+#[derive(Clone, Debug)]
+pub struct RfqState {
+    provider: String,
+    token_a: Bytes,
+    token_b: Bytes,
+    /// Latest indicative quotes keyed by (sell token, buy token)
+    quotes: HashMap<(Bytes, Bytes), IndicativeQuote>,
+    /// Maximum quote age in seconds
+    max_age: u64,
+}
+
+impl RfqState {
+    pub fn new(provider: &str, token_a: Bytes, token_b: Bytes, max_age: u64) -> Self {
+        Self { provider: provider.to_string(), token_a, token_b, quotes: HashMap::new(), max_age }
+    }
+
+    pub fn provider(&self) -> &str {
+        &self.provider
+    }
+
+    /// Sets the indicative quote for the quote's direction, replacing any previous one.
+    pub fn insert_quote(&mut self, quote: IndicativeQuote) -> Result<(), SimulationError> {
+        let pair = [&self.token_a, &self.token_b];
+        if quote.sell_token == quote.buy_token ||
+            !pair.contains(&&quote.sell_token) ||
+            !pair.contains(&&quote.buy_token)
+        {
+            return Err(SimulationError::InvalidInput(
+                format!(
+                    "Quote {} -> {} does not match the pair",
+                    quote.sell_token, quote.buy_token
+                ),
+                None,
+            ));
+        }
+        self.quotes
+            .insert((quote.sell_token.clone(), quote.buy_token.clone()), quote);
+        Ok(())
+    }
+
+    /// Fetches fresh indicative quotes for both directions from `provider`.
+    pub async fn refresh<P: RfqProvider>(&mut self, provider: &P) -> Result<(), SimulationError> {
+        let a_to_b = provider
+            .indicative_quote(&self.token_a, &self.token_b)
+            .await?;
+        let b_to_a = provider
+            .indicative_quote(&self.token_b, &self.token_a)
+            .await?;
+        self.insert_quote(a_to_b)?;
+        self.insert_quote(b_to_a)
+    }
+
+    /// Returns the quote for the given direction if it is not stale.
+    fn fresh_quote(
+        &self,
+        sell_token: &Bytes,
+        buy_token: &Bytes,
+    ) -> Result<&IndicativeQuote, SimulationError> {
+        let quote = self
+            .quotes
+            .get(&(sell_token.clone(), buy_token.clone()))
+            .ok_or_else(|| {
+                SimulationError::RecoverableError(format!(
+                    "No {} quote for {} -> {}",
+                    self.provider, sell_token, buy_token
+                ))
+            })?;
+        let now = now();
+        if now >= quote.expiry || now.saturating_sub(quote.timestamp) > self.max_age {
+            return Err(SimulationError::RecoverableError(format!(
+                "Stale {} quote for {} -> {}",
+                self.provider, sell_token, buy_token
+            )));
+        }
+        Ok(quote)
+    }
+}
+
+impl ProtocolSim for RfqState {
+    fn fee(&self) -> f64 {
+        // Fees are priced into the quote levels
+        0.0
+    }
+
+    fn spot_price(&self, base: &Token, quote: &Token) -> Result<f64, SimulationError> {
+        // Price of base in quote is given by the quote buying base for quote.
+        let rfq_quote = self.fresh_quote(&quote.address, &base.address)?;
+        let level = rfq_quote
+            .levels
+            .first()
+            .filter(|level| !level.amount_out.is_zero())
+            .ok_or_else(|| {
+                SimulationError::RecoverableError(format!("Empty {} quote", self.provider))
+            })?;
+        Ok((u256_to_f64(level.amount_in) / u256_to_f64(quote.try_one()?)) /
+            (u256_to_f64(level.amount_out) / u256_to_f64(base.try_one()?)))
+    }
+
+    fn get_amount_out(
+        &self,
+        amount_in: BigUint,
+        token_in: &Token,
+        token_out: &Token,
+    ) -> Result<GetAmountOutResult, SimulationError> {
+        let amount_in = checked_biguint_to_u256(&amount_in)?;
+        if amount_in == U256::ZERO {
+            return Err(SimulationError::InvalidInput("Amount in cannot be zero".to_string(), None));
+        }
+        let quote = self.fresh_quote(&token_in.address, &token_out.address)?;
+
+        let mut remaining = amount_in;
+        let mut amount_out = U256::ZERO;
+        for level in quote
+            .levels
+            .iter()
+            .filter(|level| !level.amount_in.is_zero())
+        {
+            if remaining.is_zero() {
+                break;
+            }
+            let fill = remaining.min(level.amount_in);
+            amount_out = safe_add_u256(
+                amount_out,
+                safe_div_u256(safe_mul_u256(fill, level.amount_out)?, level.amount_in)?,
+            )?;
+            remaining -= fill;
+        }
+
+        // Quotes are indicative, so filling does not consume the liquidity of the state.
+        let result = GetAmountOutResult::new(
+            u256_to_biguint(amount_out),
+            BigUint::from(SETTLEMENT_GAS),
+            Box::new(self.clone()),
+        );
+        if !remaining.is_zero() {
+            return Err(SimulationError::InvalidInput(
+                format!("Amount exceeds the {} quote depth by {}", self.provider, remaining),
+                Some(result),
+            ));
+        }
+        Ok(result)
+    }
+
+    fn state_fingerprint(&self) -> u64 {
+        let quotes: BTreeMap<_, _> = self.quotes.iter().collect();
+        fingerprint(&(&self.provider, &self.token_a, &self.token_b, quotes, self.max_age))
+    }
+
+    fn delta_transition(
+        &mut self,
+        _delta: ProtocolStateDelta,
+        _tokens: &HashMap<Bytes, Token>,
+        _balances: &Balances,
+    ) -> Result<(), TransitionError<String>> {
+        // Quotes are refreshed off-chain, nothing to do on a new block.
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn ProtocolSim> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn eq(&self, other: &dyn ProtocolSim) -> bool {
+        if let Some(other_state) = other
+            .as_any()
+            .downcast_ref::<RfqState>()
+        {
+            self.provider == other_state.provider &&
+                self.token_a == other_state.token_a &&
+                self.token_b == other_state.token_b &&
+                self.quotes == other_state.quotes
+        } else {
+            false
+        }
+    }
+}
+
+/// Validates a firm quote by simulating its settlement call.
+///
+/// The quote is rejected if it is expired, if the settlement reverts, or if the settlement returns
+/// an amount (as its first return word) smaller than the quoted `amount_out`. On success, returns
+/// the gas used by the settlement.
+///
+/// The taker must hold and have approved `amount_in` of the sell token in the engine's state, e.g.
+/// by using storage overrides on the engine's database.
+pub fn validate_firm_quote<D>(
+    quote: &FirmQuote,
+    taker: Address,
+    block: &BlockHeader,
+    engine: &SimulationEngine<D>,
+) -> Result<u64, SimulationError>
+where
+    D: EngineDatabaseInterface + Clone + Debug,
+    <D as DatabaseRef>::Error: Debug,
+    <D as EngineDatabaseInterface>::Error: Debug,
+{
+    if block.timestamp >= quote.expiry {
+        return Err(SimulationError::InvalidInput(
+            format!("Firm quote {} expired at {}", quote.quote_id, quote.expiry),
+            None,
+        ));
+    }
+
+    let params = SimulationParameters {
+        caller: taker,
+        to: quote.settlement,
+        data: quote.calldata.to_vec(),
+        value: quote.value,
+        overrides: None,
+        gas_limit: None,
+        spec_id: None,
+        block: *block,
+    };
+    let result = engine
+        .simulate(&params)
+        .map_err(|e| coerce_error(&e, &format!("rfq quote {}", quote.quote_id), None))?;
+
+    if result.result.len() >= 32 {
+        let settled = U256::abi_decode(&result.result[..32], false).map_err(|e| {
+            SimulationError::FatalError(format!(
+                "Settlement of quote {} failed: Failed to decode return value: {:?}",
+                quote.quote_id, e
+            ))
+        })?;
+        if settled < quote.amount_out {
+            return Err(SimulationError::InvalidInput(
+                format!(
+                    "Settlement of quote {} returned {} which is less than the quoted {}",
+                    quote.quote_id, settled, quote.amount_out
+                ),
+                None,
+            ));
+        }
+    }
+    Ok(result.gas_used)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use num_bigint::ToBigUint;
+
+    use super::*;
+    use crate::{
+        evm::protocol::rfq::provider::{FirmQuoteRequest, QuoteLevel},
+        protocol::clock::{with_clock, MockClock},
+    };
+
+    fn weth() -> Token {
+        Token::new(
+            "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2",
+            18,
+            "WETH",
+            10_000.to_biguint().unwrap(),
+        )
+    }
+
+    fn usdc() -> Token {
+        Token::new(
+            "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48",
+            6,
+            "USDC",
+            10_000.to_biguint().unwrap(),
+        )
+    }
+
+    fn quote(sell: &Token, buy: &Token, timestamp: u64) -> IndicativeQuote {
+        IndicativeQuote {
+            sell_token: sell.address.clone(),
+            buy_token: buy.address.clone(),
+            levels: vec![
+                QuoteLevel { amount_in: U256::from(100), amount_out: U256::from(200) },
+                QuoteLevel { amount_in: U256::from(100), amount_out: U256::from(100) },
+            ],
+            timestamp,
+            expiry: timestamp + 60,
+        }
+    }
+
+    #[derive(Debug)]
+    struct MockProvider;
+
+    impl RfqProvider for MockProvider {
+        fn name(&self) -> &str {
+            "mock"
+        }
+
+        async fn indicative_quote(
+            &self,
+            sell_token: &Bytes,
+            buy_token: &Bytes,
+        ) -> Result<IndicativeQuote, SimulationError> {
+            let (sell, buy) =
+                if sell_token == &weth().address { (weth(), usdc()) } else { (usdc(), weth()) };
+            assert_eq!(&buy.address, buy_token);
+            Ok(quote(&sell, &buy, now()))
+        }
+
+        async fn firm_quote(
+            &self,
+            _request: &FirmQuoteRequest,
+        ) -> Result<FirmQuote, SimulationError> {
+            Err(SimulationError::RecoverableError("Mock provider gives no firm quotes".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refresh_and_get_amount_out() {
+        let mut state = RfqState::new("mock", weth().address, usdc().address, 30);
+        state
+            .refresh(&MockProvider)
+            .await
+            .unwrap();
+
+        let res = state
+            .get_amount_out(BigUint::from(150u64), &weth(), &usdc())
+            .unwrap();
+
+        assert_eq!(res.amount, BigUint::from(250u64));
+    }
+
+    #[test]
+    fn test_get_amount_out_exceeds_depth() {
+        let mut state = RfqState::new("mock", weth().address, usdc().address, 30);
+        state
+            .insert_quote(quote(&weth(), &usdc(), now()))
+            .unwrap();
+
+        let res = state.get_amount_out(BigUint::from(300u64), &weth(), &usdc());
+
+        match res {
+            Err(SimulationError::InvalidInput(_, Some(partial))) => {
+                assert_eq!(partial.amount, BigUint::from(300u64));
+            }
+            _ => panic!("Expected InvalidInput with partial result"),
+        }
+    }
+
+    #[test]
+    fn test_stale_quote() {
+        let mut state = RfqState::new("mock", weth().address, usdc().address, 30);
+        state
+            .insert_quote(quote(&weth(), &usdc(), now() - 45))
+            .unwrap();
+
+        let res = state.get_amount_out(BigUint::from(10u64), &weth(), &usdc());
+
+        assert!(matches!(res, Err(SimulationError::RecoverableError(_))));
+    }
+
+    #[test]
+    fn test_insert_quote_wrong_pair() {
+        let mut state = RfqState::new("mock", weth().address, weth().address, 30);
+
+        assert!(state
+            .insert_quote(quote(&weth(), &usdc(), now()))
+            .is_err());
+    }
+
+    #[test]
+    fn test_quote_expires_with_clock() {
+        let clock = MockClock::new(1_700_000_000);
+        let mut state = RfqState::new("mock", weth().address, usdc().address, 30);
+
+        with_clock(Arc::new(clock.clone()), || {
+            state
+                .insert_quote(quote(&weth(), &usdc(), now()))
+                .unwrap();
+            assert!(state
+                .get_amount_out(BigUint::from(10u64), &weth(), &usdc())
+                .is_ok());
+
+            clock.advance(31);
+            assert!(matches!(
+                state.get_amount_out(BigUint::from(10u64), &weth(), &usdc()),
+                Err(SimulationError::RecoverableError(_))
+            ));
+        });
+    }
+}
@@ -7,7 +7,7 @@
 //! Functions for the types I256, U256, U512 are available.
 use alloy_primitives::{I256, U256, U512};
 
-use crate::protocol::errors::SimulationError;
+use crate::protocol::{errors::SimulationError, rounding::RoundingPolicy};
 
 pub fn safe_mul_u256(a: U256, b: U256) -> Result<U256, SimulationError> {
     let res = a.checked_mul(b);
@@ -19,6 +19,16 @@ pub fn safe_div_u256(a: U256, b: U256) -> Result<U256, SimulationError> {
     _construc_result_u256(res)
 }
 
+/// Divides, rounding the result according to `policy`.
+pub fn safe_div_u256_rounded(
+    a: U256,
+    b: U256,
+    policy: RoundingPolicy,
+) -> Result<U256, SimulationError> {
+    let (quotient, remainder) = div_mod_u256(a, b)?;
+    Ok(policy.round(quotient, remainder, b))
+}
+
 pub fn safe_add_u256(a: U256, b: U256) -> Result<U256, SimulationError> {
     let res = a.checked_add(b);
     _construc_result_u256(res)
@@ -4,6 +4,8 @@ use std::{cmp::max, collections::HashMap, panic};
 use alloy_primitives::{bytes::Bytes, U256};
 use num_bigint::BigUint;
 
+use crate::protocol::errors::SimulationError;
+
 /// Converts a U256 integer into it's closest floating point representation
 ///
 /// Rounds to "nearest even" if the number has to be truncated (number uses more than 53 bits).
@@ -99,11 +101,32 @@ pub fn u256_to_biguint(value: U256) -> BigUint {
     BigUint::from_bytes_be(&bytes)
 }
 
+/// Converts a BigUint into a U256.
+///
+/// Panics if the value exceeds 256 bits, use `checked_biguint_to_u256` for user provided amounts.
 pub fn biguint_to_u256(value: &BigUint) -> U256 {
     let bytes = value.to_bytes_be();
     U256::from_be_slice(&bytes)
 }
 
+/// Converts a BigUint into a U256. Errors with `InvalidInput` if the value exceeds 256 bits, e.g.
+/// an amount of a token with an absurd supply.
+pub fn checked_biguint_to_u256(value: &BigUint) -> Result<U256, SimulationError> {
+    U256::try_from_be_slice(&value.to_bytes_be()).ok_or_else(|| {
+        SimulationError::InvalidInput(format!("Amount {} exceeds 256 bits", value), None)
+    })
+}
+
+/// Converts big-endian bytes into a U256. Errors with `InvalidInput` if the value exceeds 256 bits.
+pub fn checked_bytes_to_u256(bytes: &[u8]) -> Result<U256, SimulationError> {
+    U256::try_from_be_slice(bytes).ok_or_else(|| {
+        SimulationError::InvalidInput(
+            format!("Value 0x{} exceeds 256 bits", alloy_primitives::hex::encode(bytes)),
+            None,
+        )
+    })
+}
+
 pub fn bytes_to_u256(bytes: Bytes) -> U256 {
     // Ensure the input is exactly 32 bytes
     let mut padded_bytes = [0u8; 32];
@@ -147,4 +170,17 @@ mod test {
 
         assert_eq!(res, out);
     }
+
+    #[test]
+    fn test_checked_biguint_to_u256() {
+        let max = BigUint::from(2u8).pow(256) - 1u8;
+
+        assert_eq!(checked_biguint_to_u256(&max).unwrap(), U256::MAX);
+        assert!(matches!(
+            checked_biguint_to_u256(&(max + 1u8)),
+            Err(SimulationError::InvalidInput(..))
+        ));
+        assert_eq!(checked_bytes_to_u256(&[0u8; 40]).unwrap(), U256::ZERO);
+        assert!(checked_bytes_to_u256(&[1u8; 33]).is_err());
+    }
 }
@@ -1,4 +1,4 @@
 //! Uniswap V2 Decentralized Exchange
-mod reserve_price;
+pub(crate) mod reserve_price;
 pub mod state;
 pub mod tycho_decoder;
@@ -8,7 +8,7 @@ use crate::evm::protocol::u256_num::u256_to_f64;
 ///     1. The nominator and denominator are converted to float (this conversion is lossy)
 ///     2. The price is computed by using float division
 ///     3. Finally, the price is correct for difference in token decimals.
-pub(super) fn spot_price_from_reserves(
+pub(crate) fn spot_price_from_reserves(
     r0: U256,
     r1: U256,
     token_0_decimals: u32,
@@ -7,18 +7,23 @@ use tycho_core::{dto::ProtocolStateDelta, Bytes};
 use super::reserve_price::spot_price_from_reserves;
 use crate::{
     evm::protocol::{
-        safe_math::{safe_add_u256, safe_div_u256, safe_mul_u256, safe_sub_u256},
-        u256_num::{biguint_to_u256, u256_to_biguint},
+        fee::FeeModel,
+        safe_math::{safe_add_u256, safe_sub_u256},
+        u256_num::{checked_biguint_to_u256, u256_to_biguint},
     },
     models::{Balances, Token},
     protocol::{
         errors::{SimulationError, TransitionError},
         models::GetAmountOutResult,
-        state::ProtocolSim,
+        rounding::RoundingPolicy,
+        state::{fingerprint, ProtocolSim},
     },
 };
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+/// Uniswap V2 charges 0.3% of the input
+const FEE: FeeModel = FeeModel::InputBps(30);
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct UniswapV2State {
     pub reserve0: U256,
     pub reserve1: U256,
@@ -38,7 +43,7 @@ impl UniswapV2State {
 
 impl ProtocolSim for UniswapV2State {
     fn fee(&self) -> f64 {
-        0.003
+        FEE.rate()
     }
 
     fn spot_price(&self, base: &Token, quote: &Token) -> Result<f64, SimulationError> {
@@ -65,7 +70,7 @@ impl ProtocolSim for UniswapV2State {
         token_in: &Token,
         token_out: &Token,
     ) -> Result<GetAmountOutResult, SimulationError> {
-        let amount_in = biguint_to_u256(&amount_in);
+        let amount_in = checked_biguint_to_u256(&amount_in)?;
         if amount_in == U256::from(0u64) {
             return Err(SimulationError::InvalidInput("Amount in cannot be zero".to_string(), None));
         }
@@ -73,16 +78,19 @@ impl ProtocolSim for UniswapV2State {
         let reserve_sell = if zero2one { self.reserve0 } else { self.reserve1 };
         let reserve_buy = if zero2one { self.reserve1 } else { self.reserve0 };
 
+        if reserve_sell == U256::from(0u64) && reserve_buy == U256::from(0u64) {
+            return Err(SimulationError::PoolNotInitialized("No reserves".to_string()));
+        }
         if reserve_sell == U256::from(0u64) || reserve_buy == U256::from(0u64) {
             return Err(SimulationError::RecoverableError("No liquidity".to_string()));
         }
 
-        let amount_in_with_fee = safe_mul_u256(amount_in, U256::from(997))?;
-        let numerator = safe_mul_u256(amount_in_with_fee, reserve_buy)?;
-        let denominator =
-            safe_add_u256(safe_mul_u256(reserve_sell, U256::from(1000))?, amount_in_with_fee)?;
-
-        let amount_out = safe_div_u256(numerator, denominator)?;
+        let amount_out = FEE.constant_product_out(
+            amount_in,
+            reserve_sell,
+            reserve_buy,
+            RoundingPolicy::current(),
+        )?;
         let mut new_state = self.clone();
         if zero2one {
             new_state.reserve0 = safe_add_u256(self.reserve0, amount_in)?;
@@ -100,6 +108,10 @@ impl ProtocolSim for UniswapV2State {
         ))
     }
 
+    fn state_fingerprint(&self) -> u64 {
+        fingerprint(self)
+    }
+
     fn delta_transition(
         &mut self,
         delta: ProtocolStateDelta,
@@ -154,11 +166,14 @@ mod tests {
     };
 
     use approx::assert_ulps_eq;
-    use num_traits::One;
+    use num_traits::{One, Zero};
     use rstest::rstest;
     use tycho_core::hex_bytes::Bytes;
 
     use super::*;
+    use crate::{
+        evm::protocol::u256_num::biguint_to_u256, protocol::rounding::RoundingErrorReport,
+    };
 
     #[rstest]
     #[case::same_dec(
@@ -216,6 +231,86 @@ mod tests {
         assert_eq!(state.reserve1, r1);
     }
 
+    #[test]
+    fn test_get_amount_out_exceeding_u256() {
+        // e.g. a token with 36 decimals and an absurd supply
+        let t0 = Token::new(
+            "0x0000000000000000000000000000000000000000",
+            36,
+            "T0",
+            10_000.to_biguint().unwrap(),
+        );
+        let t1 = Token::new(
+            "0x0000000000000000000000000000000000000001",
+            0,
+            "T1",
+            10_000.to_biguint().unwrap(),
+        );
+        let state = UniswapV2State::new(U256::from(1_000_000u64), U256::from(1_000_000u64));
+
+        let res = state.get_amount_out(BigUint::from(2u8).pow(256), &t0, &t1);
+
+        assert!(matches!(res, Err(SimulationError::InvalidInput(..))));
+    }
+
+    #[test]
+    fn test_get_amount_out_not_initialized() {
+        let t0 = Token::new(
+            "0x0000000000000000000000000000000000000000",
+            18,
+            "T0",
+            10_000.to_biguint().unwrap(),
+        );
+        let t1 = Token::new(
+            "0x0000000000000000000000000000000000000001",
+            18,
+            "T1",
+            10_000.to_biguint().unwrap(),
+        );
+        let amount_in = BigUint::from(1_000u32);
+
+        let empty =
+            UniswapV2State::new(U256::ZERO, U256::ZERO).get_amount_out(amount_in.clone(), &t0, &t1);
+        let drained = UniswapV2State::new(U256::from(1_000u32), U256::ZERO)
+            .get_amount_out(amount_in, &t0, &t1);
+
+        assert!(matches!(empty, Err(SimulationError::PoolNotInitialized(_))));
+        assert!(matches!(drained, Err(SimulationError::RecoverableError(_))));
+    }
+
+    #[test]
+    fn test_amount_out_rounding_error() {
+        // On-chain amounts out of the cases above
+        let cases = [
+            (
+                "6770398782322527849696614",
+                "5124813135806900540214",
+                "10000000000000000000000",
+                "7535635391574243447",
+            ),
+            ("33372357002392258830279", "43356945776493", "10000000000000000000", "12949029867"),
+        ];
+        let mut floor = RoundingErrorReport::new();
+        let mut nearest = RoundingErrorReport::new();
+
+        for (r0, r1, amount_in, expected) in cases {
+            let (r0, r1) = (U256::from_str(r0).unwrap(), U256::from_str(r1).unwrap());
+            let amount_in = U256::from_str(amount_in).unwrap();
+            let expected = BigUint::from_str(expected).unwrap();
+            for (report, policy) in
+                [(&mut floor, RoundingPolicy::Floor), (&mut nearest, RoundingPolicy::Nearest)]
+            {
+                let out = FEE
+                    .constant_product_out(amount_in, r0, r1, policy)
+                    .unwrap();
+                report.record(&expected, &u256_to_biguint(out));
+            }
+        }
+
+        assert!(floor.max_abs().is_zero());
+        assert!(nearest.max_abs() <= &BigUint::one());
+    }
+
     #[test]
     fn test_get_amount_out_overflow() {
         let r0 = U256::from_str("33372357002392258830279").unwrap();
@@ -308,6 +403,32 @@ mod tests {
         assert_eq!(state.reserve1, U256::from_str("2000").unwrap());
     }
 
+    #[test]
+    fn test_state_fingerprint() {
+        let mut state = UniswapV2State::new(U256::from(1000), U256::from(1000));
+        let unchanged = state.state_fingerprint();
+        let delta = ProtocolStateDelta {
+            component_id: "State1".to_owned(),
+            updated_attributes: vec![
+                ("reserve0".to_string(), Bytes::from(1500_u64.to_be_bytes().to_vec())),
+                ("reserve1".to_string(), Bytes::from(2000_u64.to_be_bytes().to_vec())),
+            ]
+            .into_iter()
+            .collect(),
+            deleted_attributes: HashSet::new(),
+        };
+
+        state
+            .delta_transition(delta, &HashMap::new(), &Balances::default())
+            .unwrap();
+
+        assert_eq!(
+            UniswapV2State::new(U256::from(1000), U256::from(1000)).state_fingerprint(),
+            unchanged
+        );
+        assert_ne!(state.state_fingerprint(), unchanged);
+    }
+
     #[test]
     fn test_delta_transition_missing_attribute() {
         let mut state =
@@ -1,7 +1,9 @@
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum FeeAmount {
     Lowest = 100,
     Low = 500,
+    /// PancakeSwap V3 only
+    MediumLow = 2500,
     Medium = 3000,
     High = 10_000,
 }
@@ -13,6 +15,7 @@ impl std::convert::TryFrom<i32> for FeeAmount {
         match value {
             100 => Ok(FeeAmount::Lowest),
             500 => Ok(FeeAmount::Low),
+            2500 => Ok(FeeAmount::MediumLow),
             3000 => Ok(FeeAmount::Medium),
             10_000 => Ok(FeeAmount::High),
             _ => Err(()),
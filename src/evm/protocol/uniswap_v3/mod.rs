@@ -1,4 +1,7 @@
 //! Uniswap V3 Decentralized Exchange
+//!
+//! Also decodes PancakeSwap V3 pools, which share the Uniswap V3 math and only add the 0.25% fee
+//! tier.
 pub mod enums;
 pub mod state;
 pub mod tycho_decoder;
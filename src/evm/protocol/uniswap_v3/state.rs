@@ -8,29 +8,26 @@ use tycho_core::{dto::ProtocolStateDelta, Bytes};
 use super::enums::FeeAmount;
 use crate::{
     evm::protocol::{
-        safe_math::{safe_add_u256, safe_sub_u256},
-        u256_num::u256_to_biguint,
+        u256_num::{checked_biguint_to_u256, u256_to_biguint},
         utils::uniswap::{
-            i24_be_bytes_to_i32, liquidity_math,
+            concentrated_liquidity::{ConcentratedPool, SwapGas},
+            i24_be_bytes_to_i32,
             sqrt_price_math::sqrt_price_q96_to_f64,
-            swap_math,
-            tick_list::{TickInfo, TickList, TickListErrorKind},
-            tick_math::{
-                get_sqrt_ratio_at_tick, get_tick_at_sqrt_ratio, MAX_SQRT_RATIO, MAX_TICK,
-                MIN_SQRT_RATIO, MIN_TICK,
-            },
-            StepComputation, SwapResults, SwapState,
+            tick_list::{TickInfo, TickList},
+            SwapResults,
         },
     },
     models::{Balances, Token},
     protocol::{
         errors::{SimulationError, TransitionError},
         models::GetAmountOutResult,
-        state::ProtocolSim,
+        state::{fingerprint, ProtocolSim},
     },
 };
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+const SWAP_GAS: SwapGas = SwapGas { base: 130_000, per_step: 2000 };
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct UniswapV3State {
     liquidity: u128,
     sqrt_price: U256,
@@ -60,10 +57,11 @@ impl UniswapV3State {
         UniswapV3State { liquidity, sqrt_price, fee, tick, ticks: tick_list }
     }
 
-    fn get_spacing(fee: FeeAmount) -> u16 {
+    pub(crate) fn get_spacing(fee: FeeAmount) -> u16 {
         match fee {
             FeeAmount::Lowest => 1,
             FeeAmount::Low => 10,
+            FeeAmount::MediumLow => 50,
             FeeAmount::Medium => 60,
             FeeAmount::High => 200,
         }
@@ -75,143 +73,26 @@ impl UniswapV3State {
         amount_specified: I256,
         sqrt_price_limit: Option<U256>,
     ) -> Result<SwapResults, SimulationError> {
-        if self.liquidity == 0 {
-            return Err(SimulationError::RecoverableError("No liquidity".to_string()));
-        }
-        let price_limit = if let Some(limit) = sqrt_price_limit {
-            limit
-        } else if zero_for_one {
-            safe_add_u256(MIN_SQRT_RATIO, U256::from(1u64))?
-        } else {
-            safe_sub_u256(MAX_SQRT_RATIO, U256::from(1u64))?
-        };
-
-        if zero_for_one {
-            assert!(price_limit > MIN_SQRT_RATIO);
-            assert!(price_limit < self.sqrt_price);
-        } else {
-            assert!(price_limit < MAX_SQRT_RATIO);
-            assert!(price_limit > self.sqrt_price);
-        }
-
-        let exact_input = amount_specified > I256::from_raw(U256::from(0u64));
-
-        let mut state = SwapState {
-            amount_remaining: amount_specified,
-            amount_calculated: I256::from_raw(U256::from(0u64)),
+        ConcentratedPool {
+            liquidity: self.liquidity,
             sqrt_price: self.sqrt_price,
             tick: self.tick,
-            liquidity: self.liquidity,
-        };
-        let mut gas_used = U256::from(130_000);
-
-        while state.amount_remaining != I256::from_raw(U256::from(0u64)) &&
-            state.sqrt_price != price_limit
-        {
-            let (mut next_tick, initialized) = match self
-                .ticks
-                .next_initialized_tick_within_one_word(state.tick, zero_for_one)
-            {
-                Ok((tick, init)) => (tick, init),
-                Err(tick_err) => match tick_err.kind {
-                    TickListErrorKind::TicksExeeded => {
-                        let mut new_state = self.clone();
-                        new_state.liquidity = state.liquidity;
-                        new_state.tick = state.tick;
-                        new_state.sqrt_price = state.sqrt_price;
-                        return Err(SimulationError::InvalidInput(
-                            "Ticks exceeded".into(),
-                            Some(GetAmountOutResult::new(
-                                u256_to_biguint(state.amount_calculated.abs().into_raw()),
-                                u256_to_biguint(gas_used),
-                                Box::new(new_state),
-                            )),
-                        ));
-                    }
-                    _ => return Err(SimulationError::FatalError("Unknown error".to_string())),
-                },
-            };
-
-            next_tick = next_tick.clamp(MIN_TICK, MAX_TICK);
-
-            let sqrt_price_next = get_sqrt_ratio_at_tick(next_tick)?;
-            let (sqrt_price, amount_in, amount_out, fee_amount) = swap_math::compute_swap_step(
-                state.sqrt_price,
-                UniswapV3State::get_sqrt_ratio_target(sqrt_price_next, price_limit, zero_for_one),
-                state.liquidity,
-                state.amount_remaining,
-                self.fee as u32,
-            )?;
-            state.sqrt_price = sqrt_price;
-
-            let step = StepComputation {
-                sqrt_price_start: state.sqrt_price,
-                tick_next: next_tick,
-                initialized,
-                sqrt_price_next,
-                amount_in,
-                amount_out,
-                fee_amount,
-            };
-            if exact_input {
-                state.amount_remaining -= I256::checked_from_sign_and_abs(
-                    Sign::Positive,
-                    safe_add_u256(step.amount_in, step.fee_amount)?,
-                )
-                .unwrap();
-                state.amount_calculated -=
-                    I256::checked_from_sign_and_abs(Sign::Positive, step.amount_out).unwrap();
-            } else {
-                state.amount_remaining +=
-                    I256::checked_from_sign_and_abs(Sign::Positive, step.amount_out).unwrap();
-                state.amount_calculated += I256::checked_from_sign_and_abs(
-                    Sign::Positive,
-                    safe_add_u256(step.amount_in, step.fee_amount)?,
-                )
-                .unwrap();
-            }
-            if state.sqrt_price == step.sqrt_price_next {
-                if step.initialized {
-                    let liquidity_raw = self
-                        .ticks
-                        .get_tick(step.tick_next)
-                        .unwrap()
-                        .net_liquidity;
-                    let liquidity_net = if zero_for_one { -liquidity_raw } else { liquidity_raw };
-                    state.liquidity =
-                        liquidity_math::add_liquidity_delta(state.liquidity, liquidity_net);
-                }
-                state.tick = if zero_for_one { step.tick_next - 1 } else { step.tick_next };
-            } else if state.sqrt_price != step.sqrt_price_start {
-                state.tick = get_tick_at_sqrt_ratio(state.sqrt_price)?;
-            }
-            gas_used = safe_add_u256(gas_used, U256::from(2000))?;
+            ticks: &self.ticks,
+            fee: self.fee as u32,
+            gas: SWAP_GAS,
         }
-        Ok(SwapResults {
-            amount_calculated: state.amount_calculated,
-            sqrt_price: state.sqrt_price,
-            liquidity: state.liquidity,
-            tick: state.tick,
-            gas_used,
+        .swap(zero_for_one, amount_specified, sqrt_price_limit, |result| {
+            Box::new(self.with_swap(result))
         })
     }
 
-    fn get_sqrt_ratio_target(
-        sqrt_price_next: U256,
-        sqrt_price_limit: U256,
-        zero_for_one: bool,
-    ) -> U256 {
-        let cond1 = if zero_for_one {
-            sqrt_price_next < sqrt_price_limit
-        } else {
-            sqrt_price_next > sqrt_price_limit
-        };
-
-        if cond1 {
-            sqrt_price_limit
-        } else {
-            sqrt_price_next
-        }
+    /// The state after a swap.
+    fn with_swap(&self, result: &SwapResults) -> Self {
+        let mut new_state = self.clone();
+        new_state.liquidity = result.liquidity;
+        new_state.tick = result.tick;
+        new_state.sqrt_price = result.sqrt_price;
+        new_state
     }
 }
 
@@ -236,19 +117,19 @@ impl ProtocolSim for UniswapV3State {
         token_b: &Token,
     ) -> Result<GetAmountOutResult, SimulationError> {
         let zero_for_one = token_a < token_b;
-        let amount_specified = I256::checked_from_sign_and_abs(
-            Sign::Positive,
-            U256::from_be_slice(&amount_in.to_bytes_be()),
-        )
-        .unwrap();
+        let amount_specified =
+            I256::checked_from_sign_and_abs(Sign::Positive, checked_biguint_to_u256(&amount_in)?)
+                .ok_or_else(|| {
+                SimulationError::InvalidInput(
+                    format!("Amount {} exceeds 255 bits", amount_in),
+                    None,
+                )
+            })?;
 
         let result = self.swap(zero_for_one, amount_specified, None)?;
 
         trace!(?amount_in, ?token_a, ?token_b, ?zero_for_one, ?result, "V3 SWAP");
-        let mut new_state = self.clone();
-        new_state.liquidity = result.liquidity;
-        new_state.tick = result.tick;
-        new_state.sqrt_price = result.sqrt_price;
+        let new_state = self.with_swap(&result);
 
         Ok(GetAmountOutResult::new(
             u256_to_biguint(
@@ -262,6 +143,10 @@ impl ProtocolSim for UniswapV3State {
         ))
     }
 
+    fn state_fingerprint(&self) -> u64 {
+        fingerprint(self)
+    }
+
     fn delta_transition(
         &mut self,
         delta: ProtocolStateDelta,
@@ -419,6 +304,28 @@ mod tests {
         assert_eq!(res.amount, expected);
     }
 
+    #[test]
+    fn test_get_amount_out_not_initialized() {
+        let token_x = Token::new(
+            "0x6b175474e89094c44da98b954eedeac495271d0f",
+            18,
+            "X",
+            10_000.to_biguint().unwrap(),
+        );
+        let token_y = Token::new(
+            "0xf1ca9cb74685755965c7458528a36934df52a3ef",
+            18,
+            "Y",
+            10_000.to_biguint().unwrap(),
+        );
+        // Created, but `initialize` not called yet
+        let pool = UniswapV3State::new(0, U256::ZERO, FeeAmount::Medium, 0, vec![]);
+
+        let res = pool.get_amount_out(BigUint::from(1_000u32), &token_x, &token_y);
+
+        assert!(matches!(res, Err(SimulationError::PoolNotInitialized(_))));
+    }
+
     struct SwapTestCase {
         symbol: &'static str,
         sell: BigUint,
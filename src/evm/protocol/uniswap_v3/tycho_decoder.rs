@@ -1,12 +1,11 @@
 use std::collections::HashMap;
 
-use alloy_primitives::U256;
 use tycho_client::feed::{synchronizer::ComponentWithState, Header};
 use tycho_core::Bytes;
 
 use super::{enums::FeeAmount, state::UniswapV3State};
 use crate::{
-    evm::protocol::utils::uniswap::{i24_be_bytes_to_i32, tick_list::TickInfo},
+    evm::protocol::utils::uniswap::concentrated_liquidity::decode_snapshot,
     models::Token,
     protocol::{errors::InvalidSnapshotError, models::TryFromWithBlock},
 };
@@ -22,39 +21,7 @@ impl TryFromWithBlock<ComponentWithState> for UniswapV3State {
         _account_balances: &HashMap<Bytes, HashMap<Bytes, Bytes>>,
         _all_tokens: &HashMap<Bytes, Token>,
     ) -> Result<Self, Self::Error> {
-        let liq = snapshot
-            .state
-            .attributes
-            .get("liquidity")
-            .ok_or_else(|| InvalidSnapshotError::MissingAttribute("liquidity".to_string()))?
-            .clone();
-
-        // This is a hotfix because if the liquidity has never been updated after creation, it's
-        // currently encoded as H256::zero(), therefore, we can't decode this as u128.
-        // We can remove this once it has been fixed on the tycho side.
-        let liq_16_bytes = if liq.len() == 32 {
-            // Make sure it only happens for 0 values, otherwise error.
-            if liq == Bytes::zero(32) {
-                Bytes::from([0; 16])
-            } else {
-                return Err(InvalidSnapshotError::ValueError(format!(
-                    "Liquidity bytes too long for {}, expected 16",
-                    liq
-                )));
-            }
-        } else {
-            liq
-        };
-
-        let liquidity = u128::from(liq_16_bytes);
-
-        let sqrt_price = U256::from_be_slice(
-            snapshot
-                .state
-                .attributes
-                .get("sqrt_price_x96")
-                .ok_or_else(|| InvalidSnapshotError::MissingAttribute("sqrt_price".to_string()))?,
-        );
+        let pool = decode_snapshot(&snapshot.state.attributes)?;
 
         let fee_value = i32::from(
             snapshot
@@ -67,61 +34,7 @@ impl TryFromWithBlock<ComponentWithState> for UniswapV3State {
         let fee = FeeAmount::try_from(fee_value)
             .map_err(|_| InvalidSnapshotError::ValueError("Unsupported fee amount".to_string()))?;
 
-        let tick = snapshot
-            .state
-            .attributes
-            .get("tick")
-            .ok_or_else(|| InvalidSnapshotError::MissingAttribute("tick".to_string()))?
-            .clone();
-
-        // This is a hotfix because if the tick has never been updated after creation, it's
-        // currently encoded as H256::zero(), therefore, we can't decode this as i32. We can
-        // remove this this will be fixed on the tycho side.
-        let ticks_4_bytes = if tick.len() == 32 {
-            // Make sure it only happens for 0 values, otherwise error.
-            if tick == Bytes::zero(32) {
-                Bytes::from([0; 4])
-            } else {
-                return Err(InvalidSnapshotError::ValueError(format!(
-                    "Tick bytes too long for {}, expected 4",
-                    tick
-                )));
-            }
-        } else {
-            tick
-        };
-        let tick = i24_be_bytes_to_i32(&ticks_4_bytes);
-
-        let ticks: Result<Vec<_>, _> = snapshot
-            .state
-            .attributes
-            .iter()
-            .filter_map(|(key, value)| {
-                if key.starts_with("ticks/") {
-                    Some(
-                        key.split('/')
-                            .nth(1)?
-                            .parse::<i32>()
-                            .map(|tick_index| TickInfo::new(tick_index, i128::from(value.clone())))
-                            .map_err(|err| InvalidSnapshotError::ValueError(err.to_string())),
-                    )
-                } else {
-                    None
-                }
-            })
-            .collect();
-
-        let mut ticks = match ticks {
-            Ok(ticks) if !ticks.is_empty() => ticks
-                .into_iter()
-                .filter(|t| t.net_liquidity != 0)
-                .collect::<Vec<_>>(),
-            _ => return Err(InvalidSnapshotError::MissingAttribute("tick_liquidities".to_string())),
-        };
-
-        ticks.sort_by_key(|tick| tick.index);
-
-        Ok(UniswapV3State::new(liquidity, sqrt_price, fee, tick, ticks))
+        Ok(UniswapV3State::new(pool.liquidity, pool.sqrt_price, fee, pool.tick, pool.ticks))
     }
 }
 
@@ -129,11 +42,13 @@ impl TryFromWithBlock<ComponentWithState> for UniswapV3State {
 mod tests {
     use std::str::FromStr;
 
+    use alloy_primitives::U256;
     use chrono::DateTime;
     use rstest::rstest;
     use tycho_core::dto::{Chain, ChangeType, ProtocolComponent, ResponseProtocolState};
 
     use super::*;
+    use crate::evm::protocol::utils::uniswap::tick_list::TickInfo;
 
     fn usv3_component() -> ProtocolComponent {
         let creation_time = DateTime::from_timestamp(1622526000, 0)
@@ -208,6 +123,46 @@ mod tests {
         assert_eq!(result.unwrap(), expected);
     }
 
+    #[tokio::test]
+    async fn test_pancakeswap_v3_try_from() {
+        let mut component = usv3_component();
+        component
+            .static_attributes
+            .insert("fee".to_string(), Bytes::from(2500_i32.to_be_bytes().to_vec()));
+        let mut attributes = usv3_attributes();
+        attributes.remove("ticks/60/net_liquidity");
+        attributes.insert(
+            "ticks/50/net_liquidity".to_string(),
+            Bytes::from(400_i128.to_be_bytes().to_vec()),
+        );
+
+        let snapshot = ComponentWithState {
+            state: ResponseProtocolState {
+                component_id: "State1".to_owned(),
+                attributes,
+                balances: HashMap::new(),
+            },
+            component,
+        };
+
+        let result = UniswapV3State::try_from_with_block(
+            snapshot,
+            header(),
+            &HashMap::new(),
+            &HashMap::new(),
+        )
+        .await;
+
+        let expected = UniswapV3State::new(
+            100,
+            U256::from(200),
+            FeeAmount::MediumLow,
+            300,
+            vec![TickInfo::new(50, 400)],
+        );
+        assert_eq!(result.unwrap(), expected);
+    }
+
     #[tokio::test]
     #[rstest]
     #[case::missing_liquidity("liquidity")]
@@ -261,7 +216,8 @@ mod tests {
 
     #[tokio::test]
     async fn test_usv3_try_from_invalid_fee() {
-        // set an invalid fee amount (100, 500, 3_000 and 10_000 are the only valid fee amounts)
+        // set an invalid fee amount (100, 500, 2_500, 3_000 and 10_000 are the only valid fee
+        // amounts)
         let mut component = usv3_component();
         component
             .static_attributes
@@ -8,7 +8,7 @@ use tycho_core::{dto::ProtocolStateDelta, Bytes};
 use crate::{
     evm::protocol::{
         safe_math::{safe_add_u256, safe_sub_u256},
-        u256_num::u256_to_biguint,
+        u256_num::{checked_biguint_to_u256, u256_to_biguint},
         utils::uniswap::{
             i24_be_bytes_to_i32, liquidity_math,
             sqrt_price_math::sqrt_price_q96_to_f64,
@@ -25,11 +25,11 @@ use crate::{
     protocol::{
         errors::{SimulationError, TransitionError},
         models::GetAmountOutResult,
-        state::ProtocolSim,
+        state::{fingerprint, ProtocolSim},
     },
 };
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct UniswapV4State {
     liquidity: u128,
     sqrt_price: U256,
@@ -38,7 +38,7 @@ pub struct UniswapV4State {
     ticks: TickList,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct UniswapV4Fees {
     // Protocol fees in the zero for one direction
     zero_for_one: u32,
@@ -86,6 +86,10 @@ impl UniswapV4State {
         amount_specified: I256,
         sqrt_price_limit: Option<U256>,
     ) -> Result<SwapResults, SimulationError> {
+        // The price is set when the pool is initialized, before any liquidity is added
+        if self.sqrt_price == U256::ZERO {
+            return Err(SimulationError::PoolNotInitialized("No price".to_string()));
+        }
         if self.liquidity == 0 {
             return Err(SimulationError::RecoverableError("No liquidity".to_string()));
         }
@@ -254,11 +258,14 @@ impl ProtocolSim for UniswapV4State {
         token_out: &Token,
     ) -> Result<GetAmountOutResult, SimulationError> {
         let zero_for_one = token_in < token_out;
-        let amount_specified = I256::checked_from_sign_and_abs(
-            Sign::Positive,
-            U256::from_be_slice(&amount_in.to_bytes_be()),
-        )
-        .expect("UniswapV4 I256 overflow");
+        let amount_specified =
+            I256::checked_from_sign_and_abs(Sign::Positive, checked_biguint_to_u256(&amount_in)?)
+                .ok_or_else(|| {
+                SimulationError::InvalidInput(
+                    format!("Amount {} exceeds 255 bits", amount_in),
+                    None,
+                )
+            })?;
 
         let result = self.swap(zero_for_one, amount_specified, None)?;
 
@@ -277,9 +284,18 @@ impl ProtocolSim for UniswapV4State {
             ),
             u256_to_biguint(result.gas_used),
             Box::new(new_state),
+        )
+        .with_fee(
+            self.fees
+                .calculate_swap_fees_pips(zero_for_one) as f64 /
+                1_000_000.0,
         ))
     }
 
+    fn state_fingerprint(&self) -> u64 {
+        fingerprint(self)
+    }
+
     fn delta_transition(
         &mut self,
         delta: ProtocolStateDelta,
@@ -0,0 +1,395 @@
+//! Swaps and snapshot decoding shared by the concentrated liquidity pools
+//!
+//! Uniswap V3 and Algebra pools run the same swap loop over their initialized ticks. They only
+//! differ in where the fee comes from, a fixed tier or a dynamic fee kept in the state, and in the
+//! gas a swap costs.
+use std::collections::HashMap;
+
+use alloy_primitives::{Sign, I256, U256};
+use tycho_core::Bytes;
+
+use super::{
+    i24_be_bytes_to_i32, liquidity_math, swap_math,
+    tick_list::{TickInfo, TickList, TickListErrorKind},
+    tick_math::{
+        get_sqrt_ratio_at_tick, get_tick_at_sqrt_ratio, MAX_SQRT_RATIO, MAX_TICK, MIN_SQRT_RATIO,
+        MIN_TICK,
+    },
+    StepComputation, SwapResults, SwapState,
+};
+use crate::{
+    evm::protocol::{
+        safe_math::{safe_add_u256, safe_sub_u256},
+        u256_num::u256_to_biguint,
+    },
+    protocol::{
+        errors::{InvalidSnapshotError, SimulationError},
+        models::GetAmountOutResult,
+        state::ProtocolSim,
+    },
+};
+
+/// Gas used by a swap: a fixed cost and a cost for every step of the swap loop.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SwapGas {
+    pub(crate) base: u64,
+    pub(crate) per_step: u64,
+}
+
+/// A concentrated liquidity pool at its current price, see `ConcentratedPool::swap`.
+#[derive(Debug)]
+pub(crate) struct ConcentratedPool<'a> {
+    pub(crate) liquidity: u128,
+    pub(crate) sqrt_price: U256,
+    pub(crate) tick: i32,
+    pub(crate) ticks: &'a TickList,
+    /// The swap fee, in hundredths of a bip
+    pub(crate) fee: u32,
+    pub(crate) gas: SwapGas,
+}
+
+impl ConcentratedPool<'_> {
+    /// Swaps `amount_specified`, exact in if positive and exact out if negative, up to
+    /// `sqrt_price_limit` or the price bounds if none is given.
+    ///
+    /// If the swap runs out of known ticks, the error carries the amount swapped so far with the
+    /// state built by `partial_state`.
+    pub(crate) fn swap(
+        &self,
+        zero_for_one: bool,
+        amount_specified: I256,
+        sqrt_price_limit: Option<U256>,
+        partial_state: impl FnOnce(&SwapResults) -> Box<dyn ProtocolSim>,
+    ) -> Result<SwapResults, SimulationError> {
+        // The price is set when the pool is initialized, before any liquidity is added
+        if self.sqrt_price == U256::ZERO {
+            return Err(SimulationError::PoolNotInitialized("No price".to_string()));
+        }
+        if self.liquidity == 0 {
+            return Err(SimulationError::RecoverableError("No liquidity".to_string()));
+        }
+        let price_limit = if let Some(limit) = sqrt_price_limit {
+            limit
+        } else if zero_for_one {
+            safe_add_u256(MIN_SQRT_RATIO, U256::from(1u64))?
+        } else {
+            safe_sub_u256(MAX_SQRT_RATIO, U256::from(1u64))?
+        };
+
+        let valid_limit = if zero_for_one {
+            price_limit > MIN_SQRT_RATIO && price_limit < self.sqrt_price
+        } else {
+            price_limit < MAX_SQRT_RATIO && price_limit > self.sqrt_price
+        };
+        if !valid_limit {
+            return Err(SimulationError::InvalidInput(
+                format!(
+                    "Price limit {} can't be reached from price {} swapping {}",
+                    price_limit,
+                    self.sqrt_price,
+                    if zero_for_one { "token0 for token1" } else { "token1 for token0" }
+                ),
+                None,
+            ));
+        }
+
+        let exact_input = amount_specified > I256::from_raw(U256::from(0u64));
+
+        let mut state = SwapState {
+            amount_remaining: amount_specified,
+            amount_calculated: I256::from_raw(U256::from(0u64)),
+            sqrt_price: self.sqrt_price,
+            tick: self.tick,
+            liquidity: self.liquidity,
+        };
+        let mut gas_used = U256::from(self.gas.base);
+
+        while state.amount_remaining != I256::from_raw(U256::from(0u64)) &&
+            state.sqrt_price != price_limit
+        {
+            let (mut next_tick, initialized) = match self
+                .ticks
+                .next_initialized_tick_within_one_word(state.tick, zero_for_one)
+            {
+                Ok((tick, init)) => (tick, init),
+                Err(tick_err) => match tick_err.kind {
+                    TickListErrorKind::TicksExeeded => {
+                        let partial = SwapResults {
+                            amount_calculated: state.amount_calculated,
+                            sqrt_price: state.sqrt_price,
+                            liquidity: state.liquidity,
+                            tick: state.tick,
+                            gas_used,
+                        };
+                        return Err(SimulationError::InvalidInput(
+                            "Ticks exceeded".into(),
+                            Some(GetAmountOutResult::new(
+                                u256_to_biguint(state.amount_calculated.abs().into_raw()),
+                                u256_to_biguint(gas_used),
+                                partial_state(&partial),
+                            )),
+                        ));
+                    }
+                    _ => return Err(SimulationError::FatalError("Unknown error".to_string())),
+                },
+            };
+
+            next_tick = next_tick.clamp(MIN_TICK, MAX_TICK);
+
+            let sqrt_price_next = get_sqrt_ratio_at_tick(next_tick)?;
+            let (sqrt_price, amount_in, amount_out, fee_amount) = swap_math::compute_swap_step(
+                state.sqrt_price,
+                get_sqrt_ratio_target(sqrt_price_next, price_limit, zero_for_one),
+                state.liquidity,
+                state.amount_remaining,
+                self.fee,
+            )?;
+            state.sqrt_price = sqrt_price;
+
+            let step = StepComputation {
+                sqrt_price_start: state.sqrt_price,
+                tick_next: next_tick,
+                initialized,
+                sqrt_price_next,
+                amount_in,
+                amount_out,
+                fee_amount,
+            };
+            if exact_input {
+                state.amount_remaining -= I256::checked_from_sign_and_abs(
+                    Sign::Positive,
+                    safe_add_u256(step.amount_in, step.fee_amount)?,
+                )
+                .unwrap();
+                state.amount_calculated -=
+                    I256::checked_from_sign_and_abs(Sign::Positive, step.amount_out).unwrap();
+            } else {
+                state.amount_remaining +=
+                    I256::checked_from_sign_and_abs(Sign::Positive, step.amount_out).unwrap();
+                state.amount_calculated += I256::checked_from_sign_and_abs(
+                    Sign::Positive,
+                    safe_add_u256(step.amount_in, step.fee_amount)?,
+                )
+                .unwrap();
+            }
+            if state.sqrt_price == step.sqrt_price_next {
+                if step.initialized {
+                    let liquidity_raw = self
+                        .ticks
+                        .get_tick(step.tick_next)
+                        .unwrap()
+                        .net_liquidity;
+                    let liquidity_net = if zero_for_one { -liquidity_raw } else { liquidity_raw };
+                    state.liquidity =
+                        liquidity_math::add_liquidity_delta(state.liquidity, liquidity_net);
+                }
+                state.tick = if zero_for_one { step.tick_next - 1 } else { step.tick_next };
+            } else if state.sqrt_price != step.sqrt_price_start {
+                state.tick = get_tick_at_sqrt_ratio(state.sqrt_price)?;
+            }
+            gas_used = safe_add_u256(gas_used, U256::from(self.gas.per_step))?;
+        }
+        Ok(SwapResults {
+            amount_calculated: state.amount_calculated,
+            sqrt_price: state.sqrt_price,
+            liquidity: state.liquidity,
+            tick: state.tick,
+            gas_used,
+        })
+    }
+}
+
+fn get_sqrt_ratio_target(
+    sqrt_price_next: U256,
+    sqrt_price_limit: U256,
+    zero_for_one: bool,
+) -> U256 {
+    let cond1 = if zero_for_one {
+        sqrt_price_next < sqrt_price_limit
+    } else {
+        sqrt_price_next > sqrt_price_limit
+    };
+
+    if cond1 {
+        sqrt_price_limit
+    } else {
+        sqrt_price_next
+    }
+}
+
+/// Liquidity, price and ticks of a pool, decoded from the attributes of a tycho snapshot, see
+/// `decode_snapshot`.
+#[derive(Debug, PartialEq)]
+pub(crate) struct PoolSnapshot {
+    pub(crate) liquidity: u128,
+    pub(crate) sqrt_price: U256,
+    pub(crate) tick: i32,
+    /// The initialized ticks, sorted by index
+    pub(crate) ticks: Vec<TickInfo>,
+}
+
+/// Decodes the `liquidity`, `sqrt_price_x96`, `tick` and `ticks/{index}/net_liquidity` attributes
+/// of a snapshot.
+pub(crate) fn decode_snapshot(
+    attributes: &HashMap<String, Bytes>,
+) -> Result<PoolSnapshot, InvalidSnapshotError> {
+    let liq = attributes
+        .get("liquidity")
+        .ok_or_else(|| InvalidSnapshotError::MissingAttribute("liquidity".to_string()))?
+        .clone();
+
+    // This is a hotfix because if the liquidity has never been updated after creation, it's
+    // currently encoded as H256::zero(), therefore, we can't decode this as u128.
+    // We can remove this once it has been fixed on the tycho side.
+    let liq_16_bytes = if liq.len() == 32 {
+        // Make sure it only happens for 0 values, otherwise error.
+        if liq == Bytes::zero(32) {
+            Bytes::from([0; 16])
+        } else {
+            return Err(InvalidSnapshotError::ValueError(format!(
+                "Liquidity bytes too long for {}, expected 16",
+                liq
+            )));
+        }
+    } else {
+        liq
+    };
+
+    let liquidity = u128::from(liq_16_bytes);
+
+    let sqrt_price = U256::from_be_slice(
+        attributes
+            .get("sqrt_price_x96")
+            .ok_or_else(|| InvalidSnapshotError::MissingAttribute("sqrt_price".to_string()))?,
+    );
+
+    let tick = attributes
+        .get("tick")
+        .ok_or_else(|| InvalidSnapshotError::MissingAttribute("tick".to_string()))?
+        .clone();
+
+    // This is a hotfix because if the tick has never been updated after creation, it's
+    // currently encoded as H256::zero(), therefore, we can't decode this as i32. We can
+    // remove this this will be fixed on the tycho side.
+    let ticks_4_bytes = if tick.len() == 32 {
+        // Make sure it only happens for 0 values, otherwise error.
+        if tick == Bytes::zero(32) {
+            Bytes::from([0; 4])
+        } else {
+            return Err(InvalidSnapshotError::ValueError(format!(
+                "Tick bytes too long for {}, expected 4",
+                tick
+            )));
+        }
+    } else {
+        tick
+    };
+    let tick = i24_be_bytes_to_i32(&ticks_4_bytes);
+
+    let ticks: Result<Vec<_>, _> = attributes
+        .iter()
+        .filter_map(|(key, value)| {
+            if key.starts_with("ticks/") {
+                Some(
+                    key.split('/')
+                        .nth(1)?
+                        .parse::<i32>()
+                        .map(|tick_index| TickInfo::new(tick_index, i128::from(value.clone())))
+                        .map_err(|err| InvalidSnapshotError::ValueError(err.to_string())),
+                )
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    let mut ticks = match ticks {
+        Ok(ticks) if !ticks.is_empty() => ticks
+            .into_iter()
+            .filter(|t| t.net_liquidity != 0)
+            .collect::<Vec<_>>(),
+        _ => return Err(InvalidSnapshotError::MissingAttribute("tick_liquidities".to_string())),
+    };
+
+    ticks.sort_by_key(|tick| tick.index);
+
+    Ok(PoolSnapshot { liquidity, sqrt_price, tick, ticks })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn ticks() -> TickList {
+        TickList::from(
+            10,
+            vec![
+                TickInfo::new(255760, 1759015528199933i128),
+                TickInfo::new(255900, -1759015528199933i128),
+            ],
+        )
+    }
+
+    fn pool(ticks: &TickList) -> ConcentratedPool<'_> {
+        ConcentratedPool {
+            liquidity: 1759015528199933,
+            sqrt_price: U256::from_str("28437325270877025820973479874632004").unwrap(),
+            tick: 255830,
+            ticks,
+            fee: 500,
+            gas: SwapGas { base: 100_000, per_step: 1_000 },
+        }
+    }
+
+    #[test]
+    fn test_swap_invalid_price_limit() {
+        let ticks = ticks();
+        let pool = pool(&ticks);
+
+        // Selling token0 can only lower the price
+        let res = pool.swap(true, I256::from_raw(U256::from(1000)), Some(U256::MAX), |_| {
+            unreachable!("The swap fails before any step")
+        });
+
+        assert!(matches!(res, Err(SimulationError::InvalidInput(_, None))));
+    }
+
+    #[test]
+    fn test_swap_gas() {
+        let ticks = ticks();
+        let pool = pool(&ticks);
+
+        let res = pool
+            .swap(true, I256::from_raw(U256::from(1000)), None, |_| unreachable!("Ticks suffice"))
+            .unwrap();
+
+        assert!(res.amount_calculated < I256::ZERO);
+        assert_eq!(res.gas_used, U256::from(101_000));
+    }
+
+    #[test]
+    fn test_decode_snapshot() {
+        let attributes = HashMap::from([
+            ("liquidity".to_string(), Bytes::from(100_u128.to_be_bytes().to_vec())),
+            ("sqrt_price_x96".to_string(), Bytes::from(200_u64.to_be_bytes().to_vec())),
+            ("tick".to_string(), Bytes::zero(32)),
+            ("ticks/60/net_liquidity".to_string(), Bytes::from(400_i128.to_be_bytes().to_vec())),
+            ("ticks/-60/net_liquidity".to_string(), Bytes::from(0_i128.to_be_bytes().to_vec())),
+            ("ticks/0/net_liquidity".to_string(), Bytes::from((-5_i128).to_be_bytes().to_vec())),
+        ]);
+
+        let snapshot = decode_snapshot(&attributes).unwrap();
+
+        assert_eq!(
+            snapshot,
+            PoolSnapshot {
+                liquidity: 100,
+                sqrt_price: U256::from(200),
+                tick: 0,
+                ticks: vec![TickInfo::new(0, -5), TickInfo::new(60, 400)],
+            }
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use alloy_primitives::{I256, U256};
 use tycho_core::Bytes;
 
+pub(crate) mod concentrated_liquidity;
 pub(crate) mod liquidity_math;
 mod solidity_math;
 pub(crate) mod sqrt_price_math;
@@ -4,7 +4,7 @@ use alloy_primitives::U256;
 
 use super::tick_math;
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct TickInfo {
     pub(crate) index: i32,
     pub(crate) net_liquidity: i128,
@@ -39,7 +39,7 @@ pub(crate) enum TickListErrorKind {
     TicksExeeded,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub(crate) struct TickList {
     tick_spacing: u16,
     ticks: Vec<TickInfo>,
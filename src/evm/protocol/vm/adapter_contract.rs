@@ -3,39 +3,158 @@ use std::{
     fmt::Debug,
 };
 
-use alloy_primitives::{Address, U256};
+use alloy_primitives::{hex, Address, U256};
 use alloy_sol_types::SolValue;
 use revm::DatabaseRef;
+use thiserror::Error;
 
 use super::{
-    erc20_token::Overwrites, models::Capability, tycho_simulation_contract::TychoSimulationContract,
+    erc20_token::Overwrites,
+    models::{Capability, OrderSide},
+    tycho_simulation_contract::TychoSimulationContract,
 };
 use crate::{
     evm::{
         account_storage::StateUpdate,
-        engine_db::engine_db_interface::EngineDatabaseInterface,
-        protocol::{u256_num::u256_to_f64, vm::utils::string_to_bytes32},
+        engine_db::{engine_db_interface::EngineDatabaseInterface, simulation_db::BlockHeader},
+        protocol::{
+            u256_num::u256_to_f64,
+            vm::utils::{coerce_error, string_to_bytes32},
+        },
+        StorageMap,
     },
-    protocol::errors::SimulationError,
+    protocol::{clock, errors::SimulationError},
 };
 
-#[derive(Debug)]
+/// Errors decoding the return data of adapter calls.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum AdapterDecodeError {
+    #[error("Malformed return data of {function}: expected {expected}, found 0x{found}")]
+    MalformedReturnData { function: &'static str, expected: &'static str, found: String },
+    #[error("Price fraction has a zero denominator")]
+    ZeroDenominator,
+    #[error("Empty price list")]
+    EmptyPriceList,
+}
+
+impl AdapterDecodeError {
+    fn malformed(function: &'static str, expected: &'static str, data: &[u8]) -> Self {
+        AdapterDecodeError::MalformedReturnData { function, expected, found: hex::encode(data) }
+    }
+}
+
+impl From<AdapterDecodeError> for SimulationError {
+    fn from(error: AdapterDecodeError) -> Self {
+        SimulationError::FatalError(format!("Adapter call failed: {}", error))
+    }
+}
+
+/// Result of an adapter `swap` call.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Trade {
-    pub received_amount: U256,
-    pub gas_used: U256,
+    /// The amount computed by the swap: the amount of the buy token received for sell orders, the
+    /// amount of the sell token paid for buy orders
+    pub amount: U256,
+    /// Gas used by the swap, as reported by the adapter
+    pub gas: U256,
+    /// Marginal price after the swap
     pub price: f64,
 }
 
+impl Trade {
+    /// Decodes the return data of the adapter's `swap` function:
+    /// `(uint256 amount, uint256 gasUsed, (uint256 numerator, uint256 denominator) price)`.
+    pub fn decode(data: &[u8]) -> Result<Self, AdapterDecodeError> {
+        let (amount, gas, price_fraction) = SwapReturn::abi_decode(data, true).map_err(|_| {
+            AdapterDecodeError::malformed("swap", "(uint256,uint256,(uint256,uint256))", data)
+        })?;
+        let price = fraction_to_f64(price_fraction)?;
+        Ok(Trade { amount, gas, price })
+    }
+
+    /// Decodes the return data of a V2 adapter's `swap` function, which returns the price as a
+    /// fixed point number: `(uint256 amount, uint256 gasUsed, uint256 price)`.
+    pub fn decode_fixed_point(data: &[u8]) -> Result<Self, AdapterDecodeError> {
+        let (amount, gas, price) = SwapReturnV2::abi_decode(data, true).map_err(|_| {
+            AdapterDecodeError::malformed("swap", "(uint256,uint256,uint256)", data)
+        })?;
+        Ok(Trade { amount, gas, price: fixed_point_to_f64(price) })
+    }
+
+    /// Returns the gas reported by the adapter, or `measured_gas` if the adapter doesn't report
+    /// any. Adapters only report the gas of the underlying protocol, so this is the relevant
+    /// estimate for routing.
+    pub fn gas_or(&self, measured_gas: u64) -> U256 {
+        if self.gas.is_zero() {
+            U256::from(measured_gas)
+        } else {
+            self.gas
+        }
+    }
+}
+
+/// Decodes the return data of a V1 adapter's `price` function.
+pub(crate) fn decode_price_fractions(data: &[u8]) -> Result<Vec<f64>, AdapterDecodeError> {
+    let fractions = PriceReturn::abi_decode(data, true)
+        .map_err(|_| AdapterDecodeError::malformed("price", "(uint256,uint256)[]", data))?;
+    non_empty(
+        fractions
+            .into_iter()
+            .map(fraction_to_f64)
+            .collect::<Result<Vec<_>, _>>()?,
+    )
+}
+
+/// Decodes the return data of a V2 adapter's `price` function.
+pub(crate) fn decode_fixed_point_prices(data: &[u8]) -> Result<Vec<f64>, AdapterDecodeError> {
+    let prices = PriceReturnV2::abi_decode(data, true)
+        .map_err(|_| AdapterDecodeError::malformed("price", "uint256[]", data))?;
+    non_empty(
+        prices
+            .into_iter()
+            .map(fixed_point_to_f64)
+            .collect(),
+    )
+}
+
+fn non_empty(prices: Vec<f64>) -> Result<Vec<f64>, AdapterDecodeError> {
+    if prices.is_empty() {
+        return Err(AdapterDecodeError::EmptyPriceList);
+    }
+    Ok(prices)
+}
+
+/// Converts an 18 decimals fixed point price returned by an adapter into a float.
+fn fixed_point_to_f64(price: U256) -> f64 {
+    u256_to_f64(price) / 1e18
+}
+
+/// Converts a price fraction returned by an adapter into a float.
+fn fraction_to_f64((numerator, denominator): (U256, U256)) -> Result<f64, AdapterDecodeError> {
+    if denominator.is_zero() {
+        return Err(AdapterDecodeError::ZeroDenominator);
+    }
+    Ok(u256_to_f64(numerator) / u256_to_f64(denominator))
+}
+
 /// Type aliases are defined to ensure compatibility with `alloy_sol_types::abi_decode`,
 /// which requires explicit types matching the Solidity ABI. These aliases correspond
 /// directly to the outputs of the contract's functions.
 /// These types ensure correct decoding and alignment with the ABI.
 type PriceReturn = Vec<(U256, U256)>;
 type SwapReturn = (U256, U256, (U256, U256));
+type PriceReturnV2 = Vec<U256>;
+type SwapReturnV2 = (U256, U256, U256);
 type LimitsReturn = Vec<U256>;
 type CapabilitiesReturn = Vec<U256>;
 type MinGasUsageReturn = U256;
 
+/// Returns the header adapter calls run in: block `number` at the current time. Pool states keep
+/// the header of the block they were created at, so its timestamp may be long outdated.
+fn current_block(number: u64) -> BlockHeader {
+    BlockHeader { number, timestamp: clock::now(), ..Default::default() }
+}
+
 /// An implementation of `TychoSimulationContract` specific to the `AdapterContract` ABI interface,
 /// providing methods for price calculations, token swaps, capability checks, and more.
 ///
@@ -44,8 +163,10 @@ type MinGasUsageReturn = U256;
 /// contract's interface, enabling seamless integration with tycho's simulation environment.
 ///
 /// # Methods
-/// - `price`: Calculates price information for a token pair within the adapter.
-/// - `swap`: Simulates a token swap operation, returning details about the trade and state updates.
+/// - `price`: Calculates price information for a token pair within the adapter, along with the gas
+///   used to compute it.
+/// - `swap`: Simulates a token swap operation of either side, returning details about the trade and
+///   state updates.
 /// - `get_limits`: Retrieves the trade limits for a given token pair.
 /// - `get_capabilities`: Checks the capabilities of the adapter for a specific token pair.
 /// - `min_gas_usage`: Queries the minimum gas usage required for operations within the adapter.
@@ -62,20 +183,17 @@ where
         amounts: Vec<U256>,
         block: u64,
         overwrites: Option<HashMap<Address, Overwrites>>,
-    ) -> Result<Vec<f64>, SimulationError> {
+    ) -> Result<(Vec<f64>, u64), SimulationError> {
         let args = (string_to_bytes32(pair_id)?, sell_token, buy_token, amounts);
         let selector = "price(bytes32,address,address,uint256[])";
 
-        let res = self
-            .call(selector, args, block, None, overwrites, None, U256::from(0u64))?
-            .return_value;
-
-        let decoded: PriceReturn = PriceReturn::abi_decode(&res, true).map_err(|e| {
-            SimulationError::FatalError(format!("Failed to decode price return value: {:?}", e))
-        })?;
+        let res =
+            self.call(selector, args, &current_block(block), overwrites, None, U256::from(0u64))?;
 
-        let price = self.calculate_price(decoded)?;
-        Ok(price)
+        let prices = self
+            .version()
+            .decode_prices(&res.return_value)?;
+        Ok((prices, res.simulation_result.gas_used))
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -84,36 +202,29 @@ where
         pair_id: &str,
         sell_token: Address,
         buy_token: Address,
-        is_buy: bool,
+        side: OrderSide,
         amount: U256,
         block: u64,
         overwrites: Option<HashMap<Address, HashMap<U256, U256>>>,
-    ) -> Result<(Trade, HashMap<Address, StateUpdate>), SimulationError> {
+        caller: Option<Address>,
+    ) -> Result<(Trade, StorageMap<Address, StateUpdate>), SimulationError> {
+        let is_buy = side == OrderSide::Buy;
         let args = (string_to_bytes32(pair_id)?, sell_token, buy_token, is_buy, amount);
         let selector = "swap(bytes32,address,address,uint8,uint256)";
 
-        let res = self.call(selector, args, block, None, overwrites, None, U256::from(0u64))?;
+        let res =
+            self.call(selector, args, &current_block(block), overwrites, caller, U256::from(0u64))?;
 
-        let decoded: SwapReturn = SwapReturn::abi_decode(&res.return_value, true).map_err(|_| {
-            SimulationError::FatalError(format!(
-                "Adapter swap call failed: Failed to decode return value. Expected amount, gas, and price elements in the format (U256, U256, (U256, U256)). Found {:?}",
-                        &res.return_value[..],
-            ))
-        })?;
-
-        let (received_amount, gas_used, price_elements) = decoded;
-
-        let price = self
-            .calculate_price(vec![price_elements])?
-            .first()
-            .cloned()
-            .ok_or_else(|| {
-                SimulationError::FatalError(
-                    "Adapter swap call failed: An empty price list was returned".into(),
-                )
-            })?;
+        let mut trade = self
+            .version()
+            .decode_trade(&res.return_value)?;
+        trade.gas = trade.gas_or(res.simulation_result.gas_used);
+        // The state updates become overwrites of the pool state after the swap
+        res.simulation_result
+            .committable_state_updates()
+            .map_err(|e| coerce_error(&e, "pool_state", None))?;
 
-        Ok((Trade { received_amount, gas_used, price }, res.simulation_result.state_updates))
+        Ok((trade, res.simulation_result.state_updates))
     }
 
     pub fn get_limits(
@@ -127,17 +238,15 @@ where
         let args = (string_to_bytes32(pair_id)?, sell_token, buy_token);
         let selector = "getLimits(bytes32,address,address)";
         let res = self
-            .call(selector, args, block, None, overwrites, None, U256::from(0u64))?
+            .call(selector, args, &current_block(block), overwrites, None, U256::from(0u64))?
             .return_value;
 
-        let decoded: LimitsReturn = LimitsReturn::abi_decode(&res, true).map_err(|e| {
-            SimulationError::FatalError(format!(
-                "Adapter get_limits call failed: Failed to decode return value: {:?}",
-                e
-            ))
-        })?;
-
-        Ok((decoded[0], decoded[1]))
+        let decoded: LimitsReturn = LimitsReturn::abi_decode(&res, true)
+            .map_err(|_| AdapterDecodeError::malformed("getLimits", "uint256[2]", &res))?;
+        match decoded[..] {
+            [sell_limit, buy_limit, ..] => Ok((sell_limit, buy_limit)),
+            _ => Err(AdapterDecodeError::malformed("getLimits", "uint256[2]", &res).into()),
+        }
     }
 
     pub fn get_capabilities(
@@ -149,7 +258,7 @@ where
         let args = (string_to_bytes32(pair_id)?, sell_token, buy_token);
         let selector = "getCapabilities(bytes32,address,address)";
         let res = self
-            .call(selector, args, 1, None, None, None, U256::from(0u64))?
+            .call(selector, args, &current_block(1), None, None, U256::from(0u64))?
             .return_value;
         let decoded: CapabilitiesReturn =
             CapabilitiesReturn::abi_decode(&res, true).map_err(|e| {
@@ -172,7 +281,7 @@ where
         let args = ();
         let selector = "minGasUsage()";
         let res = self
-            .call(selector, args, 1, None, None, None, U256::from(0u64))?
+            .call(selector, args, &current_block(1), None, None, U256::from(0u64))?
             .return_value;
 
         let decoded: MinGasUsageReturn =
@@ -186,21 +295,44 @@ where
             .try_into()
             .map_err(|_| SimulationError::FatalError("Decoded value exceeds u64 range".to_string()))
     }
+}
 
-    fn calculate_price(&self, fractions: Vec<(U256, U256)>) -> Result<Vec<f64>, SimulationError> {
-        fractions
-            .into_iter()
-            .map(|(numerator, denominator)| {
-                if denominator.is_zero() {
-                    Err(SimulationError::FatalError(
-                        "Adapter price calculation failed: Denominator is zero".to_string(),
-                    ))
-                } else {
-                    let num_f64 = u256_to_f64(numerator);
-                    let den_f64 = u256_to_f64(denominator);
-                    Ok(num_f64 / den_f64)
-                }
-            })
-            .collect()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_trade() {
+        let data =
+            (U256::from(100), U256::from(50_000), (U256::from(3), U256::from(2))).abi_encode();
+
+        let trade = Trade::decode(&data).unwrap();
+
+        assert_eq!(trade, Trade { amount: U256::from(100), gas: U256::from(50_000), price: 1.5 });
+        assert_eq!(trade.gas_or(70_000), U256::from(50_000));
+    }
+
+    #[test]
+    fn test_decode_trade_malformed() {
+        let data = U256::from(100).abi_encode();
+
+        assert!(matches!(
+            Trade::decode(&data),
+            Err(AdapterDecodeError::MalformedReturnData { function: "swap", .. })
+        ));
+    }
+
+    #[test]
+    fn test_decode_trade_zero_denominator() {
+        let data = (U256::from(100), U256::ZERO, (U256::from(3), U256::ZERO)).abi_encode();
+
+        assert_eq!(Trade::decode(&data), Err(AdapterDecodeError::ZeroDenominator));
+    }
+
+    #[test]
+    fn test_trade_gas_fallback() {
+        let trade = Trade { amount: U256::from(1), gas: U256::ZERO, price: 1.0 };
+
+        assert_eq!(trade.gas_or(70_000), U256::from(70_000));
     }
 }
@@ -0,0 +1,126 @@
+//! Executor addresses adapter calls are simulated from
+//!
+//! Some adapters only accept swaps from allowlisted executors. A `SwapCaller` configures the
+//! address the calls are sent from and the allowlists it has to be part of; the allowlist entries
+//! are set through storage overrides on every call. Callers are configured per protocol with
+//! `register_swap_caller` and picked up when the protocol's pools are decoded.
+use std::{collections::HashMap, sync::RwLock};
+
+use alloy_primitives::{Address, U256};
+use lazy_static::lazy_static;
+
+use super::erc20_token::Overwrites;
+use crate::evm::ContractCompiler;
+
+lazy_static! {
+    /// Swap callers by protocol name, without the `vm:` prefix
+    static ref SWAP_CALLERS: RwLock<HashMap<String, SwapCaller>> = RwLock::new(HashMap::new());
+}
+
+/// An `address => bool` mapping a contract checks callers against.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Allowlist {
+    /// The contract holding the mapping
+    pub contract: Address,
+    /// Storage slot of the mapping
+    pub slot: U256,
+    pub compiler: ContractCompiler,
+}
+
+/// Address adapter calls are simulated from, and the allowlists it must be part of.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SwapCaller {
+    pub address: Address,
+    pub allowlists: Vec<Allowlist>,
+}
+
+impl SwapCaller {
+    pub fn new(address: Address) -> Self {
+        Self { address, allowlists: Vec::new() }
+    }
+
+    /// Adds the `address => bool` mapping at `slot` of `contract` to the allowlists the caller is
+    /// set in.
+    pub fn allowlisted_in(
+        mut self,
+        contract: Address,
+        slot: U256,
+        compiler: ContractCompiler,
+    ) -> Self {
+        self.allowlists
+            .push(Allowlist { contract, slot, compiler });
+        self
+    }
+
+    /// Storage overrides setting `caller` in all allowlists of the caller. Calls can be sent from
+    /// another address than the caller's, e.g. the sender of a `QuoteContext`, which then has to
+    /// be allowlisted instead.
+    pub(crate) fn overwrites(&self, caller: Address) -> HashMap<Address, Overwrites> {
+        let mut overwrites: HashMap<Address, Overwrites> = HashMap::new();
+        for allowlist in &self.allowlists {
+            let slot = allowlist
+                .compiler
+                .compute_map_slot(&allowlist.slot.to_be_bytes::<32>(), &caller.into_word().0);
+            overwrites
+                .entry(allowlist.contract)
+                .or_default()
+                .insert(slot, U256::from(1));
+        }
+        overwrites
+    }
+}
+
+/// Configures the caller adapter calls of `protocol` are simulated from, for pools decoded from
+/// then on. `protocol` is the protocol system without the `vm:` prefix.
+pub fn register_swap_caller(protocol: &str, caller: SwapCaller) {
+    SWAP_CALLERS
+        .write()
+        .unwrap()
+        .insert(protocol.to_string(), caller);
+}
+
+/// Returns the caller configured for `protocol`, if any.
+pub fn swap_caller(protocol: &str) -> Option<SwapCaller> {
+    SWAP_CALLERS
+        .read()
+        .unwrap()
+        .get(protocol)
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_overwrites() {
+        let executor = Address::from_str("0x0000000000000000000000000000000000000abc").unwrap();
+        let router = Address::from_str("0x0000000000000000000000000000000000000def").unwrap();
+        let caller = SwapCaller::new(executor)
+            .allowlisted_in(router, U256::from(3), ContractCompiler::Solidity)
+            .allowlisted_in(router, U256::from(5), ContractCompiler::Vyper);
+
+        let overwrites = caller.overwrites(executor);
+
+        let solidity_slot = ContractCompiler::Solidity
+            .compute_map_slot(&U256::from(3).to_be_bytes::<32>(), &executor.into_word().0);
+        let vyper_slot = ContractCompiler::Vyper
+            .compute_map_slot(&U256::from(5).to_be_bytes::<32>(), &executor.into_word().0);
+        assert_eq!(
+            overwrites[&router],
+            HashMap::from([(solidity_slot, U256::from(1)), (vyper_slot, U256::from(1))])
+        );
+    }
+
+    #[test]
+    fn test_register_swap_caller() {
+        let caller = SwapCaller::new(Address::repeat_byte(0x11));
+
+        register_swap_caller("test_protocol", caller.clone());
+
+        assert_eq!(swap_caller("test_protocol"), Some(caller));
+        assert_eq!(swap_caller("other_protocol"), None);
+    }
+}
@@ -157,8 +157,7 @@ where
                 .call(
                     "balanceOf(address)",
                     *EXTERNAL_ACCOUNT,
-                    block.number,
-                    Some(block.timestamp),
+                    block,
                     Some(overwrite_factory.get_overwrites()),
                     Some(*EXTERNAL_ACCOUNT),
                     U256::from(0u64),
@@ -197,8 +196,7 @@ where
             .call(
                 "allowance(address,address)",
                 (*EXTERNAL_ACCOUNT, *SPENDER),
-                block.number,
-                Some(block.timestamp),
+                block,
                 Some(overwrite_factory.get_overwrites()),
                 Some(*EXTERNAL_ACCOUNT),
                 U256::from(0u64),
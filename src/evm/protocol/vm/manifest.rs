@@ -0,0 +1,172 @@
+//! Integrity of adapter bytecode
+//!
+//! VM pools are quoted by running a protocol's swap adapter, so a changed adapter silently
+//! changes every quote of the protocol. The code hashes of the adapter releases this crate was
+//! tested with are embedded in `ADAPTER_MANIFEST`, per protocol and version. When a pool is
+//! built with `EVMPoolStateBuilder::adapter_protocol`, as decoded pools are, `verify_adapter`
+//! checks the adapter bytecode it's given against the releases of that protocol. What happens on
+//! a mismatch is configured with `set_adapter_integrity`: by default it's logged once per
+//! bytecode and the pool is built anyway.
+//!
+//! Adapters of protocols without manifest entries, e.g. ones added by users, are only checked
+//! if their releases are registered with `register_adapter_release`.
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Mutex, RwLock},
+};
+
+use alloy_primitives::{b256, keccak256, B256};
+use lazy_static::lazy_static;
+use tracing::warn;
+
+use crate::protocol::errors::SimulationError;
+
+/// A released adapter bytecode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdapterRelease {
+    /// Protocol system without the `vm:` prefix
+    pub protocol: &'static str,
+    pub version: &'static str,
+    /// Keccak hash of the adapter's runtime bytecode
+    pub code_hash: B256,
+}
+
+/// The adapter releases bundled with this crate, see `constants::get_adapter_file`
+pub const ADAPTER_MANIFEST: &[AdapterRelease] = &[
+    AdapterRelease {
+        protocol: "balancer_v2",
+        version: "1",
+        code_hash: b256!("4f1ffae37976e10586286bc85f586b723f1db2e7d6d23ac2c01d0bd518e87f40"),
+    },
+    AdapterRelease {
+        protocol: "curve",
+        version: "1",
+        code_hash: b256!("2763024a8bb1b3c7c3327fed258d5b573af9e1419ade257b39fb3d203cddf3f5"),
+    },
+];
+
+/// How adapters not matching the manifest are handled, see `set_adapter_integrity`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AdapterIntegrity {
+    /// Adapters are not checked
+    Off,
+    /// Mismatching adapters are logged once per bytecode and used anyway
+    #[default]
+    Warn,
+    /// Pools with mismatching adapters fail to decode
+    Refuse,
+}
+
+lazy_static! {
+    static ref INTEGRITY: RwLock<AdapterIntegrity> = RwLock::new(AdapterIntegrity::default());
+    /// Releases registered at runtime, by protocol name
+    static ref RELEASES: RwLock<HashMap<String, Vec<(String, B256)>>> = RwLock::new(HashMap::new());
+    /// Code hashes of mismatching adapters already warned about
+    static ref WARNED: Mutex<HashSet<B256>> = Mutex::new(HashSet::new());
+}
+
+/// Sets how adapters not matching the manifest are handled, for pools decoded from then on.
+pub fn set_adapter_integrity(integrity: AdapterIntegrity) {
+    *INTEGRITY.write().unwrap() = integrity;
+}
+
+/// Adds a release of `protocol`'s adapter to the manifest, e.g. for protocols whose adapters
+/// aren't bundled with this crate. `protocol` is the protocol system without the `vm:` prefix.
+pub fn register_adapter_release(protocol: &str, version: &str, code_hash: B256) {
+    RELEASES
+        .write()
+        .unwrap()
+        .entry(protocol.to_string())
+        .or_default()
+        .push((version.to_string(), code_hash));
+}
+
+/// Checks the adapter `bytecode` of `protocol` against the manifest, see module docs. Returns the
+/// version of the matching release, `None` if none matches and the mismatch is tolerated.
+///
+/// # Errors
+///
+/// `SimulationError::FatalError` if the bytecode matches no release of a protocol with releases
+/// and adapter integrity is set to `Refuse`.
+pub fn verify_adapter(protocol: &str, bytecode: &[u8]) -> Result<Option<String>, SimulationError> {
+    verify_adapter_with(*INTEGRITY.read().unwrap(), protocol, bytecode)
+}
+
+fn verify_adapter_with(
+    integrity: AdapterIntegrity,
+    protocol: &str,
+    bytecode: &[u8],
+) -> Result<Option<String>, SimulationError> {
+    if integrity == AdapterIntegrity::Off {
+        return Ok(None);
+    }
+    let mut releases: Vec<(String, B256)> = ADAPTER_MANIFEST
+        .iter()
+        .filter(|release| release.protocol == protocol)
+        .map(|release| (release.version.to_string(), release.code_hash))
+        .collect();
+    if let Some(registered) = RELEASES.read().unwrap().get(protocol) {
+        releases.extend(registered.iter().cloned());
+    }
+    if releases.is_empty() {
+        return Ok(None);
+    }
+
+    let code_hash = keccak256(bytecode);
+    if let Some((version, _)) = releases
+        .into_iter()
+        .find(|(_, hash)| *hash == code_hash)
+    {
+        return Ok(Some(version));
+    }
+    let message = format!("Adapter of {protocol} with code hash {code_hash} matches no release");
+    match integrity {
+        AdapterIntegrity::Refuse => Err(SimulationError::FatalError(message)),
+        _ => {
+            if WARNED.lock().unwrap().insert(code_hash) {
+                warn!("{message}, quotes of its pools may be wrong");
+            }
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evm::protocol::vm::constants::{BALANCER_V2, CURVE};
+
+    #[test]
+    fn test_bundled_adapters_match_manifest() {
+        for (protocol, bytecode) in [("balancer_v2", BALANCER_V2), ("curve", CURVE)] {
+            assert_eq!(
+                verify_adapter_with(AdapterIntegrity::Refuse, protocol, bytecode).unwrap(),
+                Some("1".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_mismatching_adapter() {
+        let mut tampered = CURVE.to_vec();
+        tampered.push(0);
+
+        assert!(matches!(
+            verify_adapter_with(AdapterIntegrity::Refuse, "curve", &tampered),
+            Err(SimulationError::FatalError(_))
+        ));
+        assert_eq!(verify_adapter_with(AdapterIntegrity::Warn, "curve", &tampered).unwrap(), None);
+        assert_eq!(verify_adapter_with(AdapterIntegrity::Off, "curve", &tampered).unwrap(), None);
+        // Protocols without releases aren't checked
+        assert_eq!(
+            verify_adapter_with(AdapterIntegrity::Refuse, "unreleased", &tampered).unwrap(),
+            None
+        );
+
+        register_adapter_release("custom_adapter", "2", keccak256(&tampered));
+        assert_eq!(
+            verify_adapter_with(AdapterIntegrity::Refuse, "custom_adapter", &tampered).unwrap(),
+            Some("2".to_string())
+        );
+    }
+}
@@ -1,9 +1,12 @@
 mod adapter_contract;
+pub mod caller;
 pub mod constants;
 mod erc20_token;
+pub mod manifest;
 mod models;
+pub mod quirks;
 pub mod state;
 pub mod state_builder;
 pub mod tycho_decoder;
-mod tycho_simulation_contract;
+pub(crate) mod tycho_simulation_contract;
 pub mod utils;
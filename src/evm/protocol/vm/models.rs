@@ -1,4 +1,4 @@
-use alloy_primitives::U256;
+use alloy_primitives::{Address, U256};
 use strum_macros::Display;
 
 use crate::protocol::errors::SimulationError;
@@ -23,6 +23,7 @@ use crate::protocol::errors::SimulationError;
 /// - `HardLimits`: Indicates that if we try to go over the sell limits, the pool will revert.
 /// - `MarginalPrice`: Indicates whether the pool's price function can be called with amountIn=0 to
 ///   return the current price
+/// - `AdapterV2`: Indicates that the adapter implements the V2 interface, see `AdapterVersion`.
 #[derive(Eq, PartialEq, Hash, Debug, Display, Clone)]
 pub enum Capability {
     SellSide = 1,
@@ -34,6 +35,81 @@ pub enum Capability {
     ScaledPrice = 7,
     HardLimits = 8,
     MarginalPrice = 9,
+    AdapterV2 = 10,
+}
+
+/// Which amount of a swap is given: the amount sold or the amount bought.
+#[derive(Eq, PartialEq, Hash, Debug, Display, Clone, Copy)]
+pub enum OrderSide {
+    /// The sell amount is given, the buy amount is computed
+    Sell,
+    /// The buy amount is given, the sell amount is computed
+    Buy,
+}
+
+impl OrderSide {
+    /// The capability an adapter needs to quote orders of this side.
+    pub fn capability(self) -> Capability {
+        match self {
+            OrderSide::Sell => Capability::SellSide,
+            OrderSide::Buy => Capability::BuySide,
+        }
+    }
+}
+
+/// A price an oracle stores and a pool reads at swap time, see
+/// `EVMPoolState::get_amount_out_bounds`.
+///
+/// Oracles often pack the price with other values, e.g. a timestamp, into one storage word. The
+/// price is the `bits` wide field starting `offset` bits from the least significant end of the
+/// word at `slot`.
+#[derive(Eq, PartialEq, Hash, Debug, Clone, Copy)]
+pub struct PriceOracle {
+    pub address: Address,
+    pub slot: U256,
+    pub offset: usize,
+    pub bits: usize,
+}
+
+impl PriceOracle {
+    /// An oracle storing the price in a whole storage word.
+    pub fn new(address: Address, slot: U256) -> Self {
+        Self { address, slot, offset: 0, bits: 256 }
+    }
+
+    /// An oracle storing the price in the `bits` wide field starting `offset` bits from the least
+    /// significant end of the word.
+    pub fn packed(address: Address, slot: U256, offset: usize, bits: usize) -> Self {
+        Self { address, slot, offset, bits }
+    }
+
+    /// Scales the price in `word` by `factor_bps / 10_000`, leaving the other fields of the word
+    /// untouched.
+    ///
+    /// # Errors
+    ///
+    /// Returns `SimulationError::FatalError` if the field doesn't fit the word or the scaled price
+    /// doesn't fit the field.
+    pub fn scale(&self, word: U256, factor_bps: u32) -> Result<U256, SimulationError> {
+        if self.bits == 0 || self.offset + self.bits > 256 {
+            return Err(SimulationError::FatalError(format!(
+                "Invalid oracle price field of {} bits at offset {}",
+                self.bits, self.offset
+            )));
+        }
+        let mask = U256::MAX >> (256 - self.bits);
+        let price = (word >> self.offset) & mask;
+        let scaled = price
+            .checked_mul(U256::from(factor_bps))
+            .map(|price| price / U256::from(10_000))
+            .filter(|scaled| *scaled <= mask)
+            .ok_or_else(|| {
+                SimulationError::FatalError(format!(
+                    "Oracle price {price} overflows its field when scaled"
+                ))
+            })?;
+        Ok((word & !(mask << self.offset)) | (scaled << self.offset))
+    }
 }
 
 impl Capability {
@@ -49,6 +125,7 @@ impl Capability {
             7 => Ok(Capability::ScaledPrice),
             8 => Ok(Capability::HardLimits),
             9 => Ok(Capability::MarginalPrice),
+            10 => Ok(Capability::AdapterV2),
             _ => {
                 Err(SimulationError::FatalError(format!("Unexpected Capability value: {}", value)))
             }
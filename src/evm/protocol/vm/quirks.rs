@@ -0,0 +1,149 @@
+//! Protocol specific handling of VM pools
+//!
+//! Pools of some protocols can't be simulated like the others: their adapters need more gas than
+//! simulations get by default, they reject swaps paying out to the zero address, or their tokens
+//! revert on transfers of zero. Instead of special cases in `EVMPoolState`, such handling is
+//! described as data: the `Quirks` of a protocol are configured with `register_quirks` and picked
+//! up when the protocol's pools are decoded, like swap callers.
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+};
+
+use alloy_primitives::Address;
+use lazy_static::lazy_static;
+use num_bigint::BigUint;
+use num_traits::Zero;
+
+use crate::protocol::{errors::SimulationError, models::QuoteContext};
+
+lazy_static! {
+    /// Quirks by protocol name, without the `vm:` prefix
+    static ref QUIRKS: RwLock<HashMap<String, Quirks>> = RwLock::new(HashMap::new());
+}
+
+/// How the pools of a protocol deviate from the default simulation, see module docs. The default
+/// has no quirks.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Quirks {
+    /// Gas limit of adapter calls, for adapters needing more than the default of 8M
+    pub gas_limit: Option<u64>,
+    /// Whether the pools reject swaps paying out to the zero address
+    pub non_zero_recipient: bool,
+    /// Tokens whose transfers of zero revert. Quotes of zero of them return zero instead of
+    /// simulating a reverting swap.
+    pub zero_transfer_reverts: HashSet<Address>,
+}
+
+impl Quirks {
+    /// Sets the gas limit of adapter calls.
+    pub fn with_gas_limit(mut self, gas_limit: u64) -> Self {
+        self.gas_limit = Some(gas_limit);
+        self
+    }
+
+    /// Rejects quotes paying out to the zero address.
+    pub fn with_non_zero_recipient(mut self) -> Self {
+        self.non_zero_recipient = true;
+        self
+    }
+
+    /// Marks `token` as reverting on transfers of zero.
+    pub fn with_zero_transfer_revert(mut self, token: Address) -> Self {
+        self.zero_transfer_reverts.insert(token);
+        self
+    }
+
+    /// Checks a quote's context against the quirks.
+    ///
+    /// # Errors
+    ///
+    /// `SimulationError::InvalidInput` if the pools require a non-zero recipient and the context
+    /// pays out to the zero address.
+    pub(crate) fn check_context(&self, context: &QuoteContext) -> Result<(), SimulationError> {
+        let zero_recipient = context
+            .recipient
+            .as_ref()
+            .is_some_and(|recipient| recipient.iter().all(|byte| *byte == 0));
+        if self.non_zero_recipient && zero_recipient {
+            return Err(SimulationError::InvalidInput(
+                "Pool rejects swaps to the zero address".to_string(),
+                None,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether a swap of `amount` between `tokens` reverts only because it transfers zero of a
+    /// token that doesn't allow it.
+    pub(crate) fn is_zero_transfer(&self, amount: &BigUint, tokens: [Address; 2]) -> bool {
+        amount.is_zero() &&
+            tokens.iter().any(|token| {
+                self.zero_transfer_reverts
+                    .contains(token)
+            })
+    }
+}
+
+/// Configures the quirks of `protocol`, for pools decoded from then on. `protocol` is the
+/// protocol system without the `vm:` prefix.
+pub fn register_quirks(protocol: &str, quirks: Quirks) {
+    QUIRKS
+        .write()
+        .unwrap()
+        .insert(protocol.to_string(), quirks);
+}
+
+/// Returns the quirks configured for `protocol`, none if it has no entry.
+pub fn quirks(protocol: &str) -> Quirks {
+    QUIRKS
+        .read()
+        .unwrap()
+        .get(protocol)
+        .cloned()
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use tycho_core::Bytes;
+
+    use super::*;
+
+    #[test]
+    fn test_register_quirks() {
+        let quirks = Quirks::default()
+            .with_gas_limit(30_000_000)
+            .with_non_zero_recipient();
+
+        register_quirks("quirky_protocol", quirks.clone());
+
+        assert_eq!(super::quirks("quirky_protocol"), quirks);
+        assert_eq!(super::quirks("unknown_protocol"), Quirks::default());
+    }
+
+    #[test]
+    fn test_check_context() {
+        let quirks = Quirks::default().with_non_zero_recipient();
+        let to_zero = QuoteContext::default().with_recipient(Bytes::from(Address::ZERO.to_vec()));
+
+        assert!(matches!(quirks.check_context(&to_zero), Err(SimulationError::InvalidInput(..))));
+        assert!(quirks
+            .check_context(&QuoteContext::default())
+            .is_ok());
+        assert!(Quirks::default()
+            .check_context(&to_zero)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_is_zero_transfer() {
+        let token = Address::repeat_byte(1);
+        let quirks = Quirks::default().with_zero_transfer_revert(token);
+        let tokens = [Address::repeat_byte(2), token];
+
+        assert!(quirks.is_zero_transfer(&BigUint::zero(), tokens));
+        assert!(!quirks.is_zero_transfer(&BigUint::from(1u8), tokens));
+        assert!(!quirks.is_zero_transfer(&BigUint::zero(), [Address::repeat_byte(2); 2]));
+    }
+}
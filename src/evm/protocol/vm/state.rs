@@ -3,35 +3,40 @@ use std::{
     any::Any,
     collections::{HashMap, HashSet},
     fmt::Debug,
-    str::FromStr,
+    path::Path,
 };
 
-use alloy_primitives::{Address, U256};
+use alloy_primitives::{Address, B256, U256};
 use itertools::Itertools;
 use num_bigint::BigUint;
 use revm::DatabaseRef;
 use tycho_core::{dto::ProtocolStateDelta, Bytes};
 
 use super::{
-    constants::{EXTERNAL_ACCOUNT, MAX_BALANCE},
+    constants::MAX_BALANCE,
     erc20_token::{ERC20OverwriteFactory, ERC20Slots, Overwrites},
-    models::Capability,
+    models::{Capability, OrderSide, PriceOracle},
+    quirks::Quirks,
     tycho_simulation_contract::TychoSimulationContract,
 };
 use crate::{
     evm::{
         engine_db::{
-            engine_db_interface::EngineDatabaseInterface, simulation_db::BlockHeader,
-            tycho_db::PreCachedDB,
+            engine_db_interface::EngineDatabaseInterface, history::BlockHistory,
+            simulation_db::BlockHeader, tycho_db::PreCachedDB,
         },
-        protocol::{u256_num::u256_to_biguint, utils::bytes_to_address},
+        protocol::{
+            u256_num::{checked_biguint_to_u256, checked_bytes_to_u256, u256_to_biguint},
+            utils::bytes_to_address,
+        },
+        simulation::recycle_state_updates,
         ContractCompiler, SlotId,
     },
     models::{Balances, Token},
     protocol::{
         errors::{SimulationError, TransitionError},
-        models::GetAmountOutResult,
-        state::ProtocolSim,
+        models::{GetAmountOutResult, QuoteBounds, QuoteContext},
+        state::{fingerprint, ProtocolSim},
     },
 };
 
@@ -56,6 +61,8 @@ where
     balance_owner: Option<Address>,
     /// Spot prices of the pool by token pair
     spot_prices: HashMap<(Address, Address), f64>,
+    /// Gas used by the simulations computing `spot_prices`
+    spot_prices_gas: u64,
     /// The supported capabilities of this pool
     capabilities: HashSet<Capability>,
     /// Storage overwrites that will be applied to all simulations. They will be cleared
@@ -75,8 +82,20 @@ where
     /// triggers to recalculate spot prices ect. Default is to update on all changes on
     /// the pool.
     manual_updates: bool,
+    /// Contract the pool reads its fee from at swap time, for dynamic fee pools. The fee can
+    /// change without any update to the pool itself, so cached prices are refreshed every
+    /// block.
+    fee_controller: Option<Address>,
+    /// Oracle prices the pool reads at swap time. Oracles can be updated within a block, so
+    /// quotes of the pool carry drift risk, see `get_amount_out_bounds`.
+    price_oracles: Vec<PriceOracle>,
+    /// How the protocol deviates from the default simulation, see `quirks`
+    quirks: Quirks,
     /// The adapter contract. This is used to interact with the protocol when running simulations
     adapter_contract: TychoSimulationContract<D>,
+    /// Number of state updates applied. The pool's storage lives in the engine's database, so
+    /// this stands in for it in the state fingerprint.
+    revision: u64,
 }
 
 impl<D> EVMPoolState<D>
@@ -103,6 +122,9 @@ where
         involved_contracts: HashSet<Address>,
         token_storage_slots: HashMap<Address, (ERC20Slots, ContractCompiler)>,
         manual_updates: bool,
+        fee_controller: Option<Address>,
+        price_oracles: Vec<PriceOracle>,
+        quirks: Quirks,
         adapter_contract: TychoSimulationContract<D>,
     ) -> Self {
         Self {
@@ -112,13 +134,18 @@ where
             balances: component_balances,
             balance_owner,
             spot_prices,
+            spot_prices_gas: 0,
             capabilities,
             block_lasting_overwrites,
             involved_contracts,
             contract_balances,
             token_storage_slots,
             manual_updates,
+            fee_controller,
+            price_oracles,
+            quirks,
             adapter_contract,
+            revision: 0,
         }
     }
 
@@ -181,6 +208,7 @@ where
         tokens: &HashMap<Bytes, Token>,
     ) -> Result<(), SimulationError> {
         self.ensure_capability(Capability::PriceFunction)?;
+        let mut gas = 0u64;
         for [sell_token_address, buy_token_address] in self
             .tokens
             .iter()
@@ -192,12 +220,13 @@ where
             let overwrites = Some(self.get_overwrites(
                 vec![sell_token_address, buy_token_address],
                 *MAX_BALANCE / U256::from(100),
+                self.adapter_contract.caller_address(),
             )?);
             let sell_amount_limit = self.get_sell_amount_limit(
                 vec![sell_token_address, buy_token_address],
                 overwrites.clone(),
             )?;
-            let price_result = self.adapter_contract.price(
+            let (price_result, price_gas) = self.adapter_contract.price(
                 &self.id,
                 sell_token_address,
                 buy_token_address,
@@ -205,6 +234,7 @@ where
                 self.block.number,
                 overwrites,
             )?;
+            gas = gas.saturating_add(price_gas);
 
             let price = if self
                 .capabilities
@@ -219,13 +249,14 @@ where
                 })?;
                 let sell_token_decimals = self.get_decimals(tokens, &sell_token_address)?;
                 let buy_token_decimals = self.get_decimals(tokens, &buy_token_address)?;
-                *unscaled_price * 10f64.powi(sell_token_decimals as i32) /
-                    10f64.powi(buy_token_decimals as i32)
+                // Scaled by the difference, so tokens with many decimals don't overflow to inf/inf
+                *unscaled_price * 10f64.powi(sell_token_decimals as i32 - buy_token_decimals as i32)
             };
 
             self.spot_prices
                 .insert((sell_token_address, buy_token_address), price);
         }
+        self.spot_prices_gas = gas;
         Ok(())
     }
 
@@ -297,6 +328,7 @@ where
             .engine
             .clear_temp_storage();
         self.block_lasting_overwrites.clear();
+        self.revision += 1;
 
         // set balances
         if !self.balances.is_empty() {
@@ -314,7 +346,7 @@ where
                                 token
                             ))
                         })?;
-                        Ok((addr, U256::from_be_slice(bal)))
+                        Ok((addr, checked_bytes_to_u256(bal)?))
                     })
                     .collect::<Result<HashMap<_, _>, SimulationError>>()?;
             }
@@ -336,7 +368,7 @@ where
                                 token
                             ))
                         })?;
-                        contract_entry.insert(addr, U256::from_be_slice(bal));
+                        contract_entry.insert(addr, checked_bytes_to_u256(bal)?);
                     }
                 }
             }
@@ -351,8 +383,9 @@ where
         &self,
         tokens: Vec<Address>,
         max_amount: U256,
+        caller: Address,
     ) -> Result<HashMap<Address, Overwrites>, SimulationError> {
-        let token_overwrites = self.get_token_overwrites(tokens, max_amount)?;
+        let token_overwrites = self.get_token_overwrites(tokens, max_amount, caller)?;
 
         // Merge `block_lasting_overwrites` with `token_overwrites`
         let merged_overwrites =
@@ -365,6 +398,7 @@ where
         &self,
         tokens: Vec<Address>,
         max_amount: U256,
+        caller: Address,
     ) -> Result<HashMap<Address, Overwrites>, SimulationError> {
         let sell_token = &tokens[0].clone(); //TODO: need to make it clearer from the interface
         let mut res: Vec<HashMap<Address, Overwrites>> = Vec::new();
@@ -386,10 +420,10 @@ where
 
         let mut overwrites = ERC20OverwriteFactory::new(*sell_token, slots.clone(), compiler);
 
-        overwrites.set_balance(max_amount, Address::from_slice(&*EXTERNAL_ACCOUNT.0));
+        overwrites.set_balance(max_amount, caller);
 
         // Set allowance for adapter_address to max_amount
-        overwrites.set_allowance(max_amount, self.adapter_contract.address, *EXTERNAL_ACCOUNT);
+        overwrites.set_allowance(max_amount, self.adapter_contract.address, caller);
 
         res.push(overwrites.get_overwrites());
 
@@ -412,8 +446,10 @@ where
     fn get_balance_overwrites(&self) -> Result<HashMap<Address, Overwrites>, SimulationError> {
         let mut balance_overwrites: HashMap<Address, Overwrites> = HashMap::new();
 
-        // Use component balances for overrides
+        // Use component balances for overrides. Without balances, e.g. for states built from a
+        // node, the on-chain balances are used.
         let address = match self.balance_owner {
+            _ if self.balances.is_empty() => None,
             Some(owner) => Some(owner),
             None if !self.contract_balances.is_empty() => None,
             None => Some(self.id.parse().map_err(|_| {
@@ -483,6 +519,42 @@ where
         merged
     }
 
+    /// Returns the contracts the pool depends on: the contracts involved according to the
+    /// snapshot, the fee controller and all accounts accessed by the simulations run on the pool so
+    /// far, like oracles and math libraries. Updates to any of them may change the pool's prices.
+    pub fn dependencies(&self) -> HashSet<Address> {
+        let mut dependencies = self
+            .adapter_contract
+            .accessed_accounts();
+        dependencies.remove(&self.adapter_contract.address);
+        dependencies.extend(self.involved_contracts.iter().copied());
+        dependencies.extend(self.fee_controller);
+        dependencies.extend(
+            self.price_oracles
+                .iter()
+                .map(|oracle| oracle.address),
+        );
+        dependencies
+    }
+
+    /// Returns the contract the pool reads its fee from, if it has a dynamic fee.
+    pub fn fee_controller(&self) -> Option<Address> {
+        self.fee_controller
+    }
+
+    /// Returns a copy of the pool simulating on `db` instead of its current database. `db` must
+    /// hold the same accounts, e.g. a copy kept in sync with `PreCachedDB::apply`.
+    pub fn with_db(&self, db: D) -> Self {
+        let mut pool = self.clone();
+        pool.adapter_contract.engine.state = db;
+        pool
+    }
+
+    /// Returns the quirks of the pool's protocol.
+    pub fn quirks(&self) -> &Quirks {
+        &self.quirks
+    }
+
     #[cfg(test)]
     pub fn get_involved_contracts(&self) -> HashSet<Address> {
         self.involved_contracts.clone()
@@ -522,96 +594,40 @@ where
             )))
     }
 
+    fn spot_price_gas(&self) -> u64 {
+        self.spot_prices_gas
+    }
+
     fn get_amount_out(
         &self,
         amount_in: BigUint,
         token_in: &Token,
         token_out: &Token,
     ) -> Result<GetAmountOutResult, SimulationError> {
-        let sell_token_address = bytes_to_address(&token_in.address)?;
-        let buy_token_address = bytes_to_address(&token_out.address)?;
-        let sell_amount = U256::from_be_slice(&amount_in.to_bytes_be());
-        let overwrites = self.get_overwrites(
-            vec![sell_token_address, buy_token_address],
-            U256::from_be_slice(&(*MAX_BALANCE / U256::from(100)).to_be_bytes::<32>()),
-        )?;
-        let sell_amount_limit = self.get_sell_amount_limit(
-            vec![sell_token_address, buy_token_address],
-            Some(overwrites.clone()),
-        )?;
-        let (sell_amount_respecting_limit, sell_amount_exceeds_limit) = if self
-            .capabilities
-            .contains(&Capability::HardLimits) &&
-            sell_amount_limit < sell_amount
-        {
-            (sell_amount_limit, true)
-        } else {
-            (sell_amount, false)
-        };
-
-        let overwrites_with_sell_limit =
-            self.get_overwrites(vec![sell_token_address, buy_token_address], sell_amount_limit)?;
-        let complete_overwrites = self.merge(&overwrites, &overwrites_with_sell_limit);
-
-        let (trade, state_changes) = self.adapter_contract.swap(
-            &self.id,
-            sell_token_address,
-            buy_token_address,
-            false,
-            sell_amount_respecting_limit,
-            self.block.number,
-            Some(complete_overwrites),
-        )?;
-
-        let mut new_state = self.clone();
-
-        // Apply state changes to the new state
-        for (address, state_update) in state_changes {
-            if let Some(storage) = state_update.storage {
-                let block_overwrites = new_state
-                    .block_lasting_overwrites
-                    .entry(address)
-                    .or_default();
-                for (slot, value) in storage {
-                    let slot = U256::from_str(&slot.to_string()).map_err(|_| {
-                        SimulationError::FatalError("Failed to decode slot index".to_string())
-                    })?;
-                    let value = U256::from_str(&value.to_string()).map_err(|_| {
-                        SimulationError::FatalError("Failed to decode slot overwrite".to_string())
-                    })?;
-                    block_overwrites.insert(slot, value);
-                }
-            }
-        }
+        self.get_amount_out_with_context(amount_in, token_in, token_out, &QuoteContext::default())
+    }
 
-        // Update spot prices
-        let new_price = trade.price;
-        if new_price != 0.0f64 {
-            new_state
-                .spot_prices
-                .insert((sell_token_address, buy_token_address), new_price);
-            new_state
-                .spot_prices
-                .insert((buy_token_address, sell_token_address), 1.0f64 / new_price);
-        }
+    /// Simulates the swap from the context's sender, so adapters with fee tiers by sender quote
+    /// the sender's fee. The sender is funded and approves the adapter like the default caller.
+    ///
+    /// The adapter interface only exposes the caller, so the recipient and referral are ignored,
+    /// apart from rejecting the zero address as recipient if the protocol's quirks require it.
+    fn get_amount_out_with_context(
+        &self,
+        amount_in: BigUint,
+        token_in: &Token,
+        token_out: &Token,
+        context: &QuoteContext,
+    ) -> Result<GetAmountOutResult, SimulationError> {
+        self.quote(OrderSide::Sell, amount_in, token_in, token_out, context, &HashMap::new())
+    }
 
-        let buy_amount = trade.received_amount;
+    fn is_blocking(&self) -> bool {
+        true
+    }
 
-        if sell_amount_exceeds_limit {
-            return Err(SimulationError::InvalidInput(
-                format!("Sell amount exceeds limit {}", sell_amount_limit),
-                Some(GetAmountOutResult::new(
-                    u256_to_biguint(buy_amount),
-                    u256_to_biguint(trade.gas_used),
-                    Box::new(new_state.clone()),
-                )),
-            ));
-        }
-        Ok(GetAmountOutResult::new(
-            u256_to_biguint(buy_amount),
-            u256_to_biguint(trade.gas_used),
-            Box::new(new_state.clone()),
-        ))
+    fn state_fingerprint(&self) -> u64 {
+        fingerprint(&(&self.id, self.revision))
     }
 
     fn delta_transition(
@@ -662,9 +678,336 @@ where
     }
 }
 
+impl<D> EVMPoolState<D>
+where
+    D: EngineDatabaseInterface + Clone + Debug + 'static,
+    <D as DatabaseRef>::Error: Debug,
+    <D as EngineDatabaseInterface>::Error: Debug,
+{
+    /// Returns the oracle prices the pool reads at swap time.
+    pub fn price_oracles(&self) -> &[PriceOracle] {
+        &self.price_oracles
+    }
+
+    /// Quotes a swap with all oracle prices of the pool shifted by `-drift_bps`, 0 and
+    /// `+drift_bps`, so consumers can tell how far the quote moves if an oracle is updated before
+    /// the swap executes. Pools without oracles return exact bounds.
+    pub fn get_amount_out_bounds(
+        &self,
+        amount_in: BigUint,
+        token_in: &Token,
+        token_out: &Token,
+        drift_bps: u32,
+    ) -> Result<QuoteBounds, SimulationError> {
+        let context = QuoteContext::default();
+        let mid = self
+            .quote(
+                OrderSide::Sell,
+                amount_in.clone(),
+                token_in,
+                token_out,
+                &context,
+                &HashMap::new(),
+            )?
+            .amount;
+        if self.price_oracles.is_empty() || drift_bps == 0 {
+            return Ok(QuoteBounds::exact(mid));
+        }
+
+        let mut amounts = vec![mid.clone()];
+        for factor in [10_000u32.saturating_sub(drift_bps), 10_000 + drift_bps] {
+            let overwrites = self.oracle_overwrites(factor)?;
+            amounts.push(
+                self.quote(
+                    OrderSide::Sell,
+                    amount_in.clone(),
+                    token_in,
+                    token_out,
+                    &context,
+                    &overwrites,
+                )?
+                .amount,
+            );
+        }
+        Ok(QuoteBounds {
+            low: amounts
+                .iter()
+                .min()
+                .cloned()
+                .unwrap_or_default(),
+            high: amounts
+                .iter()
+                .max()
+                .cloned()
+                .unwrap_or_default(),
+            mid,
+        })
+    }
+
+    /// Overwrites of the oracle prices scaled by `factor_bps / 10_000`, see `PriceOracle::scale`.
+    /// Words overwritten by earlier swaps in the block are scaled instead of the stored ones.
+    fn oracle_overwrites(
+        &self,
+        factor_bps: u32,
+    ) -> Result<HashMap<Address, Overwrites>, SimulationError> {
+        let mut overwrites: HashMap<Address, Overwrites> = HashMap::new();
+        for oracle in &self.price_oracles {
+            let (address, slot) = (oracle.address, oracle.slot);
+            // Several prices may be packed into the same word
+            let word = match [&overwrites, &self.block_lasting_overwrites]
+                .into_iter()
+                .find_map(|overwrites| overwrites.get(&address)?.get(&slot))
+            {
+                Some(word) => *word,
+                None => self
+                    .adapter_contract
+                    .engine
+                    .state
+                    .storage_ref(address, slot)
+                    .map_err(|e| {
+                        SimulationError::FatalError(format!(
+                            "Failed to read oracle price {address}:{slot}: {e:?}"
+                        ))
+                    })?,
+            };
+            let scaled = oracle.scale(word, factor_bps)?;
+            overwrites
+                .entry(address)
+                .or_default()
+                .insert(slot, scaled);
+        }
+        Ok(overwrites)
+    }
+
+    /// Quotes the amount of `token_in` needed to receive `amount_out` of `token_out`. The
+    /// result's `amount` is the amount in.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the adapter doesn't support buy orders, see `Capability::BuySide`. Like
+    /// `get_amount_out`, returns `SimulationError::InvalidInput` with the quote of the buy limit if
+    /// the pool has hard limits and `amount_out` exceeds them.
+    pub fn get_amount_in(
+        &self,
+        amount_out: BigUint,
+        token_in: &Token,
+        token_out: &Token,
+    ) -> Result<GetAmountOutResult, SimulationError> {
+        self.quote(
+            OrderSide::Buy,
+            amount_out,
+            token_in,
+            token_out,
+            &QuoteContext::default(),
+            &HashMap::new(),
+        )
+    }
+
+    /// Simulates a swap of `side` with `extra_overwrites`, e.g. shifted oracle prices, applied on
+    /// top of the pool's overwrites. `amount` is the sell amount of sell orders and the buy amount
+    /// of buy orders; the result holds the other one.
+    fn quote(
+        &self,
+        side: OrderSide,
+        amount: BigUint,
+        token_in: &Token,
+        token_out: &Token,
+        context: &QuoteContext,
+        extra_overwrites: &HashMap<Address, Overwrites>,
+    ) -> Result<GetAmountOutResult, SimulationError> {
+        if side == OrderSide::Buy {
+            self.ensure_capability(side.capability())?;
+        }
+        self.quirks.check_context(context)?;
+        let sell_token_address = bytes_to_address(&token_in.address)?;
+        let buy_token_address = bytes_to_address(&token_out.address)?;
+        if self
+            .quirks
+            .is_zero_transfer(&amount, [sell_token_address, buy_token_address])
+        {
+            return Ok(GetAmountOutResult::new(
+                BigUint::ZERO,
+                BigUint::ZERO,
+                Box::new(self.clone()),
+            )
+            .with_fee(0.0));
+        }
+        let caller = match &context.sender {
+            Some(sender) => bytes_to_address(sender)?,
+            None => self.adapter_contract.caller_address(),
+        };
+        let specified_amount = checked_biguint_to_u256(&amount)?;
+        let overwrites = self.get_overwrites(
+            vec![sell_token_address, buy_token_address],
+            U256::from_be_slice(&(*MAX_BALANCE / U256::from(100)).to_be_bytes::<32>()),
+            caller,
+        )?;
+        let (sell_amount_limit, buy_amount_limit) = self
+            .adapter_contract
+            .get_limits(
+                &self.id,
+                sell_token_address,
+                buy_token_address,
+                self.block.number,
+                Some(overwrites.clone()),
+            )
+            .map_err(|error| self.not_initialized_or(error))?;
+        let amount_limit = match side {
+            OrderSide::Sell => sell_amount_limit,
+            OrderSide::Buy => buy_amount_limit,
+        };
+        let (amount_respecting_limit, amount_exceeds_limit) = if self
+            .capabilities
+            .contains(&Capability::HardLimits) &&
+            amount_limit < specified_amount
+        {
+            (amount_limit, true)
+        } else {
+            (specified_amount, false)
+        };
+
+        let overwrites_with_sell_limit = self.get_overwrites(
+            vec![sell_token_address, buy_token_address],
+            sell_amount_limit,
+            caller,
+        )?;
+        let complete_overwrites =
+            self.merge(&self.merge(&overwrites, &overwrites_with_sell_limit), extra_overwrites);
+
+        let (trade, state_changes) = self
+            .adapter_contract
+            .swap(
+                &self.id,
+                sell_token_address,
+                buy_token_address,
+                side,
+                amount_respecting_limit,
+                self.block.number,
+                Some(complete_overwrites),
+                Some(caller),
+            )
+            .map_err(|error| self.not_initialized_or(error))?;
+
+        let mut new_state = self.clone();
+
+        // Apply state changes to the new state
+        for (address, state_update) in &state_changes {
+            if let Some(storage) = &state_update.storage {
+                new_state
+                    .block_lasting_overwrites
+                    .entry(*address)
+                    .or_default()
+                    .extend(storage);
+            }
+        }
+        recycle_state_updates(state_changes);
+
+        // Update spot prices
+        let new_price = trade.price;
+        if new_price != 0.0f64 {
+            new_state
+                .spot_prices
+                .insert((sell_token_address, buy_token_address), new_price);
+            new_state
+                .spot_prices
+                .insert((buy_token_address, sell_token_address), 1.0f64 / new_price);
+        }
+
+        if amount_exceeds_limit {
+            return Err(SimulationError::InvalidInput(
+                format!("{side} amount exceeds limit {amount_limit}"),
+                Some(
+                    GetAmountOutResult::new(
+                        u256_to_biguint(trade.amount),
+                        u256_to_biguint(trade.gas),
+                        Box::new(new_state.clone()),
+                    )
+                    .with_fee(0.0),
+                ),
+            ));
+        }
+        // Adapters include the pool's fee in their prices, so it's part of the price impact
+        Ok(GetAmountOutResult::new(
+            u256_to_biguint(trade.amount),
+            u256_to_biguint(trade.gas),
+            Box::new(new_state.clone()),
+        )
+        .with_fee(0.0))
+    }
+
+    /// Reports reverts of pools whose tracked balances are all zero as
+    /// `SimulationError::PoolNotInitialized`: such pools were usually created in the current
+    /// block, before liquidity was added. Pools without any tracked balance can't be told apart
+    /// from broken ones, so their reverts are reported as is.
+    fn not_initialized_or(&self, error: SimulationError) -> SimulationError {
+        let mut balances = self.balances.values().chain(
+            self.contract_balances
+                .values()
+                .flat_map(HashMap::values),
+        );
+        let empty = balances
+            .next()
+            .is_some_and(U256::is_zero) &&
+            balances.all(U256::is_zero);
+        match error {
+            SimulationError::FatalError(reason) if empty => SimulationError::PoolNotInitialized(
+                format!("Pool {} has no balances: {reason}", self.id),
+            ),
+            error => error,
+        }
+    }
+}
+
+impl EVMPoolState<PreCachedDB> {
+    /// Quotes a swap at a past block retained in `history`.
+    ///
+    /// The pool reads the contract storage of the database it was created with, which only holds
+    /// the latest block. This simulates on the checkpoint of `block_hash` instead. `self` should be
+    /// the pool's state at that block too, e.g. from `PoolRegistry::state_at`.
+    pub fn get_amount_out_at(
+        &self,
+        history: &BlockHistory,
+        block_hash: &B256,
+        amount_in: BigUint,
+        token_in: &Token,
+        token_out: &Token,
+    ) -> Result<GetAmountOutResult, SimulationError> {
+        let db = history.at(block_hash).ok_or_else(|| {
+            SimulationError::InvalidInput(format!("Block {block_hash} is not retained"), None)
+        })?;
+        self.with_db(db.clone())
+            .get_amount_out(amount_in, token_in, token_out)
+    }
+
+    /// Offloads the storage of the pool's dependencies to `dir`, see `PreCachedDB::offload`.
+    /// Meant for pools that are rarely quoted, e.g. `PoolRegistry::idle_pools`: the pool stays
+    /// quotable and its storage is loaded back on the next quote. Token contracts are kept in
+    /// memory as they are shared with most other pools. Returns the number of contracts offloaded.
+    pub fn offload_storage(&self, dir: impl AsRef<Path>) -> Result<usize, SimulationError> {
+        let tokens = self
+            .tokens
+            .iter()
+            .map(bytes_to_address)
+            .collect::<Result<HashSet<_>, _>>()?;
+        self.adapter_contract
+            .engine
+            .state
+            .offload(
+                self.dependencies()
+                    .difference(&tokens)
+                    .copied(),
+                dir,
+            )
+            .map_err(|e| {
+                SimulationError::RecoverableError(format!("Failed to offload storage: {e}"))
+            })
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use alloy_primitives::B256;
+    use std::str::FromStr;
+
     use num_bigint::ToBigUint;
     use num_traits::One;
     use revm::primitives::{AccountInfo, Bytecode, KECCAK_EMPTY};
@@ -673,7 +1016,11 @@ mod tests {
     use super::*;
     use crate::evm::{
         engine_db::{create_engine, SHARED_TYCHO_DB},
-        protocol::vm::{constants::BALANCER_V2, state_builder::EVMPoolStateBuilder},
+        protocol::vm::{
+            constants::{BALANCER_V2, EXTERNAL_ACCOUNT},
+            state_builder::EVMPoolStateBuilder,
+        },
+        sharded_engine::{QuoteRequest, ShardedEngine},
         simulation::SimulationEngine,
         tycho_models::AccountUpdate,
     };
@@ -721,6 +1068,7 @@ mod tests {
             )
             .unwrap(),
             timestamp: 1722875891,
+            ..Default::default()
         };
 
         for account in accounts.clone() {
@@ -749,6 +1097,7 @@ mod tests {
             )
             .expect("Invalid block hash"),
             timestamp: 0,
+            ..Default::default()
         };
 
         let pool_id: String =
@@ -864,6 +1213,219 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_get_amount_in() {
+        let pool_state = setup_pool_state().await;
+        // The amount of BAL bought with 1 DAI, see `test_get_amount_out`
+        let amount_out = BigUint::from_str("137780051463393923").unwrap();
+
+        let result = pool_state
+            .get_amount_in(amount_out.clone(), &dai(), &bal())
+            .unwrap();
+
+        // Buy orders round the amount in up, so selling it buys at least the amount out
+        let sold = pool_state
+            .get_amount_out(result.amount.clone(), &dai(), &bal())
+            .unwrap();
+        assert!(sold.amount >= amount_out);
+        let one_dai = BigUint::from_str("1000000000000000000").unwrap();
+        assert!(
+            result
+                .amount
+                .clone()
+                .max(one_dai.clone()) -
+                result.amount.min(one_dai) <
+                BigUint::from(1_000_000_000u64)
+        );
+
+        let mut sell_only = pool_state.clone();
+        sell_only
+            .capabilities
+            .remove(&Capability::BuySide);
+        assert!(sell_only
+            .get_amount_in(amount_out, &dai(), &bal())
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_amount_out_with_context() {
+        let pool_state = setup_pool_state().await;
+        let amount_in = BigUint::from_str("1000000000000000000").unwrap();
+        let context = QuoteContext::default()
+            .with_sender(Bytes::from_str("0x0000000000000000000000000000000000000abc").unwrap());
+
+        let result = pool_state
+            .get_amount_out_with_context(amount_in.clone(), &dai(), &bal(), &context)
+            .unwrap();
+
+        // the Balancer adapter charges every sender the same fee
+        let expected = pool_state
+            .get_amount_out(amount_in, &dai(), &bal())
+            .unwrap();
+        assert_eq!(result.amount, expected.amount);
+    }
+
+    #[tokio::test]
+    async fn test_sharded_get_amount_out() {
+        let pool_state = setup_pool_state().await;
+        let amount_in = BigUint::from_str("1000000000000000000").unwrap();
+        let expected = pool_state
+            .get_amount_out(amount_in.clone(), &dai(), &bal())
+            .unwrap()
+            .amount;
+        let engine = ShardedEngine::new(2);
+        engine.sync(&SHARED_TYCHO_DB);
+
+        engine
+            .insert_pool(&pool_state.id, &pool_state)
+            .unwrap();
+        let res = engine
+            .get_amount_out(QuoteRequest {
+                pool: pool_state.id.clone(),
+                amount_in,
+                token_in: dai(),
+                token_out: bal(),
+            })
+            .unwrap();
+
+        assert_eq!(res.amount, expected);
+    }
+
+    #[tokio::test]
+    async fn test_get_amount_out_at() {
+        let pool_state = setup_pool_state().await;
+        let amount_in = BigUint::from_str("1000000000000000000").unwrap();
+        let expected = pool_state
+            .get_amount_out(amount_in.clone(), &dai(), &bal())
+            .unwrap()
+            .amount;
+        let mut history = BlockHistory::new(4);
+        history.record(&SHARED_TYCHO_DB);
+        let block = SHARED_TYCHO_DB.block().unwrap();
+
+        let res = pool_state
+            .get_amount_out_at(&history, &block.hash, amount_in.clone(), &dai(), &bal())
+            .unwrap();
+        let unknown =
+            pool_state.get_amount_out_at(&history, &B256::ZERO, amount_in, &dai(), &bal());
+
+        assert_eq!(res.amount, expected);
+        assert!(matches!(unknown, Err(SimulationError::InvalidInput(_, None))));
+    }
+
+    #[tokio::test]
+    async fn test_offload_storage() {
+        let pool_state = setup_pool_state().await;
+        let amount_in = BigUint::from_str("1000000000000000000").unwrap();
+        let expected = pool_state
+            .get_amount_out(amount_in.clone(), &dai(), &bal())
+            .unwrap()
+            .amount;
+        // Offload from a detached copy, the shared database is used by other tests
+        let pool_state = pool_state.with_db(SHARED_TYCHO_DB.checkpoint());
+        let dir = tempfile::tempdir().unwrap();
+
+        let offloaded = pool_state
+            .offload_storage(dir.path())
+            .unwrap();
+        let res = pool_state
+            .get_amount_out(amount_in, &dai(), &bal())
+            .unwrap();
+
+        assert!(offloaded > 0);
+        assert_eq!(res.amount, expected);
+    }
+
+    #[tokio::test]
+    async fn test_dependencies() {
+        let pool_state = setup_pool_state().await;
+        let vault = Address::from_str("0xBA12222222228d8Ba445958a75a0704d566BF2C8").unwrap();
+
+        pool_state
+            .get_amount_out(BigUint::from_str("1000000000000000000").unwrap(), &dai(), &bal())
+            .unwrap();
+
+        let dependencies = pool_state.dependencies();
+        assert!(dependencies.contains(&vault));
+        assert!(dependencies.contains(&dai_addr()));
+        assert!(!dependencies.contains(&pool_state.adapter_contract.address));
+        assert!(!dependencies.contains(&*EXTERNAL_ACCOUNT));
+    }
+
+    #[tokio::test]
+    async fn test_get_amount_out_bounds() {
+        let mut pool_state = setup_pool_state().await;
+        let amount_in = BigUint::from_str("1000000000000000000").unwrap();
+        let expected = pool_state
+            .get_amount_out(amount_in.clone(), &dai(), &bal())
+            .unwrap()
+            .amount;
+
+        let bounds = pool_state
+            .get_amount_out_bounds(amount_in.clone(), &dai(), &bal(), 50)
+            .unwrap();
+
+        assert_eq!(bounds, QuoteBounds::exact(expected));
+        assert!(!bounds.has_drift());
+
+        // A slot of a contract the pool doesn't read, the bounds can't drift
+        let oracle = PriceOracle::new(dai_addr(), U256::from(0xdead));
+        pool_state.price_oracles = vec![oracle];
+
+        let bounds = pool_state
+            .get_amount_out_bounds(amount_in, &dai(), &bal(), 50)
+            .unwrap();
+
+        assert_eq!(bounds.mid, expected);
+        assert!(bounds.low <= bounds.mid && bounds.mid <= bounds.high);
+        assert!(pool_state
+            .dependencies()
+            .contains(&dai_addr()));
+    }
+
+    #[tokio::test]
+    async fn test_oracle_overwrites_packed() {
+        let mut pool_state = setup_pool_state().await;
+        let (address, slot) = (Address::with_last_byte(0x0a), U256::from(1));
+        // A 112 bit price with a 32 bit timestamp packed above it
+        let timestamp = U256::from(1_700_000_000u64) << 112;
+        pool_state.price_oracles = vec![PriceOracle::packed(address, slot, 0, 112)];
+        pool_state
+            .block_lasting_overwrites
+            .insert(address, HashMap::from([(slot, timestamp | U256::from(2_000))]));
+
+        let overwrites = pool_state
+            .oracle_overwrites(9_950)
+            .unwrap();
+
+        assert_eq!(overwrites[&address][&slot], timestamp | U256::from(1_990));
+
+        // The scaled price must still fit its field
+        pool_state.price_oracles = vec![PriceOracle::packed(address, slot, 0, 11)];
+
+        assert!(matches!(
+            pool_state.oracle_overwrites(10_250),
+            Err(SimulationError::FatalError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_get_amount_out_async() {
+        let pool_state: Box<dyn ProtocolSim> = Box::new(setup_pool_state().await);
+        let amount_in = BigUint::from_str("1000000000000000000").unwrap();
+
+        let result = pool_state
+            .get_amount_out_async(amount_in.clone(), &dai(), &bal())
+            .await
+            .unwrap();
+
+        let expected = pool_state
+            .get_amount_out(amount_in, &dai(), &bal())
+            .unwrap();
+        assert_eq!(result.amount, expected.amount);
+        assert_eq!(result.gas, expected.gas);
+    }
+
     #[tokio::test]
     async fn test_sequential_get_amount_outs() {
         let pool_state = setup_pool_state().await;
@@ -912,6 +1474,19 @@ mod tests {
         assert_eq!(new_state.spot_prices, pool_state.spot_prices)
     }
 
+    #[tokio::test]
+    async fn test_get_amount_out_zero_transfer_quirk() {
+        let mut pool_state = setup_pool_state().await;
+        pool_state.quirks = Quirks::default().with_zero_transfer_revert(dai_addr());
+
+        let result = pool_state
+            .get_amount_out(BigUint::ZERO, &dai(), &bal())
+            .unwrap();
+
+        assert_eq!(result.amount, BigUint::ZERO);
+        assert_eq!(result.gas, BigUint::ZERO);
+    }
+
     #[tokio::test]
     async fn test_get_amount_out_sell_limit() {
         let pool_state = setup_pool_state().await;
@@ -945,6 +1520,9 @@ mod tests {
                     bytes_to_address(&pool_state.tokens[1]).unwrap(),
                 ],
                 *MAX_BALANCE / U256::from(100),
+                pool_state
+                    .adapter_contract
+                    .caller_address(),
             )
             .unwrap();
         let dai_limit = pool_state
@@ -995,6 +1573,27 @@ mod tests {
         assert_eq!(bal_dai_spot_price, &7.071_503_245_428_246);
     }
 
+    #[tokio::test]
+    async fn test_not_initialized_or() {
+        let mut pool_state: EVMPoolState<PreCachedDB> = setup_pool_state().await;
+        let revert = || SimulationError::FatalError("Revert".to_string());
+
+        assert!(matches!(pool_state.not_initialized_or(revert()), SimulationError::FatalError(_)));
+
+        pool_state
+            .balances
+            .values_mut()
+            .for_each(|balance| *balance = U256::ZERO);
+        assert!(matches!(
+            pool_state.not_initialized_or(revert()),
+            SimulationError::PoolNotInitialized(_)
+        ));
+
+        // Without any tracked balance, the pool can't be told to be empty
+        pool_state.balances.clear();
+        assert!(matches!(pool_state.not_initialized_or(revert()), SimulationError::FatalError(_)));
+    }
+
     #[tokio::test]
     async fn test_get_balance_overwrites_with_component_balances() {
         let pool_state: EVMPoolState<PreCachedDB> = setup_pool_state().await;
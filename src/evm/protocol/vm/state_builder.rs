@@ -5,20 +5,22 @@ use std::{
 
 use alloy_primitives::{Address, U256};
 use alloy_sol_types::SolValue;
-use chrono::Utc;
 use itertools::Itertools;
 use revm::{
     precompile::Bytes,
-    primitives::{alloy_primitives::Keccak256, AccountInfo, Bytecode, KECCAK_EMPTY},
+    primitives::{alloy_primitives::Keccak256, Bytecode, KECCAK_EMPTY},
     DatabaseRef,
 };
 use tracing::warn;
 use tycho_core::Bytes as TychoBytes;
 
 use super::{
+    caller::SwapCaller,
     constants::{EXTERNAL_ACCOUNT, MAX_BALANCE},
     erc20_token::{brute_force_slots, ERC20Slots},
-    models::Capability,
+    manifest::verify_adapter,
+    models::{Capability, PriceOracle},
+    quirks::Quirks,
     state::EVMPoolState,
     tycho_simulation_contract::TychoSimulationContract,
     utils::get_code_for_contract,
@@ -26,13 +28,16 @@ use super::{
 use crate::{
     evm::{
         engine_db::{
-            create_engine, engine_db_interface::EngineDatabaseInterface, simulation_db::BlockHeader,
+            account_builder::AccountBuilder,
+            create_engine,
+            engine_db_interface::EngineDatabaseInterface,
+            simulation_db::{BlockHeader, RpcDB},
         },
         protocol::{utils::bytes_to_address, vm::constants::ERC20_BYTECODE},
         simulation::{SimulationEngine, SimulationParameters},
         ContractCompiler,
     },
-    protocol::errors::SimulationError,
+    protocol::{clock, errors::SimulationError},
 };
 
 #[derive(Debug)]
@@ -67,6 +72,7 @@ use crate::{
 ///         number: 1,
 ///         hash: Default::default(),
 ///         timestamp: 1632456789,
+///         ..Default::default()
 ///     };
 ///
 ///     // Build the EVMPoolState
@@ -94,10 +100,18 @@ where
     stateless_contracts: Option<HashMap<String, Option<Vec<u8>>>>,
     token_storage_slots: Option<HashMap<Address, (ERC20Slots, ContractCompiler)>>,
     manual_updates: Option<bool>,
+    fee_controller: Option<Address>,
+    price_oracles: Vec<PriceOracle>,
+    swap_caller: Option<SwapCaller>,
+    quirks: Quirks,
     trace: Option<bool>,
     engine: Option<SimulationEngine<D>>,
     adapter_contract: Option<TychoSimulationContract<D>>,
     adapter_contract_bytecode: Option<Bytecode>,
+    /// Protocol the adapter bytecode is verified for, see `manifest`
+    adapter_protocol: Option<String>,
+    /// Keep the on-chain code of the tokens instead of mocking them
+    on_chain_tokens: bool,
 }
 
 impl<D> EVMPoolStateBuilder<D>
@@ -125,10 +139,16 @@ where
             stateless_contracts: None,
             token_storage_slots: None,
             manual_updates: None,
+            fee_controller: None,
+            price_oracles: Vec::new(),
+            swap_caller: None,
+            quirks: Quirks::default(),
             trace: None,
             engine: None,
             adapter_contract: None,
             adapter_contract_bytecode: None,
+            adapter_protocol: None,
+            on_chain_tokens: false,
         }
     }
 
@@ -185,6 +205,31 @@ where
         self
     }
 
+    /// Sets the contract a dynamic fee pool reads its fee from.
+    pub fn fee_controller(mut self, fee_controller: Address) -> Self {
+        self.fee_controller = Some(fee_controller);
+        self
+    }
+
+    /// Adds an oracle price the pool reads at swap time, see
+    /// `EVMPoolState::get_amount_out_bounds`.
+    pub fn price_oracle(mut self, oracle: PriceOracle) -> Self {
+        self.price_oracles.push(oracle);
+        self
+    }
+
+    /// Sends the adapter calls from an allowlisted executor instead of `EXTERNAL_ACCOUNT`.
+    pub fn swap_caller(mut self, swap_caller: SwapCaller) -> Self {
+        self.swap_caller = Some(swap_caller);
+        self
+    }
+
+    /// Sets how the protocol deviates from the default simulation, see `quirks`.
+    pub fn quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = quirks;
+        self
+    }
+
     pub fn trace(mut self, trace: bool) -> Self {
         self.trace = Some(trace);
         self
@@ -205,8 +250,20 @@ where
         self
     }
 
+    /// Verifies the adapter contract bytecode against the releases of `protocol` when building,
+    /// see `manifest`. `protocol` is the protocol system without the `vm:` prefix.
+    pub fn adapter_protocol(mut self, protocol: &str) -> Self {
+        self.adapter_protocol = Some(protocol.to_string());
+        self
+    }
+
     /// Build the final EVMPoolState object
     pub async fn build(mut self, db: D) -> Result<EVMPoolState<D>, SimulationError> {
+        if let (None, Some(protocol), Some(bytecode)) =
+            (&self.adapter_contract, &self.adapter_protocol, &self.adapter_contract_bytecode)
+        {
+            verify_adapter(protocol, bytecode.original_byte_slice())?;
+        }
         let engine = if let Some(engine) = &self.engine {
             engine.clone()
         } else {
@@ -233,11 +290,19 @@ where
             self.get_default_capabilities()?
         };
 
-        let adapter_contract = self.adapter_contract.ok_or_else(|| {
+        let mut adapter_contract = self.adapter_contract.ok_or_else(|| {
             SimulationError::FatalError(
                 "Failed to get build engine: Adapter contract not initialized".to_string(),
             )
         })?;
+        adapter_contract.negotiate_version(&capabilities);
+        if let Some(swap_caller) = self.swap_caller {
+            init_swap_caller(&engine, &swap_caller)?;
+            adapter_contract = adapter_contract.with_caller(swap_caller);
+        }
+        if let Some(gas_limit) = self.quirks.gas_limit {
+            adapter_contract = adapter_contract.with_gas_limit(gas_limit);
+        }
 
         Ok(EVMPoolState::new(
             self.id,
@@ -254,50 +319,42 @@ where
             self.token_storage_slots
                 .unwrap_or_default(),
             self.manual_updates.unwrap_or(false),
+            self.fee_controller,
+            self.price_oracles,
+            self.quirks,
             adapter_contract,
         ))
     }
 
     async fn get_default_engine(&self, db: D) -> Result<SimulationEngine<D>, SimulationError> {
         let engine = create_engine(db, self.trace.unwrap_or(false))?;
-        for token_address in &self.tokens {
-            let info = AccountInfo {
-                balance: Default::default(),
-                nonce: 0,
-                code_hash: KECCAK_EMPTY,
-                code: Some(Bytecode::new_raw(ERC20_BYTECODE.into())),
-            };
-            engine
-                .state
-                .init_account(bytes_to_address(token_address)?, info, None, false);
+        let mut accounts = Vec::with_capacity(self.tokens.len() + 1);
+        if !self.on_chain_tokens {
+            for token_address in &self.tokens {
+                accounts.push(
+                    AccountBuilder::new(bytes_to_address(token_address)?).code(ERC20_BYTECODE),
+                );
+            }
         }
-
-        engine.state.init_account(
-            *EXTERNAL_ACCOUNT,
-            AccountInfo { balance: *MAX_BALANCE, nonce: 0, code_hash: KECCAK_EMPTY, code: None },
-            None,
-            false,
-        );
+        accounts.push(AccountBuilder::new(*EXTERNAL_ACCOUNT).balance(*MAX_BALANCE));
+        engine.state.init_accounts(accounts);
 
         if let Some(stateless_contracts) = &self.stateless_contracts {
             for (address, bytecode) in stateless_contracts.iter() {
                 let mut addr_str = address.clone();
-                let (code, code_hash) = if bytecode.is_none() {
+                let code = if bytecode.is_none() {
                     if addr_str.starts_with("call") {
                         addr_str = self
                             .get_address_from_call(&engine, &addr_str)?
                             .to_string();
                     }
-                    let code = get_code_for_contract(&addr_str, None).await?;
-                    (Some(code.clone()), code.hash_slow())
+                    get_code_for_contract(&addr_str, None).await?
                 } else {
-                    let code =
-                        Bytecode::new_raw(Bytes::from(bytecode.clone().ok_or_else(|| {
-                            SimulationError::FatalError(
-                                "Failed to get default engine: Byte code from stateless contracts is None".into(),
-                            )
-                        })?));
-                    (Some(code.clone()), code.hash_slow())
+                    Bytecode::new_raw(Bytes::from(bytecode.clone().ok_or_else(|| {
+                        SimulationError::FatalError(
+                            "Failed to get default engine: Byte code from stateless contracts is None".into(),
+                        )
+                    })?))
                 };
                 let account_address: Address = addr_str.parse().map_err(|_| {
                     SimulationError::FatalError(format!(
@@ -305,12 +362,9 @@ where
                         address
                     ))
                 })?;
-                engine.state.init_account(
-                    alloy_primitives::Address(*account_address),
-                    AccountInfo { balance: Default::default(), nonce: 0, code_hash, code },
-                    None,
-                    false,
-                );
+                AccountBuilder::new(account_address)
+                    .bytecode(code)
+                    .init(&engine.state);
             }
         }
         Ok(engine)
@@ -428,10 +482,7 @@ where
                 )
             })?;
 
-        let timestamp = Utc::now()
-            .naive_utc()
-            .and_utc()
-            .timestamp() as u64;
+        let timestamp = clock::now();
 
         let parsed_address: Address = to_address.parse().map_err(|_| {
             SimulationError::FatalError(format!(
@@ -443,12 +494,12 @@ where
         let sim_params = SimulationParameters {
             data: selector.to_vec(),
             to: parsed_address,
-            block_number: self.block.number,
-            timestamp,
+            block: BlockHeader { timestamp, ..self.block },
             overrides: Some(HashMap::new()),
             caller: *EXTERNAL_ACCOUNT,
             value: U256::from(0u64),
             gas_limit: None,
+            spec_id: None,
         };
 
         let sim_result = engine
@@ -463,14 +514,82 @@ where
     }
 }
 
+/// Funds the caller of the adapter calls. Simulated transactions can't be sent from accounts
+/// with code (EIP-3607), so executors that are contracts are rejected.
+fn init_swap_caller<D>(
+    engine: &SimulationEngine<D>,
+    swap_caller: &SwapCaller,
+) -> Result<(), SimulationError>
+where
+    D: EngineDatabaseInterface + Clone + Debug,
+    <D as DatabaseRef>::Error: Debug,
+    <D as EngineDatabaseInterface>::Error: Debug,
+{
+    // Accounts missing from the database are EOAs to the simulation
+    let account = engine
+        .state
+        .basic_ref(swap_caller.address)
+        .ok()
+        .flatten();
+    if account.is_some_and(|info| info.code_hash != KECCAK_EMPTY && !info.code_hash.is_zero()) {
+        return Err(SimulationError::FatalError(format!(
+            "Swap caller {} is a contract: simulated calls can only be sent from accounts without \
+             code. Configure the EOA calling the executor instead, or allowlist another address",
+            swap_caller.address
+        )));
+    }
+    AccountBuilder::new(swap_caller.address)
+        .balance(*MAX_BALANCE)
+        .init(&engine.state);
+    Ok(())
+}
+
+impl EVMPoolStateBuilder<RpcDB> {
+    /// Builds the state on top of a node instead of a Tycho stream, e.g. to quote a single pool.
+    ///
+    /// The accounts accessed by simulations are fetched from the node on first access, at the
+    /// block `db` is pinned to, which also becomes the block of the state. Unlike pools streamed
+    /// from Tycho, the tokens keep their on-chain code and balances, so no balances need to be
+    /// set; their storage slots are detected while building.
+    ///
+    /// Blocks on node requests, so it must be called outside of an async runtime, see
+    /// `RpcDB::connect`.
+    pub fn build_from_rpc(mut self, db: RpcDB) -> Result<EVMPoolState<RpcDB>, SimulationError> {
+        if let Some(block) = db.block() {
+            self.block = block;
+        }
+        let mut involved_contracts = self
+            .involved_contracts
+            .take()
+            .unwrap_or_default();
+        for token in &self.tokens {
+            involved_contracts.insert(bytes_to_address(token)?);
+        }
+        self.involved_contracts = Some(involved_contracts);
+        self.on_chain_tokens = true;
+        futures::executor::block_on(self.build(db))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
 
-    use alloy_primitives::B256;
+    use alloy_primitives::{keccak256, B256};
+    use num_bigint::BigUint;
 
     use super::*;
-    use crate::evm::engine_db::{tycho_db::PreCachedDB, SHARED_TYCHO_DB};
+    use crate::{
+        evm::{
+            engine_db::{tycho_db::PreCachedDB, SHARED_TYCHO_DB},
+            protocol::vm::{
+                constants::{BALANCER_V2, CURVE},
+                manifest::{register_adapter_release, set_adapter_integrity, AdapterIntegrity},
+            },
+        },
+        models::Token,
+        protocol::state::ProtocolSim,
+    };
 
     #[test]
     fn test_build_without_required_fields() {
@@ -478,7 +597,8 @@ mod tests {
         let tokens =
             vec![TychoBytes::from_str("0000000000000000000000000000000000000000").unwrap()];
         let balances = HashMap::new();
-        let block = BlockHeader { number: 1, hash: B256::default(), timestamp: 234 };
+        let block =
+            BlockHeader { number: 1, hash: B256::default(), timestamp: 234, ..Default::default() };
         let adapter_address =
             Address::from_str("0xA2C5C98A892fD6656a7F39A2f63228C0Bc846270").unwrap();
         let result = tokio_test::block_on(
@@ -496,13 +616,38 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_build_verifies_adapter() {
+        let mut tampered = CURVE.to_vec();
+        tampered.push(0);
+        register_adapter_release("builder_test", "1", keccak256(BALANCER_V2));
+        set_adapter_integrity(AdapterIntegrity::Refuse);
+        let result = tokio_test::block_on(
+            EVMPoolStateBuilder::<PreCachedDB>::new(
+                "pool_1".to_string(),
+                vec![],
+                BlockHeader::default(),
+                Address::repeat_byte(0xad),
+            )
+            .adapter_contract_bytecode(Bytecode::new_raw(tampered.into()))
+            .adapter_protocol("builder_test")
+            .build(SHARED_TYCHO_DB.clone()),
+        );
+        set_adapter_integrity(AdapterIntegrity::default());
+
+        assert!(
+            matches!(result, Err(SimulationError::FatalError(msg)) if msg.contains("matches no release"))
+        );
+    }
+
     #[test]
     fn test_engine_setup() {
         let id = "pool_1".to_string();
         let token2 = TychoBytes::from_str("0000000000000000000000000000000000000002").unwrap();
         let token3 = TychoBytes::from_str("0000000000000000000000000000000000000003").unwrap();
         let tokens = vec![token2.clone(), token3.clone()];
-        let block = BlockHeader { number: 1, hash: B256::default(), timestamp: 234 };
+        let block =
+            BlockHeader { number: 1, hash: B256::default(), timestamp: 234, ..Default::default() };
         let balances = HashMap::new();
         let adapter_address =
             Address::from_str("0xA2C5C98A892fD6656a7F39A2f63228C0Bc846270").unwrap();
@@ -521,4 +666,57 @@ mod tests {
             .get_account_storage()
             .account_present(&bytes_to_address(&token3).unwrap()));
     }
+
+    #[test]
+    fn test_init_swap_caller() {
+        let builder = EVMPoolStateBuilder::<PreCachedDB>::new(
+            "pool_1".to_string(),
+            vec![],
+            BlockHeader::default(),
+            Address::from_str("0xA2C5C98A892fD6656a7F39A2f63228C0Bc846270").unwrap(),
+        );
+        let engine =
+            tokio_test::block_on(builder.get_default_engine(SHARED_TYCHO_DB.clone())).unwrap();
+        let executor = Address::repeat_byte(0xe1);
+        let contract = Address::repeat_byte(0xe2);
+        AccountBuilder::new(contract)
+            .code(ERC20_BYTECODE)
+            .init(&engine.state);
+
+        init_swap_caller(&engine, &SwapCaller::new(executor)).unwrap();
+        let err = init_swap_caller(&engine, &SwapCaller::new(contract)).unwrap_err();
+
+        assert!(engine
+            .state
+            .get_account_storage()
+            .account_present(&executor));
+        assert!(matches!(err, SimulationError::FatalError(msg) if msg.contains("is a contract")));
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "network_tests"), ignore)]
+    fn test_build_from_rpc() {
+        dotenv::dotenv().ok();
+        let rpc_url = std::env::var("RPC_URL").expect("Missing RPC_URL in environment");
+        let dai =
+            Token::new("0x6b175474e89094c44da98b954eedeac495271d0f", 18, "DAI", 10_000u32.into());
+        let bal =
+            Token::new("0xba100000625a3754423978a60c9317c58a424e3d", 18, "BAL", 10_000u32.into());
+        let db = RpcDB::connect(&rpc_url, Some(20463609)).unwrap();
+
+        let state = EVMPoolStateBuilder::new(
+            "0x4626d81b3a1711beb79f4cecff2413886d461677000200000000000000000011".into(),
+            vec![dai.address.clone(), bal.address.clone()],
+            BlockHeader::default(),
+            Address::from_str("0xA2C5C98A892fD6656a7F39A2f63228C0Bc846270").unwrap(),
+        )
+        .adapter_contract_bytecode(Bytecode::new_raw(BALANCER_V2.into()))
+        .build_from_rpc(db)
+        .unwrap();
+
+        let result = state
+            .get_amount_out(BigUint::from(10u64.pow(18)), &dai, &bal)
+            .unwrap();
+        assert!(result.amount > BigUint::from(0u32));
+    }
 }
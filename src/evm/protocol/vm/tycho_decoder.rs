@@ -1,7 +1,6 @@
 use std::{
     collections::{HashMap, HashSet},
     str::FromStr,
-    time::{SystemTime, UNIX_EPOCH},
 };
 
 use alloy_primitives::{Address, B256, U256};
@@ -9,22 +8,25 @@ use revm::primitives::Bytecode;
 use tycho_client::feed::{synchronizer::ComponentWithState, Header};
 use tycho_core::Bytes;
 
-use super::{state::EVMPoolState, state_builder::EVMPoolStateBuilder};
+use super::{
+    caller::swap_caller, models::PriceOracle, quirks::quirks, state::EVMPoolState,
+    state_builder::EVMPoolStateBuilder,
+};
 use crate::{
     evm::{
         engine_db::{simulation_db::BlockHeader, tycho_db::PreCachedDB, SHARED_TYCHO_DB},
         protocol::vm::constants::get_adapter_file,
     },
     models::Token,
-    protocol::{errors::InvalidSnapshotError, models::TryFromWithBlock},
+    protocol::{clock, errors::InvalidSnapshotError, models::TryFromWithBlock},
 };
 
+/// Tycho headers carry neither timestamp, base fee nor chain: the timestamp is set to the time of
+/// reception and the chain to the default. The stream decoder sets the chain and the block
+/// timestamp of the headers it applies to the engine.
 impl From<Header> for BlockHeader {
     fn from(header: Header) -> Self {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_secs();
+        let now = clock::now();
         BlockHeader {
             number: header.number,
             hash: B256::new(
@@ -34,7 +36,15 @@ impl From<Header> for BlockHeader {
                     .try_into()
                     .expect("Hash must be 32 bytes"),
             ),
+            parent_hash: B256::new(
+                header
+                    .parent_hash
+                    .as_ref()
+                    .try_into()
+                    .expect("Hash must be 32 bytes"),
+            ),
             timestamp: now,
+            ..Default::default()
         }
     }
 }
@@ -131,6 +141,36 @@ impl TryFromWithBlock<ComponentWithState> for EVMPoolState<PreCachedDB> {
             .component
             .static_attributes
             .contains_key("manual_updates");
+        let fee_controller = snapshot
+            .component
+            .static_attributes
+            .get("fee_controller")
+            .map(|controller| Address::from_slice(controller.as_ref()));
+        let price_oracle = snapshot
+            .component
+            .static_attributes
+            .get("price_oracle")
+            .map(|oracle| {
+                let attributes = &snapshot.component.static_attributes;
+                let slot = attributes
+                    .get("price_oracle_slot")
+                    .ok_or_else(|| {
+                        InvalidSnapshotError::MissingAttribute("price_oracle_slot".to_string())
+                    })?;
+                // Prices packed with other values give the bits of the word they occupy
+                let field = |name: &str, default: usize| {
+                    attributes
+                        .get(name)
+                        .map_or(default, |value| u32::from(value.clone()) as usize)
+                };
+                Ok::<_, InvalidSnapshotError>(PriceOracle::packed(
+                    Address::from_slice(oracle.as_ref()),
+                    U256::from_be_slice(slot.as_ref()),
+                    field("price_oracle_offset", 0),
+                    field("price_oracle_bits", 256),
+                ))
+            })
+            .transpose()?;
 
         let protocol_name = snapshot
             .component
@@ -142,7 +182,8 @@ impl TryFromWithBlock<ComponentWithState> for EVMPoolState<PreCachedDB> {
                     .protocol_system
                     .as_str()
             });
-        let adapter_bytecode = Bytecode::new_raw(get_adapter_file(protocol_name)?.into());
+        let adapter_file = get_adapter_file(protocol_name)?;
+        let adapter_bytecode = Bytecode::new_raw(adapter_file.into());
         let adapter_contract_address =
             Address::from_str(&format!("{:0>40}", hex::encode(protocol_name))).map_err(|_| {
                 InvalidSnapshotError::ValueError(
@@ -155,13 +196,24 @@ impl TryFromWithBlock<ComponentWithState> for EVMPoolState<PreCachedDB> {
                 .balances(component_balances)
                 .account_balances(account_balances)
                 .adapter_contract_bytecode(adapter_bytecode)
+                .adapter_protocol(protocol_name)
                 .involved_contracts(involved_contracts)
                 .stateless_contracts(stateless_contracts)
-                .manual_updates(manual_updates);
+                .manual_updates(manual_updates)
+                .quirks(quirks(protocol_name));
 
         if let Some(balance_owner) = balance_owner {
             pool_state_builder = pool_state_builder.balance_owner(balance_owner)
         };
+        if let Some(fee_controller) = fee_controller {
+            pool_state_builder = pool_state_builder.fee_controller(fee_controller)
+        };
+        if let Some(price_oracle) = price_oracle {
+            pool_state_builder = pool_state_builder.price_oracle(price_oracle)
+        };
+        if let Some(swap_caller) = swap_caller(protocol_name) {
+            pool_state_builder = pool_state_builder.swap_caller(swap_caller)
+        };
 
         let mut pool_state = pool_state_builder
             .build(SHARED_TYCHO_DB.clone())
@@ -1,25 +1,99 @@
-use std::{collections::HashMap, fmt::Debug};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    sync::{Arc, RwLock},
+};
 
-use alloy_primitives::{keccak256, Address, Keccak256, B256, U256};
+use alloy_primitives::{Address, Keccak256, B256, U256};
 use alloy_sol_types::SolValue;
-use chrono::Utc;
-use revm::{
-    db::DatabaseRef,
-    primitives::{AccountInfo, Bytecode},
-};
+use lazy_static::lazy_static;
+use revm::{db::DatabaseRef, primitives::Bytecode};
 
 use super::{
+    adapter_contract::{
+        decode_fixed_point_prices, decode_price_fractions, AdapterDecodeError, Trade,
+    },
+    caller::SwapCaller,
     constants::{EXTERNAL_ACCOUNT, MAX_BALANCE},
+    models::Capability,
     utils::coerce_error,
 };
 use crate::{
     evm::{
-        engine_db::engine_db_interface::EngineDatabaseInterface,
+        engine_db::{
+            account_builder::AccountBuilder, engine_db_interface::EngineDatabaseInterface,
+            simulation_db::BlockHeader,
+        },
         simulation::{SimulationEngine, SimulationParameters, SimulationResult},
     },
     protocol::errors::SimulationError,
 };
 
+lazy_static! {
+    /// Interface versions of known adapter bytecodes, by code hash
+    static ref ADAPTER_VERSIONS: RwLock<HashMap<B256, AdapterVersion>> =
+        RwLock::new(HashMap::new());
+}
+
+/// Interface version of a swap adapter contract.
+///
+/// The version is negotiated when a pool is built: adapters whose bytecode hash was registered with
+/// `register_adapter_version` use the registered version, others use `V2` if they advertise the
+/// `AdapterV2` capability and `V1` otherwise.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum AdapterVersion {
+    /// Prices are returned as `(numerator, denominator)` fractions.
+    #[default]
+    V1,
+    /// Prices are returned as fixed point numbers with 18 decimals.
+    V2,
+}
+
+impl AdapterVersion {
+    /// Returns the registered version of an adapter bytecode.
+    pub fn from_code_hash(code_hash: &B256) -> Option<Self> {
+        ADAPTER_VERSIONS
+            .read()
+            .unwrap()
+            .get(code_hash)
+            .copied()
+    }
+
+    /// Returns the version advertised by an adapter's capabilities.
+    pub fn from_capabilities(capabilities: &HashSet<Capability>) -> Self {
+        if capabilities.contains(&Capability::AdapterV2) {
+            AdapterVersion::V2
+        } else {
+            AdapterVersion::V1
+        }
+    }
+
+    /// Decodes the return data of the adapter's `price` function.
+    pub fn decode_prices(&self, data: &[u8]) -> Result<Vec<f64>, AdapterDecodeError> {
+        match self {
+            AdapterVersion::V1 => decode_price_fractions(data),
+            AdapterVersion::V2 => decode_fixed_point_prices(data),
+        }
+    }
+
+    /// Decodes the return data of the adapter's `swap` function.
+    pub fn decode_trade(&self, data: &[u8]) -> Result<Trade, AdapterDecodeError> {
+        match self {
+            AdapterVersion::V1 => Trade::decode(data),
+            AdapterVersion::V2 => Trade::decode_fixed_point(data),
+        }
+    }
+}
+
+/// Registers the interface version of an adapter bytecode. Use this for adapters that can't
+/// advertise their version through their capabilities.
+pub fn register_adapter_version(code_hash: B256, version: AdapterVersion) {
+    ADAPTER_VERSIONS
+        .write()
+        .unwrap()
+        .insert(code_hash, version);
+}
+
 #[derive(Debug, Clone)]
 pub struct TychoSimulationResponse {
     pub return_value: Vec<u8>,
@@ -45,6 +119,11 @@ pub struct TychoSimulationResponse {
 /// - `address`: The address of the contract being simulated.
 /// - `engine`: The `SimulationEngine` instance responsible for simulating transactions and managing
 ///   the contract's state.
+/// - `version`: The adapter interface version, `None` until negotiated.
+/// - `caller`: The executor calls are sent from, `EXTERNAL_ACCOUNT` if not set.
+/// - `gas_limit`: The gas limit of calls, the engine's default if not set.
+/// - `accessed_accounts`: All accounts read or written by the simulations run so far, shared by all
+///   clones of the contract.
 ///
 /// # Errors
 /// Returns errors of type `SimulationError` when encoding, decoding, or simulation operations
@@ -57,6 +136,10 @@ where
 {
     pub(crate) address: Address,
     pub(crate) engine: SimulationEngine<D>,
+    pub(crate) version: Option<AdapterVersion>,
+    caller: Option<SwapCaller>,
+    gas_limit: Option<u64>,
+    accessed_accounts: Arc<RwLock<HashSet<Address>>>,
 }
 
 impl<D: EngineDatabaseInterface + Clone + Debug> TychoSimulationContract<D>
@@ -65,7 +148,14 @@ where
     <D as EngineDatabaseInterface>::Error: std::fmt::Debug,
 {
     pub fn new(address: Address, engine: SimulationEngine<D>) -> Result<Self, SimulationError> {
-        Ok(Self { address, engine })
+        Ok(Self {
+            address,
+            engine,
+            version: None,
+            caller: None,
+            gas_limit: None,
+            accessed_accounts: Arc::default(),
+        })
     }
 
     // Creates a new instance with the ISwapAdapter ABI
@@ -74,45 +164,70 @@ where
         adapter_contract_bytecode: Bytecode,
         engine: SimulationEngine<D>,
     ) -> Result<Self, SimulationError> {
-        engine.state.init_account(
+        let code_hash = adapter_contract_bytecode.hash_slow();
+        AccountBuilder::new(address)
+            .bytecode(adapter_contract_bytecode)
+            .balance(*MAX_BALANCE)
+            .init(&engine.state);
+
+        Ok(Self {
             address,
-            AccountInfo {
-                balance: *MAX_BALANCE,
-                nonce: 0,
-                code_hash: B256::from(keccak256(
-                    adapter_contract_bytecode
-                        .clone()
-                        .bytes(),
-                )),
-                code: Some(adapter_contract_bytecode),
-            },
-            None,
-            false,
-        );
+            engine,
+            version: AdapterVersion::from_code_hash(&code_hash),
+            caller: None,
+            gas_limit: None,
+            accessed_accounts: Arc::default(),
+        })
+    }
 
-        Ok(Self { address, engine })
+    /// Pins the adapter interface version, skipping negotiation.
+    pub fn with_version(mut self, version: AdapterVersion) -> Self {
+        self.version = Some(version);
+        self
     }
 
-    fn encode_input(&self, selector: &str, args: impl SolValue) -> Vec<u8> {
-        let mut hasher = Keccak256::new();
-        hasher.update(selector.as_bytes());
-        let selector_bytes = &hasher.finalize()[..4];
-        let mut call_data = selector_bytes.to_vec();
-        let mut encoded_args = args.abi_encode();
-        // Remove extra prefix if present (32 bytes for dynamic data)
-        // Alloy encoding is including a prefix for dynamic data indicating the offset or length
-        // but at this point we don't want that
-        if encoded_args.len() > 32 &&
-            encoded_args[..32] ==
-                [0u8; 31]
-                    .into_iter()
-                    .chain([32].to_vec())
-                    .collect::<Vec<u8>>()
-        {
-            encoded_args = encoded_args[32..].to_vec();
+    /// Sends all calls from `caller`, with the storage overrides allowlisting it.
+    pub(crate) fn with_caller(mut self, caller: SwapCaller) -> Self {
+        self.caller = Some(caller);
+        self
+    }
+
+    /// Sets the gas limit of all calls, for adapters needing more than the engine's default.
+    pub(crate) fn with_gas_limit(mut self, gas_limit: u64) -> Self {
+        self.gas_limit = Some(gas_limit);
+        self
+    }
+
+    /// Returns the address calls are sent from by default.
+    pub(crate) fn caller_address(&self) -> Address {
+        self.caller
+            .as_ref()
+            .map_or(*EXTERNAL_ACCOUNT, |caller| caller.address)
+    }
+
+    /// Returns the adapter interface version. Defaults to `V1` if not negotiated.
+    pub fn version(&self) -> AdapterVersion {
+        self.version.unwrap_or_default()
+    }
+
+    /// Sets the interface version from the adapter's capabilities, unless it is already known.
+    pub(crate) fn negotiate_version(&mut self, capabilities: &HashSet<Capability>) {
+        if self.version.is_none() {
+            self.version = Some(AdapterVersion::from_capabilities(capabilities));
         }
-        call_data.extend(encoded_args);
-        call_data
+    }
+
+    /// Returns all accounts read or written by the simulations of this contract so far, e.g. the
+    /// pool, its tokens, oracles and libraries it calls into.
+    pub fn accessed_accounts(&self) -> HashSet<Address> {
+        self.accessed_accounts
+            .read()
+            .unwrap()
+            .clone()
+    }
+
+    fn encode_input(&self, selector: &str, args: impl SolValue) -> Vec<u8> {
+        encode_call(selector, args)
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -120,27 +235,35 @@ where
         &self,
         selector: &str,
         args: impl SolValue,
-        block_number: u64,
-        timestamp: Option<u64>,
+        block: &BlockHeader,
         overrides: Option<HashMap<Address, HashMap<U256, U256>>>,
         caller: Option<Address>,
         value: U256,
     ) -> Result<TychoSimulationResponse, SimulationError> {
         let call_data = self.encode_input(selector, args);
+        let caller = caller.unwrap_or_else(|| self.caller_address());
+        let overrides = match &self.caller {
+            Some(swap_caller) if !swap_caller.allowlists.is_empty() => {
+                let mut merged = overrides.unwrap_or_default();
+                for (address, slots) in swap_caller.overwrites(caller) {
+                    merged
+                        .entry(address)
+                        .or_default()
+                        .extend(slots);
+                }
+                Some(merged)
+            }
+            _ => overrides,
+        };
         let params = SimulationParameters {
             data: call_data,
             to: self.address,
-            block_number,
-            timestamp: timestamp.unwrap_or_else(|| {
-                Utc::now()
-                    .naive_utc()
-                    .and_utc()
-                    .timestamp() as u64
-            }),
+            block: *block,
             overrides,
-            caller: caller.unwrap_or(*EXTERNAL_ACCOUNT),
+            caller,
             value,
-            gas_limit: None,
+            gas_limit: self.gas_limit,
+            spec_id: None,
         };
 
         let sim_result = self.simulate(params)?;
@@ -152,10 +275,43 @@ where
     }
 
     fn simulate(&self, params: SimulationParameters) -> Result<SimulationResult, SimulationError> {
-        self.engine
+        let result = self
+            .engine
             .simulate(&params)
-            .map_err(|e| coerce_error(&e, "pool_state", params.gas_limit))
+            .map_err(|e| coerce_error(&e, "pool_state", params.gas_limit))?;
+        // The result holds every account loaded during execution, read only ones included
+        let mut accessed = self.accessed_accounts.write().unwrap();
+        accessed.extend(
+            result
+                .state_updates
+                .keys()
+                .filter(|address| **address != params.caller),
+        );
+        Ok(result)
+    }
+}
+
+/// Encodes the call data of a call to the function `selector`, e.g. `balanceOf(address)`.
+pub(crate) fn encode_call(selector: &str, args: impl SolValue) -> Vec<u8> {
+    let mut hasher = Keccak256::new();
+    hasher.update(selector.as_bytes());
+    let selector_bytes = &hasher.finalize()[..4];
+    let mut call_data = selector_bytes.to_vec();
+    let mut encoded_args = args.abi_encode();
+    // Remove extra prefix if present (32 bytes for dynamic data)
+    // Alloy encoding is including a prefix for dynamic data indicating the offset or length
+    // but at this point we don't want that
+    if encoded_args.len() > 32 &&
+        encoded_args[..32] ==
+            [0u8; 31]
+                .into_iter()
+                .chain([32].to_vec())
+                .collect::<Vec<u8>>()
+    {
+        encoded_args = encoded_args[32..].to_vec();
     }
+    call_data.extend(encoded_args);
+    call_data
 }
 
 #[cfg(test)]
@@ -170,8 +326,12 @@ mod tests {
 
     use super::*;
     use crate::evm::{
-        engine_db::engine_db_interface::EngineDatabaseInterface,
+        asm::assemble,
+        engine_db::{
+            create_engine, engine_db_interface::EngineDatabaseInterface, tycho_db::PreCachedDB,
+        },
         protocol::vm::{constants::BALANCER_V2, utils::string_to_bytes32},
+        ContractCompiler,
     };
 
     #[derive(Debug, Clone)]
@@ -267,4 +427,97 @@ mod tests {
         assert_eq!(&encoded[36..68], &expected_sell_token); // 32 bytes for address (padded)
         assert_eq!(&encoded[68..100], &expected_buy_token); // 32 bytes for address (padded)
     }
+
+    #[test]
+    fn test_negotiate_version() {
+        let mut contract = create_contract();
+        assert_eq!(contract.version, None);
+
+        contract.negotiate_version(&HashSet::from([Capability::SellSide, Capability::AdapterV2]));
+        assert_eq!(contract.version(), AdapterVersion::V2);
+
+        // Already negotiated versions are kept
+        contract.negotiate_version(&HashSet::new());
+        assert_eq!(contract.version(), AdapterVersion::V2);
+    }
+
+    #[test]
+    fn test_registered_version() {
+        let bytecode = Bytecode::new_raw(vec![0x60, 0x00, 0x00].into());
+        register_adapter_version(bytecode.hash_slow(), AdapterVersion::V2);
+
+        let mut contract = TychoSimulationContract::new_swap_adapter(
+            Address::ZERO,
+            bytecode,
+            create_mock_engine(),
+        )
+        .unwrap();
+        contract.negotiate_version(&HashSet::new());
+
+        assert_eq!(contract.version(), AdapterVersion::V2);
+    }
+
+    #[test]
+    fn test_decode_versions() {
+        let v1 = (U256::from(100), U256::from(50_000), (U256::from(3), U256::from(2))).abi_encode();
+        let v2 = (U256::from(100), U256::from(50_000), U256::from(1_500_000_000_000_000_000u128))
+            .abi_encode();
+
+        let expected = Trade { amount: U256::from(100), gas: U256::from(50_000), price: 1.5 };
+        assert_eq!(
+            AdapterVersion::V1
+                .decode_trade(&v1)
+                .unwrap(),
+            expected
+        );
+        assert_eq!(
+            AdapterVersion::V2
+                .decode_trade(&v2)
+                .unwrap(),
+            expected
+        );
+        assert!(AdapterVersion::V2
+            .decode_trade(&v1)
+            .is_err());
+    }
+
+    #[test]
+    fn test_call_allowlists_sender() {
+        // Returns whether the caller is set in the `address => bool` mapping at slot 0
+        let allowlist = assemble(
+            "CALLER\n PUSH1 0x00\n MSTORE\n PUSH1 0x00\n PUSH1 0x20\n MSTORE\n PUSH1 0x40\n \
+             PUSH1 0x00\n KECCAK256\n SLOAD\n PUSH1 0x00\n MSTORE\n PUSH1 0x20\n PUSH1 0x00\n \
+             RETURN",
+        );
+        let (address, executor, sender) =
+            (Address::repeat_byte(0x01), Address::repeat_byte(0x02), Address::repeat_byte(0x03));
+        let engine = create_engine(PreCachedDB::new().unwrap(), false).unwrap();
+        AccountBuilder::new(address)
+            .code(allowlist)
+            .empty_storage()
+            .mocked()
+            .init(&engine.state);
+        for account in [executor, sender] {
+            engine
+                .state
+                .init_account(account, AccountInfo::default(), None, false);
+        }
+        let contract = TychoSimulationContract::new(address, engine)
+            .unwrap()
+            .with_caller(SwapCaller::new(executor).allowlisted_in(
+                address,
+                U256::ZERO,
+                ContractCompiler::Solidity,
+            ));
+        let is_allowed = |caller: Option<Address>| {
+            let res = contract
+                .call("isAllowed()", (), &BlockHeader::default(), None, caller, U256::ZERO)
+                .unwrap();
+            U256::from_be_slice(&res.return_value)
+        };
+
+        assert_eq!(is_allowed(None), U256::from(1));
+        // Calls on behalf of a sender allowlist the sender, not the executor
+        assert_eq!(is_allowed(Some(sender)), U256::from(1));
+    }
 }
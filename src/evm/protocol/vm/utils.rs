@@ -80,6 +80,9 @@ pub(crate) fn coerce_error(
         SimulationEngineError::StorageError(message) => {
             SimulationError::RecoverableError(message.clone())
         }
+        SimulationEngineError::InvalidParams(message) => {
+            SimulationError::InvalidInput(message.clone(), None)
+        }
         _ => SimulationError::FatalError(err.clone().to_string()), /* Otherwise return the
                                                                     * original error */
     }
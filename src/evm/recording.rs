@@ -0,0 +1,266 @@
+//! Recording and replay of raw stream messages
+//!
+//! A `StreamRecorder` captures the raw messages of a Tycho stream, before decoding, to a gzip
+//! compressed file, see `ProtocolStreamBuilder::record_to`. A `Recording` read back from that file
+//! can be replayed into the decoder with `ProtocolStreamBuilder::replay`, which decodes the
+//! messages one after another in the recorded order, so a replay always produces the same block
+//! updates. Recordings are also the input of backtests, see `Recording::into_fixture`, and the
+//! easiest way for users to share a stream that fails to decode.
+//!
+//! # Format
+//!
+//! The file is gzip compressed JSON lines. The first line is a header with the format version,
+//! the chain and the tokens known to the decoder, every following line is a `FeedMessage`.
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Write},
+    path::Path,
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tycho_client::feed::FeedMessage;
+use tycho_core::{dto::ResponseToken, models::Chain, Bytes};
+
+use crate::{evm::backtest::BacktestFixture, models::Token};
+
+/// Version of the recording format written by `StreamRecorder`
+pub const RECORDING_VERSION: u32 = 1;
+
+#[derive(Error, Debug)]
+pub enum RecordingError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid recording: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Unsupported recording version {0}, expected {RECORDING_VERSION}")]
+    Version(u32),
+    #[error("Recording is empty")]
+    Empty,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RecordingHeader {
+    version: u32,
+    chain: Chain,
+    tokens: Vec<ResponseToken>,
+}
+
+/// Writes the raw messages of a stream to a recording file, see module docs.
+pub struct StreamRecorder {
+    writer: GzEncoder<BufWriter<File>>,
+    messages: usize,
+}
+
+impl StreamRecorder {
+    /// Creates the recording file at `path`, replacing any existing file, and writes the header.
+    pub fn create(
+        path: impl AsRef<Path>,
+        chain: Chain,
+        tokens: &HashMap<Bytes, Token>,
+    ) -> Result<Self, RecordingError> {
+        let mut tokens: Vec<_> = tokens
+            .values()
+            .map(|token| response_token(token, chain))
+            .collect();
+        // Sorted, so recordings of the same stream are identical
+        tokens.sort_by(|a, b| a.address.cmp(&b.address));
+
+        let mut recorder = Self {
+            writer: GzEncoder::new(BufWriter::new(File::create(path)?), Compression::default()),
+            messages: 0,
+        };
+        recorder.write_line(&RecordingHeader { version: RECORDING_VERSION, chain, tokens })?;
+        Ok(recorder)
+    }
+
+    /// Appends a message to the recording.
+    ///
+    /// The compressed stream is flushed after every message, so a recording stays readable up to
+    /// the last message if the process crashes.
+    pub fn record(&mut self, msg: &FeedMessage) -> Result<(), RecordingError> {
+        self.write_line(msg)?;
+        self.writer.flush()?;
+        self.messages += 1;
+        Ok(())
+    }
+
+    /// Number of messages recorded so far
+    pub fn messages(&self) -> usize {
+        self.messages
+    }
+
+    /// Completes the recording. Returns the number of recorded messages.
+    pub fn finish(self) -> Result<usize, RecordingError> {
+        self.writer.finish()?.flush()?;
+        Ok(self.messages)
+    }
+
+    fn write_line<T: Serialize>(&mut self, value: &T) -> Result<(), RecordingError> {
+        serde_json::to_writer(&mut self.writer, value)?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+/// A recorded stream, see module docs.
+#[derive(Debug, Clone)]
+pub struct Recording {
+    pub chain: Chain,
+    pub tokens: Vec<ResponseToken>,
+    /// The recorded messages, in the order they were received
+    pub messages: Vec<FeedMessage>,
+}
+
+impl Recording {
+    /// Reads the recording at `path`.
+    ///
+    /// A truncated last line, e.g. of a recording interrupted by a crash, is ignored.
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, RecordingError> {
+        let mut lines = BufReader::new(GzDecoder::new(File::open(path)?)).lines();
+        let header: RecordingHeader = serde_json::from_str(
+            &lines
+                .next()
+                .ok_or(RecordingError::Empty)??,
+        )?;
+        if header.version != RECORDING_VERSION {
+            return Err(RecordingError::Version(header.version));
+        }
+
+        let mut messages = Vec::new();
+        for line in lines {
+            let line = match line {
+                Ok(line) => line,
+                // gzip trailer missing
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            };
+            match serde_json::from_str(&line) {
+                Ok(msg) => messages.push(msg),
+                Err(e) if e.is_eof() => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(Self { chain: header.chain, tokens: header.tokens, messages })
+    }
+
+    /// The tokens of the recording, as used by the decoder.
+    pub fn decoder_tokens(&self) -> HashMap<Bytes, Token> {
+        self.tokens
+            .iter()
+            .filter_map(|token| {
+                Token::try_from(token.clone())
+                    .ok()
+                    .map(|token| (token.address.clone(), token))
+            })
+            .collect()
+    }
+
+    /// Converts the recording into a backtest range. The first message must hold the snapshots of
+    /// the pools, which is the case for recordings started together with the stream.
+    pub fn into_fixture(self) -> Result<BacktestFixture, RecordingError> {
+        let mut messages = self.messages.into_iter();
+        let snapshot = messages
+            .next()
+            .ok_or(RecordingError::Empty)?;
+        Ok(BacktestFixture { tokens: self.tokens, snapshot, deltas: messages.collect() })
+    }
+}
+
+fn response_token(token: &Token, chain: Chain) -> ResponseToken {
+    ResponseToken {
+        chain: chain.into(),
+        address: token.address.clone(),
+        symbol: token.symbol.clone(),
+        decimals: token.decimals as u32,
+        tax: 0,
+        gas: vec![Some(u64::try_from(&token.gas).unwrap_or(u64::MAX))],
+        quality: 100,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use num_bigint::ToBigUint;
+
+    use super::*;
+
+    fn load_test_msg(name: &str) -> FeedMessage {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join(format!("tests/assets/decoder/{}.json", name));
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    fn tokens() -> HashMap<Bytes, Token> {
+        [
+            Token::new(
+                "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2",
+                18,
+                "WETH",
+                10_000.to_biguint().unwrap(),
+            ),
+            Token::new(
+                "0xdac17f958d2ee523a2206206994597c13d831ec7",
+                6,
+                "USDT",
+                10_000.to_biguint().unwrap(),
+            ),
+        ]
+        .into_iter()
+        .map(|token| (token.address.clone(), token))
+        .collect()
+    }
+
+    #[test]
+    fn test_record_and_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stream.jsonl.gz");
+        let messages =
+            vec![load_test_msg("uniswap_v2_snapshot"), load_test_msg("uniswap_v2_delta")];
+
+        let mut recorder = StreamRecorder::create(&path, Chain::Ethereum, &tokens()).unwrap();
+        for msg in &messages {
+            recorder.record(msg).unwrap();
+        }
+        assert_eq!(recorder.finish().unwrap(), 2);
+
+        let recording = Recording::read(&path).unwrap();
+        assert_eq!(recording.chain, Chain::Ethereum);
+        assert_eq!(recording.messages, messages);
+        assert_eq!(recording.decoder_tokens(), tokens());
+
+        let fixture = recording.into_fixture().unwrap();
+        assert_eq!(fixture.snapshot, messages[0]);
+        assert_eq!(fixture.deltas, messages[1..]);
+    }
+
+    #[test]
+    fn test_read_unfinished_recording() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stream.jsonl.gz");
+        let msg = load_test_msg("uniswap_v2_snapshot");
+
+        let mut recorder = StreamRecorder::create(&path, Chain::Ethereum, &tokens()).unwrap();
+        recorder.record(&msg).unwrap();
+        // simulate a crash: the gzip trailer is never written
+        std::mem::forget(recorder);
+
+        let recording = Recording::read(&path).unwrap();
+        assert_eq!(recording.messages, vec![msg]);
+    }
+
+    #[test]
+    fn test_read_unsupported_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stream.jsonl.gz");
+        let mut writer = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        writeln!(writer, r#"{{"version":0,"chain":"ethereum","tokens":[]}}"#).unwrap();
+        writer.finish().unwrap();
+
+        assert!(matches!(Recording::read(&path), Err(RecordingError::Version(0))));
+    }
+}
@@ -0,0 +1,328 @@
+//! Chain reorg simulation for tests
+//!
+//! Enabled with the `test-utils` feature. `ReorgChain` applies blocks to a `PreCachedDB` and to a
+//! set of protocol states while remembering how to undo each of them, so reorg sequences like
+//! "apply A and B, revert to A, apply B'" can be replayed. Every step returns the `BlockUpdate` a
+//! stream would emit for it, to be fed into the consumer under test. The `assert_*` methods then
+//! check the consumer converged to the state of the canonical chain.
+//!
+//! ```ignore
+//! let mut chain = ReorgChain::new(PreCachedDB::new()?);
+//! store.apply(chain.apply(block_a));
+//! store.apply(chain.apply(block_b));
+//! store.apply(chain.revert_to(block_a.header.number));
+//! store.apply(chain.apply(block_b_prime));
+//! chain.assert_states_converged(&store);
+//! ```
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    evm::{
+        engine_db::{
+            simulation_db::BlockHeader,
+            tycho_db::{DbDelta, PreCachedDB},
+        },
+        tycho_models::AccountUpdate,
+    },
+    protocol::{
+        freshness::StateStore,
+        models::{BlockUpdate, ProtocolComponent},
+        state::ProtocolSim,
+    },
+};
+
+/// A block to apply to a `ReorgChain`.
+#[derive(Debug)]
+pub struct ChainBlock {
+    pub header: BlockHeader,
+    /// Account changes applied to the database
+    pub accounts: Vec<AccountUpdate>,
+    /// Protocol states changed in this block
+    pub states: HashMap<String, Box<dyn ProtocolSim>>,
+    /// Pairs created in this block
+    pub new_pairs: HashMap<String, ProtocolComponent>,
+}
+
+impl ChainBlock {
+    pub fn new(header: BlockHeader) -> Self {
+        Self { header, accounts: Vec::new(), states: HashMap::new(), new_pairs: HashMap::new() }
+    }
+
+    pub fn account(mut self, update: AccountUpdate) -> Self {
+        self.accounts.push(update);
+        self
+    }
+
+    pub fn state(mut self, id: &str, state: Box<dyn ProtocolSim>) -> Self {
+        self.states
+            .insert(id.to_string(), state);
+        self
+    }
+
+    pub fn pair(mut self, id: &str, component: ProtocolComponent) -> Self {
+        self.new_pairs
+            .insert(id.to_string(), component);
+        self
+    }
+}
+
+/// How to undo an applied block
+#[derive(Debug)]
+struct Undo {
+    header: BlockHeader,
+    previous_block: Option<BlockHeader>,
+    db: DbDelta,
+    /// States before the block, `None` if the state did not exist
+    states: HashMap<String, Option<Box<dyn ProtocolSim>>>,
+    /// Pairs created by the block
+    pairs: Vec<String>,
+}
+
+/// A chain of blocks that can be reverted, backed by a `PreCachedDB`.
+#[derive(Debug)]
+pub struct ReorgChain {
+    db: PreCachedDB,
+    block: Option<BlockHeader>,
+    states: HashMap<String, Box<dyn ProtocolSim>>,
+    pairs: HashMap<String, ProtocolComponent>,
+    history: Vec<Undo>,
+}
+
+impl ReorgChain {
+    /// Creates a chain on top of the current state of `db`.
+    pub fn new(db: PreCachedDB) -> Self {
+        let block = db.block();
+        Self { db, block, states: HashMap::new(), pairs: HashMap::new(), history: Vec::new() }
+    }
+
+    pub fn db(&self) -> &PreCachedDB {
+        &self.db
+    }
+
+    /// Returns the current head of the chain.
+    pub fn head(&self) -> Option<BlockHeader> {
+        self.block
+    }
+
+    pub fn get(&self, id: &str) -> Option<&dyn ProtocolSim> {
+        self.states.get(id).map(Box::as_ref)
+    }
+
+    /// Applies a block on top of the current head and returns the update a stream would emit for
+    /// it.
+    pub fn apply(&mut self, block: ChainBlock) -> BlockUpdate {
+        let before = copy_db(&self.db);
+        self.db
+            .update(block.accounts, Some(block.header));
+        let mut db_undo = PreCachedDB::diff(&self.db, &before);
+        db_undo.block = self.block;
+
+        let states = block
+            .states
+            .iter()
+            .map(|(id, _)| {
+                (
+                    id.clone(),
+                    self.states
+                        .get(id)
+                        .map(|state| state.clone_box()),
+                )
+            })
+            .collect();
+        self.history.push(Undo {
+            header: block.header,
+            previous_block: self.block,
+            db: db_undo,
+            states,
+            pairs: block
+                .new_pairs
+                .keys()
+                .cloned()
+                .collect(),
+        });
+
+        self.block = Some(block.header);
+        self.pairs
+            .extend(block.new_pairs.clone());
+        self.states.extend(
+            block
+                .states
+                .iter()
+                .map(|(id, state)| (id.clone(), state.clone_box())),
+        );
+        BlockUpdate::new(block.header.number, block.states, block.new_pairs)
+            .set_block((&block.header).into())
+    }
+
+    /// Reverts all blocks above `number` and returns the update a stream would emit for the revert:
+    /// the states as of block `number` and the pairs created after it as removed.
+    ///
+    /// # Panics
+    ///
+    /// If the chain would have to be reverted past the first applied block.
+    pub fn revert_to(&mut self, number: u64) -> BlockUpdate {
+        let mut reverted_states = HashSet::new();
+        let mut removed_pairs = HashMap::new();
+        while self
+            .history
+            .last()
+            .is_some_and(|undo| undo.header.number > number)
+        {
+            let undo = self
+                .history
+                .pop()
+                .expect("History is not empty");
+            self.db.apply(&undo.db);
+            self.block = undo.previous_block;
+            for (id, state) in undo.states {
+                match state {
+                    Some(state) => self.states.insert(id.clone(), state),
+                    None => self.states.remove(&id),
+                };
+                reverted_states.insert(id);
+            }
+            for id in undo.pairs {
+                if let Some(component) = self.pairs.remove(&id) {
+                    removed_pairs.insert(id, component);
+                }
+            }
+        }
+        assert!(
+            self.block
+                .is_some_and(|block| block.number == number),
+            "Block {} is not part of the chain",
+            number
+        );
+
+        let states = reverted_states
+            .into_iter()
+            .filter_map(|id| {
+                let state = self.states.get(&id)?.clone_box();
+                Some((id, state))
+            })
+            .collect();
+        let block = self.block.expect("Head is set");
+        BlockUpdate::new(block.number, states, HashMap::new())
+            .set_removed_pairs(removed_pairs)
+            .set_block((&block).into())
+    }
+
+    /// Asserts `db` holds the same accounts, storage and block as the chain's database.
+    pub fn assert_db_converged(&self, db: &PreCachedDB) {
+        let delta = PreCachedDB::diff(&self.db, db);
+        assert!(delta.is_empty(), "Database diverged from the chain: {:?}", delta);
+    }
+
+    /// Asserts `store` holds exactly the protocol states of the chain, at the chain's head.
+    pub fn assert_states_converged(&self, store: &StateStore) {
+        assert_eq!(
+            store.block().map(|block| block.number),
+            self.block.map(|block| block.number),
+            "Store is at a different block than the chain"
+        );
+        for (id, state) in &self.states {
+            let stored = store
+                .get(id)
+                .unwrap_or_else(|| panic!("Pool {} missing from store", id));
+            assert!(state.eq(stored), "Pool {} diverged: {:?} != {:?}", id, stored, state);
+        }
+        for id in store.ids() {
+            assert!(self.states.contains_key(id), "Pool {} is not part of the chain", id);
+        }
+    }
+}
+
+/// Returns an independent copy of `db`, ignoring temp storage.
+fn copy_db(db: &PreCachedDB) -> PreCachedDB {
+    let copy = PreCachedDB::new().expect("Failed to create PreCachedDB");
+    copy.apply(&PreCachedDB::diff(&copy, db));
+    copy
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy_primitives::{Address, B256, U256};
+
+    use super::*;
+    use crate::evm::{
+        engine_db::account_builder::AccountBuilder,
+        protocol::uniswap_v2::state::UniswapV2State,
+        tycho_models::{Chain, ChangeType},
+    };
+
+    fn header(number: u64, fork: u8) -> BlockHeader {
+        BlockHeader {
+            number,
+            hash: B256::repeat_byte(fork),
+            timestamp: number * 12,
+            ..Default::default()
+        }
+    }
+
+    fn block(number: u64, fork: u8, slot_value: u64, reserve: u64) -> ChainBlock {
+        ChainBlock::new(header(number, fork))
+            .account(AccountUpdate::new(
+                Address::with_last_byte(1),
+                Chain::Ethereum,
+                HashMap::from([(U256::ZERO, U256::from(slot_value))]),
+                None,
+                None,
+                ChangeType::Update,
+            ))
+            .state("pool", Box::new(UniswapV2State::new(U256::from(reserve), U256::from(reserve))))
+    }
+
+    fn chain() -> ReorgChain {
+        let db = PreCachedDB::new().unwrap();
+        AccountBuilder::new(Address::with_last_byte(1))
+            .empty_storage()
+            .mocked()
+            .init(&db);
+        ReorgChain::new(db)
+    }
+
+    #[test]
+    fn test_reorg_converges() {
+        let mut reorged = chain();
+        let mut store = StateStore::new(None, 0);
+        store.apply(reorged.apply(block(1, 0xa, 10, 100)));
+        store.apply(reorged.apply(block(2, 0xb, 20, 200)));
+        store.apply(reorged.revert_to(1));
+        store.apply(reorged.apply(block(2, 0xc, 30, 300)));
+
+        let mut canonical = chain();
+        canonical.apply(block(1, 0xa, 10, 100));
+        canonical.apply(block(2, 0xc, 30, 300));
+
+        canonical.assert_db_converged(reorged.db());
+        canonical.assert_states_converged(&store);
+        assert_eq!(reorged.head(), Some(header(2, 0xc)));
+    }
+
+    #[test]
+    fn test_revert_restores_state() {
+        let mut chain = chain();
+        chain.apply(block(1, 0xa, 10, 100));
+        chain.apply(block(2, 0xb, 20, 200));
+
+        let update = chain.revert_to(1);
+
+        assert_eq!(update.block_number, 1);
+        assert!(update.states["pool"].eq(&UniswapV2State::new(U256::from(100), U256::from(100))));
+        assert_eq!(
+            chain
+                .db()
+                .get_storage(&Address::with_last_byte(1), &U256::ZERO),
+            Some(U256::from(10))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "not part of the chain")]
+    fn test_revert_unknown_block() {
+        let mut chain = chain();
+        chain.apply(block(2, 0xa, 10, 100));
+
+        chain.revert_to(1);
+    }
+}
@@ -0,0 +1,287 @@
+//! Batch settlement simulation
+//!
+//! Simulates CoW Protocol style batch settlements: a single settlement contract call executing
+//! multiple user trades (and arbitrary interactions) at uniform clearing prices. After simulating
+//! the settlement, the amount each user actually received is measured and checked against the
+//! user's limit price and the clearing prices of the batch.
+use std::{collections::HashMap, fmt::Debug};
+
+use alloy_primitives::{Address, U256};
+use alloy_sol_types::SolValue;
+use revm::DatabaseRef;
+
+use crate::{
+    evm::{
+        account_storage::StateUpdate,
+        engine_db::{engine_db_interface::EngineDatabaseInterface, simulation_db::BlockHeader},
+        protocol::vm::{tycho_simulation_contract::TychoSimulationContract, utils::coerce_error},
+        simulation::{SimulationEngine, SimulationParameters},
+        StorageMap,
+    },
+    protocol::errors::SimulationError,
+};
+
+/// A user trade executed in a batch.
+///
+/// The user signed an order to sell at most `sell_amount` of `sell_token` for at least
+/// `buy_amount` of `buy_token`. The limit price is therefore `buy_amount / sell_amount`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserTrade {
+    /// The order owner, receiving the bought tokens
+    pub owner: Address,
+    pub sell_token: Address,
+    pub buy_token: Address,
+    /// Sell amount of the signed order
+    pub sell_amount: U256,
+    /// Minimum buy amount of the signed order
+    pub buy_amount: U256,
+    /// Amount of the sell token executed in this batch
+    pub executed_sell_amount: U256,
+}
+
+/// A batch settlement to simulate.
+#[derive(Debug, Clone)]
+pub struct BatchSettlement {
+    /// The settlement contract
+    pub settlement: Address,
+    /// The solver submitting the settlement
+    pub solver: Address,
+    /// Calldata of the settlement call, encoding trades and interactions
+    pub calldata: Vec<u8>,
+    /// Uniform clearing prices of the batch, by token
+    pub clearing_prices: HashMap<Address, U256>,
+    /// The user trades contained in the calldata
+    pub trades: Vec<UserTrade>,
+}
+
+/// Outcome of a single trade within a simulated settlement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TradeExecution {
+    pub trade: UserTrade,
+    /// Buy token amount the owner received in the simulation
+    pub received: U256,
+    /// Buy token amount implied by the clearing prices
+    pub expected: U256,
+    /// Whether `received` satisfies the order's limit price
+    pub limit_satisfied: bool,
+}
+
+impl TradeExecution {
+    /// Whether the trade respects both the limit price and the uniform clearing prices.
+    pub fn is_valid(&self) -> bool {
+        self.limit_satisfied && self.received >= self.expected
+    }
+}
+
+/// Result of a simulated batch settlement.
+#[derive(Debug, Clone)]
+pub struct BatchSimulationResult {
+    pub gas_used: u64,
+    pub trades: Vec<TradeExecution>,
+    /// State changes caused by the settlement
+    pub state_updates: StorageMap<Address, StateUpdate>,
+}
+
+impl BatchSimulationResult {
+    /// Whether every trade of the batch is valid.
+    pub fn is_valid(&self) -> bool {
+        self.trades
+            .iter()
+            .all(TradeExecution::is_valid)
+    }
+
+    /// Returns the trades which violate their limit price or the clearing prices.
+    pub fn invalid_trades(&self) -> Vec<&TradeExecution> {
+        self.trades
+            .iter()
+            .filter(|trade| !trade.is_valid())
+            .collect()
+    }
+}
+
+/// Simulates a batch settlement and validates every user trade against the simulated clearing.
+///
+/// The settlement call is simulated from the solver account. The buy token balance of each trade
+/// owner is then read before and after the settlement; the difference is the amount received.
+/// Only ERC20 buy tokens are supported. If several trades of the same owner buy the same token,
+/// the received amount is attributed to each of them, so such batches should be split.
+///
+/// Returns an error if the settlement reverts, or if a clearing price is missing or zero.
+pub fn simulate_batch<D>(
+    batch: &BatchSettlement,
+    block: &BlockHeader,
+    engine: &SimulationEngine<D>,
+) -> Result<BatchSimulationResult, SimulationError>
+where
+    D: EngineDatabaseInterface + Clone + Debug,
+    <D as DatabaseRef>::Error: Debug,
+    <D as EngineDatabaseInterface>::Error: Debug,
+{
+    let params = SimulationParameters {
+        caller: batch.solver,
+        to: batch.settlement,
+        data: batch.calldata.clone(),
+        value: U256::ZERO,
+        overrides: None,
+        gas_limit: None,
+        spec_id: None,
+        block: *block,
+    };
+    let result = engine
+        .simulate(&params)
+        .map_err(|e| coerce_error(&e, "batch settlement", None))?;
+
+    let post_settlement: HashMap<Address, HashMap<U256, U256>> = result
+        .committable_state_updates()
+        .map_err(|e| coerce_error(&e, "batch settlement", None))?
+        .iter()
+        .filter_map(|(address, update)| {
+            update.storage.clone().map(|storage| {
+                (
+                    *address,
+                    storage
+                        .into_iter()
+                        .collect::<HashMap<_, _>>(),
+                )
+            })
+        })
+        .collect();
+
+    let mut trades = Vec::with_capacity(batch.trades.len());
+    for trade in &batch.trades {
+        let before = balance_of(engine, block, trade.buy_token, trade.owner, None)?;
+        let after =
+            balance_of(engine, block, trade.buy_token, trade.owner, Some(post_settlement.clone()))?;
+        let received = after.saturating_sub(before);
+        trades.push(check_trade(trade, received, &batch.clearing_prices)?);
+    }
+
+    Ok(BatchSimulationResult {
+        gas_used: result.gas_used,
+        trades,
+        state_updates: result.state_updates,
+    })
+}
+
+/// Checks a trade's received amount against its limit price and the batch clearing prices.
+fn check_trade(
+    trade: &UserTrade,
+    received: U256,
+    clearing_prices: &HashMap<Address, U256>,
+) -> Result<TradeExecution, SimulationError> {
+    let price = |token: &Address| {
+        clearing_prices
+            .get(token)
+            .filter(|price| !price.is_zero())
+            .copied()
+            .ok_or_else(|| {
+                SimulationError::InvalidInput(format!("Missing clearing price for {}", token), None)
+            })
+    };
+    let sell_price = price(&trade.sell_token)?;
+    let buy_price = price(&trade.buy_token)?;
+
+    // executed_buy = executed_sell * p(sell) / p(buy)
+    let expected = trade
+        .executed_sell_amount
+        .checked_mul(sell_price)
+        .map(|value| value / buy_price)
+        .ok_or_else(|| {
+            SimulationError::FatalError("Overflow computing the expected buy amount".to_string())
+        })?;
+
+    // received / executed_sell >= buy_amount / sell_amount, compared without division
+    let limit_satisfied = match (
+        received.checked_mul(trade.sell_amount),
+        trade
+            .buy_amount
+            .checked_mul(trade.executed_sell_amount),
+    ) {
+        (Some(lhs), Some(rhs)) => lhs >= rhs,
+        _ => {
+            return Err(SimulationError::FatalError("Overflow checking the limit price".to_string()))
+        }
+    };
+
+    Ok(TradeExecution { trade: trade.clone(), received, expected, limit_satisfied })
+}
+
+fn balance_of<D>(
+    engine: &SimulationEngine<D>,
+    block: &BlockHeader,
+    token: Address,
+    owner: Address,
+    overrides: Option<HashMap<Address, HashMap<U256, U256>>>,
+) -> Result<U256, SimulationError>
+where
+    D: EngineDatabaseInterface + Clone + Debug,
+    <D as DatabaseRef>::Error: Debug,
+    <D as EngineDatabaseInterface>::Error: Debug,
+{
+    let contract = TychoSimulationContract::new(token, engine.clone())?;
+    let res = contract.call("balanceOf(address)", owner, block, overrides, None, U256::ZERO)?;
+    U256::abi_decode(&res.return_value, true).map_err(|e| {
+        SimulationError::FatalError(format!(
+            "balanceOf call failed: Failed to decode return value: {:?}",
+            e
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trade() -> UserTrade {
+        UserTrade {
+            owner: Address::repeat_byte(1),
+            sell_token: Address::repeat_byte(2),
+            buy_token: Address::repeat_byte(3),
+            // Limit price: at least 2 buy token per sell token
+            sell_amount: U256::from(100),
+            buy_amount: U256::from(200),
+            executed_sell_amount: U256::from(50),
+        }
+    }
+
+    fn clearing_prices() -> HashMap<Address, U256> {
+        // 1 sell token = 3 buy token
+        HashMap::from([
+            (Address::repeat_byte(2), U256::from(3_000)),
+            (Address::repeat_byte(3), U256::from(1_000)),
+        ])
+    }
+
+    #[test]
+    fn test_check_trade_valid() {
+        let res = check_trade(&trade(), U256::from(150), &clearing_prices()).unwrap();
+
+        assert_eq!(res.expected, U256::from(150));
+        assert!(res.limit_satisfied);
+        assert!(res.is_valid());
+    }
+
+    #[test]
+    fn test_check_trade_below_clearing_price() {
+        // Within the limit price, but less than the clearing prices imply
+        let res = check_trade(&trade(), U256::from(120), &clearing_prices()).unwrap();
+
+        assert!(res.limit_satisfied);
+        assert!(!res.is_valid());
+    }
+
+    #[test]
+    fn test_check_trade_violates_limit() {
+        let res = check_trade(&trade(), U256::from(99), &clearing_prices()).unwrap();
+
+        assert!(!res.limit_satisfied);
+        assert!(!res.is_valid());
+    }
+
+    #[test]
+    fn test_check_trade_missing_price() {
+        let res = check_trade(&trade(), U256::from(150), &HashMap::new());
+
+        assert!(matches!(res, Err(SimulationError::InvalidInput(_, None))));
+    }
+}
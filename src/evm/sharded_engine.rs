@@ -0,0 +1,536 @@
+//! Quoting VM pools on several engines in parallel
+//!
+//! All VM pools simulate on the shared `PreCachedDB` by default. With thousands of quotes in
+//! flight, every core reads the same hash maps and their lock, and cache lines bounce between
+//! cores. A `ShardedEngine` partitions the pools by id across several copies of the database,
+//! each owned by a dedicated worker thread that runs all simulations of its pools, so a pool's
+//! state and storage stay in the caches of one core.
+//!
+//! The shards are copies: call `ShardedEngine::sync` with the source database, usually
+//! `SHARED_TYCHO_DB`, after every block. Pools are quoted at the state of the last sync.
+//!
+//! Large batches, e.g. the tens of thousands of quotes of a full depth curve, can be streamed
+//! with `ShardedEngine::quote_stream`, which yields results as they complete and bounds the
+//! number of quotes in flight.
+//!
+//! Quotes have a `Priority`: every shard runs all queued latency-critical quotes before any
+//! background quote, so warm-up or depth-curve jobs can't delay the repricing of a block.
+use std::{
+    collections::{HashMap, VecDeque},
+    iter::Enumerate,
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Condvar, Mutex},
+    thread,
+};
+
+use num_bigint::BigUint;
+use thiserror::Error;
+use tracing::warn;
+
+use crate::{
+    evm::{engine_db::tycho_db::PreCachedDB, protocol::vm::state::EVMPoolState},
+    models::Token,
+    protocol::{
+        errors::SimulationError,
+        models::GetAmountOutResult,
+        state::{fingerprint, ProtocolSim},
+    },
+};
+
+#[derive(Error, Debug)]
+pub enum ShardedEngineError {
+    #[error("Unknown pool {0}")]
+    UnknownPool(String),
+    #[error("Shard {0} stopped")]
+    ShardStopped(usize),
+    #[error(transparent)]
+    Simulation(#[from] SimulationError),
+}
+
+/// A quote to run on a `ShardedEngine`.
+#[derive(Debug, Clone)]
+pub struct QuoteRequest {
+    pub pool: String,
+    pub amount_in: BigUint,
+    pub token_in: Token,
+    pub token_out: Token,
+}
+
+pub type QuoteResult = Result<GetAmountOutResult, ShardedEngineError>;
+
+/// Receives the results of quotes, tagged with the index of their request.
+type QuoteReply = mpsc::Sender<(usize, QuoteResult)>;
+
+/// Scheduling class of a quote, see module docs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Priority {
+    /// Latency-critical quotes, e.g. of a solver repricing the current block
+    #[default]
+    High,
+    /// Quotes that can wait, e.g. cache warm-up or depth curves. Only run while no high priority
+    /// quote is queued on the shard.
+    Background,
+}
+
+enum Command {
+    Insert(String, Box<EVMPoolState<PreCachedDB>>),
+    Remove(String),
+    Quote(usize, QuoteRequest, QuoteReply),
+}
+
+#[derive(Default)]
+struct Queues {
+    /// Pool updates and high priority quotes, in order
+    high: VecDeque<Command>,
+    background: VecDeque<Command>,
+    /// Set when the engine is dropped or the worker exited
+    closed: bool,
+}
+
+/// The commands of a shard, shared by the engine and the shard's worker.
+#[derive(Default)]
+struct ShardQueue {
+    queues: Mutex<Queues>,
+    ready: Condvar,
+}
+
+impl ShardQueue {
+    /// Queues `command`. Returns `false` if the shard is closed.
+    fn push(&self, command: Command, priority: Priority) -> bool {
+        let mut queues = self.queues.lock().unwrap();
+        if queues.closed {
+            return false;
+        }
+        match priority {
+            Priority::High => queues.high.push_back(command),
+            Priority::Background => queues.background.push_back(command),
+        }
+        self.ready.notify_one();
+        true
+    }
+
+    /// Waits for the next command, high priority first. `None` once the queue is closed.
+    fn pop(&self) -> Option<Command> {
+        let mut queues = self.queues.lock().unwrap();
+        loop {
+            if queues.closed {
+                return None;
+            }
+            if let Some(command) = queues
+                .high
+                .pop_front()
+                .or_else(|| queues.background.pop_front())
+            {
+                return Some(command);
+            }
+            queues = self.ready.wait(queues).unwrap();
+        }
+    }
+
+    fn close(&self) {
+        let mut queues = self.queues.lock().unwrap();
+        queues.closed = true;
+        // Pending quotes are dropped with their reply senders, so requesters stop waiting
+        queues.high.clear();
+        queues.background.clear();
+        self.ready.notify_all();
+    }
+}
+
+/// Closes the queue when the worker exits, even by panicking.
+struct CloseOnExit(Arc<ShardQueue>);
+
+impl Drop for CloseOnExit {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+struct Shard {
+    db: PreCachedDB,
+    queue: Arc<ShardQueue>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl Shard {
+    fn send(
+        &self,
+        index: usize,
+        command: Command,
+        priority: Priority,
+    ) -> Result<(), ShardedEngineError> {
+        if self.queue.push(command, priority) {
+            Ok(())
+        } else {
+            Err(ShardedEngineError::ShardStopped(index))
+        }
+    }
+}
+
+/// Partitions VM pools across several database copies and worker threads, see module docs.
+pub struct ShardedEngine {
+    shards: Vec<Shard>,
+}
+
+impl ShardedEngine {
+    /// Starts `shards` workers, each with an empty database. Sync them before inserting pools.
+    pub fn new(shards: usize) -> Self {
+        let shards = (0..shards.max(1))
+            .map(|index| {
+                let db = PreCachedDB::new().expect("Failed to create shard database");
+                let queue = Arc::new(ShardQueue::default());
+                let worker_queue = queue.clone();
+                let worker = thread::Builder::new()
+                    .name(format!("engine-shard-{index}"))
+                    .spawn(move || run_shard(worker_queue))
+                    .expect("Failed to spawn shard worker");
+                Shard { db, queue, worker: Some(worker) }
+            })
+            .collect();
+        Self { shards }
+    }
+
+    /// Number of shards
+    pub fn len(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.is_empty()
+    }
+
+    /// The shard the pool `id` is assigned to.
+    pub fn shard_of(&self, id: &str) -> usize {
+        (fingerprint(id) % self.shards.len() as u64) as usize
+    }
+
+    /// The database of shard `index`.
+    pub fn shard_db(&self, index: usize) -> &PreCachedDB {
+        &self.shards[index].db
+    }
+
+    /// Brings all shards to the state of `source`.
+    ///
+    /// The shards always hold identical data, so the changes are computed once against the first
+    /// shard and applied to all of them.
+    pub fn sync(&self, source: &PreCachedDB) {
+        let delta = PreCachedDB::diff(&self.shards[0].db, source);
+        if delta.is_empty() {
+            return;
+        }
+        for shard in &self.shards {
+            shard.db.apply(&delta);
+        }
+    }
+
+    /// Moves a copy of `pool` onto its shard, replacing any previous state of the pool.
+    pub fn insert_pool(
+        &self,
+        id: &str,
+        pool: &EVMPoolState<PreCachedDB>,
+    ) -> Result<(), ShardedEngineError> {
+        let index = self.shard_of(id);
+        let shard = &self.shards[index];
+        // Pool updates are queued with high priority, so they apply before background quotes
+        // already queued
+        shard.send(
+            index,
+            Command::Insert(id.to_string(), Box::new(pool.with_db(shard.db.clone()))),
+            Priority::High,
+        )
+    }
+
+    pub fn remove_pool(&self, id: &str) -> Result<(), ShardedEngineError> {
+        let index = self.shard_of(id);
+        self.shards[index].send(index, Command::Remove(id.to_string()), Priority::High)
+    }
+
+    /// Quotes a single swap on the pool's shard, with high priority.
+    pub fn get_amount_out(
+        &self,
+        request: QuoteRequest,
+    ) -> Result<GetAmountOutResult, ShardedEngineError> {
+        self.quote_many(vec![request])
+            .pop()
+            .expect("One result per request")
+    }
+
+    /// Quotes all `requests` with high priority, in parallel across shards. Returns the results in
+    /// request order.
+    pub fn quote_many(&self, requests: Vec<QuoteRequest>) -> Vec<QuoteResult> {
+        let shards: Vec<_> = requests
+            .iter()
+            .map(|request| self.shard_of(&request.pool))
+            .collect();
+        let mut results: Vec<Option<QuoteResult>> = shards.iter().map(|_| None).collect();
+        for (index, result) in self.quote_stream(requests, usize::MAX) {
+            results[index] = Some(result);
+        }
+        results
+            .into_iter()
+            .zip(shards)
+            .map(|(result, shard)| result.unwrap_or(Err(ShardedEngineError::ShardStopped(shard))))
+            .collect()
+    }
+
+    /// Quotes `requests` in parallel across shards, yielding each result with the index of its
+    /// request as soon as it completes, so results may arrive out of order.
+    ///
+    /// At most `max_in_flight` quotes are queued at a time and requests are only pulled from
+    /// `requests` as results are consumed, so memory stays bounded for arbitrarily large batches.
+    /// Quotes have high priority unless set otherwise with `QuoteStream::with_priority`.
+    pub fn quote_stream<I>(&self, requests: I, max_in_flight: usize) -> QuoteStream<'_, I::IntoIter>
+    where
+        I: IntoIterator<Item = QuoteRequest>,
+    {
+        let (reply, results) = mpsc::channel();
+        QuoteStream {
+            engine: self,
+            requests: requests.into_iter().enumerate(),
+            max_in_flight: max_in_flight.max(1),
+            in_flight: 0,
+            priority: Priority::High,
+            reply: Some(reply),
+            results,
+        }
+    }
+
+    fn send_quote(
+        &self,
+        index: usize,
+        request: QuoteRequest,
+        reply: &QuoteReply,
+        priority: Priority,
+    ) -> Result<(), ShardedEngineError> {
+        let shard = self.shard_of(&request.pool);
+        self.shards[shard].send(shard, Command::Quote(index, request, reply.clone()), priority)
+    }
+}
+
+/// Results of a batch of quotes as they complete, see `ShardedEngine::quote_stream`.
+pub struct QuoteStream<'a, I> {
+    engine: &'a ShardedEngine,
+    requests: Enumerate<I>,
+    max_in_flight: usize,
+    in_flight: usize,
+    priority: Priority,
+    /// Dropped once all requests are sent, so the stream ends if a shard stops
+    reply: Option<QuoteReply>,
+    results: mpsc::Receiver<(usize, QuoteResult)>,
+}
+
+impl<I> QuoteStream<'_, I> {
+    /// Queues the quotes of this stream with `priority`, e.g. `Background` for batch jobs.
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+impl<I: Iterator<Item = QuoteRequest>> Iterator for QuoteStream<'_, I> {
+    type Item = (usize, QuoteResult);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.in_flight < self.max_in_flight {
+            let Some(reply) = &self.reply else {
+                break;
+            };
+            let Some((index, request)) = self.requests.next() else {
+                self.reply = None;
+                break;
+            };
+            match self
+                .engine
+                .send_quote(index, request, reply, self.priority)
+            {
+                Ok(()) => self.in_flight += 1,
+                Err(e) => return Some((index, Err(e))),
+            }
+        }
+        if self.in_flight == 0 {
+            return None;
+        }
+        match self.results.recv() {
+            Ok(result) => {
+                self.in_flight -= 1;
+                Some(result)
+            }
+            Err(_) => {
+                warn!(lost = self.in_flight, "Shards stopped with quotes in flight");
+                self.in_flight = 0;
+                None
+            }
+        }
+    }
+}
+
+impl Drop for ShardedEngine {
+    fn drop(&mut self) {
+        for shard in &mut self.shards {
+            shard.queue.close();
+            if let Some(worker) = shard.worker.take() {
+                if worker.join().is_err() {
+                    warn!("Shard worker panicked");
+                }
+            }
+        }
+    }
+}
+
+fn run_shard(queue: Arc<ShardQueue>) {
+    let _close = CloseOnExit(queue.clone());
+    let mut pools: HashMap<String, EVMPoolState<PreCachedDB>> = HashMap::new();
+    while let Some(command) = queue.pop() {
+        match command {
+            Command::Insert(id, pool) => {
+                pools.insert(id, *pool);
+            }
+            Command::Remove(id) => {
+                pools.remove(&id);
+            }
+            Command::Quote(index, request, reply) => {
+                let result = match pools.get(&request.pool) {
+                    // A panicking pool must not take down the other pools of the shard
+                    Some(pool) => panic::catch_unwind(AssertUnwindSafe(|| {
+                        pool.get_amount_out(
+                            request.amount_in,
+                            &request.token_in,
+                            &request.token_out,
+                        )
+                    }))
+                    .unwrap_or_else(|_| {
+                        Err(SimulationError::FatalError(format!(
+                            "Quote of {} panicked",
+                            request.pool
+                        )))
+                    })
+                    .map_err(ShardedEngineError::from),
+                    None => Err(ShardedEngineError::UnknownPool(request.pool)),
+                };
+                // The requester may have given up waiting
+                let _ = reply.send((index, result));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use alloy_primitives::{Address, U256};
+    use num_bigint::ToBigUint;
+    use revm::DatabaseRef;
+
+    use super::*;
+    use crate::evm::engine_db::account_builder::AccountBuilder;
+
+    #[test]
+    fn test_sync() {
+        let engine = ShardedEngine::new(3);
+        let source = PreCachedDB::new().unwrap();
+        let address = Address::with_last_byte(0x42);
+        AccountBuilder::new(address)
+            .code(vec![0x60, 0x00])
+            .storage(U256::from(1), U256::from(7))
+            .init(&source);
+
+        engine.sync(&source);
+
+        for index in 0..engine.len() {
+            let db = engine.shard_db(index);
+            assert!(db.basic_ref(address).unwrap().is_some());
+            assert_eq!(
+                db.storage_ref(address, U256::from(1))
+                    .unwrap(),
+                U256::from(7)
+            );
+        }
+    }
+
+    #[test]
+    fn test_quote_unknown_pool() {
+        let engine = ShardedEngine::new(2);
+        let token = Token::new(
+            "0x6b175474e89094c44da98b954eedeac495271d0f",
+            18,
+            "DAI",
+            10_000.to_biguint().unwrap(),
+        );
+
+        let result = engine.get_amount_out(QuoteRequest {
+            pool: "0xabc".to_string(),
+            amount_in: BigUint::from(1u8),
+            token_in: token.clone(),
+            token_out: token,
+        });
+
+        assert!(matches!(result, Err(ShardedEngineError::UnknownPool(pool)) if pool == "0xabc"));
+        assert_eq!(engine.shard_of("0xabc"), engine.shard_of("0xabc"));
+        assert!(engine.shard_of("0xabc") < engine.len());
+    }
+
+    #[test]
+    fn test_shard_queue_priority() {
+        let queue = ShardQueue::default();
+        let remove = |id: &str| Command::Remove(id.to_string());
+        let id = |command: Option<Command>| match command {
+            Some(Command::Remove(id)) => id,
+            _ => panic!("Expected a remove command"),
+        };
+
+        assert!(queue.push(remove("a"), Priority::Background));
+        assert!(queue.push(remove("b"), Priority::High));
+        assert!(queue.push(remove("c"), Priority::Background));
+        assert!(queue.push(remove("d"), Priority::High));
+
+        assert_eq!(
+            (0..4)
+                .map(|_| id(queue.pop()))
+                .collect::<Vec<_>>(),
+            vec!["b", "d", "a", "c"]
+        );
+        queue.close();
+        assert!(queue.pop().is_none());
+        assert!(!queue.push(remove("e"), Priority::High));
+    }
+
+    #[test]
+    fn test_quote_stream() {
+        let engine = ShardedEngine::new(3);
+        let token = Token::new(
+            "0x6b175474e89094c44da98b954eedeac495271d0f",
+            18,
+            "DAI",
+            10_000.to_biguint().unwrap(),
+        );
+        // Counts the requests pulled by the stream and the results it returned, the difference
+        // being the quotes in flight
+        let (pulled, received, peak) = (Cell::new(0), Cell::new(0), Cell::new(0));
+        let requests = (0..100).map(|i| {
+            pulled.set(pulled.get() + 1);
+            QuoteRequest {
+                pool: format!("0x{i:x}"),
+                amount_in: BigUint::from(1u8),
+                token_in: token.clone(),
+                token_out: token.clone(),
+            }
+        });
+
+        let mut indices: Vec<_> = engine
+            .quote_stream(requests, 4)
+            .with_priority(Priority::Background)
+            .map(|(index, result)| {
+                // In flight while this result was awaited
+                peak.set(peak.get().max(pulled.get() - received.get()));
+                received.set(received.get() + 1);
+                assert!(matches!(result, Err(ShardedEngineError::UnknownPool(pool)) if pool == format!("0x{index:x}")));
+                index
+            })
+            .collect();
+
+        indices.sort_unstable();
+        assert_eq!(indices, (0..100).collect::<Vec<_>>());
+        assert_eq!(peak.get(), 4);
+    }
+}
@@ -1,14 +1,21 @@
-use std::{clone::Clone, collections::HashMap, default::Default, fmt::Debug};
+use std::{
+    cell::RefCell,
+    clone::Clone,
+    collections::HashMap,
+    default::Default,
+    fmt::Debug,
+    path::{Path, PathBuf},
+};
 
-use alloy_primitives::U256;
+use alloy_primitives::{I256, U256};
 use foundry_config::{Chain, Config};
 use foundry_evm::traces::{SparsedTraceArena, TraceKind};
 use revm::{
     inspector_handle_register,
     interpreter::{return_ok, InstructionResult},
     primitives::{
-        alloy_primitives, bytes, Address, BlockEnv, EVMError, EVMResult, EvmState, ExecutionResult,
-        Output, ResultAndState, SpecId, TransactTo, TxEnv,
+        alloy_primitives, bytes, AccountInfo, Address, BlockEnv, CfgEnv, EVMError, EVMResult,
+        EvmState, ExecutionResult, Output, ResultAndState, SpecId, TransactTo, TxEnv,
     },
     DatabaseRef, Evm,
 };
@@ -21,10 +28,83 @@ use super::{
     account_storage::StateUpdate,
     traces::{handle_traces, TraceResult},
 };
-use crate::evm::engine_db::{
-    engine_db_interface::EngineDatabaseInterface, simulation_db::OverriddenSimulationDB,
+use crate::evm::{
+    engine_db::{
+        engine_db_interface::EngineDatabaseInterface,
+        simulation_db::{BlockHeader, OverriddenSimulationDB},
+    },
+    tycho_models::Chain,
+    StorageMap,
 };
 
+/// Maximum number of maps kept per thread for reuse, see `recycle_state_updates`
+const MAX_RECYCLED_MAPS: usize = 64;
+
+thread_local! {
+    /// Cleared state update maps of consumed simulation results
+    static ACCOUNT_MAPS: RefCell<Vec<StorageMap<Address, StateUpdate>>> = const { RefCell::new(Vec::new()) };
+    /// Cleared storage maps of consumed simulation results
+    static SLOT_MAPS: RefCell<Vec<StorageMap<U256, U256>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Hands the state updates of a consumed simulation result back to the engine.
+///
+/// Simulations allocate a map per result and per updated account. At high quote rates this
+/// allocation churn is significant, so the cleared maps are kept and reused by the following
+/// simulations on the same thread.
+pub fn recycle_state_updates(mut updates: StorageMap<Address, StateUpdate>) {
+    SLOT_MAPS.with_borrow_mut(|maps| {
+        for (_, update) in updates.drain() {
+            if let Some(mut storage) = update.storage {
+                if maps.len() < MAX_RECYCLED_MAPS {
+                    storage.clear();
+                    maps.push(storage);
+                }
+            }
+        }
+    });
+    ACCOUNT_MAPS.with_borrow_mut(|maps| {
+        if maps.len() < MAX_RECYCLED_MAPS {
+            maps.push(updates);
+        }
+    });
+}
+
+fn take_account_map() -> StorageMap<Address, StateUpdate> {
+    ACCOUNT_MAPS
+        .with_borrow_mut(Vec::pop)
+        .unwrap_or_default()
+}
+
+fn take_slot_map() -> StorageMap<U256, U256> {
+    SLOT_MAPS
+        .with_borrow_mut(Vec::pop)
+        .unwrap_or_default()
+}
+
+/// Gas limits below the intrinsic gas of a transaction can't execute anything.
+const MIN_GAS_LIMIT: u64 = 21_000;
+
+/// Gas limits above this are certainly mistakes, e.g. an amount passed as gas limit.
+const MAX_GAS_LIMIT: u64 = 1 << 32;
+
+/// Start of the runtime code of Solidity contracts without any payable function: the compiler
+/// sets up the free memory pointer and reverts right away if value was sent.
+const NON_PAYABLE_PREFIX: &[u8] = &[0x60, 0x80, 0x60, 0x40, 0x52, 0x34, 0x80, 0x15];
+
+/// Spec id used by simulations unless configured otherwise: the latest hardfork activated on
+/// Ethereum mainnet.
+pub const DEFAULT_SPEC_ID: SpecId = SpecId::CANCUN;
+
+/// Returns the latest hardfork activated on the given chain, or `None` if the chain is not
+/// EVM-equivalent and can't be simulated with REVM.
+pub fn latest_spec_id(chain: Chain) -> Option<SpecId> {
+    match chain {
+        Chain::Ethereum | Chain::Arbitrum | Chain::Base => Some(SpecId::CANCUN),
+        _ => None,
+    }
+}
+
 /// An error representing any transaction simulation result other than successful execution
 #[derive(Debug, Display, Clone, PartialEq)]
 pub enum SimulationEngineError {
@@ -36,6 +116,27 @@ pub enum SimulationEngineError {
     OutOfGas(String, String),
     /// Simulation didn't succeed; likely not related to network or gas, so retrying won't help
     TransactionError { data: String, gas_used: Option<u64> },
+    /// The requested hardfork is not supported by the engine's chain
+    UnsupportedSpecId(String),
+    /// The simulation parameters are invalid, see `SimulationEngine::validate_params`
+    InvalidParams(String),
+    /// The state updates exceeded the engine's `StateUpdateLimits` and were truncated, so they
+    /// can't be committed
+    TruncatedStateUpdates(String),
+}
+
+/// Change of the native balance of an account in a simulation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BalanceChange {
+    pub before: U256,
+    pub after: U256,
+}
+
+impl BalanceChange {
+    /// Signed difference of the balances, positive if the account received native tokens.
+    pub fn delta(&self) -> I256 {
+        I256::from_raw(self.after.wrapping_sub(self.before))
+    }
 }
 
 /// A result of a successful transaction simulation
@@ -43,10 +144,128 @@ pub enum SimulationEngineError {
 pub struct SimulationResult {
     /// Output of transaction execution as bytes
     pub result: bytes::Bytes,
-    /// State changes caused by the transaction
-    pub state_updates: HashMap<Address, StateUpdate>,
+    /// State changes caused by the transaction. The balance of an account is only set if it
+    /// changed, see `balance_changes`.
+    pub state_updates: StorageMap<Address, StateUpdate>,
+    /// Native balance changes by account, only for the accounts whose balance changed, e.g. by
+    /// value transfers
+    pub balance_changes: HashMap<Address, BalanceChange>,
     /// Gas used by the transaction (already reduced by the refunded gas)
     pub gas_used: u64,
+    /// Set if `state_updates` exceeded the engine's `StateUpdateLimits` and were truncated. Such
+    /// updates are incomplete and must not be committed to a state.
+    pub truncation: Option<StateUpdateTruncation>,
+}
+
+impl SimulationResult {
+    /// The state updates, to be committed to a state. Fails if they were truncated, since
+    /// committing incomplete updates corrupts the state.
+    pub fn committable_state_updates(
+        &self,
+    ) -> Result<&StorageMap<Address, StateUpdate>, SimulationEngineError> {
+        match &self.truncation {
+            None => Ok(&self.state_updates),
+            Some(truncation) => Err(SimulationEngineError::TruncatedStateUpdates(format!(
+                "{} accounts and {} slots were dropped",
+                truncation.dropped_accounts, truncation.dropped_slots
+            ))),
+        }
+    }
+}
+
+/// Caps on the state updates a simulation result keeps, see
+/// `SimulationEngine::with_state_update_limits`.
+///
+/// A transaction touching millions of storage slots, e.g. a malicious token, yields results of
+/// hundreds of megabytes. Batch jobs holding many results run out of memory, so results exceeding
+/// the caps are truncated: accounts and slots with the lowest addresses and indices are kept, so
+/// the same simulation is always truncated the same way. The caps bound what results hold, not the
+/// memory used while simulating.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StateUpdateLimits {
+    /// Maximum number of updated accounts kept
+    pub max_accounts: Option<usize>,
+    /// Maximum number of updated storage slots kept, over all accounts
+    pub max_slots: Option<usize>,
+}
+
+impl StateUpdateLimits {
+    pub fn new(max_accounts: Option<usize>, max_slots: Option<usize>) -> Self {
+        Self { max_accounts, max_slots }
+    }
+
+    /// Truncates `updates` to the limits, see type docs. Returns what was dropped, `None` if the
+    /// updates are within the limits.
+    pub(crate) fn truncate(
+        &self,
+        updates: &mut StorageMap<Address, StateUpdate>,
+    ) -> Option<StateUpdateTruncation> {
+        let slot_count = |update: &StateUpdate| {
+            update
+                .storage
+                .as_ref()
+                .map_or(0, |storage| storage.len())
+        };
+        let max_accounts = self.max_accounts.unwrap_or(usize::MAX);
+        let mut slots_left = self.max_slots.unwrap_or(usize::MAX);
+        if updates.len() <= max_accounts &&
+            updates
+                .values()
+                .map(slot_count)
+                .sum::<usize>() <=
+                slots_left
+        {
+            return None;
+        }
+
+        let mut addresses: Vec<Address> = updates.keys().copied().collect();
+        addresses.sort_unstable();
+        let mut truncation = StateUpdateTruncation::default();
+        let mut dropped = take_account_map();
+        for (position, address) in addresses.into_iter().enumerate() {
+            if position >= max_accounts {
+                if let Some(update) = updates.remove(&address) {
+                    truncation.dropped_accounts += 1;
+                    truncation.dropped_slots += slot_count(&update);
+                    dropped.insert(address, update);
+                }
+                continue;
+            }
+            let Some(update) = updates.get_mut(&address) else { continue };
+            let Some(storage) = update.storage.as_mut() else { continue };
+            if storage.len() <= slots_left {
+                slots_left -= storage.len();
+                continue;
+            }
+            let mut indices: Vec<U256> = storage.keys().copied().collect();
+            indices.sort_unstable();
+            for index in &indices[slots_left..] {
+                storage.remove(index);
+            }
+            truncation.dropped_slots += indices.len() - slots_left;
+            slots_left = 0;
+            if storage.is_empty() {
+                update.storage = None;
+            }
+            truncation
+                .partial_accounts
+                .push(address);
+        }
+        recycle_state_updates(dropped);
+        Some(truncation)
+    }
+}
+
+/// What was dropped from the state updates of a result exceeding the engine's
+/// `StateUpdateLimits`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StateUpdateTruncation {
+    /// Number of updated accounts dropped with all their updates
+    pub dropped_accounts: usize,
+    /// Number of updated slots dropped, including those of dropped accounts
+    pub dropped_slots: usize,
+    /// Kept accounts whose storage updates are incomplete, sorted by address
+    pub partial_accounts: Vec<Address>,
 }
 
 /// Simulation engine
@@ -58,6 +277,18 @@ where
 {
     pub state: D,
     pub trace: bool,
+    /// Hardfork used by simulations that don't request one
+    spec_id: SpecId,
+    /// Chain the simulated state belongs to, used to validate requested hardforks
+    chain: Option<Chain>,
+    /// Directory to write reproducers of failing simulations to, see `failure_dump`
+    failure_dump_dir: Option<PathBuf>,
+    /// Whether parameters are validated before simulating, see `validate_params`
+    validate_params: bool,
+    /// Whether gas is neither checked nor paid, see `with_gasless`
+    gasless: bool,
+    /// Caps on the state updates of results, see `with_state_update_limits`
+    state_update_limits: StateUpdateLimits,
 }
 
 impl<D: EngineDatabaseInterface + Clone + Debug> SimulationEngine<D>
@@ -72,7 +303,188 @@ where
     /// * `state` - Database reference to be used for simulation
     /// * `trace` - Whether to print the entire execution trace
     pub fn new(state: D, trace: bool) -> Self {
-        Self { state, trace }
+        Self {
+            state,
+            trace,
+            spec_id: DEFAULT_SPEC_ID,
+            chain: None,
+            failure_dump_dir: None,
+            validate_params: false,
+            gasless: false,
+            state_update_limits: StateUpdateLimits::default(),
+        }
+    }
+
+    /// Simulates without paying for gas. By default, simulations pay gas like transactions: the
+    /// gas price is the block's base fee and the caller must afford the gas limit and value. In
+    /// gasless mode the gas price is zero and the base fee is not enforced, so callers without
+    /// any balance can be simulated at any block without overriding their balance. Applies to
+    /// `simulate` and `simulate_blocks`.
+    pub fn with_gasless(mut self) -> Self {
+        self.gasless = true;
+        self
+    }
+
+    pub fn is_gasless(&self) -> bool {
+        self.gasless
+    }
+
+    /// Truncates the state updates of results exceeding `limits`, see `StateUpdateLimits`.
+    /// Truncated results are marked with `SimulationResult::truncation`. By default, results keep
+    /// all state updates.
+    pub fn with_state_update_limits(mut self, limits: StateUpdateLimits) -> Self {
+        self.state_update_limits = limits;
+        self
+    }
+
+    pub fn state_update_limits(&self) -> StateUpdateLimits {
+        self.state_update_limits
+    }
+
+    /// Validates the parameters of every simulation before running it, see `validate_params`.
+    pub fn with_param_validation(mut self) -> Self {
+        self.validate_params = true;
+        self
+    }
+
+    /// Checks `params` for mistakes that would otherwise surface as opaque reverts or halts:
+    ///
+    /// * a gas limit too low to cover the intrinsic gas, or implausibly high
+    /// * a deployment without init code, or a call to a contract without calldata or value
+    /// * value sent to a contract that can't receive it, or exceeding the caller's balance
+    /// * a block older than the block of the state
+    ///
+    /// Accounts unknown to the database are not checked.
+    ///
+    /// # Errors
+    ///
+    /// * `InvalidParams` - describing the first mistake found
+    pub fn validate_params(
+        &self,
+        params: &SimulationParameters,
+    ) -> Result<(), SimulationEngineError> {
+        let invalid = |reason: String| Err(SimulationEngineError::InvalidParams(reason));
+        if let Some(gas_limit) = params.gas_limit {
+            if !(MIN_GAS_LIMIT..=MAX_GAS_LIMIT).contains(&gas_limit) {
+                return invalid(format!(
+                    "Gas limit {} is outside of [{}, {}]",
+                    gas_limit, MIN_GAS_LIMIT, MAX_GAS_LIMIT
+                ));
+            }
+        }
+
+        if params.to == Address::ZERO {
+            if params.data.is_empty() {
+                return invalid("Deployment without init code".to_string());
+            }
+        } else if let Ok(Some(target)) = self.state.basic_ref(params.to) {
+            let code = target
+                .code
+                .as_ref()
+                .map(|code| code.original_byte_slice())
+                .unwrap_or_default();
+            if !code.is_empty() && params.data.is_empty() && params.value.is_zero() {
+                return invalid(format!("Call to contract {} without calldata", params.to));
+            }
+            if !params.value.is_zero() && code.starts_with(NON_PAYABLE_PREFIX) {
+                return invalid(format!(
+                    "Value sent to contract {} which has no payable function",
+                    params.to
+                ));
+            }
+        }
+        if !params.value.is_zero() {
+            if let Ok(Some(caller)) = self.state.basic_ref(params.caller) {
+                if caller.balance < params.value {
+                    return invalid(format!(
+                        "Value {} exceeds the balance {} of caller {}",
+                        params.value, caller.balance, params.caller
+                    ));
+                }
+            }
+        }
+
+        if let Some(state_block) = self.state.block_header() {
+            if params.block.number < state_block.number {
+                return invalid(format!(
+                    "Block {} is older than the block {} of the state",
+                    params.block.number, state_block.number
+                ));
+            }
+            if params.block.timestamp < state_block.timestamp {
+                return invalid(format!(
+                    "Block timestamp {} is older than the timestamp {} of the state",
+                    params.block.timestamp, state_block.timestamp
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a reproducer of every failing simulation into `dir`, which can be re-run offline
+    /// with `failure_dump::replay_failure`.
+    pub fn with_failure_dumps(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.failure_dump_dir = Some(dir.into());
+        self
+    }
+
+    pub fn failure_dump_dir(&self) -> Option<&Path> {
+        self.failure_dump_dir.as_deref()
+    }
+
+    /// Sets the chain of the simulated state and uses its latest hardfork by default.
+    ///
+    /// # Errors
+    ///
+    /// * `UnsupportedSpecId` - the chain is not EVM-equivalent
+    pub fn with_chain(mut self, chain: Chain) -> Result<Self, SimulationEngineError> {
+        self.spec_id = latest_spec_id(chain).ok_or_else(|| {
+            SimulationEngineError::UnsupportedSpecId(format!(
+                "Chain {:?} can't be simulated with REVM",
+                chain
+            ))
+        })?;
+        self.chain = Some(chain);
+        Ok(self)
+    }
+
+    /// Sets the hardfork used by simulations that don't request one, e.g. to replay old blocks.
+    ///
+    /// # Errors
+    ///
+    /// * `UnsupportedSpecId` - the hardfork is not yet activated on the engine's chain
+    pub fn with_spec_id(mut self, spec_id: SpecId) -> Result<Self, SimulationEngineError> {
+        self.spec_id = self.resolve_spec_id(Some(spec_id))?;
+        Ok(self)
+    }
+
+    pub fn spec_id(&self) -> SpecId {
+        self.spec_id
+    }
+
+    /// Returns the hardfork to simulate with: the requested one if any, the engine's default
+    /// otherwise.
+    ///
+    /// # Errors
+    ///
+    /// * `UnsupportedSpecId` - the requested hardfork is not yet activated on the engine's chain
+    pub fn resolve_spec_id(
+        &self,
+        requested: Option<SpecId>,
+    ) -> Result<SpecId, SimulationEngineError> {
+        let spec_id = requested.unwrap_or(self.spec_id);
+        if let Some(chain) = self.chain {
+            match latest_spec_id(chain) {
+                Some(latest) if spec_id <= latest => {}
+                _ => {
+                    return Err(SimulationEngineError::UnsupportedSpecId(format!(
+                        "{:?} is not activated on chain {:?}",
+                        spec_id, chain
+                    )))
+                }
+            }
+        }
+        Ok(spec_id)
     }
 
     /// Simulate a transaction
@@ -89,37 +501,27 @@ where
         // db, the db is simply a reference wrapper. To avoid lifetimes leaking we don't let the evm
         // struct outlive this scope.
 
+        let spec_id = self.resolve_spec_id(params.spec_id)?;
+        if self.validate_params {
+            self.validate_params(params)?;
+        }
+
         // We protect the state from being consumed.
+        let no_overrides = HashMap::new();
         let db_ref = OverriddenSimulationDB {
             inner_db: &self.state,
-            overrides: &params
+            overrides: params
                 .overrides
-                .clone()
-                .unwrap_or_default(),
-        };
-
-        let tx_env = TxEnv {
-            caller: params.revm_caller(),
-            gas_limit: params
-                .revm_gas_limit()
-                .unwrap_or(8_000_000),
-            transact_to: params.revm_to(),
-            value: params.value,
-            data: params.revm_data(),
-            ..Default::default()
-        };
-
-        let block_env = BlockEnv {
-            number: params.revm_block_number(),
-            timestamp: params.revm_timestamp(),
-            ..Default::default()
+                .as_ref()
+                .unwrap_or(&no_overrides),
         };
 
         let default_builder = Evm::builder()
-            .with_spec_id(SpecId::CANCUN)
+            .with_spec_id(spec_id)
             .with_ref_db(db_ref)
-            .with_block_env(block_env)
-            .with_tx_env(tx_env);
+            .with_block_env(params.block_env())
+            .with_tx_env(params.tx_env(self.gasless))
+            .modify_cfg_env(|cfg| configure_gas(cfg, self.gasless));
 
         let evm_result = if self.trace {
             let mut tracer = TracingInspector::new(TracingInspectorConfig::default());
@@ -146,7 +548,17 @@ where
             vm.transact()
         };
 
-        interpret_evm_result(evm_result)
+        let mut result =
+            interpret_evm_result(evm_result, |address| account_before(&self.state, address));
+        match &mut result {
+            Ok(result) => {
+                result.truncation = self
+                    .state_update_limits
+                    .truncate(&mut result.state_updates)
+            }
+            Err(err) => self.maybe_dump_failure(params, spec_id, err),
+        }
+        result
     }
 
     pub fn clear_temp_storage(&mut self) {
@@ -198,6 +610,25 @@ where
     }
 }
 
+/// Gas price of a simulation in `block`: its base fee, zero if `gasless`, see
+/// `SimulationEngine::with_gasless`.
+pub(crate) fn gas_price(block: &BlockEnv, gasless: bool) -> U256 {
+    if gasless {
+        U256::ZERO
+    } else {
+        block.basefee
+    }
+}
+
+/// Configures an EVM for the engine's gas mode. Gasless simulations have a zero gas price, see
+/// `gas_price`, which is allowed below the base fee. The balance check stays enabled: revm would
+/// otherwise raise the caller's balance to cover the value sent and report it as a state update.
+pub(crate) fn configure_gas(cfg: &mut CfgEnv, gasless: bool) {
+    if gasless {
+        cfg.disable_base_fee = true;
+    }
+}
+
 /// Convert a complex EVMResult into a simpler structure
 ///
 /// EVMResult is not of an error type even if the transaction was not successful.
@@ -209,17 +640,27 @@ where
 /// # Arguments
 ///
 /// * `evm_result` - output from calling `revm.transact()`
+/// * `account_before` - an account before the transaction, `None` if unknown. State updates only
+///   contain the balance and code of an account if they changed compared to it. Unknown balances
+///   are always reported as changed, unknown code only if the contract was created.
 ///
 /// # Errors
 ///
 /// * `SimulationError` - simulation wasn't successful for any reason. See variants for details.
-fn interpret_evm_result<DBError: std::fmt::Debug>(
+pub(crate) fn interpret_evm_result<DBError: std::fmt::Debug>(
     evm_result: EVMResult<DBError>,
+    account_before: impl Fn(Address) -> Option<AccountInfo>,
 ) -> Result<SimulationResult, SimulationEngineError> {
     match evm_result {
         Ok(result_and_state) => match result_and_state.result {
             ExecutionResult::Success { gas_used, gas_refunded, output, .. } => {
-                Ok(interpret_evm_success(gas_used, gas_refunded, output, result_and_state.state))
+                Ok(interpret_evm_success(
+                    gas_used,
+                    gas_refunded,
+                    output,
+                    result_and_state.state,
+                    account_before,
+                ))
             }
             ExecutionResult::Revert { output, gas_used } => {
                 Err(SimulationEngineError::TransactionError {
@@ -259,13 +700,22 @@ fn interpret_evm_result<DBError: std::fmt::Debug>(
     }
 }
 
+/// An account in `db`, empty if the account doesn't exist. `None` if it can't be read.
+pub(crate) fn account_before<D: DatabaseRef>(db: &D, address: Address) -> Option<AccountInfo> {
+    db.basic_ref(address)
+        .ok()
+        .map(Option::unwrap_or_default)
+}
+
 // Helper function to extract some details from a successful transaction execution
 fn interpret_evm_success(
     gas_used: u64,
     gas_refunded: u64,
     output: Output,
     state: EvmState,
+    account_before: impl Fn(Address) -> Option<AccountInfo>,
 ) -> SimulationResult {
+    let mut balance_changes = HashMap::new();
     SimulationResult {
         result: output.into_data().into(),
         state_updates: {
@@ -277,25 +727,54 @@ fn interpret_evm_success(
             // we set this field to None. If REVM did return storage, we return one record
             // per *modified* slot (sometimes REVM returns a storage record for an account
             // even if the slots are not modified).
-            let mut account_updates: HashMap<Address, StateUpdate> = HashMap::new();
+            // The maps are taken from the ones recycled by previous simulations on this thread, see
+            // `recycle_state_updates`.
+            let mut account_updates = take_account_map();
             for (address, account) in state {
+                // revm doesn't say if the balance or code were actually changed, so they are
+                // compared to the account before the transaction, if known
+                let before = account_before(address);
+                let after = account.info.balance;
+                let balance = match &before {
+                    Some(before) if before.balance == after => None,
+                    Some(before) => {
+                        balance_changes
+                            .insert(address, BalanceChange { before: before.balance, after });
+                        Some(after)
+                    }
+                    None => Some(after),
+                };
+                let code_changed = match &before {
+                    Some(before) => before.code_hash != account.info.code_hash,
+                    None => account.is_created(),
+                };
+                let code = account
+                    .info
+                    .code
+                    .filter(|_| code_changed)
+                    .map(|code| code.original_bytes());
+                let nonce = match &before {
+                    Some(before) if before.nonce == account.info.nonce => None,
+                    _ => Some(account.info.nonce),
+                };
                 account_updates.insert(
                     address,
                     StateUpdate {
-                        // revm doesn't say if the balance was actually changed
-                        balance: Some(account.info.balance),
-                        // revm doesn't say if the code was actually changed
+                        balance,
+                        code,
+                        nonce,
                         storage: {
                             if account.storage.is_empty() {
                                 None
                             } else {
-                                let mut slot_updates: HashMap<U256, U256> = HashMap::new();
+                                let mut slot_updates = take_slot_map();
                                 for (index, slot) in account.storage {
                                     if slot.is_changed() {
                                         slot_updates.insert(index, slot.present_value);
                                     }
                                 }
                                 if slot_updates.is_empty() {
+                                    SLOT_MAPS.with_borrow_mut(|maps| maps.push(slot_updates));
                                     None
                                 } else {
                                     Some(slot_updates)
@@ -307,7 +786,9 @@ fn interpret_evm_success(
             }
             account_updates
         },
+        balance_changes,
         gas_used: gas_used - gas_refunded,
+        truncation: None,
     }
 }
 
@@ -327,10 +808,10 @@ pub struct SimulationParameters {
     pub overrides: Option<HashMap<Address, HashMap<U256, U256>>>,
     /// Limit of gas to be used by the transaction
     pub gas_limit: Option<u64>,
-    /// The block number to be used by the transaction. This is independent of the states block.
-    pub block_number: u64,
-    /// The timestamp to be used by the transaction
-    pub timestamp: u64,
+    /// Hardfork to simulate with. Defaults to the engine's spec id.
+    pub spec_id: Option<SpecId>,
+    /// The block the transaction is executed in. This is independent of the states block.
+    pub block: BlockHeader,
 }
 
 // Converters of fields to revm types
@@ -356,12 +837,24 @@ impl SimulationParameters {
         self.gas_limit
     }
 
-    fn revm_block_number(&self) -> U256 {
-        U256::from_limbs([self.block_number, 0, 0, 0])
+    /// The transaction environment. Gas is paid at the block's base fee unless `gasless`, see
+    /// `SimulationEngine::with_gasless`.
+    pub(crate) fn tx_env(&self, gasless: bool) -> TxEnv {
+        TxEnv {
+            caller: self.revm_caller(),
+            gas_limit: self
+                .revm_gas_limit()
+                .unwrap_or(8_000_000),
+            gas_price: gas_price(&self.block_env(), gasless),
+            transact_to: self.revm_to(),
+            value: self.value,
+            data: self.revm_data(),
+            ..Default::default()
+        }
     }
 
-    fn revm_timestamp(&self) -> U256 {
-        U256::from_limbs([self.timestamp, 0, 0, 0])
+    pub(crate) fn block_env(&self) -> BlockEnv {
+        self.block.block_env()
     }
 }
 
@@ -386,6 +879,7 @@ mod tests {
     use crate::{
         evm::engine_db::{
             engine_db_interface::EngineDatabaseInterface, simulation_db::SimulationDB,
+            tycho_db::PreCachedDB,
         },
         protocol::errors::SimulationError,
     };
@@ -411,8 +905,8 @@ mod tests {
                 .collect(),
             ),
             gas_limit: Some(33),
-            block_number: 0,
-            timestamp: 0,
+            spec_id: None,
+            block: BlockHeader::default(),
         };
 
         assert_eq!(params.revm_caller(), Address::from_str(address_string).unwrap());
@@ -439,8 +933,359 @@ mod tests {
         .collect();
         assert_eq!(params.overrides.clone().unwrap(), expected_overrides);
         assert_eq!(params.revm_gas_limit().unwrap(), 33_u64);
-        assert_eq!(params.revm_block_number(), U256::ZERO);
-        assert_eq!(params.revm_timestamp(), U256::ZERO);
+        assert_eq!(params.block_env().number, U256::ZERO);
+        assert_eq!(params.block_env().timestamp, U256::ZERO);
+    }
+
+    #[test]
+    fn test_resolve_spec_id() {
+        let engine = SimulationEngine::new(PreCachedDB::new().unwrap(), false);
+        assert_eq!(engine.resolve_spec_id(None).unwrap(), DEFAULT_SPEC_ID);
+        assert_eq!(
+            engine
+                .resolve_spec_id(Some(SpecId::PRAGUE))
+                .unwrap(),
+            SpecId::PRAGUE
+        );
+
+        let engine = engine
+            .with_chain(Chain::Ethereum)
+            .unwrap()
+            .with_spec_id(SpecId::MERGE)
+            .unwrap();
+        assert_eq!(engine.spec_id(), SpecId::MERGE);
+        assert_eq!(
+            engine
+                .resolve_spec_id(Some(SpecId::CANCUN))
+                .unwrap(),
+            SpecId::CANCUN
+        );
+        assert!(matches!(
+            engine.resolve_spec_id(Some(SpecId::PRAGUE)),
+            Err(SimulationEngineError::UnsupportedSpecId(_))
+        ));
+    }
+
+    #[test]
+    fn test_simulate_pre_shanghai() {
+        // Runtime code using PUSH0, introduced in Shanghai: PUSH0 PUSH0 RETURN
+        let code = Bytecode::new_raw(Bytes::from_static(&[0x5f, 0x5f, 0xf3]));
+        let contract = Address::repeat_byte(0xaa);
+        let caller = Address::repeat_byte(0x01);
+        let engine = SimulationEngine::new(PreCachedDB::new().unwrap(), false);
+        engine.state.init_account(
+            contract,
+            AccountInfo {
+                balance: U256::ZERO,
+                nonce: 0,
+                code_hash: code.hash_slow(),
+                code: Some(code),
+            },
+            None,
+            false,
+        );
+        engine
+            .state
+            .init_account(caller, AccountInfo::default(), None, false);
+        let mut params = SimulationParameters {
+            caller,
+            to: contract,
+            data: vec![],
+            value: U256::ZERO,
+            overrides: None,
+            gas_limit: None,
+            spec_id: None,
+            block: BlockHeader { number: 1, timestamp: 1, ..Default::default() },
+        };
+
+        assert!(engine.simulate(&params).is_ok());
+
+        params.spec_id = Some(SpecId::MERGE);
+        let res = engine.simulate(&params);
+        assert!(matches!(
+            res,
+            Err(SimulationEngineError::TransactionError { ref data, .. }) if data.contains("NotActivated")
+        ));
+    }
+
+    #[test]
+    fn test_simulate_reads_own_writes_to_overridden_slots() {
+        // Token: without calldata decrements slot 0, like a transfer out, otherwise returns it
+        let token_code = [
+            0x36, 0x60, 0x0e, 0x57, 0x60, 0x01, 0x60, 0x00, 0x54, 0x03, 0x60, 0x00, 0x55, 0x00,
+            0x5b, 0x60, 0x00, 0x54, 0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0xf3,
+        ];
+        let token = Address::repeat_byte(0xaa);
+        // Router: calls the token to transfer, then to read slot 0, and returns the read value
+        let call_token = |args_size: u8, ret_size: u8| {
+            let mut code =
+                vec![0x60, ret_size, 0x60, 0x00, 0x60, args_size, 0x60, 0x00, 0x60, 0x00];
+            code.push(0x73);
+            code.extend_from_slice(token.as_slice());
+            code.extend([0x5a, 0xf1, 0x50]);
+            code
+        };
+        let mut router_code = call_token(0, 0);
+        router_code.extend(call_token(1, 0x20));
+        router_code.extend([0x60, 0x20, 0x60, 0x00, 0xf3]);
+        let router = Address::repeat_byte(0xbb);
+        let caller = Address::repeat_byte(0x01);
+
+        let engine = SimulationEngine::new(PreCachedDB::new().unwrap(), false);
+        for (address, code) in [(token, token_code.to_vec()), (router, router_code)] {
+            let code = Bytecode::new_raw(Bytes::from(code));
+            engine.state.init_account(
+                address,
+                AccountInfo {
+                    balance: U256::ZERO,
+                    nonce: 0,
+                    code_hash: code.hash_slow(),
+                    code: Some(code),
+                },
+                // Only overridden in the simulation, the permanent value must not leak in
+                Some(HashMap::from([(U256::ZERO, U256::from(100))])),
+                true,
+            );
+        }
+        engine
+            .state
+            .init_account(caller, AccountInfo::default(), None, false);
+        let params = SimulationParameters {
+            caller,
+            to: router,
+            data: vec![],
+            value: U256::ZERO,
+            overrides: Some(HashMap::from([(token, HashMap::from([(U256::ZERO, U256::from(5))]))])),
+            gas_limit: None,
+            spec_id: None,
+            block: BlockHeader { number: 1, timestamp: 1, ..Default::default() },
+        };
+
+        let res = engine.simulate(&params).unwrap();
+
+        assert_eq!(U256::from_be_slice(&res.result), U256::from(4));
+        assert_eq!(
+            res.state_updates[&token]
+                .storage
+                .as_ref()
+                .unwrap()[&U256::ZERO],
+            U256::from(4)
+        );
+        // Overrides only apply to the simulation
+        assert_eq!(
+            engine
+                .state
+                .get_storage(&token, &U256::ZERO),
+            Some(U256::from(100))
+        );
+    }
+
+    #[test]
+    fn test_simulate_value_transfer() {
+        let caller = Address::repeat_byte(0x01);
+        let recipient = Address::repeat_byte(0x02);
+        let engine = SimulationEngine::new(PreCachedDB::new().unwrap(), false);
+        engine.state.init_account(
+            caller,
+            AccountInfo { balance: U256::from(10), ..Default::default() },
+            None,
+            false,
+        );
+        engine
+            .state
+            .init_account(recipient, AccountInfo::default(), None, false);
+        let params = |value: u64| SimulationParameters {
+            caller,
+            to: recipient,
+            data: vec![],
+            value: U256::from(value),
+            overrides: None,
+            gas_limit: None,
+            spec_id: None,
+            block: BlockHeader { number: 1, timestamp: 1, ..Default::default() },
+        };
+
+        let res = engine.simulate(&params(3)).unwrap();
+
+        assert_eq!(res.gas_used, 21_000);
+        assert_eq!(
+            res.balance_changes,
+            HashMap::from([
+                (caller, BalanceChange { before: U256::from(10), after: U256::from(7) }),
+                (recipient, BalanceChange { before: U256::ZERO, after: U256::from(3) }),
+            ])
+        );
+        assert_eq!(res.balance_changes[&caller].delta(), I256::try_from(-3i64).unwrap());
+        assert_eq!(res.state_updates[&recipient].balance, Some(U256::from(3)));
+
+        // Without value, no balance changes
+        let res = engine.simulate(&params(0)).unwrap();
+
+        assert!(res.balance_changes.is_empty());
+        assert_eq!(res.state_updates[&caller].balance, None);
+    }
+
+    #[test]
+    fn test_simulate_gasless() {
+        let caller = Address::repeat_byte(0x01);
+        let recipient = Address::repeat_byte(0x02);
+        let engine = SimulationEngine::new(PreCachedDB::new().unwrap(), false);
+        for account in [caller, recipient] {
+            engine
+                .state
+                .init_account(account, AccountInfo::default(), None, false);
+        }
+        let params = SimulationParameters {
+            caller,
+            to: recipient,
+            data: vec![],
+            value: U256::ZERO,
+            overrides: None,
+            gas_limit: None,
+            spec_id: None,
+            block: BlockHeader { number: 1, timestamp: 1, basefee: Some(7), ..Default::default() },
+        };
+
+        assert!(engine.simulate(&params).is_err());
+
+        let engine = engine.with_gasless();
+        let res = engine.simulate(&params).unwrap();
+
+        assert_eq!(res.gas_used, 21_000);
+        assert!(res.balance_changes.is_empty());
+
+        // Gasless callers still can't send value they don't have
+        let with_value = SimulationParameters { value: U256::from(1), ..params };
+        assert!(engine.simulate(&with_value).is_err());
+    }
+
+    #[test]
+    fn test_simulate_pays_base_fee() {
+        let caller = Address::repeat_byte(0x01);
+        let recipient = Address::repeat_byte(0x02);
+        let engine = SimulationEngine::new(PreCachedDB::new().unwrap(), false);
+        let balance = U256::from(10u64.pow(18));
+        engine.state.init_account(
+            caller,
+            AccountInfo { balance, ..Default::default() },
+            None,
+            false,
+        );
+        engine
+            .state
+            .init_account(recipient, AccountInfo::default(), None, false);
+        let params = SimulationParameters {
+            caller,
+            to: recipient,
+            data: vec![],
+            value: U256::ZERO,
+            overrides: None,
+            gas_limit: None,
+            spec_id: None,
+            block: BlockHeader { number: 1, timestamp: 1, basefee: Some(7), ..Default::default() },
+        };
+
+        let res = engine.simulate(&params).unwrap();
+
+        assert_eq!(res.gas_used, 21_000);
+        assert_eq!(
+            res.balance_changes[&caller],
+            BalanceChange { before: balance, after: balance - U256::from(21_000 * 7) }
+        );
+    }
+
+    #[test]
+    fn test_simulate_contract_creation() {
+        let caller = Address::repeat_byte(0x01);
+        let engine = SimulationEngine::new(PreCachedDB::new().unwrap(), false);
+        engine
+            .state
+            .init_account(caller, AccountInfo::default(), None, false);
+        // Init code returning the runtime code `STOP`
+        let init_code =
+            vec![0x60, 0x01, 0x60, 0x0c, 0x60, 0x00, 0x39, 0x60, 0x01, 0x60, 0x00, 0xf3, 0x00];
+        let params = SimulationParameters {
+            caller,
+            to: Address::ZERO,
+            data: init_code,
+            value: U256::ZERO,
+            overrides: None,
+            gas_limit: None,
+            spec_id: None,
+            block: BlockHeader { number: 1, timestamp: 1, ..Default::default() },
+        };
+
+        let res = engine.simulate(&params).unwrap();
+
+        let created = caller.create(0);
+        assert_eq!(res.state_updates[&created].code, Some(Bytes::from_static(&[0x00])));
+        assert_eq!(res.state_updates[&created].balance, None);
+        assert_eq!(res.state_updates[&created].nonce, Some(1));
+        assert_eq!(
+            res.state_updates[&caller],
+            StateUpdate { nonce: Some(1), ..Default::default() }
+        );
+    }
+
+    #[test]
+    fn test_validate_params() {
+        let contract = Address::repeat_byte(0xaa);
+        let caller = Address::repeat_byte(0x01);
+        let mut engine =
+            SimulationEngine::new(PreCachedDB::new().unwrap(), false).with_param_validation();
+        // A contract compiled without payable functions: reverts on value, otherwise returns
+        let code = Bytecode::new_raw(Bytes::from_static(&[
+            0x60, 0x80, 0x60, 0x40, 0x52, 0x34, 0x80, 0x15, 0x60, 0x0c, 0x57, 0xfd, 0x5b, 0x00,
+        ]));
+        engine.state.init_account(
+            contract,
+            AccountInfo {
+                balance: U256::ZERO,
+                nonce: 0,
+                code_hash: code.hash_slow(),
+                code: Some(code),
+            },
+            None,
+            false,
+        );
+        engine.state.init_account(
+            caller,
+            AccountInfo { balance: U256::from(10), ..Default::default() },
+            None,
+            false,
+        );
+        engine.state.update_state(
+            &StorageMap::default(),
+            BlockHeader { number: 5, timestamp: 50, ..Default::default() },
+        );
+        let params = || SimulationParameters {
+            caller,
+            to: contract,
+            data: vec![0x01],
+            value: U256::ZERO,
+            overrides: None,
+            gas_limit: Some(100_000),
+            spec_id: None,
+            block: BlockHeader { number: 6, timestamp: 60, ..Default::default() },
+        };
+        let invalid = |params: SimulationParameters| {
+            matches!(engine.simulate(&params), Err(SimulationEngineError::InvalidParams(_)))
+        };
+
+        assert!(engine.simulate(&params()).is_ok());
+        assert!(invalid(SimulationParameters { gas_limit: Some(1_000), ..params() }));
+        assert!(invalid(SimulationParameters { data: vec![], ..params() }));
+        assert!(invalid(SimulationParameters { value: U256::from(1), ..params() }));
+        assert!(invalid(SimulationParameters {
+            to: Address::repeat_byte(0xbb),
+            value: U256::from(11),
+            ..params()
+        }));
+        assert!(invalid(SimulationParameters {
+            block: BlockHeader { number: 4, timestamp: 60, ..Default::default() },
+            ..params()
+        }));
+        assert!(invalid(SimulationParameters { to: Address::ZERO, data: vec![], ..params() }));
     }
 
     #[test]
@@ -452,8 +1297,8 @@ mod tests {
             value: U256::from(0u64),
             overrides: None,
             gas_limit: None,
-            block_number: 0,
-            timestamp: 0,
+            spec_id: None,
+            block: BlockHeader::default(),
         };
 
         assert_eq!(params.overrides, None);
@@ -511,7 +1356,7 @@ mod tests {
             .collect(),
         });
 
-        let result = interpret_evm_result(evm_result);
+        let result = interpret_evm_result(evm_result, |_| None);
         let simulation_result = result.unwrap();
 
         assert_eq!(simulation_result.result, bytes::Bytes::from_static(b"output"));
@@ -525,6 +1370,8 @@ mod tests {
                         .collect(),
                 ),
                 balance: Some(U256::from_limbs([1, 0, 0, 0])),
+                code: None,
+                nonce: Some(2),
             },
         )]
         .iter()
@@ -534,6 +1381,67 @@ mod tests {
         assert_eq!(simulation_result.gas_used, 90);
     }
 
+    #[test]
+    fn test_recycle_state_updates() {
+        let mut storage = StorageMap::with_capacity_and_hasher(16, Default::default());
+        storage.insert(U256::from(1), U256::from(2));
+        let updates = StorageMap::from_iter([(
+            Address::ZERO,
+            StateUpdate {
+                storage: Some(storage),
+                balance: Some(U256::from(1)),
+                code: None,
+                nonce: None,
+            },
+        )]);
+
+        recycle_state_updates(updates);
+
+        let account_map = take_account_map();
+        let slot_map = take_slot_map();
+        assert!(account_map.is_empty() && account_map.capacity() > 0);
+        assert!(slot_map.is_empty() && slot_map.capacity() >= 16);
+    }
+
+    #[test]
+    fn test_truncate_state_updates() {
+        let account = |slots: u64| StateUpdate {
+            storage: Some(StorageMap::from_iter(
+                (0..slots).map(|slot| (U256::from(slot), U256::ZERO)),
+            )),
+            balance: Some(U256::from(1)),
+            code: None,
+            nonce: None,
+        };
+        let mut updates = StorageMap::from_iter([
+            (Address::repeat_byte(1), account(3)),
+            (Address::repeat_byte(2), account(3)),
+            (Address::repeat_byte(3), account(2)),
+        ]);
+
+        assert_eq!(StateUpdateLimits::new(Some(3), Some(8)).truncate(&mut updates), None);
+
+        let truncation = StateUpdateLimits::new(Some(2), Some(4))
+            .truncate(&mut updates)
+            .unwrap();
+
+        assert_eq!(
+            truncation,
+            StateUpdateTruncation {
+                dropped_accounts: 1,
+                dropped_slots: 4,
+                partial_accounts: vec![Address::repeat_byte(2)],
+            }
+        );
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[&Address::repeat_byte(1)], account(3));
+        let partial = updates[&Address::repeat_byte(2)]
+            .storage
+            .as_ref()
+            .unwrap();
+        assert_eq!(partial.keys().collect::<Vec<_>>(), vec![&U256::ZERO]);
+    }
+
     #[test]
     fn test_interpret_result_ok_revert() {
         let evm_result: EVMResult<TransportError> = Ok(ResultAndState {
@@ -544,7 +1452,7 @@ mod tests {
             state: rState::default(),
         });
 
-        let result = interpret_evm_result(evm_result);
+        let result = interpret_evm_result(evm_result, |_| None);
 
         assert!(result.is_err());
         let err = result.err().unwrap();
@@ -570,7 +1478,7 @@ mod tests {
             state: rState::default(),
         });
 
-        let result = interpret_evm_result(evm_result);
+        let result = interpret_evm_result(evm_result, |_| None);
 
         assert!(result.is_err());
         let err = result.err().unwrap();
@@ -588,7 +1496,7 @@ mod tests {
         let evm_result: EVMResult<TransportError> =
             Err(EVMError::Transaction(InvalidTransaction::PriorityFeeGreaterThanMaxFee));
 
-        let result = interpret_evm_result(evm_result);
+        let result = interpret_evm_result(evm_result, |_| None);
 
         assert!(result.is_err());
         let err = result.err().unwrap();
@@ -607,7 +1515,7 @@ mod tests {
             TransportErrorKind::Custom(Box::from("boo".to_string())),
         )));
 
-        let result = interpret_evm_result(evm_result);
+        let result = interpret_evm_result(evm_result, |_| None);
 
         assert!(result.is_err());
         let err = result.err().unwrap();
@@ -679,8 +1587,8 @@ mod tests {
             value: U256::from(0u64),
             overrides: None,
             gas_limit: None,
-            block_number: 0,
-            timestamp: 0,
+            spec_id: None,
+            block: BlockHeader::default(),
         };
         let eng = SimulationEngine::new(state, true);
 
@@ -807,8 +1715,8 @@ mod tests {
             value: U256::from(0u64),
             overrides: Some(overrides),
             gas_limit: None,
-            block_number: 0,
-            timestamp: 0,
+            spec_id: None,
+            block: BlockHeader::default(),
         };
 
         let eng = SimulationEngine::new(state, false);
@@ -0,0 +1,501 @@
+//! Solidity storage layouts
+//!
+//! Parses the storage layout emitted by solc (`--storage-layout`) and resolves variable paths
+//! such as `balances[0xabc...]`, `allowances[0xabc...][0xdef...]`, `config.fee` or `owners[2]` to
+//! their storage slot. This allows building storage overrides by variable name instead of by
+//! hand-computed slot.
+use std::{collections::HashMap, str::FromStr};
+
+use alloy_primitives::{keccak256, Address, I256, U256};
+use serde::Deserialize;
+use thiserror::Error;
+
+use super::{ContractCompiler, SlotId};
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum StorageLayoutError {
+    #[error("Failed to parse storage layout: {0}")]
+    Parse(String),
+    #[error("Unknown storage variable: {0}")]
+    UnknownVariable(String),
+    #[error("Invalid storage path {0}: {1}")]
+    InvalidPath(String, String),
+    #[error("Invalid key {0} for type {1}")]
+    InvalidKey(String, String),
+    #[error("No storage layout registered for {0}")]
+    UnknownContract(Address),
+}
+
+/// A storage variable or struct member as found in the solc storage layout.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct StorageEntry {
+    pub label: String,
+    /// Byte offset within the slot, for packed variables
+    pub offset: usize,
+    /// Slot as a decimal string
+    pub slot: String,
+    #[serde(rename = "type")]
+    pub type_id: String,
+}
+
+/// A type as found in the solc storage layout.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageType {
+    /// One of `inplace`, `mapping`, `dynamic_array` or `bytes`
+    pub encoding: String,
+    pub label: String,
+    /// Size in bytes as a decimal string
+    pub number_of_bytes: String,
+    /// Key type of mappings
+    pub key: Option<String>,
+    /// Value type of mappings
+    pub value: Option<String>,
+    /// Element type of arrays
+    pub base: Option<String>,
+    /// Members of structs
+    pub members: Option<Vec<StorageEntry>>,
+}
+
+/// The storage layout of a contract, as emitted by solc with `--storage-layout`.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct StorageLayout {
+    pub storage: Vec<StorageEntry>,
+    #[serde(default)]
+    pub types: Option<HashMap<String, StorageType>>,
+}
+
+/// Location of a value in storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlotLocation {
+    pub slot: SlotId,
+    /// Byte offset within the slot, counted from the least significant byte
+    pub offset: usize,
+    /// Size of the value in bytes
+    pub size: usize,
+}
+
+impl SlotLocation {
+    /// Writes `value` into `current`, the current content of the slot, leaving the bytes of
+    /// other variables packed into the same slot untouched.
+    pub fn apply(&self, current: U256, value: U256) -> U256 {
+        if self.size >= 32 {
+            return value;
+        }
+        let mask = ((U256::from(1) << (self.size * 8)) - U256::from(1)) << (self.offset * 8);
+        (current & !mask) | ((value << (self.offset * 8)) & mask)
+    }
+}
+
+/// One step of a storage path: a mapping key or array index (`[...]`), or a struct member
+/// (`.name`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PathSegment {
+    Index(String),
+    Member(String),
+}
+
+fn parse_path(path: &str) -> Result<(String, Vec<PathSegment>), StorageLayoutError> {
+    let invalid = |reason: &str| StorageLayoutError::InvalidPath(path.to_string(), reason.into());
+    let name_end = path
+        .find(['[', '.'])
+        .unwrap_or(path.len());
+    let name = path[..name_end].trim();
+    if name.is_empty() {
+        return Err(invalid("missing variable name"));
+    }
+
+    let mut segments = Vec::new();
+    let mut rest = &path[name_end..];
+    while !rest.is_empty() {
+        if let Some(stripped) = rest.strip_prefix('[') {
+            let end = stripped
+                .find(']')
+                .ok_or_else(|| invalid("unclosed '['"))?;
+            segments.push(PathSegment::Index(stripped[..end].trim().to_string()));
+            rest = &stripped[end + 1..];
+        } else if let Some(stripped) = rest.strip_prefix('.') {
+            let end = stripped
+                .find(['[', '.'])
+                .unwrap_or(stripped.len());
+            if end == 0 {
+                return Err(invalid("empty member name"));
+            }
+            segments.push(PathSegment::Member(stripped[..end].trim().to_string()));
+            rest = &stripped[end..];
+        } else {
+            return Err(invalid("expected '[' or '.'"));
+        }
+    }
+    Ok((name.to_string(), segments))
+}
+
+fn parse_number(value: &str) -> Option<U256> {
+    match value.strip_prefix("0x") {
+        Some(hex) => U256::from_str_radix(hex, 16).ok(),
+        None => U256::from_str(value).ok(),
+    }
+}
+
+impl StorageLayout {
+    /// Parses a storage layout from the JSON emitted by solc.
+    pub fn from_json(json: &str) -> Result<Self, StorageLayoutError> {
+        serde_json::from_str(json).map_err(|e| StorageLayoutError::Parse(e.to_string()))
+    }
+
+    fn get_type(&self, type_id: &str) -> Result<&StorageType, StorageLayoutError> {
+        self.types
+            .as_ref()
+            .and_then(|types| types.get(type_id))
+            .ok_or_else(|| StorageLayoutError::Parse(format!("Missing type {}", type_id)))
+    }
+
+    fn type_size(&self, type_id: &str) -> Result<usize, StorageLayoutError> {
+        let ty = self.get_type(type_id)?;
+        ty.number_of_bytes
+            .parse()
+            .map_err(|_| StorageLayoutError::Parse(format!("Invalid size of type {}", type_id)))
+    }
+
+    /// Resolves a variable path to its storage location.
+    ///
+    /// Mapping keys may be given as hex addresses, decimal or hex numbers, `true`/`false` or
+    /// strings, depending on the mapping key type. Array indices are decimal or hex numbers.
+    pub fn locate(&self, path: &str) -> Result<SlotLocation, StorageLayoutError> {
+        let (name, segments) = parse_path(path)?;
+        let entry = self
+            .storage
+            .iter()
+            .find(|entry| entry.label == name)
+            .ok_or_else(|| StorageLayoutError::UnknownVariable(name.clone()))?;
+
+        let mut slot = U256::from_str(&entry.slot)
+            .map_err(|_| StorageLayoutError::Parse(format!("Invalid slot of {}", name)))?;
+        let mut offset = entry.offset;
+        let mut type_id = entry.type_id.clone();
+
+        for segment in segments {
+            let ty = self.get_type(&type_id)?;
+            match (segment, ty.encoding.as_str()) {
+                (PathSegment::Index(key), "mapping") => {
+                    let key_type = ty.key.clone().ok_or_else(|| {
+                        StorageLayoutError::Parse(format!("Missing key type of {}", type_id))
+                    })?;
+                    let value_type = ty.value.clone().ok_or_else(|| {
+                        StorageLayoutError::Parse(format!("Missing value type of {}", type_id))
+                    })?;
+                    let key_bytes = self.encode_key(&key, &key_type)?;
+                    slot = if matches!(
+                        self.get_type(&key_type)?
+                            .encoding
+                            .as_str(),
+                        "bytes"
+                    ) {
+                        // String and bytes keys are hashed unpadded
+                        U256::from_be_bytes(
+                            keccak256([key_bytes.as_slice(), &slot.to_be_bytes::<32>()].concat()).0,
+                        )
+                    } else {
+                        ContractCompiler::Solidity
+                            .compute_map_slot(&slot.to_be_bytes::<32>(), &key_bytes)
+                    };
+                    offset = 0;
+                    type_id = value_type;
+                }
+                (PathSegment::Index(index), encoding @ ("dynamic_array" | "inplace"))
+                    if ty.base.is_some() =>
+                {
+                    let base = ty.base.clone().unwrap_or_default();
+                    let index = parse_number(&index).ok_or_else(|| {
+                        StorageLayoutError::InvalidKey(index.clone(), ty.label.clone())
+                    })?;
+                    let first_slot = if encoding == "dynamic_array" {
+                        U256::from_be_bytes(keccak256(slot.to_be_bytes::<32>()).0)
+                    } else {
+                        slot
+                    };
+                    let size = self.type_size(&base)?;
+                    let (slot_delta, element_offset) = if size <= 16 {
+                        let per_slot = U256::from(32 / size);
+                        (index / per_slot, (index % per_slot).to::<usize>() * size)
+                    } else {
+                        (index * U256::from(size.div_ceil(32)), 0)
+                    };
+                    slot = first_slot.wrapping_add(slot_delta);
+                    offset = element_offset;
+                    type_id = base;
+                }
+                (PathSegment::Member(member), _) if ty.members.is_some() => {
+                    let member_entry = ty
+                        .members
+                        .iter()
+                        .flatten()
+                        .find(|entry| entry.label == member)
+                        .ok_or_else(|| {
+                            StorageLayoutError::UnknownVariable(format!("{}.{}", ty.label, member))
+                        })?;
+                    let member_slot = U256::from_str(&member_entry.slot).map_err(|_| {
+                        StorageLayoutError::Parse(format!("Invalid slot of {}", member))
+                    })?;
+                    slot = slot.wrapping_add(member_slot);
+                    offset = member_entry.offset;
+                    type_id = member_entry.type_id.clone();
+                }
+                (segment, _) => {
+                    return Err(StorageLayoutError::InvalidPath(
+                        path.to_string(),
+                        format!("{:?} can't be applied to {}", segment, ty.label),
+                    ))
+                }
+            }
+        }
+
+        Ok(SlotLocation { slot, offset, size: self.type_size(&type_id)?.min(32) })
+    }
+
+    /// Encodes a mapping key for hashing. Value types are left padded to 32 bytes (bytesN right
+    /// padded), string and bytes keys are returned unpadded.
+    fn encode_key(&self, key: &str, key_type: &str) -> Result<Vec<u8>, StorageLayoutError> {
+        let ty = self.get_type(key_type)?;
+        let invalid = || StorageLayoutError::InvalidKey(key.to_string(), ty.label.clone());
+        let label = ty.label.as_str();
+
+        if ty.encoding == "bytes" {
+            return Ok(if label == "bytes" {
+                hex::decode(key.trim_start_matches("0x")).map_err(|_| invalid())?
+            } else {
+                key.as_bytes().to_vec()
+            });
+        }
+
+        let word: [u8; 32] = if label == "address" || label.starts_with("contract ") {
+            Address::from_str(key)
+                .map_err(|_| invalid())?
+                .into_word()
+                .0
+        } else if label == "bool" {
+            match key {
+                "true" => U256::from(1),
+                "false" => U256::ZERO,
+                _ => return Err(invalid()),
+            }
+            .to_be_bytes()
+        } else if let Some(size) = label.strip_prefix("bytes") {
+            let size: usize = size.parse().map_err(|_| invalid())?;
+            let bytes = hex::decode(key.trim_start_matches("0x")).map_err(|_| invalid())?;
+            if bytes.len() > size {
+                return Err(invalid());
+            }
+            let mut word = [0u8; 32];
+            word[..bytes.len()].copy_from_slice(&bytes);
+            word
+        } else if label.starts_with("int") {
+            I256::from_dec_str(key)
+                .map_err(|_| invalid())?
+                .to_be_bytes()
+        } else {
+            // uint and enums
+            parse_number(key)
+                .ok_or_else(invalid)?
+                .to_be_bytes()
+        };
+        Ok(word.to_vec())
+    }
+}
+
+/// Builds storage overrides by variable name.
+///
+/// Layouts are registered per contract, overrides are accumulated and can be passed to
+/// `SimulationParameters::overrides` via `into_overrides`. Packed variables are merged into the
+/// slot's overridden value; bytes of the slot that were not overridden are assumed to be zero.
+#[derive(Debug, Clone, Default)]
+pub struct StorageLayoutOverrides {
+    layouts: HashMap<Address, StorageLayout>,
+    overrides: HashMap<Address, HashMap<SlotId, U256>>,
+}
+
+impl StorageLayoutOverrides {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the storage layout of `contract`.
+    pub fn with_layout(mut self, contract: Address, layout: StorageLayout) -> Self {
+        self.layouts.insert(contract, layout);
+        self
+    }
+
+    /// Overrides the variable at `path` of `contract` with `value`.
+    ///
+    /// # Example
+    ///
+    /// `overrides.override_by_name(token, "balances[0xabc...]", U256::from(100))`
+    pub fn override_by_name(
+        &mut self,
+        contract: Address,
+        path: &str,
+        value: U256,
+    ) -> Result<&mut Self, StorageLayoutError> {
+        let location = self
+            .layouts
+            .get(&contract)
+            .ok_or(StorageLayoutError::UnknownContract(contract))?
+            .locate(path)?;
+        let slots = self
+            .overrides
+            .entry(contract)
+            .or_default();
+        let current = slots
+            .get(&location.slot)
+            .copied()
+            .unwrap_or_default();
+        slots.insert(location.slot, location.apply(current, value));
+        Ok(self)
+    }
+
+    /// Returns the accumulated overrides.
+    pub fn into_overrides(self) -> HashMap<Address, HashMap<SlotId, U256>> {
+        self.overrides
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAYOUT: &str = r#"{
+        "storage": [
+            {"astId": 1, "contract": "Token", "label": "totalSupply", "offset": 0, "slot": "0", "type": "t_uint256"},
+            {"astId": 2, "contract": "Token", "label": "balances", "offset": 0, "slot": "1", "type": "t_mapping(t_address,t_uint256)"},
+            {"astId": 3, "contract": "Token", "label": "allowances", "offset": 0, "slot": "2", "type": "t_mapping(t_address,t_mapping(t_address,t_uint256))"},
+            {"astId": 4, "contract": "Token", "label": "config", "offset": 0, "slot": "3", "type": "t_struct(Config)"},
+            {"astId": 5, "contract": "Token", "label": "owners", "offset": 0, "slot": "5", "type": "t_array(t_address)dyn_storage"},
+            {"astId": 6, "contract": "Token", "label": "names", "offset": 0, "slot": "6", "type": "t_mapping(t_string_memory_ptr,t_uint256)"}
+        ],
+        "types": {
+            "t_address": {"encoding": "inplace", "label": "address", "numberOfBytes": "20"},
+            "t_bool": {"encoding": "inplace", "label": "bool", "numberOfBytes": "1"},
+            "t_uint64": {"encoding": "inplace", "label": "uint64", "numberOfBytes": "8"},
+            "t_uint256": {"encoding": "inplace", "label": "uint256", "numberOfBytes": "32"},
+            "t_string_memory_ptr": {"encoding": "bytes", "label": "string", "numberOfBytes": "32"},
+            "t_mapping(t_address,t_uint256)": {"encoding": "mapping", "key": "t_address", "label": "mapping(address => uint256)", "numberOfBytes": "32", "value": "t_uint256"},
+            "t_mapping(t_address,t_mapping(t_address,t_uint256))": {"encoding": "mapping", "key": "t_address", "label": "mapping(address => mapping(address => uint256))", "numberOfBytes": "32", "value": "t_mapping(t_address,t_uint256)"},
+            "t_mapping(t_string_memory_ptr,t_uint256)": {"encoding": "mapping", "key": "t_string_memory_ptr", "label": "mapping(string => uint256)", "numberOfBytes": "32", "value": "t_uint256"},
+            "t_array(t_address)dyn_storage": {"encoding": "dynamic_array", "label": "address[]", "numberOfBytes": "32", "base": "t_address"},
+            "t_struct(Config)": {"encoding": "inplace", "label": "struct Token.Config", "numberOfBytes": "64", "members": [
+                {"astId": 7, "contract": "Token", "label": "fee", "offset": 0, "slot": "0", "type": "t_uint64"},
+                {"astId": 8, "contract": "Token", "label": "paused", "offset": 8, "slot": "0", "type": "t_bool"},
+                {"astId": 9, "contract": "Token", "label": "owner", "offset": 0, "slot": "1", "type": "t_address"}
+            ]}
+        }
+    }"#;
+
+    const HOLDER: &str = "0x00000000000000000000000000000000000000ab";
+    const SPENDER: &str = "0x00000000000000000000000000000000000000cd";
+
+    fn layout() -> StorageLayout {
+        StorageLayout::from_json(LAYOUT).unwrap()
+    }
+
+    #[test]
+    fn test_locate_simple_and_mapping() {
+        let layout = layout();
+        let holder = Address::from_str(HOLDER).unwrap();
+
+        assert_eq!(
+            layout
+                .locate("totalSupply")
+                .unwrap()
+                .slot,
+            U256::ZERO
+        );
+        assert_eq!(
+            layout
+                .locate(&format!("balances[{}]", HOLDER))
+                .unwrap()
+                .slot,
+            ContractCompiler::Solidity
+                .compute_map_slot(&U256::from(1).to_be_bytes::<32>(), &holder.into_word().0)
+        );
+    }
+
+    #[test]
+    fn test_locate_nested_mapping() {
+        let layout = layout();
+        let holder = Address::from_str(HOLDER).unwrap();
+        let spender = Address::from_str(SPENDER).unwrap();
+
+        let inner = ContractCompiler::Solidity
+            .compute_map_slot(&U256::from(2).to_be_bytes::<32>(), &holder.into_word().0);
+        let expected = ContractCompiler::Solidity
+            .compute_map_slot(&inner.to_be_bytes::<32>(), &spender.into_word().0);
+
+        assert_eq!(
+            layout
+                .locate(&format!("allowances[{}][{}]", HOLDER, SPENDER))
+                .unwrap()
+                .slot,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_locate_struct_and_array() {
+        let layout = layout();
+
+        assert_eq!(
+            layout.locate("config.paused").unwrap(),
+            SlotLocation { slot: U256::from(3), offset: 8, size: 1 }
+        );
+        assert_eq!(
+            layout.locate("config.owner").unwrap(),
+            SlotLocation { slot: U256::from(4), offset: 0, size: 20 }
+        );
+        let first = U256::from_be_bytes(keccak256(U256::from(5).to_be_bytes::<32>()).0);
+        assert_eq!(layout.locate("owners[2]").unwrap().slot, first + U256::from(2));
+    }
+
+    #[test]
+    fn test_locate_string_key() {
+        let layout = layout();
+        let expected = U256::from_be_bytes(
+            keccak256([b"alice".as_slice(), &U256::from(6).to_be_bytes::<32>()].concat()).0,
+        );
+
+        assert_eq!(
+            layout
+                .locate("names[alice]")
+                .unwrap()
+                .slot,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_locate_errors() {
+        let layout = layout();
+
+        assert!(matches!(layout.locate("unknown"), Err(StorageLayoutError::UnknownVariable(_))));
+        assert!(matches!(layout.locate("balances[0xzz]"), Err(StorageLayoutError::InvalidKey(..))));
+        assert!(matches!(
+            layout.locate("totalSupply[1]"),
+            Err(StorageLayoutError::InvalidPath(..))
+        ));
+        assert!(matches!(layout.locate("balances[1"), Err(StorageLayoutError::InvalidPath(..))));
+    }
+
+    #[test]
+    fn test_override_by_name_packed() {
+        let contract = Address::repeat_byte(1);
+        let mut overrides = StorageLayoutOverrides::new().with_layout(contract, layout());
+
+        overrides
+            .override_by_name(contract, "config.fee", U256::from(30))
+            .unwrap()
+            .override_by_name(contract, "config.paused", U256::from(1))
+            .unwrap();
+
+        let overrides = overrides.into_overrides();
+        assert_eq!(overrides[&contract][&U256::from(3)], U256::from(30) | (U256::from(1) << 64));
+    }
+}
@@ -1,15 +1,30 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use futures::{Stream, StreamExt};
+use tokio::{
+    sync::{watch, RwLock},
+    task::AbortHandle,
+};
 use tokio_stream::wrappers::ReceiverStream;
+use tracing::{info, warn};
 use tycho_client::{
-    feed::{component_tracker::ComponentFilter, synchronizer::ComponentWithState},
+    feed::{component_tracker::ComponentFilter, synchronizer::ComponentWithState, FeedMessage},
     stream::{StreamError, TychoStreamBuilder},
 };
 use tycho_core::{models::Chain, Bytes};
 
 use crate::{
-    evm::decoder::{StreamDecodeError, TychoStreamDecoder},
+    evm::{
+        decoder::{ProtocolStats, StreamDecodeError, TychoStreamDecoder},
+        engine_db::{tycho_db::PreCachedDBError, SHARED_TYCHO_DB},
+        plugin::{PluginRegistrar, ProtocolPlugin},
+        recording::{Recording, StreamRecorder},
+    },
     models::Token,
     protocol::{
         errors::InvalidSnapshotError,
@@ -50,13 +65,19 @@ use crate::{
 pub struct ProtocolStreamBuilder {
     decoder: TychoStreamDecoder,
     stream_builder: TychoStreamBuilder,
+    snapshot_path: Option<PathBuf>,
+    recorder: Option<Arc<Mutex<StreamRecorder>>>,
 }
 
 impl ProtocolStreamBuilder {
     pub fn new(tycho_url: &str, chain: Chain) -> Self {
+        let mut decoder = TychoStreamDecoder::new();
+        decoder.chain(chain);
         Self {
-            decoder: TychoStreamDecoder::new(),
+            decoder,
             stream_builder: TychoStreamBuilder::new(tycho_url, chain.into()),
+            snapshot_path: None,
+            recorder: None,
         }
     }
 
@@ -86,6 +107,19 @@ impl ProtocolStreamBuilder {
         self
     }
 
+    /// Adds the exchanges of a plugin, see `evm::plugin`. All of them are streamed with `filter`.
+    pub fn plugin(mut self, plugin: &dyn ProtocolPlugin, filter: ComponentFilter) -> Self {
+        let mut registrar = PluginRegistrar::new(&mut self.decoder);
+        plugin.register(&mut registrar);
+        for name in registrar.into_exchanges() {
+            info!(plugin = plugin.name(), exchange = %name, "Registered plugin exchange");
+            self.stream_builder = self
+                .stream_builder
+                .exchange(&name, filter.clone());
+        }
+        self
+    }
+
     /// Sets the block time for the Tycho client.
     pub fn block_time(mut self, block_time: u64) -> Self {
         self.stream_builder = self
@@ -135,18 +169,312 @@ impl ProtocolStreamBuilder {
         self
     }
 
+    /// Sets the number of snapshots decoded in parallel during the initial sync. Defaults to the
+    /// number of available CPUs.
+    pub fn decode_concurrency(mut self, concurrency: usize) -> Self {
+        self.decoder
+            .decode_concurrency(concurrency);
+        self
+    }
+
+    /// Exports the shared VM database to the given path on shutdown, see `PreCachedDB::export`.
+    ///
+    /// Only used by streams built with `build_with_handle`.
+    pub fn snapshot_on_shutdown(mut self, path: impl Into<PathBuf>) -> Self {
+        self.snapshot_path = Some(path.into());
+        self
+    }
+
+    /// Records the raw messages of the stream to `recorder` before decoding them, see
+    /// `evm::recording`. Messages failing to be recorded are logged and still decoded.
+    pub fn record_to(mut self, recorder: StreamRecorder) -> Self {
+        self.recorder = Some(Arc::new(Mutex::new(recorder)));
+        self
+    }
+
+    /// Replays a recording through the configured decoders instead of connecting to Tycho.
+    ///
+    /// Messages are decoded one after another in the recorded order, with the recorded chain and
+    /// tokens, so replaying a recording always yields the same block updates.
+    pub async fn replay(
+        mut self,
+        recording: Recording,
+    ) -> impl Stream<Item = Result<BlockUpdate, StreamDecodeError>> {
+        self.decoder.chain(recording.chain);
+        self.decoder
+            .set_tokens(recording.decoder_tokens())
+            .await;
+        let decoder = Arc::new(self.decoder);
+
+        Box::pin(futures::stream::iter(recording.messages).then(move |msg| {
+            let decoder = decoder.clone();
+            async move { decoder.decode(msg).await }
+        }))
+    }
+
+    /// Builds the stream together with a handle to monitor its health and shut it down.
+    ///
+    /// Once shutdown is requested, the stream finishes decoding the current block and then ends.
+    pub async fn build_with_handle(
+        self,
+    ) -> Result<
+        (StreamHandle, impl Stream<Item = Result<BlockUpdate, StreamDecodeError>>),
+        StreamError,
+    > {
+        let (client_handle, rx) = self.stream_builder.build().await?;
+        let decoder = Arc::new(self.decoder);
+        let recorder = self.recorder;
+        let health = Arc::new(HealthTracker::default());
+        let in_flight = Arc::new(RwLock::new(()));
+        let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+
+        let shutdown_signal = async move {
+            // Resolves once shutdown is requested or the handle is dropped
+            let _ = shutdown_rx
+                .wait_for(|requested| *requested)
+                .await;
+        };
+        let stream = ReceiverStream::new(rx)
+            .take_until(shutdown_signal)
+            .then({
+                let decoder = decoder.clone();
+                let health = health.clone();
+                let in_flight = in_flight.clone();
+                move |msg| {
+                    record(recorder.as_deref(), &msg);
+                    let decoder = decoder.clone();
+                    let health = health.clone();
+                    let in_flight = in_flight.clone();
+                    async move {
+                        let _guard = in_flight.read().await;
+                        let res = decoder.decode(msg).await;
+                        match &res {
+                            Ok(update) => health.record_block(update.block_number),
+                            Err(_) => health.record_error(),
+                        }
+                        res
+                    }
+                }
+            });
+
+        let handle = StreamHandle {
+            client_handle: client_handle.abort_handle(),
+            decoder: decoder.clone(),
+            shutdown_tx,
+            in_flight,
+            health,
+            snapshot_path: self.snapshot_path,
+        };
+        Ok((handle, Box::pin(stream)))
+    }
+
     pub async fn build(
         self,
     ) -> Result<impl Stream<Item = Result<BlockUpdate, StreamDecodeError>>, StreamError> {
         let (_, rx) = self.stream_builder.build().await?;
         let decoder = Arc::new(self.decoder);
+        let recorder = self.recorder;
 
         Ok(Box::pin(ReceiverStream::new(rx).then({
             let decoder = decoder.clone(); // Clone the decoder for the closure
             move |msg| {
+                record(recorder.as_deref(), &msg);
                 let decoder = decoder.clone(); // Clone again for the async block
                 async move { decoder.decode(msg).await }
             }
         })))
     }
 }
+
+fn record(recorder: Option<&Mutex<StreamRecorder>>, msg: &FeedMessage) {
+    if let Some(recorder) = recorder {
+        if let Err(e) = recorder.lock().unwrap().record(msg) {
+            warn!(?e, "Failed to record stream message");
+        }
+    }
+}
+
+/// Health of a protocol stream, for liveness and readiness probes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Health {
+    /// Whether the stream is running, false once shut down or if the websocket client stopped
+    pub running: bool,
+    /// Number of the last successfully decoded block
+    pub last_block: Option<u64>,
+    /// Seconds since the last block was decoded
+    pub lag: Option<u64>,
+    /// Number of successfully decoded blocks
+    pub blocks: u64,
+    /// Number of messages that failed to decode
+    pub errors: u64,
+    /// Decoding statistics by protocol system
+    pub protocols: HashMap<String, ProtocolStats>,
+}
+
+#[derive(Debug, Default)]
+struct HealthTracker {
+    inner: Mutex<HealthState>,
+}
+
+#[derive(Debug, Default)]
+struct HealthState {
+    last_block: Option<u64>,
+    last_block_at: Option<u64>,
+    blocks: u64,
+    errors: u64,
+}
+
+impl HealthTracker {
+    fn record_block(&self, number: u64) {
+        let mut state = self.inner.lock().unwrap();
+        state.last_block = Some(number);
+        state.last_block_at = Some(unix_now());
+        state.blocks += 1;
+    }
+
+    fn record_error(&self) {
+        self.inner.lock().unwrap().errors += 1;
+    }
+
+    fn health(&self, running: bool, now: u64) -> Health {
+        let state = self.inner.lock().unwrap();
+        Health {
+            running,
+            last_block: state.last_block,
+            lag: state
+                .last_block_at
+                .map(|at| now.saturating_sub(at)),
+            blocks: state.blocks,
+            errors: state.errors,
+            protocols: HashMap::new(),
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// Handle to a stream built with `ProtocolStreamBuilder::build_with_handle`.
+pub struct StreamHandle {
+    /// Handle of the websocket client task
+    client_handle: AbortHandle,
+    shutdown_tx: watch::Sender<bool>,
+    in_flight: Arc<RwLock<()>>,
+    health: Arc<HealthTracker>,
+    decoder: Arc<TychoStreamDecoder>,
+    snapshot_path: Option<PathBuf>,
+}
+
+impl StreamHandle {
+    pub fn health(&self) -> Health {
+        let running = !*self.shutdown_tx.borrow() && !self.client_handle.is_finished();
+        Health { protocols: self.decoder.stats(), ..self.health.health(running, unix_now()) }
+    }
+
+    /// Shuts the stream down.
+    ///
+    /// Stops the stream from taking new messages, waits up to `timeout` for the block being
+    /// decoded to complete, stops the websocket client and, if configured, exports the shared VM
+    /// database. The stream ends once the current block was yielded.
+    pub async fn shutdown(self, timeout: Duration) -> Result<Health, PreCachedDBError> {
+        info!("Shutting down protocol stream");
+        let _ = self.shutdown_tx.send(true);
+        if tokio::time::timeout(timeout, self.in_flight.write())
+            .await
+            .is_err()
+        {
+            warn!("Timed out waiting for the current block to be decoded");
+        }
+        self.client_handle.abort();
+
+        if let Some(path) = &self.snapshot_path {
+            info!(?path, "Exporting VM database");
+            SHARED_TYCHO_DB.export(path)?;
+        }
+        Ok(Health { protocols: self.decoder.stats(), ..self.health.health(false, unix_now()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use num_bigint::ToBigUint;
+
+    use super::*;
+    use crate::evm::protocol::uniswap_v2::state::UniswapV2State;
+
+    fn load_test_msg(name: &str) -> FeedMessage {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join(format!("tests/assets/decoder/{}.json", name));
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_health_tracker() {
+        let tracker = HealthTracker::default();
+        assert_eq!(tracker.health(true, 0), Health { running: true, ..Default::default() });
+
+        tracker.record_block(10);
+        tracker.record_error();
+        let now = unix_now();
+        let health = tracker.health(true, now + 5);
+
+        assert_eq!(health.last_block, Some(10));
+        assert!(health.lag.unwrap() >= 5);
+        assert_eq!(health.blocks, 1);
+        assert_eq!(health.errors, 1);
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stream.jsonl.gz");
+        let tokens: HashMap<Bytes, Token> = [
+            ("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2", "WETH"),
+            ("0xdac17f958d2ee523a2206206994597c13d831ec7", "USDT"),
+        ]
+        .iter()
+        .map(|(address, symbol)| {
+            let token = Token::new(address, 18, symbol, 10_000.to_biguint().unwrap());
+            (token.address.clone(), token)
+        })
+        .collect();
+        let mut recorder = StreamRecorder::create(&path, Chain::Ethereum, &tokens).unwrap();
+        recorder
+            .record(&load_test_msg("uniswap_v2_snapshot"))
+            .unwrap();
+        recorder
+            .record(&load_test_msg("uniswap_v2_delta"))
+            .unwrap();
+        recorder.finish().unwrap();
+
+        let updates: Vec<_> = ProtocolStreamBuilder::new("localhost:4242", Chain::Ethereum)
+            .exchange::<UniswapV2State>(
+                "uniswap_v2",
+                ComponentFilter::with_tvl_range(0.0, 0.0),
+                None,
+            )
+            .replay(Recording::read(&path).unwrap())
+            .await
+            .collect()
+            .await;
+
+        assert_eq!(updates.len(), 2);
+        let snapshot = updates[0].as_ref().unwrap();
+        assert_eq!(snapshot.new_pairs.len(), 1);
+        assert_eq!(snapshot.states.len(), 1);
+        assert_eq!(
+            updates[1]
+                .as_ref()
+                .unwrap()
+                .states
+                .len(),
+            1
+        );
+    }
+}
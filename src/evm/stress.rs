@@ -0,0 +1,268 @@
+//! Load testing of snapshot and delta processing
+//!
+//! Enabled with the `stress` feature. Replays a fixture, a snapshot followed by block deltas,
+//! through the decoder and records the processing latency and resident memory after every block,
+//! so the scaling of the decoder and `PreCachedDB` to many thousand pools can be measured. The
+//! `stress` example drives this from the command line.
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::BufReader,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use thiserror::Error;
+use tycho_client::feed::FeedMessage;
+use tycho_core::{dto::ResponseToken, Bytes};
+
+use crate::{
+    evm::decoder::{StreamDecodeError, TychoStreamDecoder},
+    models::Token,
+    protocol::models::BlockUpdate,
+};
+
+#[derive(Error, Debug)]
+pub enum StressError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid fixture: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Decoding failed: {0}")]
+    Decode(#[from] StreamDecodeError),
+}
+
+/// A recorded stream: the tokens, a snapshot of the pools and the deltas of the following blocks.
+#[derive(Deserialize)]
+pub struct StressFixture {
+    pub tokens: Vec<ResponseToken>,
+    pub snapshot: FeedMessage,
+    #[serde(default)]
+    pub deltas: Vec<FeedMessage>,
+}
+
+impl StressFixture {
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, StressError> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+}
+
+/// Measurements of a single processed message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockSample {
+    pub block_number: u64,
+    /// Number of pool states decoded or updated by the message
+    pub updated_pools: usize,
+    pub latency: Duration,
+    /// Resident memory after processing, if it can be read on this platform
+    pub rss_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StressReport {
+    /// Number of pools loaded from the snapshot
+    pub pools: usize,
+    pub snapshot: BlockSample,
+    pub blocks: Vec<BlockSample>,
+}
+
+impl StressReport {
+    /// Returns the `quantile` (between 0 and 1) of the per-block latencies.
+    pub fn latency_quantile(&self, quantile: f64) -> Option<Duration> {
+        let mut latencies: Vec<_> = self
+            .blocks
+            .iter()
+            .map(|sample| sample.latency)
+            .collect();
+        latencies.sort_unstable();
+        let last = latencies.len().checked_sub(1)?;
+        let index = (quantile.clamp(0.0, 1.0) * last as f64).round() as usize;
+        Some(latencies[index])
+    }
+
+    /// Highest resident memory observed during the run
+    pub fn peak_rss(&self) -> Option<u64> {
+        std::iter::once(&self.snapshot)
+            .chain(&self.blocks)
+            .filter_map(|sample| sample.rss_bytes)
+            .max()
+    }
+}
+
+impl fmt::Display for StressReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mib = |bytes: Option<u64>| match bytes {
+            Some(bytes) => format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0)),
+            None => "n/a".to_string(),
+        };
+        writeln!(
+            f,
+            "snapshot: {} pools in {:?}, rss {}",
+            self.pools,
+            self.snapshot.latency,
+            mib(self.snapshot.rss_bytes)
+        )?;
+        match (
+            self.latency_quantile(0.0),
+            self.latency_quantile(0.5),
+            self.latency_quantile(0.99),
+            self.latency_quantile(1.0),
+        ) {
+            (Some(min), Some(p50), Some(p99), Some(max)) => writeln!(
+                f,
+                "blocks: {} processed, latency min {:?} p50 {:?} p99 {:?} max {:?}",
+                self.blocks.len(),
+                min,
+                p50,
+                p99,
+                max
+            )?,
+            _ => writeln!(f, "blocks: none processed")?,
+        }
+        write!(f, "peak rss: {}", mib(self.peak_rss()))
+    }
+}
+
+/// Decodes the snapshot of `fixture`, then replays its deltas until `blocks` deltas were
+/// processed, starting over with the first delta when they run out.
+///
+/// The decoder is configured with all builtin exchanges and skips pools failing to decode, like a
+/// production stream would.
+pub async fn replay(fixture: StressFixture, blocks: usize) -> Result<StressReport, StressError> {
+    let tokens: HashMap<Bytes, Token> = fixture
+        .tokens
+        .into_iter()
+        .filter_map(|token| {
+            Token::try_from(token)
+                .ok()
+                .map(|token| (token.address.clone(), token))
+        })
+        .collect();
+
+    let mut decoder = TychoStreamDecoder::new();
+    decoder.skip_state_decode_failures(true);
+    decoder.register_builtin_exchanges();
+    decoder.set_tokens(tokens).await;
+
+    let (update, snapshot) = measure(&decoder, fixture.snapshot).await?;
+    let pools = update.new_pairs.len();
+
+    let mut samples = Vec::with_capacity(blocks);
+    for msg in fixture
+        .deltas
+        .iter()
+        .cycle()
+        .take(blocks)
+    {
+        let (_, sample) = measure(&decoder, msg.clone()).await?;
+        samples.push(sample);
+    }
+
+    Ok(StressReport { pools, snapshot, blocks: samples })
+}
+
+async fn measure(
+    decoder: &TychoStreamDecoder,
+    msg: FeedMessage,
+) -> Result<(BlockUpdate, BlockSample), StressError> {
+    let start = Instant::now();
+    let update = decoder.decode(msg).await?;
+    let latency = start.elapsed();
+    let sample = BlockSample {
+        block_number: update.block_number,
+        updated_pools: update.states.len(),
+        latency,
+        rss_bytes: resident_memory(),
+    };
+    Ok((update, sample))
+}
+
+/// Returns the resident memory of this process. Only supported on Linux.
+pub fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kib = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn load_test_msg(name: &str) -> FeedMessage {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join(format!("tests/assets/decoder/{}.json", name));
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    fn sample(latency_ms: u64, rss_bytes: Option<u64>) -> BlockSample {
+        BlockSample {
+            block_number: 1,
+            updated_pools: 1,
+            latency: Duration::from_millis(latency_ms),
+            rss_bytes,
+        }
+    }
+
+    #[test]
+    fn test_report_quantiles() {
+        let report = StressReport {
+            pools: 1,
+            snapshot: sample(100, Some(10)),
+            blocks: (1..=100)
+                .map(|ms| sample(ms, Some(ms)))
+                .collect(),
+        };
+
+        assert_eq!(report.latency_quantile(0.0), Some(Duration::from_millis(1)));
+        assert_eq!(report.latency_quantile(0.5), Some(Duration::from_millis(51)));
+        assert_eq!(report.latency_quantile(1.0), Some(Duration::from_millis(100)));
+        assert_eq!(report.peak_rss(), Some(100));
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let tokens = [
+            ("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2", "WETH"),
+            ("0xdac17f958d2ee523a2206206994597c13d831ec7", "USDT"),
+        ]
+        .iter()
+        .map(|(address, symbol)| {
+            serde_json::from_value(serde_json::json!({
+                "chain": "ethereum",
+                "address": address,
+                "symbol": symbol,
+                "decimals": 18,
+                "tax": 0,
+                "gas": [100000],
+                "quality": 100
+            }))
+            .unwrap()
+        })
+        .collect();
+        let fixture = StressFixture {
+            tokens,
+            snapshot: load_test_msg("uniswap_v2_snapshot"),
+            deltas: vec![load_test_msg("uniswap_v2_delta")],
+        };
+
+        let report = replay(fixture, 3).await.unwrap();
+
+        assert_eq!(report.pools, 1);
+        assert_eq!(report.blocks.len(), 3);
+        assert!(report
+            .blocks
+            .iter()
+            .all(|sample| sample.updated_pools == 1));
+    }
+}
@@ -0,0 +1,165 @@
+//! Trace export
+//!
+//! Runs a simulation with a tracing inspector and exports the call trace and state diff in the
+//! format of Geth's `callTracer` and `prestateTracer` (diff mode). Tenderly and most block
+//! explorers accept these documents, so failing simulations can be shared and visualized with
+//! existing tooling.
+use std::fmt::Debug;
+
+use alloy::rpc::types::trace::geth::{CallConfig, CallFrame, PreStateConfig, PreStateFrame};
+use revm::{inspector_handle_register, DatabaseRef, Evm};
+use revm_inspectors::tracing::{TracingInspector, TracingInspectorConfig};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    engine_db::{
+        engine_db_interface::EngineDatabaseInterface, simulation_db::OverriddenSimulationDB,
+    },
+    simulation::{
+        account_before, configure_gas, interpret_evm_result, SimulationEngine,
+        SimulationEngineError, SimulationParameters, SimulationResult,
+    },
+};
+
+/// Call trace and state diff of a simulated transaction.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SimulationTrace {
+    /// Call tree including logs, as returned by Geth's `callTracer`
+    pub call_tracer: CallFrame,
+    /// Touched accounts before and after the transaction, as returned by Geth's
+    /// `prestateTracer` with `diffMode` enabled
+    pub prestate_tracer: PreStateFrame,
+}
+
+impl SimulationTrace {
+    /// Serializes the trace into a JSON document.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).expect("Serializing a simulation trace can't fail")
+    }
+
+    /// Serializes the trace into a pretty printed JSON string.
+    pub fn to_json_string(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Serializing a simulation trace can't fail")
+    }
+}
+
+impl<D: EngineDatabaseInterface + Clone + Debug> SimulationEngine<D>
+where
+    <D as DatabaseRef>::Error: std::fmt::Debug,
+    <D as EngineDatabaseInterface>::Error: std::fmt::Debug,
+{
+    /// Simulates a transaction and records its trace.
+    ///
+    /// Returns the simulation result, exactly as `simulate` would, together with the trace. The
+    /// trace is also returned if the transaction reverts or halts; it is only `None` if the
+    /// transaction could not be executed at all (e.g. a storage error).
+    pub fn simulate_with_trace(
+        &self,
+        params: &SimulationParameters,
+    ) -> (Result<SimulationResult, SimulationEngineError>, Option<SimulationTrace>) {
+        let spec_id = match self.resolve_spec_id(params.spec_id) {
+            Ok(spec_id) => spec_id,
+            Err(err) => return (Err(err), None),
+        };
+        let overrides = params
+            .overrides
+            .clone()
+            .unwrap_or_default();
+        let db_ref = OverriddenSimulationDB { inner_db: &self.state, overrides: &overrides };
+
+        let mut tracer = TracingInspector::new(TracingInspectorConfig::all());
+        let res = {
+            let mut vm = Evm::builder()
+                .with_spec_id(spec_id)
+                .with_ref_db(&db_ref)
+                .with_block_env(params.block_env())
+                .with_tx_env(params.tx_env(self.is_gasless()))
+                .modify_cfg_env(|cfg| configure_gas(cfg, self.is_gasless()))
+                .with_external_context(&mut tracer)
+                .append_handler_register(inspector_handle_register)
+                .build();
+            vm.transact()
+        };
+
+        let trace = res
+            .as_ref()
+            .ok()
+            .and_then(|result_and_state| {
+                let builder = tracer.geth_builder();
+                let call_tracer = builder.geth_call_traces(
+                    CallConfig::default().with_log(),
+                    result_and_state.result.gas_used(),
+                );
+                let prestate_tracer = builder
+                    .geth_prestate_traces(
+                        result_and_state,
+                        &PreStateConfig {
+                            diff_mode: Some(true),
+                            disable_code: Some(false),
+                            disable_storage: Some(false),
+                        },
+                        &db_ref,
+                    )
+                    .ok()?;
+                Some(SimulationTrace { call_tracer, prestate_tracer })
+            });
+
+        (interpret_evm_result(res, |address| account_before(&self.state, address)), trace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use alloy_primitives::{hex, keccak256, Address, U256};
+    use revm::primitives::{AccountInfo, Bytecode};
+
+    use super::*;
+    use crate::evm::engine_db::{create_engine, simulation_db::BlockHeader, tycho_db::PreCachedDB};
+
+    // Runtime code reverting with empty data: PUSH1 0 PUSH1 0 REVERT
+    const REVERT: &str = "60006000fd";
+
+    #[test]
+    fn test_simulate_with_trace_revert() {
+        let engine = create_engine(PreCachedDB::new().unwrap(), false).unwrap();
+        let contract = Address::repeat_byte(0xaa);
+        let code = Bytecode::new_raw(hex::decode(REVERT).unwrap().into());
+        engine.state.init_account(
+            contract,
+            AccountInfo {
+                balance: U256::ZERO,
+                nonce: 0,
+                code_hash: keccak256(code.bytes()),
+                code: Some(code),
+            },
+            Some(HashMap::new()),
+            false,
+        );
+        engine
+            .state
+            .init_account(Address::repeat_byte(0x01), AccountInfo::default(), None, false);
+        let params = SimulationParameters {
+            caller: Address::repeat_byte(0x01),
+            to: contract,
+            data: vec![],
+            value: U256::ZERO,
+            overrides: None,
+            gas_limit: None,
+            spec_id: None,
+            block: BlockHeader { number: 1, timestamp: 1, ..Default::default() },
+        };
+
+        let (result, trace) = engine.simulate_with_trace(&params);
+
+        assert!(matches!(result, Err(SimulationEngineError::TransactionError { .. })));
+        let trace = trace.unwrap();
+        assert_eq!(trace.call_tracer.to, Some(contract));
+        assert_eq!(trace.call_tracer.error.as_deref(), Some("execution reverted"));
+        let json = trace.to_json();
+        assert_eq!(json["callTracer"]["type"], "CALL");
+        assert!(json.get("prestateTracer").is_some());
+    }
+}
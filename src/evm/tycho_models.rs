@@ -68,7 +68,10 @@ impl From<Block> for BlockHeader {
         Self {
             number: value.number,
             hash: value.hash,
+            parent_hash: value.parent_hash,
             timestamp: value.ts.and_utc().timestamp() as u64,
+            basefee: None,
+            chain: value.chain,
         }
     }
 }
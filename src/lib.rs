@@ -17,4 +17,5 @@ pub mod evm;
 pub mod models;
 pub mod protocol;
 pub mod serde_helpers;
+pub mod tokens;
 pub mod utils;
@@ -14,7 +14,22 @@ use alloy_primitives::U256;
 use num_bigint::BigUint;
 use tycho_core::{dto::ResponseToken, Bytes};
 
-use crate::utils::hexstring_to_vec;
+use crate::{protocol::errors::SimulationError, utils::hexstring_to_vec};
+
+/// The largest number of decimals whose unit, `10^decimals`, still fits into a U256
+pub const MAX_TOKEN_DECIMALS: usize = 77;
+
+/// How safely amounts and prices of a token can be scaled, see `Token::sanity`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenSanity {
+    /// 1 to 18 decimals
+    Standard,
+    /// 0 or more than 18 decimals. Supported, but prices lose precision and amounts approach the
+    /// limits of U256 quickly
+    Unusual,
+    /// More than `MAX_TOKEN_DECIMALS` decimals. One unit of the token doesn't fit into a U256
+    Unsupported,
+}
 
 #[derive(Clone, Debug, Eq)]
 pub struct Token {
@@ -58,9 +73,34 @@ impl Token {
     ///
     /// ## Return
     /// Return one token as U256
+    ///
+    /// ## Panic
+    /// - Overflows for tokens with more than `MAX_TOKEN_DECIMALS` decimals, use `try_one` for
+    ///   tokens that weren't checked with `sanity`
     pub fn one(&self) -> U256 {
         U256::from(10).pow(U256::from(self.decimals))
     }
+
+    /// Get one token in U256 format, or an `InvalidInput` error if it doesn't fit into a U256
+    pub fn try_one(&self) -> Result<U256, SimulationError> {
+        U256::from(10)
+            .checked_pow(U256::from(self.decimals))
+            .ok_or_else(|| {
+                SimulationError::InvalidInput(
+                    format!("Token {} has too many decimals: {}", self.symbol, self.decimals),
+                    None,
+                )
+            })
+    }
+
+    /// Classifies how safely amounts and prices of this token can be scaled by its decimals.
+    pub fn sanity(&self) -> TokenSanity {
+        match self.decimals {
+            1..=18 => TokenSanity::Standard,
+            decimals if decimals > MAX_TOKEN_DECIMALS => TokenSanity::Unsupported,
+            _ => TokenSanity::Unusual,
+        }
+    }
 }
 
 impl PartialOrd for Token {
@@ -166,4 +206,23 @@ mod tests {
 
         assert_eq!(usdc.one(), U256::from(1000000));
     }
+
+    #[test]
+    fn test_sanity() {
+        let token = |decimals| {
+            Token::new(
+                "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48",
+                decimals,
+                "TKN",
+                10000.to_biguint().unwrap(),
+            )
+        };
+
+        assert_eq!(token(6).sanity(), TokenSanity::Standard);
+        assert_eq!(token(0).sanity(), TokenSanity::Unusual);
+        assert_eq!(token(36).sanity(), TokenSanity::Unusual);
+        assert_eq!(token(77).try_one().unwrap(), U256::from(10).pow(U256::from(77)));
+        assert_eq!(token(78).sanity(), TokenSanity::Unsupported);
+        assert!(matches!(token(78).try_one(), Err(SimulationError::InvalidInput(..))));
+    }
 }
@@ -0,0 +1,339 @@
+//! Audit log of served quotes
+//!
+//! Market makers reconcile their trades against the quotes they acted on. An `AuditLog` records
+//! every quote served through it: its inputs, pool, block, result and latency, together with an
+//! optional idempotency key supplied by the caller, e.g. the id of the order the quote is for, so
+//! every trade can be matched with exactly the quote it was based on.
+//!
+//! Records are written to a pluggable `AuditSink`. `FileSink` appends them to a file as
+//! newline-delimited JSON; other destinations, like a Kafka topic, implement `AuditSink`
+//! themselves. Sinks are called on the quoting path, so they should buffer rather than block. A
+//! record that can't be written is logged, but never fails the quote. Buffered records are written
+//! out with `AuditLog::flush`, or when the sink is dropped.
+//!
+//! `PoolRegistry::with_audit_log` records the quotes served by a registry.
+use std::{
+    fmt::Debug,
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::warn;
+use tycho_core::Bytes;
+
+use crate::{
+    models::Token,
+    protocol::{clock, errors::SimulationError, models::GetAmountOutResult},
+};
+
+#[derive(Error, Debug)]
+pub enum AuditError {
+    #[error("Failed to write audit record: {0}")]
+    Io(#[from] io::Error),
+    #[error("Failed to serialize audit record: {0}")]
+    Serialization(#[from] serde_json::Error),
+}
+
+/// A served quote. Amounts are decimal strings in the token's smallest unit.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuoteRecord {
+    /// Key supplied by the caller to identify the quote
+    pub idempotency_key: Option<String>,
+    pub pool: String,
+    pub protocol_system: String,
+    /// Block of the state the quote was computed on, if known
+    pub block_number: Option<u64>,
+    pub block_hash: Option<Bytes>,
+    pub token_in: Bytes,
+    pub token_out: Bytes,
+    pub amount_in: String,
+    /// `None` if the quote failed, see `error`
+    pub amount_out: Option<String>,
+    pub gas: Option<String>,
+    pub error: Option<String>,
+    /// Time spent computing the quote
+    pub latency_micros: u64,
+    /// Time the quote was served, in seconds since the unix epoch
+    pub timestamp: u64,
+}
+
+/// A destination of audit records.
+pub trait AuditSink: Debug + Send + Sync {
+    fn record(&self, record: &QuoteRecord) -> Result<(), AuditError>;
+
+    /// Writes out the buffered records, if any.
+    fn flush(&self) -> Result<(), AuditError> {
+        Ok(())
+    }
+}
+
+/// How often `FileSink` flushes its buffer by default
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Appends audit records to a file, one JSON object per line.
+///
+/// Records are buffered and flushed on the first record after the flush interval elapsed, when
+/// the buffer is full, on `AuditSink::flush` and on drop. A crash loses the records of the last
+/// interval at most.
+#[derive(Debug)]
+pub struct FileSink {
+    writer: Mutex<FileWriter>,
+    flush_interval: Duration,
+}
+
+#[derive(Debug)]
+struct FileWriter {
+    buffer: BufWriter<File>,
+    last_flush: Instant,
+}
+
+impl FileSink {
+    /// Opens `path` for appending, creating it if needed.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, AuditError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Self {
+            writer: Mutex::new(FileWriter {
+                buffer: BufWriter::new(file),
+                last_flush: Instant::now(),
+            }),
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+        })
+    }
+
+    /// Flushes the buffered records at most every `interval`, 1 second by default.
+    pub fn with_flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = interval;
+        self
+    }
+}
+
+impl FileWriter {
+    fn flush(&mut self) -> Result<(), AuditError> {
+        self.buffer.flush()?;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+}
+
+impl AuditSink for FileSink {
+    fn record(&self, record: &QuoteRecord) -> Result<(), AuditError> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut writer = self
+            .writer
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        writer.buffer.write_all(&line)?;
+        if writer.last_flush.elapsed() >= self.flush_interval {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), AuditError> {
+        self.writer
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .flush()
+    }
+}
+
+impl Drop for FileSink {
+    fn drop(&mut self) {
+        let writer = self
+            .writer
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Err(error) = writer.flush() {
+            warn!(%error, "Failed to flush the audit log");
+        }
+    }
+}
+
+/// What a quote is requested for.
+#[derive(Debug, Clone, Copy)]
+pub struct QuoteRequest<'a> {
+    pub idempotency_key: Option<&'a str>,
+    pub pool: &'a str,
+    pub protocol_system: &'a str,
+    pub amount_in: &'a BigUint,
+    pub token_in: &'a Token,
+    pub token_out: &'a Token,
+}
+
+/// Records served quotes to an `AuditSink`, see module docs.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    sink: Arc<dyn AuditSink>,
+}
+
+impl AuditLog {
+    pub fn new(sink: impl AuditSink + 'static) -> Self {
+        Self { sink: Arc::new(sink) }
+    }
+
+    /// Writes out the records buffered by the sink.
+    pub fn flush(&self) -> Result<(), AuditError> {
+        self.sink.flush()
+    }
+
+    /// Computes a quote with `quote` and records it, whether it succeeded or not.
+    pub fn quote(
+        &self,
+        request: QuoteRequest<'_>,
+        quote: impl FnOnce() -> Result<GetAmountOutResult, SimulationError>,
+    ) -> Result<GetAmountOutResult, SimulationError> {
+        let start = Instant::now();
+        let result = quote();
+        let latency = start.elapsed();
+
+        let (amount_out, gas, error) = match &result {
+            Ok(result) => (Some(result.amount.to_string()), Some(result.gas.to_string()), None),
+            Err(e) => (None, None, Some(e.to_string())),
+        };
+        let block = result
+            .as_ref()
+            .ok()
+            .and_then(|result| result.block.as_ref());
+        let record = QuoteRecord {
+            idempotency_key: request
+                .idempotency_key
+                .map(str::to_string),
+            pool: request.pool.to_string(),
+            protocol_system: request.protocol_system.to_string(),
+            block_number: block.map(|block| block.number),
+            block_hash: block.map(|block| block.hash.clone()),
+            token_in: request.token_in.address.clone(),
+            token_out: request.token_out.address.clone(),
+            amount_in: request.amount_in.to_string(),
+            amount_out,
+            gas,
+            error,
+            latency_micros: latency.as_micros() as u64,
+            timestamp: clock::now(),
+        };
+        if let Err(error) = self.sink.record(&record) {
+            warn!(pool = request.pool, %error, "Failed to record quote in the audit log");
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, str::FromStr};
+
+    use super::*;
+    use crate::protocol::{
+        fixtures::{USDC, WETH},
+        models::BlockInfo,
+        state::{MockProtocolSim, ProtocolSim},
+    };
+
+    fn new_state() -> Box<dyn ProtocolSim> {
+        Box::new(MockProtocolSim::new())
+    }
+
+    #[test]
+    fn test_file_sink() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("quotes.jsonl");
+        let log = AuditLog::new(FileSink::open(&path).unwrap());
+        let usdc = Token::new(USDC, 6, "USDC", BigUint::from(10_000u32));
+        let weth = Token::new(WETH, 18, "WETH", BigUint::from(10_000u32));
+        let amount_in = BigUint::from(1_000u32);
+        let request = QuoteRequest {
+            idempotency_key: Some("order-1"),
+            pool: "0x01",
+            protocol_system: "uniswap_v2",
+            amount_in: &amount_in,
+            token_in: &usdc,
+            token_out: &weth,
+        };
+        let block = BlockInfo { number: 7, hash: Bytes::from_str("0x07").unwrap(), timestamp: 0 };
+
+        let result = log
+            .quote(request, || {
+                Ok(GetAmountOutResult::new(BigUint::from(5u8), BigUint::from(100u8), new_state())
+                    .with_block(block.clone()))
+            })
+            .unwrap();
+        assert_eq!(result.amount, BigUint::from(5u8));
+        let failed = log.quote(QuoteRequest { idempotency_key: None, ..request }, || {
+            Err(SimulationError::InvalidInput("Unknown token".to_string(), None))
+        });
+        assert!(failed.is_err());
+        // Dropping the log flushes the records
+        drop(log);
+
+        let records: Vec<QuoteRecord> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].idempotency_key.as_deref(), Some("order-1"));
+        assert_eq!(records[0].block_number, Some(7));
+        assert_eq!(records[0].block_hash, Some(block.hash));
+        assert_eq!(records[0].token_in, usdc.address);
+        assert_eq!(
+            (records[0].amount_in.as_str(), records[0].amount_out.as_deref()),
+            ("1000", Some("5"))
+        );
+        assert_eq!(records[0].gas.as_deref(), Some("100"));
+        assert_eq!(records[1].idempotency_key, None);
+        assert_eq!(records[1].amount_out, None);
+        assert!(records[1]
+            .error
+            .as_ref()
+            .is_some_and(|error| error.contains("Unknown token")));
+    }
+
+    #[test]
+    fn test_file_sink_flush() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("quotes.jsonl");
+        let log = AuditLog::new(
+            FileSink::open(&path)
+                .unwrap()
+                .with_flush_interval(Duration::from_secs(3600)),
+        );
+        let usdc = Token::new(USDC, 6, "USDC", BigUint::from(10_000u32));
+        let weth = Token::new(WETH, 18, "WETH", BigUint::from(10_000u32));
+        let amount_in = BigUint::from(1_000u32);
+        let request = QuoteRequest {
+            idempotency_key: None,
+            pool: "0x01",
+            protocol_system: "uniswap_v2",
+            amount_in: &amount_in,
+            token_in: &usdc,
+            token_out: &weth,
+        };
+
+        let _ = log.quote(request, || {
+            Err(SimulationError::InvalidInput("Unknown token".to_string(), None))
+        });
+
+        // The record is buffered until the interval elapsed
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .is_empty());
+        log.flush().unwrap();
+        assert_eq!(
+            fs::read_to_string(&path)
+                .unwrap()
+                .lines()
+                .count(),
+            1
+        );
+    }
+}
@@ -0,0 +1,197 @@
+//! Quote breakdowns
+//!
+//! A quote returns less than the input is worth at the pool's mid price. `QuoteBreakdown` splits
+//! the difference into what end users are usually shown: the fee paid to the pool, the price
+//! impact of the trade and the gas to execute it, all in the output token. The split is computed
+//! from `ProtocolSim::spot_price` of the state quoted and the fee rate of the quote, see
+//! `GetAmountOutResult::fee`, or `ProtocolSim::fee` if the quote has none. It is the same for
+//! every protocol:
+//!
+//! - the mid amount is the input converted at the spot price
+//! - the fee is the fee rate applied to the mid amount, rounded to the nearest unit
+//! - the price impact is what the quote falls short of the mid amount after the fee
+//!
+//! States whose spot price already includes the fee have their fee counted twice, which shows as
+//! a smaller price impact.
+use num_bigint::BigUint;
+use num_traits::{ToPrimitive, Zero};
+
+use crate::{
+    models::Token,
+    protocol::{
+        errors::SimulationError,
+        gas::{gas_cost, GasPrice},
+        models::GetAmountOutResult,
+        price::{f64_ratio, Price},
+        state::ProtocolSim,
+    },
+};
+
+/// What a quote loses compared to the mid price, see module docs. Amounts are in the smallest
+/// unit of the output token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuoteBreakdown {
+    /// The input converted at the spot price, before fees
+    pub mid_amount: BigUint,
+    /// The amount out of the quote
+    pub amount: BigUint,
+    /// Fee paid to the pool
+    pub fee: BigUint,
+    /// Shortfall of the amount out from the mid amount after the fee, zero if the quote is better
+    pub price_impact: BigUint,
+    /// `price_impact` as a share of `mid_amount`
+    pub price_impact_rate: f64,
+    /// Cost of the quote's gas, if a gas price is given, see `with_gas_cost`
+    pub gas_cost: Option<BigUint>,
+}
+
+impl QuoteBreakdown {
+    /// Breaks down `result`, the quote of `amount_in` of `token_in` for `token_out` on `state`.
+    /// `state` is the state before the swap, not the quote's `new_state`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the spot price of the state can't be computed or isn't a finite, non-negative
+    /// number.
+    pub fn new(
+        state: &dyn ProtocolSim,
+        amount_in: &BigUint,
+        token_in: &Token,
+        token_out: &Token,
+        result: &GetAmountOutResult,
+    ) -> Result<Self, SimulationError> {
+        let spot_price = state.spot_price(token_in, token_out)?;
+        let (numerator, denominator) = Price::from_f64(token_in, token_out, spot_price)
+            .ok_or_else(|| SimulationError::FatalError(format!("Invalid spot price {spot_price}")))?
+            .to_wei_per_wei();
+        let mid_amount = amount_in * numerator / denominator;
+        let fee_rate = result
+            .fee
+            .unwrap_or_else(|| state.fee());
+        let fee = match f64_ratio(fee_rate) {
+            // (2 * mid * numerator + denominator) / (2 * denominator) rounds halves up
+            Some((numerator, denominator)) => {
+                (&mid_amount * numerator * 2u8 + &denominator) / (denominator * 2u8)
+            }
+            None => BigUint::zero(),
+        };
+        let after_fee = if mid_amount > fee { &mid_amount - &fee } else { BigUint::zero() };
+        let price_impact =
+            if after_fee > result.amount { after_fee - &result.amount } else { BigUint::zero() };
+        let price_impact_rate = if mid_amount.is_zero() {
+            0.0
+        } else {
+            price_impact
+                .to_f64()
+                .unwrap_or(f64::INFINITY) /
+                mid_amount
+                    .to_f64()
+                    .unwrap_or(f64::INFINITY)
+        };
+        Ok(Self {
+            mid_amount,
+            amount: result.amount.clone(),
+            fee,
+            price_impact,
+            price_impact_rate,
+            gas_cost: None,
+        })
+    }
+
+    /// Adds the cost of `gas` at `gas_price`, see `gas::gas_cost`.
+    ///
+    /// # Arguments
+    ///
+    /// * `gas` - The gas of the quote.
+    /// * `gas_price` - The gas price paid.
+    /// * `token_out` - The output token of the quote.
+    /// * `native_price` - Price of the chain's native token in `token_out`, in whole units of both.
+    pub fn with_gas_cost(
+        mut self,
+        gas: &BigUint,
+        gas_price: &GasPrice,
+        token_out: &Token,
+        native_price: f64,
+    ) -> Self {
+        self.gas_cost = Some(gas_cost(gas, gas_price, token_out, native_price));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::state::MockProtocolSim;
+
+    #[test]
+    fn test_quote_breakdown() {
+        let weth = Token::new(
+            "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2",
+            18,
+            "WETH",
+            BigUint::from(10_000u32),
+        );
+        let usdc = Token::new(
+            "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48",
+            6,
+            "USDC",
+            BigUint::from(10_000u32),
+        );
+        let mut state = MockProtocolSim::new();
+        state
+            .expect_spot_price()
+            .returning(|_, _| Ok(2000.0));
+        state.expect_fee().return_const(0.003);
+        // 1 WETH sold for 1990 USDC using 100k gas
+        let amount_in = BigUint::from(10u64.pow(18));
+        let result = GetAmountOutResult::new(
+            BigUint::from(1_990_000_000u64),
+            BigUint::from(100_000u32),
+            Box::new(MockProtocolSim::new()),
+        );
+
+        let breakdown = QuoteBreakdown::new(&state, &amount_in, &weth, &usdc, &result)
+            .unwrap()
+            // 10 gwei and 2000 USDC per ETH
+            .with_gas_cost(&result.gas, &GasPrice::new(10_000_000_000, 0), &usdc, 2000.0);
+
+        assert_eq!(breakdown.mid_amount, BigUint::from(2_000_000_000u64));
+        assert_eq!(breakdown.fee, BigUint::from(6_000_000u64));
+        assert_eq!(breakdown.price_impact, BigUint::from(4_000_000u64));
+        assert_eq!(breakdown.price_impact_rate, 0.002);
+        assert_eq!(breakdown.gas_cost, Some(BigUint::from(2_000_000u64)));
+    }
+
+    #[test]
+    fn test_quote_breakdown_fee_of_quote() {
+        let weth = Token::new(
+            "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2",
+            18,
+            "WETH",
+            BigUint::from(10_000u32),
+        );
+        let usdc = Token::new(
+            "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48",
+            6,
+            "USDC",
+            BigUint::from(10_000u32),
+        );
+        // No fee expectation: the state's fee must not be used
+        let mut state = MockProtocolSim::new();
+        state
+            .expect_spot_price()
+            .returning(|_, _| Ok(2000.0));
+        let amount_in = BigUint::from(10u64.pow(18));
+        let result = GetAmountOutResult::new(
+            BigUint::from(1_990_000_000u64),
+            BigUint::from(100_000u32),
+            Box::new(MockProtocolSim::new()),
+        )
+        .with_fee(0.001);
+
+        let breakdown = QuoteBreakdown::new(&state, &amount_in, &weth, &usdc, &result).unwrap();
+
+        assert_eq!(breakdown.fee, BigUint::from(2_000_000u64));
+        assert_eq!(breakdown.price_impact, BigUint::from(8_000_000u64));
+    }
+}
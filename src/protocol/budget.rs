@@ -0,0 +1,253 @@
+//! Per-protocol simulation budgets
+//!
+//! Simulating a block's worth of pools has to finish before the next block arrives. A few
+//! pathological pools, e.g. VM pools with expensive adapters, can use up all of that time on their
+//! own. A `SimulationBudget` caps the time and gas spent on simulations per block, overall and per
+//! protocol system as a share of the total, e.g. VM Curve pools at most 30% of the budget.
+//!
+//! Each block starts a fresh `BlockBudget` from the budget. Callers check `BlockBudget::allows`
+//! before simulating a pool and `BlockBudget::record` what the simulation used afterwards. Once a
+//! protocol used up its share, its remaining pools are skipped for the block and counted in the
+//! `BudgetReport`. A simulation that is already running is never interrupted, so a protocol can
+//! overshoot its share by at most one simulation.
+//!
+//! `PoolRegistry::with_budget` enforces a budget on `PoolRegistry::compute_all_spot_prices`.
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use tracing::warn;
+
+/// Limits on the simulations run per block.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SimulationBudget {
+    /// Total time per block, unlimited if `None`
+    time: Option<Duration>,
+    /// Total gas per block, unlimited if `None`
+    gas: Option<u64>,
+    /// Share of the totals each protocol system may use, between 0 and 1
+    shares: HashMap<String, f64>,
+}
+
+impl SimulationBudget {
+    /// A budget without limits.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Caps the total time spent on simulations per block.
+    pub fn with_time(mut self, time: Duration) -> Self {
+        self.time = Some(time);
+        self
+    }
+
+    /// Caps the total gas used by simulations per block.
+    pub fn with_gas(mut self, gas: u64) -> Self {
+        self.gas = Some(gas);
+        self
+    }
+
+    /// Caps the simulations of a protocol system to `share` of the totals. Shares are clamped to
+    /// `0..=1` and don't need to add up to 1; protocols without a share may use the whole budget.
+    pub fn with_share(mut self, protocol_system: &str, share: f64) -> Self {
+        self.shares
+            .insert(protocol_system.to_string(), share.clamp(0.0, 1.0));
+        self
+    }
+
+    /// Starts tracking the simulations of a block.
+    pub fn start_block(&self, block: u64) -> BlockBudget {
+        BlockBudget { budget: self.clone(), report: BudgetReport { block, ..Default::default() } }
+    }
+
+    fn time_limit(&self, protocol_system: &str) -> Option<Duration> {
+        let time = self.time?;
+        Some(match self.shares.get(protocol_system) {
+            Some(share) => time.mul_f64(*share),
+            None => time,
+        })
+    }
+
+    fn gas_limit(&self, protocol_system: &str) -> Option<u64> {
+        let gas = self.gas?;
+        Some(match self.shares.get(protocol_system) {
+            Some(share) => (gas as f64 * share) as u64,
+            None => gas,
+        })
+    }
+}
+
+/// What the simulations of a protocol system used in a block.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProtocolUsage {
+    /// Number of simulations run
+    pub simulations: usize,
+    /// Number of simulations skipped because the budget was used up
+    pub skipped: usize,
+    pub time: Duration,
+    pub gas: u64,
+    /// Whether the protocol used up its share of the budget
+    pub exhausted: bool,
+}
+
+/// Usage of a `SimulationBudget` in a block.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BudgetReport {
+    pub block: u64,
+    /// Usage by protocol system, sorted by name
+    pub protocols: BTreeMap<String, ProtocolUsage>,
+}
+
+impl BudgetReport {
+    /// Total time spent on simulations.
+    pub fn time(&self) -> Duration {
+        self.protocols
+            .values()
+            .map(|usage| usage.time)
+            .sum()
+    }
+
+    /// Total gas used by simulations.
+    pub fn gas(&self) -> u64 {
+        self.protocols
+            .values()
+            .map(|usage| usage.gas)
+            .sum()
+    }
+
+    /// Total number of skipped simulations.
+    pub fn skipped(&self) -> usize {
+        self.protocols
+            .values()
+            .map(|usage| usage.skipped)
+            .sum()
+    }
+}
+
+/// Tracks the simulations of a block against a `SimulationBudget`.
+#[derive(Debug, Clone)]
+pub struct BlockBudget {
+    budget: SimulationBudget,
+    report: BudgetReport,
+}
+
+impl BlockBudget {
+    /// Whether a simulation of `protocol_system` may run. Counts it as skipped if not.
+    pub fn allows(&mut self, protocol_system: &str) -> bool {
+        let exhausted = self.is_exhausted(protocol_system);
+        let usage = self
+            .report
+            .protocols
+            .entry(protocol_system.to_string())
+            .or_default();
+        if exhausted {
+            if !usage.exhausted {
+                warn!(
+                    block = self.report.block,
+                    protocol_system, "Simulation budget exhausted, skipping remaining pools"
+                );
+            }
+            usage.exhausted = true;
+            usage.skipped += 1;
+        }
+        !exhausted
+    }
+
+    /// Records a simulation of `protocol_system`.
+    pub fn record(&mut self, protocol_system: &str, time: Duration, gas: u64) {
+        let usage = self
+            .report
+            .protocols
+            .entry(protocol_system.to_string())
+            .or_default();
+        usage.simulations += 1;
+        usage.time += time;
+        usage.gas = usage.gas.saturating_add(gas);
+    }
+
+    /// Usage of the budget so far.
+    pub fn report(&self) -> &BudgetReport {
+        &self.report
+    }
+
+    /// Ends the block and returns the usage of the budget.
+    pub fn finish(self) -> BudgetReport {
+        self.report
+    }
+
+    fn is_exhausted(&self, protocol_system: &str) -> bool {
+        let usage = self
+            .report
+            .protocols
+            .get(protocol_system);
+        let (time, gas) = usage.map_or((Duration::ZERO, 0), |usage| (usage.time, usage.gas));
+        let over_share = self
+            .budget
+            .time_limit(protocol_system)
+            .is_some_and(|limit| time >= limit) ||
+            self.budget
+                .gas_limit(protocol_system)
+                .is_some_and(|limit| gas >= limit);
+        let over_total = self
+            .budget
+            .time
+            .is_some_and(|limit| self.report.time() >= limit) ||
+            self.budget
+                .gas
+                .is_some_and(|limit| self.report.gas() >= limit);
+        over_share || over_total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_budget() {
+        let budget = SimulationBudget::new()
+            .with_time(Duration::from_millis(100))
+            .with_gas(1_000_000)
+            .with_share("vm:curve", 0.3);
+        let mut block = budget.start_block(7);
+
+        assert!(block.allows("vm:curve"));
+        block.record("vm:curve", Duration::from_millis(20), 100_000);
+        assert!(block.allows("vm:curve"));
+        block.record("vm:curve", Duration::from_millis(20), 100_000);
+        // 40ms is over the 30ms share of vm:curve, the other protocols may continue
+        assert!(!block.allows("vm:curve"));
+        assert!(!block.allows("vm:curve"));
+        assert!(block.allows("uniswap_v2"));
+        block.record("uniswap_v2", Duration::from_millis(1), 800_000);
+        // The total gas is used up
+        assert!(!block.allows("uniswap_v2"));
+
+        let report = block.finish();
+        assert_eq!(report.block, 7);
+        assert_eq!(report.time(), Duration::from_millis(41));
+        assert_eq!(report.gas(), 1_000_000);
+        assert_eq!(report.skipped(), 3);
+        assert_eq!(
+            report.protocols["vm:curve"],
+            ProtocolUsage {
+                simulations: 2,
+                skipped: 2,
+                time: Duration::from_millis(40),
+                gas: 200_000,
+                exhausted: true
+            }
+        );
+        assert!(report.protocols["uniswap_v2"].exhausted);
+    }
+
+    #[test]
+    fn test_unlimited_budget() {
+        let mut block = SimulationBudget::new().start_block(1);
+        block.record("vm:curve", Duration::from_secs(60), u64::MAX);
+
+        assert!(block.allows("vm:curve"));
+        assert_eq!(block.report().skipped(), 0);
+    }
+}
@@ -0,0 +1,147 @@
+//! Time source for time dependent quoting
+//!
+//! Everything that depends on the current time, like quote expiries or staleness checks, reads it
+//! through `now`. It is the system time by default; tests and backtests control it with a
+//! `MockClock`, so quotes replayed from history see the time of the replayed blocks. Blocks are
+//! stamped with their own timestamp, taken from the stream's deltas, and only fall back to `now`
+//! for messages without deltas. States that follow the chain's time, like TWAMM order execution,
+//! are moved with the block timestamps too, see `ProtocolSim::set_block_timestamp`.
+//!
+//! A clock is either installed process-wide with `set_clock`, or scoped to a closure or a future
+//! with `with_clock` and `scope_clock`, which take precedence. Scoped clocks let backtests running
+//! in parallel each see their own time. Threads spawned within a scope don't inherit its clock.
+use std::{
+    fmt::Debug,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    static ref CLOCK: RwLock<Arc<dyn Clock>> = RwLock::new(Arc::new(SystemClock));
+}
+
+tokio::task_local! {
+    /// Clock of the current scope, see `with_clock` and `scope_clock`
+    static SCOPED_CLOCK: Arc<dyn Clock>;
+}
+
+/// A source of the current time.
+pub trait Clock: Debug + Send + Sync {
+    /// The current time, in seconds since the unix epoch
+    fn now(&self) -> u64;
+}
+
+/// The system time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time.
+#[derive(Debug, Clone, Default)]
+pub struct MockClock {
+    now: Arc<AtomicU64>,
+}
+
+impl MockClock {
+    pub fn new(now: u64) -> Self {
+        Self { now: Arc::new(AtomicU64::new(now)) }
+    }
+
+    pub fn set(&self, now: u64) {
+        self.now.store(now, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, seconds: u64) {
+        self.now
+            .fetch_add(seconds, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+/// Replaces the process-wide clock.
+pub fn set_clock(clock: Arc<dyn Clock>) {
+    *CLOCK.write().unwrap() = clock;
+}
+
+/// Restores the system time as the process-wide clock.
+pub fn reset_clock() {
+    set_clock(Arc::new(SystemClock));
+}
+
+/// Runs `f` with `clock` as the clock of the current thread.
+pub fn with_clock<T>(clock: Arc<dyn Clock>, f: impl FnOnce() -> T) -> T {
+    SCOPED_CLOCK.sync_scope(clock, f)
+}
+
+/// Runs `future` with `clock` as its clock, wherever it is polled.
+pub async fn scope_clock<F: Future>(clock: Arc<dyn Clock>, future: F) -> F::Output {
+    SCOPED_CLOCK.scope(clock, future).await
+}
+
+/// The current time, in seconds since the unix epoch: the time of the scoped clock if there is
+/// one, else of the process-wide clock.
+pub fn now() -> u64 {
+    SCOPED_CLOCK
+        .try_with(|clock| clock.now())
+        .unwrap_or_else(|_| CLOCK.read().unwrap().now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new(1_700_000_000);
+        let shared = clock.clone();
+
+        clock.advance(12);
+        assert_eq!(shared.now(), 1_700_000_012);
+
+        shared.set(1_600_000_000);
+        assert_eq!(clock.now(), 1_600_000_000);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_scoped_clocks() {
+        let tasks: Vec<_> = [100, 200]
+            .into_iter()
+            .map(|start| {
+                let clock = MockClock::new(start);
+                tokio::spawn(scope_clock(Arc::new(clock.clone()), async move {
+                    tokio::task::yield_now().await;
+                    clock.advance(1);
+                    now()
+                }))
+            })
+            .collect();
+        let mut times = Vec::new();
+        for task in tasks {
+            times.push(task.await.unwrap());
+        }
+
+        assert_eq!(times, vec![101, 201]);
+        assert_eq!(with_clock(Arc::new(MockClock::new(300)), now), 300);
+        // Outside of a scope, the process-wide clock is used
+        assert!(now() > 300);
+    }
+}
@@ -47,6 +47,11 @@ impl From<SimulationError> for InvalidSnapshotError {
 ///   network problem.
 /// - `InvalidInput`: Indicates that the simulation has failed due to bad input parameters.
 /// - `FatalError`: There is a bug with this pool or protocol - do not attempt simulation again.
+/// - `StaleState`: The state is older than the configured maximum staleness, e.g. because the
+///   stream stalled. Retrying once the state caught up may succeed.
+/// - `PoolNotInitialized`: The pool has no state to swap against yet, e.g. because it was created
+///   in the current block and its liquidity isn't added yet. Retrying on a later block may succeed,
+///   unlike for pools that are broken.
 #[derive(Error, Debug)]
 pub enum SimulationError {
     #[error("Fatal error: {0}")]
@@ -55,6 +60,10 @@ pub enum SimulationError {
     InvalidInput(String, Option<GetAmountOutResult>),
     #[error("Recoverable error: {0}")]
     RecoverableError(String),
+    #[error("Stale state: {0}")]
+    StaleState(String),
+    #[error("Pool not initialized: {0}")]
+    PoolNotInitialized(String),
 }
 
 impl<T> From<SimulationError> for TransitionError<T> {
@@ -0,0 +1,30 @@
+//! Tokens and pools shared by the tests of the protocol modules
+use std::{collections::HashMap, str::FromStr};
+
+use chrono::NaiveDateTime;
+use num_bigint::BigUint;
+use tycho_core::{models::Chain, Bytes};
+
+use crate::{models::Token, protocol::models::ProtocolComponent};
+
+pub(crate) const USDC: &str = "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48";
+pub(crate) const WETH: &str = "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2";
+pub(crate) const DAI: &str = "0x6b175474e89094c44da98b954eedeac495271d0f";
+
+/// A pool of `protocol_system` trading `tokens`, all with 18 decimals.
+pub(crate) fn component(id: &str, protocol_system: &str, tokens: &[&str]) -> ProtocolComponent {
+    ProtocolComponent::new(
+        Bytes::from_str(id).unwrap(),
+        protocol_system.to_string(),
+        "pool".to_string(),
+        Chain::Ethereum,
+        tokens
+            .iter()
+            .map(|address| Token::new(address, 18, "T", BigUint::from(10_000u32)))
+            .collect(),
+        Vec::new(),
+        HashMap::new(),
+        Bytes::default(),
+        NaiveDateTime::default(),
+    )
+}
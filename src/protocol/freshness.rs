@@ -0,0 +1,471 @@
+//! Stale state detection
+//!
+//! Protocol states are only as fresh as the last block the stream delivered. If the stream
+//! stalls, quoting from the held states silently serves outdated prices. `StateStore` keeps the
+//! states of a stream together with the block they are valid at, attaches that block to every
+//! quote and refuses to quote once the states fall more than `max_staleness` blocks behind the
+//! chain.
+//!
+//! The store also records the block each state was last touched in, so pipelines can reprice
+//! only the pools changed by the latest block, see `StateStore::changed_pools`.
+//!
+//! While the stream lags behind the chain, the states miss the effect of swaps already sent, in
+//! particular our own. Such swaps can be registered as `PendingFill`s: as long as the stream lags,
+//! `StateStore::get_amount_out_optimistic` simulates them on the pool before quoting, and marks
+//! the quote as optimistic. Fills are dropped once the stream passes the chain head they were
+//! sent at, from then on the states include them if they were executed. The chain head is fed
+//! with `StateStore::observe_chain_head`, e.g. from a subscription to the node's block headers.
+use std::collections::HashMap;
+
+use num_bigint::BigUint;
+use tycho_core::Bytes;
+
+use crate::{
+    models::Token,
+    protocol::{
+        clock,
+        errors::SimulationError,
+        models::{BlockInfo, BlockUpdate, GetAmountOutResult},
+        state::ProtocolSim,
+    },
+};
+
+/// A swap of our own that was sent but may not be included in the states yet, see module docs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingFill {
+    /// Hash of the transaction executing the swap
+    pub tx_hash: Bytes,
+    pub pool: String,
+    pub amount_in: BigUint,
+    pub token_in: Token,
+    pub token_out: Token,
+}
+
+/// A quote of `StateStore::get_amount_out_optimistic`.
+#[derive(Debug)]
+pub struct OptimisticQuote {
+    pub result: GetAmountOutResult,
+    /// Number of pending fills simulated on the pool before quoting. The quote is optimistic if
+    /// any were: it assumes they execute as simulated.
+    pub fills_applied: usize,
+}
+
+impl OptimisticQuote {
+    pub fn is_optimistic(&self) -> bool {
+        self.fills_applied > 0
+    }
+}
+
+/// Holds the latest protocol states of a stream and checks their freshness before quoting.
+#[derive(Debug, Default)]
+pub struct StateStore {
+    states: HashMap<String, Box<dyn ProtocolSim>>,
+    /// Last block each state was touched by an update
+    changed_at: HashMap<String, u64>,
+    /// Block all states are valid at
+    block: Option<BlockInfo>,
+    /// Latest block number known from an external source, e.g. a node
+    chain_head: Option<u64>,
+    /// Maximum number of blocks the states may lag behind the chain. Unlimited if `None`.
+    max_staleness: Option<u64>,
+    /// Expected block time, in seconds. Used to estimate the chain head from the wall clock.
+    block_time: u64,
+    /// Our own swaps not yet included in the states, with the chain head they were sent at
+    pending_fills: Vec<(PendingFill, u64)>,
+}
+
+impl StateStore {
+    /// Creates a store.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_staleness` - Maximum number of blocks the states may lag behind, `None` disables the
+    ///   check.
+    /// * `block_time` - Expected block time of the chain, in seconds.
+    pub fn new(max_staleness: Option<u64>, block_time: u64) -> Self {
+        Self { max_staleness, block_time, ..Default::default() }
+    }
+
+    /// Applies a block update of the stream.
+    pub fn apply(&mut self, update: BlockUpdate) {
+        for id in update.removed_pairs.keys() {
+            self.states.remove(id);
+            self.changed_at.remove(id);
+        }
+        for id in update.states.keys() {
+            self.changed_at
+                .insert(id.clone(), update.block_number);
+        }
+        self.states.extend(update.states);
+        self.block = Some(
+            update
+                .block
+                .unwrap_or_else(|| BlockInfo { number: update.block_number, ..Default::default() }),
+        );
+        // Fills are included in a block after the head they were sent at, if at all
+        self.pending_fills
+            .retain(|(_, sent_at)| *sent_at >= update.block_number);
+    }
+
+    /// Registers a swap of our own that was just sent, see `StateStore::get_amount_out_optimistic`.
+    /// It is dropped once the stream passes the current chain head.
+    pub fn add_pending_fill(&mut self, fill: PendingFill) {
+        let head = self
+            .chain_head
+            .into_iter()
+            .chain(
+                self.block
+                    .as_ref()
+                    .map(|block| block.number),
+            )
+            .max()
+            .unwrap_or_default();
+        self.pending_fills.push((fill, head));
+    }
+
+    /// Drops a pending fill, e.g. because its transaction was replaced. Returns whether it was
+    /// pending.
+    pub fn remove_pending_fill(&mut self, tx_hash: &Bytes) -> bool {
+        let len = self.pending_fills.len();
+        self.pending_fills
+            .retain(|(fill, _)| &fill.tx_hash != tx_hash);
+        self.pending_fills.len() != len
+    }
+
+    /// Returns the pending fills, in the order they were sent.
+    pub fn pending_fills(&self) -> impl Iterator<Item = &PendingFill> {
+        self.pending_fills
+            .iter()
+            .map(|(fill, _)| fill)
+    }
+
+    /// Records the latest block number seen on chain, so that a stalled stream is detected
+    /// without waiting for the wall clock estimate.
+    pub fn observe_chain_head(&mut self, number: u64) {
+        self.chain_head = Some(
+            self.chain_head
+                .map_or(number, |head| head.max(number)),
+        );
+    }
+
+    /// Returns the block the states are valid at.
+    pub fn block(&self) -> Option<&BlockInfo> {
+        self.block.as_ref()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&dyn ProtocolSim> {
+        self.states.get(id).map(Box::as_ref)
+    }
+
+    /// Returns the pools touched by the update of `block`: their attributes or balances changed,
+    /// or a contract they depend on was updated.
+    ///
+    /// Only pools whose latest update happened in `block` are returned, so this is meant to be
+    /// called for the current block, to reprice only what changed.
+    pub fn changed_pools(&self, block: u64) -> impl Iterator<Item = &str> {
+        self.changed_at
+            .iter()
+            .filter(move |(_, changed)| **changed == block)
+            .map(|(id, _)| id.as_str())
+    }
+
+    /// Returns the last block the state of a pool was touched by an update.
+    pub fn last_changed(&self, id: &str) -> Option<u64> {
+        self.changed_at.get(id).copied()
+    }
+
+    /// Returns the ids of all held states.
+    pub fn ids(&self) -> impl Iterator<Item = &String> {
+        self.states.keys()
+    }
+
+    /// Returns the number of blocks the states lag behind the chain, estimated from the observed
+    /// chain head and the time elapsed since the block of the states.
+    pub fn staleness(&self) -> Option<u64> {
+        self.staleness_at(clock::now())
+    }
+
+    fn staleness_at(&self, now: u64) -> Option<u64> {
+        let block = self.block.as_ref()?;
+        let by_head = self
+            .chain_head
+            .map_or(0, |head| head.saturating_sub(block.number));
+        let by_time = if self.block_time > 0 && block.timestamp > 0 {
+            now.saturating_sub(block.timestamp) / self.block_time
+        } else {
+            0
+        };
+        Some(by_head.max(by_time))
+    }
+
+    /// Returns the current block if the states are fresh enough to quote from.
+    ///
+    /// # Errors
+    ///
+    /// * `StaleState` - no block was applied yet, or the states lag more than `max_staleness`
+    ///   blocks behind.
+    pub fn check_fresh(&self) -> Result<&BlockInfo, SimulationError> {
+        self.check_fresh_at(clock::now())
+    }
+
+    fn check_fresh_at(&self, now: u64) -> Result<&BlockInfo, SimulationError> {
+        let block = self
+            .block
+            .as_ref()
+            .ok_or_else(|| SimulationError::StaleState("No block received yet".to_string()))?;
+        if let (Some(max_staleness), Some(staleness)) = (self.max_staleness, self.staleness_at(now))
+        {
+            if staleness > max_staleness {
+                return Err(SimulationError::StaleState(format!(
+                    "State at block {} is {} blocks behind, maximum is {}",
+                    block.number, staleness, max_staleness
+                )));
+            }
+        }
+        Ok(block)
+    }
+
+    /// Quotes a swap on a pool, after checking the states are fresh. The result carries the block
+    /// of the state it was computed on.
+    pub fn get_amount_out(
+        &self,
+        id: &str,
+        amount_in: BigUint,
+        token_in: &Token,
+        token_out: &Token,
+    ) -> Result<GetAmountOutResult, SimulationError> {
+        let block = self.check_fresh()?.clone();
+        let state = self
+            .get(id)
+            .ok_or_else(|| SimulationError::InvalidInput(format!("Unknown pool {}", id), None))?;
+        Ok(state
+            .get_amount_out(amount_in, token_in, token_out)?
+            .with_block(block))
+    }
+
+    /// Quotes a swap on a pool like `StateStore::get_amount_out`, but if the stream lags behind the
+    /// chain, the pending fills of the pool are simulated on it first, in the order they were
+    /// sent. The quote tells whether any were, see `OptimisticQuote`.
+    ///
+    /// # Errors
+    ///
+    /// Fails like `StateStore::get_amount_out`, or if a pending fill can't be simulated.
+    pub fn get_amount_out_optimistic(
+        &self,
+        id: &str,
+        amount_in: BigUint,
+        token_in: &Token,
+        token_out: &Token,
+    ) -> Result<OptimisticQuote, SimulationError> {
+        self.get_amount_out_optimistic_at(id, amount_in, token_in, token_out, clock::now())
+    }
+
+    fn get_amount_out_optimistic_at(
+        &self,
+        id: &str,
+        amount_in: BigUint,
+        token_in: &Token,
+        token_out: &Token,
+        now: u64,
+    ) -> Result<OptimisticQuote, SimulationError> {
+        let block = self.check_fresh_at(now)?.clone();
+        let state = self
+            .get(id)
+            .ok_or_else(|| SimulationError::InvalidInput(format!("Unknown pool {}", id), None))?;
+        let lagging = self
+            .staleness_at(now)
+            .is_some_and(|staleness| staleness > 0);
+        let mut optimistic: Option<Box<dyn ProtocolSim>> = None;
+        let mut fills_applied = 0;
+        if lagging {
+            for fill in self
+                .pending_fills()
+                .filter(|fill| fill.pool == id)
+            {
+                let current = optimistic.as_deref().unwrap_or(state);
+                optimistic = Some(
+                    current
+                        .get_amount_out(fill.amount_in.clone(), &fill.token_in, &fill.token_out)?
+                        .new_state,
+                );
+                fills_applied += 1;
+            }
+        }
+        let result = optimistic
+            .as_deref()
+            .unwrap_or(state)
+            .get_amount_out(amount_in, token_in, token_out)?
+            .with_block(block);
+        Ok(OptimisticQuote { result, fills_applied })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::protocol::{
+        clock::{with_clock, MockClock},
+        state::MockProtocolSim,
+    };
+
+    fn store(max_staleness: Option<u64>) -> StateStore {
+        let mut store = StateStore::new(max_staleness, 12);
+        let block = BlockInfo { number: 100, hash: Bytes::from(vec![1u8; 32]), timestamp: 1_000 };
+        store.apply(BlockUpdate::new(100, HashMap::new(), HashMap::new()).set_block(block));
+        store
+    }
+
+    #[test]
+    fn test_changed_pools() {
+        let pool = || Box::new(MockProtocolSim::new()) as Box<dyn ProtocolSim>;
+        let mut store = StateStore::new(None, 0);
+        store.apply(BlockUpdate::new(
+            1,
+            HashMap::from([("a".to_string(), pool()), ("b".to_string(), pool())]),
+            HashMap::new(),
+        ));
+        store.apply(BlockUpdate::new(
+            2,
+            HashMap::from([("b".to_string(), pool())]),
+            HashMap::new(),
+        ));
+
+        assert_eq!(
+            store
+                .changed_pools(2)
+                .collect::<Vec<_>>(),
+            vec!["b"]
+        );
+        assert_eq!(
+            store
+                .changed_pools(1)
+                .collect::<Vec<_>>(),
+            vec!["a"]
+        );
+        assert_eq!(store.last_changed("a"), Some(1));
+        assert_eq!(store.last_changed("c"), None);
+    }
+
+    #[test]
+    fn test_fresh_state() {
+        let store = store(Some(2));
+
+        assert_eq!(
+            store
+                .check_fresh_at(1_024)
+                .unwrap()
+                .number,
+            100
+        );
+        assert_eq!(store.staleness_at(1_024), Some(2));
+    }
+
+    #[test]
+    fn test_stale_by_time() {
+        let store = store(Some(2));
+
+        assert!(matches!(store.check_fresh_at(1_036), Err(SimulationError::StaleState(_))));
+    }
+
+    #[test]
+    fn test_stale_by_chain_head() {
+        let mut store = store(Some(2));
+        store.observe_chain_head(103);
+
+        assert!(matches!(store.check_fresh_at(1_000), Err(SimulationError::StaleState(_))));
+    }
+
+    /// A pool quoting `amount_out`, and one less after each swap
+    fn pool(amount_out: u32) -> Box<dyn ProtocolSim> {
+        let mut mock = MockProtocolSim::new();
+        mock.expect_get_amount_out()
+            .returning(move |_, _, _| {
+                Ok(GetAmountOutResult::new(
+                    BigUint::from(amount_out),
+                    BigUint::ZERO,
+                    pool(amount_out - 1),
+                ))
+            });
+        Box::new(mock)
+    }
+
+    #[test]
+    fn test_optimistic_fills() {
+        let mut store = store(None);
+        store.apply(BlockUpdate::new(
+            100,
+            HashMap::from([("a".to_string(), pool(10)), ("b".to_string(), pool(10))]),
+            HashMap::new(),
+        ));
+        let usdc = Token::new(
+            "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48",
+            6,
+            "USDC",
+            BigUint::from(10_000u32),
+        );
+        let weth = Token::new(
+            "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2",
+            18,
+            "WETH",
+            BigUint::from(10_000u32),
+        );
+        let fill = |tx: u8| PendingFill {
+            tx_hash: Bytes::from(vec![tx]),
+            pool: "a".to_string(),
+            amount_in: BigUint::from(1u8),
+            token_in: usdc.clone(),
+            token_out: weth.clone(),
+        };
+        let quote = |store: &StateStore, id: &str| {
+            let quote = store
+                .get_amount_out_optimistic_at(id, BigUint::from(1u8), &usdc, &weth, 0)
+                .unwrap();
+            (quote.result.amount, quote.fills_applied)
+        };
+        store.observe_chain_head(102);
+        store.add_pending_fill(fill(1));
+        store.add_pending_fill(fill(2));
+
+        assert_eq!(quote(&store, "a"), (BigUint::from(8u8), 2));
+        assert_eq!(quote(&store, "b"), (BigUint::from(10u8), 0));
+        assert!(store.remove_pending_fill(&Bytes::from(vec![2u8])));
+        assert!(!store.remove_pending_fill(&Bytes::from(vec![2u8])));
+        assert_eq!(quote(&store, "a"), (BigUint::from(9u8), 1));
+
+        // Caught up with the chain: the states are authoritative
+        store.apply(BlockUpdate::new(102, HashMap::new(), HashMap::new()));
+        assert_eq!(quote(&store, "a"), (BigUint::from(10u8), 0));
+        assert_eq!(store.pending_fills().count(), 1);
+
+        store.apply(BlockUpdate::new(103, HashMap::new(), HashMap::new()));
+        assert_eq!(store.pending_fills().count(), 0);
+    }
+
+    #[test]
+    fn test_staleness_unlimited() {
+        let mut store = store(None);
+        store.observe_chain_head(1_000);
+
+        assert!(store.check_fresh_at(1_000_000).is_ok());
+        assert!(matches!(
+            StateStore::new(None, 12).check_fresh_at(0),
+            Err(SimulationError::StaleState(_))
+        ));
+    }
+
+    #[test]
+    fn test_stale_with_clock() {
+        let store = store(Some(2));
+        let clock = MockClock::new(1_024);
+
+        with_clock(Arc::new(clock.clone()), || {
+            assert_eq!(store.staleness(), Some(2));
+            assert!(store.check_fresh().is_ok());
+
+            clock.advance(12);
+            assert!(matches!(store.check_fresh(), Err(SimulationError::StaleState(_))));
+        });
+    }
+}
@@ -0,0 +1,160 @@
+//! Gas adjusted quoting
+//!
+//! Quotes of different pools for the same trade consume different amounts of gas.
+//! `rank_by_net_output` converts the gas of each quote into the output token at the current gas
+//! price, see `gas_cost`, and ranks the quotes by what is left. The gas price comes from a
+//! `GasPriceOracle`; `FixedGasPrice` serves a constant, `evm::gas_oracle::FeeHistoryOracle` live
+//! estimates from a node.
+use std::cmp::Ordering;
+
+use num_bigint::BigUint;
+use num_traits::{ToPrimitive, Zero};
+
+use crate::{
+    models::Token,
+    protocol::{errors::SimulationError, models::GetAmountOutResult},
+};
+
+/// Gas price estimate, in wei per gas
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GasPrice {
+    pub base_fee: u128,
+    pub priority_fee: u128,
+}
+
+impl GasPrice {
+    pub fn new(base_fee: u128, priority_fee: u128) -> Self {
+        Self { base_fee, priority_fee }
+    }
+
+    /// Price paid per unit of gas by a transaction bidding `priority_fee`.
+    pub fn effective(&self) -> u128 {
+        self.base_fee
+            .saturating_add(self.priority_fee)
+    }
+}
+
+/// Source of the gas price used to compute net quotes.
+pub trait GasPriceOracle: Send + Sync {
+    /// Returns the current gas price estimate.
+    fn gas_price(&self) -> Result<GasPrice, SimulationError>;
+}
+
+/// An oracle returning a constant gas price.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FixedGasPrice(pub GasPrice);
+
+impl GasPriceOracle for FixedGasPrice {
+    fn gas_price(&self) -> Result<GasPrice, SimulationError> {
+        Ok(self.0)
+    }
+}
+
+/// A quote with its gas cost deducted.
+#[derive(Debug)]
+pub struct NetQuote {
+    /// Id of the quoted pool
+    pub id: String,
+    pub result: GetAmountOutResult,
+    /// Gas cost of the quote, in the output token
+    pub gas_cost: BigUint,
+    /// Amount out minus gas cost, zero if the gas costs more than the output
+    pub net_amount: BigUint,
+}
+
+/// Deducts the gas cost of each quote from its amount out and sorts the quotes by the remaining
+/// amount, best first. Ties are ordered by pool id.
+///
+/// # Arguments
+///
+/// * `quotes` - Pool ids and their quotes of the same trade.
+/// * `token_out` - The output token of the trade.
+/// * `native_price` - Price of the chain's native token in `token_out`, in whole units of both.
+/// * `oracle` - Source of the gas price.
+pub fn rank_by_net_output(
+    quotes: Vec<(String, GetAmountOutResult)>,
+    token_out: &Token,
+    native_price: f64,
+    oracle: &dyn GasPriceOracle,
+) -> Result<Vec<NetQuote>, SimulationError> {
+    let gas_price = oracle.gas_price()?;
+    let mut ranked: Vec<_> = quotes
+        .into_iter()
+        .map(|(id, result)| {
+            let gas_cost = gas_cost(&result.gas, &gas_price, token_out, native_price);
+            let net_amount =
+                if result.amount > gas_cost { &result.amount - &gas_cost } else { BigUint::zero() };
+            NetQuote { id, result, gas_cost, net_amount }
+        })
+        .collect();
+    ranked.sort_by(|a, b| match b.net_amount.cmp(&a.net_amount) {
+        Ordering::Equal => a.id.cmp(&b.id),
+        order => order,
+    });
+    Ok(ranked)
+}
+
+/// Cost of `gas` at `gas_price`, in the smallest unit of `token_out`.
+///
+/// # Arguments
+///
+/// * `gas` - The gas used.
+/// * `gas_price` - The gas price paid.
+/// * `token_out` - The token to express the cost in.
+/// * `native_price` - Price of the chain's native token in `token_out`, in whole units of both.
+pub fn gas_cost(
+    gas: &BigUint,
+    gas_price: &GasPrice,
+    token_out: &Token,
+    native_price: f64,
+) -> BigUint {
+    // token_out units per wei: native price scaled from 1e18 wei to token_out's decimals
+    let out_per_wei = native_price * 10f64.powi(token_out.decimals as i32 - 18);
+    let cost_wei = (gas * BigUint::from(gas_price.effective()))
+        .to_f64()
+        .unwrap_or(f64::INFINITY);
+    BigUint::from((cost_wei * out_per_wei).round() as u128)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::state::{MockProtocolSim, ProtocolSim};
+
+    fn quote(amount: u64, gas: u64) -> GetAmountOutResult {
+        GetAmountOutResult::new(
+            BigUint::from(amount),
+            BigUint::from(gas),
+            Box::new(MockProtocolSim::new()) as Box<dyn ProtocolSim>,
+        )
+    }
+
+    #[test]
+    fn test_rank_by_net_output() {
+        let usdc = Token::new(
+            "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48",
+            6,
+            "USDC",
+            BigUint::from(10_000u32),
+        );
+        // 10 gwei and 2000 USDC per ETH: 100k gas cost 2 USDC
+        let oracle = FixedGasPrice(GasPrice::new(9_000_000_000, 1_000_000_000));
+        let quotes = vec![
+            ("cheap".to_string(), quote(1_000_000_000, 100_000)),
+            ("expensive".to_string(), quote(1_001_000_000, 1_000_000)),
+            ("dust".to_string(), quote(1_000_000, 100_000)),
+        ];
+
+        let ranked = rank_by_net_output(quotes, &usdc, 2000.0, &oracle).unwrap();
+
+        let ids: Vec<_> = ranked
+            .iter()
+            .map(|quote| quote.id.as_str())
+            .collect();
+        assert_eq!(ids, vec!["cheap", "expensive", "dust"]);
+        assert_eq!(ranked[0].gas_cost, BigUint::from(2_000_000u64));
+        assert_eq!(ranked[0].net_amount, BigUint::from(998_000_000u64));
+        assert_eq!(ranked[1].net_amount, BigUint::from(981_000_000u64));
+        assert_eq!(ranked[2].net_amount, BigUint::zero());
+    }
+}
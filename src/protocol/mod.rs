@@ -1,3 +1,19 @@
+pub mod audit;
+pub mod breakdown;
+pub mod budget;
+pub mod clock;
 pub mod errors;
+#[cfg(test)]
+pub(crate) mod fixtures;
+pub mod freshness;
+pub mod gas;
 pub mod models;
+pub mod namespace;
+pub mod oracle;
+#[cfg(feature = "persistence")]
+pub mod persistence;
+pub mod price;
+pub mod registry;
+pub mod rounding;
 pub mod state;
+pub mod wire;
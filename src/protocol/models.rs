@@ -24,12 +24,17 @@
 //! It's worth emphasizing that although the term "pair" used in this
 //! module refers to a trading pair, it does not necessarily imply two
 //! tokens only. Some pairs might have more than two tokens.
-use std::{collections::HashMap, default::Default, future::Future};
+use std::{
+    collections::{HashMap, HashSet},
+    default::Default,
+    future::Future,
+};
 
+use alloy_primitives::B256;
 use chrono::NaiveDateTime;
 use num_bigint::BigUint;
 use tycho_client::feed::Header;
-use tycho_core::{models::Chain, Bytes};
+use tycho_core::{dto::ProtocolStateDelta, models::Chain, Bytes};
 
 use super::state::ProtocolSim;
 use crate::models::Token;
@@ -137,23 +142,116 @@ pub trait TryFromWithBlock<T> {
         Self: Sized;
 }
 
+/// The block a state or quote refers to
+///
+/// # Fields
+///
+/// * `number`: u64, the block number
+/// * `hash`: Bytes, the block hash
+/// * `timestamp`: u64, the block timestamp, in seconds since the unix epoch
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BlockInfo {
+    pub number: u64,
+    pub hash: Bytes,
+    pub timestamp: u64,
+}
+
+/// Who a quote is for, for protocols whose output depends on the executing address (e.g. fee
+/// tiers by sender).
+///
+/// # Fields
+///
+/// * `sender`: `Option<Bytes>`, the address executing the swap
+/// * `recipient`: `Option<Bytes>`, the address receiving the output tokens
+/// * `referral`: `Option<Bytes>`, the referrer of the swap
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct QuoteContext {
+    pub sender: Option<Bytes>,
+    pub recipient: Option<Bytes>,
+    pub referral: Option<Bytes>,
+}
+
+impl QuoteContext {
+    /// Quotes for swaps executed by `sender`.
+    pub fn with_sender(mut self, sender: Bytes) -> Self {
+        self.sender = Some(sender);
+        self
+    }
+
+    /// Quotes for swaps paying out to `recipient`.
+    pub fn with_recipient(mut self, recipient: Bytes) -> Self {
+        self.recipient = Some(recipient);
+        self
+    }
+
+    /// Quotes for swaps referred by `referral`.
+    pub fn with_referral(mut self, referral: Bytes) -> Self {
+        self.referral = Some(referral);
+        self
+    }
+}
+
+/// The range a quote can move within the current block, for pools whose output depends on oracles
+/// updated intra-block.
+///
+/// # Fields
+///
+/// * `low`: BigUint, the lowest amount out with the oracle prices shifted by the drift
+/// * `mid`: BigUint, the amount out at the current oracle prices
+/// * `high`: BigUint, the highest amount out with the oracle prices shifted by the drift
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuoteBounds {
+    pub low: BigUint,
+    pub mid: BigUint,
+    pub high: BigUint,
+}
+
+impl QuoteBounds {
+    /// Bounds of a quote that doesn't depend on oracles.
+    pub fn exact(amount: BigUint) -> Self {
+        QuoteBounds { low: amount.clone(), mid: amount.clone(), high: amount }
+    }
+
+    /// Whether the quote can move with the oracle prices.
+    pub fn has_drift(&self) -> bool {
+        self.low != self.high
+    }
+}
+
 /// GetAmountOutResult struct represents the result of getting the amount out of a trading pair
 ///
 /// # Fields
 ///
 /// * `amount`: BigUint, the amount of the trading pair
 /// * `gas`: BigUint, the gas of the trading pair
+/// * `block`: Option<BlockInfo>, the block of the state the quote was computed on, if known
+/// * `fee`: Option<f64>, the fee rate the swap paid, for states whose fee depends on the swap
 #[derive(Debug)]
 pub struct GetAmountOutResult {
     pub amount: BigUint,
     pub gas: BigUint,
     pub new_state: Box<dyn ProtocolSim>,
+    pub block: Option<BlockInfo>,
+    pub fee: Option<f64>,
 }
 
 impl GetAmountOutResult {
     /// Constructs a new GetAmountOutResult struct with the given amount and gas
     pub fn new(amount: BigUint, gas: BigUint, new_state: Box<dyn ProtocolSim>) -> Self {
-        GetAmountOutResult { amount, gas, new_state }
+        GetAmountOutResult { amount, gas, new_state, block: None, fee: None }
+    }
+
+    /// Attaches the fee rate the swap paid, used instead of `ProtocolSim::fee` by
+    /// `QuoteBreakdown`.
+    pub fn with_fee(mut self, fee: f64) -> Self {
+        self.fee = Some(fee);
+        self
+    }
+
+    /// Attaches the block of the state the quote was computed on.
+    pub fn with_block(mut self, block: BlockInfo) -> Self {
+        self.block = Some(block);
+        self
     }
 
     /// Aggregates the given GetAmountOutResult struct to the current one.
@@ -165,15 +263,41 @@ impl GetAmountOutResult {
     }
 }
 
+/// A change of the code of a deployed contract, e.g. the upgrade of an implementation behind a
+/// proxy.
+///
+/// The states of all pools depending on the contract are refreshed in the block of the upgrade, so
+/// they are never quoted against a mix of old and new code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeUpgrade {
+    pub address: Bytes,
+    pub previous_code_hash: B256,
+    pub code_hash: B256,
+    /// Pools depending on the contract, sorted by id
+    pub pools: Vec<String>,
+}
+
 #[derive(Debug)]
 pub struct BlockUpdate {
     pub block_number: u64,
+    /// The block of this update, if known
+    pub block: Option<BlockInfo>,
     /// The new and updated states of this block
     pub states: HashMap<String, Box<dyn ProtocolSim>>,
     /// The new pairs that were added in this block
     pub new_pairs: HashMap<String, ProtocolComponent>,
+    /// Pools created in this block. Their states are decoded before those of other new pairs, and
+    /// included in `states`
+    pub new_pools: HashSet<String>,
     /// The pairs that were removed in this block
     pub removed_pairs: HashMap<String, ProtocolComponent>,
+    /// Contracts whose code changed in this block
+    pub code_upgrades: Vec<CodeUpgrade>,
+    /// Total value locked of the pools whose TVL was reported in this block, as computed by Tycho
+    pub tvl: HashMap<String, f64>,
+    /// The attribute changes received from Tycho in this block, by pool. New pools hold all
+    /// attributes of their snapshot.
+    pub state_deltas: HashMap<String, ProtocolStateDelta>,
 }
 
 impl BlockUpdate {
@@ -182,11 +306,47 @@ impl BlockUpdate {
         states: HashMap<String, Box<dyn ProtocolSim>>,
         new_pairs: HashMap<String, ProtocolComponent>,
     ) -> Self {
-        BlockUpdate { block_number, states, new_pairs, removed_pairs: HashMap::new() }
+        BlockUpdate {
+            block_number,
+            block: None,
+            states,
+            new_pairs,
+            new_pools: HashSet::new(),
+            removed_pairs: HashMap::new(),
+            code_upgrades: Vec::new(),
+            tvl: HashMap::new(),
+            state_deltas: HashMap::new(),
+        }
+    }
+
+    pub fn set_block(mut self, block: BlockInfo) -> Self {
+        self.block_number = block.number;
+        self.block = Some(block);
+        self
     }
 
     pub fn set_removed_pairs(mut self, pairs: HashMap<String, ProtocolComponent>) -> Self {
         self.removed_pairs = pairs;
         self
     }
+
+    pub fn set_code_upgrades(mut self, upgrades: Vec<CodeUpgrade>) -> Self {
+        self.code_upgrades = upgrades;
+        self
+    }
+
+    pub fn set_new_pools(mut self, pools: HashSet<String>) -> Self {
+        self.new_pools = pools;
+        self
+    }
+
+    pub fn set_tvl(mut self, tvl: HashMap<String, f64>) -> Self {
+        self.tvl = tvl;
+        self
+    }
+
+    pub fn set_state_deltas(mut self, deltas: HashMap<String, ProtocolStateDelta>) -> Self {
+        self.state_deltas = deltas;
+        self
+    }
 }
@@ -0,0 +1,239 @@
+//! Registry namespaces
+//!
+//! A service serving several strategies often needs a different pool universe per strategy, e.g.
+//! only stablecoin pools for one and every Uniswap pool for another. Rather than opening a stream
+//! per strategy, a single stream's updates are fanned out to one `PoolRegistry` per namespace, each
+//! restricted to its universe by a `PoolFilter`:
+//!
+//! ```ignore
+//! let mut namespaces = Namespaces::new();
+//! let stables_only = PoolFilter::new().tokens(stables);
+//! namespaces.insert("stables", PoolRegistry::new().with_filter(stables_only));
+//! let uniswap = PoolFilter::new()
+//!     .protocol_system("uniswap_v2")
+//!     .protocol_system("uniswap_v3");
+//! namespaces.insert("uniswap", PoolRegistry::new().with_filter(uniswap));
+//! while let Some(update) = stream.next().await {
+//!     namespaces.apply(&update?);
+//! }
+//! ```
+//!
+//! Namespaces are isolated: each has its own metadata, history, budget and audit log, and pools
+//! outside its filter are never visible to it. The states themselves are shared: VM pools of all
+//! namespaces keep simulating on the engine of the stream's decoder, so no state is loaded twice.
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::Arc,
+};
+
+use tycho_core::Bytes;
+
+use crate::protocol::{
+    models::{BlockUpdate, ProtocolComponent},
+    registry::PoolRegistry,
+};
+
+type Predicate = Arc<dyn Fn(&ProtocolComponent) -> bool + Send + Sync>;
+
+/// The pools a registry accepts, see `PoolRegistry::with_filter`. A pool must pass every
+/// condition set; the default filter accepts every pool.
+#[derive(Clone, Default)]
+pub struct PoolFilter {
+    protocol_systems: Option<HashSet<String>>,
+    tokens: Option<HashSet<Bytes>>,
+    ids: Option<HashSet<String>>,
+    predicate: Option<Predicate>,
+}
+
+impl fmt::Debug for PoolFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoolFilter")
+            .field("protocol_systems", &self.protocol_systems)
+            .field("tokens", &self.tokens)
+            .field("ids", &self.ids)
+            .field("predicate", &self.predicate.is_some())
+            .finish()
+    }
+}
+
+impl PoolFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accepts pools of `protocol_system`, in addition to the protocol systems accepted before.
+    pub fn protocol_system(mut self, protocol_system: &str) -> Self {
+        self.protocol_systems
+            .get_or_insert_with(HashSet::new)
+            .insert(protocol_system.to_string());
+        self
+    }
+
+    /// Accepts only pools all of whose tokens are in `tokens`.
+    pub fn tokens(mut self, tokens: impl IntoIterator<Item = Bytes>) -> Self {
+        self.tokens = Some(tokens.into_iter().collect());
+        self
+    }
+
+    /// Accepts only the pools with the given ids.
+    pub fn ids(mut self, ids: impl IntoIterator<Item = String>) -> Self {
+        self.ids = Some(ids.into_iter().collect());
+        self
+    }
+
+    /// Accepts only pools for which `predicate` returns true.
+    pub fn predicate(
+        mut self,
+        predicate: impl Fn(&ProtocolComponent) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.predicate = Some(Arc::new(predicate));
+        self
+    }
+
+    /// Whether the pool `id` is accepted.
+    pub fn accepts(&self, id: &str, component: &ProtocolComponent) -> bool {
+        self.protocol_systems
+            .as_ref()
+            .map_or(true, |systems| systems.contains(&component.protocol_system)) &&
+            self.tokens
+                .as_ref()
+                .map_or(true, |tokens| {
+                    component
+                        .tokens
+                        .iter()
+                        .all(|token| tokens.contains(&token.address))
+                }) &&
+            self.ids
+                .as_ref()
+                .map_or(true, |ids| ids.contains(id)) &&
+            self.predicate
+                .as_ref()
+                .map_or(true, |predicate| predicate(component))
+    }
+}
+
+/// Registries of several consumers fed by the same stream, see module docs.
+#[derive(Debug, Default)]
+pub struct Namespaces {
+    registries: HashMap<String, PoolRegistry>,
+}
+
+impl Namespaces {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a namespace, replacing any namespace of the same name. The registry only sees the
+    /// updates applied after it was added.
+    pub fn insert(&mut self, name: &str, registry: PoolRegistry) -> Option<PoolRegistry> {
+        self.registries
+            .insert(name.to_string(), registry)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<PoolRegistry> {
+        self.registries.remove(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&PoolRegistry> {
+        self.registries.get(name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut PoolRegistry> {
+        self.registries.get_mut(name)
+    }
+
+    /// Names of the namespaces, sorted.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self
+            .registries
+            .keys()
+            .map(String::as_str)
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Applies a block update of the stream to every namespace.
+    pub fn apply(&mut self, update: &BlockUpdate) {
+        for registry in self.registries.values_mut() {
+            registry.apply(update);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::protocol::{
+        fixtures::{component, DAI, USDC, WETH},
+        registry::PoolQuery,
+        state::MockProtocolSim,
+    };
+
+    fn ids(registry: &PoolRegistry) -> Vec<&str> {
+        registry
+            .query(&PoolQuery::new())
+            .pools
+            .iter()
+            .map(|pool| pool.id)
+            .collect()
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let stables = [USDC, DAI].map(|token| Bytes::from_str(token).unwrap());
+        let mut namespaces = Namespaces::new();
+        namespaces.insert("all", PoolRegistry::new());
+        namespaces
+            .insert("stables", PoolRegistry::new().with_filter(PoolFilter::new().tokens(stables)));
+        namespaces.insert(
+            "v3",
+            PoolRegistry::new().with_filter(
+                PoolFilter::new()
+                    .protocol_system("uniswap_v3")
+                    .predicate(|component| component.tokens.len() == 2),
+            ),
+        );
+        let mut state = MockProtocolSim::new();
+        state
+            .expect_clone_box()
+            .returning(|| Box::new(MockProtocolSim::new()));
+
+        namespaces.apply(&BlockUpdate::new(
+            1,
+            HashMap::from([("0x02".to_string(), Box::new(state) as _)]),
+            HashMap::from([
+                ("0x01".to_string(), component("0x01", "uniswap_v2", &[USDC, WETH])),
+                ("0x02".to_string(), component("0x02", "uniswap_v3", &[USDC, DAI])),
+                ("0x03".to_string(), component("0x03", "uniswap_v3", &[USDC, WETH, DAI])),
+            ]),
+        ));
+
+        assert_eq!(namespaces.names(), vec!["all", "stables", "v3"]);
+        assert_eq!(ids(namespaces.get("all").unwrap()), vec!["0x01", "0x02", "0x03"]);
+        assert_eq!(ids(namespaces.get("stables").unwrap()), vec!["0x02"]);
+        assert_eq!(ids(namespaces.get("v3").unwrap()), vec!["0x02"]);
+        assert_eq!(
+            namespaces
+                .get("v3")
+                .unwrap()
+                .get("0x02")
+                .unwrap()
+                .last_updated,
+            Some(1)
+        );
+        // Metadata is per namespace
+        assert!(namespaces
+            .get_mut("stables")
+            .unwrap()
+            .set_metadata("0x02", "label", "stable"));
+        assert!(namespaces
+            .get("v3")
+            .unwrap()
+            .metadata::<&str>("0x02", "label")
+            .is_none());
+    }
+}
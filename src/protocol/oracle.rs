@@ -0,0 +1,376 @@
+//! Price oracle over streamed spot prices
+//!
+//! Aggregates the spot prices of all pools trading a pair into a single price per block, rejecting
+//! pools whose price deviates too much from the cross-pool median, and maintains a time weighted
+//! average (TWAP) and an exponentially weighted moving average (EMA) of it.
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use tycho_core::Bytes;
+
+use crate::{
+    protocol::{registry::SpotPrices, wire::WireBlockUpdate},
+    tokens::sort_pair,
+};
+
+/// An ordered pair: prices are amounts of `quote` per unit of `base`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Pair {
+    pub base: Bytes,
+    pub quote: Bytes,
+}
+
+impl Pair {
+    pub fn new(base: Bytes, quote: Bytes) -> Self {
+        Self { base, quote }
+    }
+
+    /// The pair of two tokens in canonical order, see `tokens::sort_pair`. Use it to key one price
+    /// per pair regardless of the order the tokens came in.
+    pub fn canonical(token_a: Bytes, token_b: Bytes) -> Self {
+        let (base, quote) = sort_pair(&token_a, &token_b);
+        Self { base: base.clone(), quote: quote.clone() }
+    }
+
+    /// Whether the base sorts before the quote, see `Pair::canonical`.
+    pub fn is_canonical(&self) -> bool {
+        self.base <= self.quote
+    }
+
+    /// The pair with base and quote swapped.
+    pub fn inverse(&self) -> Self {
+        Self { base: self.quote.clone(), quote: self.base.clone() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OracleConfig {
+    /// Length of the TWAP window, in seconds
+    pub twap_window: u64,
+    /// Time after which an observation's weight in the EMA is halved, in seconds
+    pub ema_half_life: u64,
+    /// Maximum relative deviation of a pool's price from the cross-pool median. Pools further
+    /// away are ignored for that block.
+    pub max_deviation: f64,
+}
+
+impl Default for OracleConfig {
+    fn default() -> Self {
+        Self { twap_window: 1800, ema_half_life: 300, max_deviation: 0.05 }
+    }
+}
+
+/// Aggregated price of a pair.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FairPrice {
+    /// Aggregated spot price of the latest block
+    pub spot: f64,
+    /// Time weighted average price over the configured window
+    pub twap: f64,
+    /// Exponentially weighted moving average price
+    pub ema: f64,
+    /// Number of pools used for the latest spot price
+    pub n_pools: usize,
+    /// Timestamp of the latest observation
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct PairAggregate {
+    /// Aggregated spot prices with their timestamp, oldest first
+    observations: VecDeque<(u64, f64)>,
+    ema: f64,
+    n_pools: usize,
+}
+
+/// Maintains TWAP and EMA aggregates per pair, see module docs.
+#[derive(Debug, Clone, Default)]
+pub struct PriceOracle {
+    config: OracleConfig,
+    /// Latest spot prices by pair and pool
+    spot_prices: HashMap<Pair, HashMap<String, f64>>,
+    aggregates: HashMap<Pair, PairAggregate>,
+}
+
+impl PriceOracle {
+    pub fn new(config: OracleConfig) -> Self {
+        Self { config, ..Default::default() }
+    }
+
+    /// Records the spot prices of a block update and updates the aggregates of all pairs whose
+    /// prices changed.
+    pub fn ingest(&mut self, update: &WireBlockUpdate, timestamp: u64) {
+        for id in &update.removed_pools {
+            for prices in self.spot_prices.values_mut() {
+                prices.remove(id);
+            }
+        }
+
+        let mut changed = HashSet::new();
+        for (id, state) in &update.states {
+            for spot_price in &state.spot_prices {
+                let pair = Pair::canonical(spot_price.base.clone(), spot_price.quote.clone());
+                let price = if pair.base == spot_price.base {
+                    spot_price.price
+                } else {
+                    1.0 / spot_price.price
+                };
+                self.spot_prices
+                    .entry(pair.clone())
+                    .or_default()
+                    .insert(id.clone(), price);
+                changed.insert(pair);
+            }
+        }
+
+        for pair in changed {
+            let prices: Vec<f64> = self.spot_prices[&pair]
+                .values()
+                .copied()
+                .collect();
+            if let Some((price, n_pools)) = aggregate(&prices, self.config.max_deviation) {
+                self.observe(pair, price, n_pools, timestamp);
+            }
+        }
+    }
+
+    /// Replaces the spot prices of all pools with the ones computed by
+    /// `PoolRegistry::compute_all_spot_prices`, and updates the aggregates of all pairs.
+    pub fn ingest_spot_prices(&mut self, spot_prices: &SpotPrices, timestamp: u64) {
+        self.spot_prices = spot_prices.prices.clone();
+        for (pair, prices) in &spot_prices.prices {
+            let prices: Vec<f64> = prices.values().copied().collect();
+            if let Some((price, n_pools)) = aggregate(&prices, self.config.max_deviation) {
+                self.observe(pair.clone(), price, n_pools, timestamp);
+            }
+        }
+    }
+
+    /// Records an aggregated spot price for a pair. `pair` should be canonical, see
+    /// `Pair::canonical`, for `fair_price` to find it.
+    pub fn observe(&mut self, pair: Pair, price: f64, n_pools: usize, timestamp: u64) {
+        let half_life = self.config.ema_half_life as f64;
+        let window = self.config.twap_window;
+        let aggregate = self.aggregates.entry(pair).or_default();
+
+        aggregate.ema = match aggregate.observations.back() {
+            Some((last_ts, _)) if half_life > 0.0 => {
+                let elapsed = timestamp.saturating_sub(*last_ts) as f64;
+                let alpha = 1.0 - 0.5_f64.powf(elapsed / half_life);
+                aggregate.ema + alpha * (price - aggregate.ema)
+            }
+            _ => price,
+        };
+        aggregate.n_pools = n_pools;
+        aggregate
+            .observations
+            .push_back((timestamp, price));
+
+        // Keep the last observation before the window, its price holds until the next one
+        let start = timestamp.saturating_sub(window);
+        while aggregate.observations.len() > 1 && aggregate.observations[1].0 <= start {
+            aggregate.observations.pop_front();
+        }
+    }
+
+    /// Returns the aggregated price of a pair, or `None` if it was never observed. Prices are
+    /// aggregated per canonical pair, see `Pair::canonical`; the prices of the inverse pair are
+    /// their reciprocals.
+    pub fn fair_price(&self, pair: &Pair) -> Option<FairPrice> {
+        let canonical = Pair::canonical(pair.base.clone(), pair.quote.clone());
+        let aggregate = self.aggregates.get(&canonical)?;
+        let (timestamp, spot) = *aggregate.observations.back()?;
+        let price = FairPrice {
+            spot,
+            twap: twap(&aggregate.observations, timestamp, self.config.twap_window),
+            ema: aggregate.ema,
+            n_pools: aggregate.n_pools,
+            timestamp,
+        };
+        if canonical == *pair {
+            Some(price)
+        } else {
+            Some(FairPrice {
+                spot: 1.0 / price.spot,
+                twap: 1.0 / price.twap,
+                ema: 1.0 / price.ema,
+                ..price
+            })
+        }
+    }
+}
+
+/// Averages the prices within `max_deviation` of their median. Returns the average and the number
+/// of prices used.
+fn aggregate(prices: &[f64], max_deviation: f64) -> Option<(f64, usize)> {
+    let mut sorted: Vec<f64> = prices
+        .iter()
+        .copied()
+        .filter(|p| p.is_finite() && *p > 0.0)
+        .collect();
+    if sorted.is_empty() {
+        return None;
+    }
+    sorted.sort_by(f64::total_cmp);
+    let mid = sorted.len() / 2;
+    let median =
+        if sorted.len() % 2 == 0 { (sorted[mid - 1] + sorted[mid]) / 2.0 } else { sorted[mid] };
+
+    let accepted: Vec<f64> = sorted
+        .into_iter()
+        .filter(|p| ((p - median) / median).abs() <= max_deviation)
+        .collect();
+    if accepted.is_empty() {
+        return None;
+    }
+    Some((accepted.iter().sum::<f64>() / accepted.len() as f64, accepted.len()))
+}
+
+/// Time weighted average of the observations over `[now - window, now]`. Each price holds until
+/// the next observation.
+fn twap(observations: &VecDeque<(u64, f64)>, now: u64, window: u64) -> f64 {
+    let start = now.saturating_sub(window);
+    let mut weighted = 0.0;
+    let mut total = 0u64;
+    for (i, (timestamp, price)) in observations.iter().enumerate() {
+        let from = (*timestamp).max(start);
+        let to = observations
+            .get(i + 1)
+            .map_or(now, |(next, _)| *next);
+        if to > from {
+            weighted += price * (to - from) as f64;
+            total += to - from;
+        }
+    }
+    if total == 0 {
+        // All observations at the same timestamp
+        observations
+            .back()
+            .map_or(0.0, |(_, price)| *price)
+    } else {
+        weighted / total as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, str::FromStr};
+
+    use approx::assert_ulps_eq;
+
+    use super::*;
+    use crate::protocol::wire::{WireBlock, WirePoolState, WireSpotPrice};
+
+    fn pair() -> Pair {
+        Pair::canonical(Bytes::from_str("0x01").unwrap(), Bytes::from_str("0x02").unwrap())
+    }
+
+    fn update(prices: &[(&str, f64)]) -> WireBlockUpdate {
+        WireBlockUpdate {
+            block: WireBlock { number: 1, ..Default::default() },
+            new_pools: BTreeMap::new(),
+            states: prices
+                .iter()
+                .map(|(id, price)| {
+                    let spot_price =
+                        WireSpotPrice { base: pair().base, quote: pair().quote, price: *price };
+                    (
+                        id.to_string(),
+                        WirePoolState { spot_prices: vec![spot_price], ..Default::default() },
+                    )
+                })
+                .collect(),
+            removed_pools: vec![],
+        }
+    }
+
+    #[test]
+    fn test_outlier_rejection() {
+        let mut oracle = PriceOracle::new(OracleConfig::default());
+
+        oracle.ingest(&update(&[("a", 100.0), ("b", 102.0), ("c", 150.0)]), 0);
+
+        let price = oracle.fair_price(&pair()).unwrap();
+        assert_ulps_eq!(price.spot, 101.0);
+        assert_eq!(price.n_pools, 2);
+    }
+
+    #[test]
+    fn test_inverse_spot_prices() {
+        let mut oracle = PriceOracle::new(OracleConfig::default());
+        let mut update = update(&[("a", 100.0)]);
+        // Pool b quotes the inverse pair
+        let inverse = WireSpotPrice { base: pair().quote, quote: pair().base, price: 0.01 };
+        update.states.insert(
+            "b".to_string(),
+            WirePoolState { spot_prices: vec![inverse], ..Default::default() },
+        );
+
+        oracle.ingest(&update, 0);
+
+        let price = oracle.fair_price(&pair()).unwrap();
+        assert_ulps_eq!(price.spot, 100.0);
+        assert_eq!(price.n_pools, 2);
+        assert_ulps_eq!(
+            oracle
+                .fair_price(&pair().inverse())
+                .unwrap()
+                .spot,
+            0.01
+        );
+    }
+
+    #[test]
+    fn test_ingest_spot_prices() {
+        let mut oracle = PriceOracle::new(OracleConfig::default());
+        let spot_prices = SpotPrices {
+            block: 1,
+            prices: HashMap::from([(
+                pair(),
+                HashMap::from([("a".to_string(), 100.0), ("b".to_string(), 104.0)]),
+            )]),
+        };
+
+        oracle.ingest_spot_prices(&spot_prices, 0);
+
+        let price = oracle.fair_price(&pair()).unwrap();
+        assert_ulps_eq!(price.spot, 102.0);
+        assert_eq!(price.n_pools, 2);
+    }
+
+    #[test]
+    fn test_twap_and_ema() {
+        let config = OracleConfig { twap_window: 100, ema_half_life: 10, max_deviation: 1.0 };
+        let mut oracle = PriceOracle::new(config);
+
+        oracle.ingest(&update(&[("a", 100.0)]), 0);
+        oracle.ingest(&update(&[("a", 200.0)]), 75);
+        oracle.ingest(&update(&[("a", 200.0)]), 100);
+
+        let price = oracle.fair_price(&pair()).unwrap();
+        // 100 for 75s, 200 for 25s
+        assert_ulps_eq!(price.twap, 125.0);
+        // After 75s (7.5 half lives) the EMA almost caught up with the new price
+        assert!(price.ema > 199.0 && price.ema < 200.0);
+
+        oracle.ingest(&update(&[("a", 200.0)]), 200);
+        assert_ulps_eq!(oracle.fair_price(&pair()).unwrap().twap, 200.0);
+    }
+
+    #[test]
+    fn test_removed_pool() {
+        let mut oracle = PriceOracle::new(OracleConfig::default());
+        oracle.ingest(&update(&[("a", 100.0), ("b", 101.0)]), 0);
+
+        let mut removal = update(&[("a", 100.0)]);
+        removal.removed_pools = vec!["b".to_string()];
+        oracle.ingest(&removal, 12);
+
+        assert_eq!(
+            oracle
+                .fair_price(&pair())
+                .unwrap()
+                .n_pools,
+            1
+        );
+    }
+}
@@ -0,0 +1,251 @@
+//! Exact prices and their output formats
+//!
+//! Spot prices are computed as `f64` in whole units of both tokens, e.g. USDC per WETH, and swaps
+//! yield amounts in the tokens' smallest units. `Price` keeps a price as an exact ratio of
+//! smallest units, so it can be compared against on-chain values and printed in whichever format
+//! a consumer expects without converting by hand:
+//!
+//! - `Price::to_decimal_string`: whole units with a fixed number of decimals, e.g. `3012.450000`
+//! - `Price::to_ratio`: whole units as a reduced fraction
+//! - `Price::to_wei_per_wei`: smallest units as a reduced fraction, as contracts compute prices
+use std::fmt;
+
+use num_bigint::BigUint;
+use num_traits::{One, ToPrimitive, Zero};
+
+use crate::models::Token;
+
+/// Price of a base token in a quote token, see module docs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Price {
+    /// Amount of the quote token, in its smallest unit
+    numerator: BigUint,
+    /// Amount of the base token worth `numerator`, in its smallest unit
+    denominator: BigUint,
+    base_decimals: usize,
+    quote_decimals: usize,
+}
+
+impl Price {
+    /// The price of `base_amount` of `base` being worth `quote_amount` of `quote`, both in the
+    /// tokens' smallest units. `None` if `base_amount` is zero.
+    pub fn from_amounts(
+        base: &Token,
+        base_amount: BigUint,
+        quote: &Token,
+        quote_amount: BigUint,
+    ) -> Option<Self> {
+        if base_amount.is_zero() {
+            return None;
+        }
+        Some(Self {
+            numerator: quote_amount,
+            denominator: base_amount,
+            base_decimals: base.decimals,
+            quote_decimals: quote.decimals,
+        })
+    }
+
+    /// The execution price of a swap of `amount_in` of `token_in` for `amount_out` of
+    /// `token_out`, in `token_out` per `token_in`. `None` if `amount_in` is zero.
+    pub fn from_swap(
+        token_in: &Token,
+        amount_in: &BigUint,
+        token_out: &Token,
+        amount_out: &BigUint,
+    ) -> Option<Self> {
+        Self::from_amounts(token_in, amount_in.clone(), token_out, amount_out.clone())
+    }
+
+    /// A price in whole units, like the ones returned by `ProtocolSim::spot_price`. The float is
+    /// converted exactly. `None` if `price` is negative, infinite or NaN.
+    pub fn from_f64(base: &Token, quote: &Token, price: f64) -> Option<Self> {
+        let (numerator, denominator) = f64_ratio(price)?;
+        Self::from_amounts(
+            base,
+            denominator * pow10(base.decimals),
+            quote,
+            numerator * pow10(quote.decimals),
+        )
+    }
+
+    /// The price of the quote token in the base token. `None` if the price is zero.
+    pub fn invert(&self) -> Option<Self> {
+        if self.numerator.is_zero() {
+            return None;
+        }
+        Some(Self {
+            numerator: self.denominator.clone(),
+            denominator: self.numerator.clone(),
+            base_decimals: self.quote_decimals,
+            quote_decimals: self.base_decimals,
+        })
+    }
+
+    /// The price in whole units as a float. Precision is lost beyond 53 bits.
+    pub fn to_f64(&self) -> f64 {
+        let (numerator, denominator) = self.to_ratio();
+        let (numerator, denominator) = (
+            numerator
+                .to_f64()
+                .unwrap_or(f64::INFINITY),
+            denominator
+                .to_f64()
+                .unwrap_or(f64::INFINITY),
+        );
+        numerator / denominator
+    }
+
+    /// The price in whole units with `precision` decimals, rounded to the nearest value, halves
+    /// up.
+    pub fn to_decimal_string(&self, precision: usize) -> String {
+        let (numerator, denominator) = self.to_ratio();
+        let scaled = numerator * pow10(precision);
+        // (2 * scaled + denominator) / (2 * denominator) rounds halves up
+        let rounded = (scaled * 2u8 + &denominator) / (denominator * 2u8);
+        let digits = rounded.to_string();
+        if precision == 0 {
+            return digits;
+        }
+        let digits = format!("{digits:0>width$}", width = precision + 1);
+        let (integer, fraction) = digits.split_at(digits.len() - precision);
+        format!("{integer}.{fraction}")
+    }
+
+    /// The price in whole units as a reduced fraction `(numerator, denominator)`.
+    pub fn to_ratio(&self) -> (BigUint, BigUint) {
+        reduce(
+            &self.numerator * pow10(self.base_decimals),
+            &self.denominator * pow10(self.quote_decimals),
+        )
+    }
+
+    /// The price in the tokens' smallest units as a reduced fraction `(numerator, denominator)`:
+    /// the amount of the quote token's smallest unit paid per smallest unit of the base token.
+    pub fn to_wei_per_wei(&self) -> (BigUint, BigUint) {
+        reduce(self.numerator.clone(), self.denominator.clone())
+    }
+}
+
+/// Formats the price in whole units, with the decimals of the quote token unless a precision is
+/// given, e.g. `{:.2}`.
+impl fmt::Display for Price {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let precision = f
+            .precision()
+            .unwrap_or(self.quote_decimals);
+        f.write_str(&self.to_decimal_string(precision))
+    }
+}
+
+fn pow10(exponent: usize) -> BigUint {
+    BigUint::from(10u8).pow(exponent as u32)
+}
+
+fn gcd(mut a: BigUint, mut b: BigUint) -> BigUint {
+    while !b.is_zero() {
+        let remainder = &a % &b;
+        a = b;
+        b = remainder;
+    }
+    a
+}
+
+/// Divides both terms of a fraction by their greatest common divisor. Zero is returned as `0/1`.
+fn reduce(numerator: BigUint, denominator: BigUint) -> (BigUint, BigUint) {
+    if numerator.is_zero() {
+        return (numerator, BigUint::one());
+    }
+    let divisor = gcd(numerator.clone(), denominator.clone());
+    (numerator / &divisor, denominator / divisor)
+}
+
+/// The exact value of a finite, non-negative float as a fraction.
+pub(crate) fn f64_ratio(value: f64) -> Option<(BigUint, BigUint)> {
+    if !value.is_finite() || value.is_sign_negative() {
+        return None;
+    }
+    let bits = value.to_bits();
+    let biased_exponent = (bits >> 52) & 0x7ff;
+    let fraction = bits & ((1 << 52) - 1);
+    // Subnormals have no implicit leading bit and the exponent of the smallest normal
+    let (mantissa, exponent) = match biased_exponent {
+        0 => (fraction, -1074),
+        _ => (fraction | (1 << 52), biased_exponent as i64 - 1075),
+    };
+    let mantissa = BigUint::from(mantissa);
+    Some(if exponent >= 0 {
+        (mantissa << exponent as usize, BigUint::one())
+    } else {
+        (mantissa, BigUint::one() << exponent.unsigned_abs() as usize)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usdc() -> Token {
+        Token::new(
+            "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48",
+            6,
+            "USDC",
+            BigUint::from(10_000u32),
+        )
+    }
+
+    fn weth() -> Token {
+        Token::new(
+            "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2",
+            18,
+            "WETH",
+            BigUint::from(10_000u32),
+        )
+    }
+
+    #[test]
+    fn test_price_formats() {
+        // 1.5 WETH sold for 4518.675 USDC
+        let price = Price::from_swap(
+            &weth(),
+            &BigUint::from(1_500_000_000_000_000_000u128),
+            &usdc(),
+            &BigUint::from(4_518_675_000u64),
+        )
+        .unwrap();
+
+        assert_eq!(price.to_decimal_string(2), "3012.45");
+        assert_eq!(price.to_decimal_string(0), "3012");
+        assert_eq!(price.to_string(), "3012.450000");
+        assert_eq!(format!("{price:.1}"), "3012.5");
+        assert_eq!(price.to_ratio(), (BigUint::from(60249u32), BigUint::from(20u8)));
+        assert_eq!(
+            price.to_wei_per_wei(),
+            (BigUint::from(60249u32), BigUint::from(20_000_000_000_000u64))
+        );
+        assert_eq!(price.to_f64(), 3012.45);
+
+        let inverted = price.invert().unwrap();
+        assert_eq!(inverted.to_decimal_string(8), "0.00033196");
+        assert_eq!(inverted.to_ratio(), (BigUint::from(20u8), BigUint::from(60249u32)));
+    }
+
+    #[test]
+    fn test_from_f64() {
+        let price = Price::from_f64(&usdc(), &weth(), 0.00025).unwrap();
+
+        assert_eq!(price.to_decimal_string(6), "0.000250");
+        assert_eq!(price.to_f64(), 0.00025);
+        // The float closest to 0.00025 isn't exactly 0.00025
+        assert_ne!(price.to_ratio(), (BigUint::from(1u8), BigUint::from(4000u16)));
+        assert_eq!(
+            Price::from_f64(&usdc(), &weth(), 2.0)
+                .unwrap()
+                .to_wei_per_wei(),
+            (BigUint::from(2_000_000_000_000u64), BigUint::one())
+        );
+        assert!(Price::from_f64(&usdc(), &weth(), -1.0).is_none());
+        assert!(Price::from_f64(&usdc(), &weth(), f64::NAN).is_none());
+        assert!(Price::from_swap(&usdc(), &BigUint::zero(), &weth(), &BigUint::one()).is_none());
+    }
+}
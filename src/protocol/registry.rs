@@ -0,0 +1,1621 @@
+//! Pool registry
+//!
+//! `PoolRegistry` keeps the components of a stream together with the metadata needed to browse
+//! them: the TVL reported by Tycho and the last block their state changed. Pools can be listed by
+//! token pair or protocol, sorted and paginated, so consumers like UIs don't need to maintain their
+//! own indexes.
+//!
+//! Sorting is stable: ties are broken by pool id, so pages don't overlap or skip pools as long as
+//! the registry doesn't change between requests.
+//!
+//! Consumers can attach their own typed metadata to pools, e.g. labels, scores or risk flags, see
+//! `PoolRegistry::set_metadata`. Metadata is kept across state updates and dropped with the pool
+//! when it is removed.
+//!
+//! The registry also keeps the latest state of every pool, so the spot prices of all pools can be
+//! computed at once each block, see `PoolRegistry::compute_all_spot_prices`.
+//!
+//! Optionally, the states of the last blocks are retained too, so swaps can be quoted as they
+//! would have been at any of these blocks, see `PoolRegistry::with_history`.
+//!
+//! The time and gas spent pricing pools can be capped per protocol with a `SimulationBudget`, see
+//! `PoolRegistry::with_budget`.
+//!
+//! Pools are always priced in a reproducible order, so runs over the same stream skip the same
+//! pools: by id, or in a pseudo-random order derived from a seed and the block, see
+//! `PoolRegistry::with_seed`. Which pools a time budget skips still depends on how long their
+//! simulations take.
+//!
+//! Quotes served by the registry can be recorded for reconciliation, see
+//! `PoolRegistry::with_audit_log`.
+//!
+//! A registry can be restricted to a universe of pools with a `PoolFilter`, so several consumers
+//! can keep their own registries fed by the same stream, see `Namespaces`.
+//!
+//! Quotes of pools that aren't initialized yet, e.g. created in the current block, can be retried
+//! once the pool's state changes, see `PoolRegistry::with_retry_uninitialized`.
+use std::{
+    any::Any,
+    cmp::Ordering,
+    collections::{HashMap, HashSet, VecDeque},
+    num::NonZeroUsize,
+    sync::Mutex,
+    thread,
+    time::Instant,
+};
+
+use alloy_primitives::{keccak256, B256};
+use num_bigint::BigUint;
+use tracing::debug;
+use tycho_core::{models::Chain, Bytes};
+
+use crate::{
+    models::Token,
+    protocol::{
+        audit::{AuditLog, QuoteRequest},
+        budget::{BlockBudget, BudgetReport, SimulationBudget},
+        errors::SimulationError,
+        models::{BlockInfo, BlockUpdate, GetAmountOutResult, ProtocolComponent},
+        namespace::PoolFilter,
+        oracle::Pair,
+        state::ProtocolSim,
+    },
+    tokens::{canonical_token, is_native, wrapped_native},
+};
+
+/// Order of the pools returned by `PoolRegistry::query`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PoolSort {
+    /// By pool id, ascending
+    #[default]
+    Id,
+    /// By TVL, descending. Pools without a known TVL come last.
+    Tvl,
+    /// By the last block the pool's state changed, most recent first. State changes are caused by
+    /// trades and liquidity changes, so this approximates the recency of the last trade.
+    LastUpdated,
+}
+
+/// Filters, order and page of a `PoolRegistry::query`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PoolQuery {
+    /// Only pools containing both tokens, in any order
+    pub pair: Option<(Bytes, Bytes)>,
+    /// Only pools of this chain. Also makes `pair` match the native token of the chain and its
+    /// wrapped version interchangeably, see `tokens::is_equivalent`.
+    pub chain: Option<Chain>,
+    /// Only pools of this protocol system, e.g. `uniswap_v2`
+    pub protocol_system: Option<String>,
+    /// Only pools with metadata under this key
+    pub metadata_key: Option<String>,
+    pub sort: PoolSort,
+    /// Number of pools to skip
+    pub offset: usize,
+    /// Maximum number of pools to return. Unlimited if `None`.
+    pub limit: Option<usize>,
+}
+
+impl PoolQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn pair(mut self, token_a: Bytes, token_b: Bytes) -> Self {
+        self.pair = Some((token_a, token_b));
+        self
+    }
+
+    pub fn chain(mut self, chain: Chain) -> Self {
+        self.chain = Some(chain);
+        self
+    }
+
+    pub fn protocol_system(mut self, protocol_system: &str) -> Self {
+        self.protocol_system = Some(protocol_system.to_string());
+        self
+    }
+
+    pub fn with_metadata(mut self, key: &str) -> Self {
+        self.metadata_key = Some(key.to_string());
+        self
+    }
+
+    pub fn sort(mut self, sort: PoolSort) -> Self {
+        self.sort = sort;
+        self
+    }
+
+    /// Selects the page of `page_size` pools starting at pool `page * page_size`.
+    pub fn page(mut self, page: usize, page_size: usize) -> Self {
+        self.offset = page * page_size;
+        self.limit = Some(page_size);
+        self
+    }
+}
+
+/// A pool of the registry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolEntry<'a> {
+    pub id: &'a str,
+    pub component: &'a ProtocolComponent,
+    pub tvl: Option<f64>,
+    /// Last block the pool's state changed, `None` if no state was received yet
+    pub last_updated: Option<u64>,
+}
+
+/// A page of query results.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolPage<'a> {
+    pub pools: Vec<PoolEntry<'a>>,
+    /// Number of pools matching the query's filters, across all pages
+    pub total: usize,
+}
+
+/// A quote of `PoolRegistry::get_amount_out` that failed because the pool wasn't initialized.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingQuote {
+    pub pool: String,
+    pub amount_in: BigUint,
+    pub token_in: Token,
+    pub token_out: Token,
+    pub idempotency_key: Option<String>,
+}
+
+/// A pending quote retried after the pool's state changed, see
+/// `PoolRegistry::with_retry_uninitialized`.
+#[derive(Debug)]
+pub struct RetriedQuote {
+    pub quote: PendingQuote,
+    /// Block the quote was retried at
+    pub block_number: u64,
+    pub result: Result<GetAmountOutResult, SimulationError>,
+}
+
+/// Spot prices of all pools of a registry, by pair and pool id. Each pair is priced once, in
+/// canonical order, see `Pair::canonical`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SpotPrices {
+    /// Block the prices were computed at
+    pub block: u64,
+    pub prices: HashMap<Pair, HashMap<String, f64>>,
+}
+
+impl SpotPrices {
+    /// Returns the prices of `pair` by pool id. `pair` must be canonical, see `Pair::canonical`.
+    pub fn get(&self, pair: &Pair) -> Option<&HashMap<String, f64>> {
+        self.prices.get(pair)
+    }
+}
+
+type Metadata = HashMap<String, Box<dyn Any + Send + Sync>>;
+
+#[derive(Debug)]
+struct PoolInfo {
+    component: ProtocolComponent,
+    tvl: Option<f64>,
+    last_updated: Option<u64>,
+    metadata: Metadata,
+    state: Option<Box<dyn ProtocolSim>>,
+    /// Spot prices of the current state, `None` if not computed yet
+    spot_prices: Option<HashMap<Pair, f64>>,
+}
+
+impl PoolInfo {
+    /// Computes the spot prices of the pool's state, if they are outdated.
+    fn update_spot_prices(&mut self) {
+        if let (Some(state), None) = (&self.state, &self.spot_prices) {
+            self.spot_prices = Some(spot_prices(state.as_ref(), &self.component.tokens));
+        }
+    }
+
+    /// Computes the spot prices of the pool's state, if they are outdated and the budget allows.
+    fn update_spot_prices_within(&mut self, budget: Option<&Mutex<BlockBudget>>) {
+        let Some(budget) = budget else {
+            return self.update_spot_prices();
+        };
+        if self.state.is_none() || self.spot_prices.is_some() {
+            return;
+        }
+        let protocol_system = &self.component.protocol_system;
+        if !budget
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .allows(protocol_system)
+        {
+            return;
+        }
+        let start = Instant::now();
+        self.update_spot_prices();
+        let gas = self
+            .state
+            .as_ref()
+            .map_or(0, |state| state.spot_price_gas());
+        budget
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .record(protocol_system, start.elapsed(), gas);
+    }
+}
+
+/// The states that changed in a retained block.
+#[derive(Debug)]
+struct BlockStates {
+    block: BlockInfo,
+    states: HashMap<String, Box<dyn ProtocolSim>>,
+    /// Pools removed in the block
+    removed: HashSet<String>,
+}
+
+/// Indexes the pools of a stream for listing and search.
+#[derive(Debug, Default)]
+pub struct PoolRegistry {
+    pools: HashMap<String, PoolInfo>,
+    by_token: HashMap<Bytes, HashSet<String>>,
+    /// Number of blocks whose states are retained
+    history_capacity: usize,
+    /// The retained blocks, oldest first
+    history: VecDeque<BlockStates>,
+    /// The states of the pools before the oldest retained block
+    history_base: HashMap<String, Box<dyn ProtocolSim>>,
+    /// Limits on pricing pools per block
+    budget: Option<SimulationBudget>,
+    /// Usage of the budget in the block of the last `compute_all_spot_prices` call
+    block_budget: Option<BlockBudget>,
+    /// Latest block received, `None` if no update carried a block yet
+    block: Option<BlockInfo>,
+    /// Log of the quotes served
+    audit_log: Option<AuditLog>,
+    /// The pools the registry accepts
+    filter: PoolFilter,
+    /// Seed of the order pools are priced in, by id if `None`
+    seed: Option<u64>,
+    /// Maximum number of quotes of uninitialized pools waiting to be retried, `None` if they
+    /// aren't retried
+    max_pending_quotes: Option<usize>,
+    /// Quotes of uninitialized pools waiting for the pool's state to change, oldest first
+    pending_quotes: Mutex<VecDeque<PendingQuote>>,
+    /// Retried quotes not taken yet
+    retried_quotes: Vec<RetriedQuote>,
+}
+
+impl PoolRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Retains the states of the last `blocks` blocks, see `PoolRegistry::get_amount_out_at`.
+    ///
+    /// Only the states that changed are kept per block, so retaining a block costs as much as its
+    /// state updates. Updates without a block (see `BlockUpdate::set_block`) are not retained.
+    pub fn with_history(mut self, blocks: usize) -> Self {
+        self.history_capacity = blocks;
+        self
+    }
+
+    /// Caps the time and gas spent pricing pools per block in
+    /// `PoolRegistry::compute_all_spot_prices`. Calls for the same block share the budget. Pools
+    /// of a protocol that used up its share of the budget are skipped until the next block, see
+    /// `PoolRegistry::budget_report`.
+    pub fn with_budget(mut self, budget: SimulationBudget) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Usage of the budget in the block of the last `PoolRegistry::compute_all_spot_prices` call,
+    /// `None` if no budget is set or no prices were computed yet.
+    pub fn budget_report(&self) -> Option<&BudgetReport> {
+        self.block_budget
+            .as_ref()
+            .map(BlockBudget::report)
+    }
+
+    /// Prices pools in a pseudo-random order derived from `seed` and the block in
+    /// `PoolRegistry::compute_all_spot_prices`, instead of by id. With a budget, pools priced last
+    /// are the first to be skipped, so this spreads skipping over all pools while keeping runs
+    /// with the same seed reproducible.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Only accepts the pools passing `filter`. Updates of other pools are ignored.
+    pub fn with_filter(mut self, filter: PoolFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Records every quote served by `PoolRegistry::get_amount_out` and
+    /// `PoolRegistry::get_amount_out_at` in `audit_log`.
+    pub fn with_audit_log(mut self, audit_log: AuditLog) -> Self {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    /// Retries quotes of `PoolRegistry::get_amount_out` failing with
+    /// `SimulationError::PoolNotInitialized` once, on the next update changing the pool's state.
+    /// The outcomes are collected with `PoolRegistry::take_retried_quotes`.
+    ///
+    /// At most `max_pending` quotes wait to be retried: beyond that, the oldest ones are dropped.
+    /// Quotes of removed pools are dropped too.
+    pub fn with_retry_uninitialized(mut self, max_pending: usize) -> Self {
+        self.max_pending_quotes = Some(max_pending);
+        self
+    }
+
+    /// Quotes waiting to be retried, see `PoolRegistry::with_retry_uninitialized`.
+    pub fn pending_quotes(&self) -> Vec<PendingQuote> {
+        self.pending_quotes
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the quotes retried since the last call, in the order they were retried.
+    pub fn take_retried_quotes(&mut self) -> Vec<RetriedQuote> {
+        std::mem::take(&mut self.retried_quotes)
+    }
+
+    /// Applies a block update of the stream: adds new pairs, drops removed ones and records TVL
+    /// and state changes. Pending quotes of pools whose state changed are retried.
+    pub fn apply(&mut self, update: &BlockUpdate) {
+        for id in update.removed_pairs.keys() {
+            self.remove(id);
+        }
+        if !update.removed_pairs.is_empty() {
+            self.pending_quotes
+                .get_mut()
+                .unwrap()
+                .retain(|quote| {
+                    !update
+                        .removed_pairs
+                        .contains_key(&quote.pool)
+                });
+        }
+        for (id, component) in &update.new_pairs {
+            if !self.filter.accepts(id, component) {
+                continue;
+            }
+            // A pair sent again, e.g. after a resync or a warm start, keeps its metadata, state
+            // and the TVL and last update known so far
+            let (metadata, state, tvl, last_updated) = self
+                .remove(id)
+                .map(|pool| (pool.metadata, pool.state, pool.tvl, pool.last_updated))
+                .unwrap_or_default();
+            for token in &component.tokens {
+                self.by_token
+                    .entry(token.address.clone())
+                    .or_default()
+                    .insert(id.clone());
+            }
+            self.pools.insert(
+                id.clone(),
+                PoolInfo {
+                    component: component.clone(),
+                    tvl,
+                    last_updated,
+                    metadata,
+                    state,
+                    spot_prices: None,
+                },
+            );
+        }
+        for (id, tvl) in &update.tvl {
+            if let Some(pool) = self.pools.get_mut(id) {
+                pool.tvl = Some(*tvl);
+            }
+        }
+        for (id, state) in &update.states {
+            if let Some(pool) = self.pools.get_mut(id) {
+                pool.last_updated = Some(update.block_number);
+                pool.state = Some(state.clone_box());
+                pool.spot_prices = None;
+            }
+        }
+        if let Some(block) = &update.block {
+            self.block = Some(block.clone());
+        }
+        self.record_history(update);
+        self.retry_pending_quotes(update);
+    }
+
+    fn retry_pending_quotes(&mut self, update: &BlockUpdate) {
+        let (due, waiting) = std::mem::take(self.pending_quotes.get_mut().unwrap())
+            .into_iter()
+            .partition(|quote: &PendingQuote| update.states.contains_key(&quote.pool));
+        *self.pending_quotes.get_mut().unwrap() = waiting;
+        for quote in due {
+            let result = self.audited(
+                &quote.pool,
+                &quote.amount_in,
+                &quote.token_in,
+                &quote.token_out,
+                quote.idempotency_key.as_deref(),
+                || {
+                    self.quote_latest(
+                        &quote.pool,
+                        quote.amount_in.clone(),
+                        &quote.token_in,
+                        &quote.token_out,
+                    )
+                },
+            );
+            self.retried_quotes.push(RetriedQuote {
+                quote,
+                block_number: update.block_number,
+                result,
+            });
+        }
+    }
+
+    fn record_history(&mut self, update: &BlockUpdate) {
+        let Some(block) = &update.block else {
+            return;
+        };
+        if self.history_capacity == 0 {
+            return;
+        }
+        // States of pools outside the registry's filter are not retained
+        let states = update
+            .states
+            .iter()
+            .filter(|(id, _)| self.pools.contains_key(*id))
+            .map(|(id, state)| (id.clone(), state.clone_box()));
+        let removed = update.removed_pairs.keys().cloned();
+        match self.history.back_mut() {
+            // A block sent again, e.g. after a revert, changes the states of the same block
+            Some(last) if last.block.hash == block.hash => {
+                last.states.extend(states);
+                last.removed.extend(removed);
+            }
+            _ => self.history.push_back(BlockStates {
+                block: block.clone(),
+                states: states.collect(),
+                removed: removed.collect(),
+            }),
+        }
+        while self.history.len() > self.history_capacity {
+            if let Some(oldest) = self.history.pop_front() {
+                // Pairs are removed before the states of the block are set
+                for id in &oldest.removed {
+                    self.history_base.remove(id);
+                }
+                self.history_base.extend(oldest.states);
+            }
+        }
+    }
+
+    /// Ids of the pools whose state didn't change in the `idle_blocks` blocks before `block`,
+    /// sorted by id. Such pools are candidates to free memory for, e.g. with
+    /// `EVMPoolState::offload_storage`.
+    pub fn idle_pools(&self, block: u64, idle_blocks: u64) -> Vec<&str> {
+        let mut ids: Vec<_> = self
+            .pools
+            .iter()
+            .filter(|(_, pool)| {
+                pool.last_updated
+                    .is_some_and(|updated| updated + idle_blocks <= block)
+            })
+            .map(|(id, _)| id.as_str())
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// The retained blocks, oldest first.
+    pub fn retained_blocks(&self) -> Vec<&BlockInfo> {
+        self.history
+            .iter()
+            .map(|states| &states.block)
+            .collect()
+    }
+
+    /// The state of a pool at a retained block. `None` if the block isn't retained, or the pool
+    /// had no state yet or was removed by then.
+    ///
+    /// VM states read contract storage from their simulation engine, which only holds the latest
+    /// block. Quote them with `EVMPoolState::get_amount_out_at` and a `BlockHistory` instead.
+    pub fn state_at(&self, block_hash: &Bytes, id: &str) -> Option<&dyn ProtocolSim> {
+        let index = self
+            .history
+            .iter()
+            .rposition(|states| &states.block.hash == block_hash)?;
+        self.history
+            .range(..=index)
+            .rev()
+            .find_map(|states| match states.states.get(id) {
+                Some(state) => Some(Some(state)),
+                None if states.removed.contains(id) => Some(None),
+                None => None,
+            })
+            .unwrap_or_else(|| self.history_base.get(id))
+            .map(Box::as_ref)
+    }
+
+    /// Quotes a swap on the latest state of a pool. The result carries the latest block, if
+    /// known.
+    ///
+    /// `idempotency_key` identifies the quote in the audit log, see
+    /// `PoolRegistry::with_audit_log`.
+    ///
+    /// If the pool isn't initialized yet, the quote is retried on the next update changing its
+    /// state, see `PoolRegistry::with_retry_uninitialized`.
+    pub fn get_amount_out(
+        &self,
+        id: &str,
+        amount_in: BigUint,
+        token_in: &Token,
+        token_out: &Token,
+        idempotency_key: Option<&str>,
+    ) -> Result<GetAmountOutResult, SimulationError> {
+        if !self.pools.contains_key(id) {
+            return Err(SimulationError::InvalidInput(format!("Unknown pool {id}"), None));
+        }
+        let result = self.audited(id, &amount_in, token_in, token_out, idempotency_key, || {
+            self.quote_latest(id, amount_in.clone(), token_in, token_out)
+        });
+        match self.max_pending_quotes {
+            Some(max_pending) if matches!(result, Err(SimulationError::PoolNotInitialized(_))) => {
+                let mut pending_quotes = self.pending_quotes.lock().unwrap();
+                pending_quotes.push_back(PendingQuote {
+                    pool: id.to_string(),
+                    amount_in,
+                    token_in: token_in.clone(),
+                    token_out: token_out.clone(),
+                    idempotency_key: idempotency_key.map(str::to_string),
+                });
+                while pending_quotes.len() > max_pending {
+                    if let Some(dropped) = pending_quotes.pop_front() {
+                        debug!(pool = dropped.pool, "Dropped pending quote, too many are waiting");
+                    }
+                }
+            }
+            _ => {}
+        }
+        result
+    }
+
+    /// Quotes a swap on the latest state of a pool, with the latest block.
+    fn quote_latest(
+        &self,
+        id: &str,
+        amount_in: BigUint,
+        token_in: &Token,
+        token_out: &Token,
+    ) -> Result<GetAmountOutResult, SimulationError> {
+        let state = self
+            .pools
+            .get(id)
+            .and_then(|pool| pool.state.as_ref())
+            .ok_or_else(|| {
+                SimulationError::InvalidInput(format!("Pool {id} has no state"), None)
+            })?;
+        let result = state.get_amount_out(amount_in, token_in, token_out)?;
+        Ok(match &self.block {
+            Some(block) => result.with_block(block.clone()),
+            None => result,
+        })
+    }
+
+    /// Quotes a swap on a pool as it would have been at a retained block, see
+    /// `PoolRegistry::state_at`. The result carries the block it was quoted at.
+    ///
+    /// Fails for states that may block (see `ProtocolSim::is_blocking`), like VM states: they
+    /// read from a database holding only the latest block, so their quotes at past blocks would
+    /// be wrong. Quote them with `EVMPoolState::get_amount_out_at` instead.
+    ///
+    /// `idempotency_key` identifies the quote in the audit log, see
+    /// `PoolRegistry::with_audit_log`.
+    pub fn get_amount_out_at(
+        &self,
+        block_hash: &Bytes,
+        id: &str,
+        amount_in: BigUint,
+        token_in: &Token,
+        token_out: &Token,
+        idempotency_key: Option<&str>,
+    ) -> Result<GetAmountOutResult, SimulationError> {
+        self.audited(id, &amount_in, token_in, token_out, idempotency_key, || {
+            let block = self
+                .history
+                .iter()
+                .rev()
+                .map(|states| &states.block)
+                .find(|block| &block.hash == block_hash)
+                .ok_or_else(|| {
+                    SimulationError::InvalidInput(
+                        format!("Block {block_hash} is not retained"),
+                        None,
+                    )
+                })?;
+            let state = self
+                .state_at(block_hash, id)
+                .ok_or_else(|| {
+                    SimulationError::InvalidInput(
+                        format!("Pool {id} has no state at block {block_hash}"),
+                        None,
+                    )
+                })?;
+            if state.is_blocking() {
+                return Err(SimulationError::InvalidInput(
+                    format!("Pool {id} can only be quoted at the latest block"),
+                    None,
+                ));
+            }
+            Ok(state
+                .get_amount_out(amount_in.clone(), token_in, token_out)?
+                .with_block(block.clone()))
+        })
+    }
+
+    /// Runs `quote`, recording it in the audit log if there is one.
+    fn audited(
+        &self,
+        id: &str,
+        amount_in: &BigUint,
+        token_in: &Token,
+        token_out: &Token,
+        idempotency_key: Option<&str>,
+        quote: impl FnOnce() -> Result<GetAmountOutResult, SimulationError>,
+    ) -> Result<GetAmountOutResult, SimulationError> {
+        let Some(audit_log) = &self.audit_log else {
+            return quote();
+        };
+        // Pools removed since a retained block can still be quoted at that block
+        let protocol_system = self
+            .pools
+            .get(id)
+            .map_or("", |pool| pool.component.protocol_system.as_str());
+        let request = QuoteRequest {
+            idempotency_key,
+            pool: id,
+            protocol_system,
+            amount_in,
+            token_in,
+            token_out,
+        };
+        audit_log.quote(request, quote)
+    }
+
+    /// Returns the spot prices of every ordered token pair of every pool with a known state.
+    ///
+    /// Prices are cached per pool and only recomputed for pools whose state changed since the
+    /// last call. Analytical states are priced inline. States that may block (see
+    /// `ProtocolSim::is_blocking`), like VM pools sharing a simulation engine, are priced together
+    /// on a batch of threads. Pairs whose price can't be computed are omitted, as are the pools
+    /// skipped because their protocol used up its budget (see `PoolRegistry::with_budget`).
+    pub fn compute_all_spot_prices(&mut self, block: u64) -> SpotPrices {
+        if let Some(budget) = &self.budget {
+            if self
+                .block_budget
+                .as_ref()
+                .is_none_or(|block_budget| block_budget.report().block != block)
+            {
+                self.block_budget = Some(budget.start_block(block));
+            }
+        }
+        let budget = self.block_budget.take().map(Mutex::new);
+        let mut outdated: Vec<_> = self
+            .pools
+            .iter_mut()
+            .filter(|(_, pool)| pool.state.is_some() && pool.spot_prices.is_none())
+            .collect();
+        match self.seed {
+            Some(seed) => outdated.sort_by_cached_key(|(id, _)| pricing_key(seed, block, id)),
+            None => outdated.sort_unstable_by(|(a, _), (b, _)| a.cmp(b)),
+        }
+        let (mut blocking, analytical): (Vec<_>, Vec<_>) = outdated
+            .into_iter()
+            .map(|(_, pool)| pool)
+            .partition(|pool| {
+                pool.state
+                    .as_ref()
+                    .is_some_and(|state| state.is_blocking())
+            });
+
+        for pool in analytical {
+            pool.update_spot_prices_within(budget.as_ref());
+        }
+        if !blocking.is_empty() {
+            let threads = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+            let chunk_size = blocking.len().div_ceil(threads);
+            let budget = budget.as_ref();
+            thread::scope(|scope| {
+                for chunk in blocking.chunks_mut(chunk_size) {
+                    scope.spawn(move || {
+                        for pool in chunk {
+                            pool.update_spot_prices_within(budget);
+                        }
+                    });
+                }
+            });
+        }
+        self.block_budget = budget.map(|budget| {
+            budget
+                .into_inner()
+                .unwrap_or_else(|e| e.into_inner())
+        });
+
+        let mut prices: HashMap<Pair, HashMap<String, f64>> = HashMap::new();
+        for (id, pool) in &self.pools {
+            for (pair, price) in pool.spot_prices.iter().flatten() {
+                prices
+                    .entry(pair.clone())
+                    .or_default()
+                    .insert(id.clone(), *price);
+            }
+        }
+        SpotPrices { block, prices }
+    }
+
+    /// Ids of the pools containing `token`, or its native or wrapped equivalent on `chain`.
+    fn pools_with(&self, token: &Bytes, chain: Option<Chain>) -> HashSet<&String> {
+        let mut tokens = vec![token.clone()];
+        if let Some(chain) = chain {
+            let canonical = canonical_token(chain, token);
+            if wrapped_native(chain).as_ref() == Some(&canonical) {
+                // Pools may list the native token under either of its addresses
+                tokens = self
+                    .by_token
+                    .keys()
+                    .filter(|address| is_native(address))
+                    .cloned()
+                    .chain([canonical])
+                    .collect();
+            }
+        }
+        tokens
+            .iter()
+            .filter_map(|token| self.by_token.get(token))
+            .flatten()
+            .collect()
+    }
+
+    fn remove(&mut self, id: &str) -> Option<PoolInfo> {
+        let pool = self.pools.remove(id)?;
+        for token in &pool.component.tokens {
+            if let Some(ids) = self.by_token.get_mut(&token.address) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    self.by_token.remove(&token.address);
+                }
+            }
+        }
+        Some(pool)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pools.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pools.is_empty()
+    }
+
+    pub fn get(&self, id: &str) -> Option<PoolEntry<'_>> {
+        self.pools
+            .get_key_value(id)
+            .map(|(id, pool)| Self::entry(id, pool))
+    }
+
+    /// The latest state of a pool, `None` if the pool is unknown or had no state yet.
+    pub fn state(&self, id: &str) -> Option<&dyn ProtocolSim> {
+        self.pools.get(id)?.state.as_deref()
+    }
+
+    /// Attaches `value` to a pool under `key`, replacing any previous value of the key. Returns
+    /// `false` if the pool is unknown.
+    pub fn set_metadata<T: Any + Send + Sync>(&mut self, id: &str, key: &str, value: T) -> bool {
+        match self.pools.get_mut(id) {
+            Some(pool) => {
+                pool.metadata
+                    .insert(key.to_string(), Box::new(value));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the metadata of a pool under `key`, if it is set and of type `T`.
+    pub fn metadata<T: Any>(&self, id: &str, key: &str) -> Option<&T> {
+        self.pools
+            .get(id)?
+            .metadata
+            .get(key)?
+            .downcast_ref()
+    }
+
+    /// Removes the metadata of a pool under `key`. Returns whether it was set.
+    pub fn remove_metadata(&mut self, id: &str, key: &str) -> bool {
+        self.pools
+            .get_mut(id)
+            .is_some_and(|pool| pool.metadata.remove(key).is_some())
+    }
+
+    fn entry<'a>(id: &'a str, pool: &'a PoolInfo) -> PoolEntry<'a> {
+        PoolEntry { id, component: &pool.component, tvl: pool.tvl, last_updated: pool.last_updated }
+    }
+
+    /// Returns the page of pools matching `query`.
+    pub fn query(&self, query: &PoolQuery) -> PoolPage<'_> {
+        let candidates: Box<dyn Iterator<Item = &String>> = match &query.pair {
+            Some((token_a, token_b)) => {
+                let a = self.pools_with(token_a, query.chain);
+                let b = self.pools_with(token_b, query.chain);
+                Box::new(
+                    a.into_iter()
+                        .filter(move |id| b.contains(id)),
+                )
+            }
+            None => Box::new(self.pools.keys()),
+        };
+        let mut pools: Vec<_> = candidates
+            .filter_map(|id| self.pools.get_key_value(id))
+            .filter(|(_, pool)| {
+                query
+                    .chain
+                    .map_or(true, |chain| pool.component.chain == chain)
+            })
+            .filter(|(_, pool)| {
+                query
+                    .protocol_system
+                    .as_ref()
+                    .map_or(true, |protocol| &pool.component.protocol_system == protocol)
+            })
+            .filter(|(_, pool)| {
+                query
+                    .metadata_key
+                    .as_ref()
+                    .map_or(true, |key| pool.metadata.contains_key(key))
+            })
+            .map(|(id, pool)| Self::entry(id, pool))
+            .collect();
+
+        pools.sort_unstable_by(|a, b| {
+            let order = match query.sort {
+                PoolSort::Id => Ordering::Equal,
+                // Pools without TVL last. `total_cmp` keeps the order total, even with NaNs.
+                PoolSort::Tvl => match (a.tvl, b.tvl) {
+                    (Some(a), Some(b)) => b.total_cmp(&a),
+                    (Some(_), None) => Ordering::Less,
+                    (None, Some(_)) => Ordering::Greater,
+                    (None, None) => Ordering::Equal,
+                },
+                PoolSort::LastUpdated => b.last_updated.cmp(&a.last_updated),
+            };
+            order.then_with(|| a.id.cmp(b.id))
+        });
+
+        let total = pools.len();
+        let pools = pools
+            .into_iter()
+            .skip(query.offset)
+            .take(query.limit.unwrap_or(usize::MAX))
+            .collect();
+        PoolPage { pools, total }
+    }
+}
+
+/// Sort key of a pool in the seeded pricing order of `block`, see `PoolRegistry::with_seed`.
+fn pricing_key(seed: u64, block: u64, id: &str) -> B256 {
+    keccak256([seed.to_be_bytes().as_slice(), &block.to_be_bytes(), id.as_bytes()].concat())
+}
+
+/// Spot prices of a state for every pair of `tokens`, in canonical order, see `Pair::canonical`.
+fn spot_prices(state: &dyn ProtocolSim, tokens: &[Token]) -> HashMap<Pair, f64> {
+    let mut prices = HashMap::new();
+    for (i, token_a) in tokens.iter().enumerate() {
+        for token_b in &tokens[i + 1..] {
+            if token_a.address == token_b.address {
+                continue;
+            }
+            let pair = Pair::canonical(token_a.address.clone(), token_b.address.clone());
+            let (base, quote) =
+                if pair.base == token_a.address { (token_a, token_b) } else { (token_b, token_a) };
+            match state.spot_price(base, quote) {
+                Ok(price) => {
+                    prices.insert(pair, price);
+                }
+                Err(e) => debug!(?e, "Skipping spot price"),
+            }
+        }
+    }
+    prices
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{any::Any, str::FromStr, sync::Arc, time::Duration};
+
+    use tycho_core::dto::ProtocolStateDelta;
+
+    use super::*;
+    use crate::{
+        models::{Balances, Token},
+        protocol::{
+            audit::{AuditError, AuditSink, QuoteRecord},
+            errors::TransitionError,
+            fixtures::{component, DAI, USDC, WETH},
+            state::{MockProtocolSim, ProtocolSim},
+        },
+    };
+
+    /// A state quoting `price` for every pair
+    fn state(price: f64) -> Box<dyn ProtocolSim> {
+        let mut mock = MockProtocolSim::new();
+        mock.expect_spot_price()
+            .returning(move |_, _| Ok(price));
+        mock.expect_clone_box()
+            .returning(move || state(price));
+        Box::new(mock)
+    }
+
+    /// A state quoting `amount_out` for any swap
+    fn quoting_state(amount_out: u32) -> Box<dyn ProtocolSim> {
+        let mut mock = MockProtocolSim::new();
+        mock.expect_get_amount_out()
+            .returning(move |_, _, _| {
+                Ok(GetAmountOutResult::new(
+                    BigUint::from(amount_out),
+                    BigUint::ZERO,
+                    quoting_state(amount_out),
+                ))
+            });
+        mock.expect_clone_box()
+            .returning(move || quoting_state(amount_out));
+        Box::new(mock)
+    }
+
+    /// A state that may block, like VM states, quoting 1 for any swap
+    #[derive(Debug, Clone)]
+    struct BlockingState;
+
+    /// Gas `BlockingState` uses to compute its spot prices
+    const BLOCKING_STATE_GAS: u64 = 70_000;
+
+    impl ProtocolSim for BlockingState {
+        fn fee(&self) -> f64 {
+            0.0
+        }
+
+        fn spot_price(&self, _base: &Token, _quote: &Token) -> Result<f64, SimulationError> {
+            Ok(1.0)
+        }
+
+        fn spot_price_gas(&self) -> u64 {
+            BLOCKING_STATE_GAS
+        }
+
+        fn get_amount_out(
+            &self,
+            _amount_in: BigUint,
+            _token_in: &Token,
+            _token_out: &Token,
+        ) -> Result<GetAmountOutResult, SimulationError> {
+            Ok(GetAmountOutResult::new(BigUint::from(1u8), BigUint::ZERO, self.clone_box()))
+        }
+
+        fn is_blocking(&self) -> bool {
+            true
+        }
+
+        fn state_fingerprint(&self) -> u64 {
+            0
+        }
+
+        fn delta_transition(
+            &mut self,
+            _delta: ProtocolStateDelta,
+            _tokens: &HashMap<Bytes, Token>,
+            _balances: &Balances,
+        ) -> Result<(), TransitionError<String>> {
+            Ok(())
+        }
+
+        fn clone_box(&self) -> Box<dyn ProtocolSim> {
+            Box::new(self.clone())
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+
+        fn eq(&self, other: &dyn ProtocolSim) -> bool {
+            other.as_any().is::<Self>()
+        }
+    }
+
+    fn registry() -> PoolRegistry {
+        let pairs = HashMap::from([
+            ("0x01".to_string(), component("0x01", "uniswap_v2", &[USDC, WETH])),
+            ("0x02".to_string(), component("0x02", "uniswap_v3", &[USDC, WETH])),
+            ("0x03".to_string(), component("0x03", "uniswap_v3", &[DAI, WETH])),
+            ("0x04".to_string(), component("0x04", "vm:curve", &[USDC, WETH, DAI])),
+        ]);
+        let mut registry = PoolRegistry::new();
+        registry.apply(&BlockUpdate::new(1, HashMap::new(), pairs).set_tvl(HashMap::from([
+            ("0x01".to_string(), 10.0),
+            ("0x02".to_string(), 30.0),
+            ("0x03".to_string(), 20.0),
+        ])));
+        registry.apply(&BlockUpdate::new(
+            2,
+            HashMap::from([("0x03".to_string(), state(1.0)), ("0x01".to_string(), state(1.0))]),
+            HashMap::new(),
+        ));
+        registry.apply(&BlockUpdate::new(
+            3,
+            HashMap::from([("0x04".to_string(), state(2.0))]),
+            HashMap::new(),
+        ));
+        registry
+    }
+
+    fn ids(page: &PoolPage) -> Vec<&str> {
+        page.pools
+            .iter()
+            .map(|pool| pool.id)
+            .collect()
+    }
+
+    #[test]
+    fn test_query_by_pair() {
+        let registry = registry();
+        let query =
+            PoolQuery::new().pair(Bytes::from_str(WETH).unwrap(), Bytes::from_str(USDC).unwrap());
+
+        let page = registry.query(&query);
+
+        assert_eq!(ids(&page), vec!["0x01", "0x02", "0x04"]);
+        assert_eq!(page.total, 3);
+    }
+
+    #[test]
+    fn test_query_by_pair_with_native_token() {
+        const ETH: &str = "0x0000000000000000000000000000000000000000";
+        let mut registry = registry();
+        registry.apply(&BlockUpdate::new(
+            4,
+            HashMap::new(),
+            HashMap::from([("0x05".to_string(), component("0x05", "uniswap_v4", &[ETH, USDC]))]),
+        ));
+        let weth_usdc =
+            PoolQuery::new().pair(Bytes::from_str(WETH).unwrap(), Bytes::from_str(USDC).unwrap());
+        let eth_usdc =
+            PoolQuery::new().pair(Bytes::from_str(USDC).unwrap(), Bytes::from_str(ETH).unwrap());
+
+        assert_eq!(ids(&registry.query(&weth_usdc)), vec!["0x01", "0x02", "0x04"]);
+        assert_eq!(ids(&registry.query(&eth_usdc)), vec!["0x05"]);
+        // On a chain, ETH and WETH are the same token
+        let all = vec!["0x01", "0x02", "0x04", "0x05"];
+        assert_eq!(ids(&registry.query(&weth_usdc.chain(Chain::Ethereum))), all);
+        assert_eq!(ids(&registry.query(&eth_usdc.clone().chain(Chain::Ethereum))), all);
+        assert!(registry
+            .query(&eth_usdc.chain(Chain::Base))
+            .pools
+            .is_empty());
+    }
+
+    #[test]
+    fn test_query_by_protocol_sorted_by_tvl() {
+        let registry = registry();
+
+        let page = registry.query(&PoolQuery::new().sort(PoolSort::Tvl));
+        assert_eq!(ids(&page), vec!["0x02", "0x03", "0x01", "0x04"]);
+
+        let page = registry.query(
+            &PoolQuery::new()
+                .protocol_system("uniswap_v3")
+                .sort(PoolSort::Tvl),
+        );
+        assert_eq!(ids(&page), vec!["0x02", "0x03"]);
+    }
+
+    #[test]
+    fn test_pairs_sent_again_keep_tvl_and_last_updated() {
+        let mut registry = registry();
+
+        registry.apply(&BlockUpdate::new(
+            4,
+            HashMap::new(),
+            HashMap::from([
+                ("0x01".to_string(), component("0x01", "uniswap_v2", &[USDC, WETH])),
+                ("0x03".to_string(), component("0x03", "uniswap_v3", &[DAI, WETH])),
+            ]),
+        ));
+
+        let entry = registry.get("0x01").unwrap();
+        assert_eq!((entry.tvl, entry.last_updated), (Some(10.0), Some(2)));
+        let page = registry.query(&PoolQuery::new().sort(PoolSort::Tvl));
+        assert_eq!(ids(&page), vec!["0x02", "0x03", "0x01", "0x04"]);
+    }
+
+    #[test]
+    fn test_query_sorted_by_last_updated_paginated() {
+        let registry = registry();
+        let query = |page| {
+            registry.query(
+                &PoolQuery::new()
+                    .sort(PoolSort::LastUpdated)
+                    .page(page, 3),
+            )
+        };
+
+        let first = query(0);
+        let second = query(1);
+
+        assert_eq!(ids(&first), vec!["0x04", "0x01", "0x03"]);
+        assert_eq!(ids(&second), vec!["0x02"]);
+        assert_eq!(second.total, 4);
+        assert!(query(2).pools.is_empty());
+    }
+
+    #[test]
+    fn test_removed_pairs() {
+        let mut registry = registry();
+        let removed =
+            HashMap::from([("0x01".to_string(), component("0x01", "uniswap_v2", &[USDC, WETH]))]);
+
+        registry
+            .apply(&BlockUpdate::new(4, HashMap::new(), HashMap::new()).set_removed_pairs(removed));
+
+        assert_eq!(registry.len(), 3);
+        assert!(registry.get("0x01").is_none());
+        let query =
+            PoolQuery::new().pair(Bytes::from_str(USDC).unwrap(), Bytes::from_str(WETH).unwrap());
+        assert_eq!(ids(&registry.query(&query)), vec!["0x02", "0x04"]);
+    }
+
+    #[test]
+    fn test_idle_pools() {
+        let registry = registry();
+
+        assert_eq!(registry.idle_pools(3, 1), vec!["0x01", "0x03"]);
+        assert_eq!(registry.idle_pools(4, 1), vec!["0x01", "0x03", "0x04"]);
+        assert!(registry.idle_pools(3, 2).is_empty());
+    }
+
+    #[test]
+    fn test_metadata() {
+        #[derive(Debug, PartialEq)]
+        struct Risk(u8);
+
+        let mut registry = registry();
+        assert!(registry.set_metadata("0x02", "label", "blue chip".to_string()));
+        assert!(registry.set_metadata("0x03", "risk", Risk(3)));
+        assert!(!registry.set_metadata("0x05", "risk", Risk(1)));
+
+        // survives state updates and pairs sent again
+        registry.apply(&BlockUpdate::new(
+            4,
+            HashMap::from([("0x03".to_string(), state(1.0))]),
+            HashMap::from([("0x02".to_string(), component("0x02", "uniswap_v3", &[USDC, WETH]))]),
+        ));
+
+        assert_eq!(registry.metadata::<String>("0x02", "label"), Some(&"blue chip".to_string()));
+        assert_eq!(registry.metadata::<Risk>("0x03", "risk"), Some(&Risk(3)));
+        assert_eq!(registry.metadata::<u8>("0x03", "risk"), None);
+        assert_eq!(ids(&registry.query(&PoolQuery::new().with_metadata("risk"))), vec!["0x03"]);
+
+        let removed =
+            HashMap::from([("0x03".to_string(), component("0x03", "uniswap_v3", &[DAI, WETH]))]);
+        registry
+            .apply(&BlockUpdate::new(5, HashMap::new(), HashMap::new()).set_removed_pairs(removed));
+        assert_eq!(registry.metadata::<Risk>("0x03", "risk"), None);
+        assert!(registry.remove_metadata("0x02", "label"));
+        assert!(!registry.remove_metadata("0x02", "label"));
+    }
+
+    #[test]
+    fn test_compute_all_spot_prices() {
+        let mut registry = registry();
+        let usdc_weth =
+            Pair::canonical(Bytes::from_str(USDC).unwrap(), Bytes::from_str(WETH).unwrap());
+        let dai_weth =
+            Pair::canonical(Bytes::from_str(WETH).unwrap(), Bytes::from_str(DAI).unwrap());
+
+        let spot_prices = registry.compute_all_spot_prices(3);
+
+        assert_eq!(spot_prices.block, 3);
+        // 0x02 never received a state
+        assert_eq!(
+            spot_prices.get(&usdc_weth),
+            Some(&HashMap::from([("0x01".to_string(), 1.0), ("0x04".to_string(), 2.0)]))
+        );
+        assert_eq!(
+            spot_prices.get(&dai_weth),
+            Some(&HashMap::from([("0x03".to_string(), 1.0), ("0x04".to_string(), 2.0)]))
+        );
+        // One price per pair of tokens: USDC/WETH, DAI/WETH and DAI/USDC
+        assert_eq!(spot_prices.prices.len(), 3);
+        assert!(spot_prices
+            .prices
+            .keys()
+            .all(Pair::is_canonical));
+
+        registry.apply(&BlockUpdate::new(
+            4,
+            HashMap::from([("0x01".to_string(), state(3.0))]),
+            HashMap::new(),
+        ));
+        let spot_prices = registry.compute_all_spot_prices(4);
+
+        assert_eq!(spot_prices.get(&usdc_weth).unwrap()["0x01"], 3.0);
+        assert_eq!(spot_prices.get(&dai_weth).unwrap()["0x04"], 2.0);
+    }
+
+    #[test]
+    fn test_compute_all_spot_prices_within_budget() {
+        let mut registry = registry().with_budget(
+            SimulationBudget::new()
+                .with_time(Duration::from_secs(3600))
+                .with_share("vm:curve", 0.0),
+        );
+        let usdc_weth =
+            Pair::canonical(Bytes::from_str(USDC).unwrap(), Bytes::from_str(WETH).unwrap());
+        assert!(registry.budget_report().is_none());
+
+        let spot_prices = registry.compute_all_spot_prices(3);
+
+        // 0x04 is skipped, vm:curve has no share of the budget
+        assert_eq!(spot_prices.get(&usdc_weth), Some(&HashMap::from([("0x01".to_string(), 1.0)])));
+        let report = registry.budget_report().unwrap();
+        assert_eq!(report.block, 3);
+        assert_eq!(report.skipped(), 1);
+        assert!(report.protocols["vm:curve"].exhausted);
+        assert_eq!(report.protocols["uniswap_v2"].simulations, 1);
+        assert_eq!(report.protocols["uniswap_v3"].simulations, 1);
+    }
+
+    #[test]
+    fn test_gas_budget_per_block() {
+        let mut registry =
+            PoolRegistry::new().with_budget(SimulationBudget::new().with_gas(100_000));
+        let pairs = HashMap::from([
+            ("0x01".to_string(), component("0x01", "vm:curve", &[USDC, WETH])),
+            ("0x02".to_string(), component("0x02", "vm:curve", &[USDC, WETH])),
+        ]);
+        let update = |block: u64, id: &str| {
+            BlockUpdate::new(
+                block,
+                HashMap::from([(id.to_string(), Box::new(BlockingState) as Box<dyn ProtocolSim>)]),
+                HashMap::new(),
+            )
+        };
+        registry.apply(&BlockUpdate::new(1, HashMap::new(), pairs));
+
+        registry.apply(&update(2, "0x01"));
+        registry.compute_all_spot_prices(10);
+        assert_eq!(registry.budget_report().unwrap().gas(), BLOCKING_STATE_GAS);
+
+        // Calls for the same block share the budget, which is used up after this one
+        registry.apply(&update(3, "0x02"));
+        registry.compute_all_spot_prices(10);
+        let report = registry.budget_report().unwrap();
+        assert_eq!(report.gas(), 2 * BLOCKING_STATE_GAS);
+        assert_eq!(report.protocols["vm:curve"].simulations, 2);
+
+        registry.apply(&update(4, "0x01"));
+        let spot_prices = registry.compute_all_spot_prices(10);
+        assert_eq!(
+            registry
+                .budget_report()
+                .unwrap()
+                .skipped(),
+            1
+        );
+        let usdc_weth =
+            Pair::canonical(Bytes::from_str(USDC).unwrap(), Bytes::from_str(WETH).unwrap());
+        assert!(!spot_prices
+            .get(&usdc_weth)
+            .unwrap()
+            .contains_key("0x01"));
+
+        // The next block starts with a fresh budget
+        let spot_prices = registry.compute_all_spot_prices(11);
+        let report = registry.budget_report().unwrap();
+        assert_eq!((report.block, report.gas(), report.skipped()), (11, BLOCKING_STATE_GAS, 0));
+        assert!(spot_prices
+            .get(&usdc_weth)
+            .unwrap()
+            .contains_key("0x01"));
+    }
+
+    #[test]
+    fn test_seeded_pricing_order() {
+        let order = |seed: u64, block: u64| {
+            let mut ids: Vec<_> = (0..16u8)
+                .map(|i| format!("0x{i:02x}"))
+                .collect();
+            ids.sort_by_cached_key(|id| pricing_key(seed, block, id));
+            ids
+        };
+
+        assert_eq!(order(1, 10), order(1, 10));
+        assert_ne!(order(1, 10), order(1, 11));
+        assert_ne!(order(1, 10), order(2, 10));
+    }
+
+    #[test]
+    fn test_get_amount_out_at() {
+        let mut registry = PoolRegistry::new().with_history(2);
+        let pairs = HashMap::from([
+            ("0x01".to_string(), component("0x01", "uniswap_v2", &[USDC, WETH])),
+            ("0x02".to_string(), component("0x02", "uniswap_v3", &[USDC, WETH])),
+        ]);
+        let block =
+            |number: u64| BlockInfo { number, hash: Bytes::from(vec![number as u8]), timestamp: 0 };
+        let usdc = Token::new(USDC, 6, "USDC", BigUint::from(10_000u32));
+        let weth = Token::new(WETH, 18, "WETH", BigUint::from(10_000u32));
+        let quote = |registry: &PoolRegistry, number: u64, id: &str| {
+            registry
+                .get_amount_out_at(&block(number).hash, id, BigUint::from(1u8), &usdc, &weth, None)
+                .map(|result| (result.amount, result.block.unwrap().number))
+        };
+
+        registry.apply(
+            &BlockUpdate::new(
+                1,
+                HashMap::from([
+                    ("0x01".to_string(), quoting_state(10)),
+                    ("0x02".to_string(), quoting_state(20)),
+                ]),
+                pairs,
+            )
+            .set_block(block(1)),
+        );
+        registry.apply(&BlockUpdate::new(2, HashMap::new(), HashMap::new()).set_block(block(2)));
+        registry.apply(
+            &BlockUpdate::new(
+                3,
+                HashMap::from([("0x01".to_string(), quoting_state(30))]),
+                HashMap::new(),
+            )
+            .set_block(block(3)),
+        );
+
+        assert_eq!(
+            registry
+                .retained_blocks()
+                .iter()
+                .map(|block| block.number)
+                .collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert!(quote(&registry, 1, "0x01").is_err());
+        assert_eq!(quote(&registry, 2, "0x01").unwrap(), (BigUint::from(10u8), 2));
+        assert_eq!(quote(&registry, 3, "0x01").unwrap(), (BigUint::from(30u8), 3));
+        assert_eq!(quote(&registry, 3, "0x02").unwrap(), (BigUint::from(20u8), 3));
+        assert!(quote(&registry, 3, "0x03").is_err());
+    }
+
+    #[test]
+    fn test_history_of_removed_pool() {
+        let mut registry = PoolRegistry::new().with_history(2);
+        let block =
+            |number: u64| BlockInfo { number, hash: Bytes::from(vec![number as u8]), timestamp: 0 };
+        let pool = component("0x01", "uniswap_v2", &[USDC, WETH]);
+        registry.apply(
+            &BlockUpdate::new(
+                1,
+                HashMap::from([("0x01".to_string(), quoting_state(10))]),
+                HashMap::from([("0x01".to_string(), pool.clone())]),
+            )
+            .set_block(block(1)),
+        );
+        registry.apply(&BlockUpdate::new(2, HashMap::new(), HashMap::new()).set_block(block(2)));
+        registry.apply(
+            &BlockUpdate::new(3, HashMap::new(), HashMap::new())
+                .set_removed_pairs(HashMap::from([("0x01".to_string(), pool)]))
+                .set_block(block(3)),
+        );
+
+        // Block 1 moved to the base, the pool can still be quoted at block 2
+        assert!(registry
+            .state_at(&block(2).hash, "0x01")
+            .is_some());
+        assert!(registry
+            .state_at(&block(3).hash, "0x01")
+            .is_none());
+
+        registry.apply(&BlockUpdate::new(4, HashMap::new(), HashMap::new()).set_block(block(4)));
+        registry.apply(&BlockUpdate::new(5, HashMap::new(), HashMap::new()).set_block(block(5)));
+
+        assert!(registry.history_base.is_empty());
+        assert!(registry
+            .state_at(&block(5).hash, "0x01")
+            .is_none());
+    }
+
+    #[test]
+    fn test_get_amount_out_at_blocking_state() {
+        let mut registry = PoolRegistry::new().with_history(1);
+        let block = BlockInfo { number: 1, hash: Bytes::from(vec![1]), timestamp: 0 };
+        let usdc = Token::new(USDC, 6, "USDC", BigUint::from(10_000u32));
+        let weth = Token::new(WETH, 18, "WETH", BigUint::from(10_000u32));
+        registry.apply(
+            &BlockUpdate::new(
+                1,
+                HashMap::from([(
+                    "0x01".to_string(),
+                    Box::new(BlockingState) as Box<dyn ProtocolSim>,
+                )]),
+                HashMap::from([("0x01".to_string(), component("0x01", "vm:curve", &[USDC, WETH]))]),
+            )
+            .set_block(block.clone()),
+        );
+
+        let result =
+            registry.get_amount_out_at(&block.hash, "0x01", BigUint::from(1u8), &usdc, &weth, None);
+
+        assert!(matches!(result, Err(SimulationError::InvalidInput(_, None))));
+        assert!(registry
+            .get_amount_out("0x01", BigUint::from(1u8), &usdc, &weth, None)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_retry_uninitialized() {
+        let mut registry = PoolRegistry::new().with_retry_uninitialized(10);
+        let usdc = Token::new(USDC, 6, "USDC", BigUint::from(10_000u32));
+        let weth = Token::new(WETH, 18, "WETH", BigUint::from(10_000u32));
+        let mut uninitialized = MockProtocolSim::new();
+        uninitialized
+            .expect_get_amount_out()
+            .returning(|_, _, _| Err(SimulationError::PoolNotInitialized("No reserves".into())));
+        registry.apply(&BlockUpdate::new(
+            1,
+            HashMap::from([("0x01".to_string(), Box::new(uninitialized) as Box<dyn ProtocolSim>)]),
+            HashMap::from([("0x01".to_string(), component("0x01", "uniswap_v2", &[USDC, WETH]))]),
+        ));
+
+        let result = registry.get_amount_out("0x01", BigUint::from(1u8), &usdc, &weth, Some("a"));
+
+        assert!(matches!(result, Err(SimulationError::PoolNotInitialized(_))));
+        assert_eq!(registry.pending_quotes().len(), 1);
+
+        // Updates not changing the pool's state don't retry the quote
+        registry.apply(&BlockUpdate::new(2, HashMap::new(), HashMap::new()));
+        assert!(registry
+            .take_retried_quotes()
+            .is_empty());
+
+        registry.apply(&BlockUpdate::new(
+            3,
+            HashMap::from([("0x01".to_string(), quoting_state(10))]),
+            HashMap::new(),
+        ));
+        let retried = registry.take_retried_quotes();
+
+        assert_eq!(retried.len(), 1);
+        assert_eq!(
+            retried[0]
+                .quote
+                .idempotency_key
+                .as_deref(),
+            Some("a")
+        );
+        assert_eq!(retried[0].block_number, 3);
+        assert_eq!(
+            retried[0]
+                .result
+                .as_ref()
+                .unwrap()
+                .amount,
+            BigUint::from(10u8)
+        );
+        assert!(registry.pending_quotes().is_empty());
+        assert!(registry
+            .take_retried_quotes()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_pending_quotes_dropped() {
+        let mut registry = PoolRegistry::new().with_retry_uninitialized(2);
+        let usdc = Token::new(USDC, 6, "USDC", BigUint::from(10_000u32));
+        let weth = Token::new(WETH, 18, "WETH", BigUint::from(10_000u32));
+        let mut uninitialized = MockProtocolSim::new();
+        uninitialized
+            .expect_get_amount_out()
+            .returning(|_, _, _| Err(SimulationError::PoolNotInitialized("No reserves".into())));
+        let pool = component("0x01", "uniswap_v2", &[USDC, WETH]);
+        registry.apply(&BlockUpdate::new(
+            1,
+            HashMap::from([("0x01".to_string(), Box::new(uninitialized) as Box<dyn ProtocolSim>)]),
+            HashMap::from([("0x01".to_string(), pool.clone())]),
+        ));
+
+        for key in ["a", "b", "c"] {
+            let _ = registry.get_amount_out("0x01", BigUint::from(1u8), &usdc, &weth, Some(key));
+        }
+
+        // The oldest quote was dropped
+        let keys: Vec<_> = registry
+            .pending_quotes()
+            .into_iter()
+            .map(|quote| quote.idempotency_key.unwrap())
+            .collect();
+        assert_eq!(keys, ["b", "c"]);
+
+        registry.apply(
+            &BlockUpdate::new(2, HashMap::new(), HashMap::new())
+                .set_removed_pairs(HashMap::from([("0x01".to_string(), pool)])),
+        );
+
+        assert!(registry.pending_quotes().is_empty());
+    }
+
+    /// Collects audit records in memory
+    #[derive(Debug, Default, Clone)]
+    struct MemorySink(Arc<Mutex<Vec<QuoteRecord>>>);
+
+    impl AuditSink for MemorySink {
+        fn record(&self, record: &QuoteRecord) -> Result<(), AuditError> {
+            self.0
+                .lock()
+                .unwrap()
+                .push(record.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_get_amount_out_with_audit_log() {
+        let sink = MemorySink::default();
+        let mut registry = PoolRegistry::new().with_audit_log(AuditLog::new(sink.clone()));
+        let block = BlockInfo { number: 5, hash: Bytes::from(vec![5]), timestamp: 0 };
+        registry.apply(
+            &BlockUpdate::new(
+                5,
+                HashMap::from([("0x01".to_string(), quoting_state(10))]),
+                HashMap::from([
+                    ("0x01".to_string(), component("0x01", "uniswap_v2", &[USDC, WETH])),
+                    ("0x02".to_string(), component("0x02", "uniswap_v3", &[USDC, WETH])),
+                ]),
+            )
+            .set_block(block.clone()),
+        );
+        let usdc = Token::new(USDC, 6, "USDC", BigUint::from(10_000u32));
+        let weth = Token::new(WETH, 18, "WETH", BigUint::from(10_000u32));
+
+        let result = registry
+            .get_amount_out("0x01", BigUint::from(1u8), &usdc, &weth, Some("order-1"))
+            .unwrap();
+        // No state yet
+        assert!(registry
+            .get_amount_out("0x02", BigUint::from(1u8), &usdc, &weth, Some("order-2"))
+            .is_err());
+        // Unknown pools are rejected before quoting
+        assert!(registry
+            .get_amount_out("0x09", BigUint::from(1u8), &usdc, &weth, None)
+            .is_err());
+        // Without history, past blocks can't be quoted, but the attempt is recorded
+        assert!(registry
+            .get_amount_out_at(
+                &block.hash,
+                "0x01",
+                BigUint::from(1u8),
+                &usdc,
+                &weth,
+                Some("order-3")
+            )
+            .is_err());
+
+        assert_eq!(result.amount, BigUint::from(10u8));
+        assert_eq!(result.block, Some(block.clone()));
+        let records = sink.0.lock().unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].idempotency_key.as_deref(), Some("order-1"));
+        assert_eq!(records[0].protocol_system, "uniswap_v2");
+        assert_eq!(records[0].block_hash, Some(block.hash));
+        assert_eq!(records[0].amount_out.as_deref(), Some("10"));
+        assert_eq!((records[1].pool.as_str(), records[1].amount_out.as_ref()), ("0x02", None));
+        assert_eq!(records[2].idempotency_key.as_deref(), Some("order-3"));
+    }
+}
@@ -0,0 +1,162 @@
+//! Rounding of simulated amounts
+//!
+//! On-chain protocol math rounds integer results down. `RoundingPolicy::Floor` reproduces this
+//! and is the default; it must be used for anything compared against or executed on chain.
+//! `RoundingPolicy::Nearest` rounds to the closest integer instead, which gives slightly more
+//! accurate amounts for display and analytics.
+//!
+//! The policy is set per thread for the duration of a closure, see `with_rounding_policy`, so
+//! consumers quoting concurrently don't affect each other. It applies to the final divisions of the
+//! analytical protocol implementations, e.g. the amount out of Uniswap V2. Intermediate steps that
+//! mirror Solidity's directed rounding, like the tick math of Uniswap V3 and V4, always round the
+//! way the contracts do.
+use std::{cell::Cell, fmt};
+
+use alloy_primitives::U256;
+use num_bigint::BigUint;
+use num_traits::{ToPrimitive, Zero};
+
+thread_local! {
+    static ROUNDING_POLICY: Cell<RoundingPolicy> = const { Cell::new(RoundingPolicy::Floor) };
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum RoundingPolicy {
+    /// Round down, like on-chain math
+    #[default]
+    Floor,
+    /// Round to the nearest integer, halves up
+    Nearest,
+}
+
+impl RoundingPolicy {
+    /// Returns the policy of the current thread, see `with_rounding_policy`.
+    pub fn current() -> Self {
+        ROUNDING_POLICY.with(Cell::get)
+    }
+
+    /// Rounds `quotient + remainder / divisor` to an integer. `remainder` must be smaller than
+    /// `divisor`.
+    pub fn round(self, quotient: U256, remainder: U256, divisor: U256) -> U256 {
+        match self {
+            RoundingPolicy::Floor => quotient,
+            // remainder >= divisor / 2, written to avoid the precision loss of halving
+            RoundingPolicy::Nearest if remainder >= divisor - remainder => {
+                quotient.saturating_add(U256::from(1u64))
+            }
+            RoundingPolicy::Nearest => quotient,
+        }
+    }
+}
+
+/// Runs `f` with `policy` as the rounding policy of the current thread. The previous policy is
+/// restored afterwards, even if `f` panics.
+pub fn with_rounding_policy<T>(policy: RoundingPolicy, f: impl FnOnce() -> T) -> T {
+    /// Restores the previous policy on drop
+    struct Restore(RoundingPolicy);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            ROUNDING_POLICY.with(|current| current.set(self.0));
+        }
+    }
+
+    let _restore = Restore(ROUNDING_POLICY.with(|current| current.replace(policy)));
+    f()
+}
+
+/// Tracks the deviation of simulated amounts from reference amounts, e.g. on-chain results in
+/// differential tests.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RoundingErrorReport {
+    samples: usize,
+    max_abs: BigUint,
+    max_rel: f64,
+}
+
+impl RoundingErrorReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a simulated amount and the reference amount it should match.
+    pub fn record(&mut self, expected: &BigUint, actual: &BigUint) {
+        let abs = if expected > actual { expected - actual } else { actual - expected };
+        let rel = match expected.to_f64() {
+            Some(expected) if expected > 0.0 => abs.to_f64().unwrap_or(f64::INFINITY) / expected,
+            _ if abs.is_zero() => 0.0,
+            _ => f64::INFINITY,
+        };
+        self.samples += 1;
+        self.max_abs = self.max_abs.clone().max(abs);
+        self.max_rel = self.max_rel.max(rel);
+    }
+
+    pub fn samples(&self) -> usize {
+        self.samples
+    }
+
+    /// Largest absolute deviation, in the token's smallest unit
+    pub fn max_abs(&self) -> &BigUint {
+        &self.max_abs
+    }
+
+    /// Largest deviation relative to the reference amount
+    pub fn max_rel(&self) -> f64 {
+        self.max_rel
+    }
+}
+
+impl fmt::Display for RoundingErrorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} samples, max absolute error {}, max relative error {:e}",
+            self.samples, self.max_abs, self.max_rel
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round() {
+        let round = |policy: RoundingPolicy, remainder: u64, divisor: u64| {
+            policy.round(U256::from(7u64), U256::from(remainder), U256::from(divisor))
+        };
+
+        assert_eq!(round(RoundingPolicy::Floor, 5, 10), U256::from(7u64));
+        assert_eq!(round(RoundingPolicy::Nearest, 5, 10), U256::from(8u64));
+        assert_eq!(round(RoundingPolicy::Nearest, 4, 10), U256::from(7u64));
+        assert_eq!(round(RoundingPolicy::Nearest, 1, 3), U256::from(7u64));
+        assert_eq!(round(RoundingPolicy::Nearest, 2, 3), U256::from(8u64));
+    }
+
+    #[test]
+    fn test_with_rounding_policy() {
+        let policy = with_rounding_policy(RoundingPolicy::Nearest, || {
+            // Other threads keep the default policy
+            let other = std::thread::spawn(RoundingPolicy::current)
+                .join()
+                .unwrap();
+            (RoundingPolicy::current(), other)
+        });
+
+        assert_eq!(policy, (RoundingPolicy::Nearest, RoundingPolicy::Floor));
+        assert_eq!(RoundingPolicy::current(), RoundingPolicy::Floor);
+    }
+
+    #[test]
+    fn test_rounding_error_report() {
+        let mut report = RoundingErrorReport::new();
+
+        report.record(&BigUint::from(1000u32), &BigUint::from(999u32));
+        report.record(&BigUint::from(10u32), &BigUint::from(10u32));
+
+        assert_eq!(report.samples(), 2);
+        assert_eq!(report.max_abs(), &BigUint::from(1u32));
+        assert_eq!(report.max_rel(), 0.001);
+    }
+}
@@ -7,7 +7,13 @@
 //! The `ProtocolSim` trait has several key methods:
 //!  - `fee`: Returns the protocol's fee as a ratio.
 //!  - `spot_price`: Returns the current spot price between two tokens.
+//!  - `spot_price_gas`: Returns the gas used to compute the spot prices.
 //!  - `get_amount_out`: Returns the amount of output tokens given an amount of input tokens.
+//!  - `get_amount_out_with_context`: Like `get_amount_out`, for a given sender, recipient and
+//!    referral.
+//!  - `is_blocking`: Whether quoting may block the calling thread.
+//!  - `state_fingerprint`: Returns a hash of the state, to detect changes.
+//!  - `set_block_timestamp`: Moves states depending on time to the time of a block.
 //!  - `delta_transition`: Applies a state delta to the simulated protocol.
 //!  - `clone_box`: Clones the simulated protocol state as a trait object.
 //!  - `as_any`: Allows downcasting of the trait object.
@@ -44,7 +50,11 @@
 //! assert_eq!(state.spot_price(&weth, &usdc).unwrap(), 1218.0683462769755f64);
 //! assert_eq!(out, 1214374202.to_biguint().unwrap());
 //! ```
-use std::{any::Any, collections::HashMap};
+use std::{
+    any::Any,
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+};
 
 #[cfg(test)]
 use mockall::mock;
@@ -55,7 +65,7 @@ use crate::{
     models::{Balances, Token},
     protocol::{
         errors::{SimulationError, TransitionError},
-        models::GetAmountOutResult,
+        models::{GetAmountOutResult, QuoteContext},
     },
 };
 
@@ -85,6 +95,13 @@ pub trait ProtocolSim: std::fmt::Debug + Send + Sync + 'static {
     ///   BTC/USDT, USDT would be the quote asset.
     fn spot_price(&self, base: &Token, quote: &Token) -> Result<f64, SimulationError>;
 
+    /// Returns the gas used to compute the spot prices of the state, e.g. by simulating a price
+    /// function, which simulation budgets charge for pricing the pool. Analytical states compute
+    /// prices without any simulation and return 0, the default.
+    fn spot_price_gas(&self) -> u64 {
+        0
+    }
+
     /// Returns the amount out given an amount in and input/output tokens.
     ///
     /// # Arguments
@@ -104,6 +121,43 @@ pub trait ProtocolSim: std::fmt::Debug + Send + Sync + 'static {
         token_out: &Token,
     ) -> Result<GetAmountOutResult, SimulationError>;
 
+    /// Returns the amount of output tokens for a swap executed as described by `context`.
+    ///
+    /// States whose output depends on the executing address, e.g. with fee tiers by sender,
+    /// override this. The default ignores the context and quotes with `get_amount_out`.
+    fn get_amount_out_with_context(
+        &self,
+        amount_in: BigUint,
+        token_in: &Token,
+        token_out: &Token,
+        _context: &QuoteContext,
+    ) -> Result<GetAmountOutResult, SimulationError> {
+        self.get_amount_out(amount_in, token_in, token_out)
+    }
+
+    /// Returns whether quoting this state may block the calling thread, e.g. because it executes
+    /// EVM code or fetches storage from a node.
+    ///
+    /// Blocking states are quoted on tokio's blocking thread pool by `get_amount_out_async`.
+    fn is_blocking(&self) -> bool {
+        false
+    }
+
+    /// Returns a hash of the state's content.
+    ///
+    /// The fingerprint changes whenever a delta changes the state, so consumers can skip
+    /// re-quoting pools whose fingerprint is the same as in the previous block. Equal fingerprints
+    /// don't guarantee equal states across pools; only compare fingerprints of the same pool.
+    ///
+    /// Implementations hash the fields quotes depend on, see `fingerprint`. It is computed for
+    /// every pool on every block, so it should be cheap.
+    fn state_fingerprint(&self) -> u64;
+
+    /// Sets the time of the block the state belongs to, for states whose quotes depend on time,
+    /// e.g. pending orders executing over time. The stream decoder calls it with the block's
+    /// timestamp on every state it decodes or updates. Does nothing by default.
+    fn set_block_timestamp(&mut self, _timestamp: u64) {}
+
     /// Decodes and applies a protocol state delta to the state
     ///
     /// Will error if the provided delta is missing any required attributes or if any of the
@@ -140,6 +194,60 @@ pub trait ProtocolSim: std::fmt::Debug + Send + Sync + 'static {
     fn eq(&self, other: &dyn ProtocolSim) -> bool;
 }
 
+/// Hashes a value into a state fingerprint, see `ProtocolSim::state_fingerprint`.
+///
+/// Uses a fixed hasher, so fingerprints are stable within a process.
+pub fn fingerprint<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl dyn ProtocolSim {
+    /// Async variant of `get_amount_out`, for quoting from async code.
+    ///
+    /// States that may block (see `is_blocking`) are quoted on tokio's blocking thread pool, so
+    /// the calling task's worker thread is never blocked. Other states are quoted inline.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `get_amount_out`, or a `FatalError` if the quoting task panicked.
+    pub async fn get_amount_out_async(
+        &self,
+        amount_in: BigUint,
+        token_in: &Token,
+        token_out: &Token,
+    ) -> Result<GetAmountOutResult, SimulationError> {
+        self.get_amount_out_with_context_async(
+            amount_in,
+            token_in,
+            token_out,
+            &QuoteContext::default(),
+        )
+        .await
+    }
+
+    /// Async variant of `get_amount_out_with_context`, see `get_amount_out_async`.
+    pub async fn get_amount_out_with_context_async(
+        &self,
+        amount_in: BigUint,
+        token_in: &Token,
+        token_out: &Token,
+        context: &QuoteContext,
+    ) -> Result<GetAmountOutResult, SimulationError> {
+        if !self.is_blocking() {
+            return self.get_amount_out_with_context(amount_in, token_in, token_out, context);
+        }
+        let state = self.clone_box();
+        let (token_in, token_out, context) = (token_in.clone(), token_out.clone(), context.clone());
+        tokio::task::spawn_blocking(move || {
+            state.get_amount_out_with_context(amount_in, &token_in, &token_out, &context)
+        })
+        .await
+        .map_err(|e| SimulationError::FatalError(format!("Quoting task failed: {}", e)))?
+    }
+}
+
 impl Clone for Box<dyn ProtocolSim> {
     fn clone(&self) -> Box<dyn ProtocolSim> {
         self.clone_box()
@@ -164,6 +272,7 @@ mock! {
             tokens: &HashMap<Bytes, Token>,
             balances: &Balances,
         ) -> Result<(), TransitionError<String>>;
+        pub fn state_fingerprint(&self) -> u64;
         pub fn clone_box(&self) -> Box<dyn ProtocolSim>;
         pub fn eq(&self, other: &dyn ProtocolSim) -> bool;
     }
@@ -197,6 +306,10 @@ impl ProtocolSim for MockProtocolSim {
         self.delta_transition(delta, tokens, balances)
     }
 
+    fn state_fingerprint(&self) -> u64 {
+        self.state_fingerprint()
+    }
+
     fn clone_box(&self) -> Box<dyn ProtocolSim> {
         self.clone_box()
     }
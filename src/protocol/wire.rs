@@ -0,0 +1,650 @@
+//! Wire format and network fan-out of decoded block updates
+//!
+//! `BlockUpdate`s hold decoded protocol states as trait objects, which can't leave the process.
+//! This module converts them into a language neutral wire format, holding the block header and for
+//! every updated pool its fee, spot prices and the attribute changes received from tycho, and
+//! publishes them over plain TCP so that non-Rust services can consume the decoder's output without
+//! re-implementing tycho decoding.
+//!
+//! # Framing
+//!
+//! Every message is a JSON document prefixed with its length as a big-endian `u32`. Addresses and
+//! ids are hex strings. A subscriber first receives a `WireMessage::Snapshot` with all known pools,
+//! followed by one `WireMessage::Update` per block.
+//!
+//! Pools are sorted by id in every message, so the same update is always encoded to the same
+//! bytes.
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{broadcast, Mutex},
+    task::{JoinError, JoinHandle},
+};
+use tracing::{debug, warn};
+use tycho_core::{dto::ProtocolStateDelta, Bytes};
+
+use crate::{
+    models::Token,
+    protocol::{
+        models::{BlockInfo, BlockUpdate, ProtocolComponent},
+        state::ProtocolSim,
+    },
+};
+
+/// Maximum size of a single frame, protects subscribers from allocating on corrupted input.
+const MAX_FRAME_SIZE: usize = 256 * 1024 * 1024;
+
+/// Number of messages buffered per subscriber before it is considered too slow and disconnected.
+const SUBSCRIBER_BUFFER: usize = 64;
+
+#[derive(Error, Debug)]
+pub enum WireError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to encode or decode message: {0}")]
+    Codec(#[from] serde_json::Error),
+    #[error("Frame of {0} bytes exceeds the maximum frame size")]
+    FrameTooLarge(usize),
+    #[error("Encoding task failed: {0}")]
+    Encode(#[from] JoinError),
+}
+
+/// Header of the block an update belongs to.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct WireBlock {
+    pub number: u64,
+    pub hash: Bytes,
+    /// The block timestamp, in seconds since the unix epoch
+    pub timestamp: u64,
+}
+
+impl From<&BlockInfo> for WireBlock {
+    fn from(block: &BlockInfo) -> Self {
+        Self { number: block.number, hash: block.hash.clone(), timestamp: block.timestamp }
+    }
+}
+
+/// Static properties of a pool.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WirePool {
+    pub id: Bytes,
+    pub protocol_system: String,
+    pub protocol_type_name: String,
+    pub tokens: Vec<Bytes>,
+    pub contract_ids: Vec<Bytes>,
+}
+
+impl From<&ProtocolComponent> for WirePool {
+    fn from(component: &ProtocolComponent) -> Self {
+        Self {
+            id: component.id.clone(),
+            protocol_system: component.protocol_system.clone(),
+            protocol_type_name: component.protocol_type_name.clone(),
+            tokens: component
+                .tokens
+                .iter()
+                .map(|t| t.address.clone())
+                .collect(),
+            contract_ids: component.contract_ids.clone(),
+        }
+    }
+}
+
+/// Spot price of `base` denominated in `quote`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WireSpotPrice {
+    pub base: Bytes,
+    pub quote: Bytes,
+    pub price: f64,
+}
+
+/// Decoded state of a pool at a block.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct WirePoolState {
+    pub fee: f64,
+    /// Spot prices for every ordered pair of the pool's tokens. Pairs whose price can't be
+    /// computed are omitted.
+    pub spot_prices: Vec<WireSpotPrice>,
+    /// Attributes set in this block, as received from tycho. New pools, and all pools of a
+    /// `WireSnapshot`, hold all their attributes.
+    pub updated_attributes: BTreeMap<String, Bytes>,
+    /// Attributes removed in this block, sorted
+    pub deleted_attributes: Vec<String>,
+}
+
+impl WirePoolState {
+    pub fn new(state: &dyn ProtocolSim, tokens: &[Token]) -> Self {
+        let mut spot_prices = Vec::new();
+        for base in tokens {
+            for quote in tokens {
+                if base.address == quote.address {
+                    continue;
+                }
+                match state.spot_price(base, quote) {
+                    Ok(price) => spot_prices.push(WireSpotPrice {
+                        base: base.address.clone(),
+                        quote: quote.address.clone(),
+                        price,
+                    }),
+                    Err(e) => debug!(?e, "Skipping spot price"),
+                }
+            }
+        }
+        Self { fee: state.fee(), spot_prices, ..Default::default() }
+    }
+
+    pub fn with_delta(mut self, delta: &ProtocolStateDelta) -> Self {
+        self.updated_attributes = delta
+            .updated_attributes
+            .clone()
+            .into_iter()
+            .collect();
+        self.deleted_attributes = delta
+            .deleted_attributes
+            .iter()
+            .cloned()
+            .collect();
+        self.deleted_attributes.sort_unstable();
+        self
+    }
+
+    /// Applies the state of a later block, keeping all attributes.
+    pub fn apply(&mut self, update: &WirePoolState) {
+        self.fee = update.fee;
+        self.spot_prices = update.spot_prices.clone();
+        for attribute in &update.deleted_attributes {
+            self.updated_attributes
+                .remove(attribute);
+        }
+        self.updated_attributes
+            .extend(update.updated_attributes.clone());
+    }
+}
+
+/// The changes of one block, in wire format.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WireBlockUpdate {
+    pub block: WireBlock,
+    /// Pools that started being tracked in this block
+    pub new_pools: BTreeMap<String, WirePool>,
+    /// New and updated pool states
+    pub states: BTreeMap<String, WirePoolState>,
+    /// Ids of the pools that stopped being tracked in this block
+    pub removed_pools: Vec<String>,
+}
+
+/// All pools known to a publisher, sent to subscribers when they connect.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct WireSnapshot {
+    pub block: WireBlock,
+    pub pools: BTreeMap<String, WirePool>,
+    pub states: BTreeMap<String, WirePoolState>,
+}
+
+impl WireSnapshot {
+    /// Applies a block update to the snapshot.
+    pub fn apply(&mut self, update: &WireBlockUpdate) {
+        self.block = update.block.clone();
+        self.pools
+            .extend(update.new_pools.clone());
+        for (id, state) in &update.states {
+            self.states
+                .entry(id.clone())
+                .or_default()
+                .apply(state);
+        }
+        for id in &update.removed_pools {
+            self.pools.remove(id);
+            self.states.remove(id);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum WireMessage {
+    Snapshot(WireSnapshot),
+    Update(WireBlockUpdate),
+}
+
+/// Converts `BlockUpdate`s into `WireBlockUpdate`s.
+///
+/// Block updates only contain the components of new pools, so the encoder remembers them to
+/// resolve the tokens of pools updated in later blocks.
+#[derive(Debug, Default)]
+pub struct WireEncoder {
+    components: HashMap<String, ProtocolComponent>,
+}
+
+impl WireEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn encode(&mut self, update: &BlockUpdate) -> WireBlockUpdate {
+        self.prepare(update).encode()
+    }
+
+    /// Records the pools of a block update and collects the states to encode, without computing
+    /// any spot price yet.
+    fn prepare(&mut self, update: &BlockUpdate) -> PendingUpdate {
+        self.components
+            .extend(update.new_pairs.clone());
+        let states = update
+            .states
+            .iter()
+            .filter_map(|(id, state)| match self.components.get(id) {
+                Some(component) => Some(PendingState {
+                    id: id.clone(),
+                    state: state.clone_box(),
+                    tokens: component.tokens.clone(),
+                    delta: update.state_deltas.get(id).cloned(),
+                }),
+                None => {
+                    warn!(pool = id, "Skipping state of unknown pool");
+                    None
+                }
+            })
+            .collect();
+        for id in update.removed_pairs.keys() {
+            self.components.remove(id);
+        }
+        let mut removed_pools: Vec<_> = update
+            .removed_pairs
+            .keys()
+            .cloned()
+            .collect();
+        removed_pools.sort_unstable();
+
+        let block = match &update.block {
+            Some(block) => WireBlock::from(block),
+            None => WireBlock { number: update.block_number, ..Default::default() },
+        };
+        PendingUpdate {
+            update: WireBlockUpdate {
+                block,
+                new_pools: update
+                    .new_pairs
+                    .iter()
+                    .map(|(id, component)| (id.clone(), component.into()))
+                    .collect(),
+                states: BTreeMap::new(),
+                removed_pools,
+            },
+            states,
+        }
+    }
+}
+
+struct PendingState {
+    id: String,
+    state: Box<dyn ProtocolSim>,
+    tokens: Vec<Token>,
+    delta: Option<ProtocolStateDelta>,
+}
+
+/// A block update whose pool states are not encoded yet, see `WireEncoder::prepare`.
+struct PendingUpdate {
+    update: WireBlockUpdate,
+    states: Vec<PendingState>,
+}
+
+impl PendingUpdate {
+    /// Computes the spot prices of all states, which may simulate, e.g. for VM pools.
+    fn encode(self) -> WireBlockUpdate {
+        let mut update = self.update;
+        update.states = self
+            .states
+            .into_iter()
+            .map(|pending| {
+                let state = WirePoolState::new(pending.state.as_ref(), &pending.tokens);
+                let state = match &pending.delta {
+                    Some(delta) => state.with_delta(delta),
+                    None => state,
+                };
+                (pending.id, state)
+            })
+            .collect();
+        update
+    }
+}
+
+/// Encodes a message into a length prefixed frame.
+pub fn encode_frame(message: &WireMessage) -> Result<Vec<u8>, WireError> {
+    let payload = serde_json::to_vec(message)?;
+    let len = u32::try_from(payload.len())
+        .ok()
+        .filter(|len| *len as usize <= MAX_FRAME_SIZE)
+        .ok_or(WireError::FrameTooLarge(payload.len()))?;
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// Reads one frame. Returns `None` if the stream was closed between frames.
+pub async fn read_frame<R: AsyncReadExt + Unpin>(
+    reader: &mut R,
+) -> Result<Option<WireMessage>, WireError> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(WireError::FrameTooLarge(len));
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(serde_json::from_slice(&payload)?))
+}
+
+struct PublisherState {
+    snapshot: WireSnapshot,
+    sender: broadcast::Sender<Arc<Vec<u8>>>,
+}
+
+/// Publishes block updates to TCP subscribers.
+///
+/// New subscribers receive a snapshot of all pools, then every published update. Subscribers
+/// falling more than `SUBSCRIBER_BUFFER` messages behind are disconnected and have to reconnect
+/// to resync.
+pub struct UpdatePublisher {
+    state: Arc<Mutex<PublisherState>>,
+    encoder: Mutex<WireEncoder>,
+    local_addr: SocketAddr,
+    accept_handle: JoinHandle<()>,
+}
+
+impl UpdatePublisher {
+    /// Binds a TCP listener and starts accepting subscribers.
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self, WireError> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let (sender, _) = broadcast::channel(SUBSCRIBER_BUFFER);
+        let state =
+            Arc::new(Mutex::new(PublisherState { snapshot: WireSnapshot::default(), sender }));
+        let accept_handle = tokio::spawn(Self::accept_loop(listener, state.clone()));
+        Ok(Self { state, encoder: Mutex::new(WireEncoder::new()), local_addr, accept_handle })
+    }
+
+    /// Returns the address the publisher is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Encodes a block update and sends it to all subscribers. Blocks are expected to be
+    /// published one after the other.
+    pub async fn publish(&self, update: &BlockUpdate) -> Result<(), WireError> {
+        let pending = self
+            .encoder
+            .lock()
+            .await
+            .prepare(update);
+        // Spot prices may simulate, so they are computed off the async runtime
+        let update = tokio::task::spawn_blocking(move || pending.encode()).await?;
+        self.publish_wire(update).await
+    }
+
+    /// Sends an already encoded block update to all subscribers.
+    pub async fn publish_wire(&self, update: WireBlockUpdate) -> Result<(), WireError> {
+        let frame = Arc::new(encode_frame(&WireMessage::Update(update.clone()))?);
+        let mut state = self.state.lock().await;
+        state.snapshot.apply(&update);
+        // Sending only fails if there are no subscribers
+        let _ = state.sender.send(frame);
+        Ok(())
+    }
+
+    async fn accept_loop(listener: TcpListener, state: Arc<Mutex<PublisherState>>) {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!(error = %e, "Failed to accept subscriber");
+                    continue;
+                }
+            };
+            debug!(%peer, "Subscriber connected");
+            // Snapshot and subscription are taken under the same lock, so no update is missed
+            // or sent twice.
+            let (snapshot, receiver) = {
+                let state = state.lock().await;
+                (
+                    encode_frame(&WireMessage::Snapshot(state.snapshot.clone())),
+                    state.sender.subscribe(),
+                )
+            };
+            match snapshot {
+                Ok(snapshot) => {
+                    tokio::spawn(Self::serve_subscriber(stream, snapshot, receiver));
+                }
+                Err(e) => warn!(error = %e, "Failed to encode snapshot"),
+            }
+        }
+    }
+
+    async fn serve_subscriber<W: AsyncWrite + Unpin>(
+        mut stream: W,
+        snapshot: Vec<u8>,
+        mut receiver: broadcast::Receiver<Arc<Vec<u8>>>,
+    ) {
+        if let Err(e) = stream.write_all(&snapshot).await {
+            debug!(error = %e, "Subscriber disconnected");
+            return;
+        }
+        loop {
+            match receiver.recv().await {
+                Ok(frame) => {
+                    if let Err(e) = stream.write_all(&frame).await {
+                        debug!(error = %e, "Subscriber disconnected");
+                        return;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!(skipped = n, "Disconnecting lagging subscriber");
+                    return;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+impl Drop for UpdatePublisher {
+    fn drop(&mut self) {
+        self.accept_handle.abort();
+    }
+}
+
+/// Receives block updates from an `UpdatePublisher`.
+pub struct UpdateSubscriber {
+    stream: TcpStream,
+}
+
+impl UpdateSubscriber {
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, WireError> {
+        Ok(Self { stream: TcpStream::connect(addr).await? })
+    }
+
+    /// Waits for the next message. Returns `None` once the publisher closed the connection.
+    pub async fn next(&mut self) -> Result<Option<WireMessage>, WireError> {
+        read_frame(&mut self.stream).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, str::FromStr};
+
+    use chrono::NaiveDateTime;
+    use num_bigint::BigUint;
+    use tycho_core::models::Chain;
+
+    use super::*;
+    use crate::protocol::state::MockProtocolSim;
+
+    fn tokens() -> Vec<Token> {
+        vec![
+            Token::new("0x0000000000000000000000000000000000000001", 18, "A", BigUint::from(1u8)),
+            Token::new("0x0000000000000000000000000000000000000002", 6, "B", BigUint::from(1u8)),
+        ]
+    }
+
+    fn component() -> ProtocolComponent {
+        ProtocolComponent::new(
+            Bytes::from_str("0xaa").unwrap(),
+            "uniswap_v2".to_string(),
+            "pool".to_string(),
+            Chain::Ethereum,
+            tokens(),
+            vec![],
+            HashMap::new(),
+            Bytes::default(),
+            NaiveDateTime::default(),
+        )
+    }
+
+    fn mock_state() -> Box<dyn ProtocolSim> {
+        let mut state = MockProtocolSim::new();
+        state.expect_fee().return_const(0.003);
+        state
+            .expect_spot_price()
+            .returning(|base, _| Ok(if base.symbol == "A" { 2.0 } else { 0.5 }));
+        Box::new(state)
+    }
+
+    fn block_update(block_number: u64, new_pool: bool) -> BlockUpdate {
+        let new_pairs = if new_pool {
+            HashMap::from([("pool".to_string(), component())])
+        } else {
+            HashMap::new()
+        };
+        BlockUpdate::new(
+            block_number,
+            HashMap::from([("pool".to_string(), mock_state())]),
+            new_pairs,
+        )
+    }
+
+    #[test]
+    fn test_encode_block_update() {
+        let mut encoder = WireEncoder::new();
+
+        let update = encoder.encode(&block_update(1, true));
+        let state = &update.states["pool"];
+        assert_eq!(update.new_pools["pool"].tokens.len(), 2);
+        assert_eq!(state.fee, 0.003);
+        assert_eq!(state.spot_prices.len(), 2);
+        assert_eq!(
+            state
+                .spot_prices
+                .iter()
+                .map(|p| p.price)
+                .sum::<f64>(),
+            2.5
+        );
+
+        // Tokens of known pools are resolved in later blocks
+        let update = encoder.encode(&block_update(2, false));
+        assert!(update.new_pools.is_empty());
+        assert!(update.states.contains_key("pool"));
+    }
+
+    #[test]
+    fn test_encode_block_and_deltas() {
+        let mut encoder = WireEncoder::new();
+        let block = BlockInfo { number: 1, hash: Bytes::from_str("0x01").unwrap(), timestamp: 12 };
+        let delta = ProtocolStateDelta {
+            component_id: "pool".to_string(),
+            updated_attributes: HashMap::from([
+                ("a".to_string(), Bytes::from(vec![1u8])),
+                ("b".to_string(), Bytes::from(vec![2u8])),
+            ]),
+            ..Default::default()
+        };
+        let update = block_update(1, true)
+            .set_block(block.clone())
+            .set_state_deltas(HashMap::from([("pool".to_string(), delta)]));
+
+        let update = encoder.encode(&update);
+        assert_eq!(update.block, WireBlock::from(&block));
+        assert_eq!(
+            update.states["pool"]
+                .updated_attributes
+                .len(),
+            2
+        );
+
+        let mut snapshot = WireSnapshot::default();
+        snapshot.apply(&update);
+        let delta = ProtocolStateDelta {
+            component_id: "pool".to_string(),
+            updated_attributes: HashMap::from([("a".to_string(), Bytes::from(vec![3u8]))]),
+            deleted_attributes: HashSet::from(["b".to_string()]),
+        };
+        let update =
+            block_update(2, false).set_state_deltas(HashMap::from([("pool".to_string(), delta)]));
+        snapshot.apply(&encoder.encode(&update));
+
+        // The snapshot holds all current attributes
+        assert_eq!(snapshot.block.number, 2);
+        assert_eq!(
+            snapshot.states["pool"].updated_attributes,
+            BTreeMap::from([("a".to_string(), Bytes::from(vec![3u8]))])
+        );
+        assert!(snapshot.states["pool"]
+            .deleted_attributes
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_frame_roundtrip() {
+        let message = WireMessage::Update(WireEncoder::new().encode(&block_update(1, true)));
+
+        let frame = encode_frame(&message).unwrap();
+        let mut reader = frame.as_slice();
+
+        assert_eq!(read_frame(&mut reader).await.unwrap(), Some(message));
+        assert_eq!(read_frame(&mut reader).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_publish_subscribe() {
+        let publisher = UpdatePublisher::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        publisher
+            .publish(&block_update(1, true))
+            .await
+            .unwrap();
+
+        let mut subscriber = UpdateSubscriber::connect(publisher.local_addr())
+            .await
+            .unwrap();
+        let Some(WireMessage::Snapshot(snapshot)) = subscriber.next().await.unwrap() else {
+            panic!("Expected a snapshot")
+        };
+        assert_eq!(snapshot.block.number, 1);
+        assert!(snapshot.pools.contains_key("pool"));
+
+        publisher
+            .publish(&block_update(2, false))
+            .await
+            .unwrap();
+        let Some(WireMessage::Update(update)) = subscriber.next().await.unwrap() else {
+            panic!("Expected an update")
+        };
+        assert_eq!(update.block.number, 2);
+    }
+}
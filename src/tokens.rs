@@ -0,0 +1,198 @@
+//! Token address utilities
+//!
+//! Pools, prices and consumers refer to tokens by address, but the same token can be spelled in
+//! several ways: upper, lower or checksummed hex, with or without `0x`, as the native token or as
+//! its wrapped version. This module gives every spelling a single canonical form, so lookups by
+//! token match no matter where the address came from:
+//!
+//! - `parse_address` reads addresses in any casing, `to_checksum` formats them as EIP-55.
+//! - `sort_pair` orders two tokens canonically, by address, like Uniswap's `token0`/`token1`.
+//! - `wrapped_native` and `canonical_token` map the native token of a chain to its wrapped token,
+//!   `is_equivalent` treats both as the same token.
+//! - `TokenId` identifies a token across chains, formatted as `<chain>:<checksummed address>`.
+use std::{fmt, str::FromStr};
+
+use alloy_primitives::Address;
+use tycho_core::{models::Chain, Bytes};
+
+use crate::protocol::errors::SimulationError;
+
+/// Address Tycho uses for the native token of a chain, e.g. ETH on Ethereum
+pub const NATIVE_ADDRESS: Address = Address::ZERO;
+
+/// Address many protocols use for the native token, see EIP-7528
+pub const EIP7528_NATIVE_ADDRESS: Address = Address::repeat_byte(0xee);
+
+/// Parses a 20 byte address, with or without `0x` prefix and in any casing. The checksum of mixed
+/// case addresses is not verified, see `parse_checksummed`.
+pub fn parse_address(address: &str) -> Result<Bytes, SimulationError> {
+    let hex = address
+        .strip_prefix("0x")
+        .unwrap_or(address);
+    Address::from_str(hex)
+        .map(|address| Bytes::from(address.to_vec()))
+        .map_err(|e| SimulationError::InvalidInput(format!("Invalid address {address}: {e}"), None))
+}
+
+/// Parses a 20 byte address and verifies its EIP-55 checksum if it is mixed case.
+pub fn parse_checksummed(address: &str) -> Result<Bytes, SimulationError> {
+    let parsed = parse_address(address)?;
+    let hex = address
+        .strip_prefix("0x")
+        .unwrap_or(address);
+    let mixed_case = hex
+        .chars()
+        .any(|c| c.is_ascii_lowercase()) &&
+        hex.chars()
+            .any(|c| c.is_ascii_uppercase());
+    if mixed_case && to_checksum(&parsed)?[2..] != *hex {
+        return Err(SimulationError::InvalidInput(
+            format!("Invalid checksum of address {address}"),
+            None,
+        ));
+    }
+    Ok(parsed)
+}
+
+/// Formats an address with its EIP-55 checksum. Fails if the address isn't 20 bytes long.
+pub fn to_checksum(address: &Bytes) -> Result<String, SimulationError> {
+    let address: [u8; 20] = address
+        .as_ref()
+        .try_into()
+        .map_err(|_| {
+            SimulationError::InvalidInput(format!("Invalid address length: {address}"), None)
+        })?;
+    Ok(Address::from(address).to_checksum(None))
+}
+
+/// Orders two tokens canonically, lowest address first.
+pub fn sort_pair<'a>(token_a: &'a Bytes, token_b: &'a Bytes) -> (&'a Bytes, &'a Bytes) {
+    if token_a <= token_b {
+        (token_a, token_b)
+    } else {
+        (token_b, token_a)
+    }
+}
+
+/// Whether the address stands for the native token of a chain.
+pub fn is_native(address: &Bytes) -> bool {
+    address.as_ref() == NATIVE_ADDRESS.as_slice() ||
+        address.as_ref() == EIP7528_NATIVE_ADDRESS.as_slice()
+}
+
+/// The wrapped native token of a chain, e.g. WETH on Ethereum. `None` for chains without one.
+pub fn wrapped_native(chain: Chain) -> Option<Bytes> {
+    let address = match chain {
+        Chain::Ethereum => "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2",
+        Chain::Arbitrum => "0x82af49447d8a07e3bd95bd0d56f35241523fbab1",
+        Chain::Base => "0x4200000000000000000000000000000000000006",
+        Chain::ZkSync => "0x5aea5775959fbc2557cc8789bc1bf90a239d9a91",
+        _ => return None,
+    };
+    Some(Bytes::from_str(address).expect("Invalid wrapped native address"))
+}
+
+/// The canonical address of a token: its wrapped version for the native token, if the chain has
+/// one, else the address itself.
+pub fn canonical_token(chain: Chain, address: &Bytes) -> Bytes {
+    if is_native(address) {
+        if let Some(wrapped) = wrapped_native(chain) {
+            return wrapped;
+        }
+    }
+    address.clone()
+}
+
+/// Whether two addresses stand for the same token on a chain, treating the native token and its
+/// wrapped version as equivalent.
+pub fn is_equivalent(chain: Chain, token_a: &Bytes, token_b: &Bytes) -> bool {
+    canonical_token(chain, token_a) == canonical_token(chain, token_b)
+}
+
+/// Identifies a token across chains. Formatted as `<chain>:<checksummed address>`, e.g.
+/// `ethereum:0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2`, and parsed from any casing.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TokenId {
+    pub chain: Chain,
+    pub address: Bytes,
+}
+
+impl TokenId {
+    pub fn new(chain: Chain, address: Bytes) -> Self {
+        Self { chain, address }
+    }
+
+    /// The id of the canonical token, see `canonical_token`.
+    pub fn canonical(&self) -> Self {
+        Self { chain: self.chain, address: canonical_token(self.chain, &self.address) }
+    }
+}
+
+impl fmt::Display for TokenId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match to_checksum(&self.address) {
+            Ok(address) => write!(f, "{}:{}", self.chain, address),
+            Err(_) => write!(f, "{}:{}", self.chain, self.address),
+        }
+    }
+}
+
+impl FromStr for TokenId {
+    type Err = SimulationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (chain, address) = s.split_once(':').ok_or_else(|| {
+            SimulationError::InvalidInput(
+                format!("Invalid token id {s}, expected chain:address"),
+                None,
+            )
+        })?;
+        let chain = Chain::from_str(chain)
+            .map_err(|_| SimulationError::InvalidInput(format!("Unknown chain {chain}"), None))?;
+        Ok(Self { chain, address: parse_address(address)? })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WETH: &str = "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2";
+    const WETH_CHECKSUMMED: &str = "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2";
+
+    #[test]
+    fn test_parse_and_checksum() {
+        let weth = Bytes::from_str(WETH).unwrap();
+
+        assert_eq!(parse_address(WETH).unwrap(), weth);
+        assert_eq!(parse_address(&WETH[2..].to_uppercase()).unwrap(), weth);
+        assert_eq!(to_checksum(&weth).unwrap(), WETH_CHECKSUMMED);
+        assert_eq!(parse_checksummed(WETH_CHECKSUMMED).unwrap(), weth);
+        assert!(parse_checksummed("0xc02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2").is_err());
+        assert!(parse_address("0x1234").is_err());
+        assert!(to_checksum(&Bytes::from(vec![1, 2])).is_err());
+    }
+
+    #[test]
+    fn test_native_equivalence() {
+        let weth = Bytes::from_str(WETH).unwrap();
+        let eth = Bytes::from(NATIVE_ADDRESS.to_vec());
+        let eee = Bytes::from(EIP7528_NATIVE_ADDRESS.to_vec());
+
+        assert_eq!(sort_pair(&weth, &eth), (&eth, &weth));
+        assert_eq!(canonical_token(Chain::Ethereum, &eth), weth);
+        assert!(is_equivalent(Chain::Ethereum, &eee, &weth));
+        assert!(!is_equivalent(Chain::Base, &eth, &weth));
+        assert_eq!(canonical_token(Chain::Starknet, &eth), eth);
+    }
+
+    #[test]
+    fn test_token_id() {
+        let id = TokenId::new(Chain::Ethereum, Bytes::from(NATIVE_ADDRESS.to_vec()));
+
+        assert_eq!(id.canonical().to_string(), format!("ethereum:{WETH_CHECKSUMMED}"));
+        assert_eq!(TokenId::from_str(&format!("ethereum:{WETH}")).unwrap(), id.canonical());
+        assert!(TokenId::from_str(WETH).is_err());
+        assert!(TokenId::from_str("moon:0x00").is_err());
+    }
+}
@@ -0,0 +1,264 @@
+//! Node.js bindings for Tycho Simulation
+//!
+//! Exposes the protocol stream and quoting to Node.js through napi-rs. A `ProtocolStream` consumes
+//! block updates from Tycho, keeps the latest protocol states and quotes swaps on them.
+use std::{collections::HashMap, pin::Pin, str::FromStr, sync::Arc};
+
+use futures::{Stream, StreamExt};
+use napi::{Error, Result};
+use napi_derive::napi;
+use num_bigint::BigUint;
+use tokio::sync::{Mutex, RwLock};
+use tycho_simulation::{
+    evm::{
+        decoder::StreamDecodeError,
+        engine_db::tycho_db::PreCachedDB,
+        protocol::{
+            algebra::state::AlgebraState,
+            filters::{balancer_pool_filter, curve_pool_filter, uniswap_v4_pool_with_hook_filter},
+            fraxswap::state::FraxswapState,
+            uniswap_v2::state::UniswapV2State,
+            uniswap_v3::state::UniswapV3State,
+            uniswap_v4::state::UniswapV4State,
+            vm::state::EVMPoolState,
+        },
+        stream::ProtocolStreamBuilder,
+    },
+    models::Token,
+    protocol::{freshness::StateStore, models::BlockUpdate, price::Price},
+    tycho_client::feed::component_tracker::ComponentFilter,
+    tycho_core::{models::Chain, Bytes},
+    utils::load_all_tokens,
+};
+
+type UpdateStream =
+    Pin<Box<dyn Stream<Item = std::result::Result<BlockUpdate, StreamDecodeError>> + Send>>;
+
+fn to_napi_error(error: impl ToString) -> Error {
+    Error::from_reason(error.to_string())
+}
+
+#[napi(object)]
+pub struct StreamConfig {
+    /// Tycho host, without scheme
+    pub tycho_url: String,
+    pub chain: String,
+    pub auth_key: Option<String>,
+    /// Minimum TVL of the streamed pools, in the chain's native token
+    pub tvl_threshold: f64,
+    /// Exchanges to stream, e.g. `uniswap_v2` or `vm:balancer_v2`
+    pub exchanges: Vec<String>,
+}
+
+#[napi(object)]
+pub struct BlockUpdateSummary {
+    pub block_number: i64,
+    pub new_pools: Vec<String>,
+    pub removed_pools: Vec<String>,
+    pub updated_pools: Vec<String>,
+}
+
+#[napi(object)]
+pub struct Quote {
+    /// Amount received, as a decimal string
+    pub amount_out: String,
+    /// Estimated gas of the swap, as a decimal string
+    pub gas: String,
+    /// Block of the state the quote was computed on
+    pub block_number: Option<i64>,
+}
+
+#[napi(object)]
+pub struct SpotPrice {
+    /// Price in whole units, as a decimal string with the decimals of the quote token
+    pub price: String,
+    /// Smallest units of the quote token per smallest unit of the base token, as a reduced
+    /// fraction of decimal strings
+    pub numerator: String,
+    pub denominator: String,
+}
+
+/// A stream of protocol states, quoting on the latest states received.
+#[napi]
+pub struct ProtocolStream {
+    stream: Mutex<UpdateStream>,
+    states: Arc<RwLock<StateStore>>,
+    tokens: HashMap<Bytes, Token>,
+}
+
+fn register_exchange(
+    builder: ProtocolStreamBuilder,
+    exchange: &str,
+    filter: ComponentFilter,
+) -> Result<ProtocolStreamBuilder> {
+    Ok(match exchange {
+        "uniswap_v2" => builder.exchange::<UniswapV2State>(exchange, filter, None),
+        "uniswap_v3" | "pancakeswap_v3" => {
+            builder.exchange::<UniswapV3State>(exchange, filter, None)
+        }
+        "camelot_v3" | "quickswap_v3" => builder.exchange::<AlgebraState>(exchange, filter, None),
+        "fraxswap" => builder.exchange::<FraxswapState>(exchange, filter, None),
+        "uniswap_v4" => builder.exchange::<UniswapV4State>(
+            exchange,
+            filter,
+            Some(uniswap_v4_pool_with_hook_filter),
+        ),
+        "vm:balancer_v2" => builder.exchange::<EVMPoolState<PreCachedDB>>(
+            exchange,
+            filter,
+            Some(balancer_pool_filter),
+        ),
+        "vm:curve" => {
+            builder.exchange::<EVMPoolState<PreCachedDB>>(exchange, filter, Some(curve_pool_filter))
+        }
+        _ => return Err(Error::from_reason(format!("Unsupported exchange {}", exchange))),
+    })
+}
+
+#[napi]
+impl ProtocolStream {
+    /// Loads the tokens of the chain and connects to Tycho.
+    #[napi(factory)]
+    pub async fn connect(config: StreamConfig) -> Result<ProtocolStream> {
+        let chain = Chain::from_str(&config.chain).map_err(to_napi_error)?;
+        let tokens = load_all_tokens(
+            &config.tycho_url,
+            false,
+            config.auth_key.as_deref(),
+            chain,
+            None,
+            None,
+        )
+        .await;
+
+        let filter = ComponentFilter::with_tvl_range(config.tvl_threshold, config.tvl_threshold);
+        let mut builder = ProtocolStreamBuilder::new(&config.tycho_url, chain);
+        for exchange in &config.exchanges {
+            builder = register_exchange(builder, exchange, filter.clone())?;
+        }
+        let stream = builder
+            .auth_key(config.auth_key)
+            .skip_state_decode_failures(true)
+            .set_tokens(tokens.clone())
+            .await
+            .build()
+            .await
+            .map_err(to_napi_error)?;
+
+        Ok(ProtocolStream {
+            stream: Mutex::new(Box::pin(stream)),
+            states: Arc::new(RwLock::new(StateStore::new(None, 0))),
+            tokens,
+        })
+    }
+
+    /// Waits for the next block update and applies it. Returns `null` once the stream ended.
+    #[napi]
+    pub async fn next(&self) -> Result<Option<BlockUpdateSummary>> {
+        let update = match self.stream.lock().await.next().await {
+            Some(update) => update.map_err(to_napi_error)?,
+            None => return Ok(None),
+        };
+        let summary = BlockUpdateSummary {
+            block_number: update.block_number as i64,
+            new_pools: update
+                .new_pairs
+                .keys()
+                .cloned()
+                .collect(),
+            removed_pools: update
+                .removed_pairs
+                .keys()
+                .cloned()
+                .collect(),
+            updated_pools: update.states.keys().cloned().collect(),
+        };
+        self.states.write().await.apply(update);
+        Ok(Some(summary))
+    }
+
+    /// Quotes selling `amount_in` of `token_in` for `token_out` on a pool.
+    #[napi]
+    pub fn quote(
+        &self,
+        pool_id: String,
+        token_in: String,
+        token_out: String,
+        amount_in: String,
+    ) -> Result<Quote> {
+        let (token_in, token_out) = (self.token(&token_in)?, self.token(&token_out)?);
+        let amount_in = BigUint::from_str(&amount_in).map_err(to_napi_error)?;
+        let states = self.states.blocking_read();
+        let result = states
+            .get_amount_out(&pool_id, amount_in, token_in, token_out)
+            .map_err(to_napi_error)?;
+        Ok(Quote {
+            amount_out: result.amount.to_string(),
+            gas: result.gas.to_string(),
+            block_number: result
+                .block
+                .map(|block| block.number as i64),
+        })
+    }
+
+    /// Quotes like `quote`, without blocking the event loop while EVM code executes.
+    #[napi]
+    pub async fn quote_async(
+        &self,
+        pool_id: String,
+        token_in: String,
+        token_out: String,
+        amount_in: String,
+    ) -> Result<Quote> {
+        let (token_in, token_out) = (self.token(&token_in)?, self.token(&token_out)?);
+        let amount_in = BigUint::from_str(&amount_in).map_err(to_napi_error)?;
+        let (state, block) = {
+            let states = self.states.read().await;
+            let block = states
+                .check_fresh()
+                .map_err(to_napi_error)?
+                .clone();
+            let state = states
+                .get(&pool_id)
+                .ok_or_else(|| Error::from_reason(format!("Unknown pool {}", pool_id)))?
+                .clone_box();
+            (state, block)
+        };
+        let result = state
+            .get_amount_out_async(amount_in, token_in, token_out)
+            .await
+            .map_err(to_napi_error)?;
+        Ok(Quote {
+            amount_out: result.amount.to_string(),
+            gas: result.gas.to_string(),
+            block_number: Some(block.number as i64),
+        })
+    }
+
+    /// Returns the spot price of `base` in units of `quote` on a pool.
+    #[napi]
+    pub fn spot_price(&self, pool_id: String, base: String, quote: String) -> Result<SpotPrice> {
+        let (base, quote) = (self.token(&base)?, self.token(&quote)?);
+        let states = self.states.blocking_read();
+        let price = states
+            .get(&pool_id)
+            .ok_or_else(|| Error::from_reason(format!("Unknown pool {}", pool_id)))?
+            .spot_price(base, quote)
+            .map_err(to_napi_error)?;
+        let price = Price::from_f64(base, quote, price)
+            .ok_or_else(|| Error::from_reason(format!("Invalid spot price {}", price)))?;
+        let (numerator, denominator) = price.to_wei_per_wei();
+        Ok(SpotPrice {
+            price: price.to_string(),
+            numerator: numerator.to_string(),
+            denominator: denominator.to_string(),
+        })
+    }
+
+    fn token(&self, address: &str) -> Result<&Token> {
+        let address = Bytes::from_str(address).map_err(to_napi_error)?;
+        self.tokens
+            .get(&address)
+            .ok_or_else(|| Error::from_reason(format!("Unknown token {}", address)))
+    }
+}
@@ -112,8 +112,11 @@ impl From<SimulationParameters> for simulation::SimulationParameters {
             value: U256::from_be_slice(params.value.to_bytes_be().as_slice()),
             overrides,
             gas_limit: params.gas_limit,
-            block_number: params.block_number.unwrap_or(0),
-            timestamp: params.timestamp.unwrap_or(0),
+            block: simulation_db::BlockHeader {
+                number: params.block_number.unwrap_or(0),
+                timestamp: params.timestamp.unwrap_or(0),
+                ..Default::default()
+            },
         }
     }
 }
@@ -126,6 +129,8 @@ impl From<SimulationParameters> for simulation::SimulationParameters {
 ///     New values of storage slots
 /// balance: Optional[int]
 ///     New native token balance
+/// code: Optional[bytearray]
+///     New code of a created or updated contract
 #[pyclass]
 #[derive(Clone, Debug)]
 pub struct StateUpdate {
@@ -133,14 +138,20 @@ pub struct StateUpdate {
     pub storage: Option<HashMap<BigUint, BigUint>>,
     #[pyo3(get)]
     pub balance: Option<BigUint>,
+    #[pyo3(get)]
+    pub code: Option<Vec<u8>>,
 }
 
 #[pymethods]
 impl StateUpdate {
     #[new]
-    #[pyo3(signature = (storage = None, balance = None))]
-    fn new(storage: Option<HashMap<BigUint, BigUint>>, balance: Option<BigUint>) -> Self {
-        Self { storage, balance }
+    #[pyo3(signature = (storage = None, balance = None, code = None))]
+    fn new(
+        storage: Option<HashMap<BigUint, BigUint>>,
+        balance: Option<BigUint>,
+        code: Option<Vec<u8>>,
+    ) -> Self {
+        Self { storage, balance, code }
     }
 }
 
@@ -161,7 +172,13 @@ impl From<account_storage::StateUpdate> for StateUpdate {
             py_balances = Some(BigUint::from_bytes_le(rust_balances.as_le_slice()))
         }
 
-        StateUpdate { storage: Some(py_storage), balance: py_balances }
+        StateUpdate {
+            storage: Some(py_storage),
+            balance: py_balances,
+            code: state_update
+                .code
+                .map(|code| code.to_vec()),
+        }
     }
 }
 
@@ -182,7 +199,12 @@ impl From<StateUpdate> for account_storage::StateUpdate {
             rust_balance = Some(U256::from_str(&py_balance.to_string()).unwrap());
         }
 
-        account_storage::StateUpdate { storage: Some(rust_storage), balance: rust_balance }
+        account_storage::StateUpdate {
+            storage: Some(rust_storage),
+            balance: rust_balance,
+            code: py_state_update.code.map(Into::into),
+            nonce: None,
+        }
     }
 }
 
@@ -411,6 +433,7 @@ impl From<BlockHeader> for tycho_simulation::evm::engine_db::simulation_db::Bloc
             number: py_header.number,
             hash: B256::from_str(&py_header.hash).unwrap(),
             timestamp: py_header.timestamp,
+            ..Default::default()
         }
     }
 }
@@ -456,6 +479,11 @@ impl From<simulation::SimulationEngineError> for SimulationErrorDetails {
             simulation::SimulationEngineError::OutOfGas(reason, _) => {
                 SimulationErrorDetails { data: reason, gas_used: None }
             }
+            simulation::SimulationEngineError::UnsupportedSpecId(reason) |
+            simulation::SimulationEngineError::InvalidParams(reason) |
+            simulation::SimulationEngineError::TruncatedStateUpdates(reason) => {
+                SimulationErrorDetails { data: reason, gas_used: None }
+            }
         }
     }
 }